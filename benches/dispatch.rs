@@ -0,0 +1,43 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use std::sync::Arc;
+use zkwork_ironminer::{HeaderBuffers, HeaderLayout, Work};
+
+const HEADER: &str = "0000000000000000677101000000000000000000000232f50bb970eeab81d7e2053ebaa585d9b7297f7d14c2063a60e8509d3e86a44918c8f318377cbb327f4fc5b602e78784994cf2926f0addd55d1b0d36880100000000f1baa930706f8b9058bc55be1f464b472639a288763a16f7a5713aa761052e43f7bec3000000000000000000000c6072a3898d86f685d4b9bba50e87f750f9773da7ac2cf96663e357c8b30082010000000000007735ccc1666978796f750000000000000000000000000000000000000000000000000000";
+
+/// The notify to dispatch path: decode the header, splice the graffiti, share the job.
+fn notify_dispatch(c: &mut Criterion) {
+    let layout = HeaderLayout::IRONFISH;
+    let graffiti = [0x7a; 32];
+    let target = [0xff; 32];
+
+    c.bench_function("notify_dispatch_fresh", |b| {
+        b.iter(|| {
+            let work =
+                Work::from_notify(1, black_box(HEADER), &graffiti, target, &layout, None).unwrap();
+            black_box(Arc::new(work))
+        })
+    });
+
+    let buffers = HeaderBuffers::default();
+    c.bench_function("notify_dispatch_recycled", |b| {
+        b.iter(|| {
+            let work = Work::from_notify(
+                1,
+                black_box(HEADER),
+                &graffiti,
+                target,
+                &layout,
+                buffers.take(),
+            )
+            .unwrap();
+            buffers.recycle(black_box(Arc::new(work)));
+        })
+    });
+}
+
+criterion_group!(benches, notify_dispatch);
+criterion_main!(benches);
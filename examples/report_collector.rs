@@ -0,0 +1,103 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! Collects the stats reports miners push with `--report-to`, over UDP datagrams and TCP
+//! lines on the same port, and prints each one as the sender followed by the report's JSON.
+//! The first line is the address it listens on, gaps in a worker's sequence numbers and
+//! restarts are printed as lines of their own. Run with `cargo run --example report_collector`.
+
+use anyhow::Result;
+use clap::Parser;
+use log::*;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    net::{TcpListener, UdpSocket},
+};
+use zkwork_ironminer::StatsReport;
+
+#[derive(Clone, Debug, Parser)]
+#[clap(name = "report_collector", author = "zk.work")]
+#[clap(about = "Prints the stats reports miners push with --report-to")]
+struct Args {
+    /// Address and port to listen on, for both UDP and TCP
+    #[clap(long = "listen", default_value = "0.0.0.0:9999")]
+    listen: SocketAddr,
+}
+
+/// The last sequence number seen per worker.
+#[derive(Default)]
+struct Collector {
+    last_seq: Mutex<HashMap<String, u64>>,
+}
+
+impl Collector {
+    fn received(&self, peer: SocketAddr, line: &str) {
+        let report: StatsReport = match serde_json::from_str(line) {
+            Ok(report) => report,
+            Err(error) => {
+                warn!("{} sent an invalid report: {}", peer, error);
+                return;
+            }
+        };
+        let previous = self
+            .last_seq
+            .lock()
+            .unwrap()
+            .insert(report.worker_name.clone(), report.seq);
+        match previous {
+            Some(previous) if report.seq > previous + 1 => println!(
+                "{} missed {} reports before {}",
+                report.worker_name,
+                report.seq - previous - 1,
+                report.seq
+            ),
+            Some(previous) if report.seq <= previous => {
+                println!("{} restarted", report.worker_name)
+            }
+            _ => {}
+        }
+        println!("{} {}", peer, line);
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    pretty_env_logger::init_timed();
+    let args = Args::parse();
+    let udp = UdpSocket::bind(args.listen).await?;
+    let tcp = TcpListener::bind(udp.local_addr()?).await?;
+    println!("listening on {}", udp.local_addr()?);
+    let collector = Arc::new(Collector::default());
+
+    let tcp_collector = collector.clone();
+    tokio::spawn(async move {
+        loop {
+            let (stream, peer) = match tcp.accept().await {
+                Ok(accepted) => accepted,
+                Err(error) => {
+                    warn!("accept failed: {}", error);
+                    continue;
+                }
+            };
+            let collector = tcp_collector.clone();
+            tokio::spawn(async move {
+                let mut lines = BufReader::new(stream).lines();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    collector.received(peer, &line);
+                }
+            });
+        }
+    });
+
+    let mut datagram = vec![0; 65536];
+    loop {
+        let (len, peer) = udp.recv_from(&mut datagram).await?;
+        collector.received(peer, &String::from_utf8_lossy(&datagram[..len]));
+    }
+}
@@ -0,0 +1,363 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::{
+    supervise, ConnectionStats, HistorySample, HistoryWindow, Meter, MinerSet, PoolStats,
+    RestartPolicy,
+};
+use anyhow::{anyhow, Result};
+use log::*;
+use serde::Serialize;
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    task, time,
+};
+
+const MAX_REQUEST_SIZE: usize = 8192;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Serialize)]
+struct MinerHistory {
+    label: String,
+    samples: Vec<HistorySample>,
+    /// Timestamps of the samples with an irregular one-second sample in them.
+    irregular: Vec<u64>,
+}
+
+#[derive(Serialize)]
+struct HistoryResponse {
+    window: String,
+    miners: Vec<MinerHistory>,
+}
+
+#[derive(Serialize)]
+struct MinerConnections {
+    label: String,
+    connections: Vec<ConnectionStats>,
+}
+
+#[derive(Serialize)]
+struct ConnectionsResponse {
+    miners: Vec<MinerConnections>,
+}
+
+#[derive(Serialize)]
+struct MinerPools {
+    label: String,
+    pools: Vec<PoolStats>,
+}
+
+#[derive(Serialize)]
+struct PoolsResponse {
+    miners: Vec<MinerPools>,
+}
+
+#[derive(Serialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+/// A minimal HTTP endpoint serving the miners' stats as JSON, from the latest
+/// [`crate::StatsSnapshot`].
+///
+/// * `GET /stats` - current rates of every miner instance
+/// * `GET /stats/history?window=1s|1m&points=N` - hashrate history, averaged down to at most
+///   `N` points
+/// * `GET /stats/connections` - the last pool connections of every miner instance
+/// * `GET /stats/pools` - the totals of `--pool` and every `--failover-pool` of every miner
+///   instance
+/// * `GET /stats/config` - the build, the machine and the options every miner instance runs
+///   with, see [`crate::ConfigReport`]
+/// * `POST /control/pause` and `POST /control/resume` - pause and resume every miner instance
+/// * `POST /control/upgrade` - upgrade in place, with `--upgrade`
+///
+/// The control requests are only served to loopback peers, and never to a browser page, which
+/// gives itself away by its `Origin` header. [`crate::ApiSocket`] serves the same requests on a
+/// local socket.
+pub struct Api;
+
+impl Api {
+    pub async fn start(address: SocketAddr, miners: Arc<MinerSet>) -> Result<()> {
+        let listener = Arc::new(TcpListener::bind(address).await?);
+        info!("Stats api listening on http://{}", address);
+        supervise("stats api", RestartPolicy::default(), move || {
+            Self::serve(listener.clone(), miners.clone())
+        });
+        Ok(())
+    }
+
+    async fn serve(listener: Arc<TcpListener>, miners: Arc<MinerSet>) {
+        loop {
+            match listener.accept().await {
+                Ok((stream, peer)) => {
+                    let miners = miners.clone();
+                    let local = peer.ip().is_loopback();
+                    task::spawn(async move {
+                        if let Err(error) = Self::handle_connection(stream, miners, local).await {
+                            debug!("[Stats api] {}", error);
+                        }
+                    });
+                }
+                Err(error) => {
+                    warn!("[Stats api] failed to accept connection: {}", error);
+                    time::sleep(Duration::from_millis(100)).await;
+                }
+            }
+        }
+    }
+
+    async fn handle_connection(
+        mut stream: TcpStream,
+        miners: Arc<MinerSet>,
+        local: bool,
+    ) -> Result<()> {
+        let request = time::timeout(REQUEST_TIMEOUT, Self::read_request(&mut stream)).await??;
+        let (status, body) = Self::respond(&request, &miners, local).await;
+        let reason = match status {
+            200 => "OK",
+            202 => "Accepted",
+            400 => "Bad Request",
+            403 => "Forbidden",
+            404 => "Not Found",
+            _ => "Method Not Allowed",
+        };
+        let response = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            reason,
+            body.len(),
+            body
+        );
+        stream.write_all(response.as_bytes()).await?;
+        stream.shutdown().await?;
+        Ok(())
+    }
+
+    /// The status code and JSON body for a request starting with "<METHOD> <target>", however
+    /// it came in. Control requests are refused unless the request is `local`.
+    pub(crate) async fn respond(request: &str, miners: &MinerSet, local: bool) -> (u16, String) {
+        match Self::parse_request_line(request) {
+            Ok(("GET", target)) => Self::route(target, miners),
+            Ok(("POST", target)) if target.starts_with("/control/") => {
+                match Self::control_refused(request, local) {
+                    Some(reason) => (403, Self::error(reason)),
+                    None => Self::control(target, miners).await,
+                }
+            }
+            Ok(_) => (405, Self::error("method not allowed")),
+            Err(error) => (400, Self::error(&error.to_string())),
+        }
+    }
+
+    /// Why a control request may not be served, `None` if it may.
+    fn control_refused(request: &str, local: bool) -> Option<&'static str> {
+        if !local {
+            return Some("control requests are only served to loopback peers");
+        }
+        // a page in a browser may post to 127.0.0.1, but it can't leave out its origin
+        let mut headers = request.lines().skip(1).take_while(|line| !line.is_empty());
+        let from_page = headers.any(|header| {
+            header
+                .split_once(':')
+                .is_some_and(|(name, _)| name.trim().eq_ignore_ascii_case("origin"))
+        });
+        from_page.then_some("control requests from a browser page are refused")
+    }
+
+    async fn control(target: &str, miners: &MinerSet) -> (u16, String) {
+        match target {
+            "/control/pause" => {
+                miners.pause_mining().await;
+                (200, String::from("{\"paused\":true}"))
+            }
+            "/control/resume" => {
+                miners.resume_mining().await;
+                (200, String::from("{\"paused\":false}"))
+            }
+            "/control/upgrade" => Self::upgrade(miners),
+            _ => (404, Self::error("not found")),
+        }
+    }
+
+    fn upgrade(miners: &MinerSet) -> (u16, String) {
+        if !miners.request_upgrade() {
+            return (
+                403,
+                Self::error("start the miner with --upgrade to allow upgrades"),
+            );
+        }
+        (202, String::from("{\"upgrading\":true}"))
+    }
+
+    async fn read_request(stream: &mut TcpStream) -> Result<String> {
+        let mut buf = vec![0u8; MAX_REQUEST_SIZE];
+        let mut len = 0;
+        loop {
+            let n = stream.read(&mut buf[len..]).await?;
+            if n == 0 {
+                break;
+            }
+            len += n;
+            if buf[..len].windows(4).any(|w| w == b"\r\n\r\n") || len == buf.len() {
+                break;
+            }
+        }
+        Ok(String::from_utf8_lossy(&buf[..len]).into_owned())
+    }
+
+    fn parse_request_line(request: &str) -> Result<(&str, &str)> {
+        let mut parts = request
+            .lines()
+            .next()
+            .unwrap_or_default()
+            .split_whitespace();
+        match (parts.next(), parts.next()) {
+            (Some(method), Some(target)) => Ok((method, target)),
+            _ => Err(anyhow!("malformed request line")),
+        }
+    }
+
+    fn route(target: &str, miners: &MinerSet) -> (u16, String) {
+        let (path, query) = Self::parse_target(target);
+        match path {
+            "/stats" => (200, serde_json::to_string(&*miners.snapshot()).unwrap()),
+            "/stats/history" => match Self::history(&query, miners) {
+                Ok(response) => (200, serde_json::to_string(&response).unwrap()),
+                Err(error) => (400, Self::error(&error.to_string())),
+            },
+            "/stats/connections" => (
+                200,
+                serde_json::to_string(&Self::connections(miners)).unwrap(),
+            ),
+            "/stats/pools" => (200, serde_json::to_string(&Self::pools(miners)).unwrap()),
+            "/stats/config" => (200, serde_json::to_string(&*miners.config()).unwrap()),
+            _ => (404, Self::error("not found")),
+        }
+    }
+
+    fn connections(miners: &MinerSet) -> ConnectionsResponse {
+        ConnectionsResponse {
+            miners: miners
+                .snapshot()
+                .miners
+                .iter()
+                .map(|stats| MinerConnections {
+                    label: stats.label.clone(),
+                    connections: stats.connections.clone(),
+                })
+                .collect(),
+        }
+    }
+
+    fn pools(miners: &MinerSet) -> PoolsResponse {
+        PoolsResponse {
+            miners: miners
+                .snapshot()
+                .miners
+                .iter()
+                .map(|stats| MinerPools {
+                    label: stats.label.clone(),
+                    pools: stats.pools.clone(),
+                })
+                .collect(),
+        }
+    }
+
+    fn history(query: &[(&str, &str)], miners: &MinerSet) -> Result<HistoryResponse> {
+        let mut window_name = "1s";
+        let mut points = 0;
+        for &(key, value) in query {
+            match key {
+                "window" => window_name = value,
+                "points" => {
+                    points = value
+                        .parse()
+                        .map_err(|_| anyhow!("invalid points '{}'", value))?
+                }
+                _ => {}
+            }
+        }
+        let window: HistoryWindow = window_name.parse()?;
+        let snapshot = miners.snapshot();
+        let histories = snapshot
+            .miners
+            .iter()
+            .zip(&snapshot.histories)
+            .map(|(stats, history)| MinerHistory {
+                label: stats.label.clone(),
+                samples: Meter::downsample(history.window(window), points),
+                irregular: match window {
+                    HistoryWindow::Second => {
+                        Meter::downsample_irregular(&history.second, points, &history.irregular)
+                    }
+                    HistoryWindow::Minute => vec![],
+                },
+            })
+            .collect();
+        Ok(HistoryResponse {
+            window: window_name.to_string(),
+            miners: histories,
+        })
+    }
+
+    fn parse_target(target: &str) -> (&str, Vec<(&str, &str)>) {
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let query = query
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| pair.split_once('=').unwrap_or((pair, "")))
+            .collect();
+        (path, query)
+    }
+
+    fn error(message: &str) -> String {
+        serde_json::to_string(&ErrorResponse {
+            error: message.to_string(),
+        })
+        .unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_target() {
+        assert_eq!(Api::parse_target("/stats"), ("/stats", vec![]));
+        assert_eq!(
+            Api::parse_target("/stats/history?window=1m&points=120"),
+            ("/stats/history", vec![("window", "1m"), ("points", "120")])
+        );
+        assert_eq!(
+            Api::parse_target("/stats/history?points&"),
+            ("/stats/history", vec![("points", "")])
+        );
+    }
+
+    #[test]
+    fn test_parse_request_line() {
+        let request = "GET /stats HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        assert_eq!(Api::parse_request_line(request).unwrap(), ("GET", "/stats"));
+        assert!(Api::parse_request_line("").is_err());
+    }
+
+    #[test]
+    fn test_control_refused() {
+        let request = "POST /control/pause HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        assert_eq!(Api::control_refused(request, true), None);
+        assert!(Api::control_refused(request, false)
+            .unwrap()
+            .contains("loopback"));
+        // a cross-origin post from a browser page
+        let request = "POST /control/pause HTTP/1.1\r\nHost: 127.0.0.1:8000\r\norigin: http://example.com\r\n\r\n";
+        assert!(Api::control_refused(request, true)
+            .unwrap()
+            .contains("browser"));
+        // the api socket takes a request line alone
+        assert_eq!(Api::control_refused("POST /control/pause", true), None);
+    }
+}
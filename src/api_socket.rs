@@ -0,0 +1,253 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! The stats api on a local socket, for hosts where even a localhost port is not allowed:
+//! a Unix domain socket on Unix, a named pipe on Windows, e.g. `\\.\pipe\ironminer`.
+//!
+//! Requests and responses are lines. A request is what would start an HTTP request to
+//! [`Api`], e.g. `GET /stats` or `POST /control/pause`, and its response is the status code,
+//! a space and the JSON body:
+//!
+//! ```text
+//! > GET /stats/connections
+//! < 200 {"miners":[...]}
+//! ```
+//!
+//! A connection may carry any number of requests, one after the other.
+
+use crate::{supervise, Api, MinerSet, RestartPolicy};
+use anyhow::{anyhow, Result};
+use futures::SinkExt;
+use log::*;
+use std::{io, path::Path, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    task, time,
+};
+use tokio_stream::StreamExt;
+use tokio_util::codec::{Framed, LinesCodec};
+
+/// The longest request line.
+const MAX_LINE_LENGTH: usize = 8192;
+/// Connections without a request for this long are closed.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Where `status` looks for the api socket.
+#[cfg(unix)]
+pub const DEFAULT_API_SOCKET: &str = "/run/ironminer.sock";
+#[cfg(windows)]
+pub const DEFAULT_API_SOCKET: &str = r"\\.\pipe\ironminer";
+
+/// A connection to the api socket.
+#[cfg(unix)]
+pub type ApiSocketStream = tokio::net::UnixStream;
+#[cfg(windows)]
+pub type ApiSocketStream = tokio::net::windows::named_pipe::NamedPipeClient;
+
+/// Parses `--api-socket-mode`, octal permission bits like `600` or `0660`.
+pub fn parse_socket_mode(s: &str) -> Result<u32> {
+    let digits = s.trim_start_matches("0o");
+    match u32::from_str_radix(digits, 8) {
+        Ok(mode) if mode <= 0o777 && !digits.is_empty() => Ok(mode),
+        _ => Err(anyhow!("expected octal permissions like 600, got '{}'", s)),
+    }
+}
+
+/// Serves [`Api`] requests on `--api-socket`, see the module docs.
+pub struct ApiSocket;
+
+impl ApiSocket {
+    /// Listens on `path`, replacing a socket file no miner listens on anymore, as left behind
+    /// by an upgrade in place or a crash. `mode` is applied to the socket file on Unix.
+    #[cfg(unix)]
+    pub async fn start(path: &Path, mode: u32, miners: Arc<MinerSet>) -> Result<()> {
+        use std::{fs, os::unix::fs::PermissionsExt};
+        use tokio::net::{UnixListener, UnixStream};
+
+        if path.exists() {
+            if UnixStream::connect(path).await.is_ok() {
+                return Err(anyhow!("{} is in use by another process", path.display()));
+            }
+            fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+        info!("Stats api listening on {}", path.display());
+        let listener = Arc::new(listener);
+        supervise("stats api socket", RestartPolicy::default(), move || {
+            let listener = listener.clone();
+            let miners = miners.clone();
+            async move {
+                loop {
+                    match listener.accept().await {
+                        Ok((stream, _)) => Self::spawn_connection(stream, miners.clone()),
+                        Err(error) => {
+                            warn!("[Stats api] failed to accept connection: {}", error);
+                            time::sleep(Duration::from_millis(100)).await;
+                        }
+                    }
+                }
+            }
+        });
+        Ok(())
+    }
+
+    /// Listens on the pipe `path`, failing if another process already serves it. `mode` does
+    /// not apply to pipes, which only the user's processes and administrators may open by
+    /// default.
+    #[cfg(windows)]
+    pub async fn start(path: &Path, _mode: u32, miners: Arc<MinerSet>) -> Result<()> {
+        use std::sync::Mutex;
+        use tokio::net::windows::named_pipe::ServerOptions;
+
+        let first = ServerOptions::new()
+            .first_pipe_instance(true)
+            .create(path)?;
+        info!("Stats api listening on {}", path.display());
+        let first = Arc::new(Mutex::new(Some(first)));
+        let path = path.to_path_buf();
+        supervise("stats api socket", RestartPolicy::default(), move || {
+            let first = first.clone();
+            let path = path.clone();
+            let miners = miners.clone();
+            async move {
+                let mut server = first.lock().unwrap().take();
+                loop {
+                    let pipe = match server.take() {
+                        Some(pipe) => pipe,
+                        None => match ServerOptions::new().create(&path) {
+                            Ok(pipe) => pipe,
+                            Err(error) => {
+                                warn!("[Stats api] failed to create pipe: {}", error);
+                                time::sleep(Duration::from_millis(100)).await;
+                                continue;
+                            }
+                        },
+                    };
+                    match pipe.connect().await {
+                        Ok(()) => Self::spawn_connection(pipe, miners.clone()),
+                        Err(error) => {
+                            warn!("[Stats api] failed to accept connection: {}", error);
+                            time::sleep(Duration::from_millis(100)).await;
+                        }
+                    }
+                }
+            }
+        });
+        Ok(())
+    }
+
+    fn spawn_connection<S>(stream: S, miners: Arc<MinerSet>)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        task::spawn(async move {
+            if let Err(error) = Self::handle_connection(stream, miners).await {
+                debug!("[Stats api] {}", error);
+            }
+        });
+    }
+
+    async fn handle_connection<S>(stream: S, miners: Arc<MinerSet>) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut framed = Framed::new(stream, LinesCodec::new_with_max_length(MAX_LINE_LENGTH));
+        while let Ok(Some(request)) = time::timeout(IDLE_TIMEOUT, framed.next()).await {
+            // only local users can connect to the socket
+            let (status, body) = Api::respond(&request?, &miners, true).await;
+            framed.send(format!("{} {}", status, body)).await?;
+        }
+        Ok(())
+    }
+
+    /// Connects to the api socket at `path`.
+    pub async fn connect(path: &Path) -> io::Result<ApiSocketStream> {
+        #[cfg(unix)]
+        {
+            tokio::net::UnixStream::connect(path).await
+        }
+        #[cfg(windows)]
+        {
+            tokio::net::windows::named_pipe::ClientOptions::new().open(path)
+        }
+    }
+
+    /// Sends one request, e.g. "GET /stats", and returns the JSON body of a 2xx response.
+    pub async fn request(stream: ApiSocketStream, request: &str) -> Result<String> {
+        let mut framed = Framed::new(stream, LinesCodec::new());
+        framed.send(request).await?;
+        let response = framed
+            .next()
+            .await
+            .ok_or_else(|| anyhow!("the miner closed the api socket"))??;
+        let (status, body) = response
+            .split_once(' ')
+            .ok_or_else(|| anyhow!("malformed response '{}'", response))?;
+        match status.parse::<u16>() {
+            Ok(200..=299) => Ok(body.to_string()),
+            Ok(status) => Err(anyhow!("{} {}", status, body)),
+            Err(_) => Err(anyhow!("malformed response '{}'", response)),
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::{test_util::test_cli, PauseReason, StatsSnapshot};
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn test_parse_socket_mode() {
+        assert_eq!(parse_socket_mode("600").unwrap(), 0o600);
+        assert_eq!(parse_socket_mode("0660").unwrap(), 0o660);
+        assert_eq!(parse_socket_mode("0o777").unwrap(), 0o777);
+        assert!(parse_socket_mode("800").is_err());
+        assert!(parse_socket_mode("1777").is_err());
+        assert!(parse_socket_mode("").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_api_socket() {
+        let dir = std::env::temp_dir().join(format!("ironminer-api-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("api.sock");
+        // left behind by a previous process
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        let set = MinerSet::initialize(test_cli("127.0.0.1:8181".parse().unwrap()))
+            .await
+            .unwrap();
+        ApiSocket::start(&path, 0o600, set.clone()).await.unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert!(ApiSocket::start(&path, 0o600, set.clone()).await.is_err());
+
+        let stream = ApiSocket::connect(&path).await.unwrap();
+        let stats = ApiSocket::request(stream, "GET /stats").await.unwrap();
+        let stats: StatsSnapshot = serde_json::from_str(&stats).unwrap();
+        assert_eq!(stats.miners.len(), 1);
+        let stream = ApiSocket::connect(&path).await.unwrap();
+        let error = ApiSocket::request(stream, "GET /nowhere")
+            .await
+            .unwrap_err();
+        assert!(error.to_string().starts_with("404 "), "{}", error);
+
+        // pause and resume on one connection
+        let stream = ApiSocket::connect(&path).await.unwrap();
+        let mut framed = Framed::new(stream, LinesCodec::new());
+        framed.send("POST /control/pause").await.unwrap();
+        let response = framed.next().await.unwrap().unwrap();
+        assert_eq!(response, r#"200 {"paused":true}"#);
+        let miner = &set.miners()[0];
+        assert_eq!(miner.stats().await.pauses.reason, Some(PauseReason::Manual));
+        framed.send("POST /control/resume").await.unwrap();
+        let response = framed.next().await.unwrap().unwrap();
+        assert_eq!(response, r#"200 {"paused":false}"#);
+        assert_ne!(miner.stats().await.pauses.reason, Some(PauseReason::Manual));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(ApiSocket::connect(&path).await.is_err());
+    }
+}
@@ -0,0 +1,253 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! Tells when the hashrate drops well below what the rig usually does, e.g. because of
+//! thermal throttling or an update running in the background.
+
+use std::time::{Duration, Instant};
+
+/// Once the alert is up, the rate has to come back this much above the threshold, as a
+/// fraction of the baseline, to clear it. Keeps a rate hovering at the threshold from
+/// raising and clearing the alert over and over.
+const CLEAR_MARGIN: f64 = 0.05;
+/// A rate above the baseline pulls it half way up in this time, so an upgraded rig gets a new
+/// baseline within a day, while a short burst barely moves it.
+const ADAPT_HALF_LIFE: Duration = Duration::from_secs(6 * 3600);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BaselineConfig {
+    /// Mining time after which the median of the rates seen becomes the baseline.
+    pub warmup: Duration,
+    /// Fraction of the baseline below which the rate counts as dropped.
+    pub threshold: f64,
+    /// How long the rate has to stay below the threshold before the alert is raised.
+    pub alert_after: Duration,
+}
+
+impl Default for BaselineConfig {
+    fn default() -> Self {
+        Self {
+            warmup: Duration::from_secs(600),
+            threshold: 0.8,
+            alert_after: Duration::from_secs(300),
+        }
+    }
+}
+
+/// What changed with one observed rate.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BaselineEvent {
+    Learned { baseline: f64 },
+    AlertRaised { rate: f64, baseline: f64 },
+    AlertCleared { rate: f64, baseline: f64 },
+}
+
+/// Learns the usual hashrate and alerts when the rate stays below a fraction of it.
+#[derive(Debug)]
+pub struct HashrateBaseline {
+    config: BaselineConfig,
+    warmup_started: Option<Instant>,
+    warmup_rates: Vec<f64>,
+    baseline: Option<f64>,
+    last_observed: Option<Instant>,
+    below_since: Option<Instant>,
+    alerting: bool,
+}
+
+impl HashrateBaseline {
+    pub fn new(config: BaselineConfig) -> Self {
+        Self {
+            config,
+            warmup_started: None,
+            warmup_rates: Vec::new(),
+            baseline: None,
+            last_observed: None,
+            below_since: None,
+            alerting: false,
+        }
+    }
+
+    pub fn baseline(&self) -> Option<f64> {
+        self.baseline
+    }
+
+    pub fn is_alerting(&self) -> bool {
+        self.alerting
+    }
+
+    /// Feeds the rate as of `now`. Only call it while mining, see [`Self::idle`].
+    pub fn observe(&mut self, now: Instant, rate: f64) -> Option<BaselineEvent> {
+        let elapsed = self
+            .last_observed
+            .map(|last| now.saturating_duration_since(last))
+            .unwrap_or_default();
+        self.last_observed = Some(now);
+        let baseline = match self.baseline {
+            Some(baseline) => baseline,
+            None => return self.warm_up(now, rate),
+        };
+        if rate < baseline * self.config.threshold {
+            let below_since = *self.below_since.get_or_insert(now);
+            if !self.alerting
+                && now.saturating_duration_since(below_since) > self.config.alert_after
+            {
+                self.alerting = true;
+                return Some(BaselineEvent::AlertRaised { rate, baseline });
+            }
+            return None;
+        }
+        self.below_since = None;
+        if self.alerting {
+            if rate < baseline * (self.config.threshold + CLEAR_MARGIN) {
+                return None;
+            }
+            self.alerting = false;
+            return Some(BaselineEvent::AlertCleared { rate, baseline });
+        }
+        if rate > baseline {
+            let alpha = 1.0 - 0.5f64.powf(elapsed.as_secs_f64() / ADAPT_HALF_LIFE.as_secs_f64());
+            self.baseline = Some(baseline + alpha * (rate - baseline));
+        }
+        None
+    }
+
+    /// Mining is paused, a rate of zero now is no drop. Restarts the time below the
+    /// threshold, an alert already raised stays up until the rate recovers.
+    pub fn idle(&mut self) {
+        self.below_since = None;
+        self.last_observed = None;
+    }
+
+    fn warm_up(&mut self, now: Instant, rate: f64) -> Option<BaselineEvent> {
+        let started = *self.warmup_started.get_or_insert(now);
+        self.warmup_rates.push(rate);
+        if now.saturating_duration_since(started) < self.config.warmup {
+            return None;
+        }
+        let mut rates = std::mem::take(&mut self.warmup_rates);
+        rates.sort_by(f64::total_cmp);
+        let baseline = rates[rates.len() / 2];
+        if baseline <= 0.0 {
+            // nothing mined, learn it over again
+            self.warmup_started = None;
+            return None;
+        }
+        self.baseline = Some(baseline);
+        Some(BaselineEvent::Learned { baseline })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    /// Feeds one rate per minute, starting a minute after `*now`, and returns the events.
+    fn feed(
+        detector: &mut HashrateBaseline,
+        now: &mut Instant,
+        rates: &[f64],
+    ) -> Vec<BaselineEvent> {
+        rates
+            .iter()
+            .filter_map(|rate| {
+                *now += MINUTE;
+                detector.observe(*now, *rate)
+            })
+            .collect()
+    }
+
+    fn learned(now: &mut Instant) -> HashrateBaseline {
+        let mut detector = HashrateBaseline::new(BaselineConfig::default());
+        // the median ignores the slow first minute and the spike
+        let mut rates = vec![100.0; 11];
+        rates[0] = 10.0;
+        rates[5] = 500.0;
+        assert_eq!(
+            feed(&mut detector, now, &rates),
+            vec![BaselineEvent::Learned { baseline: 100.0 }]
+        );
+        detector
+    }
+
+    #[test]
+    fn test_learn_baseline() {
+        let mut now = Instant::now();
+        let mut detector = HashrateBaseline::new(BaselineConfig::default());
+        assert!(feed(&mut detector, &mut now, &[100.0; 10]).is_empty());
+        assert_eq!(detector.baseline(), None);
+        assert_eq!(
+            feed(&mut detector, &mut now, &[100.0]),
+            vec![BaselineEvent::Learned { baseline: 100.0 }]
+        );
+
+        // the baseline follows an upgraded rig up, slowly, but never down
+        assert!(feed(&mut detector, &mut now, &[200.0; 60]).is_empty());
+        let baseline = detector.baseline().unwrap();
+        assert!(baseline > 105.0 && baseline < 150.0, "{}", baseline);
+        assert!(feed(&mut detector, &mut now, &[90.0; 60]).is_empty());
+        assert_eq!(detector.baseline(), Some(baseline));
+    }
+
+    #[test]
+    fn test_alert_after_sustained_drop() {
+        let mut now = Instant::now();
+        let mut detector = learned(&mut now);
+        // five minutes below is not yet more than five minutes
+        assert!(feed(&mut detector, &mut now, &[70.0; 6]).is_empty());
+        assert_eq!(
+            feed(&mut detector, &mut now, &[70.0]),
+            vec![BaselineEvent::AlertRaised {
+                rate: 70.0,
+                baseline: 100.0
+            }]
+        );
+        assert!(detector.is_alerting());
+        assert!(feed(&mut detector, &mut now, &[70.0; 10]).is_empty());
+        assert_eq!(
+            feed(&mut detector, &mut now, &[95.0]),
+            vec![BaselineEvent::AlertCleared {
+                rate: 95.0,
+                baseline: 100.0
+            }]
+        );
+        assert!(!detector.is_alerting());
+    }
+
+    #[test]
+    fn test_flapping_around_threshold() {
+        let mut now = Instant::now();
+        let mut detector = learned(&mut now);
+        // dips that recover in time never alert
+        let flapping: Vec<f64> = (0..30)
+            .map(|i| if i % 4 == 3 { 81.0 } else { 79.0 })
+            .collect();
+        assert!(feed(&mut detector, &mut now, &flapping).is_empty());
+
+        // once raised, rates just above the threshold do not clear the alert
+        assert_eq!(feed(&mut detector, &mut now, &[79.0; 7]).len(), 1);
+        let flapping: Vec<f64> = (0..30)
+            .map(|i| if i % 2 == 0 { 79.0 } else { 82.0 })
+            .collect();
+        assert!(feed(&mut detector, &mut now, &flapping).is_empty());
+        assert!(detector.is_alerting());
+        assert_eq!(feed(&mut detector, &mut now, &[86.0]).len(), 1);
+
+        // a recovery in between starts the time below over
+        assert!(feed(&mut detector, &mut now, &[79.0, 79.0, 79.0, 85.0]).is_empty());
+        assert!(feed(&mut detector, &mut now, &[79.0; 6]).is_empty());
+        assert_eq!(feed(&mut detector, &mut now, &[79.0]).len(), 1);
+    }
+
+    #[test]
+    fn test_idle_is_no_drop() {
+        let mut now = Instant::now();
+        let mut detector = learned(&mut now);
+        assert!(feed(&mut detector, &mut now, &[0.0; 5]).is_empty());
+        detector.idle();
+        assert!(feed(&mut detector, &mut now, &[0.0; 5]).is_empty());
+        assert!(!detector.is_alerting());
+    }
+}
@@ -0,0 +1,330 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! Drives many simulated miners against a pool, for pool side load testing.
+//!
+//! Every client subscribes like the miner does and fake-mines: at the share rate it searches
+//! the current job for a randomness that meets the pool target and submits it. Pools under
+//! test should hand out an easy target, the search gives up after `--max-search` hashes.
+
+use anyhow::{anyhow, Result};
+use clap::Parser;
+use futures::SinkExt;
+use log::*;
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{split, ReadHalf, WriteHalf},
+    net::TcpStream,
+    task,
+    time::{self, Instant},
+};
+use tokio_stream::StreamExt;
+use tokio_util::codec::{FramedRead, FramedWrite};
+use zkwork_ironminer::{
+    verify_share, HeaderLayout, MiningNotifyBody, MiningNotifyMessage, MiningSetTargetBody,
+    MiningSetTargetMessage, MiningSubmitBody, MiningSubmitMessage, MiningSubscribeBody,
+    MiningSubscribeMessage, MiningSubscribedBody, MiningSubscribedMessage, Randomness,
+    StratumMessage, StratumMessageCodec, Work,
+};
+
+/// A pool that accepts the connection but never answers the subscribe is a failed connect.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug, Parser)]
+#[clap(name = "stress_client", author = "zk.work")]
+#[clap(about = "Opens many stratum connections to a pool and submits shares on all of them")]
+struct Args {
+    /// Specify the IP address and port of pool to connect to.
+    #[clap(long = "pool")]
+    pool: SocketAddr,
+    /// Reward address the clients subscribe with.
+    #[clap(
+        long = "address",
+        default_value = "91f65bdad677058fe9e674931a7be0fa34d615317e992fb1af2ae30547c2c276bb9a"
+    )]
+    address: String,
+    /// Worker names are this prefix followed by the client number
+    #[clap(long = "worker-prefix", default_value = "stress")]
+    worker_prefix: String,
+    /// Number of concurrent pool connections
+    #[clap(long = "clients", default_value_t = 100)]
+    clients: usize,
+    /// New connections opened per second until all clients are started
+    #[clap(long = "ramp", default_value_t = 10.0)]
+    ramp: f64,
+    /// Shares submitted per second by every client
+    #[clap(long = "share-rate", default_value_t = 1.0)]
+    share_rate: f64,
+    /// Hashes tried per share before giving up on the current target
+    #[clap(long = "max-search", default_value_t = 100_000)]
+    max_search: u64,
+    /// Milliseconds a client waits before reconnecting after the pool dropped it
+    #[clap(long = "reconnect-delay", default_value_t = 2000)]
+    reconnect_delay: u64,
+    /// Seconds between two stats reports
+    #[clap(long = "report-interval", default_value_t = 10)]
+    report_interval: u64,
+    /// Stop after this many seconds, run until interrupted if not set
+    #[clap(long = "duration")]
+    duration: Option<u64>,
+}
+
+/// Counters summed over all clients.
+#[derive(Debug, Default)]
+struct Stats {
+    connects: AtomicU64,
+    connect_errors: AtomicU64,
+    subscribed: AtomicI64,
+    disconnects: AtomicU64,
+    read_errors: AtomicU64,
+    write_errors: AtomicU64,
+    jobs: AtomicU64,
+    submits: AtomicU64,
+    searches_exhausted: AtomicU64,
+}
+
+impl Stats {
+    fn report(&self, elapsed: Duration, submits_before: u64, period: Duration) -> u64 {
+        let submits = self.submits.load(Ordering::Relaxed);
+        info!(
+            "{}s: {} subscribed, {} connects ({} failed), {} disconnects, {} jobs, {} submits ({:.1}/s), {} read errors, {} write errors, {} searches exhausted",
+            elapsed.as_secs(),
+            self.subscribed.load(Ordering::Relaxed),
+            self.connects.load(Ordering::Relaxed),
+            self.connect_errors.load(Ordering::Relaxed),
+            self.disconnects.load(Ordering::Relaxed),
+            self.jobs.load(Ordering::Relaxed),
+            submits,
+            (submits - submits_before) as f64 / period.as_secs_f64().max(1e-3),
+            self.read_errors.load(Ordering::Relaxed),
+            self.write_errors.load(Ordering::Relaxed),
+            self.searches_exhausted.load(Ordering::Relaxed),
+        );
+        submits
+    }
+}
+
+/// Searches `work` for a randomness meeting its target, starting at `start`.
+fn search(work: &Work, start: u64, max_search: u64) -> Option<u64> {
+    let layout = HeaderLayout::IRONFISH;
+    (start..start.saturating_add(max_search)).find(|randomness| {
+        let randomness = Randomness::from_value(*randomness, &layout);
+        verify_share(&work.header, &randomness, &work.target, &layout).unwrap_or_default()
+    })
+}
+
+type Reader = FramedRead<ReadHalf<TcpStream>, StratumMessageCodec>;
+type Writer = FramedWrite<WriteHalf<TcpStream>, StratumMessageCodec>;
+
+/// Connects and subscribes like the miner does. Returns the graffiti the pool assigned.
+async fn subscribe(index: usize, args: &Args) -> Result<(Reader, Writer, String)> {
+    let stream = match time::timeout(HANDSHAKE_TIMEOUT, TcpStream::connect(args.pool)).await {
+        Ok(stream) => stream?,
+        Err(_) => return Err(anyhow!("connect timed out")),
+    };
+    let (r, w) = split(stream);
+    let mut w = FramedWrite::new(w, StratumMessageCodec::default());
+    let mut r = FramedRead::new(r, StratumMessageCodec::default());
+    w.send(StratumMessage::MiningSubscribeMessage(
+        MiningSubscribeMessage {
+            id: 0.into(),
+            method: String::from("mining.subscribe"),
+            body: MiningSubscribeBody {
+                version: 1,
+                name: format!("{}{}", args.worker_prefix, index),
+                publicAddress: Some(args.address.clone()),
+                agent: None,
+                graffitiSuffix: None,
+                capabilities: vec![],
+            },
+        },
+    ))
+    .await?;
+    let graffiti = match time::timeout(HANDSHAKE_TIMEOUT, r.next()).await {
+        Ok(Some(Ok(StratumMessage::MiningSubscribedMessage(MiningSubscribedMessage {
+            body: MiningSubscribedBody { graffiti, .. },
+            ..
+        })))) => graffiti,
+        Ok(Some(Ok(message))) => return Err(anyhow!("unexpected message {:?}", message)),
+        Ok(Some(Err(error))) => return Err(error),
+        Ok(None) => return Err(anyhow!("pool closed the connection")),
+        Err(_) => return Err(anyhow!("no answer to subscribe")),
+    };
+    Ok((r, w, graffiti))
+}
+
+/// Subscribes and mines on one connection until the pool drops it.
+async fn run_connection(index: usize, args: &Args, stats: &Stats) -> Result<()> {
+    let layout = HeaderLayout::IRONFISH;
+    let (mut r, mut w, graffiti) = match subscribe(index, args).await {
+        Ok(connection) => connection,
+        Err(error) => {
+            stats.connect_errors.fetch_add(1, Ordering::Relaxed);
+            return Err(error);
+        }
+    };
+    let mut graffiti_bytes = vec![0u8; layout.graffiti_size];
+    let len = graffiti.len().min(layout.graffiti_size);
+    graffiti_bytes[..len].copy_from_slice(&graffiti.as_bytes()[..len]);
+    stats.connects.fetch_add(1, Ordering::Relaxed);
+    stats.subscribed.fetch_add(1, Ordering::Relaxed);
+
+    let result = async {
+        let mut target = None;
+        let mut work: Option<Arc<Work>> = None;
+        // every client searches its own part of the randomness space
+        let mut next_randomness = (index as u64) << 40;
+        let mut shares = time::interval(Duration::from_secs_f64(1.0 / args.share_rate));
+        let mut next_message_id = 1;
+        loop {
+            tokio::select! {
+                _ = shares.tick() => {
+                    let job = match work.clone() {
+                        Some(job) => job,
+                        None => continue,
+                    };
+                    let mining_request_id = job.mining_request_id;
+                    let (start, max_search) = (next_randomness, args.max_search);
+                    let found = task::spawn_blocking(move || search(&job, start, max_search)).await?;
+                    let randomness = match found {
+                        Some(randomness) => randomness,
+                        None => {
+                            stats.searches_exhausted.fetch_add(1, Ordering::Relaxed);
+                            next_randomness += max_search;
+                            continue;
+                        }
+                    };
+                    next_randomness = randomness + 1;
+                    let submit = StratumMessage::MiningSubmitMessage(MiningSubmitMessage {
+                        id: next_message_id.into(),
+                        method: String::from("mining.submit"),
+                        body: MiningSubmitBody {
+                            miningRequestId: mining_request_id,
+                            randomness: Randomness::from_value(randomness, &layout).to_wire_hex(),
+                            elapsedMs: None,
+                        },
+                    });
+                    next_message_id += 1;
+                    if let Err(error) = w.send(submit).await {
+                        stats.write_errors.fetch_add(1, Ordering::Relaxed);
+                        return Err(error);
+                    }
+                    stats.submits.fetch_add(1, Ordering::Relaxed);
+                }
+                message = r.next() => match message {
+                    Some(Ok(StratumMessage::MiningSetTargetMessage(MiningSetTargetMessage {
+                        body: MiningSetTargetBody { target: hex_target },
+                        ..
+                    }))) => {
+                        let mut bytes = [0u8; 32];
+                        hex::decode_to_slice(&hex_target, &mut bytes)?;
+                        target = Some(bytes);
+                    }
+                    Some(Ok(StratumMessage::MiningNotifyMessage(MiningNotifyMessage {
+                        body: MiningNotifyBody { miningRequestId: mining_request_id, header, .. },
+                        ..
+                    }))) => {
+                        stats.jobs.fetch_add(1, Ordering::Relaxed);
+                        if let Some(target) = target {
+                            work = Some(Arc::new(Work::from_notify(
+                                mining_request_id,
+                                &header,
+                                &graffiti_bytes,
+                                target,
+                                &layout,
+                                None,
+                            )?));
+                        }
+                    }
+                    Some(Ok(StratumMessage::MiningWaitForWorkMessage(_))) => work = None,
+                    Some(Ok(_)) => {}
+                    Some(Err(error)) => {
+                        stats.read_errors.fetch_add(1, Ordering::Relaxed);
+                        return Err(error);
+                    }
+                    None => return Err(anyhow!("pool closed the connection")),
+                }
+            }
+        }
+    }
+    .await;
+    stats.subscribed.fetch_sub(1, Ordering::Relaxed);
+    stats.disconnects.fetch_add(1, Ordering::Relaxed);
+    result
+}
+
+/// Keeps one simulated miner connected, reconnecting whenever the pool drops it.
+async fn run_client(index: usize, args: Arc<Args>, stats: Arc<Stats>) {
+    loop {
+        let subscribed = stats.connects.load(Ordering::Relaxed);
+        if let Err(error) = run_connection(index, &args, &stats).await {
+            // a client that subscribed was disconnected, the others failed to connect
+            if stats.connects.load(Ordering::Relaxed) == subscribed {
+                stats.connect_errors.fetch_add(1, Ordering::Relaxed);
+            }
+            debug!("client {}: {}", index, error);
+        }
+        time::sleep(Duration::from_millis(args.reconnect_delay)).await;
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    pretty_env_logger::init_timed();
+    let args = Arc::new(Args::parse());
+    if args.clients == 0 || args.ramp <= 0.0 || args.share_rate <= 0.0 {
+        return Err(anyhow!(
+            "--clients, --ramp and --share-rate must be greater than zero"
+        ));
+    }
+    info!(
+        "starting {} clients against pool({}) at {} per second",
+        args.clients, args.pool, args.ramp
+    );
+    let stats = Arc::new(Stats::default());
+    let started = Instant::now();
+
+    let ramp = {
+        let (args, stats) = (args.clone(), stats.clone());
+        task::spawn(async move {
+            let mut ramp = time::interval(Duration::from_secs_f64(1.0 / args.ramp));
+            for index in 0..args.clients {
+                ramp.tick().await;
+                task::spawn(run_client(index, args.clone(), stats.clone()));
+            }
+            info!("all {} clients started", args.clients);
+        })
+    };
+
+    let period = Duration::from_secs(args.report_interval.max(1));
+    let mut reports = time::interval_at(Instant::now() + period, period);
+    let deadline = time::sleep(
+        args.duration
+            .map(Duration::from_secs)
+            .unwrap_or(Duration::MAX / 4),
+    );
+    tokio::pin!(deadline);
+    let mut submits = 0;
+    loop {
+        tokio::select! {
+            _ = reports.tick() => {
+                submits = stats.report(started.elapsed(), submits, period);
+            }
+            _ = &mut deadline => break,
+            _ = tokio::signal::ctrl_c() => break,
+        }
+    }
+    ramp.abort();
+    let elapsed = started.elapsed();
+    info!("final stats after {}s", elapsed.as_secs());
+    stats.report(elapsed, 0, elapsed);
+    Ok(())
+}
@@ -2,97 +2,472 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 
+use anyhow::anyhow;
+use bytes::BytesMut;
+use clap::Parser;
 use futures::SinkExt;
 use log::*;
-use tokio::io::split;
-use tokio::net::TcpListener;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::io::{split, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
 use tokio_stream::StreamExt;
-use tokio_util::codec::{FramedRead, FramedWrite};
+use tokio_util::codec::{Decoder, FramedRead, FramedWrite, LinesCodec};
 use zkwork_ironminer::{
-    MiningNotifyBody, MiningNotifyMessage, MiningSetTargetBody, MiningSetTargetMessage,
+    append_graffiti_suffix, graffiti_bytes, is_compressed, validate_client_message, verify_share,
+    CompressionCounts, HeaderLayout, LineInflater, MiningAuthorizeBody, MiningAuthorizeMessage,
+    MiningHashrateBody, MiningHashrateMessage, MiningNotifyBody, MiningNotifyMessage,
+    MiningSetTargetBody, MiningSetTargetMessage, MiningSubmitBody, MiningSubmitMessage,
     MiningSubscribeBody, MiningSubscribeMessage, MiningSubscribedBody, MiningSubscribedMessage,
-    StratumMessage, StratumMessageCodec,
+    Randomness, StratumMessage, StratumMessageCodec, Target, CAPABILITY_DEFLATE,
+    CAPABILITY_HASHRATE, CAPABILITY_SOLVE_TIME, CAPABILITY_TIMESTAMPS, MAX_LINE_LENGTH,
 };
 
+const TARGET: &str = "00000049494cff9a3f4f473f91d116af7382c45e653facfeef85b8f43d9d6b64";
+const HEADER: &str = "0000000000000000677101000000000000000000000232f50bb970eeab81d7e2053ebaa585d9b7297f7d14c2063a60e8509d3e86a44918c8f318377cbb327f4fc5b602e78784994cf2926f0addd55d1b0d36880100000000f1baa930706f8b9058bc55be1f464b472639a288763a16f7a5713aa761052e43f7bec3000000000000000000000c6072a3898d86f685d4b9bba50e87f750f9773da7ac2cf96663e357c8b30082010000000000007735ccc1666978796f750000000000000000000000000000000000000000000000000000";
+const GRAFFITI: &str = "Iron Fish Pool.1";
+/// Over the 32 bytes of the header, with a multibyte character across the boundary.
+const LONG_GRAFFITI: &str = "Iron Fish Pool.1 long graffiti über 32 bytes";
+
+/// How often the jobs are sent again, as a pool sends a job for every new block.
+const JOB_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The header of the job with `mining_request_id`. The jobs after the first differ from it in
+/// the last byte before the graffiti, so that each has shares of its own.
+fn job_header(mining_request_id: u32, layout: &HeaderLayout) -> anyhow::Result<Vec<u8>> {
+    let mut header = hex::decode(HEADER)?;
+    header[layout.graffiti_offset - 1] ^= mining_request_id as u8;
+    Ok(header)
+}
+
+/// Checks a submitted share the way the pool would, on the header the miner received with
+/// our graffiti spliced in, followed by the suffix the miner subscribed with.
+fn verify_submit(
+    mining_request_id: u32,
+    randomness: &str,
+    suffix: &str,
+    args: &Args,
+) -> anyhow::Result<bool> {
+    let layout = args.layout()?;
+    if mining_request_id >= args.jobs {
+        return Err(anyhow!(
+            "no job of mining request id({})",
+            mining_request_id
+        ));
+    }
+    let mut header = job_header(mining_request_id, &layout)?;
+    let mut graffiti = graffiti_bytes(args.graffiti())?.0;
+    append_graffiti_suffix(&mut graffiti, args.graffiti().len(), suffix);
+    header[layout.graffiti_range()].copy_from_slice(&graffiti);
+    verify_share(
+        &header,
+        &Randomness::from_wire_hex(randomness, &layout)?,
+        args.target.as_bytes(),
+        &layout,
+    )
+}
+
+/// Connections seen per agent the miners subscribed with.
+type Agents = Arc<Mutex<HashMap<String, u64>>>;
+
+#[derive(Clone, Debug, Parser)]
+#[clap(name = "test_server", author = "zk.work")]
+#[clap(about = "A stratum pool with fixed jobs, for trying out the miner locally")]
+struct Args {
+    /// Check every line the miner sends against the wire format and drop the miner on the
+    /// first violation
+    #[clap(long = "strict")]
+    strict: bool,
+    /// The share target: 64 hex digits, a shorter hex prefix padded with f, or
+    /// diff:<difficulty>
+    #[clap(long = "target", default_value = TARGET)]
+    target: Target,
+    /// Hand out a graffiti over 32 bytes, which the miner has to cut
+    #[clap(long = "long-graffiti")]
+    long_graffiti: bool,
+    /// Accept shares mined with the graffiti suffix the miner subscribed with, written after
+    /// our graffiti. Without it a suffix is ignored and its shares are invalid
+    #[clap(long = "allow-graffiti-suffix")]
+    allow_graffiti_suffix: bool,
+    /// Bytes of randomness the submits must carry, as the miner's --randomness-width
+    #[clap(long = "randomness-width", default_value_t = 8, value_name = "BYTES")]
+    randomness_width: usize,
+    /// Close every connection this long after the subscribe, like a flapping load balancer
+    #[clap(long = "drop-after-ms", value_name = "MS")]
+    drop_after_ms: Option<u64>,
+    /// Hand out this many jobs at once, with mining request ids 0 up, as a pool that expects
+    /// the miner to split its effort between them, see the miner's --max-concurrent-jobs
+    #[clap(
+        long = "jobs",
+        default_value_t = 1,
+        value_name = "N",
+        value_parser = clap::value_parser!(u32).range(1..=256)
+    )]
+    jobs: u32,
+    /// Agree to the solve_time capability, so that the miner submits how long each share took
+    /// since its job was dispatched
+    #[clap(long = "solve-time")]
+    solve_time: bool,
+    /// Send nothing more once the miner has its jobs, while still reading what it sends, like
+    /// a firewall that drops the pool's side of the connection. The miner should take the
+    /// connection for half open after its --half-open-timeout
+    #[clap(long = "blackhole-responses")]
+    blackhole_responses: bool,
+    /// Agree to the deflate capability, and compress the lines both ways after the subscribe
+    /// ack, as for a miner with --compress
+    #[clap(long = "compress")]
+    compress: bool,
+}
+
+impl Args {
+    fn graffiti(&self) -> &'static str {
+        if self.long_graffiti {
+            LONG_GRAFFITI
+        } else {
+            GRAFFITI
+        }
+    }
+
+    fn layout(&self) -> anyhow::Result<HeaderLayout> {
+        HeaderLayout::IRONFISH.with_randomness_width(self.randomness_width)
+    }
+
+    /// A "mining.notify" per job, the first with message id `first_id`.
+    fn notify_messages(&self, first_id: i64) -> anyhow::Result<Vec<StratumMessage>> {
+        let layout = self.layout()?;
+        let mut messages = Vec::with_capacity(self.jobs as usize);
+        for mining_request_id in 0..self.jobs {
+            let header = job_header(mining_request_id, &layout)?;
+            messages.push(StratumMessage::MiningNotifyMessage(MiningNotifyMessage {
+                id: (first_id + mining_request_id as i64).into(),
+                method: String::from("mining.notify"),
+                body: MiningNotifyBody {
+                    miningRequestId: mining_request_id,
+                    header: hex::encode(header),
+                    timestamp: SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .ok()
+                        .map(|duration| duration.as_millis() as u64),
+                    expiresInMs: None,
+                },
+            }));
+        }
+        Ok(messages)
+    }
+}
+
+/// A line from the miner that breaks the wire format in `--strict` mode.
+#[derive(Debug)]
+struct Violation {
+    line: String,
+    reason: anyhow::Error,
+}
+
+impl std::fmt::Display for Violation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.reason, self.line)
+    }
+}
+
+impl std::error::Error for Violation {}
+
+/// Decodes what the miner sends, checking each line with `validate_client_message` first in
+/// `--strict` mode.
+struct ServerCodec {
+    messages: StratumMessageCodec,
+    lines: LinesCodec,
+    /// Restores the compressed lines ahead of the checks in `--strict` mode.
+    inflater: Option<LineInflater>,
+    strict: bool,
+    last_id: Option<i64>,
+}
+
+impl ServerCodec {
+    fn new(strict: bool) -> Self {
+        Self {
+            messages: StratumMessageCodec::default(),
+            lines: LinesCodec::new_with_max_length(MAX_LINE_LENGTH),
+            inflater: None,
+            strict,
+            last_id: None,
+        }
+    }
+
+    /// Reads compressed lines from now on.
+    fn decompress_reads(&mut self, counts: Arc<CompressionCounts>) {
+        if self.strict {
+            self.inflater = Some(LineInflater::new(counts));
+        } else {
+            self.messages.decompress_reads(counts);
+        }
+    }
+}
+
+impl Decoder for ServerCodec {
+    type Error = anyhow::Error;
+    type Item = StratumMessage;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if !self.strict {
+            return self.messages.decode(src);
+        }
+        let line = match self.lines.decode(src)? {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+        let line = match self.inflater.as_mut() {
+            Some(inflater) if is_compressed(line.as_bytes()) => {
+                String::from_utf8(inflater.inflate(line.as_bytes())?)?
+            }
+            _ => line,
+        };
+        match validate_client_message(&line, self.last_id) {
+            Ok(id) => self.last_id = Some(id),
+            Err(reason) => return Err(Violation { line, reason }.into()),
+        }
+        serde_json::from_str(&line)
+            .map(Some)
+            .map_err(|error| anyhow!("{}: {}", error, line))
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     pretty_env_logger::init_timed();
-    info!("server listen at 127.0.0.1:8181");
+    let args = Args::parse();
+    args.layout()?;
+    info!(
+        "server listen at 127.0.0.1:8181{}, target {} (difficulty {:.0})",
+        if args.strict { ", strict" } else { "" },
+        args.target,
+        args.target.difficulty()
+    );
     let listener = TcpListener::bind("127.0.0.1:8181").await?;
-    let (stream, _) = listener.accept().await?;
+    let agents = Agents::default();
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        tokio::spawn(serve(stream, peer, agents.clone(), args.clone()));
+    }
+}
+
+async fn serve(stream: TcpStream, peer: SocketAddr, agents: Agents, args: Args) {
     let (r, w) = split(stream);
     let mut w = FramedWrite::new(w, StratumMessageCodec::default());
-    let mut r = FramedRead::new(r, StratumMessageCodec::default());
+    let mut r = FramedRead::new(r, ServerCodec::new(args.strict));
+    let suffix;
+    let solve_time;
+    let mut compression = None;
 
     match r.next().await {
-        Some(Ok(message)) => {
-            if let StratumMessage::MiningSubscribeMessage(MiningSubscribeMessage {
+        Some(Ok(StratumMessage::MiningSubscribeMessage(MiningSubscribeMessage {
+            id,
+            method,
+            body:
+                MiningSubscribeBody {
+                    version,
+                    name,
+                    publicAddress: public_address,
+                    agent,
+                    graffitiSuffix: graffiti_suffix,
+                    capabilities,
+                },
+        }))) => {
+            let agent = agent.unwrap_or_else(|| String::from("unknown"));
+            suffix = match graffiti_suffix {
+                Some(graffiti_suffix) if args.allow_graffiti_suffix => {
+                    info!(
+                        "{} mines with the graffiti suffix '{}'",
+                        peer, graffiti_suffix
+                    );
+                    graffiti_suffix
+                }
+                Some(graffiti_suffix) => {
+                    warn!(
+                        "{} mines with the graffiti suffix '{}', its shares will be invalid without --allow-graffiti-suffix",
+                        peer, graffiti_suffix
+                    );
+                    String::new()
+                }
+                None => String::new(),
+            };
+            let connections = {
+                let mut agents = agents.lock().unwrap();
+                let connections = agents.entry(agent.clone()).or_default();
+                *connections += 1;
+                *connections
+            };
+            info!(
+                "{} id({}) method({}) version({}) worker_name({}) public address({}) agent({}) connections with this agent({})",
+                peer,
                 id,
                 method,
-                body:
-                    MiningSubscribeBody {
-                        version,
-                        name,
-                        publicAddress: public_address,
+                version,
+                name,
+                public_address.as_deref().unwrap_or("none, expecting a token"),
+                agent,
+                connections
+            );
+            // a client that offers nothing gets the legacy ack
+            let capabilities: Option<Vec<String>> = if capabilities.is_empty() {
+                None
+            } else {
+                Some(
+                    capabilities
+                        .into_iter()
+                        .filter(|capability| {
+                            capability == CAPABILITY_TIMESTAMPS
+                                || capability == CAPABILITY_HASHRATE
+                                || (capability == CAPABILITY_SOLVE_TIME && args.solve_time)
+                                || (capability == CAPABILITY_DEFLATE && args.compress)
+                        })
+                        .collect(),
+                )
+            };
+            solve_time = capabilities
+                .iter()
+                .flatten()
+                .any(|capability| capability == CAPABILITY_SOLVE_TIME);
+            let deflate = capabilities
+                .iter()
+                .flatten()
+                .any(|capability| capability == CAPABILITY_DEFLATE);
+            // "mining.subscribed"
+            let subscribed_message =
+                StratumMessage::MiningSubscribedMessage(MiningSubscribedMessage {
+                    id: 0.into(),
+                    method: String::from("mining.subscribed"),
+                    body: MiningSubscribedBody {
+                        clientId: 1,
+                        graffiti: String::from(args.graffiti()),
+                        capabilities,
                     },
-            }) = message
-            {
-                info!(
-                    "id({}) method({}) version({}) worker_name({}) public address({})",
-                    id, method, version, name, public_address
-                );
-                // "mining.subscribed"
-                let subscribed_message =
-                    StratumMessage::MiningSubscribedMessage(MiningSubscribedMessage {
-                        id: 0,
-                        method: String::from("mining.subscribed"),
-                        body: MiningSubscribedBody {
-                            clientId: 1,
-                            graffiti: String::from("Iron Fish Pool.1"),
-                        },
-                    });
-                let _ = w.send(subscribed_message).await;
-
-                // "mining.set_target"
-                let set_target_message =
-                    StratumMessage::MiningSetTargetMessage(MiningSetTargetMessage {
-                        id: 1,
-                        method: String::from("mining.set_target"),
-                        body: MiningSetTargetBody {
-                            target: String::from(
-                                "00000049494cff9a3f4f473f91d116af7382c45e653facfeef85b8f43d9d6b64",
-                            ),
-                        },
-                    });
-                let _ = w.send(set_target_message).await;
-
-                // "mining.notify"
-                let notify_message = StratumMessage::MiningNotifyMessage(
-                MiningNotifyMessage {
-                    id: 2,
-                    method: String::from("mining.notify"),
-                    body: MiningNotifyBody {
-                        miningRequestId: 0,
-                        header: String::from("0000000000000000677101000000000000000000000232f50bb970eeab81d7e2053ebaa585d9b7297f7d14c2063a60e8509d3e86a44918c8f318377cbb327f4fc5b602e78784994cf2926f0addd55d1b0d36880100000000f1baa930706f8b9058bc55be1f464b472639a288763a16f7a5713aa761052e43f7bec3000000000000000000000c6072a3898d86f685d4b9bba50e87f750f9773da7ac2cf96663e357c8b30082010000000000007735ccc1666978796f750000000000000000000000000000000000000000000000000000"),
+                });
+            let _ = w.send(subscribed_message).await;
+            if deflate {
+                let counts = Arc::new(CompressionCounts::default());
+                w.encoder_mut().compress_writes(counts.clone());
+                r.decoder_mut().decompress_reads(counts.clone());
+                info!("{} compresses the lines from now on", peer);
+                compression = Some(counts);
+            }
+
+            // "mining.set_target"
+            let set_target_message =
+                StratumMessage::MiningSetTargetMessage(MiningSetTargetMessage {
+                    id: 1.into(),
+                    method: String::from("mining.set_target"),
+                    body: MiningSetTargetBody {
+                        target: args.target.to_string(),
                     },
+                });
+            let _ = w.send(set_target_message).await;
+
+            // "mining.notify", one per job
+            match args.notify_messages(2) {
+                Ok(messages) => {
+                    for message in messages {
+                        let _ = w.send(message).await;
+                    }
                 }
-            );
-                let _ = w.send(notify_message).await;
-            } else {
-                error!("unexpected message, expected(MiningSubscribeMessage)");
-                return Ok(());
+                Err(error) => {
+                    error!("{}", error);
+                    return;
+                }
+            }
+            if args.blackhole_responses {
+                info!("{} gets nothing more, as --blackhole-responses says", peer);
             }
         }
+        Some(Err(error)) if error.is::<Violation>() => {
+            reject(w, peer, error).await;
+            return;
+        }
         _ => {
             error!("unexpected message, expected(MiningSubscribeMessage)");
-            return Ok(());
+            return;
         }
     }
+    let drop_at = args
+        .drop_after_ms
+        .map(|ms| tokio::time::Instant::now() + Duration::from_millis(ms));
+    let mut refresh = tokio::time::interval_at(
+        tokio::time::Instant::now() + JOB_REFRESH_INTERVAL,
+        JOB_REFRESH_INTERVAL,
+    );
+    let mut next_id = 2 + args.jobs as i64;
     loop {
-        match r.next().await {
+        let next = tokio::select! {
+            next = r.next() => next,
+            _ = tokio::time::sleep_until(drop_at.unwrap_or_else(tokio::time::Instant::now)),
+                if drop_at.is_some() =>
+            {
+                info!("{} dropped as --drop-after-ms says", peer);
+                break;
+            }
+            _ = refresh.tick(), if !args.blackhole_responses => {
+                // the same jobs again, which the miner takes as a sign of life
+                if let Ok(messages) = args.notify_messages(next_id) {
+                    next_id += messages.len() as i64;
+                    for message in messages {
+                        let _ = w.send(message).await;
+                    }
+                }
+                continue;
+            }
+        };
+        match next {
+            Some(Ok(StratumMessage::MiningSubmitMessage(MiningSubmitMessage {
+                body:
+                    MiningSubmitBody {
+                        miningRequestId: mining_request_id,
+                        randomness,
+                        elapsedMs: elapsed_ms,
+                    },
+                ..
+            }))) => {
+                if elapsed_ms.is_some() && !solve_time {
+                    warn!(
+                        "{} submitted a solve time without agreeing to {}",
+                        peer, CAPABILITY_SOLVE_TIME
+                    );
+                }
+                let solved_in = elapsed_ms
+                    .map(|ms| format!(" solved in({}ms)", ms))
+                    .unwrap_or_default();
+                match verify_submit(mining_request_id, &randomness, &suffix, &args) {
+                    Ok(true) => info!(
+                        "valid share: mining request id({}) randomness({}){}",
+                        mining_request_id, randomness, solved_in
+                    ),
+                    Ok(false) => warn!(
+                        "invalid share, hash above target: mining request id({}) randomness({}){}",
+                        mining_request_id, randomness, solved_in
+                    ),
+                    Err(error) => warn!("malformed share randomness({}): {}", randomness, error),
+                }
+            }
+            Some(Ok(StratumMessage::MiningAuthorizeMessage(MiningAuthorizeMessage {
+                body: MiningAuthorizeBody { token },
+                ..
+            }))) => info!("{} authorized with a {} character token", peer, token.len()),
+            Some(Ok(StratumMessage::MiningHashrateMessage(MiningHashrateMessage {
+                body: MiningHashrateBody { hashrate, name },
+                ..
+            }))) => info!(
+                "{} reports hashrate({:.0} H/s) worker_name({})",
+                peer, hashrate, name
+            ),
             Some(Ok(message)) => {
                 info!("{:?}", message);
             }
+            Some(Err(error)) if error.is::<Violation>() => {
+                reject(w, peer, error).await;
+                return;
+            }
             Some(Err(error)) => {
                 error!("{}", error);
                 break;
@@ -100,5 +475,38 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             None => break,
         }
     }
-    Ok(())
+    match compression {
+        Some(counts) => {
+            let stats = counts.stats();
+            info!(
+                "{} disconnected, compression saved {} bytes ({:.0}%)",
+                peer,
+                stats.saved(),
+                stats.saved_percent()
+            );
+        }
+        None => info!("{} disconnected", peer),
+    }
+}
+
+/// Tells the miner what was wrong with its line and closes the connection. The reason goes out
+/// as a plain line, which the miner logs as a line it could not decode.
+async fn reject<W>(
+    mut w: FramedWrite<W, StratumMessageCodec>,
+    peer: SocketAddr,
+    violation: anyhow::Error,
+) where
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    error!("{} protocol violation: {}", peer, violation);
+    let reason = match violation.downcast_ref::<Violation>() {
+        Some(violation) => violation.reason.to_string(),
+        None => violation.to_string(),
+    };
+    let w = w.get_mut();
+    let _ = w
+        .write_all(format!("protocol error: {}\n", reason).as_bytes())
+        .await;
+    let _ = w.shutdown().await;
+    info!("{} disconnected", peer);
 }
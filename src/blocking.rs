@@ -0,0 +1,273 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! A miner for embedders that do not run tokio, e.g. a GUI. It owns its runtime and every call
+//! blocks until done, so none of it may be called from within a tokio runtime.
+
+use crate::{build_runtime, Api, Cli, MinerEvent, MinerSet, MinerStats};
+use anyhow::{anyhow, Result};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    runtime::Runtime,
+    sync::broadcast::{self, error::RecvError},
+    time,
+};
+
+/// Events kept for a caller that does not read them, the oldest are dropped beyond that.
+const EVENT_CAPACITY: usize = 256;
+/// How long dropping the miner waits for the tasks left on its runtime.
+const RUNTIME_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    Created,
+    Running,
+    Stopped,
+}
+
+/// The stats of every miner at one point in time.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StatsSnapshot {
+    /// Started and not stopped yet.
+    pub running: bool,
+    pub miners: Vec<MinerStats>,
+}
+
+/// Mines with the configuration of the command line, one miner per pool of `--split`.
+/// Dropping it stops the miners and shuts the runtime down.
+pub struct Miner {
+    runtime: Option<Runtime>,
+    miners: Arc<MinerSet>,
+    api: Option<SocketAddr>,
+    state: Mutex<State>,
+    events: Mutex<broadcast::Receiver<MinerEvent>>,
+}
+
+impl Miner {
+    /// Sets the miners up on a runtime of their own, sized like the command line miner's.
+    /// Nothing connects to the pool before [`Miner::start`].
+    pub fn new(mut cli: Cli) -> Result<Self> {
+        let runtime = build_runtime(&mut cli)?;
+        let api = cli.api;
+        let miners = runtime.block_on(MinerSet::initialize(cli))?;
+        let (sender, events) = broadcast::channel(EVENT_CAPACITY);
+        for miner in miners.miners() {
+            let mut receiver = miner.subscribe_events();
+            let sender = sender.clone();
+            runtime.spawn(async move {
+                loop {
+                    match receiver.recv().await {
+                        Ok(event) => {
+                            let _ = sender.send(event);
+                        }
+                        Err(RecvError::Lagged(_)) => {}
+                        Err(RecvError::Closed) => return,
+                    }
+                }
+            });
+        }
+        Ok(Self {
+            runtime: Some(runtime),
+            miners,
+            api,
+            state: Mutex::new(State::Created),
+            events: Mutex::new(events),
+        })
+    }
+
+    fn runtime(&self) -> &Runtime {
+        self.runtime
+            .as_ref()
+            .expect("runtime is only taken on drop")
+    }
+
+    /// Connects to the pools and starts mining. Starting a running miner does nothing, a
+    /// stopped one can not be started again.
+    pub fn start(&self) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        match *state {
+            State::Running => return Ok(()),
+            State::Stopped => return Err(anyhow!("a stopped miner can not be started again")),
+            State::Created => {}
+        }
+        self.runtime().block_on(async {
+            if let Some(address) = self.api {
+                if let Err(error) = Api::start(address, self.miners.clone()).await {
+                    error!("failed to start stats api on {}: {}", address, error);
+                }
+            }
+            for miner in self.miners.miners() {
+                crate::Miner::launch(miner.clone()).await;
+            }
+        });
+        *state = State::Running;
+        Ok(())
+    }
+
+    /// Stops mining, submits the last shares and closes the pool connections. Returns once
+    /// everything is down. Stopping again does nothing.
+    pub fn stop(&self) {
+        let mut state = self.state.lock().unwrap();
+        if *state == State::Running {
+            self.runtime().block_on(self.miners.stop());
+        }
+        *state = State::Stopped;
+    }
+
+    pub fn stats(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            running: *self.state.lock().unwrap() == State::Running,
+            miners: self.runtime().block_on(self.miners.stats()),
+        }
+    }
+
+    /// Waits up to `timeout` for the next event of any of the miners. Events the caller was
+    /// too slow for are skipped.
+    pub fn next_event(&self, timeout: Duration) -> Option<MinerEvent> {
+        let mut events = self.events.lock().unwrap();
+        self.runtime().block_on(async {
+            time::timeout(timeout, async {
+                loop {
+                    match events.recv().await {
+                        Ok(event) => return Some(event),
+                        Err(RecvError::Lagged(_)) => continue,
+                        Err(RecvError::Closed) => return None,
+                    }
+                }
+            })
+            .await
+            .ok()
+            .flatten()
+        })
+    }
+}
+
+impl Drop for Miner {
+    fn drop(&mut self) {
+        self.stop();
+        if let Some(runtime) = self.runtime.take() {
+            runtime.shutdown_timeout(RUNTIME_SHUTDOWN_TIMEOUT);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::test_cli;
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        sync::atomic::{AtomicUsize, Ordering},
+        thread,
+        time::Instant,
+    };
+
+    /// A pool on a plain thread that sends one job and counts the submits it receives.
+    fn spawn_pool() -> (SocketAddr, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let submits = Arc::new(AtomicUsize::new(0));
+        let counter = submits.clone();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            for line in BufReader::new(stream).lines() {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(_) => return,
+                };
+                if line.contains("\"mining.subscribe\"") {
+                    // about one share in 65536 hashes, so the pool is not flooded
+                    let lines = [
+                        String::from(
+                            r#"{"id":0,"method":"mining.subscribed","body":{"clientId":1,"graffiti":"zk.work"}}"#,
+                        ),
+                        format!(
+                            r#"{{"id":1,"method":"mining.set_target","body":{{"target":"0000{}"}}}}"#,
+                            "ff".repeat(30)
+                        ),
+                        format!(
+                            r#"{{"id":2,"method":"mining.notify","body":{{"miningRequestId":7,"header":"{}"}}}}"#,
+                            "00".repeat(208)
+                        ),
+                    ];
+                    for line in lines {
+                        if writeln!(writer, "{}", line).is_err() {
+                            return;
+                        }
+                    }
+                } else if line.contains("\"mining.submit\"") {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+        });
+        (address, submits)
+    }
+
+    fn cli(pool: SocketAddr) -> Cli {
+        Cli {
+            batch_size: 1000,
+            max_submit_rate: "100000/1s".parse().unwrap(),
+            ..test_cli(pool)
+        }
+    }
+
+    #[test]
+    fn test_blocking_miner() {
+        let (pool, submits) = spawn_pool();
+        let miner = Miner::new(cli(pool)).unwrap();
+        assert!(!miner.stats().running);
+        miner.start().unwrap();
+        miner.start().unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(30);
+        let mut events = vec![];
+        while !matches!(events.last(), Some(MinerEvent::ShareFound { .. })) {
+            assert!(Instant::now() < deadline, "no share found: {:?}", events);
+            events.extend(miner.next_event(Duration::from_secs(1)));
+        }
+        assert_eq!(
+            events[0],
+            MinerEvent::Subscribed {
+                graffiti: String::from("zk.work")
+            }
+        );
+        assert!(events.contains(&MinerEvent::NewJob {
+            mining_request_id: 7
+        }));
+        let stats = miner.stats();
+        assert!(stats.running);
+        assert!(stats.miners[0].subscribed);
+        assert!(stats.miners[0].shares_found > 0);
+
+        miner.stop();
+        miner.stop();
+        let stats = miner.stats();
+        assert!(!stats.running);
+        assert!(!stats.miners[0].subscribed);
+        while miner.next_event(Duration::from_secs(1)) != Some(MinerEvent::Stopped) {
+            assert!(Instant::now() < deadline, "no stopped event");
+        }
+        assert!(miner.start().is_err());
+        assert!(submits.load(Ordering::SeqCst) > 0);
+        assert_eq!(miner.next_event(Duration::from_millis(100)), None);
+    }
+
+    #[test]
+    fn test_drop_running_miner() {
+        let (pool, _) = spawn_pool();
+        let miner = Miner::new(cli(pool)).unwrap();
+        miner.start().unwrap();
+        let dropped = Instant::now();
+        drop(miner);
+        assert!(dropped.elapsed() < Duration::from_secs(15));
+    }
+}
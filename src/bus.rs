@@ -0,0 +1,265 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! Where the pool connection and the miner meet: what the pool side tells the miner goes out
+//! as [`PoolEvent`]s, what the miner asks of the pool connection as [`MinerCommand`]s. Each
+//! side owns one end and handles what arrives on it in its own task, in order.
+//!
+//! The two directions wait on each other in a loop: the pool connection hands jobs to the
+//! miner, whose mining loop hands shares to the pool connection. Events are never held up, so
+//! the pool connection does not wait on a busy miner and the loop cannot close. Commands are
+//! bounded, a submit waits for room like any other share that must arrive.
+
+use crate::{
+    monitored_channel, AssembledJob, ChannelCounters, ChannelStats, MonitoredSender, PauseReason,
+    SEND_TIMEOUT,
+};
+use std::{
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+use tokio::{
+    sync::{mpsc, oneshot},
+    time,
+};
+
+/// What the pool side tells the miner.
+#[derive(Debug)]
+pub enum PoolEvent {
+    /// The pool assigned this graffiti in the subscribe.
+    Graffiti(String),
+    /// `mining.set_target`, hex as the pool sent it.
+    Target(String),
+    /// A job, with the target it is to be mined against already set.
+    NewWork {
+        mining_request_id: u32,
+        header: String,
+    },
+    WaitForWork(PauseReason),
+    /// A share was written to the pool connection at the given time.
+    ShareSubmitted {
+        mining_request_id: u32,
+        at: Instant,
+    },
+    /// Asks for the hashes per second over the last minute.
+    Hashrate(oneshot::Sender<f64>),
+    /// Asks for the last target and the job being mined, for a handover.
+    CurrentJob(oneshot::Sender<(Option<String>, Option<AssembledJob>)>),
+}
+
+/// What the miner asks of the pool connection.
+#[derive(Debug)]
+pub enum MinerCommand {
+    Submit {
+        mining_request_id: u32,
+        /// The randomness field as it goes on the wire.
+        randomness: String,
+        /// Milliseconds from the dispatch of the job to the share, sent only to pools that
+        /// agreed to the `solve_time` capability.
+        elapsed_ms: Option<u64>,
+    },
+    /// Answered once every command before it was handled.
+    Flush(oneshot::Sender<()>),
+}
+
+/// Creates both ends of a bus, with room for `capacity` commands whose delivery failures are
+/// recorded into `counters`.
+pub fn bus(capacity: usize, counters: Arc<ChannelCounters>) -> (PoolEnd, MinerEnd) {
+    let (events, event_receiver) = mpsc::unbounded_channel();
+    let (commands, command_receiver) = monitored_channel(capacity, counters);
+    (
+        PoolEnd {
+            events,
+            commands: Mutex::new(Some(command_receiver)),
+        },
+        MinerEnd {
+            commands,
+            events: Mutex::new(Some(event_receiver)),
+        },
+    )
+}
+
+/// The end the stratum client owns.
+#[derive(Debug)]
+pub struct PoolEnd {
+    events: mpsc::UnboundedSender<PoolEvent>,
+    commands: Mutex<Option<mpsc::Receiver<MinerCommand>>>,
+}
+
+impl PoolEnd {
+    /// An end whose events go nowhere and that gets no commands, for a client without a
+    /// miner.
+    pub fn detached() -> Self {
+        bus(1, Default::default()).0
+    }
+
+    /// Never waits. Nobody listening is fine, there is no miner then.
+    pub fn send(&self, event: PoolEvent) {
+        let _ = self.events.send(event);
+    }
+
+    /// Sends the event `ask` makes and waits up to [`SEND_TIMEOUT`] for the answer. `None`
+    /// without a miner, or one too busy to answer.
+    pub async fn ask<T>(&self, ask: impl FnOnce(oneshot::Sender<T>) -> PoolEvent) -> Option<T> {
+        let (reply, answer) = oneshot::channel();
+        self.events.send(ask(reply)).ok()?;
+        time::timeout(SEND_TIMEOUT, answer).await.ok()?.ok()
+    }
+
+    /// The commands, to the first caller only.
+    pub fn take_commands(&self) -> Option<mpsc::Receiver<MinerCommand>> {
+        self.commands.lock().unwrap().take()
+    }
+}
+
+/// The end the miner owns.
+#[derive(Debug)]
+pub struct MinerEnd {
+    commands: MonitoredSender<MinerCommand>,
+    events: Mutex<Option<mpsc::UnboundedReceiver<PoolEvent>>>,
+}
+
+impl MinerEnd {
+    /// Waits up to [`SEND_TIMEOUT`] for room. The command is handed back if it could not be
+    /// sent.
+    pub async fn send(&self, command: MinerCommand) -> Result<(), MinerCommand> {
+        self.commands.send(command, SEND_TIMEOUT).await
+    }
+
+    /// Returns once the commands sent before were handled, e.g. the last shares handed to the
+    /// pool connection before it is closed. Gives up after [`SEND_TIMEOUT`] if nobody handles
+    /// them.
+    pub async fn flush(&self) {
+        let (reply, done) = oneshot::channel();
+        if self.send(MinerCommand::Flush(reply)).await.is_ok() {
+            let _ = time::timeout(SEND_TIMEOUT, done).await;
+        }
+    }
+
+    /// The events, to the first caller only.
+    pub fn take_events(&self) -> Option<mpsc::UnboundedReceiver<PoolEvent>> {
+        self.events.lock().unwrap().take()
+    }
+
+    pub fn stats(&self) -> ChannelStats {
+        self.commands.stats()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const FLOOD: u32 = 20_000;
+
+    /// Both sides flood each other while answering every message from the other side with
+    /// one of their own, as the mining loop answers jobs with shares and the pool connection
+    /// answers shares with submit events. Only a loop of full queues could stop them.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_flood_both_directions() {
+        let (pool, miner) = bus(4, Default::default());
+        let mut commands = pool.take_commands().unwrap();
+        let mut events = miner.take_events().unwrap();
+        assert!(pool.take_commands().is_none());
+        assert!(miner.take_events().is_none());
+        let pool = Arc::new(pool);
+        let miner = Arc::new(miner);
+
+        let pool_side = {
+            let pool = pool.clone();
+            tokio::spawn(async move {
+                let flood = {
+                    let pool = pool.clone();
+                    tokio::spawn(async move {
+                        for mining_request_id in 0..FLOOD {
+                            pool.send(PoolEvent::NewWork {
+                                mining_request_id,
+                                header: String::new(),
+                            });
+                        }
+                    })
+                };
+                let mut received = 0;
+                while received < 2 * FLOOD {
+                    let mining_request_id = match commands.recv().await.unwrap() {
+                        MinerCommand::Submit {
+                            mining_request_id, ..
+                        } => mining_request_id,
+                        command => panic!("unexpected {:?}", command),
+                    };
+                    pool.send(PoolEvent::ShareSubmitted {
+                        mining_request_id,
+                        at: Instant::now(),
+                    });
+                    received += 1;
+                }
+                flood.await.unwrap();
+                received
+            })
+        };
+        let miner_side = {
+            let miner = miner.clone();
+            tokio::spawn(async move {
+                let flood = {
+                    let miner = miner.clone();
+                    tokio::spawn(async move {
+                        for mining_request_id in 0..FLOOD {
+                            let command = MinerCommand::Submit {
+                                mining_request_id,
+                                randomness: String::new(),
+                                elapsed_ms: None,
+                            };
+                            miner.send(command).await.unwrap();
+                        }
+                    })
+                };
+                let (mut jobs, mut submitted) = (0, 0);
+                while jobs < FLOOD || submitted < 2 * FLOOD {
+                    match events.recv().await.unwrap() {
+                        PoolEvent::NewWork {
+                            mining_request_id, ..
+                        } => {
+                            jobs += 1;
+                            let command = MinerCommand::Submit {
+                                mining_request_id,
+                                randomness: String::new(),
+                                elapsed_ms: None,
+                            };
+                            miner.send(command).await.unwrap();
+                        }
+                        PoolEvent::ShareSubmitted { .. } => submitted += 1,
+                        event => panic!("unexpected {:?}", event),
+                    }
+                }
+                flood.await.unwrap();
+                (jobs, submitted)
+            })
+        };
+        let (received, (jobs, submitted)) = time::timeout(Duration::from_secs(30), async {
+            (pool_side.await.unwrap(), miner_side.await.unwrap())
+        })
+        .await
+        .expect("the bus should not deadlock");
+        assert_eq!(received, 2 * FLOOD);
+        assert_eq!((jobs, submitted), (FLOOD, 2 * FLOOD));
+        assert_eq!(miner.stats().timeouts, 0);
+    }
+
+    #[tokio::test]
+    async fn test_ask() {
+        let (pool, miner) = bus(4, Default::default());
+        let mut events = miner.take_events().unwrap();
+        tokio::spawn(async move {
+            while let Some(event) = events.recv().await {
+                if let PoolEvent::Hashrate(reply) = event {
+                    let _ = reply.send(1234.5);
+                }
+            }
+        });
+        assert_eq!(pool.ask(PoolEvent::Hashrate).await, Some(1234.5));
+        // no miner to answer
+        assert_eq!(PoolEnd::detached().ask(PoolEvent::Hashrate).await, None);
+    }
+}
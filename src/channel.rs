@@ -0,0 +1,152 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! Bounded internal channels that count what they could not deliver.
+//!
+//! Every sender picks a policy per message:
+//! * `try_send` for advisory messages that may be dropped when the queue is full, counted as
+//!   overflows (e.g. a pause request, the next job resumes mining anyway);
+//! * `send` for messages that must arrive (jobs, submits, stop), which waits for room up to a
+//!   timeout and hands the message back on failure so the caller can take an error path.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::sync::mpsc::{self, error::SendTimeoutError, error::TrySendError};
+
+pub const CHANNEL_CAPACITY: usize = 1024;
+/// How long a must-deliver message waits for room in a full channel.
+pub const SEND_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Default)]
+pub struct ChannelCounters {
+    overflows: AtomicU64,
+    timeouts: AtomicU64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChannelStats {
+    pub depth: usize,
+    pub capacity: usize,
+    pub overflows: u64,
+    pub timeouts: u64,
+}
+
+#[derive(Debug)]
+pub struct MonitoredSender<T> {
+    sender: mpsc::Sender<T>,
+    capacity: usize,
+    counters: Arc<ChannelCounters>,
+}
+
+/// Creates a bounded channel whose sender records its counters into `counters`, so that they
+/// can outlive a channel that gets replaced (e.g. on every pool connection).
+pub fn monitored_channel<T>(
+    capacity: usize,
+    counters: Arc<ChannelCounters>,
+) -> (MonitoredSender<T>, mpsc::Receiver<T>) {
+    let (sender, receiver) = mpsc::channel(capacity);
+    (
+        MonitoredSender {
+            sender,
+            capacity,
+            counters,
+        },
+        receiver,
+    )
+}
+
+impl<T> MonitoredSender<T> {
+    /// Sends without waiting. Returns false if the message was dropped.
+    pub fn try_send(&self, message: T) -> bool {
+        match self.sender.try_send(message) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                self.counters.overflows.fetch_add(1, Ordering::SeqCst);
+                false
+            }
+            Err(TrySendError::Closed(_)) => false,
+        }
+    }
+
+    /// Waits up to `timeout` for room. The message is handed back if it could not be sent.
+    pub async fn send(&self, message: T, timeout: Duration) -> Result<(), T> {
+        match self.sender.send_timeout(message, timeout).await {
+            Ok(()) => Ok(()),
+            Err(SendTimeoutError::Timeout(message)) => {
+                self.counters.timeouts.fetch_add(1, Ordering::SeqCst);
+                Err(message)
+            }
+            Err(SendTimeoutError::Closed(message)) => Err(message),
+        }
+    }
+
+    /// Number of messages waiting to be received.
+    pub fn depth(&self) -> usize {
+        self.capacity - self.sender.capacity()
+    }
+
+    pub fn stats(&self) -> ChannelStats {
+        ChannelStats {
+            depth: self.depth(),
+            ..self.counters.stats(self.capacity)
+        }
+    }
+}
+
+impl ChannelCounters {
+    pub fn stats(&self, capacity: usize) -> ChannelStats {
+        ChannelStats {
+            depth: 0,
+            capacity,
+            overflows: self.overflows.load(Ordering::Relaxed),
+            timeouts: self.timeouts.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_overflow() {
+        let counters = Arc::new(ChannelCounters::default());
+        // nobody receives until the end, like a stuck consumer
+        let (sender, mut receiver) = monitored_channel(2, counters.clone());
+        assert!(sender.try_send(1));
+        assert!(sender.try_send(2));
+        assert_eq!(sender.depth(), 2);
+        assert!(!sender.try_send(3));
+        assert_eq!(sender.send(4, Duration::from_millis(10)).await, Err(4));
+        assert_eq!(
+            sender.stats(),
+            ChannelStats {
+                depth: 2,
+                capacity: 2,
+                overflows: 1,
+                timeouts: 1,
+            }
+        );
+
+        // once the consumer catches up, must-deliver messages get through
+        assert_eq!(receiver.recv().await, Some(1));
+        assert_eq!(sender.depth(), 1);
+        assert_eq!(sender.send(5, Duration::from_millis(10)).await, Ok(()));
+        assert_eq!(receiver.recv().await, Some(2));
+        assert_eq!(receiver.recv().await, Some(5));
+
+        // counters survive the channel being replaced
+        drop(receiver);
+        assert!(!sender.try_send(6));
+        let (sender, _receiver) = monitored_channel::<u32>(2, counters);
+        assert_eq!(sender.stats().overflows, 1);
+        assert_eq!(sender.stats().timeouts, 1);
+    }
+}
@@ -0,0 +1,494 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! The `--check` self-check: tries everything mining needs without mining, so that a rig
+//! can be vetted before it joins the farm.
+
+#[cfg(feature = "tls")]
+use crate::tls_connect;
+use crate::{
+    hash_header, validate_thread_pool, Cli, Connector, HashrateUnit, HeaderLayout, Meter,
+    Randomness, ResolverCache, StratumClientConfig, StratumSession, ThreadPool,
+};
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use std::{
+    future::Future,
+    panic::{self, AssertUnwindSafe},
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    task, time,
+};
+
+/// The checks in the order they run, the names `--check-skip` takes.
+pub const CHECKS: [&str; 7] = [
+    "address",
+    "resolve",
+    "connect",
+    "tls",
+    "subscribe",
+    "threads",
+    "hash",
+];
+/// Longest wait for each network check.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+const HASH_TEST_DURATION: Duration = Duration::from_secs(2);
+/// The test server's job, hashed with the randomness 0x1234.
+pub(crate) const HASH_TEST_HEADER: &str = "0000000000000000677101000000000000000000000232f50bb970eeab81d7e2053ebaa585d9b7297f7d14c2063a60e8509d3e86a44918c8f318377cbb327f4fc5b602e78784994cf2926f0addd55d1b0d36880100000000f1baa930706f8b9058bc55be1f464b472639a288763a16f7a5713aa761052e43f7bec3000000000000000000000c6072a3898d86f685d4b9bba50e87f750f9773da7ac2cf96663e357c8b30082010000000000007735ccc1666978796f750000000000000000000000000000000000000000000000000000";
+const HASH_TEST_HASH: &str = "d4c5351759e62f047a319c6c223d92025c2b745ddcf1644306c121931ab5c6a2";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckStatus {
+    Pass,
+    /// Works, but not as configured.
+    Warn,
+    Fail,
+    /// Skipped with `--check-skip`, not applicable, or needs a check that did not pass.
+    Skip,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct CheckResult {
+    pub check: &'static str,
+    /// The pool the check ran against, `None` for the local checks.
+    pub target: Option<String>,
+    pub status: CheckStatus,
+    pub detail: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CheckReport {
+    /// No check failed.
+    pub ok: bool,
+    pub checks: Vec<CheckResult>,
+}
+
+/// Collects the results. A check that does not pass skips the ones after it against the
+/// same target, e.g. no subscribe without a connection.
+struct Checks<'a> {
+    skip: &'a [String],
+    target: Option<String>,
+    blocked_by: Option<&'static str>,
+    results: Vec<CheckResult>,
+}
+
+impl<'a> Checks<'a> {
+    fn new(skip: &'a [String]) -> Self {
+        Self {
+            skip,
+            target: None,
+            blocked_by: None,
+            results: Vec::new(),
+        }
+    }
+
+    /// Starts the checks against `target`.
+    fn start(&mut self, target: Option<String>) {
+        self.target = target;
+        self.blocked_by = None;
+    }
+
+    /// Whether `check` is to run, records it as skipped otherwise.
+    fn begin(&mut self, check: &'static str) -> bool {
+        let detail = match self.blocked_by {
+            _ if self.skip.iter().any(|skip| skip == check) => String::from("skipped"),
+            Some(blocked_by) => format!("needs {}", blocked_by),
+            None => return true,
+        };
+        self.blocked_by.get_or_insert(check);
+        self.push(check, CheckStatus::Skip, detail);
+        false
+    }
+
+    fn end(&mut self, check: &'static str, outcome: Result<String>) {
+        match outcome {
+            Ok(detail) => self.push(check, CheckStatus::Pass, detail),
+            Err(error) => {
+                self.blocked_by = Some(check);
+                self.push(check, CheckStatus::Fail, error.to_string());
+            }
+        }
+    }
+
+    fn push(&mut self, check: &'static str, status: CheckStatus, detail: String) {
+        self.results.push(CheckResult {
+            check,
+            target: self.target.clone(),
+            status,
+            detail,
+        });
+    }
+}
+
+/// Runs every check `cli` does not skip.
+pub async fn check_all(cli: &Cli) -> Vec<CheckResult> {
+    let mut checks = Checks::new(&cli.check_skip);
+
+    checks.start(None);
+    if checks.begin("address") {
+        checks.end("address", check_address(&cli.address, cli.pass.is_some()));
+    }
+
+    let pools = match (&cli.split, &cli.pool) {
+        (Some(split), _) => split.0.iter().map(|share| share.pool.clone()).collect(),
+        (None, Some(pool)) => std::iter::once(pool)
+            .chain(&cli.failover_pools)
+            .cloned()
+            .collect(),
+        (None, None) => vec![],
+    };
+    for pool in pools {
+        let config = StratumClientConfig::from_cli(&Cli {
+            pool: Some(pool),
+            split: None,
+            failover_pools: vec![],
+            dump_stratum: None,
+            ..cli.clone()
+        })
+        .expect("the pool is set");
+        check_pool(&mut checks, &config).await;
+    }
+
+    checks.start(None);
+    if checks.begin("threads") {
+        let mut cli = cli.clone();
+        let clamped = cli.clamp_threads(num_cpus::get());
+        match check_threads(&cli) {
+            Ok(detail) if clamped.is_some() => checks.push(
+                "threads",
+                CheckStatus::Warn,
+                format!(
+                    "{}, lowered from --threads {} to the core count",
+                    detail,
+                    clamped.unwrap()
+                ),
+            ),
+            outcome => checks.end("threads", outcome),
+        }
+    }
+
+    checks.start(None);
+    if checks.begin("hash") {
+        let unit = cli.hashrate_unit;
+        let outcome = task::spawn_blocking(move || check_hash(unit))
+            .await
+            .unwrap_or_else(|error| Err(anyhow!("{}", error)));
+        checks.end("hash", outcome);
+    }
+    checks.results
+}
+
+/// A pool that takes a token needs no address.
+fn check_address(address: &str, token: bool) -> Result<String> {
+    if token {
+        return Ok(String::from("none, identified by the --pass token"));
+    }
+    if address.is_empty() {
+        return Err(anyhow!("no address given"));
+    }
+    if !address.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(anyhow!("'{}' is not a hex address", address));
+    }
+    Ok(address.to_string())
+}
+
+async fn check_pool(checks: &mut Checks<'_>, config: &StratumClientConfig) {
+    let pool = &config.pool_address;
+    checks.start(Some(pool.to_string()));
+
+    let mut address = None;
+    if checks.begin("resolve") {
+        let outcome =
+            with_timeout(ResolverCache::new(config.dns_ttl).resolve(pool, Instant::now())).await;
+        address = outcome.as_ref().ok().copied();
+        checks.end("resolve", outcome.map(|address| address.to_string()));
+    }
+
+    let mut tcp_stream = None;
+    if checks.begin("connect") {
+        let started = Instant::now();
+        let connector = Connector::new(config.source_ports);
+        match with_timeout(connector.connect(address.expect("resolved"))).await {
+            Ok(stream) => {
+                tcp_stream = Some(stream);
+                let detail = format!("{} ms", started.elapsed().as_millis());
+                checks.end("connect", Ok(detail));
+            }
+            Err(error) => checks.end("connect", Err(error)),
+        }
+    }
+
+    if !config.tls {
+        checks.push("tls", CheckStatus::Skip, String::from("not enabled"));
+        if checks.begin("subscribe") {
+            let outcome = check_subscribe(config, tcp_stream.expect("connected")).await;
+            checks.end("subscribe", outcome);
+        }
+        return;
+    }
+    #[cfg(feature = "tls")]
+    {
+        let mut tls_stream = None;
+        if checks.begin("tls") {
+            match with_timeout(tls_connect(pool, tcp_stream.expect("connected"))).await {
+                Ok(stream) => {
+                    tls_stream = Some(stream);
+                    let detail = String::from("handshake done, the certificate is not checked");
+                    checks.end("tls", Ok(detail));
+                }
+                Err(error) => checks.end("tls", Err(error)),
+            }
+        }
+        if checks.begin("subscribe") {
+            let outcome = check_subscribe(config, tls_stream.expect("handshake done")).await;
+            checks.end("subscribe", outcome);
+        }
+    }
+    #[cfg(not(feature = "tls"))]
+    {
+        if checks.begin("tls") {
+            checks.end("tls", Err(anyhow!(crate::TLS_UNSUPPORTED)));
+        }
+        // listed as needing tls
+        checks.begin("subscribe");
+    }
+}
+
+/// Subscribes like the miner would and hangs up on the ack.
+async fn check_subscribe<T: AsyncRead + AsyncWrite>(
+    config: &StratumClientConfig,
+    stream: T,
+) -> Result<String> {
+    let mut session = StratumSession::new(stream, config.lenient_decode);
+    let subscribed = with_timeout(async {
+        session
+            .subscribe(0, config.subscribe_body())
+            .await
+            .map_err(|error| anyhow!("{}", error))
+    })
+    .await?;
+    let capabilities = match subscribed.capabilities {
+        Some(capabilities) => format!(" capabilities({})", capabilities.join(", ")),
+        None => String::new(),
+    };
+    Ok(format!(
+        "client id({}) graffiti({}){}",
+        subscribed.clientId, subscribed.graffiti, capabilities
+    ))
+}
+
+fn check_threads(cli: &Cli) -> Result<String> {
+    let batch_size = cli.effective_batch_size()?;
+    validate_thread_pool(cli.threads_count, batch_size)?;
+    panic::catch_unwind(AssertUnwindSafe(|| {
+        let thread_pool = ThreadPool::new(cli.threads_count, batch_size);
+        thread_pool.stop();
+    }))
+    .map_err(|_| anyhow!("the thread pool could not be created"))?;
+    Ok(format!(
+        "{} threads, batch size {}",
+        cli.threads_count, batch_size
+    ))
+}
+
+/// Hashes the test vector, then hashes on one thread for a while to measure the rate.
+fn check_hash(unit: HashrateUnit) -> Result<String> {
+    let layout = HeaderLayout::IRONFISH;
+    let mut header = hex::decode(HASH_TEST_HEADER)?;
+    Randomness::from_value(0x1234, &layout).apply_to_header(&mut header, &layout)?;
+    let hash = hex::encode(hash_header(&header));
+    if hash != HASH_TEST_HASH {
+        return Err(anyhow!(
+            "the test vector hashes to {}, expected {}",
+            hash,
+            HASH_TEST_HASH
+        ));
+    }
+    let started = Instant::now();
+    let mut hashes = 0u64;
+    while started.elapsed() < HASH_TEST_DURATION {
+        for _ in 0..1024 {
+            Randomness::from_value(hashes, &layout).apply_to_header(&mut header, &layout)?;
+            std::hint::black_box(hash_header(&header));
+            hashes += 1;
+        }
+    }
+    Ok(format!(
+        "{} on one thread",
+        Meter::format_as(hashes as f64 / started.elapsed().as_secs_f64(), unit)
+    ))
+}
+
+async fn with_timeout<T>(future: impl Future<Output = Result<T>>) -> Result<T> {
+    time::timeout(CHECK_TIMEOUT, future)
+        .await
+        .unwrap_or_else(|_| Err(anyhow!("timed out after {}s", CHECK_TIMEOUT.as_secs())))
+}
+
+/// The results as a table, one check per line.
+pub fn format_table(results: &[CheckResult]) -> String {
+    let width = results
+        .iter()
+        .filter_map(|result| result.target.as_ref().map(String::len))
+        .max()
+        .unwrap_or_default()
+        .max("TARGET".len());
+    let mut table = format!(
+        "{:<9} {:<width$} {:<6} DETAIL\n",
+        "CHECK",
+        "TARGET",
+        "STATUS",
+        width = width
+    );
+    for result in results {
+        let status = serde_json::to_value(result.status).unwrap_or_default();
+        table.push_str(&format!(
+            "{:<9} {:<width$} {:<6} {}\n",
+            result.check,
+            result.target.as_deref().unwrap_or("-"),
+            status.as_str().unwrap_or_default(),
+            result.detail,
+            width = width
+        ));
+    }
+    table
+}
+
+/// Runs `--check` and returns the exit code.
+pub async fn run(cli: &Cli) -> i32 {
+    let checks = check_all(cli).await;
+    let report = CheckReport {
+        ok: checks
+            .iter()
+            .all(|result| result.status != CheckStatus::Fail),
+        checks,
+    };
+    if cli.json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&report).unwrap_or_default()
+        );
+    } else {
+        print!("{}", format_table(&report.checks));
+    }
+    if report.ok {
+        0
+    } else {
+        1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{subscribed_message, MockPoolListener, KNOWN_HASH, KNOWN_HEADER};
+    use clap::Parser;
+    use tokio::net::TcpListener;
+
+    /// A pool that acks one subscribe.
+    async fn mock_pool() -> String {
+        let listener = MockPoolListener::bind().await;
+        let address = listener.address().to_string();
+        task::spawn(async move {
+            let mut pool = listener.accept().await;
+            pool.expect_subscribe().await;
+            pool.send(subscribed_message(7, "zk.work", None)).await;
+        });
+        address
+    }
+
+    fn statuses(results: &[CheckResult]) -> Vec<(&'static str, CheckStatus)> {
+        results
+            .iter()
+            .map(|result| (result.check, result.status))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_check_pools() {
+        let pool = mock_pool().await;
+        // nothing listens on a port just given up
+        let closed = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let split = format!("{}=50,{}=50", pool, closed);
+        let mut cli = Cli::parse_from([
+            "zkwork_ironminer",
+            "--split",
+            &split,
+            "--address",
+            "not hex",
+            "--check",
+            "--check-skip",
+            "threads,hash",
+        ]);
+        // the mock pool speaks plain TCP
+        cli.tls = false;
+        let results = check_all(&cli).await;
+        use CheckStatus::*;
+        assert_eq!(
+            statuses(&results),
+            vec![
+                ("address", Fail),
+                ("resolve", Pass),
+                ("connect", Pass),
+                ("tls", Skip),
+                ("subscribe", Pass),
+                ("resolve", Pass),
+                ("connect", Fail),
+                ("tls", Skip),
+                ("subscribe", Skip),
+                ("threads", Skip),
+                ("hash", Skip),
+            ]
+        );
+        assert_eq!(results[4].target, Some(pool));
+        assert_eq!(results[4].detail, "client id(7) graffiti(zk.work)");
+        assert_eq!(results[8].detail, "needs connect");
+        assert_eq!(results[9].detail, "skipped");
+        let table = format_table(&results);
+        assert_eq!(table.lines().count(), results.len() + 1);
+        assert!(table.lines().nth(5).unwrap().contains("subscribe"));
+    }
+
+    #[tokio::test]
+    async fn test_check_local() {
+        // the hash check runs the vector the tests check
+        assert_eq!(HASH_TEST_HEADER, KNOWN_HEADER);
+        assert_eq!(HASH_TEST_HASH, KNOWN_HASH);
+        let cli = Cli::parse_from([
+            "zkwork_ironminer",
+            "--pool",
+            "127.0.0.1:8181",
+            "--address",
+            "91f65bdad677058f",
+            "--threads",
+            "1",
+            "--check",
+            "--check-skip",
+            "resolve",
+        ]);
+        let results = check_all(&cli).await;
+        use CheckStatus::*;
+        assert_eq!(
+            statuses(&results),
+            vec![
+                ("address", Pass),
+                ("resolve", Skip),
+                ("connect", Skip),
+                ("tls", Skip),
+                ("subscribe", Skip),
+                ("threads", Pass),
+                ("hash", Pass),
+            ]
+        );
+        assert_eq!(results[5].detail, "1 threads, batch size 10000");
+        assert!(results[6].detail.ends_with("on one thread"));
+    }
+}
@@ -0,0 +1,365 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! Time as the timers of the miner see it. [`SystemClock`] is tokio's clock, [`ManualClock`]
+//! only moves when told to, so that backoffs, timeouts and meter ticks can be tested without
+//! waiting for them. Types that keep time take a clock in their constructor, the real one by
+//! default. The wall-clock time of timestamps is read from the clock too.
+
+use futures::future::BoxFuture;
+use std::{
+    fmt,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::{sync::oneshot, time};
+
+/// A clock shared by the parts of a miner.
+pub type SharedClock = Arc<dyn Clock>;
+
+pub trait Clock: fmt::Debug + Send + Sync {
+    fn now(&self) -> Instant;
+
+    /// The wall-clock time, for timestamps only: it may go backwards.
+    fn system_time(&self) -> SystemTime;
+
+    /// Unix seconds, 0 for a time before 1970.
+    fn unix_timestamp(&self) -> u64 {
+        self.system_time()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or_default()
+    }
+
+    /// Unix milliseconds, 0 for a time before 1970.
+    fn unix_timestamp_millis(&self) -> u64 {
+        self.system_time()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_millis() as u64)
+            .unwrap_or_default()
+    }
+
+    /// Resolves once `duration` has passed on this clock.
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()>;
+
+    /// Resolves at `deadline` on this clock, right away if it has passed.
+    fn sleep_until(&self, deadline: Instant) -> BoxFuture<'static, ()> {
+        self.sleep(deadline.saturating_duration_since(self.now()))
+    }
+
+    /// Ticks every `period`, the first tick right away.
+    fn interval(&self, period: Duration) -> Interval<Self>
+    where
+        Self: Clone + Sized,
+    {
+        self.interval_at(self.now(), period)
+    }
+
+    /// Ticks every `period` from `start` on.
+    fn interval_at(&self, start: Instant, period: Duration) -> Interval<Self>
+    where
+        Self: Clone + Sized,
+    {
+        Interval {
+            clock: self.clone(),
+            period,
+            next: start,
+        }
+    }
+
+    /// `future`, unless `duration` passes on this clock first.
+    fn timeout<F>(
+        &self,
+        duration: Duration,
+        future: F,
+    ) -> impl Future<Output = Result<F::Output, TimedOut>> + Send
+    where
+        Self: Sized,
+        F: Future + Send,
+    {
+        let sleep = self.sleep(duration);
+        async move {
+            tokio::select! {
+                output = future => Ok(output),
+                _ = sleep => Err(TimedOut),
+            }
+        }
+    }
+}
+
+impl<C: Clock + ?Sized> Clock for Arc<C> {
+    fn now(&self) -> Instant {
+        (**self).now()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        (**self).system_time()
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        (**self).sleep(duration)
+    }
+}
+
+/// The clock of tokio's runtime.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl SystemClock {
+    pub fn shared() -> SharedClock {
+        Arc::new(SystemClock)
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        time::Instant::now().into_std()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        Box::pin(time::sleep(duration))
+    }
+}
+
+/// [`Clock::timeout`] ran out.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimedOut;
+
+impl fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "timed out")
+    }
+}
+
+impl std::error::Error for TimedOut {}
+
+/// Ticks of a [`Clock`]. A tick that comes late is not caught up on, the next one is a period
+/// after it.
+#[derive(Debug)]
+pub struct Interval<C> {
+    clock: C,
+    period: Duration,
+    next: Instant,
+}
+
+impl<C: Clock> Interval<C> {
+    /// Waits for the next tick and returns when it was due. Dropping the wait loses no tick.
+    pub async fn tick(&mut self) -> Instant {
+        let due = self.next;
+        self.clock.sleep_until(due).await;
+        let now = self.clock.now();
+        self.next = if now.saturating_duration_since(due) >= self.period {
+            now + self.period
+        } else {
+            due + self.period
+        };
+        due
+    }
+
+    /// The next tick a whole period from now.
+    pub fn reset(&mut self) {
+        self.next = self.clock.now() + self.period;
+    }
+
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+}
+
+#[derive(Debug)]
+struct ManualTime {
+    now: Instant,
+    /// The wall-clock time at `now`.
+    system_time: SystemTime,
+    /// The sleeps not over yet, with their deadlines.
+    sleepers: Vec<(Instant, oneshot::Sender<()>)>,
+    /// Every sleep asked for.
+    sleeps: Vec<Duration>,
+    /// Whether a sleep moves the clock to its end right away.
+    auto_advance: bool,
+}
+
+/// A clock that only moves on [`ManualClock::advance`], or in auto-advance mode by as much as
+/// each sleep asks for. Clones share the time.
+#[derive(Clone, Debug)]
+pub struct ManualClock(Arc<Mutex<ManualTime>>);
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        Self(Arc::new(Mutex::new(ManualTime {
+            now: Instant::now(),
+            system_time: SystemTime::now(),
+            sleepers: Vec::new(),
+            sleeps: Vec::new(),
+            auto_advance: false,
+        })))
+    }
+
+    /// A clock whose sleeps end at once, moving the clock to their end.
+    pub fn auto_advance() -> Self {
+        let clock = Self::new();
+        clock.0.lock().unwrap().auto_advance = true;
+        clock
+    }
+
+    /// Moves the clock and ends the sleeps that are over by then.
+    pub fn advance(&self, duration: Duration) {
+        let mut time = self.0.lock().unwrap();
+        time.now += duration;
+        time.system_time += duration;
+        let now = time.now;
+        let (over, sleeping) = std::mem::take(&mut time.sleepers)
+            .into_iter()
+            .partition(|(deadline, _)| *deadline <= now);
+        time.sleepers = sleeping;
+        drop(time);
+        for (_, sleeper) in over {
+            let _ = sleeper.send(());
+        }
+    }
+
+    /// Sets the wall-clock time, which may go backwards unlike [`Clock::now`].
+    pub fn set_system_time(&self, system_time: SystemTime) {
+        self.0.lock().unwrap().system_time = system_time;
+    }
+
+    /// Sleeps not over yet.
+    pub fn sleeping(&self) -> usize {
+        self.0.lock().unwrap().sleepers.len()
+    }
+
+    /// Every sleep asked for so far, in order.
+    pub fn sleeps(&self) -> Vec<Duration> {
+        self.0.lock().unwrap().sleeps.clone()
+    }
+
+    /// Waits until at least `count` sleeps are pending, e.g. before advancing past them.
+    pub async fn sleepers(&self, count: usize) {
+        while self.sleeping() < count {
+            tokio::task::yield_now().await;
+        }
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.0.lock().unwrap().now
+    }
+
+    fn system_time(&self) -> SystemTime {
+        self.0.lock().unwrap().system_time
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        let mut time = self.0.lock().unwrap();
+        time.sleeps.push(duration);
+        if duration.is_zero() {
+            return Box::pin(async {});
+        }
+        if time.auto_advance {
+            time.now += duration;
+            time.system_time += duration;
+            return Box::pin(async {});
+        }
+        let (sleeper, over) = oneshot::channel();
+        let deadline = time.now + duration;
+        time.sleepers.push((deadline, sleeper));
+        Box::pin(async move {
+            // a dropped clock ends its sleeps
+            let _ = over.await;
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+
+    #[tokio::test]
+    async fn test_manual_clock() {
+        let clock = ManualClock::new();
+        let start = clock.now();
+        let mut short = clock.sleep(Duration::from_secs(1));
+        let mut long = clock.sleep(Duration::from_secs(3));
+        assert!((&mut short).now_or_never().is_none());
+        assert_eq!(clock.sleeping(), 2);
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(clock.now() - start, Duration::from_secs(2));
+        assert!((&mut short).now_or_never().is_some());
+        assert!((&mut long).now_or_never().is_none());
+        clock.advance(Duration::from_secs(1));
+        assert!(long.now_or_never().is_some());
+        assert_eq!(
+            clock.sleeps(),
+            [Duration::from_secs(1), Duration::from_secs(3)]
+        );
+
+        // a future that does not finish in time
+        let timeout = clock.timeout(Duration::from_secs(5), futures::future::pending::<()>());
+        let (result, _) = tokio::join!(timeout, async {
+            clock.sleepers(1).await;
+            clock.advance(Duration::from_secs(5));
+        });
+        assert_eq!(result, Err(TimedOut));
+        assert_eq!(
+            clock.timeout(Duration::from_secs(5), async { 7 }).await,
+            Ok(7)
+        );
+
+        let auto = ManualClock::auto_advance();
+        let start = auto.now();
+        auto.sleep(Duration::from_secs(60)).await;
+        assert_eq!(auto.now() - start, Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_manual_system_time() {
+        let clock = ManualClock::new();
+        clock.set_system_time(UNIX_EPOCH + Duration::from_millis(1_665_000_000_500));
+        assert_eq!(clock.unix_timestamp(), 1_665_000_000);
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(clock.unix_timestamp(), 1_665_000_002);
+        assert_eq!(clock.unix_timestamp_millis(), 1_665_000_002_500);
+        // the wall clock goes backwards, the timers don't
+        let now = clock.now();
+        clock.set_system_time(UNIX_EPOCH - Duration::from_secs(1));
+        assert_eq!(clock.unix_timestamp(), 0);
+        assert_eq!(clock.now(), now);
+    }
+
+    #[tokio::test]
+    async fn test_interval() {
+        let clock = ManualClock::auto_advance();
+        let start = clock.now();
+        let mut interval = clock.interval(Duration::from_secs(1));
+        assert_eq!(interval.tick().await, start);
+        assert_eq!(interval.tick().await, start + Duration::from_secs(1));
+        assert_eq!(interval.tick().await, start + Duration::from_secs(2));
+        // a late tick is not caught up on
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(interval.tick().await, start + Duration::from_secs(3));
+        assert_eq!(interval.tick().await, clock.now());
+        interval.reset();
+        let reset = clock.now();
+        assert_eq!(interval.tick().await, reset + Duration::from_secs(1));
+
+        // shared as the miner shares it
+        let shared: SharedClock = Arc::new(clock.clone());
+        let mut interval = shared.interval(Duration::from_secs(2));
+        let first = interval.tick().await;
+        assert_eq!(interval.tick().await, first + Duration::from_secs(2));
+    }
+}
@@ -0,0 +1,218 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! Expected-earnings estimates from a hashrate and the network difficulty.
+//!
+//! On Iron Fish a block needs on average `difficulty` hashes, so the network hashrate is
+//! `difficulty / block time` and a miner finds `hashrate * seconds / difficulty` blocks.
+//! Everything here is an estimate: luck, pool fees and reward changes are ignored.
+
+use crate::{supervise, Cli, RestartPolicy};
+use anyhow::{anyhow, Result};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::time;
+
+/// Target time between two blocks, in seconds.
+pub const BLOCK_TIME_SECONDS: f64 = 60.0;
+/// 1 IRON is 10^8 ORE.
+pub const ORE_PER_IRON: f64 = 100_000_000.0;
+/// Miner reward of a block, in ORE.
+pub const BLOCK_REWARD_ORE: f64 = 20.0 * ORE_PER_IRON;
+const SECONDS_PER_DAY: f64 = 86_400.0;
+const DIFFICULTY_REFRESH_INTERVAL: Duration = Duration::from_secs(600);
+const DIFFICULTY_FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct EarningsEstimate {
+    pub hashrate: f64,
+    pub network_difficulty: f64,
+    pub network_hashrate: f64,
+    pub share_of_network: f64,
+    pub blocks_per_day: f64,
+    pub ore_per_day: f64,
+}
+
+pub fn network_hashrate(difficulty: f64) -> f64 {
+    difficulty / BLOCK_TIME_SECONDS
+}
+
+pub fn share_of_network(hashrate: f64, difficulty: f64) -> f64 {
+    hashrate / network_hashrate(difficulty)
+}
+
+pub fn blocks_per_day(hashrate: f64, difficulty: f64) -> f64 {
+    hashrate * SECONDS_PER_DAY / difficulty
+}
+
+pub fn estimate(hashrate: f64, difficulty: f64) -> Option<EarningsEstimate> {
+    if !(difficulty.is_finite() && difficulty > 0.0 && hashrate.is_finite() && hashrate >= 0.0) {
+        return None;
+    }
+    let blocks_per_day = blocks_per_day(hashrate, difficulty);
+    Some(EarningsEstimate {
+        hashrate,
+        network_difficulty: difficulty,
+        network_hashrate: network_hashrate(difficulty),
+        share_of_network: share_of_network(hashrate, difficulty),
+        blocks_per_day,
+        ore_per_day: blocks_per_day * BLOCK_REWARD_ORE,
+    })
+}
+
+impl EarningsEstimate {
+    pub fn format(&self) -> String {
+        format!(
+            "Estimated earnings (estimate only): {:.4} IRON/day ({:.0} ORE/day, {:.4} blocks/day, {:.6}% of network)",
+            self.ore_per_day / ORE_PER_IRON,
+            self.ore_per_day,
+            self.blocks_per_day,
+            self.share_of_network * 100.0
+        )
+    }
+}
+
+/// The latest known network difficulty, either fixed from the command line or refreshed
+/// from a user supplied JSON url. Fetch failures keep the last known value.
+#[derive(Debug, Default)]
+pub struct NetworkDifficulty {
+    url: Option<String>,
+    // f64 bits, 0 means unknown
+    value: AtomicU64,
+}
+
+impl NetworkDifficulty {
+    pub fn new(cli: &Cli) -> Arc<Self> {
+        let feed = Self {
+            url: cli.difficulty_url.clone(),
+            value: Default::default(),
+        };
+        if let Some(difficulty) = cli.network_difficulty {
+            feed.set(difficulty);
+        }
+        Arc::new(feed)
+    }
+
+    pub fn get(&self) -> Option<f64> {
+        match self.value.load(Ordering::Relaxed) {
+            0 => None,
+            bits => Some(f64::from_bits(bits)),
+        }
+    }
+
+    pub fn set(&self, difficulty: f64) {
+        if difficulty.is_finite() && difficulty > 0.0 {
+            self.value.store(difficulty.to_bits(), Ordering::SeqCst);
+        }
+    }
+
+    pub async fn start(feed: Arc<Self>) {
+        let url = match feed.url.clone() {
+            Some(url) => url,
+            None => return,
+        };
+        supervise(
+            "network difficulty feed",
+            RestartPolicy::default(),
+            move || Self::poll(feed.clone(), url.clone()),
+        );
+    }
+
+    async fn poll(feed: Arc<Self>, url: String) {
+        let mut interval = time::interval(DIFFICULTY_REFRESH_INTERVAL);
+        let mut failing = false;
+        loop {
+            interval.tick().await;
+            match Self::fetch(&url).await {
+                Ok(difficulty) => {
+                    debug!("network difficulty {} from {}", difficulty, url);
+                    feed.set(difficulty);
+                    failing = false;
+                }
+                Err(error) => {
+                    if !failing {
+                        warn!("failed to fetch network difficulty from {}: {}", url, error);
+                    }
+                    failing = true;
+                }
+            }
+        }
+    }
+
+    async fn fetch(url: &str) -> Result<f64> {
+        let client = reqwest::Client::builder()
+            .timeout(DIFFICULTY_FETCH_TIMEOUT)
+            .build()?;
+        let body: serde_json::Value = client
+            .get(url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Self::parse(&body)
+    }
+
+    /// Accepts a bare number, a numeric string, or an object with a `difficulty` field
+    /// holding either of those.
+    pub fn parse(body: &serde_json::Value) -> Result<f64> {
+        let value = match body.get("difficulty") {
+            Some(value) => value,
+            None => body,
+        };
+        let difficulty = match value {
+            serde_json::Value::Number(number) => number.as_f64(),
+            serde_json::Value::String(string) => string.trim().parse().ok(),
+            _ => None,
+        }
+        .ok_or_else(|| anyhow!("no difficulty in response"))?;
+        if difficulty.is_finite() && difficulty > 0.0 {
+            Ok(difficulty)
+        } else {
+            Err(anyhow!("invalid difficulty {}", difficulty))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_estimate() {
+        // 1 MH/s against a network of 1 GH/s
+        let estimate = estimate(1_000_000.0, 60_000_000_000.0).unwrap();
+        assert_eq!(estimate.network_hashrate, 1_000_000_000.0);
+        assert_eq!(estimate.share_of_network, 0.001);
+        assert!((estimate.blocks_per_day - 1.44).abs() < 1e-9);
+        assert!((estimate.ore_per_day - 2_880_000_000.0).abs() < 1e-3);
+
+        let estimate = super::estimate(0.0, 1.0).unwrap();
+        assert_eq!(estimate.ore_per_day, 0.0);
+        assert!(super::estimate(1.0, 0.0).is_none());
+        assert!(super::estimate(1.0, f64::NAN).is_none());
+    }
+
+    #[test]
+    fn test_parse_difficulty() {
+        let parse = |s: &str| NetworkDifficulty::parse(&serde_json::from_str(s).unwrap());
+        assert_eq!(parse("123.5").unwrap(), 123.5);
+        assert_eq!(parse("\"4000\"").unwrap(), 4000.0);
+        assert_eq!(parse("{\"difficulty\": 42}").unwrap(), 42.0);
+        assert_eq!(
+            parse("{\"difficulty\": \"42\", \"sequence\": 1}").unwrap(),
+            42.0
+        );
+        assert!(parse("{\"sequence\": 1}").is_err());
+        assert!(parse("-1").is_err());
+        assert!(parse("\"abc\"").is_err());
+    }
+}
@@ -0,0 +1,311 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! The summary logged last when the process exits, and written to `--summary-file` for
+//! scripts. It is assembled from the stats, so it agrees with what the stats api served last.
+
+use crate::{
+    HashrateUnit, IntervalVerdict, LifetimeCounters, Meter, MinerStats, PoolStats,
+    CRITICAL_PANIC_EXIT_CODE,
+};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::{fmt, fs, path::Path};
+
+/// Why the process ends.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExitReason {
+    /// Ctrl-C.
+    StoppedByUser,
+    /// `--max-runtime` elapsed.
+    MaxRuntime,
+    /// A task mining depends on panicked.
+    CriticalFailure,
+    /// An upgrade in place failed.
+    UpgradeFailed,
+    /// A panic was reported, the process may not get to shut down cleanly.
+    Panic,
+}
+
+impl ExitReason {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::StoppedByUser | Self::MaxRuntime => 0,
+            Self::CriticalFailure | Self::Panic => CRITICAL_PANIC_EXIT_CODE,
+            Self::UpgradeFailed => 1,
+        }
+    }
+}
+
+impl fmt::Display for ExitReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::StoppedByUser => f.write_str("stopped by the user"),
+            Self::MaxRuntime => f.write_str("--max-runtime reached"),
+            Self::CriticalFailure => f.write_str("a task mining depends on panicked"),
+            Self::UpgradeFailed => f.write_str("the upgrade in place failed"),
+            Self::Panic => f.write_str("panic"),
+        }
+    }
+}
+
+/// The totals of one miner since the process started, and over every run with `--data-dir`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FinalReport {
+    pub label: String,
+    pub pool: String,
+    pub exit_reason: ExitReason,
+    pub runtime_secs: u64,
+    /// Hashes per second since the start.
+    pub average_hashrate: f64,
+    pub shares_found: u64,
+    pub shares_submitted: u64,
+    /// Found, then dropped because they no longer met the pool's target.
+    pub shares_stale: u64,
+    /// Handed in by the mining threads while stopping, and submitted or discarded.
+    #[serde(default)]
+    pub shares_submitted_on_stop: u64,
+    #[serde(default)]
+    pub shares_discarded_on_stop: u64,
+    /// The summed difficulty of the shares submitted, what a pool with vardiff pays for.
+    #[serde(default)]
+    pub share_value: f64,
+    /// Pool sessions after the first one.
+    pub reconnects: u64,
+    pub best_share_difficulty: Option<f64>,
+    /// Whether the share intervals of the last pool session look like a random search.
+    #[serde(default)]
+    pub share_intervals: IntervalVerdict,
+    #[serde(default)]
+    pub share_interval_cv: Option<f64>,
+    /// How each pool of the list did, only with `--failover-pool`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pools: Vec<PoolStats>,
+    /// Over every run, this one included, only present with `--data-dir`.
+    #[serde(default)]
+    pub lifetime: Option<LifetimeCounters>,
+}
+
+impl FinalReport {
+    pub fn new(stats: &MinerStats, exit_reason: ExitReason) -> Self {
+        Self {
+            label: stats.label.clone(),
+            pool: stats.pool.clone(),
+            exit_reason,
+            runtime_secs: stats.runtime_secs,
+            average_hashrate: stats.rate_avg,
+            shares_found: stats.shares_found,
+            shares_submitted: stats.shares_submitted,
+            shares_stale: stats.shares_below_target,
+            shares_submitted_on_stop: stats.shares_submitted_on_stop,
+            shares_discarded_on_stop: stats.shares_discarded_on_stop,
+            share_value: stats.share_value.total_value,
+            reconnects: stats.sessions_started.saturating_sub(1),
+            best_share_difficulty: stats.best_share_difficulty,
+            share_intervals: stats.share_intervals.verdict,
+            share_interval_cv: stats.share_intervals.cv,
+            pools: if stats.pools.len() > 1 {
+                stats.pools.clone()
+            } else {
+                vec![]
+            },
+            lifetime: stats
+                .lifetime
+                .as_ref()
+                .map(|lifetime| lifetime.total.clone()),
+        }
+    }
+
+    /// One line per total, hashrates in `unit`.
+    pub fn format(&self, unit: HashrateUnit) -> String {
+        let prefix = if self.label.is_empty() {
+            String::new()
+        } else {
+            format!("[{}] ", self.label)
+        };
+        let duration =
+            |secs: u64| format!("{}h {:02}m {:02}s", secs / 3600, secs / 60 % 60, secs % 60);
+        let mut lines = vec![
+            format!("{}Final report for pool({})", prefix, self.pool),
+            format!("  runtime:          {}", duration(self.runtime_secs)),
+            format!(
+                "  average hashrate: {}",
+                Meter::format_as(self.average_hashrate, unit)
+            ),
+            format!(
+                "  shares:           {} found, {} submitted, {} stale",
+                self.shares_found, self.shares_submitted, self.shares_stale
+            ),
+            format!(
+                "  on stop:          {} submitted, {} discarded",
+                self.shares_submitted_on_stop, self.shares_discarded_on_stop
+            ),
+            format!("  share value:      {:.2}", self.share_value),
+            format!("  reconnects:       {}", self.reconnects),
+            format!(
+                "  best share:       {}",
+                match self.best_share_difficulty {
+                    Some(difficulty) => format!("difficulty {:.0}", difficulty),
+                    None => String::from("none"),
+                }
+            ),
+            format!(
+                "  share intervals:  {}{}",
+                self.share_intervals,
+                match self.share_interval_cv {
+                    Some(cv) => format!(" (cv {:.2})", cv),
+                    None => String::new(),
+                }
+            ),
+        ];
+        if let Some(lifetime) = &self.lifetime {
+            lines.push(format!(
+                "  lifetime:         {}, {} hashes, {} reconnects",
+                duration(lifetime.runtime_secs),
+                lifetime.hashes,
+                lifetime.reconnects
+            ));
+            lines.push(format!(
+                "  lifetime shares:  {} found, {} submitted, {} stale",
+                lifetime.shares_found, lifetime.shares_submitted, lifetime.shares_stale
+            ));
+        }
+        if !self.pools.is_empty() {
+            lines.push(String::from("  pools:"));
+            for line in PoolStats::format_table(&self.pools).lines() {
+                lines.push(format!("  {}", line));
+            }
+        }
+        lines.push(format!("  exit reason:      {}", self.exit_reason));
+        lines.join("\n")
+    }
+}
+
+/// The body of `--summary-file`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FinalSummary {
+    pub exit_reason: ExitReason,
+    pub exit_code: i32,
+    pub miners: Vec<FinalReport>,
+}
+
+impl FinalSummary {
+    pub fn new(stats: &[MinerStats], exit_reason: ExitReason) -> Self {
+        Self {
+            exit_reason,
+            exit_code: exit_reason.exit_code(),
+            miners: stats
+                .iter()
+                .map(|stats| FinalReport::new(stats, exit_reason))
+                .collect(),
+        }
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        fs::write(path, serde_json::to_vec_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_final_report() {
+        let report = FinalReport {
+            label: String::from("pool.example.com:8181"),
+            pool: String::from("pool.example.com:8181"),
+            exit_reason: ExitReason::StoppedByUser,
+            runtime_secs: 3661,
+            average_hashrate: 1_500_000.0,
+            shares_found: 12,
+            shares_submitted: 11,
+            shares_stale: 1,
+            shares_submitted_on_stop: 3,
+            shares_discarded_on_stop: 0,
+            share_value: 27.5,
+            reconnects: 2,
+            best_share_difficulty: Some(123456.7),
+            share_intervals: IntervalVerdict::Random,
+            share_interval_cv: Some(0.97),
+            pools: vec![],
+            lifetime: None,
+        };
+        let text = report.format(HashrateUnit::Auto);
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 10);
+        assert_eq!(
+            lines[0],
+            "[pool.example.com:8181] Final report for pool(pool.example.com:8181)"
+        );
+        assert_eq!(lines[1], "  runtime:          1h 01m 01s");
+        assert_eq!(lines[4], "  on stop:          3 submitted, 0 discarded");
+        assert_eq!(lines[5], "  share value:      27.50");
+        assert_eq!(lines[7], "  best share:       difficulty 123457");
+        assert_eq!(
+            lines[8],
+            "  share intervals:  random, as expected (cv 0.97)"
+        );
+        assert_eq!(lines[9], "  exit reason:      stopped by the user");
+
+        // the totals over every run with --data-dir
+        let report = FinalReport {
+            lifetime: Some(LifetimeCounters {
+                runtime_secs: 90061,
+                hashes: 120_000_000_000,
+                shares_found: 1200,
+                shares_submitted: 1190,
+                shares_stale: 10,
+                reconnects: 7,
+                ..Default::default()
+            }),
+            ..report
+        };
+        let text = report.format(HashrateUnit::Auto);
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 12);
+        assert_eq!(
+            lines[9],
+            "  lifetime:         25h 01m 01s, 120000000000 hashes, 7 reconnects"
+        );
+        assert_eq!(
+            lines[10],
+            "  lifetime shares:  1200 found, 1190 submitted, 10 stale"
+        );
+        assert_eq!(lines[11], "  exit reason:      stopped by the user");
+
+        // with --failover-pool, a table of the pools
+        let pool = |index: usize, address: &str| PoolStats {
+            index,
+            address: String::from(address),
+            ..Default::default()
+        };
+        let report = FinalReport {
+            pools: vec![pool(0, "127.0.0.1:8181"), pool(1, "127.0.0.1:8182")],
+            lifetime: None,
+            ..report
+        };
+        let text = report.format(HashrateUnit::Auto);
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 14);
+        assert_eq!(lines[9], "  pools:");
+        assert!(lines[10].trim_start().starts_with("pool "));
+        assert!(lines[12].trim_start().starts_with("#1 "));
+        assert!(lines[12].contains("127.0.0.1:8182"));
+        assert_eq!(lines[13], "  exit reason:      stopped by the user");
+
+        assert_eq!(ExitReason::StoppedByUser.exit_code(), 0);
+        assert_eq!(ExitReason::MaxRuntime.exit_code(), 0);
+        assert_eq!(
+            ExitReason::CriticalFailure.exit_code(),
+            CRITICAL_PANIC_EXIT_CODE
+        );
+        assert_eq!(
+            serde_json::to_string(&ExitReason::MaxRuntime).unwrap(),
+            "\"max_runtime\""
+        );
+    }
+}
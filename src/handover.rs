@@ -0,0 +1,312 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! Upgrades in place with `--upgrade`: on SIGUSR2 or `POST /control/upgrade` the running miner
+//! stops mining, detaches its pool connections and execs the binary at its own path, which
+//! resumes the sessions without subscribing again.
+//!
+//! The connections are inherited as file descriptors. Everything else the new process needs
+//! goes as JSON through a pipe, whose descriptor is named by [`HANDOVER_ENV`]. Only plain TCP
+//! sessions can be handed over, a miner without one connects and subscribes again in the new
+//! process like after a restart.
+
+use crate::{
+    AssembledJob, MiningNotifyBody, MiningNotifyMessage, MiningSetTargetBody,
+    MiningSetTargetMessage, MiningSubmitMessage, MiningSubscribedBody, StratumMessage,
+};
+use serde::{Deserialize, Serialize};
+
+/// Names the descriptor of the pipe the state comes through.
+pub const HANDOVER_ENV: &str = "ZKWORK_HANDOVER_FD";
+
+/// A pool session as one process leaves it to the next.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SessionHandover {
+    pub client_id: u64,
+    pub graffiti: String,
+    /// What the pool agreed to on the subscribe.
+    pub capabilities: Option<Vec<String>>,
+    pub next_message_id: i64,
+    /// The last target, hex as the pool sent it.
+    pub target: Option<String>,
+    /// The job being mined.
+    pub job: Option<AssembledJob>,
+    /// Hex of what was read from the pool but not decoded yet.
+    pub buffered: String,
+    /// Shares not written to the pool yet.
+    pub pending_submits: Vec<MiningSubmitMessage>,
+}
+
+impl SessionHandover {
+    /// What the pool acknowledged the subscribe with.
+    pub fn subscribed(&self) -> MiningSubscribedBody {
+        MiningSubscribedBody {
+            clientId: self.client_id,
+            graffiti: self.graffiti.clone(),
+            capabilities: self.capabilities.clone(),
+        }
+    }
+
+    /// The messages that set the job being mined up again: its target, the job, then the
+    /// last target if it changed since.
+    pub fn replay(&self) -> Vec<StratumMessage> {
+        let set_target = |target: &str| {
+            StratumMessage::MiningSetTargetMessage(MiningSetTargetMessage {
+                id: 0.into(),
+                method: String::from("mining.set_target"),
+                body: MiningSetTargetBody {
+                    target: target.to_string(),
+                },
+            })
+        };
+        let mut messages = vec![];
+        if let Some(job) = &self.job {
+            messages.push(set_target(&job.target));
+            messages.push(StratumMessage::MiningNotifyMessage(MiningNotifyMessage {
+                id: 0.into(),
+                method: String::from("mining.notify"),
+                body: MiningNotifyBody {
+                    miningRequestId: job.mining_request_id,
+                    header: job.header.clone(),
+                    timestamp: None,
+                    expiresInMs: None,
+                },
+            }));
+        }
+        match &self.target {
+            Some(target) if self.job.as_ref().map(|job| &job.target) != Some(target) => {
+                messages.push(set_target(target))
+            }
+            _ => {}
+        }
+        messages
+    }
+}
+
+/// One miner's part of the handover.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MinerHandover {
+    pub label: String,
+    /// The descriptor of the pool connection.
+    pub socket: i32,
+    pub session: SessionHandover,
+    pub shares_found: u64,
+    /// Unix time in seconds of the last share found, 0 before the first one.
+    pub last_share_at: u64,
+}
+
+/// Everything the new process takes over.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct HandoverState {
+    pub miners: Vec<MinerHandover>,
+}
+
+#[cfg(unix)]
+pub use self::unix::*;
+
+#[cfg(unix)]
+mod unix {
+    use super::*;
+    use anyhow::{anyhow, Result};
+    use std::{
+        convert::Infallible,
+        fs::File,
+        io::{self, Read, Write},
+        os::unix::{
+            io::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd, RawFd},
+            process::CommandExt,
+        },
+        path::PathBuf,
+        process::Command,
+    };
+    use tokio::net::TcpStream;
+
+    /// Leaves the connection open for the process this one execs, returns its descriptor.
+    pub fn detach_socket(stream: TcpStream) -> Result<RawFd> {
+        let fd = stream.into_std()?.into_raw_fd();
+        set_inherited(fd, true)?;
+        Ok(fd)
+    }
+
+    /// Takes over a connection left by [`detach_socket`].
+    pub fn attach_socket(fd: RawFd) -> Result<TcpStream> {
+        // SAFETY: the previous process left this descriptor for us alone
+        let stream = unsafe { std::net::TcpStream::from_raw_fd(fd) };
+        set_inherited(fd, false)?;
+        stream.set_nonblocking(true)?;
+        Ok(TcpStream::from_std(stream)?)
+    }
+
+    fn set_inherited(fd: RawFd, inherited: bool) -> io::Result<()> {
+        // SAFETY: only the flags of a descriptor we own are changed
+        unsafe {
+            let flags = libc::fcntl(fd, libc::F_GETFD);
+            if flags < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let flags = if inherited {
+                flags & !libc::FD_CLOEXEC
+            } else {
+                flags | libc::FD_CLOEXEC
+            };
+            if libc::fcntl(fd, libc::F_SETFD, flags) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the state into a new pipe and returns its read end. Fails rather than blocking
+    /// if the state does not fit the pipe, nobody reads it before the exec.
+    pub fn send_state(state: &HandoverState) -> Result<OwnedFd> {
+        let blob = serde_json::to_vec(state)?;
+        let mut fds = [0; 2];
+        // SAFETY: pipe fills in two new descriptors, owned right below
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        // SAFETY: both descriptors were just created and are not used elsewhere
+        let (read, mut write) =
+            unsafe { (OwnedFd::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+        for fd in fds {
+            set_inherited(fd, false)?;
+        }
+        // SAFETY: only the status flags of the write end are changed
+        unsafe {
+            let flags = libc::fcntl(write.as_raw_fd(), libc::F_GETFL);
+            if flags < 0
+                || libc::fcntl(write.as_raw_fd(), libc::F_SETFL, flags | libc::O_NONBLOCK) < 0
+            {
+                return Err(io::Error::last_os_error().into());
+            }
+        }
+        write.write_all(&blob).map_err(|error| {
+            anyhow!(
+                "failed to write the {} byte handover state: {}",
+                blob.len(),
+                error
+            )
+        })?;
+        Ok(read)
+    }
+
+    /// Reads the state from the pipe [`send_state`] returned.
+    pub fn receive_state(pipe: OwnedFd) -> Result<HandoverState> {
+        let mut blob = vec![];
+        File::from(pipe).read_to_end(&mut blob)?;
+        Ok(serde_json::from_slice(&blob)?)
+    }
+
+    /// The descriptor the previous process left the state in, if this process was started by
+    /// an upgrade. Clears [`HANDOVER_ENV`] for the next upgrade, so it must be called before
+    /// any other thread is started.
+    pub fn take_handover_fd() -> Result<Option<RawFd>> {
+        let fd = match std::env::var(HANDOVER_ENV) {
+            Ok(fd) => fd,
+            Err(_) => return Ok(None),
+        };
+        std::env::remove_var(HANDOVER_ENV);
+        fd.parse()
+            .map(Some)
+            .map_err(|_| anyhow!("invalid {} '{}'", HANDOVER_ENV, fd))
+    }
+
+    /// The state the previous process handed over through `fd`, see [`take_handover_fd`].
+    pub fn inherited_state(fd: RawFd) -> Result<HandoverState> {
+        set_inherited(fd, false)?;
+        // SAFETY: the previous process left this descriptor for us alone
+        receive_state(unsafe { OwnedFd::from_raw_fd(fd) })
+    }
+
+    /// Execs the binary at the path this one was started from, with the same arguments, and
+    /// hands it the state and the connections in it. Only returns if the exec failed.
+    pub fn exec_upgrade(state: &HandoverState) -> Result<Infallible> {
+        let pipe = send_state(state)?;
+        set_inherited(pipe.as_raw_fd(), true)?;
+        let exe = executable()?;
+        let error = Command::new(&exe)
+            .args(std::env::args_os().skip(1))
+            .env(HANDOVER_ENV, pipe.as_raw_fd().to_string())
+            .exec();
+        Err(anyhow!("failed to exec {}: {}", exe.display(), error))
+    }
+
+    /// The path this process was started from. Linux marks it deleted once the upgrade has
+    /// replaced the file, the new file is at the same path.
+    fn executable() -> Result<PathBuf> {
+        let exe = std::env::current_exe()?;
+        Ok(exe
+            .to_str()
+            .and_then(|exe| exe.strip_suffix(" (deleted)"))
+            .map(PathBuf::from)
+            .unwrap_or(exe))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session() -> SessionHandover {
+        SessionHandover {
+            client_id: 3,
+            graffiti: String::from("zk.work"),
+            capabilities: Some(vec![String::from("timestamps")]),
+            next_message_id: 12,
+            target: Some(String::from("0f")),
+            job: Some(AssembledJob {
+                mining_request_id: 7,
+                header: "00".repeat(208),
+                target: String::from("ff"),
+            }),
+            buffered: hex::encode(b"{\"id\":4,"),
+            pending_submits: vec![],
+        }
+    }
+
+    #[test]
+    fn test_replay() {
+        let mut session = session();
+        let targets = |messages: &[StratumMessage]| {
+            messages
+                .iter()
+                .map(|message| match message {
+                    StratumMessage::MiningSetTargetMessage(message) => message.body.target.clone(),
+                    StratumMessage::MiningNotifyMessage(message) => {
+                        message.body.miningRequestId.to_string()
+                    }
+                    message => panic!("unexpected {:?}", message),
+                })
+                .collect::<Vec<_>>()
+        };
+        // the job with its own target, then the newer target
+        assert_eq!(targets(&session.replay()), ["ff", "7", "0f"]);
+        session.target = Some(String::from("ff"));
+        assert_eq!(targets(&session.replay()), ["ff", "7"]);
+        session.job = None;
+        assert_eq!(targets(&session.replay()), ["ff"]);
+        session.target = None;
+        assert!(session.replay().is_empty());
+        assert_eq!(session.subscribed().clientId, 3);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_pipe() {
+        let state = HandoverState {
+            miners: vec![MinerHandover {
+                label: String::new(),
+                socket: 9,
+                session: session(),
+                shares_found: 5,
+                last_share_at: 1_700_000_000,
+            }],
+        };
+        assert_eq!(receive_state(send_state(&state).unwrap()).unwrap(), state);
+        // more than a pipe holds fails instead of blocking
+        let mut big = state.clone();
+        big.miners[0].session.buffered = "00".repeat(1 << 20);
+        assert!(send_state(&big).is_err());
+    }
+}
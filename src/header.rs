@@ -0,0 +1,485 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! Where the miner writes into the block header and how the randomness travels on the wire.
+//!
+//! The pool splices the submitted randomness hex back into the header as is, so the wire
+//! bytes must be exactly the bytes that were hashed. Every encoder and decoder of the
+//! randomness goes through [`Randomness`] so that this holds in one place.
+
+use anyhow::{anyhow, Result};
+use std::ops::Range;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Endianness {
+    Big,
+    Little,
+}
+
+/// Offsets of the fields the miner touches in a block header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HeaderLayout {
+    pub name: &'static str,
+    pub header_size: usize,
+    pub randomness_offset: usize,
+    /// Bytes of the randomness field, one of [`Randomness::WIDTHS`].
+    pub randomness_width: usize,
+    /// Byte order of the u64 the thread pool searches over, in the first bytes of the
+    /// randomness field, and therefore on the wire.
+    pub randomness_endianness: Endianness,
+    pub graffiti_offset: usize,
+    pub graffiti_size: usize,
+}
+
+impl HeaderLayout {
+    /// The Iron Fish header: big-endian randomness in bytes 0..8, graffiti in bytes 176..208.
+    pub const IRONFISH: Self = Self {
+        name: "ironfish",
+        header_size: 208,
+        randomness_offset: 0,
+        randomness_width: 8,
+        randomness_endianness: Endianness::Big,
+        graffiti_offset: 176,
+        graffiti_size: 32,
+    };
+
+    /// The layouts `--header-layout` picks from.
+    pub const KNOWN: [Self; 1] = [Self::IRONFISH];
+
+    /// The known layout called `name`.
+    pub fn by_name(name: &str) -> Result<Self> {
+        Self::KNOWN
+            .into_iter()
+            .find(|layout| layout.name == name)
+            .ok_or_else(|| anyhow!("unknown header layout '{}'", name))
+    }
+
+    /// Whether a job header of `len` bytes is of this layout. A longer one, e.g. with fields a
+    /// pool upgrade appended, is mined at the layout's offsets when `tolerate_longer`. Any
+    /// other is mined only when `force`d.
+    pub fn fit(&self, len: usize, force: bool, tolerate_longer: bool) -> HeaderFit {
+        self.fit_among(len, force, tolerate_longer, &Self::KNOWN)
+    }
+
+    /// The end of the last field the miner touches, the shortest header it can mine.
+    pub fn fields_end(&self) -> usize {
+        self.randomness_range().end.max(self.graffiti_range().end)
+    }
+
+    fn fit_among(
+        &self,
+        len: usize,
+        force: bool,
+        tolerate_longer: bool,
+        known: &[Self],
+    ) -> HeaderFit {
+        if len == self.header_size {
+            return HeaderFit::Fits;
+        }
+        let mismatch = format!(
+            "the pool sent a header of {} bytes, the {} layout has {}",
+            len, self.name, self.header_size
+        );
+        if tolerate_longer && len > self.header_size && len >= self.fields_end() {
+            return HeaderFit::Longer(format!(
+                "{}, mining it with the {} offsets and the {} extra bytes as the pool sent them",
+                mismatch,
+                self.name,
+                len - self.header_size
+            ));
+        }
+        if force {
+            return HeaderFit::Forced(format!(
+                "{}, mining it anyway as --force-header-layout asks",
+                mismatch
+            ));
+        }
+        let names: Vec<&str> = known
+            .iter()
+            .filter(|layout| layout.header_size == len)
+            .map(|layout| layout.name)
+            .collect();
+        let hint = if names.is_empty() {
+            format!(
+                "no known layout has {} bytes, is this an Iron Fish pool?",
+                len
+            )
+        } else {
+            format!("try --header-layout {}", names.join(" or "))
+        };
+        HeaderFit::Mismatch(format!("{}: {}", mismatch, hint))
+    }
+
+    /// The layout with a randomness field of `width` bytes, which must be one of
+    /// [`Randomness::WIDTHS`] and fit in the header before the graffiti.
+    pub fn with_randomness_width(self, width: usize) -> Result<Self> {
+        if !Randomness::WIDTHS.contains(&width) {
+            return Err(anyhow!(
+                "randomness width {} is not one of {:?}",
+                width,
+                Randomness::WIDTHS
+            ));
+        }
+        let layout = Self {
+            randomness_width: width,
+            ..self
+        };
+        let end = layout.randomness_range().end;
+        if end > layout.header_size
+            || (layout.randomness_offset < layout.graffiti_range().end
+                && layout.graffiti_offset < end)
+        {
+            return Err(anyhow!(
+                "a randomness of {} bytes does not fit the {} header",
+                width,
+                layout.name
+            ));
+        }
+        Ok(layout)
+    }
+
+    pub fn randomness_range(&self) -> Range<usize> {
+        self.randomness_offset..self.randomness_offset + self.randomness_width
+    }
+
+    pub fn graffiti_range(&self) -> Range<usize> {
+        self.graffiti_offset..self.graffiti_offset + self.graffiti_size
+    }
+}
+
+/// How a job header fits the layout the miner mines with, see [`HeaderLayout::fit`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HeaderFit {
+    Fits,
+    /// Longer, with the fields of the layout in place, and mined. Holds the note.
+    Longer(String),
+    /// Of another length, but mined anyway. Holds the warning.
+    Forced(String),
+    /// Of another length, and not mined. Holds the error, with a hint at the right layout.
+    Mismatch(String),
+}
+
+impl Default for HeaderLayout {
+    fn default() -> Self {
+        Self::IRONFISH
+    }
+}
+
+/// The randomness field of a header, its bytes in header order. The thread pool searches over
+/// a u64 in its first [`Randomness::SEARCH_SIZE`] bytes. A wider field keeps the rest of its
+/// bytes as the pool sent them in the job.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Randomness(Vec<u8>);
+
+impl Randomness {
+    /// Bytes of the value the thread pool searches over.
+    pub const SEARCH_SIZE: usize = 8;
+    /// The widths a randomness field can have: 8 bytes today, 32 in the pool protocol drafts
+    /// for the post-fork chain.
+    pub const WIDTHS: [usize; 2] = [8, 32];
+
+    /// The search `value` in a field of zeros.
+    pub fn from_value(value: u64, layout: &HeaderLayout) -> Self {
+        let mut bytes = vec![0; layout.randomness_width];
+        bytes[..Self::SEARCH_SIZE].copy_from_slice(&Self::value_bytes(value, layout));
+        Self(bytes)
+    }
+
+    /// The search `value` in the randomness field of the job `header`, as the thread pool
+    /// hashed it.
+    pub fn in_header(value: u64, header: &[u8], layout: &HeaderLayout) -> Result<Self> {
+        let range = Self::range_in(header, layout)?;
+        let mut bytes = header[range].to_vec();
+        bytes[..Self::SEARCH_SIZE].copy_from_slice(&Self::value_bytes(value, layout));
+        Ok(Self(bytes))
+    }
+
+    fn value_bytes(value: u64, layout: &HeaderLayout) -> [u8; Self::SEARCH_SIZE] {
+        match layout.randomness_endianness {
+            Endianness::Big => value.to_be_bytes(),
+            Endianness::Little => value.to_le_bytes(),
+        }
+    }
+
+    /// The search value in the first bytes of the field.
+    pub fn value(&self, layout: &HeaderLayout) -> u64 {
+        let mut bytes = [0; Self::SEARCH_SIZE];
+        bytes.copy_from_slice(&self.0[..Self::SEARCH_SIZE]);
+        match layout.randomness_endianness {
+            Endianness::Big => u64::from_be_bytes(bytes),
+            Endianness::Little => u64::from_le_bytes(bytes),
+        }
+    }
+
+    /// The bytes as they appear in the header.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// The hex submitted to the pool, the header bytes in order.
+    pub fn to_wire_hex(&self) -> String {
+        hex::encode(&self.0)
+    }
+
+    pub fn from_wire_hex(wire: &str, layout: &HeaderLayout) -> Result<Self> {
+        let bytes = hex::decode(wire)?;
+        if bytes.len() != layout.randomness_width {
+            return Err(anyhow!(
+                "randomness is {} bytes, expected {}",
+                bytes.len(),
+                layout.randomness_width
+            ));
+        }
+        Ok(Self(bytes))
+    }
+
+    fn range_in(header: &[u8], layout: &HeaderLayout) -> Result<Range<usize>> {
+        let range = layout.randomness_range();
+        if header.len() < range.end {
+            return Err(anyhow!(
+                "header is {} bytes, too short for randomness at {:?}",
+                header.len(),
+                range
+            ));
+        }
+        Ok(range)
+    }
+
+    /// Writes the randomness into its place in `header`.
+    pub fn apply_to_header(&self, header: &mut [u8], layout: &HeaderLayout) -> Result<()> {
+        let range = Self::range_in(header, layout)?;
+        if self.0.len() != range.len() {
+            return Err(anyhow!(
+                "randomness is {} bytes, the {} layout has {}",
+                self.0.len(),
+                layout.name,
+                range.len()
+            ));
+        }
+        header[range].copy_from_slice(&self.0);
+        Ok(())
+    }
+}
+
+/// The proof of work hash of a header.
+pub fn hash_header(header: &[u8]) -> [u8; 32] {
+    *blake3::hash(header).as_bytes()
+}
+
+/// The hash of `header` with `randomness` spliced in.
+pub fn share_hash(
+    header: &[u8],
+    randomness: &Randomness,
+    layout: &HeaderLayout,
+) -> Result<[u8; 32]> {
+    let mut header = header.to_vec();
+    randomness.apply_to_header(&mut header, layout)?;
+    Ok(hash_header(&header))
+}
+
+/// Recomputes a share the way the pool does: splices the submitted randomness into the header
+/// and checks the hash against the target, both compared as big-endian numbers.
+pub fn verify_share(
+    header: &[u8],
+    randomness: &Randomness,
+    target: &[u8; 32],
+    layout: &HeaderLayout,
+) -> Result<bool> {
+    Ok(share_hash(header, randomness, layout)? <= *target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{known_header, EMPTY_HASH, KNOWN_HASH, KNOWN_RANDOMNESS};
+
+    #[test]
+    fn test_header_fit() {
+        let layout = HeaderLayout::IRONFISH;
+        assert_eq!(HeaderLayout::by_name("ironfish").unwrap(), layout);
+        assert!(HeaderLayout::by_name("bitcoin").is_err());
+        assert_eq!(layout.fit(208, false, false), HeaderFit::Fits);
+        assert_eq!(layout.fit(208, true, true), HeaderFit::Fits);
+        // the wider randomness does not change the header length
+        assert_eq!(
+            layout
+                .with_randomness_width(32)
+                .unwrap()
+                .fit(208, false, false),
+            HeaderFit::Fits
+        );
+
+        assert_eq!(
+            layout.fit(80, false, true),
+            HeaderFit::Mismatch(String::from(
+                "the pool sent a header of 80 bytes, the ironfish layout has 208: no known layout has 80 bytes, is this an Iron Fish pool?"
+            ))
+        );
+        let wide = HeaderLayout {
+            name: "wide",
+            header_size: 240,
+            ..layout
+        };
+        let longer = HeaderLayout {
+            name: "longer",
+            ..wide
+        };
+        assert_eq!(
+            layout.fit_among(240, false, false, &[layout, wide, longer]),
+            HeaderFit::Mismatch(String::from(
+                "the pool sent a header of 240 bytes, the ironfish layout has 208: try --header-layout wide or longer"
+            ))
+        );
+        assert_eq!(
+            layout.fit(240, true, false),
+            HeaderFit::Forced(String::from(
+                "the pool sent a header of 240 bytes, the ironfish layout has 208, mining it anyway as --force-header-layout asks"
+            ))
+        );
+    }
+
+    #[test]
+    fn test_longer_header_fit() {
+        let layout = HeaderLayout::IRONFISH;
+        assert_eq!(layout.fields_end(), 208);
+        assert_eq!(
+            layout.fit(240, false, true),
+            HeaderFit::Longer(String::from(
+                "the pool sent a header of 240 bytes, the ironfish layout has 208, mining it with the ironfish offsets and the 32 extra bytes as the pool sent them"
+            ))
+        );
+        // shorter ones cut off the graffiti, tolerated or not
+        assert!(matches!(
+            layout.fit(200, false, true),
+            HeaderFit::Mismatch(_)
+        ));
+        // a layout whose fields run past its own size never fits a header they don't fit in
+        let overlong = HeaderLayout {
+            graffiti_offset: 230,
+            ..layout
+        };
+        assert!(matches!(
+            overlong.fit(240, false, true),
+            HeaderFit::Mismatch(_)
+        ));
+
+        // the share is hashed over the whole header, extra bytes included
+        let mut header = known_header();
+        header.extend_from_slice(&[0xab; 32]);
+        let randomness = Randomness::from_value(KNOWN_RANDOMNESS, &layout);
+        let hash = share_hash(&header, &randomness, &layout).unwrap();
+        let mut hashed = header.clone();
+        randomness.apply_to_header(&mut hashed, &layout).unwrap();
+        assert_eq!(hash, hash_header(&hashed));
+        assert_ne!(hex::encode(hash), KNOWN_HASH);
+        assert!(verify_share(&header, &randomness, &hash, &layout).unwrap());
+    }
+
+    #[test]
+    fn test_wire_hex() {
+        let layout = HeaderLayout::IRONFISH;
+        let randomness = Randomness::from_value(0x1234, &layout);
+        assert_eq!(randomness.to_wire_hex(), "0000000000001234");
+        assert_eq!(randomness.to_wire_hex(), format!("{:016x}", 0x1234));
+        assert_eq!(
+            Randomness::from_wire_hex("0000000000001234", &layout).unwrap(),
+            randomness
+        );
+        assert_eq!(randomness.value(&layout), 0x1234);
+        assert!(Randomness::from_wire_hex("1234", &layout).is_err());
+        assert!(Randomness::from_wire_hex("zz00000000001234", &layout).is_err());
+
+        let little = HeaderLayout {
+            randomness_endianness: Endianness::Little,
+            ..layout
+        };
+        let randomness = Randomness::from_value(0x1234, &little);
+        assert_eq!(randomness.to_wire_hex(), "3412000000000000");
+        assert_eq!(
+            Randomness::from_wire_hex("3412000000000000", &little).unwrap(),
+            randomness
+        );
+        assert_eq!(randomness.value(&little), 0x1234);
+    }
+
+    #[test]
+    fn test_randomness_width() {
+        let wide = HeaderLayout::IRONFISH.with_randomness_width(32).unwrap();
+        assert_eq!(wide.randomness_range(), 0..32);
+        assert_eq!(
+            HeaderLayout::IRONFISH.with_randomness_width(8).unwrap(),
+            HeaderLayout::IRONFISH
+        );
+        assert!(HeaderLayout::IRONFISH.with_randomness_width(16).is_err());
+        let late = HeaderLayout {
+            randomness_offset: 160,
+            ..HeaderLayout::IRONFISH
+        };
+        assert!(late.with_randomness_width(32).is_err());
+
+        // the search value goes first, the rest of the field is the job's
+        let mut header = known_header();
+        header[8..32].fill(0xab);
+        let randomness = Randomness::in_header(0x1234, &header, &wide).unwrap();
+        let wire = randomness.to_wire_hex();
+        assert_eq!(wire, format!("{:016x}{}", 0x1234, "ab".repeat(24)));
+        assert_eq!(Randomness::from_wire_hex(&wire, &wide).unwrap(), randomness);
+        assert_eq!(randomness.value(&wide), 0x1234);
+        assert!(Randomness::from_wire_hex("0000000000001234", &wide).is_err());
+        assert!(randomness
+            .apply_to_header(&mut header, &HeaderLayout::IRONFISH)
+            .is_err());
+
+        // width 8 ignores the rest of the header
+        assert_eq!(
+            Randomness::in_header(0x1234, &header, &HeaderLayout::IRONFISH).unwrap(),
+            Randomness::from_value(0x1234, &HeaderLayout::IRONFISH)
+        );
+    }
+
+    #[test]
+    fn test_apply_to_header() {
+        let layout = HeaderLayout::IRONFISH;
+        let mut header = known_header();
+        assert_eq!(header.len(), layout.header_size);
+        let randomness = Randomness::from_value(0x0102030405060708, &layout);
+        randomness.apply_to_header(&mut header, &layout).unwrap();
+        assert_eq!(header[0..8], [1, 2, 3, 4, 5, 6, 7, 8]);
+        // the wire hex is exactly what ends up in the header
+        assert_eq!(
+            hex::encode(&header[layout.randomness_range()]),
+            randomness.to_wire_hex()
+        );
+        assert!(randomness.apply_to_header(&mut [0u8; 4], &layout).is_err());
+    }
+
+    #[test]
+    fn test_share_vector() {
+        let layout = HeaderLayout::IRONFISH;
+        let header = known_header();
+        let randomness = Randomness::from_wire_hex("0000000000001234", &layout).unwrap();
+        assert_eq!(
+            randomness,
+            Randomness::from_value(KNOWN_RANDOMNESS, &layout)
+        );
+        let mut hashed = header.clone();
+        randomness.apply_to_header(&mut hashed, &layout).unwrap();
+        assert_eq!(hex::encode(hash_header(&[])), EMPTY_HASH);
+        assert_eq!(hex::encode(hash_header(&hashed)), KNOWN_HASH);
+
+        // the hash itself is the easiest target the share meets
+        let target = hash_header(&hashed);
+        assert!(verify_share(&header, &randomness, &target, &layout).unwrap());
+        let mut harder = target;
+        harder[31] -= 1;
+        assert!(!verify_share(&header, &randomness, &harder, &layout).unwrap());
+        // the same value in the other byte order is a different share
+        let little = HeaderLayout {
+            randomness_endianness: Endianness::Little,
+            ..layout
+        };
+        let swapped = Randomness::from_value(KNOWN_RANDOMNESS, &little);
+        assert!(!verify_share(&header, &swapped, &target, &layout).unwrap());
+    }
+}
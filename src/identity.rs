@@ -0,0 +1,196 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! Tells apart miners started from one cloned machine image. They share the default worker
+//! name, and without a pool graffiti of their own they would hash the same headers, which pools
+//! punish as duplicate shares. Each miner derives a short suffix for the default worker name
+//! from the machine, and a start for the spare randomness bytes from the OS random generator.
+
+use serde::{Deserialize, Serialize};
+use std::fs;
+
+/// The `--worker_name` default, which gets the identity suffix.
+pub const DEFAULT_WORKER_NAME: &str = "zkwork miner";
+/// Bytes of the suffix, written as hex.
+const SUFFIX_BYTES: usize = 3;
+/// The source that differs between clones of one image, as it changes with every boot.
+const BOOT_ID: &str = "boot id";
+
+/// What a miner is told apart by.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Identity {
+    /// Hex appended to the default worker name.
+    pub suffix: String,
+    /// Where the search of every job begins with `--randomness-start random`, and what the
+    /// randomness bytes past the search value are seeded with, see
+    /// [`crate::Work::seed_randomness`].
+    pub nonce_start: u64,
+    /// What the suffix was derived from, e.g. `["machine-id", "hostname", "boot id"]`.
+    pub derived_from: Vec<String>,
+}
+
+impl Identity {
+    /// From `--identity-seed` if given, from the machine otherwise.
+    pub fn derive(seed: Option<u64>) -> Self {
+        match seed {
+            Some(seed) => Self::from_seed(seed),
+            None => Self::from_sources(&machine_sources(), os_random()),
+        }
+    }
+
+    /// The same identity for the same seed, for deterministic test setups.
+    pub fn from_seed(seed: u64) -> Self {
+        Self {
+            suffix: suffix(&seed.to_le_bytes()),
+            nonce_start: split_mix(seed),
+            derived_from: vec![String::from("--identity-seed")],
+        }
+    }
+
+    /// The suffix from the named `sources`, or from `random` without any. The nonce start is
+    /// `random`.
+    pub fn from_sources(sources: &[(&'static str, String)], random: u64) -> Self {
+        let suffix = if sources.is_empty() {
+            suffix(&random.to_le_bytes())
+        } else {
+            let mut hasher = blake3::Hasher::new();
+            for (name, value) in sources {
+                hasher.update(name.as_bytes());
+                hasher.update(b"=");
+                hasher.update(value.as_bytes());
+                hasher.update(b"\n");
+            }
+            hex::encode(&hasher.finalize().as_bytes()[..SUFFIX_BYTES])
+        };
+        Self {
+            suffix,
+            nonce_start: random,
+            derived_from: sources.iter().map(|(name, _)| name.to_string()).collect(),
+        }
+    }
+
+    /// Why miners cloned from one image may still share this identity, if they may.
+    pub fn collision_warning(&self) -> Option<String> {
+        if self.derived_from.is_empty() {
+            return Some(String::from(
+                "no machine-id, hostname or boot id could be read, the worker name suffix is random and changes with every start",
+            ));
+        }
+        if self
+            .derived_from
+            .iter()
+            .any(|source| source == BOOT_ID || source == "--identity-seed")
+        {
+            return None;
+        }
+        Some(format!(
+            "the worker name suffix comes from {} only, which machines cloned from one image share. Set --worker_name or --identity-seed on each of them",
+            self.derived_from.join(", ")
+        ))
+    }
+
+    /// `worker_name` with the suffix if it is the default, as given otherwise.
+    pub fn worker_name(&self, worker_name: &str) -> String {
+        if worker_name == DEFAULT_WORKER_NAME {
+            format!("{}-{}", worker_name, self.suffix)
+        } else {
+            worker_name.to_string()
+        }
+    }
+}
+
+/// 64 bits from the OS random generator.
+pub fn os_random() -> u64 {
+    let mut bytes = [0; 8];
+    getrandom::getrandom(&mut bytes).expect("the OS random generator can be read");
+    u64::from_le_bytes(bytes)
+}
+
+/// What the machine can be told apart by, those that can be read.
+fn machine_sources() -> Vec<(&'static str, String)> {
+    let read = |paths: &[&str]| {
+        paths
+            .iter()
+            .filter_map(|path| fs::read_to_string(path).ok())
+            .map(|value| value.trim().to_string())
+            .find(|value| !value.is_empty())
+    };
+    let hostname = read(&["/etc/hostname"])
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .or_else(|| std::env::var("COMPUTERNAME").ok());
+    [
+        (
+            "machine-id",
+            read(&["/etc/machine-id", "/var/lib/dbus/machine-id"]),
+        ),
+        ("hostname", hostname),
+        (BOOT_ID, read(&["/proc/sys/kernel/random/boot_id"])),
+    ]
+    .into_iter()
+    .filter_map(|(name, value)| Some((name, value.filter(|value| !value.is_empty())?)))
+    .collect()
+}
+
+fn suffix(bytes: &[u8]) -> String {
+    hex::encode(&blake3::hash(bytes).as_bytes()[..SUFFIX_BYTES])
+}
+
+/// The SplitMix64 output for `seed`, so that close seeds give far apart starts.
+fn split_mix(seed: u64) -> u64 {
+    let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seeded_identity() {
+        let one = Identity::from_seed(1);
+        let two = Identity::from_seed(2);
+        assert_ne!(one.suffix, two.suffix);
+        assert_ne!(one.nonce_start, two.nonce_start);
+        assert_eq!(one, Identity::from_seed(1));
+        assert_eq!(one.suffix.len(), SUFFIX_BYTES * 2);
+        assert_eq!(one.collision_warning(), None);
+
+        assert_eq!(
+            one.worker_name(DEFAULT_WORKER_NAME),
+            format!("zkwork miner-{}", one.suffix)
+        );
+        assert_eq!(one.worker_name("rig-7"), "rig-7");
+    }
+
+    #[test]
+    fn test_machine_identity() {
+        let clone = [
+            ("machine-id", String::from("0123")),
+            ("hostname", String::from("rig")),
+        ];
+        // clones share the machine-id and hostname, and are warned about it
+        let first = Identity::from_sources(&clone, 1);
+        assert_eq!(first.suffix, Identity::from_sources(&clone, 2).suffix);
+        assert!(first
+            .collision_warning()
+            .unwrap()
+            .contains("machine-id, hostname"));
+        // the boot id tells them apart
+        let booted = |boot_id: &str| {
+            let mut sources = clone.to_vec();
+            sources.push((BOOT_ID, boot_id.to_string()));
+            Identity::from_sources(&sources, 1)
+        };
+        assert_ne!(booted("a").suffix, booted("b").suffix);
+        assert_eq!(booted("a").collision_warning(), None);
+        // with nothing to read it is random
+        let random = Identity::from_sources(&[], 1);
+        assert_ne!(random.suffix, Identity::from_sources(&[], 2).suffix);
+        assert!(random.collision_warning().is_some());
+
+        assert_ne!(os_random(), os_random());
+    }
+}
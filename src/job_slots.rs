@@ -0,0 +1,118 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! The jobs mined side by side with `--max-concurrent-jobs`, for pools that hand out more than
+//! one job at a time and expect the effort split between them. Each job has a thread pool of
+//! its own.
+
+/// The jobs being mined, one per slot. A job with the id of one being mined replaces only
+/// that one, any other job takes a free slot or the one of the oldest job.
+#[derive(Debug)]
+pub struct JobSlots<T> {
+    /// The id of the job in each slot, when it was assigned and the job.
+    slots: Vec<Option<(u32, u64, T)>>,
+    assigned: u64,
+}
+
+impl<T> JobSlots<T> {
+    /// `count` slots, at least one.
+    pub fn new(count: usize) -> Self {
+        Self {
+            slots: (0..count.max(1)).map(|_| None).collect(),
+            assigned: 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slots.iter().all(Option::is_none)
+    }
+
+    /// Puts the job in its slot. Returns the slot and the job it took the place of.
+    pub fn assign(&mut self, mining_request_id: u32, job: T) -> (usize, Option<T>) {
+        let slot = self
+            .slots
+            .iter()
+            .position(|slot| matches!(slot, Some((id, _, _)) if *id == mining_request_id))
+            .or_else(|| self.slots.iter().position(Option::is_none))
+            .unwrap_or_else(|| {
+                let (slot, _) = self
+                    .slots
+                    .iter()
+                    .enumerate()
+                    .min_by_key(|(_, slot)| slot.as_ref().map(|(_, assigned, _)| *assigned))
+                    .expect("there is at least one slot");
+                slot
+            });
+        self.assigned += 1;
+        let replaced = self.slots[slot].replace((mining_request_id, self.assigned, job));
+        (slot, replaced.map(|(_, _, job)| job))
+    }
+
+    /// The jobs with their slots.
+    pub fn jobs(&self) -> impl Iterator<Item = (usize, &T)> {
+        self.slots
+            .iter()
+            .enumerate()
+            .filter_map(|(slot, job)| job.as_ref().map(|(_, _, job)| (slot, job)))
+    }
+
+    /// The ids of the jobs being mined, oldest first.
+    pub fn mining_request_ids(&self) -> Vec<u32> {
+        let mut jobs = self.slots.iter().flatten().collect::<Vec<_>>();
+        jobs.sort_by_key(|(_, assigned, _)| *assigned);
+        jobs.into_iter().map(|(id, _, _)| *id).collect()
+    }
+
+    /// Forgets every job, e.g. when the pool has no work.
+    pub fn clear(&mut self) {
+        self.slots.iter_mut().for_each(|slot| *slot = None);
+    }
+}
+
+/// The threads of each of `jobs` thread pools, split evenly with the remainder going to the
+/// first ones. Every job gets a thread, even when throttled below one per job.
+pub fn split_threads(threads: usize, jobs: usize) -> Vec<usize> {
+    let jobs = jobs.max(1);
+    let threads = threads.max(jobs);
+    (0..jobs)
+        .map(|job| threads / jobs + usize::from(job < threads % jobs))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_assign() {
+        let mut slots = JobSlots::new(2);
+        assert!(slots.is_empty());
+        assert_eq!(slots.assign(1, "a"), (0, None));
+        assert_eq!(slots.assign(2, "b"), (1, None));
+        // the same id replaces its own job only
+        assert_eq!(slots.assign(1, "a2"), (0, Some("a")));
+        assert_eq!(slots.mining_request_ids(), [2, 1]);
+        // a new id takes the place of the oldest job
+        assert_eq!(slots.assign(3, "c"), (1, Some("b")));
+        assert_eq!(slots.mining_request_ids(), [1, 3]);
+        assert_eq!(slots.jobs().collect::<Vec<_>>(), [(0, &"a2"), (1, &"c")]);
+        slots.clear();
+        assert!(slots.is_empty());
+        assert_eq!(slots.assign(4, "d"), (0, None));
+
+        // a single slot is the job being mined, as without concurrent jobs
+        let mut single = JobSlots::new(0);
+        assert_eq!(single.assign(1, "a"), (0, None));
+        assert_eq!(single.assign(2, "b"), (0, Some("a")));
+    }
+
+    #[test]
+    fn test_split_threads() {
+        assert_eq!(split_threads(8, 1), [8]);
+        assert_eq!(split_threads(8, 2), [4, 4]);
+        assert_eq!(split_threads(7, 3), [3, 2, 2]);
+        assert_eq!(split_threads(1, 2), [1, 1]);
+        assert_eq!(split_threads(4, 0), [4]);
+    }
+}
@@ -0,0 +1,253 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! How quickly new jobs are picked up: from the notify to the thread pool working on it, and
+//! from the notify to the first share found for it and written to the pool.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/// Upper bounds of the buckets in milliseconds, the last bucket takes everything above.
+const BUCKETS_MS: [f64; 16] = [
+    0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1000.0, 2500.0, 5000.0,
+    10000.0,
+];
+/// Jobs notified but not yet dispatched that are remembered, more only come with a stuck
+/// mining loop.
+const MAX_NOTIFIED: usize = 8;
+
+/// A histogram of latencies over fixed buckets.
+#[derive(Clone, Debug, Default)]
+pub struct LatencyHistogram {
+    counts: [u64; BUCKETS_MS.len() + 1],
+    total: u64,
+    max_ms: f64,
+}
+
+impl LatencyHistogram {
+    pub fn record(&mut self, latency: Duration) {
+        let ms = latency.as_micros() as f64 / 1000.0;
+        let bucket = BUCKETS_MS
+            .iter()
+            .position(|bound| ms <= *bound)
+            .unwrap_or(BUCKETS_MS.len());
+        self.counts[bucket] += 1;
+        self.total += 1;
+        self.max_ms = self.max_ms.max(ms);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.total
+    }
+
+    /// The upper bound of the bucket the `q` quantile falls in, in milliseconds, or the
+    /// largest latency seen if that is lower. `None` before the first sample.
+    pub fn quantile_ms(&self, q: f64) -> Option<f64> {
+        if self.total == 0 {
+            return None;
+        }
+        let rank = ((q * self.total as f64).ceil() as u64).clamp(1, self.total);
+        let mut seen = 0;
+        for (bucket, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                let bound = BUCKETS_MS.get(bucket).copied().unwrap_or(f64::INFINITY);
+                return Some(bound.min(self.max_ms));
+            }
+        }
+        Some(self.max_ms)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct JobLatencyStats {
+    /// Jobs given to the thread pool, a job the pool sent again counts again.
+    pub jobs: u64,
+    /// Jobs superseded before a share was found for them, left out of the share latency.
+    pub jobs_without_share: u64,
+    /// From the notify to the thread pool working on the job.
+    pub dispatch_p50_ms: Option<f64>,
+    pub dispatch_p95_ms: Option<f64>,
+    /// From the notify to the first share found for the job.
+    pub first_share_p50_ms: Option<f64>,
+    pub first_share_p95_ms: Option<f64>,
+    /// From the notify to the first share written to the pool connection.
+    #[serde(default)]
+    pub first_submit_p50_ms: Option<f64>,
+    #[serde(default)]
+    pub first_submit_p95_ms: Option<f64>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct CurrentJob {
+    mining_request_id: u32,
+    notified_at: Instant,
+    share_found: bool,
+    share_submitted: bool,
+}
+
+/// Follows each job from the notify to its dispatch and its first share.
+#[derive(Debug, Default)]
+pub struct JobLatency {
+    notified: VecDeque<(u32, Instant)>,
+    current: Option<CurrentJob>,
+    dispatch: LatencyHistogram,
+    first_share: LatencyHistogram,
+    first_submit: LatencyHistogram,
+    jobs_without_share: u64,
+}
+
+impl JobLatency {
+    /// The pool sent the job at `now`.
+    pub fn notified(&mut self, mining_request_id: u32, now: Instant) {
+        self.notified.retain(|(id, _)| *id != mining_request_id);
+        if self.notified.len() >= MAX_NOTIFIED {
+            self.notified.pop_front();
+        }
+        self.notified.push_back((mining_request_id, now));
+    }
+
+    /// The thread pool started on the job at `now`.
+    pub fn dispatched(&mut self, mining_request_id: u32, now: Instant) {
+        let index = match self
+            .notified
+            .iter()
+            .position(|(id, _)| *id == mining_request_id)
+        {
+            Some(index) => index,
+            None => return,
+        };
+        let (_, notified_at) = self.notified.remove(index).unwrap();
+        self.dispatch
+            .record(now.saturating_duration_since(notified_at));
+        match self.current {
+            // the pool sent the same job again, its first share is still the one to time
+            Some(current) if current.mining_request_id == mining_request_id => return,
+            Some(current) if !current.share_found => self.jobs_without_share += 1,
+            _ => {}
+        }
+        self.current = Some(CurrentJob {
+            mining_request_id,
+            notified_at,
+            share_found: false,
+            share_submitted: false,
+        });
+    }
+
+    /// When the pool sent the job, while it is waiting for its dispatch or the current one.
+    pub fn notified_at(&self, mining_request_id: u32) -> Option<Instant> {
+        match self.current {
+            Some(current) if current.mining_request_id == mining_request_id => {
+                Some(current.notified_at)
+            }
+            _ => self
+                .notified
+                .iter()
+                .find(|(id, _)| *id == mining_request_id)
+                .map(|(_, notified_at)| *notified_at),
+        }
+    }
+
+    /// A share for the job came out of the thread pool at `now`.
+    pub fn share_found(&mut self, mining_request_id: u32, now: Instant) {
+        if let Some(current) = self.current.as_mut() {
+            if current.mining_request_id == mining_request_id && !current.share_found {
+                current.share_found = true;
+                self.first_share
+                    .record(now.saturating_duration_since(current.notified_at));
+            }
+        }
+    }
+
+    /// A share for the job was written to the pool connection at `now`.
+    pub fn share_submitted(&mut self, mining_request_id: u32, now: Instant) {
+        if let Some(current) = self.current.as_mut() {
+            if current.mining_request_id == mining_request_id && !current.share_submitted {
+                current.share_submitted = true;
+                self.first_submit
+                    .record(now.saturating_duration_since(current.notified_at));
+            }
+        }
+    }
+
+    pub fn stats(&self) -> JobLatencyStats {
+        JobLatencyStats {
+            jobs: self.dispatch.count(),
+            jobs_without_share: self.jobs_without_share,
+            dispatch_p50_ms: self.dispatch.quantile_ms(0.5),
+            dispatch_p95_ms: self.dispatch.quantile_ms(0.95),
+            first_share_p50_ms: self.first_share.quantile_ms(0.5),
+            first_share_p95_ms: self.first_share.quantile_ms(0.95),
+            first_submit_p50_ms: self.first_submit.quantile_ms(0.5),
+            first_submit_p95_ms: self.first_submit.quantile_ms(0.95),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MS: Duration = Duration::from_millis(1);
+
+    #[test]
+    fn test_histogram() {
+        let mut histogram = LatencyHistogram::default();
+        assert_eq!(histogram.quantile_ms(0.5), None);
+        for ms in 1..=100 {
+            histogram.record(MS * ms);
+        }
+        assert_eq!(histogram.quantile_ms(0.5), Some(50.0));
+        assert_eq!(histogram.quantile_ms(0.95), Some(100.0));
+        // beyond the last bucket the largest latency is all there is
+        histogram.record(Duration::from_secs(60));
+        assert_eq!(histogram.quantile_ms(1.0), Some(60000.0));
+        let mut fast = LatencyHistogram::default();
+        fast.record(Duration::from_micros(30));
+        assert_eq!(fast.quantile_ms(0.5), Some(0.03));
+    }
+
+    #[test]
+    fn test_job_latency() {
+        let start = Instant::now();
+        let mut latency = JobLatency::default();
+        latency.notified(1, start);
+        latency.dispatched(1, start + MS);
+        latency.share_found(1, start + MS * 40);
+        latency.share_submitted(1, start + MS * 41);
+        // only the first share of a job counts
+        latency.share_found(1, start + MS * 4000);
+        latency.share_submitted(1, start + MS * 4001);
+
+        // superseded before any share
+        latency.notified(2, start + MS * 5000);
+        latency.dispatched(2, start + MS * 5001);
+        latency.notified(3, start + MS * 6000);
+        latency.dispatched(3, start + MS * 6002);
+        // a late share of the superseded job does not count for it
+        latency.share_found(2, start + MS * 6003);
+        // unknown jobs are ignored
+        latency.dispatched(9, start + MS * 7000);
+
+        let stats = latency.stats();
+        assert_eq!(stats.jobs, 3);
+        assert_eq!(stats.jobs_without_share, 1);
+        assert_eq!(stats.dispatch_p50_ms, Some(1.0));
+        assert_eq!(stats.dispatch_p95_ms, Some(2.0));
+        assert_eq!(stats.first_share_p50_ms, Some(40.0));
+        assert_eq!(stats.first_submit_p50_ms, Some(41.0));
+        assert_eq!(stats.first_submit_p95_ms, Some(41.0));
+
+        // the same job sent again is not a new job, its share is timed from the first notify
+        latency.notified(3, start + MS * 8000);
+        latency.dispatched(3, start + MS * 8001);
+        latency.share_found(3, start + MS * 8100);
+        let stats = latency.stats();
+        assert_eq!(stats.jobs_without_share, 1);
+        assert_eq!(stats.first_share_p95_ms, Some(2100.0));
+    }
+}
@@ -2,14 +2,135 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 
+pub mod api;
+pub use api::*;
+
+pub mod api_socket;
+pub use api_socket::*;
+
+pub mod baseline;
+pub use baseline::*;
+
+pub mod blocking;
+
+pub mod bus;
+pub use bus::*;
+
+pub mod channel;
+pub use channel::*;
+
+pub mod check;
+
 pub mod cli;
 pub use cli::*;
 
+pub mod clock;
+pub use clock::*;
+
+pub mod config_report;
+pub use config_report::*;
+
 pub mod stratum;
 pub use stratum::*;
 
+pub mod estimate;
+pub use estimate::{EarningsEstimate, NetworkDifficulty};
+
+pub mod final_report;
+pub use final_report::*;
+
+pub mod handover;
+pub use handover::*;
+
+pub mod header;
+pub use header::*;
+
+pub mod identity;
+pub use identity::*;
+
+pub mod job_slots;
+pub use job_slots::*;
+
+pub mod latency;
+pub use latency::*;
+
+pub mod lifetime;
+pub use lifetime::*;
+
+pub mod limits;
+pub use limits::*;
+
+pub mod log_limiter;
+pub use log_limiter::*;
+
 pub mod miner;
 pub use miner::*;
 
+pub mod miner_set;
+pub use miner_set::*;
+
 pub mod meter;
 pub use meter::*;
+
+pub mod pause;
+pub use pause::*;
+
+pub mod pool_api;
+pub use pool_api::*;
+
+pub mod proxy;
+
+pub mod replay;
+
+pub mod report;
+pub use report::*;
+
+pub mod retry;
+pub use retry::*;
+
+pub mod runtime;
+pub use runtime::*;
+
+pub mod self_check;
+pub use self_check::*;
+
+pub mod share_guard;
+pub use share_guard::*;
+
+pub mod share_hook;
+pub use share_hook::*;
+
+pub mod share_intervals;
+pub use share_intervals::*;
+
+pub mod share_log;
+pub use share_log::*;
+
+pub mod share_value;
+pub use share_value::*;
+
+pub mod snapshot;
+pub use snapshot::*;
+
+pub mod status;
+
+pub mod supervisor;
+pub use supervisor::*;
+
+pub mod target;
+pub use target::*;
+
+pub mod target_history;
+pub use target_history::*;
+
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
+
+pub mod thermal;
+pub use thermal::*;
+
+pub mod thread_pool;
+pub use thread_pool::*;
+
+pub mod work;
+pub use work::*;
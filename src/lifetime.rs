@@ -0,0 +1,382 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! Totals that survive restarts with `--data-dir`: the counters of every miner are saved to
+//! [`LIFETIME_FILE`] every [`LIFETIME_SAVE_INTERVAL`] and on a clean shutdown, and added to
+//! the counters of the next process.
+//!
+//! The file carries a checksum of the counters. A file that does not parse or whose checksum
+//! does not match is moved aside and the counters start over, a warning says so. Saving never
+//! waits on the disk, the writes are left to a task of their own.
+
+use crate::MinerStats;
+use anyhow::{anyhow, Result};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::{
+    sync::{mpsc, oneshot},
+    task,
+};
+
+/// The file in `--data-dir` the counters are saved to.
+pub const LIFETIME_FILE: &str = "lifetime.json";
+/// How often the counters are saved while mining.
+pub const LIFETIME_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+/// The file format this build writes, and the newest it reads.
+const LIFETIME_VERSION: u32 = 1;
+/// Saves queued for the writer. A save that finds the queue full is dropped, the next one
+/// carries its counters too.
+const WRITER_CAPACITY: usize = 4;
+
+/// What one miner did, since the process started or over every run.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LifetimeCounters {
+    #[serde(default)]
+    pub runtime_secs: u64,
+    /// Hashes computed, the idle ones included.
+    #[serde(default)]
+    pub hashes: u64,
+    #[serde(default)]
+    pub shares_found: u64,
+    #[serde(default)]
+    pub shares_submitted: u64,
+    /// Dropped because they no longer met the pool's target.
+    #[serde(default)]
+    pub shares_stale: u64,
+    /// Dropped by `--max-submit-rate`.
+    #[serde(default)]
+    pub shares_rate_limited: u64,
+    /// Dropped by `--discard-shares-on-stop`.
+    #[serde(default)]
+    pub shares_discarded_on_stop: u64,
+    /// Suspicious shares that did not meet their job's target.
+    #[serde(default)]
+    pub shares_suspicious_dropped: u64,
+    /// Pool sessions after the first one of each process.
+    #[serde(default)]
+    pub reconnects: u64,
+}
+
+impl LifetimeCounters {
+    /// The counters of the process so far.
+    pub fn from_stats(stats: &MinerStats) -> Self {
+        Self {
+            runtime_secs: stats.runtime_secs,
+            hashes: stats.hashes,
+            shares_found: stats.shares_found,
+            shares_submitted: stats.shares_submitted,
+            shares_stale: stats.shares_below_target,
+            shares_rate_limited: stats.shares_rate_limited,
+            shares_discarded_on_stop: stats.shares_discarded_on_stop,
+            shares_suspicious_dropped: stats.suspicious_shares_dropped,
+            reconnects: stats.sessions_started.saturating_sub(1),
+        }
+    }
+
+    pub fn plus(&self, other: &Self) -> Self {
+        Self {
+            runtime_secs: self.runtime_secs.saturating_add(other.runtime_secs),
+            hashes: self.hashes.saturating_add(other.hashes),
+            shares_found: self.shares_found.saturating_add(other.shares_found),
+            shares_submitted: self.shares_submitted.saturating_add(other.shares_submitted),
+            shares_stale: self.shares_stale.saturating_add(other.shares_stale),
+            shares_rate_limited: self
+                .shares_rate_limited
+                .saturating_add(other.shares_rate_limited),
+            shares_discarded_on_stop: self
+                .shares_discarded_on_stop
+                .saturating_add(other.shares_discarded_on_stop),
+            shares_suspicious_dropped: self
+                .shares_suspicious_dropped
+                .saturating_add(other.shares_suspicious_dropped),
+            reconnects: self.reconnects.saturating_add(other.reconnects),
+        }
+    }
+}
+
+/// The counters of a miner with `--data-dir`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LifetimeStats {
+    /// Since the process started.
+    pub process: LifetimeCounters,
+    /// Over every run with the same `--data-dir`, this one included.
+    pub total: LifetimeCounters,
+}
+
+/// The body of [`LIFETIME_FILE`].
+#[derive(Debug, Serialize, Deserialize)]
+struct LifetimeFile {
+    version: u32,
+    /// Blake3 of `miners` as compact JSON, in hex.
+    checksum: String,
+    /// By miner label, the empty label for a single `--pool`.
+    miners: BTreeMap<String, LifetimeCounters>,
+}
+
+fn checksum(miners: &BTreeMap<String, LifetimeCounters>) -> Result<String> {
+    Ok(blake3::hash(&serde_json::to_vec(miners)?)
+        .to_hex()
+        .to_string())
+}
+
+/// The counters saved at `path`, `None` if there is no file. Fails on a file that does not
+/// parse, is of a newer version or does not match its checksum.
+pub fn load_lifetime(path: &Path) -> Result<Option<BTreeMap<String, LifetimeCounters>>> {
+    let contents = match fs::read(path) {
+        Ok(contents) => contents,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(error) => return Err(error.into()),
+    };
+    let file: LifetimeFile = serde_json::from_slice(&contents)?;
+    if file.version > LIFETIME_VERSION {
+        return Err(anyhow!(
+            "version {} is newer than this build reads ({})",
+            file.version,
+            LIFETIME_VERSION
+        ));
+    }
+    if checksum(&file.miners)? != file.checksum {
+        return Err(anyhow!("the checksum does not match the counters"));
+    }
+    Ok(Some(file.miners))
+}
+
+/// Writes the counters next to `path` first and renames them over it, so that a crash while
+/// writing leaves the previous save.
+pub fn save_lifetime(path: &Path, miners: BTreeMap<String, LifetimeCounters>) -> Result<()> {
+    let file = LifetimeFile {
+        version: LIFETIME_VERSION,
+        checksum: checksum(&miners)?,
+        miners,
+    };
+    let partial = path.with_extension("json.partial");
+    fs::write(&partial, serde_json::to_vec_pretty(&file)?)?;
+    fs::rename(&partial, path)?;
+    Ok(())
+}
+
+#[derive(Debug)]
+enum WriteRequest {
+    Save(BTreeMap<String, LifetimeCounters>),
+    /// Answered once the saves before it are written.
+    Flush(oneshot::Sender<()>),
+}
+
+/// The counters of the previous runs, and the task that saves the totals.
+#[derive(Debug)]
+pub struct LifetimeStore {
+    path: PathBuf,
+    previous: BTreeMap<String, LifetimeCounters>,
+    writer: mpsc::Sender<WriteRequest>,
+}
+
+impl LifetimeStore {
+    /// Creates `data_dir` if needed, loads the counters saved there and starts the writer.
+    pub fn open(data_dir: &Path) -> Result<Self> {
+        fs::create_dir_all(data_dir)
+            .map_err(|error| anyhow!("failed to create {}: {}", data_dir.display(), error))?;
+        let path = data_dir.join(LIFETIME_FILE);
+        let previous = match load_lifetime(&path) {
+            Ok(Some(previous)) => {
+                info!("lifetime counters loaded from {}", path.display());
+                previous
+            }
+            Ok(None) => Default::default(),
+            Err(error) => {
+                let aside = path.with_extension("json.corrupt");
+                warn!(
+                    "{} is corrupt, the lifetime counters start over and the file is kept as {}: {}",
+                    path.display(),
+                    aside.display(),
+                    error
+                );
+                if let Err(error) = fs::rename(&path, &aside) {
+                    warn!("failed to move {} aside: {}", path.display(), error);
+                }
+                Default::default()
+            }
+        };
+        let (writer, requests) = mpsc::channel(WRITER_CAPACITY);
+        tokio::spawn(Self::write(path.clone(), requests));
+        Ok(Self {
+            path,
+            previous,
+            writer,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The counters of the miner `label`, from what its process did so far.
+    pub fn stats(&self, label: &str, process: LifetimeCounters) -> LifetimeStats {
+        let total = match self.previous.get(label) {
+            Some(previous) => previous.plus(&process),
+            None => process.clone(),
+        };
+        LifetimeStats { process, total }
+    }
+
+    /// Queues the totals of `stats` for saving, without waiting for the write.
+    pub fn save(&self, stats: &[MinerStats]) {
+        if self
+            .writer
+            .try_send(WriteRequest::Save(self.totals(stats)))
+            .is_err()
+        {
+            debug!("lifetime counters not saved, the writer is behind");
+        }
+    }
+
+    /// Saves the totals of `stats` and waits until they are written, e.g. before exiting.
+    pub async fn save_and_flush(&self, stats: &[MinerStats]) {
+        let (done, written) = oneshot::channel();
+        if self
+            .writer
+            .send(WriteRequest::Save(self.totals(stats)))
+            .await
+            .is_err()
+            || self.writer.send(WriteRequest::Flush(done)).await.is_err()
+        {
+            return;
+        }
+        let _ = written.await;
+    }
+
+    /// The totals of `stats` by label. Miners without lifetime stats, or of a previous run
+    /// only, keep what was saved of them.
+    fn totals(&self, stats: &[MinerStats]) -> BTreeMap<String, LifetimeCounters> {
+        let mut miners = self.previous.clone();
+        for stats in stats {
+            if let Some(lifetime) = &stats.lifetime {
+                miners.insert(stats.label.clone(), lifetime.total.clone());
+            }
+        }
+        miners
+    }
+
+    async fn write(path: PathBuf, mut requests: mpsc::Receiver<WriteRequest>) {
+        while let Some(request) = requests.recv().await {
+            match request {
+                WriteRequest::Save(miners) => {
+                    let target = path.clone();
+                    let written =
+                        task::spawn_blocking(move || save_lifetime(&target, miners)).await;
+                    match written {
+                        Ok(Ok(())) => trace!("lifetime counters saved"),
+                        Ok(Err(error)) => {
+                            warn!(
+                                "failed to save the lifetime counters to {}: {}",
+                                path.display(),
+                                error
+                            )
+                        }
+                        Err(error) => error!("lifetime counters writer failed: {}", error),
+                    }
+                }
+                WriteRequest::Flush(done) => {
+                    let _ = done.send(());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TestMinerBuilder;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "ironminer-lifetime-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn counters(shares_found: u64) -> LifetimeCounters {
+        LifetimeCounters {
+            runtime_secs: 60,
+            hashes: 1_000_000,
+            shares_found,
+            shares_submitted: shares_found,
+            reconnects: 1,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_save_and_load() {
+        let dir = test_dir("cycle");
+        let store = LifetimeStore::open(&dir).unwrap();
+        assert_eq!(store.stats("", counters(3)).total, counters(3));
+
+        // the first process saves on shutdown
+        let miner = TestMinerBuilder::new("127.0.0.1:8181".parse().unwrap())
+            .build()
+            .await
+            .unwrap();
+        let mut stats = miner.stats().await;
+        stats.lifetime = Some(store.stats("", counters(3)));
+        store.save_and_flush(&[stats]).await;
+
+        // the next one starts from there
+        let store = LifetimeStore::open(&dir).unwrap();
+        let lifetime = store.stats("", counters(2));
+        assert_eq!(lifetime.process, counters(2));
+        assert_eq!(
+            lifetime.total,
+            LifetimeCounters {
+                runtime_secs: 120,
+                hashes: 2_000_000,
+                shares_found: 5,
+                shares_submitted: 5,
+                reconnects: 2,
+                ..Default::default()
+            }
+        );
+        // a miner it has not seen starts at its own counters
+        assert_eq!(store.stats("other", counters(1)).total, counters(1));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_corrupted_file() {
+        let dir = test_dir("corrupt");
+        let path = dir.join(LIFETIME_FILE);
+        fs::create_dir_all(&dir).unwrap();
+        save_lifetime(&path, BTreeMap::from([(String::new(), counters(3))])).unwrap();
+        assert_eq!(
+            load_lifetime(&path).unwrap(),
+            Some(BTreeMap::from([(String::new(), counters(3))]))
+        );
+
+        // a count changed on disk no longer matches the checksum
+        let tampered = fs::read_to_string(&path)
+            .unwrap()
+            .replace("\"shares_found\": 3", "\"shares_found\": 300");
+        fs::write(&path, tampered).unwrap();
+        assert!(load_lifetime(&path).is_err());
+        let store = LifetimeStore::open(&dir).unwrap();
+        assert_eq!(store.stats("", counters(1)).total, counters(1));
+        assert!(!path.exists());
+        assert!(path.with_extension("json.corrupt").exists());
+
+        // and so does a file cut short by a crash
+        fs::write(&path, "{\"version\":1,\"checks").unwrap();
+        assert!(load_lifetime(&path).is_err());
+        assert_eq!(load_lifetime(&dir.join("missing.json")).unwrap(), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
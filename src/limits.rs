@@ -0,0 +1,274 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! Caps on everything the crate keeps around between messages, so that a long run on a flaky
+//! network can not grow without bound. A full buffer drops its oldest entry to make room and
+//! counts the drop, the counts are in `/stats`.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashSet, VecDeque},
+    hash::Hash,
+};
+
+/// Shares whose write failed, re-sent after the next subscribe.
+pub const MAX_PENDING_SUBMITS: usize = 64;
+/// Shares found while disconnected with `--mine-through-disconnects`.
+pub const MAX_HELD_SUBMITS: usize = 64;
+/// Shares remembered to drop the ones found twice.
+pub const MAX_RECENT_SHARES: usize = 4096;
+/// Closed pool sessions kept for the stats api, whatever `--session-history` asks for.
+pub const MAX_SESSION_HISTORY: usize = 1000;
+/// Sessions the time to first share is taken over.
+pub const MAX_FIRST_SHARES: usize = 100;
+pub const MAX_DISCONNECTS: usize = 20;
+/// Closed pool connections kept for `/stats/connections`.
+pub const MAX_CONNECTIONS: usize = 20;
+/// Number of one-second hashrate samples kept for graphing (about an hour).
+pub const METER_HISTORY_SECONDS: usize = 4096;
+/// Number of one-minute hashrate samples kept for graphing (about a day and a half).
+pub const METER_HISTORY_MINUTES: usize = 2048;
+/// Shares the proxy remembers to drop the copies other workers find, a few minutes worth at
+/// the default submit rate of every worker.
+pub const MAX_RELAYED_SHARES: usize = 1024;
+
+/// How full a buffer is.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BufferUsage {
+    pub len: usize,
+    pub cap: usize,
+    /// Entries dropped to make room for newer ones.
+    pub dropped: u64,
+}
+
+/// The buffers of one miner.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BufferStats {
+    pub pending_submits: BufferUsage,
+    pub held_submits: BufferUsage,
+    pub recent_shares: BufferUsage,
+    pub session_history: BufferUsage,
+    pub first_shares: BufferUsage,
+    pub disconnects: BufferUsage,
+    #[serde(default)]
+    pub connections: BufferUsage,
+    pub meter_history_1s: BufferUsage,
+    pub meter_history_1m: BufferUsage,
+}
+
+impl BufferStats {
+    fn all(&self) -> [BufferUsage; 9] {
+        [
+            self.pending_submits,
+            self.held_submits,
+            self.recent_shares,
+            self.session_history,
+            self.first_shares,
+            self.disconnects,
+            self.connections,
+            self.meter_history_1s,
+            self.meter_history_1m,
+        ]
+    }
+
+    /// Whether every buffer is within its cap.
+    pub fn within_caps(&self) -> bool {
+        self.all().iter().all(|usage| usage.len <= usage.cap)
+    }
+
+    /// Entries dropped across all buffers.
+    pub fn dropped(&self) -> u64 {
+        self.all().iter().map(|usage| usage.dropped).sum()
+    }
+}
+
+/// A queue that drops its oldest entry when pushed to while full.
+#[derive(Clone, Debug)]
+pub struct BoundedQueue<T> {
+    items: VecDeque<T>,
+    cap: usize,
+    dropped: u64,
+}
+
+impl<T> BoundedQueue<T> {
+    pub fn new(cap: usize) -> Self {
+        Self {
+            items: VecDeque::new(),
+            cap,
+            dropped: 0,
+        }
+    }
+
+    /// Appends `item`, returns the entry dropped to make room for it. A queue with a cap of
+    /// zero keeps nothing.
+    pub fn push(&mut self, item: T) -> Option<T> {
+        if self.cap == 0 {
+            self.dropped += 1;
+            return Some(item);
+        }
+        let dropped = if self.items.len() >= self.cap {
+            self.dropped += 1;
+            self.items.pop_front()
+        } else {
+            None
+        };
+        self.items.push_back(item);
+        dropped
+    }
+
+    /// Takes every entry out, oldest first. The drop count stays.
+    pub fn take(&mut self) -> VecDeque<T> {
+        std::mem::take(&mut self.items)
+    }
+
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &T> + ExactSizeIterator {
+        self.items.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn usage(&self) -> BufferUsage {
+        BufferUsage {
+            len: self.items.len(),
+            cap: self.cap,
+            dropped: self.dropped,
+        }
+    }
+}
+
+impl<T> std::ops::Index<usize> for BoundedQueue<T> {
+    type Output = T;
+
+    fn index(&self, index: usize) -> &T {
+        &self.items[index]
+    }
+}
+
+impl<T> std::ops::IndexMut<usize> for BoundedQueue<T> {
+    fn index_mut(&mut self, index: usize) -> &mut T {
+        &mut self.items[index]
+    }
+}
+
+/// A set that forgets its oldest entry when inserted into while full.
+#[derive(Clone, Debug)]
+pub struct RecentSet<T> {
+    order: BoundedQueue<T>,
+    items: HashSet<T>,
+}
+
+impl<T: Clone + Eq + Hash> RecentSet<T> {
+    pub fn new(cap: usize) -> Self {
+        Self {
+            order: BoundedQueue::new(cap),
+            items: HashSet::new(),
+        }
+    }
+
+    /// Adds `item`, returns whether it was not in the set yet.
+    pub fn insert(&mut self, item: T) -> bool {
+        if self.items.contains(&item) {
+            return false;
+        }
+        match self.order.push(item.clone()) {
+            // no room at all
+            Some(dropped) if dropped == item => {}
+            dropped => {
+                if let Some(dropped) = dropped {
+                    self.items.remove(&dropped);
+                }
+                self.items.insert(item);
+            }
+        }
+        true
+    }
+
+    pub fn contains(&self, item: &T) -> bool {
+        self.items.contains(item)
+    }
+
+    /// Forgets every entry. The drop count stays.
+    pub fn clear(&mut self) {
+        self.order.take();
+        self.items.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn usage(&self) -> BufferUsage {
+        self.order.usage()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bounded_queue() {
+        let mut queue = BoundedQueue::new(3);
+        assert_eq!(queue.push(1), None);
+        assert_eq!(queue.push(2), None);
+        assert_eq!(queue.push(3), None);
+        assert_eq!(queue.push(4), Some(1));
+        assert_eq!(queue.push(5), Some(2));
+        assert_eq!(queue.iter().copied().collect::<Vec<_>>(), [3, 4, 5]);
+        assert_eq!(
+            queue.usage(),
+            BufferUsage {
+                len: 3,
+                cap: 3,
+                dropped: 2
+            }
+        );
+        assert_eq!(queue.take(), [3, 4, 5]);
+        assert_eq!(queue.usage().dropped, 2);
+        assert!(queue.is_empty());
+
+        let mut none = BoundedQueue::new(0);
+        assert_eq!(none.push(1), Some(1));
+        assert!(none.is_empty());
+        assert_eq!(none.usage().dropped, 1);
+    }
+
+    #[test]
+    fn test_recent_set() {
+        let mut set = RecentSet::new(2);
+        assert!(set.insert(1));
+        assert!(!set.insert(1));
+        assert!(set.insert(2));
+        // the oldest is forgotten, and found new again
+        assert!(set.insert(3));
+        assert!(!set.contains(&1));
+        assert!(set.contains(&2) && set.contains(&3));
+        assert!(set.insert(1));
+        assert_eq!(
+            set.usage(),
+            BufferUsage {
+                len: 2,
+                cap: 2,
+                dropped: 2
+            }
+        );
+        set.clear();
+        assert!(set.is_empty());
+        assert_eq!(set.usage().len, 0);
+
+        let mut none = RecentSet::new(0);
+        assert!(none.insert(1));
+        assert!(!none.contains(&1));
+    }
+}
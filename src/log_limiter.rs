@@ -0,0 +1,150 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::{Clock, SystemClock};
+use log::*;
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Messages a key may log in a burst before it is throttled.
+pub const LOG_BURST: u32 = 5;
+/// The burst refills over this period.
+pub const LOG_WINDOW: Duration = Duration::from_secs(30);
+/// Keys idle for a whole window are forgotten once this many are tracked.
+const MAX_LOG_KEYS: usize = 1024;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum LogDecision {
+    /// Log the message. `suppressed` similar messages were dropped over `period` before it.
+    Emit {
+        suppressed: u64,
+        period: Duration,
+    },
+    Suppress,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+    suppressed: u64,
+    suppressed_since: Option<Instant>,
+}
+
+/// A token bucket per key, used to keep error paths that can fire in a tight loop from
+/// flooding the log. Keys are the call site plus the message, so distinct errors are
+/// throttled separately.
+#[derive(Debug)]
+pub struct LogLimiter<C: Clock = SystemClock> {
+    clock: C,
+    burst: u32,
+    window: Duration,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl Default for LogLimiter {
+    fn default() -> Self {
+        Self::new(SystemClock, LOG_BURST, LOG_WINDOW)
+    }
+}
+
+impl<C: Clock> LogLimiter<C> {
+    pub fn new(clock: C, burst: u32, window: Duration) -> Self {
+        Self {
+            clock,
+            burst,
+            window,
+            buckets: Default::default(),
+        }
+    }
+
+    pub fn check(&self, key: &str) -> LogDecision {
+        let now = self.clock.now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_LOG_KEYS && !buckets.contains_key(key) {
+            let window = self.window;
+            buckets.retain(|_, bucket| now - bucket.refilled_at < window);
+        }
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: self.burst as f64,
+            refilled_at: now,
+            suppressed: 0,
+            suppressed_since: None,
+        });
+        let refill = (now - bucket.refilled_at).as_secs_f64() / self.window.as_secs_f64()
+            * self.burst as f64;
+        bucket.tokens = (bucket.tokens + refill).min(self.burst as f64);
+        bucket.refilled_at = now;
+        if bucket.tokens < 1.0 {
+            bucket.suppressed += 1;
+            bucket.suppressed_since.get_or_insert(now);
+            return LogDecision::Suppress;
+        }
+        bucket.tokens -= 1.0;
+        let period = bucket
+            .suppressed_since
+            .take()
+            .map(|since| now - since)
+            .unwrap_or_default();
+        LogDecision::Emit {
+            suppressed: std::mem::take(&mut bucket.suppressed),
+            period,
+        }
+    }
+
+    /// Logs `message` at `level` unless `site` has been logging it too often, and tells how
+    /// many similar messages were suppressed since the last one that got through.
+    pub fn log(&self, level: Level, site: &str, message: &str) {
+        if let LogDecision::Emit { suppressed, period } =
+            self.check(&format!("{}:{}", site, message))
+        {
+            if suppressed > 0 {
+                log!(
+                    level,
+                    "suppressed {} similar messages in the last {}s",
+                    suppressed,
+                    period.as_secs().max(1)
+                );
+            }
+            log!(level, "{}", message);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ManualClock;
+
+    #[test]
+    fn test_log_limiter() {
+        let clock = ManualClock::new();
+        let limiter = LogLimiter::new(clock.clone(), 2, Duration::from_secs(30));
+        let emit = |suppressed, period| LogDecision::Emit { suppressed, period };
+
+        assert_eq!(limiter.check("read"), emit(0, Duration::ZERO));
+        assert_eq!(limiter.check("read"), emit(0, Duration::ZERO));
+        for _ in 0..1000 {
+            assert_eq!(limiter.check("read"), LogDecision::Suppress);
+        }
+        // a distinct error still gets through
+        assert_eq!(limiter.check("decode"), emit(0, Duration::ZERO));
+
+        // one token is back after half the window, and it carries the summary
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(limiter.check("read"), LogDecision::Suppress);
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(limiter.check("read"), emit(1001, Duration::from_secs(15)));
+        assert_eq!(limiter.check("read"), LogDecision::Suppress);
+
+        // a quiet key refills to the burst, not beyond
+        clock.advance(Duration::from_secs(300));
+        assert_eq!(limiter.check("read"), emit(1, Duration::from_secs(300)));
+        assert_eq!(limiter.check("read"), emit(0, Duration::ZERO));
+        assert_eq!(limiter.check("read"), LogDecision::Suppress);
+    }
+}
@@ -5,48 +5,110 @@
 use anyhow::Result;
 use clap::Parser;
 use log::*;
-use std::{sync::Arc, time::Duration};
+use std::sync::Arc;
 use tokio::{runtime, sync::oneshot, task};
-use zkwork_ironminer::{cli::Cli, Miner};
+use zkwork_ironminer::{
+    build_runtime, check, check_thread_pools, check_tls_support,
+    cli::{Cli, Command},
+    critical_failure, install_panic_hook, proxy, replay, status, Api, ApiSocket, MinerSet,
+    CONFIG_ERROR_EXIT_CODE,
+};
+#[cfg(unix)]
+use zkwork_ironminer::{inherited_state, take_handover_fd};
 
 fn main() -> Result<()> {
     pretty_env_logger::init_timed();
-    let cli = Cli::parse();
+    install_panic_hook();
+    let mut cli = Cli::parse();
     debug!("cli: {:?}", cli);
-    let (num_tokio_worker_threads, max_tokio_blocking_threads) = (num_cpus::get(), 1024); // 512 is tokio's current default
-
+    if let Some(Command::Status(args)) = &cli.command {
+        let runtime = runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        std::process::exit(runtime.block_on(status::run(args)));
+    }
+    if let Some(Command::Replay(args)) = &cli.command {
+        let runtime = runtime::Builder::new_multi_thread().enable_all().build()?;
+        std::process::exit(runtime.block_on(replay::run(args)));
+    }
+    if cli.check {
+        let runtime = runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        std::process::exit(runtime.block_on(check::run(&cli)));
+    }
+    if let Err(error) = check_tls_support(&cli) {
+        error!("{}", error);
+        std::process::exit(CONFIG_ERROR_EXIT_CODE);
+    }
+    // the environment may only change while this is the only thread
+    #[cfg(unix)]
+    let handover = take_handover_fd().unwrap_or_else(|error| {
+        error!("failed to take over from the previous process: {}", error);
+        None
+    });
     // Initialize the runtime configuration.
-    let runtime = runtime::Builder::new_multi_thread()
-        .enable_all()
-        .thread_stack_size(16 * 1024 * 1024)
-        .worker_threads(num_tokio_worker_threads)
-        .max_blocking_threads(max_tokio_blocking_threads)
-        .build()?;
+    let runtime = build_runtime(&mut cli)?;
+    if cli.proxy_listen.is_none() {
+        if let Err(error) = check_thread_pools(&cli) {
+            error!("{}", error);
+            std::process::exit(CONFIG_ERROR_EXIT_CODE);
+        }
+    }
 
     runtime.block_on(async move {
-        let miner = Miner::initialize(cli).await;
-        let _ = handle_signals(miner.clone()).await;
-        Miner::start(miner.clone()).await.unwrap();
+        if let Some(listen) = cli.proxy_listen {
+            if let Err(error) = proxy::run(&cli, listen).await {
+                error!("{}", error);
+                std::process::exit(1);
+            }
+            return;
+        }
+        let api = cli.api;
+        let api_socket = cli.api_socket.clone();
+        let api_socket_mode = cli.api_socket_mode;
+        let miners = match MinerSet::initialize(cli).await {
+            Ok(miners) => miners,
+            Err(error) => {
+                error!("{}", error);
+                std::process::exit(1);
+            }
+        };
+        #[cfg(unix)]
+        if let Some(fd) = handover {
+            match inherited_state(fd) {
+                Ok(state) => miners.resume(state).await,
+                Err(error) => error!("failed to take over from the previous process: {}", error),
+            }
+        }
+        if let Some(address) = api {
+            if let Err(error) = Api::start(address, miners.clone()).await {
+                error!("failed to start stats api on {}: {}", address, error);
+            }
+        }
+        if let Some(path) = api_socket {
+            if let Err(error) = ApiSocket::start(&path, api_socket_mode, miners.clone()).await {
+                error!("failed to start stats api on {}: {}", path.display(), error);
+            }
+        }
+        let _ = handle_signals(miners.clone()).await;
+        MinerSet::start(miners.clone()).await.unwrap();
     });
     Ok(())
 }
 
 // Handles OS signals for the node to intercept and perform a clean shutdown.
 // Note: Only Ctrl-C is supported; it should work on both Unix-family systems and Windows.
-async fn handle_signals(miner: Arc<Miner>) -> Result<()> {
+// With --upgrade, SIGUSR2 upgrades in place on Unix.
+// A panic in the mining or stratum task, or on a mining thread, takes the same path, with
+// exit code 10, and so does --max-runtime with exit code 0. Each logs the final report last.
+async fn handle_signals(miners: Arc<MinerSet>) -> Result<()> {
     let (router, handler) = oneshot::channel();
     task::spawn(async move {
         let _ = router.send(());
-        match tokio::signal::ctrl_c().await {
-            Ok(()) => {
-                info!("shutdowning...");
-                miner.stop().await;
-                tokio::time::sleep(Duration::from_millis(5000)).await;
-                info!("goodbye");
-                std::process::exit(0);
-            }
-            Err(error) => error!("tokio::signal::ctrl_c encountered an error: {}", error),
-        }
+        let exit_code = miners.shutdown_on(critical_failure()).await;
+        info!("goodbye");
+        std::process::exit(exit_code);
     });
     let _ = handler.await;
     debug!("install signals handle");
@@ -2,99 +2,569 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 
-use futures::channel::oneshot;
+use crate::{
+    supervise, BoundedQueue, BufferUsage, Clock, RestartPolicy, SharedClock, SystemClock,
+    METER_HISTORY_MINUTES, METER_HISTORY_SECONDS,
+};
+use anyhow::{anyhow, Result};
 use log::*;
-use ringbuffer::{AllocRingBuffer, RingBuffer, RingBufferExt, RingBufferWrite};
+use serde::{Deserialize, Serialize};
 use std::{
+    collections::VecDeque,
+    f64::consts::LN_2,
+    fmt,
+    str::FromStr,
     sync::{
         atomic::{AtomicBool, AtomicU64, Ordering},
         Arc,
     },
     time::Duration,
 };
-use tokio::{
-    sync::RwLock,
-    task,
-    time::{self, Instant},
-};
+use tokio::{sync::RwLock, time::Instant};
+
+/// A sample is irregular when the time since the previous one is off the tick by more than
+/// this factor, either way, as after the process was suspended or a tick came late.
+const IRREGULAR_TICK_FACTOR: u32 = 2;
+/// An irregular sample's rate is capped at this multiple of the recent regular rate.
+const MAX_IRREGULAR_RATE_MULTIPLE: f64 = 4.0;
+/// Regular samples the cap is taken from.
+const RECENT_REGULAR_SAMPLES: usize = 60;
 
+/// The average of the last `len` samples.
 #[derive(Debug)]
 pub struct RollingAverage {
-    container: AllocRingBuffer<f64>,
-    average: f64,
-    out_of_date: bool,
+    container: VecDeque<f64>,
+    len: usize,
+    sum: f64,
 }
 
 impl RollingAverage {
     pub fn new(len: usize) -> Self {
         RollingAverage {
-            container: AllocRingBuffer::with_capacity(len),
-            average: 0.0,
-            out_of_date: false,
+            container: VecDeque::with_capacity(len),
+            len,
+            sum: 0.0,
         }
     }
 
     pub fn average(&self) -> f64 {
-        if !self.out_of_date {
-            self.average
+        if self.container.is_empty() {
+            0.0
         } else {
-            let mut sum = 0.0;
-            for &v in self.container.iter() {
-                sum += v;
-            }
-            sum / self.container.len() as f64
+            self.sum / self.container.len() as f64
         }
     }
 
     pub fn add(&mut self, val: f64) {
-        self.out_of_date = true;
-        self.container.push(val);
+        if self.container.len() == self.len {
+            if let Some(oldest) = self.container.pop_front() {
+                self.sum -= oldest;
+            }
+        }
+        self.container.push_back(val);
+        self.sum += val;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.container.is_empty()
     }
 
     pub fn reset(&mut self) {
         self.container.clear();
-        self.average = 0.0;
-        self.out_of_date = false;
+        self.sum = 0.0;
     }
 }
 
+/// An exponential moving average over samples that may come at uneven intervals.
+#[derive(Debug)]
+pub struct Ema {
+    half_life: Duration,
+    value: Option<f64>,
+}
+
+impl Ema {
+    pub fn new(half_life: Duration) -> Self {
+        Ema {
+            half_life,
+            value: None,
+        }
+    }
+
+    /// Adds a sample taken `elapsed` after the previous one.
+    pub fn add(&mut self, val: f64, elapsed: Duration) {
+        self.value = Some(match self.value {
+            Some(value) => {
+                let alpha = 1.0 - 0.5f64.powf(elapsed.as_secs_f64() / self.half_life.as_secs_f64());
+                value + alpha * (val - value)
+            }
+            None => val,
+        });
+    }
+
+    pub fn average(&self) -> f64 {
+        self.value.unwrap_or_default()
+    }
+}
+
+/// How a hashrate window turns the samples into a rate.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MeterKind {
+    /// The plain average of the samples in the window.
+    Rolling,
+    /// An exponential moving average, a sample weighs half as much after `half_life`.
+    Ema { half_life: Duration },
+}
+
+/// A hashrate window, e.g. "1m" or "15m:ema".
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MeterWindow {
+    pub duration: Duration,
+    pub kind: MeterKind,
+}
+
+impl MeterWindow {
+    pub fn rolling(duration: Duration) -> Self {
+        MeterWindow {
+            duration,
+            kind: MeterKind::Rolling,
+        }
+    }
+
+    /// An average with the same mean sample age as the rolling average over `duration`, but
+    /// without the step when a sample drops out of the window.
+    pub fn ema(duration: Duration) -> Self {
+        MeterWindow {
+            duration,
+            kind: MeterKind::Ema {
+                half_life: duration.mul_f64(LN_2 / 2.0),
+            },
+        }
+    }
+
+    /// Number of samples a rolling average keeps to cover the window at one sample per `tick`.
+    pub fn samples(&self, tick: Duration) -> usize {
+        ((self.duration.as_secs_f64() / tick.as_secs_f64()).ceil() as usize).max(1)
+    }
+
+    fn estimator(&self, tick: Duration) -> Estimator {
+        match self.kind {
+            MeterKind::Rolling => Estimator::Rolling(RollingAverage::new(self.samples(tick))),
+            MeterKind::Ema { half_life } => Estimator::Ema(Ema::new(half_life)),
+        }
+    }
+}
+
+impl fmt::Display for MeterWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secs = self.duration.as_secs();
+        match secs {
+            secs if secs > 0 && secs % 3600 == 0 => write!(f, "{}h", secs / 3600)?,
+            secs if secs > 0 && secs % 60 == 0 => write!(f, "{}m", secs / 60)?,
+            secs => write!(f, "{}s", secs)?,
+        }
+        if let MeterKind::Ema { .. } = self.kind {
+            write!(f, " ema")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for MeterWindow {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (duration, ema) = match s.trim().split_once(':') {
+            Some((duration, "ema")) => (duration, true),
+            Some((_, kind)) => return Err(anyhow!("unknown hashrate window kind '{}'", kind)),
+            None => (s.trim(), false),
+        };
+        let unit = match duration.chars().last() {
+            Some('s') => 1,
+            Some('m') => 60,
+            Some('h') => 3600,
+            _ => return Err(anyhow!("hashrate window '{}' needs a unit, s, m or h", s)),
+        };
+        let value: u64 = duration[..duration.len() - 1]
+            .parse()
+            .map_err(|_| anyhow!("invalid hashrate window '{}'", s))?;
+        if value == 0 {
+            return Err(anyhow!("hashrate window '{}' must not be empty", s));
+        }
+        let duration = Duration::from_secs(value * unit);
+        Ok(if ema {
+            MeterWindow::ema(duration)
+        } else {
+            MeterWindow::rolling(duration)
+        })
+    }
+}
+
+/// The parsed value of `--hashrate-windows`, e.g. "10s,1m,15m:ema".
+#[derive(Clone, Debug, PartialEq)]
+pub struct MeterWindows(pub Vec<MeterWindow>);
+
+impl Default for MeterWindows {
+    fn default() -> Self {
+        MeterWindows(
+            [5, 60, 300]
+                .into_iter()
+                .map(|secs| MeterWindow::rolling(Duration::from_secs(secs)))
+                .collect(),
+        )
+    }
+}
+
+impl FromStr for MeterWindows {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let windows = s
+            .split(',')
+            .map(str::trim)
+            .filter(|window| !window.is_empty())
+            .map(str::parse)
+            .collect::<Result<Vec<_>>>()?;
+        if windows.is_empty() {
+            return Err(anyhow!("at least one hashrate window is needed"));
+        }
+        Ok(MeterWindows(windows))
+    }
+}
+
+/// The unit hashrates are printed in, see `--hashrate-unit`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HashrateUnit {
+    /// The largest unit the rate has at least one of.
+    #[default]
+    Auto,
+    H,
+    KH,
+    MH,
+    GH,
+    TH,
+    PH,
+}
+
+impl HashrateUnit {
+    const FIXED: [Self; 6] = [Self::H, Self::KH, Self::MH, Self::GH, Self::TH, Self::PH];
+
+    fn scale(self) -> f64 {
+        match self {
+            Self::Auto | Self::H => 1.0,
+            Self::KH => 1e3,
+            Self::MH => 1e6,
+            Self::GH => 1e9,
+            Self::TH => 1e12,
+            Self::PH => 1e15,
+        }
+    }
+
+    fn symbol(self) -> &'static str {
+        match self {
+            Self::Auto | Self::H => "H/s",
+            Self::KH => "KH/s",
+            Self::MH => "MH/s",
+            Self::GH => "GH/s",
+            Self::TH => "TH/s",
+            Self::PH => "PH/s",
+        }
+    }
+
+    /// The fixed unit `rate` is printed in.
+    fn resolve(self, rate: f64) -> Self {
+        match self {
+            Self::Auto => Self::FIXED
+                .into_iter()
+                .rev()
+                .find(|unit| rate >= unit.scale())
+                .unwrap_or(Self::H),
+            unit => unit,
+        }
+    }
+}
+
+impl fmt::Display for HashrateUnit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Auto => "auto",
+            Self::H => "h",
+            Self::KH => "kh",
+            Self::MH => "mh",
+            Self::GH => "gh",
+            Self::TH => "th",
+            Self::PH => "ph",
+        })
+    }
+}
+
+impl FromStr for HashrateUnit {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        [Self::Auto]
+            .into_iter()
+            .chain(Self::FIXED)
+            .find(|unit| unit.to_string().eq_ignore_ascii_case(s))
+            .ok_or_else(|| {
+                anyhow!(
+                    "unknown hashrate unit '{}', expected auto, h, kh, mh, gh, th or ph",
+                    s
+                )
+            })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct MeterConfig {
+    /// How often the hashrate is sampled.
+    pub tick: Duration,
+    pub windows: Vec<MeterWindow>,
+}
+
+impl Default for MeterConfig {
+    fn default() -> Self {
+        MeterConfig {
+            tick: Duration::from_secs(1),
+            windows: MeterWindows::default().0,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Estimator {
+    Rolling(RollingAverage),
+    Ema(Ema),
+}
+
+impl Estimator {
+    fn add(&mut self, val: f64, elapsed: Duration) {
+        match self {
+            Estimator::Rolling(average) => average.add(val),
+            Estimator::Ema(ema) => ema.add(val, elapsed),
+        }
+    }
+
+    fn average(&self) -> f64 {
+        match self {
+            Estimator::Rolling(average) => average.average(),
+            Estimator::Ema(ema) => ema.average(),
+        }
+    }
+}
+
+/// The hashrate over one configured window.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct WindowRate {
+    /// The window as configured, e.g. "1m" or "15m ema".
+    pub window: String,
+    pub rate: f64,
+}
+
+/// A `(unix timestamp in seconds, hashes per second)` pair.
+pub type HistorySample = (u64, f64);
+
+/// The resolution of a hashrate history series.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HistoryWindow {
+    Second,
+    Minute,
+}
+
+impl FromStr for HistoryWindow {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "1s" => Ok(HistoryWindow::Second),
+            "1m" => Ok(HistoryWindow::Minute),
+            _ => Err(anyhow::anyhow!("unknown history window '{}'", s)),
+        }
+    }
+}
+
+/// What a meter carries over from one sample to the next.
+#[derive(Debug)]
+struct Ticker {
+    last_now: Instant,
+    last_timestamp: u64,
+    minute_sum: f64,
+    minute_samples: usize,
+    minute_elapsed: Duration,
+    /// A sample was skipped for no time having passed, the next one is irregular.
+    skipped: bool,
+    /// The rates of the last regular samples.
+    recent: RollingAverage,
+}
+
+impl Ticker {
+    fn new(now: Instant) -> Self {
+        Ticker {
+            last_now: now,
+            last_timestamp: 0,
+            minute_sum: 0.0,
+            minute_samples: 0,
+            minute_elapsed: Duration::ZERO,
+            skipped: false,
+            recent: RollingAverage::new(RECENT_REGULAR_SAMPLES),
+        }
+    }
+}
+
+/// Counts events, hashes by default, and turns them into rates once per tick.
 #[derive(Debug)]
 pub struct Meter {
     started: AtomicBool,
+    tick: Duration,
+    clock: SharedClock,
+    ticker: RwLock<Ticker>,
     rate_1s: RwLock<RollingAverage>,
-    rate_5s: RwLock<RollingAverage>,
-    rate_1m: RwLock<RollingAverage>,
-    rate_5m: RwLock<RollingAverage>,
+    windows: RwLock<Vec<(MeterWindow, Estimator)>>,
     rate_average: RwLock<RollingAverage>,
+    /// Whether each sample was irregular, see [`Self::sample`].
+    history_1s: RwLock<BoundedQueue<(HistorySample, bool)>>,
+    history_1m: RwLock<BoundedQueue<HistorySample>>,
     count: AtomicU64,
 }
 impl Meter {
     pub fn new() -> Arc<Self> {
+        Self::with_config(MeterConfig::default())
+    }
+
+    pub fn with_config(config: MeterConfig) -> Arc<Self> {
+        Self::with_clock(config, SystemClock::shared())
+    }
+
+    /// A meter that ticks on `clock`.
+    pub fn with_clock(config: MeterConfig, clock: SharedClock) -> Arc<Self> {
         Arc::new(Meter {
             started: Default::default(),
+            tick: config.tick,
+            ticker: RwLock::new(Ticker::new(Instant::from_std(clock.now()))),
+            clock,
             rate_1s: RwLock::new(RollingAverage::new(1)),
-            rate_5s: RwLock::new(RollingAverage::new(8)),
-            rate_1m: RwLock::new(RollingAverage::new(64)),
-            rate_5m: RwLock::new(RollingAverage::new(512)),
+            windows: RwLock::new(
+                config
+                    .windows
+                    .iter()
+                    .map(|window| (*window, window.estimator(config.tick)))
+                    .collect(),
+            ),
             rate_average: RwLock::new(RollingAverage::new(128)),
+            history_1s: RwLock::new(BoundedQueue::new(METER_HISTORY_SECONDS)),
+            history_1m: RwLock::new(BoundedQueue::new(METER_HISTORY_MINUTES)),
             count: Default::default(),
         })
     }
 
+    /// Returns the recorded samples of `window`, oldest first.
+    pub async fn history(&self, window: HistoryWindow) -> Vec<HistorySample> {
+        match window {
+            HistoryWindow::Second => self
+                .history_1s
+                .read()
+                .await
+                .iter()
+                .map(|&(sample, _)| sample)
+                .collect(),
+            HistoryWindow::Minute => self.history_1m.read().await.iter().copied().collect(),
+        }
+    }
+
+    /// The timestamps of the one-second samples that were irregular, oldest first.
+    pub async fn irregular_samples(&self) -> Vec<u64> {
+        self.history_1s
+            .read()
+            .await
+            .iter()
+            .filter(|&&(_, irregular)| irregular)
+            .map(|&((timestamp, _), _)| timestamp)
+            .collect()
+    }
+
+    /// How full the history of `window` is.
+    pub async fn history_usage(&self, window: HistoryWindow) -> BufferUsage {
+        match window {
+            HistoryWindow::Second => self.history_1s.read().await.usage(),
+            HistoryWindow::Minute => self.history_1m.read().await.usage(),
+        }
+    }
+
+    /// Averages `samples` into at most `points` consecutive buckets. Each bucket is stamped
+    /// with the timestamp of its newest sample.
+    pub fn downsample(samples: &[HistorySample], points: usize) -> Vec<HistorySample> {
+        if points == 0 || samples.len() <= points {
+            return samples.to_vec();
+        }
+        (0..points)
+            .map(|i| {
+                let bucket = &samples[i * samples.len() / points..(i + 1) * samples.len() / points];
+                let sum: f64 = bucket.iter().map(|&(_, rate)| rate).sum();
+                (bucket[bucket.len() - 1].0, sum / bucket.len() as f64)
+            })
+            .collect()
+    }
+
+    /// The timestamps of the [`Self::downsample`] points whose bucket has a sample stamped
+    /// with one of `irregular`.
+    pub fn downsample_irregular(
+        samples: &[HistorySample],
+        points: usize,
+        irregular: &[u64],
+    ) -> Vec<u64> {
+        let points = if points == 0 {
+            samples.len()
+        } else {
+            points.min(samples.len())
+        };
+        (0..points)
+            .filter_map(|i| {
+                let bucket = &samples[i * samples.len() / points..(i + 1) * samples.len() / points];
+                bucket
+                    .iter()
+                    .any(|(timestamp, _)| irregular.contains(timestamp))
+                    .then(|| bucket[bucket.len() - 1].0)
+            })
+            .collect()
+    }
+
     pub async fn get_rate_1s(&self) -> f64 {
         self.rate_1s.read().await.average()
     }
 
-    pub async fn get_rate_5s(&self) -> f64 {
-        self.rate_5s.read().await.average()
+    /// The rate of every configured window, in configuration order.
+    pub async fn rates(&self) -> Vec<WindowRate> {
+        self.windows
+            .read()
+            .await
+            .iter()
+            .map(|(window, estimator)| WindowRate {
+                window: window.to_string(),
+                rate: estimator.average(),
+            })
+            .collect()
     }
 
-    pub async fn get_rate_1m(&self) -> f64 {
-        self.rate_1m.read().await.average()
+    /// Average of the last hour of one-second samples, or of what has been recorded so far.
+    pub async fn get_rate_1h(&self) -> f64 {
+        self.rate_over(Duration::from_secs(3600)).await
     }
 
-    pub async fn get_rate_5m(&self) -> f64 {
-        self.rate_5m.read().await.average()
+    /// Average of the one-second samples of the last `window`, whatever windows are configured.
+    /// Irregular samples are left out.
+    pub async fn rate_over(&self, window: Duration) -> f64 {
+        let history = self.history_1s.read().await;
+        let samples = history.len().min(window.as_secs() as usize);
+        let (sum, regular) = history
+            .iter()
+            .skip(history.len() - samples)
+            .filter(|&&(_, irregular)| !irregular)
+            .fold((0.0, 0), |(sum, regular), &((_, rate), _)| {
+                (sum + rate, regular + 1)
+            });
+        if regular == 0 {
+            return 0.0;
+        }
+        sum / regular as f64
     }
 
     pub async fn get_avg(&self) -> f64 {
@@ -109,39 +579,99 @@ impl Meter {
         self.rate_average.write().await.add(count as f64);
     }
 
+    /// Starts sampling the meter on a ticker of its own. Meters of a [`MeterRegistry`] are
+    /// sampled by the registry instead.
     pub async fn start(meter: Arc<Meter>) {
         if meter.started.load(Ordering::Relaxed) {
             return;
         }
-        meter.started.store(true, Ordering::SeqCst);
-        let (router, handler) = oneshot::channel();
-        task::spawn(async move {
-            let _ = router.send(());
-            let mut interval = time::interval(Duration::from_millis(1000));
-            let mut last_now = Instant::now();
-            loop {
-                let _ = interval.tick().await;
-                if !meter.started.load(Ordering::Relaxed) {
-                    break;
-                }
-                // update
-                let now = Instant::now();
-                let count = meter.count.load(Ordering::Relaxed);
-                meter.count.fetch_sub(count, Ordering::SeqCst);
-                let elapse_ms = now.saturating_duration_since(last_now).as_millis() as u64;
-                if elapse_ms == 0 {
-                    continue;
-                }
-                let rate_sec = count / elapse_ms * 1000;
-                meter.rate_1s.write().await.add(rate_sec as f64);
-                meter.rate_5s.write().await.add(rate_sec as f64);
-                meter.rate_1m.write().await.add(rate_sec as f64);
-                meter.rate_5m.write().await.add(rate_sec as f64);
-                last_now = now;
-            }
-            debug!("Meter stop.");
+        meter.begin().await;
+        // a panic here only costs the hashrate figures, the next run starts them over
+        supervise("hashrate meter", RestartPolicy::default(), move || {
+            Self::run(meter.clone())
         });
-        let _ = handler.await;
+    }
+
+    async fn begin(&self) {
+        *self.ticker.write().await = Ticker::new(Instant::from_std(self.clock.now()));
+        self.started.store(true, Ordering::SeqCst);
+    }
+
+    async fn run(meter: Arc<Meter>) {
+        let mut interval = meter.clock.interval(meter.tick);
+        loop {
+            interval.tick().await;
+            if !meter.started.load(Ordering::Relaxed) {
+                break;
+            }
+            meter.sample(Instant::from_std(meter.clock.now())).await;
+        }
+        debug!("Meter stop.");
+    }
+
+    /// Turns what was counted since the previous sample into a rate. A sample taken off the
+    /// tick, or after one skipped for no time having passed, is irregular: its count is still
+    /// spread over the time since the previous sample, but its rate is capped at
+    /// [`MAX_IRREGULAR_RATE_MULTIPLE`] times the recent rate, and it is flagged in the history.
+    pub async fn sample(&self, now: Instant) {
+        let mut ticker = self.ticker.write().await;
+        let elapsed = now.saturating_duration_since(ticker.last_now);
+        if elapsed.as_millis() == 0 {
+            ticker.skipped = true;
+            return;
+        }
+        let count = self.count.swap(0, Ordering::SeqCst);
+        let mut rate_sec = count as f64 / elapsed.as_secs_f64();
+        let irregular = std::mem::take(&mut ticker.skipped)
+            || elapsed > self.tick * IRREGULAR_TICK_FACTOR
+            || elapsed < self.tick / IRREGULAR_TICK_FACTOR;
+        if irregular {
+            let recent = ticker.recent.average();
+            if recent > 0.0 && rate_sec > recent * MAX_IRREGULAR_RATE_MULTIPLE {
+                debug!(
+                    "Meter sample {:.3}s after the previous one capped from {:.2} to {:.2}/s",
+                    elapsed.as_secs_f64(),
+                    rate_sec,
+                    recent * MAX_IRREGULAR_RATE_MULTIPLE
+                );
+                rate_sec = recent * MAX_IRREGULAR_RATE_MULTIPLE;
+            }
+        } else {
+            ticker.recent.add(rate_sec);
+        }
+        self.rate_1s.write().await.add(rate_sec);
+        for (_, estimator) in self.windows.write().await.iter_mut() {
+            estimator.add(rate_sec, elapsed);
+        }
+        ticker.last_now = now;
+        // history, with timestamps that never go backwards even if the clock does
+        let timestamp = self.clock.unix_timestamp().max(ticker.last_timestamp);
+        ticker.last_timestamp = timestamp;
+        self.history_1s
+            .write()
+            .await
+            .push(((timestamp, rate_sec), irregular));
+        ticker.minute_sum += rate_sec;
+        ticker.minute_samples += 1;
+        ticker.minute_elapsed += elapsed;
+        if ticker.minute_elapsed >= Duration::from_secs(60) {
+            self.history_1m
+                .write()
+                .await
+                .push((timestamp, ticker.minute_sum / ticker.minute_samples as f64));
+            ticker.minute_sum = 0.0;
+            ticker.minute_samples = 0;
+            ticker.minute_elapsed = Duration::ZERO;
+        }
+    }
+
+    async fn reading(&self, name: &str) -> MeterReading {
+        MeterReading {
+            name: name.to_string(),
+            rate_1s: self.get_rate_1s().await,
+            rates: self.rates().await,
+            rate_avg: self.get_avg().await,
+        }
     }
 
     pub async fn stop(&self) {
@@ -153,20 +683,184 @@ impl Meter {
     }
 
     pub fn format(hash_rate: f64) -> String {
-        match hash_rate {
-            x if x < 1000.0 => format!("{:3.2} H/s", x),
-            x if x < 1000000.0 => format!("{:3.2} KH/s", x / 1000.0),
-            x if x < 1000000000.0 => format!("{:3.2} MH/s", x / 1000000.0),
-            x if x < 1000000000000.0 => format!("{:3.2} GH/s", x / 1000000000.0),
-            x if x < 1000000000000000.0 => format!("{:3.2} TH/s", x / 1000000000000.0),
-            x => format!("{:.2} PH/s", x / 1000000000000000.0),
+        Self::format_as(hash_rate, HashrateUnit::Auto)
+    }
+
+    /// Formats a hashrate in `unit`, e.g. "312.40 KH/s". NaN is printed as no hashrate.
+    pub fn format_as(hash_rate: f64, unit: HashrateUnit) -> String {
+        let hash_rate = if hash_rate.is_nan() { 0.0 } else { hash_rate };
+        let unit = unit.resolve(hash_rate);
+        format!("{:3.2} {}", hash_rate / unit.scale(), unit.symbol())
+    }
+
+    /// Reads back a hashrate [`Self::format_as`] printed, in H/s. The unit is not case
+    /// sensitive.
+    pub fn parse(s: &str) -> Result<f64> {
+        let invalid = || anyhow!("expected a hashrate like '312.40 KH/s', got '{}'", s);
+        let (value, symbol) = s
+            .trim()
+            .rsplit_once(char::is_whitespace)
+            .ok_or_else(invalid)?;
+        let unit = HashrateUnit::FIXED
+            .into_iter()
+            .find(|unit| unit.symbol().eq_ignore_ascii_case(symbol))
+            .ok_or_else(invalid)?;
+        match value.trim().parse::<f64>() {
+            Ok(value) if value >= 0.0 => Ok(value * unit.scale()),
+            _ => Err(invalid()),
         }
     }
+
+    /// Formats window rates as e.g. "5s 1.20 KH/s, 1m 1.18 KH/s".
+    pub fn format_rates(rates: &[WindowRate], unit: HashrateUnit) -> String {
+        rates
+            .iter()
+            .map(|rate| format!("{} {}", rate.window, Self::format_as(rate.rate, unit)))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// Formats window rates of rare events, like shares, as e.g. "5s 1.20/min, 1m 0.80/min".
+    pub fn format_per_minute(rates: &[WindowRate]) -> String {
+        rates
+            .iter()
+            .map(|rate| format!("{} {:.2}/min", rate.window, rate.rate * 60.0))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// The rates of one meter of a [`MeterRegistry`].
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MeterReading {
+    pub name: String,
+    pub rate_1s: f64,
+    pub rates: Vec<WindowRate>,
+    pub rate_avg: f64,
 }
+
+/// Every meter of a registry, read between two ticks.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct MeterSnapshot {
+    /// Ticks the registry has sampled its meters on.
+    pub ticks: u64,
+    pub meters: Vec<MeterReading>,
+}
+
+impl MeterSnapshot {
+    pub fn get(&self, name: &str) -> Option<&MeterReading> {
+        self.meters.iter().find(|reading| reading.name == name)
+    }
+}
+
+/// Named meters sampled by one ticker task, e.g. the hashrate and the share rate of a miner.
+#[derive(Debug)]
+pub struct MeterRegistry {
+    config: MeterConfig,
+    clock: SharedClock,
+    started: AtomicBool,
+    meters: RwLock<Vec<(String, Arc<Meter>)>>,
+    /// Held for writing while the meters are sampled, so a snapshot sees all or none of a tick.
+    ticks: RwLock<u64>,
+}
+
+impl MeterRegistry {
+    pub fn new(config: MeterConfig) -> Arc<Self> {
+        Self::with_clock(config, SystemClock::shared())
+    }
+
+    /// A registry that ticks, and has its meters tick, on `clock`.
+    pub fn with_clock(config: MeterConfig, clock: SharedClock) -> Arc<Self> {
+        Arc::new(MeterRegistry {
+            config,
+            clock,
+            started: Default::default(),
+            meters: Default::default(),
+            ticks: Default::default(),
+        })
+    }
+
+    /// Adds a meter with the registry's tick and windows. A meter registered twice is shared.
+    pub async fn register(&self, name: &str) -> Arc<Meter> {
+        let mut meters = self.meters.write().await;
+        if let Some((_, meter)) = meters.iter().find(|(registered, _)| registered == name) {
+            return meter.clone();
+        }
+        let meter = Meter::with_clock(self.config.clone(), self.clock.clone());
+        if self.started.load(Ordering::Relaxed) {
+            meter.begin().await;
+        }
+        meters.push((name.to_string(), meter.clone()));
+        meter
+    }
+
+    pub async fn start(registry: Arc<MeterRegistry>) {
+        if registry.started.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        for (_, meter) in registry.meters.read().await.iter() {
+            meter.begin().await;
+        }
+        // a panic here only costs the rate figures, the next run starts them over
+        supervise("meter ticker", RestartPolicy::default(), move || {
+            Self::run(registry.clone())
+        });
+    }
+
+    async fn run(registry: Arc<MeterRegistry>) {
+        let tick = registry.config.tick;
+        let clock = &registry.clock;
+        let mut interval = clock.interval_at(clock.now() + tick, tick);
+        loop {
+            interval.tick().await;
+            if !registry.started.load(Ordering::Relaxed) {
+                break;
+            }
+            registry.sample(Instant::from_std(clock.now())).await;
+        }
+        debug!("Meter registry stop.");
+    }
+
+    /// Samples every meter as of `now`.
+    pub async fn sample(&self, now: Instant) {
+        let mut ticks = self.ticks.write().await;
+        for (_, meter) in self.meters.read().await.iter() {
+            meter.sample(now).await;
+        }
+        *ticks += 1;
+    }
+
+    pub async fn snapshot(&self) -> MeterSnapshot {
+        let ticks = self.ticks.read().await;
+        let mut meters = Vec::new();
+        for (name, meter) in self.meters.read().await.iter() {
+            meters.push(meter.reading(name).await);
+        }
+        MeterSnapshot {
+            ticks: *ticks,
+            meters,
+        }
+    }
+
+    pub async fn stop(&self) {
+        if !self.started.swap(false, Ordering::SeqCst) {
+            return;
+        }
+        for (_, meter) in self.meters.read().await.iter() {
+            meter.stop().await;
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
-    use crate::{Meter, RollingAverage};
+    use crate::{
+        Ema, HashrateUnit, HistoryWindow, ManualClock, Meter, MeterConfig, MeterKind,
+        MeterRegistry, MeterWindow, MeterWindows, RollingAverage, WindowRate,
+    };
+    use std::{sync::Arc, time::Duration};
+    use tokio::time::Instant;
 
     #[test]
     fn test_rolling_average() {
@@ -210,5 +904,384 @@ mod tests {
         let format_x = Meter::format(x);
         assert_eq!(format_x, String::from("200.00 PH/s"));
         println!("{}", format_x);
+
+        assert_eq!(Meter::format(0.0), "0.00 H/s");
+        assert_eq!(Meter::format(f64::NAN), "0.00 H/s");
+        assert_eq!(Meter::format(f64::INFINITY), "inf PH/s");
+        assert_eq!(Meter::format_as(312_400.0, HashrateUnit::MH), "0.31 MH/s");
+        assert_eq!(Meter::format_as(2e9, HashrateUnit::MH), "2000.00 MH/s");
+        assert_eq!(Meter::format_as(5.0, HashrateUnit::H), "5.00 H/s");
+    }
+
+    #[test]
+    fn test_hashrate_unit() {
+        for (s, unit) in [
+            ("auto", HashrateUnit::Auto),
+            ("h", HashrateUnit::H),
+            ("kh", HashrateUnit::KH),
+            ("MH", HashrateUnit::MH),
+            ("gh", HashrateUnit::GH),
+            ("th", HashrateUnit::TH),
+            ("ph", HashrateUnit::PH),
+        ] {
+            assert_eq!(s.parse::<HashrateUnit>().unwrap(), unit);
+            assert_eq!(unit.to_string(), s.to_lowercase());
+        }
+        assert!("mh/s".parse::<HashrateUnit>().is_err());
+        assert!("".parse::<HashrateUnit>().is_err());
+        assert_eq!(HashrateUnit::default(), HashrateUnit::Auto);
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Meter::parse("312.40 KH/s").unwrap(), 312_400.0);
+        assert_eq!(Meter::parse(" 1.5 mh/s ").unwrap(), 1_500_000.0);
+        assert_eq!(Meter::parse("0.00 H/s").unwrap(), 0.0);
+        assert_eq!(Meter::parse("inf PH/s").unwrap(), f64::INFINITY);
+        for invalid in [
+            "312.40",
+            "KH/s",
+            "312.40 KH",
+            "-1.00 H/s",
+            "NaN H/s",
+            "fast H/s",
+        ] {
+            assert!(Meter::parse(invalid).is_err(), "{}", invalid);
+        }
+
+        // every unit round trips, to the two decimals printed
+        let rates = [0.0, 1.0, 999.99, 312_400.0, 7.5e8, 1.23e12, 4.56e15, 1e30];
+        for unit in [HashrateUnit::Auto].into_iter().chain(HashrateUnit::FIXED) {
+            for rate in rates {
+                let formatted = Meter::format_as(rate, unit);
+                let parsed = Meter::parse(&formatted).unwrap();
+                let resolution = 0.005 * unit.resolve(rate).scale();
+                assert!(
+                    (parsed - rate).abs() <= resolution + rate * 1e-12,
+                    "{} {} -> {} -> {}",
+                    unit,
+                    rate,
+                    formatted,
+                    parsed
+                );
+            }
+        }
+        assert_eq!(
+            Meter::parse(&Meter::format(f64::INFINITY)).unwrap(),
+            f64::INFINITY
+        );
+    }
+
+    #[tokio::test]
+    async fn test_irregular_samples() {
+        let meter = Meter::new();
+        meter.begin().await;
+        let start = Instant::now();
+        meter.ticker.write().await.last_now = start;
+        let at = |millis| start + Duration::from_millis(millis);
+        for second in 1..=5 {
+            meter.add(1000).await;
+            meter.sample(at(second * 1000)).await;
+        }
+        assert_eq!(meter.get_rate_1s().await, 1000.0);
+        assert!(meter.irregular_samples().await.is_empty());
+
+        // no time passed: the count goes to the next sample, which is flagged
+        meter.add(500).await;
+        meter.sample(at(5000)).await;
+        meter.add(500).await;
+        meter.sample(at(6000)).await;
+        assert_eq!(meter.get_rate_1s().await, 1000.0);
+        // 10s since the last sample, the count is spread over them
+        meter.add(10_000).await;
+        meter.sample(at(16_000)).await;
+        assert_eq!(meter.get_rate_1s().await, 1000.0);
+        // a burst is capped at a multiple of the recent rate, whether late or early
+        meter.add(100_000).await;
+        meter.sample(at(26_000)).await;
+        assert_eq!(meter.get_rate_1s().await, 4000.0);
+        meter.add(1000).await;
+        meter.sample(at(26_200)).await;
+        assert_eq!(meter.get_rate_1s().await, 4000.0);
+        // back on the tick
+        meter.add(1000).await;
+        meter.sample(at(27_200)).await;
+        assert_eq!(meter.get_rate_1s().await, 1000.0);
+
+        let history = meter.history(HistoryWindow::Second).await;
+        assert_eq!(history.len(), 10);
+        assert_eq!(meter.irregular_samples().await.len(), 4);
+        // irregular samples are left out of the rate the hashrate alert watches
+        assert_eq!(meter.rate_over(Duration::from_secs(3600)).await, 1000.0);
+        assert_eq!(meter.rate_over(Duration::from_secs(2)).await, 1000.0);
+    }
+
+    #[test]
+    fn test_downsample_irregular() {
+        let samples = (0..10).map(|i| (100 + i, 1.0)).collect::<Vec<_>>();
+        assert_eq!(
+            Meter::downsample_irregular(&samples, 0, &[103, 107]),
+            vec![103, 107]
+        );
+        // stamped like the downsampled points
+        assert_eq!(Meter::downsample_irregular(&samples, 2, &[103]), vec![104]);
+        assert_eq!(
+            Meter::downsample(&samples, 2)
+                .iter()
+                .map(|&(timestamp, _)| timestamp)
+                .collect::<Vec<_>>(),
+            vec![104, 109]
+        );
+        assert!(Meter::downsample_irregular(&samples, 5, &[]).is_empty());
+        assert!(Meter::downsample_irregular(&[], 5, &[1]).is_empty());
+    }
+
+    #[test]
+    fn test_downsample() {
+        let samples = (0..10).map(|i| (100 + i, i as f64)).collect::<Vec<_>>();
+        assert_eq!(Meter::downsample(&samples, 0), samples);
+        assert_eq!(Meter::downsample(&samples, 10), samples);
+        assert_eq!(Meter::downsample(&samples, 20), samples);
+        assert_eq!(
+            Meter::downsample(&samples, 5),
+            vec![(101, 0.5), (103, 2.5), (105, 4.5), (107, 6.5), (109, 8.5)]
+        );
+        // uneven buckets: 10 samples into 3 buckets of 3, 3 and 4
+        assert_eq!(
+            Meter::downsample(&samples, 3),
+            vec![(102, 1.0), (105, 4.0), (109, 7.5)]
+        );
+        assert_eq!(Meter::downsample(&samples, 1), vec![(109, 4.5)]);
+        assert!(Meter::downsample(&[], 5).is_empty());
+    }
+
+    #[test]
+    fn test_history_window() {
+        assert_eq!(
+            "1s".parse::<HistoryWindow>().unwrap(),
+            HistoryWindow::Second
+        );
+        assert_eq!(
+            "1m".parse::<HistoryWindow>().unwrap(),
+            HistoryWindow::Minute
+        );
+        assert!("5m".parse::<HistoryWindow>().is_err());
+    }
+
+    #[test]
+    fn test_meter_windows() {
+        let windows: MeterWindows = "10s, 1m,15m:ema".parse().unwrap();
+        assert_eq!(
+            windows.0[..2],
+            [
+                MeterWindow::rolling(Duration::from_secs(10)),
+                MeterWindow::rolling(Duration::from_secs(60))
+            ]
+        );
+        assert_eq!(windows.0[2], MeterWindow::ema(Duration::from_secs(900)));
+        let labels = windows
+            .0
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        assert_eq!(labels, vec!["10s", "1m", "15m ema"]);
+        assert_eq!(
+            MeterWindow::rolling(Duration::from_secs(90)).to_string(),
+            "90s"
+        );
+        assert_eq!(
+            MeterWindow::rolling(Duration::from_secs(7200)).to_string(),
+            "2h"
+        );
+        for invalid in ["", "10", "0s", "1d", "1m:sma", "m"] {
+            assert!(invalid.parse::<MeterWindows>().is_err(), "{}", invalid);
+        }
+
+        // sample counts follow the tick
+        let second = Duration::from_secs(1);
+        assert_eq!(windows.0[0].samples(second), 10);
+        assert_eq!(windows.0[1].samples(second), 60);
+        assert_eq!(windows.0[1].samples(Duration::from_millis(500)), 120);
+        assert_eq!(windows.0[0].samples(Duration::from_secs(3)), 4);
+        assert_eq!(windows.0[0].samples(Duration::from_secs(30)), 1);
+        assert_eq!(
+            MeterWindows::default().0,
+            [5, 60, 300].map(|secs| MeterWindow::rolling(Duration::from_secs(secs)))
+        );
+
+        let rates = [
+            WindowRate {
+                window: String::from("10s"),
+                rate: 1200.0,
+            },
+            WindowRate {
+                window: String::from("15m ema"),
+                rate: 200.0,
+            },
+        ];
+        assert_eq!(
+            Meter::format_rates(&rates, HashrateUnit::Auto),
+            "10s 1.20 KH/s, 15m ema 200.00 H/s"
+        );
+        assert_eq!(
+            Meter::format_rates(&rates, HashrateUnit::KH),
+            "10s 1.20 KH/s, 15m ema 0.20 KH/s"
+        );
+        assert_eq!(
+            Meter::format_per_minute(&rates[1..]),
+            "15m ema 12000.00/min"
+        );
+    }
+
+    #[test]
+    fn test_ema() {
+        let window = MeterWindow::ema(Duration::from_secs(60));
+        let half_life = match window.kind {
+            MeterKind::Ema { half_life } => half_life,
+            MeterKind::Rolling => unreachable!(),
+        };
+        assert!((half_life.as_secs_f64() - 20.79).abs() < 0.01);
+
+        let tick = Duration::from_secs(1);
+        let mut ema = Ema::new(Duration::from_secs(10));
+        assert_eq!(ema.average(), 0.0);
+        ema.add(0.0, tick);
+        // a step halves its distance every half life
+        for _ in 0..10 {
+            ema.add(100.0, tick);
+        }
+        assert!((ema.average() - 50.0).abs() < 1e-9);
+        for _ in 0..90 {
+            ema.add(100.0, tick);
+        }
+        assert!((ema.average() - 100.0).abs() < 0.1);
+        // uneven ticks weigh by time, not by sample count
+        let mut even = Ema::new(Duration::from_secs(10));
+        let mut uneven = Ema::new(Duration::from_secs(10));
+        even.add(0.0, tick);
+        uneven.add(0.0, tick);
+        for _ in 0..4 {
+            even.add(100.0, tick);
+        }
+        uneven.add(100.0, Duration::from_secs(2));
+        uneven.add(100.0, Duration::from_secs(2));
+        assert!((even.average() - uneven.average()).abs() < 1e-9);
+    }
+
+    /// Sampled by hand, the ticker task waits an hour before its first tick.
+    fn registry_config() -> MeterConfig {
+        MeterConfig {
+            tick: Duration::from_secs(3600),
+            windows: vec![MeterWindow::rolling(Duration::from_secs(7200))],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_registry_ticks_all_meters() {
+        let registry = MeterRegistry::new(registry_config());
+        let hashrate = registry.register("hashrate").await;
+        let shares = registry.register("shares").await;
+        assert!(std::sync::Arc::ptr_eq(
+            &shares,
+            &registry.register("shares").await
+        ));
+        MeterRegistry::start(registry.clone()).await;
+        let start = Instant::now() + Duration::from_secs(1);
+        registry.sample(start).await;
+        hashrate.add(3000).await;
+        shares.add(2).await;
+        registry.sample(start + Duration::from_secs(2)).await;
+        assert_eq!(hashrate.get_rate_1s().await, 1500.0);
+        assert_eq!(shares.get_rate_1s().await, 1.0);
+
+        // a meter registered late starts counting too
+        let rejects = registry.register("rejects").await;
+        rejects.add(4).await;
+        registry.sample(start + Duration::from_secs(4)).await;
+        assert!(rejects.get_rate_1s().await > 0.0);
+        assert_eq!(shares.get_rate_1s().await, 0.0);
+
+        registry.stop().await;
+        shares.add(10).await;
+        registry.sample(start + Duration::from_secs(6)).await;
+        assert_eq!(shares.get_rate_1s().await, 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_registry_snapshot() {
+        let registry = MeterRegistry::new(registry_config());
+        assert_eq!(registry.snapshot().await, Default::default());
+        let hashrate = registry.register("hashrate").await;
+        let shares = registry.register("shares").await;
+        MeterRegistry::start(registry.clone()).await;
+
+        let start = Instant::now() + Duration::from_secs(1);
+        registry.sample(start).await;
+        for i in 1..=4 {
+            hashrate.add(1000 * i).await;
+            shares.add(i).await;
+            registry.sample(start + Duration::from_secs(i)).await;
+        }
+        let snapshot = registry.snapshot().await;
+        assert_eq!(snapshot.ticks, 5);
+        let names = snapshot
+            .meters
+            .iter()
+            .map(|reading| reading.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["hashrate", "shares"]);
+        // both read from the same tick, the window holds its last two samples
+        let hashrate = snapshot.get("hashrate").unwrap();
+        let shares = snapshot.get("shares").unwrap();
+        assert_eq!(hashrate.rate_1s, 4000.0);
+        assert_eq!(shares.rate_1s, 4.0);
+        assert_eq!(hashrate.rates[0].rate, 3500.0);
+        assert_eq!(shares.rates[0].rate, 3.5);
+        assert!(snapshot.get("rejects").is_none());
+        registry.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_registry_ticker() {
+        let clock = ManualClock::new();
+        let tick = Duration::from_secs(1);
+        let registry = MeterRegistry::with_clock(
+            MeterConfig {
+                tick,
+                ..Default::default()
+            },
+            Arc::new(clock.clone()),
+        );
+        let hashrate = registry.register("hashrate").await;
+        let shares = registry.register("shares").await;
+        MeterRegistry::start(registry.clone()).await;
+        for _ in 0..10 {
+            // the ticker is waiting for its next tick
+            clock.sleepers(1).await;
+            hashrate.add(100).await;
+            shares.add(1).await;
+            clock.advance(tick);
+        }
+        clock.sleepers(1).await;
+        let snapshot = registry.snapshot().await;
+        assert_eq!(snapshot.ticks, 10, "{:?}", snapshot);
+        // every tick came on time, so each sample is exactly what was added
+        let history = hashrate.history(HistoryWindow::Second).await;
+        assert!(
+            history.iter().all(|&(_, rate)| rate == 100.0),
+            "{:?}",
+            history
+        );
+        assert!(hashrate.irregular_samples().await.is_empty());
+        // one ticker samples both meters, so both got the same number of samples
+        assert_eq!(
+            hashrate.history(HistoryWindow::Second).await.len() as u64,
+            snapshot.ticks
+        );
+        assert_eq!(
+            shares.history(HistoryWindow::Second).await.len() as u64,
+            snapshot.ticks
+        );
+        registry.stop().await;
     }
 }
@@ -2,167 +2,1418 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 
-use crate::{Cli, Meter, StratumClient, StratumClientConfig};
+#[cfg(unix)]
+use crate::{attach_socket, detach_socket, MinerHandover};
+use crate::{
+    bus, estimate, monitored_channel, share_hash, spawn_critical, split_threads, supervise,
+    verify_share, AssembledJob, BaselineConfig, BaselineEvent, BufferStats, ChannelCounters,
+    ChannelStats, Cli, Clock, ConnectionStats, DifficultyLimits, Disconnect, EarningsEstimate,
+    ExitReason, FinalReport, FirstShareStats, FlapStats, HashrateBaseline, HeaderBuffers,
+    HeaderFit, HeaderLayout, HistorySample, HistoryWindow, Identity, JobAssembler, JobLatency,
+    JobLatencyStats, JobSlots, LifetimeStats, Meter, MeterConfig, MeterRegistry, MeterSnapshot,
+    MinerCommand, MinerEnd, MonitoredSender, NetworkDifficulty, PauseClock, PauseReason,
+    PauseStats, PendingJob, PoolApiClient, PoolApiStats, PoolEvent, PoolStats, ProtocolErrors,
+    Randomness, RecentSet, RestartPolicy, ResumePolicy, SessionStats, ShareCandidate,
+    ShareDecision, ShareGuard, ShareHook, ShareHookSlot, ShareHookStats, ShareIntervalStats,
+    ShareIntervals, ShareLog, ShareValue, ShareValueStats, SharedClock, StratumClient,
+    StratumClientConfig, SubmitConnectionStats, SystemClock, Target, TargetHistory,
+    TargetHistoryStats, Thermal, ThermalStats, ThreadPool, UserPause, WindowRate, Work,
+    CHANNEL_CAPACITY, MAX_RECENT_SHARES, SEND_TIMEOUT, THERMAL_POLL_INTERVAL,
+};
 use anyhow::Result;
-use ironfish_rust::mining;
 use log::*;
+use serde::{Deserialize, Serialize};
 use std::{
+    collections::VecDeque,
     sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc,
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
     },
-    time::Duration,
-};
-use tokio::{
-    sync::{mpsc, oneshot, RwLock},
-    task, time,
+    time::{Duration, Instant},
 };
+use tokio::sync::{broadcast, mpsc, oneshot, watch, RwLock};
 
-type MinerRouter = mpsc::Sender<MinerRequest>;
+type MinerRouter = MonitoredSender<MinerRequest>;
 type MinerHandler = mpsc::Receiver<MinerRequest>;
 
 const GRAFFITI_SIZE: usize = 32;
+/// How long a pause for a lost pool connection may last before it is warned about.
+pub const DISCONNECTED_ALERT_AFTER: Duration = Duration::from_secs(60);
+/// Jobs kept for re-checking shares in strict target mode, shares of older jobs are submitted
+/// unchecked.
+const RECENT_JOBS: usize = 4;
+/// The hashrate compared against the baseline.
+const BASELINE_RATE_WINDOW: Duration = Duration::from_secs(300);
+const BASELINE_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+/// Longest wait for the mining threads to finish their batch after a stop.
+const THREAD_POOL_STOP_TIMEOUT: Duration = Duration::from_secs(2);
+/// The thread pool counts as stopped once it has reported no hashes for this long.
+const THREAD_POOL_QUIET: Duration = Duration::from_millis(100);
+/// How often found shares are picked up from the thread pool.
+const FOUND_SHARE_POLL: Duration = Duration::from_millis(10);
+/// How often hashes are taken from the thread pool, and how often they go to the meter.
+const HASHRATE_COLLECT_INTERVAL: Duration = Duration::from_millis(500);
+const HASHRATE_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+/// Events kept for a slow subscriber, which misses the oldest ones beyond that.
+const EVENT_CAPACITY: usize = 256;
+
+/// Adds up the hashes taken from the thread pool until they are due for the meter, so that
+/// each meter sample holds a second of hashing rather than many small adds.
+#[derive(Debug)]
+struct HashBatch {
+    hashes: u64,
+    since: Instant,
+}
+
+impl HashBatch {
+    fn new(now: Instant) -> Self {
+        Self {
+            hashes: 0,
+            since: now,
+        }
+    }
+
+    fn add(&mut self, hashes: u64) {
+        self.hashes += hashes;
+    }
+
+    /// The hashes added since the last flush once [`HASHRATE_FLUSH_INTERVAL`] is over.
+    fn flush(&mut self, now: Instant) -> Option<u64> {
+        if now.saturating_duration_since(self.since) < HASHRATE_FLUSH_INTERVAL {
+            return None;
+        }
+        self.since = now;
+        Some(std::mem::take(&mut self.hashes))
+    }
+}
+/// One thread pool per job mined at once, with the threads split evenly between them and the
+/// batch split along with the threads.
+fn build_thread_pools(threads: usize, jobs: usize, batch_size: u32) -> Vec<ThreadPool> {
+    let threads = split_threads(threads, jobs);
+    let total = threads.iter().sum::<usize>() as u64;
+    threads
+        .into_iter()
+        .map(|threads| {
+            let batch_size = (batch_size as u64 * threads as u64 / total).max(1) as u32;
+            ThreadPool::new(threads, batch_size)
+        })
+        .collect()
+}
+
 #[derive(Debug)]
 enum MinerRequest {
-    NewWork(Vec<u8>, [u8; 32], u32),
-    WaitForWork,
-    Stop,
+    NewWork(Arc<Work>),
+    WaitForWork(PauseReason),
+    /// Answered once the thread pool is down and its last shares are submitted.
+    Stop(oneshot::Sender<()>),
+    /// Mine on this many threads.
+    Throttle(usize),
 }
 #[derive(Debug)]
 pub struct Miner {
     cli: Cli,
+    /// Hashes per batch of the thread pool, see [`Cli::effective_batch_size`].
+    batch_size: u32,
+    label: String,
+    difficulty: Arc<NetworkDifficulty>,
+    pool_api: Arc<PoolApiClient>,
     graffiti: RwLock<Option<[u8; GRAFFITI_SIZE]>>,
     hashrare: Arc<Meter>,
+    /// Hashes the thread pool reported while not mining, left out of the hashrate.
+    idle_hashes: AtomicU64,
+    /// Hashes the thread pool reported, the idle ones included.
+    hashes: AtomicU64,
+    job_latency: RwLock<JobLatency>,
+    /// Pairs jobs with their targets, and holds a job that came before the target or the
+    /// graffiti.
+    job_assembler: RwLock<JobAssembler>,
+    /// Shares found, whether or not they were submitted.
+    share_rate: Arc<Meter>,
+    meters: Arc<MeterRegistry>,
+    baseline: RwLock<HashrateBaseline>,
+    header_buffers: HeaderBuffers,
+    layout: HeaderLayout,
     router: RwLock<Option<MinerRouter>>,
+    router_counters: Arc<ChannelCounters>,
+    /// Where the pool connection tells the miner what the pool sends, and takes its shares.
+    bus: MinerEnd,
+    shares_below_target: AtomicU64,
+    share_guard: Mutex<ShareGuard>,
+    share_hook: ShareHookSlot,
+    /// Shares flagged by the [`ShareGuard`], and those of them that failed the check.
+    suspicious_shares: AtomicU64,
+    suspicious_shares_dropped: AtomicU64,
+    /// Notifies that only repeated the job being mined.
+    redundant_notifies: AtomicU64,
+    /// Shares found since the start, submitted or not.
+    shares_found: AtomicU64,
+    /// Shares handed to the pool connection since the start.
+    shares_submitted: AtomicU64,
+    /// Shares the thread pool handed in while stopping, submitted or discarded as
+    /// `--discard-shares-on-stop` says.
+    shares_submitted_on_stop: AtomicU64,
+    shares_discarded_on_stop: AtomicU64,
+    /// The bits of the highest share difficulty found, 0 before the first share of a known job.
+    best_share: AtomicU64,
+    started_at: std::time::Instant,
+    /// Unix time in seconds of the last share found, 0 before the first one.
+    last_share_at: AtomicU64,
     stratum_client: Arc<StratumClient>,
     target: RwLock<[u8; 32]>,
     waiting: AtomicBool,
+    /// The current pause and the time spent in each kind of pause.
+    pauses: Mutex<PauseClock>,
+    /// A pause by the user and the job it resumes into. Jobs and waits go through it before
+    /// they reach the mining loop.
+    user_pause: Mutex<UserPause>,
+    /// Whether the thread pool's hashes count, see [`Self::is_idle`]. The mining loop waits on
+    /// this instead of polling while idle.
+    active: watch::Sender<bool>,
+    /// Times the mining loop woke up while idle.
+    idle_wakeups: AtomicU64,
+    events: broadcast::Sender<MinerEvent>,
+    /// Only with `--max-temp` and a readable sensor.
+    thermal: Option<Arc<Thermal>>,
+    /// The shares found for the current job. A thread pool rebuilt with fewer or more threads
+    /// starts its search over and finds them again.
+    found_shares: Mutex<RecentSet<(u32, u64)>>,
+    /// The epoch of the pool session the last share was found in, and the shares found in it.
+    session_shares: Mutex<(Option<u64>, u64)>,
+    share_intervals: Mutex<ShareIntervals>,
+    /// The summed difficulty of the shares submitted, per session and since the start.
+    share_value: Mutex<ShareValue>,
+    /// The targets the pool set, flagged against `--max-difficulty` and
+    /// `--max-difficulty-jump`.
+    target_history: Mutex<TargetHistory>,
+    /// The epoch of the pool session whose first job was checked against the layout, and
+    /// whether it is mined.
+    header_checked: Mutex<Option<(Option<u64>, bool)>>,
+    /// Bytes of the last job header from the pool, 0 before the first.
+    header_len: AtomicUsize,
+    /// Tells this miner apart from others cloned from one machine image.
+    identity: Identity,
+    /// Where the search of every job begins, from `--randomness-start`.
+    randomness_start: u64,
+    /// What the timers of the mining loop and the watchers wait on.
+    clock: SharedClock,
+}
+
+/// What happens to a miner, for [`Miner::subscribe_events`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MinerEvent {
+    /// Subscribed to the pool, which handed out this graffiti.
+    Subscribed { graffiti: String },
+    /// A job went to the mining threads.
+    NewJob { mining_request_id: u32 },
+    ShareFound {
+        mining_request_id: u32,
+        randomness: u64,
+    },
+    /// No job to mine, because the pool said so or went away.
+    WaitingForWork { reason: PauseReason },
+    /// The miner was stopped and its last shares submitted.
+    Stopped,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MinerStats {
+    pub label: String,
+    pub pool: String,
+    /// Worker threads actually mining, after `--threads` was lowered to the core count.
+    pub threads: usize,
+    /// Hashes per batch the thread pool was given.
+    pub batch_size: u32,
+    /// `--batch-per-thread`, if the batch size was derived from it.
+    pub batch_per_thread: Option<u32>,
+    pub subscribed: bool,
+    /// Assigned by the pool on the last subscribe.
+    pub client_id: Option<u64>,
+    pub graffiti: Option<String>,
+    pub rate_1s: f64,
+    /// The rates of the configured hashrate windows.
+    pub rates: Vec<WindowRate>,
+    pub rate_avg: f64,
+    pub rate_1h: f64,
+    /// Every meter of the miner, the hashrate and the rate of shares found, as of one tick.
+    pub meters: MeterSnapshot,
+    /// The usual hashrate, learned over the first minutes of mining.
+    pub hashrate_baseline: Option<f64>,
+    /// Whether the hashrate has stayed below `--alert-threshold` of the baseline.
+    pub hashrate_alert: bool,
+    /// Shares found since the start, submitted or not.
+    pub shares_found: u64,
+    /// Shares handed to the pool connection since the start.
+    #[serde(default)]
+    pub shares_submitted: u64,
+    /// Shares the thread pool handed in while stopping and that were submitted, also counted
+    /// in `shares_submitted`.
+    #[serde(default)]
+    pub shares_submitted_on_stop: u64,
+    /// Shares the thread pool handed in while stopping and that `--discard-shares-on-stop`
+    /// dropped. Not counted as found.
+    #[serde(default)]
+    pub shares_discarded_on_stop: u64,
+    /// The difficulty of the best share found, its hash read as a target.
+    #[serde(default)]
+    pub best_share_difficulty: Option<f64>,
+    #[serde(default)]
+    pub runtime_secs: u64,
+    /// Pool sessions opened since the start, the reconnects are all but the first.
+    #[serde(default)]
+    pub sessions_started: u64,
+    /// Hashes reported while waiting for work or disconnected, e.g. the end of a batch that
+    /// ran into a pause. Not part of any rate.
+    pub idle_hashes: u64,
+    /// Hashes computed since the start, the idle ones included.
+    #[serde(default)]
+    pub hashes: u64,
+    /// How quickly new jobs are dispatched and yield their first share.
+    pub job_latency: JobLatencyStats,
+    /// The time between the shares of the current or last pool session, and whether it looks
+    /// like a random search.
+    #[serde(default)]
+    pub share_intervals: ShareIntervalStats,
+    /// The shares submitted valued by the difficulty of the target their job was dispatched
+    /// with, as a pool with vardiff pays them.
+    #[serde(default)]
+    pub share_value: ShareValueStats,
+    /// The difficulty of the current target and the last targets the pool set, with those
+    /// over `--max-difficulty` or `--max-difficulty-jump` flagged.
+    #[serde(default)]
+    pub target_history: TargetHistoryStats,
+    /// Shares dropped in strict target mode because the target got harder after dispatch.
+    pub shares_below_target: u64,
+    /// Notifies ignored because they repeated the job being mined, as some pools do to keep
+    /// the connection alive.
+    pub redundant_notifies: u64,
+    /// Bytes of the last job header from the pool, to notice when a pool upgrade changes it.
+    #[serde(default)]
+    pub header_len: Option<usize>,
+    /// The worker name the pool sees, with the identity suffix if it is the default.
+    #[serde(default)]
+    pub worker_name: String,
+    /// Why mining is paused, and the time spent paused so far per reason.
+    pub pauses: PauseStats,
+    /// Shares dropped because they were found faster than `--max-submit-rate`.
+    pub shares_rate_limited: u64,
+    /// Jobs that expired with no new one from the pool, see `--job-ttl`.
+    #[serde(default)]
+    pub expired_jobs: u64,
+    /// Waits for work the pool kept up longer than `--max-wait-warn`.
+    #[serde(default)]
+    pub long_waits: u64,
+    /// Sessions ended because the connection looked half open, see `--half-open-timeout`.
+    #[serde(default)]
+    pub half_open_disconnects: u64,
+    /// Shares submitted again after a failed write, see `--resend-submits`.
+    #[serde(default)]
+    pub shares_resent: u64,
+    /// Shares of a failed write not submitted again, as the pool acknowledged them or they
+    /// were re-sent before.
+    #[serde(default)]
+    pub resends_suppressed: u64,
+    /// Whether the pool keeps dropping the connection right after the subscribe, and how long
+    /// the next reconnect waits.
+    #[serde(default)]
+    pub pool_flapping: FlapStats,
+    /// Shares whose randomness looked like a thread pool artifact, and were hashed again
+    /// before submitting, see [`ShareGuard`].
+    #[serde(default)]
+    pub suspicious_shares: u64,
+    /// Suspicious shares that did not meet their job's target, and were dropped.
+    #[serde(default)]
+    pub suspicious_shares_dropped: u64,
+    /// Unix time in seconds of the last share found.
+    pub last_share_at: Option<u64>,
+    /// Local minus pool clock in milliseconds, only known if the pool sends timestamps.
+    pub clock_skew_ms: Option<f64>,
+    /// The CPU temperature and how far mining is throttled, only present with `--max-temp`
+    /// and a readable sensor.
+    pub thermal: Option<ThermalStats>,
+    /// Expected earnings from `rate_1h`, only present when the network difficulty is known.
+    pub estimate: Option<EarningsEstimate>,
+    /// What the pool reports for the address, only present with `--pool-api-url` once a poll
+    /// succeeded.
+    pub pool_api: Option<PoolApiStats>,
+    /// What the share hook decided, only present once one is set, see
+    /// [`Miner::set_share_hook`].
+    #[serde(default)]
+    pub share_hook: Option<ShareHookStats>,
+    /// Requests queued for the mining loop and what could not be delivered to it.
+    pub miner_channel: ChannelStats,
+    /// Requests queued for the pool connection and what could not be delivered to it.
+    pub stratum_channel: ChannelStats,
+    /// Shares on their way from the miner to the pool connection, and what could not be
+    /// delivered.
+    #[serde(default)]
+    pub command_channel: ChannelStats,
+    /// The current pool session, only present while subscribed.
+    pub session: Option<SessionStats>,
+    /// The most recently closed pool sessions, oldest first.
+    pub sessions: Vec<SessionStats>,
+    /// How long the current or last session took to its first share, and the medians over
+    /// the recent sessions.
+    pub first_share: FirstShareStats,
+    /// The last disconnects from the pool, oldest first, including connections that never
+    /// got subscribed.
+    pub disconnects: Vec<Disconnect>,
+    /// The last TCP connections to the pool, oldest first, the open one last.
+    #[serde(default)]
+    pub connections: Vec<ConnectionStats>,
+    /// The totals of `--pool` and every `--failover-pool` since the start, in the order they
+    /// are tried.
+    #[serde(default)]
+    pub pools: Vec<PoolStats>,
+    /// Lines from the pool that were not usable messages, over all connections.
+    pub protocol_errors: ProtocolErrors,
+    /// How full the buffers are against their caps, and what they dropped to stay within.
+    pub buffers: BufferStats,
+    /// The connection shares are submitted on, only present with `--dual-connection`. The
+    /// other connection fields are about the main connection.
+    pub submit_connection: Option<SubmitConnectionStats>,
+    /// The counters since the start and over every run, only present with `--data-dir`. Set
+    /// by the [`crate::MinerSet`].
+    #[serde(default)]
+    pub lifetime: Option<LifetimeStats>,
 }
 
 impl Miner {
-    pub async fn initialize(cli: Cli) -> Arc<Self> {
-        let stratum_client_config = StratumClientConfig {
-            tls: cli.tls,
-            pool_address: cli.pool,
-            public_address: cli.address.clone(),
-            worker_name: cli.worker_name.clone(),
+    pub async fn initialize(cli: Cli) -> Result<Arc<Self>> {
+        Self::initialize_with_label(cli, String::new()).await
+    }
+
+    /// Creates a miner whose log lines and stats are tagged with `label`, so that several
+    /// miners can run side by side in one process.
+    pub async fn initialize_with_label(cli: Cli, label: String) -> Result<Arc<Self>> {
+        Self::initialize_with_clock(cli, label, SystemClock::shared()).await
+    }
+
+    /// [`Self::initialize_with_label`] with the timers of the miner, its meters and its pool
+    /// client on `clock`.
+    pub async fn initialize_with_clock(
+        mut cli: Cli,
+        label: String,
+        clock: SharedClock,
+    ) -> Result<Arc<Self>> {
+        let identity = Identity::derive(cli.identity_seed);
+        let randomness_start = cli.randomness_start.resolve(identity.nonce_start);
+        cli.worker_name = identity.worker_name(&cli.worker_name);
+        let stratum_client_config = StratumClientConfig::from_cli(&cli)?;
+        let batch_size = cli.effective_batch_size()?;
+        let layout = HeaderLayout::by_name(&cli.header_layout)?
+            .with_randomness_width(cli.randomness_width)?;
+        let meters = MeterRegistry::with_clock(
+            MeterConfig {
+                windows: cli.hashrate_windows.0.clone(),
+                ..Default::default()
+            },
+            clock.clone(),
+        );
+        let hashrare = meters.register("hashrate").await;
+        let share_rate = meters.register("shares").await;
+        let baseline = HashrateBaseline::new(BaselineConfig {
+            threshold: cli.alert_threshold as f64 / 100.0,
+            ..Default::default()
+        });
+        let thermal = cli.max_temp.and_then(|max_temp| match crate::system_sensor() {
+            Some(sensor) => Some(Arc::new(Thermal::new(max_temp, sensor))),
+            None => {
+                warn!("--max-temp {}: no readable CPU temperature sensor, mining without thermal throttling", max_temp);
+                None
+            }
+        });
+        let resume_policy = if cli.resume_requires_fresh_work {
+            ResumePolicy::FreshWork
+        } else {
+            ResumePolicy::LatestJob
         };
+        let difficulty_limits = DifficultyLimits::from_cli(&cli);
+        let concurrent_jobs = cli.max_concurrent_jobs as usize;
+        if concurrent_jobs > cli.threads_count {
+            warn!(
+                "--max-concurrent-jobs {} is over the {} threads, every job is mined on a thread of its own",
+                concurrent_jobs, cli.threads_count
+            );
+        }
+        let (pool_end, miner_end) = bus(CHANNEL_CAPACITY, Default::default());
         let miner = Arc::new(Miner {
+            thermal,
+            difficulty: NetworkDifficulty::new(&cli),
+            pool_api: PoolApiClient::new(&cli),
+            batch_size,
             cli,
+            label,
             graffiti: RwLock::default(),
-            hashrare: Meter::new(),
+            hashrare,
+            idle_hashes: Default::default(),
+            hashes: Default::default(),
+            job_latency: Default::default(),
+            job_assembler: RwLock::new(JobAssembler::with_concurrent_jobs(concurrent_jobs)),
+            share_rate,
+            meters,
+            baseline: RwLock::new(baseline),
+            header_buffers: Default::default(),
+            layout,
             router: RwLock::default(),
-            stratum_client: StratumClient::new(stratum_client_config),
+            router_counters: Default::default(),
+            bus: miner_end,
+            shares_below_target: Default::default(),
+            share_guard: Default::default(),
+            share_hook: Default::default(),
+            suspicious_shares: Default::default(),
+            suspicious_shares_dropped: Default::default(),
+            redundant_notifies: Default::default(),
+            shares_found: Default::default(),
+            shares_submitted: Default::default(),
+            shares_submitted_on_stop: Default::default(),
+            shares_discarded_on_stop: Default::default(),
+            best_share: Default::default(),
+            started_at: std::time::Instant::now(),
+            last_share_at: Default::default(),
+            stratum_client: StratumClient::with_clock(
+                stratum_client_config,
+                pool_end,
+                clock.clone(),
+            ),
             target: RwLock::default(),
             waiting: Default::default(),
+            pauses: Default::default(),
+            user_pause: Mutex::new(UserPause::new(resume_policy)),
+            active: watch::channel(false).0,
+            idle_wakeups: Default::default(),
+            events: broadcast::channel(EVENT_CAPACITY).0,
+            found_shares: Mutex::new(RecentSet::new(MAX_RECENT_SHARES)),
+            session_shares: Default::default(),
+            share_intervals: Default::default(),
+            share_value: Default::default(),
+            target_history: Mutex::new(TargetHistory::new(difficulty_limits)),
+            header_checked: Default::default(),
+            header_len: Default::default(),
+            identity,
+            randomness_start,
+            clock,
         });
-        miner.stratum_client.set_miner(Arc::downgrade(&miner)).await;
-        miner
+        info!(
+            "{}worker name({}), identity suffix({}) from {}, randomness start({:016x}, {})",
+            miner.log_prefix(),
+            miner.cli.worker_name,
+            miner.identity.suffix,
+            if miner.identity.derived_from.is_empty() {
+                String::from("the OS random generator")
+            } else {
+                miner.identity.derived_from.join(", ")
+            },
+            miner.randomness_start,
+            miner.cli.randomness_start
+        );
+        if let Some(warning) = miner.identity.collision_warning() {
+            warn!("{}{}", miner.log_prefix(), warning);
+        }
+        if let Some(per_thread) = miner.cli.batch_per_thread {
+            info!(
+                "{}batch size {} ({} per thread on {} threads, --batch_size {} ignored)",
+                miner.log_prefix(),
+                miner.batch_size,
+                per_thread,
+                miner.cli.threads_count,
+                miner.cli.batch_size
+            );
+        }
+        Ok(miner)
+    }
+
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    pub fn identity(&self) -> &Identity {
+        &self.identity
+    }
+
+    /// Where the search of every job begins.
+    pub fn randomness_start(&self) -> u64 {
+        self.randomness_start
+    }
+
+    /// What the miner keeps time on.
+    pub fn clock(&self) -> &SharedClock {
+        &self.clock
+    }
+
+    /// Events from now on. A subscriber that falls behind by more than 256 events misses the
+    /// oldest of them.
+    pub fn subscribe_events(&self) -> broadcast::Receiver<MinerEvent> {
+        self.events.subscribe()
+    }
+
+    fn emit(&self, event: MinerEvent) {
+        // nobody listening is fine
+        let _ = self.events.send(event);
+    }
+
+    pub async fn stats(&self) -> MinerStats {
+        let meters = self.meters.snapshot().await;
+        let hashrate = meters.get("hashrate").cloned();
+        let baseline = self.baseline.read().await;
+        let pauses = self.pauses.lock().unwrap().stats(std::time::Instant::now());
+        let recent_shares = self.found_shares.lock().unwrap().usage();
+        let share_intervals = self.share_intervals.lock().unwrap().stats();
+        let share_value = self.share_value.lock().unwrap().stats();
+        let target_history = self.target_history.lock().unwrap().stats();
+        MinerStats {
+            label: self.label.clone(),
+            pool: self
+                .cli
+                .pool
+                .as_ref()
+                .map(|pool| pool.to_string())
+                .unwrap_or_default(),
+            threads: self.cli.threads_count,
+            batch_size: self.batch_size,
+            batch_per_thread: self.cli.batch_per_thread,
+            subscribed: self.stratum_client.is_subscribed(),
+            client_id: self.stratum_client.client_id().await,
+            graffiti: self.stratum_client.graffiti().await,
+            rate_1s: hashrate
+                .as_ref()
+                .map(|rate| rate.rate_1s)
+                .unwrap_or_default(),
+            rate_avg: hashrate
+                .as_ref()
+                .map(|rate| rate.rate_avg)
+                .unwrap_or_default(),
+            rates: hashrate.map(|rate| rate.rates).unwrap_or_default(),
+            rate_1h: self.hashrare.get_rate_1h().await,
+            meters,
+            hashrate_baseline: baseline.baseline(),
+            hashrate_alert: baseline.is_alerting(),
+            shares_found: self.shares_found.load(Ordering::Relaxed),
+            shares_submitted: self.shares_submitted.load(Ordering::Relaxed),
+            shares_submitted_on_stop: self.shares_submitted_on_stop.load(Ordering::Relaxed),
+            shares_discarded_on_stop: self.shares_discarded_on_stop.load(Ordering::Relaxed),
+            best_share_difficulty: Some(f64::from_bits(self.best_share.load(Ordering::Relaxed)))
+                .filter(|difficulty| *difficulty > 0.0),
+            runtime_secs: self.started_at.elapsed().as_secs(),
+            sessions_started: self.stratum_client.sessions_started().await,
+            idle_hashes: self.idle_hashes.load(Ordering::Relaxed),
+            hashes: self.hashes.load(Ordering::Relaxed),
+            job_latency: self.job_latency.read().await.stats(),
+            share_intervals,
+            share_value,
+            target_history,
+            shares_below_target: self.shares_below_target.load(Ordering::Relaxed),
+            redundant_notifies: self.redundant_notifies.load(Ordering::Relaxed),
+            header_len: Some(self.header_len.load(Ordering::Relaxed)).filter(|len| *len > 0),
+            worker_name: self.cli.worker_name.clone(),
+            pauses,
+            shares_rate_limited: self.stratum_client.submits_dropped(),
+            expired_jobs: self.stratum_client.expired_jobs(),
+            long_waits: self.stratum_client.long_waits(),
+            half_open_disconnects: self.stratum_client.half_open_disconnects(),
+            shares_resent: self.stratum_client.shares_resent(),
+            resends_suppressed: self.stratum_client.resends_suppressed(),
+            pool_flapping: self.stratum_client.flap_stats(),
+            suspicious_shares: self.suspicious_shares.load(Ordering::Relaxed),
+            suspicious_shares_dropped: self.suspicious_shares_dropped.load(Ordering::Relaxed),
+            last_share_at: Some(self.last_share_at.load(Ordering::Relaxed)).filter(|at| *at > 0),
+            thermal: self
+                .thermal
+                .as_ref()
+                .map(|thermal| thermal.stats(self.cli.threads_count)),
+            estimate: self.estimate().await,
+            pool_api: self.pool_api.stats(),
+            share_hook: self.share_hook.stats(),
+            clock_skew_ms: self.stratum_client.clock_skew_ms().await,
+            miner_channel: match self.router.read().await.as_ref() {
+                Some(router) => router.stats(),
+                None => self.router_counters.stats(CHANNEL_CAPACITY),
+            },
+            stratum_channel: self.stratum_client.channel_stats().await,
+            command_channel: self.bus.stats(),
+            session: self.stratum_client.session().await,
+            sessions: self.stratum_client.closed_sessions().await,
+            first_share: self.stratum_client.first_share_stats().await,
+            disconnects: self.stratum_client.disconnects().await,
+            connections: self.stratum_client.connections().await,
+            pools: self.stratum_client.pool_stats(),
+            protocol_errors: self.stratum_client.protocol_errors().await,
+            buffers: BufferStats {
+                recent_shares,
+                meter_history_1s: self.hashrare.history_usage(HistoryWindow::Second).await,
+                meter_history_1m: self.hashrare.history_usage(HistoryWindow::Minute).await,
+                ..self.stratum_client.buffer_stats().await
+            },
+            submit_connection: self.stratum_client.submit_connection_stats().await,
+            lifetime: None,
+        }
+    }
+
+    /// The lifetime totals of the miner, as logged when the process exits for `reason`, see
+    /// [`FinalReport`].
+    pub async fn final_report(&self, reason: ExitReason) -> String {
+        FinalReport::new(&self.stats().await, reason).format(self.cli.hashrate_unit)
     }
 
+    /// Expected earnings from the last hour of hashrate, if the network difficulty is known.
+    pub async fn estimate(&self) -> Option<EarningsEstimate> {
+        let difficulty = self.difficulty.get()?;
+        estimate::estimate(self.hashrare.get_rate_1h().await, difficulty)
+    }
+
+    /// Hashes per second over the last minute, as reported to the pool.
+    pub async fn rate_1m(&self) -> f64 {
+        self.hashrare.rate_over(Duration::from_secs(60)).await
+    }
+
+    pub async fn history(&self, window: HistoryWindow) -> Vec<HistorySample> {
+        self.hashrare.history(window).await
+    }
+
+    pub async fn irregular_samples(&self) -> Vec<u64> {
+        self.hashrare.irregular_samples().await
+    }
+
+    fn log_prefix(&self) -> String {
+        if self.label.is_empty() {
+            String::new()
+        } else {
+            format!("[{}] ", self.label)
+        }
+    }
+
+    /// Takes the target in any form [`Target`] parses, and ignores one it can't.
     pub async fn set_target(&self, target: &str) {
-        self.target
+        let target: Target = match target.parse() {
+            Ok(target) => target,
+            Err(error) => {
+                error!("{}ignoring target from pool: {}", self.log_prefix(), error);
+                return;
+            }
+        };
+        let warnings = self
+            .target_history
+            .lock()
+            .unwrap()
+            .record(&target, self.clock.unix_timestamp());
+        for warning in warnings {
+            warn!("{}{}", self.log_prefix(), warning);
+        }
+        *self.target.write().await = target.0;
+        // dispatched under the lock, so that jobs reach the mining loop in the order they
+        // were assembled in
+        let mut job_assembler = self.job_assembler.write().await;
+        if let Some(job) = job_assembler.set_target(target.to_string()) {
+            self.dispatch(job).await;
+        }
+    }
+
+    /// Has `hook` decide on every share found from now on, right before it is submitted,
+    /// replacing the hook set before. Shares the miner drops itself, e.g. below the target in
+    /// strict mode, never reach it. The hook runs on the mining loop and must be fast: calls
+    /// over [`crate::SHARE_HOOK_BUDGET`] are logged and counted in the stats.
+    pub fn set_share_hook(&self, hook: ShareHook) {
+        self.share_hook.set(hook);
+    }
+
+    /// Mines with the graffiti the pool handed out, see [`graffiti_bytes`], followed by
+    /// `--graffiti-suffix`. Fails only on an empty graffiti, which leaves the previous one in
+    /// place.
+    pub async fn set_graffiti(&self, graffiti: &str) -> Result<()> {
+        let (mut bytes, truncated) = graffiti_bytes(graffiti)?;
+        if truncated {
+            warn!(
+                "{}graffiti '{}' is over {} bytes, mining with the characters that fit",
+                self.log_prefix(),
+                graffiti,
+                GRAFFITI_SIZE
+            );
+        }
+        let suffix = self.cli.graffiti_suffix.as_deref().unwrap_or_default();
+        if append_graffiti_suffix(&mut bytes, graffiti.len(), suffix) < suffix.len() {
+            warn!(
+                "{}graffiti suffix '{}' does not fit after the pool's graffiti '{}', mining with the characters that fit",
+                self.log_prefix(),
+                suffix,
+                graffiti
+            );
+        }
+        *self.graffiti.write().await = Some(bytes);
+        self.refresh_state().await;
+        self.emit(MinerEvent::Subscribed {
+            graffiti: graffiti.to_string(),
+        });
+        let mut job_assembler = self.job_assembler.write().await;
+        if let Some(job) = job_assembler.set_graffiti() {
+            self.dispatch(job).await;
+        }
+        Ok(())
+    }
+
+    /// Mines the job against the last target set before it, once the target and the graffiti
+    /// are known. The job is held until then.
+    pub async fn new_work(&self, mining_request_id: u32, header: &str) {
+        if !self.header_fits(header).await {
+            debug!(
+                "{}not mining job of mining request id({}), its header does not fit the layout",
+                self.log_prefix(),
+                mining_request_id
+            );
+            return;
+        }
+        let mut job_assembler = self.job_assembler.write().await;
+        // mining it again would restart the search of the thread pool from the beginning
+        if job_assembler.repeats_active(mining_request_id, header) {
+            self.redundant_notifies.fetch_add(1, Ordering::Relaxed);
+            debug!(
+                "{}ignoring repeated job of mining request id({})",
+                self.log_prefix(),
+                mining_request_id
+            );
+            return;
+        }
+        self.job_latency
             .write()
             .await
-            .copy_from_slice(hex::decode(target).unwrap().as_slice());
+            .notified(mining_request_id, std::time::Instant::now());
+        match job_assembler.notify(mining_request_id, header.to_string()) {
+            Some(job) => self.dispatch(job).await,
+            None => warn!(
+                "{}no {} yet, holding job of mining request id({})",
+                self.log_prefix(),
+                job_assembler.missing(),
+                mining_request_id
+            ),
+        }
     }
 
-    pub async fn set_graffiti(&self, graffiti: &str) {
-        let mut graffiti_bytes: [u8; 32] = [0; 32];
-        let len = graffiti.as_bytes().len();
-        graffiti_bytes[0..len].copy_from_slice(graffiti.as_bytes());
-        *self.graffiti.write().await = Some(graffiti_bytes);
+    /// Checks the first job of each pool session against the header layout, see
+    /// [`HeaderLayout::fit`]. The jobs of a session that does not fit are not mined, the miner
+    /// waits for work instead. A longer header is logged once per session.
+    async fn header_fits(&self, header: &str) -> bool {
+        self.header_len.store(header.len() / 2, Ordering::Relaxed);
+        let epoch = self
+            .stratum_client
+            .session()
+            .await
+            .map(|session| session.epoch);
+        let fit = {
+            let mut header_checked = self.header_checked.lock().unwrap();
+            match *header_checked {
+                Some((checked, fits)) if checked == epoch => return fits,
+                _ => {}
+            }
+            let fit = self.layout.fit(
+                header.len() / 2,
+                self.cli.force_header_layout,
+                self.cli.tolerate_longer_headers,
+            );
+            *header_checked = Some((epoch, !matches!(fit, HeaderFit::Mismatch(_))));
+            fit
+        };
+        match fit {
+            HeaderFit::Fits => true,
+            HeaderFit::Longer(note) => {
+                warn!("{}{}", self.log_prefix(), note);
+                true
+            }
+            HeaderFit::Forced(warning) => {
+                warn!("{}{}", self.log_prefix(), warning);
+                true
+            }
+            HeaderFit::Mismatch(mismatch) => {
+                error!("{}{}", self.log_prefix(), mismatch);
+                self.wait_for_work(PauseReason::HeaderMismatch).await;
+                false
+            }
+        }
     }
 
-    pub async fn new_work(&self, mining_request_id: u32, header: String) {
+    async fn dispatch(&self, job: AssembledJob) {
+        let mining_request_id = job.mining_request_id;
+        let job = match self.user_pause.lock().unwrap().job(job) {
+            Some(job) => job,
+            None => {
+                debug!(
+                    "{}paused by the user, keeping job of mining request id({}) for the resume",
+                    self.log_prefix(),
+                    mining_request_id
+                );
+                return;
+            }
+        };
+        let AssembledJob {
+            mining_request_id,
+            header,
+            target,
+        } = job;
+        let target: [u8; 32] = match hex::decode(&target)
+            .ok()
+            .and_then(|target| target.try_into().ok())
+        {
+            Some(target) => target,
+            None => {
+                error!(
+                    "{}invalid target({}) for mining request id({})",
+                    self.log_prefix(),
+                    target,
+                    mining_request_id
+                );
+                return;
+            }
+        };
         debug!(
             "new work: target({}) mining request id({})",
-            hex::encode(*self.target.read().await),
+            hex::encode(target),
             mining_request_id
         );
-        let mut header_bytes = hex::decode(header).unwrap();
-        header_bytes[176..176 + 32].copy_from_slice(self.graffiti.read().await.unwrap().as_slice());
+        let graffiti = match *self.graffiti.read().await {
+            Some(graffiti) => graffiti,
+            // held back by the job assembler until there is one
+            None => return,
+        };
+        // the thread pool writes the search value into bytes 0..8, the spare bytes of a wider
+        // field are seeded with the identity
+        let mut work = match Work::from_notify(
+            mining_request_id,
+            &header,
+            &graffiti,
+            target,
+            &self.layout,
+            self.header_buffers.take(),
+        ) {
+            Ok(work) => work,
+            Err(error) => {
+                error!(
+                    "{}invalid job of mining request id({}): {}",
+                    self.log_prefix(),
+                    mining_request_id,
+                    error
+                );
+                return;
+            }
+        };
+        work.seed_randomness(self.identity.nonce_start, &self.layout);
         self.waiting.store(false, Ordering::SeqCst);
+        let resumed = self
+            .pauses
+            .lock()
+            .unwrap()
+            .resume(std::time::Instant::now());
+        if let Some((reason, paused)) = resumed {
+            let level = match reason {
+                PauseReason::PoolRequested => Level::Debug,
+                _ => Level::Info,
+            };
+            log!(
+                level,
+                "{}mining resumed after {:.1}s paused ({})",
+                self.log_prefix(),
+                paused.as_secs_f64(),
+                reason
+            );
+        }
+        self.refresh_state().await;
+        self.share_guard
+            .lock()
+            .unwrap()
+            .dispatched(mining_request_id, std::time::Instant::now());
+        self.share_value
+            .lock()
+            .unwrap()
+            .job_dispatched(mining_request_id, &work.target);
+        self.send_request(MinerRequest::NewWork(Arc::new(work)))
+            .await;
+        self.emit(MinerEvent::NewJob { mining_request_id });
+    }
+
+    /// Whether a found share should be submitted. In strict target mode the share is hashed
+    /// again against the latest target, which a vardiff change may have raised since the job
+    /// was dispatched. Shares that can't be checked are submitted.
+    pub async fn should_submit(&self, header: Option<&[u8]>, randomness: u64) -> bool {
+        if !self.cli.strict_target {
+            return true;
+        }
+        let header = match header {
+            Some(header) => header,
+            None => return true,
+        };
+        let target = *self.target.read().await;
+        let verified = Randomness::in_header(randomness, header, &self.layout)
+            .and_then(|randomness| verify_share(header, &randomness, &target, &self.layout));
+        match verified {
+            Ok(false) => {
+                self.shares_below_target.fetch_add(1, Ordering::SeqCst);
+                false
+            }
+            _ => true,
+        }
+    }
+
+    /// Pauses mining until the next job. Only a [`PauseReason::Disconnected`] pause that lasts
+    /// longer than [`DISCONNECTED_ALERT_AFTER`] is warned about.
+    pub async fn wait_for_work(&self, reason: PauseReason) {
+        if !self.user_pause.lock().unwrap().wait(reason) {
+            debug!(
+                "{}paused by the user, the resume will wait for work ({})",
+                self.log_prefix(),
+                reason
+            );
+            return;
+        }
+        self.waiting.store(true, Ordering::SeqCst);
+        if self
+            .pauses
+            .lock()
+            .unwrap()
+            .pause(reason, std::time::Instant::now())
+        {
+            match reason {
+                PauseReason::PoolRequested => {
+                    debug!(
+                        "{}pool asked to wait for work, mining paused",
+                        self.log_prefix()
+                    )
+                }
+                reason => info!("{}mining paused ({})", self.log_prefix(), reason),
+            }
+        }
+        self.refresh_state().await;
+        self.send_request(MinerRequest::WaitForWork(reason)).await;
+        self.emit(MinerEvent::WaitingForWork { reason });
+    }
 
-        let request =
-            MinerRequest::NewWork(header_bytes, *self.target.read().await, mining_request_id);
-        self.send_request(request).await;
+    /// A share of the job was written to the pool connection `at`, for the notify to submit
+    /// latency.
+    pub async fn share_submitted(&self, mining_request_id: u32, at: std::time::Instant) {
+        self.job_latency
+            .write()
+            .await
+            .share_submitted(mining_request_id, at);
     }
 
-    pub async fn wait_for_work(&self) {
+    /// Pauses mining until [`Self::resume_mining`], whatever the pool sends meanwhile.
+    /// Returns `false` if already paused.
+    pub async fn pause_mining(&self) -> bool {
+        // held so that no job is dispatched while the pause starts
+        let job_assembler = self.job_assembler.read().await;
+        let current = if self.waiting.load(Ordering::SeqCst) {
+            let now = std::time::Instant::now();
+            match self.pauses.lock().unwrap().stats(now).reason {
+                Some(reason) => PendingJob::Waiting(reason),
+                None => PendingJob::None,
+            }
+        } else {
+            match job_assembler.active() {
+                Some(job) => PendingJob::Job(job.clone()),
+                None => PendingJob::None,
+            }
+        };
+        if !self.user_pause.lock().unwrap().pause(current) {
+            return false;
+        }
+        drop(job_assembler);
         self.waiting.store(true, Ordering::SeqCst);
-        self.send_request(MinerRequest::WaitForWork).await;
+        self.pauses
+            .lock()
+            .unwrap()
+            .pause(PauseReason::Manual, std::time::Instant::now());
+        info!("{}mining paused by the user", self.log_prefix());
+        self.refresh_state().await;
+        self.send_request(MinerRequest::WaitForWork(PauseReason::Manual))
+            .await;
+        self.emit(MinerEvent::WaitingForWork {
+            reason: PauseReason::Manual,
+        });
+        true
+    }
+
+    /// Ends a pause by the user and goes on with what it left off at, see
+    /// [`UserPause::resume`]. A job is mined against the current target and graffiti.
+    /// Returns `false` if not paused.
+    pub async fn resume_mining(&self) -> bool {
+        let mut job_assembler = self.job_assembler.write().await;
+        let pending = match self.user_pause.lock().unwrap().resume() {
+            Some(pending) => pending,
+            None => return false,
+        };
+        match pending {
+            PendingJob::Job(job) => {
+                let job = job_assembler.resume(job);
+                self.dispatch(job).await;
+            }
+            PendingJob::Waiting(reason) => {
+                drop(job_assembler);
+                match reason {
+                    PauseReason::Manual => info!(
+                        "{}resumed by the user, mining from the next job",
+                        self.log_prefix()
+                    ),
+                    reason => info!(
+                        "{}resumed by the user, still waiting for work ({})",
+                        self.log_prefix(),
+                        reason
+                    ),
+                }
+                self.wait_for_work(reason).await;
+            }
+            PendingJob::None => {
+                drop(job_assembler);
+                self.pauses
+                    .lock()
+                    .unwrap()
+                    .resume(std::time::Instant::now());
+                info!("{}resumed by the user, no job yet", self.log_prefix());
+                self.waiting.store(false, Ordering::SeqCst);
+                self.refresh_state().await;
+            }
+        }
+        true
     }
 
     pub async fn start(miner: Arc<Miner>) -> Result<()> {
-        StratumClient::start(miner.stratum_client.clone()).await;
-        Meter::start(miner.hashrare.clone()).await;
-        let (router, handler) = mpsc::channel(1024);
-        *miner.router.write().await = Some(router);
-        Miner::mine(miner, handler).await;
+        Miner::launch(miner).await;
         // Do not delete the following line of code
         std::future::pending::<()>().await;
         Ok(())
     }
 
+    /// Spawns the stratum client, meter and mining tasks and returns once they are running.
+    pub async fn launch(miner: Arc<Miner>) {
+        // The router must exist before the pool can push work to us.
+        let (router, handler) = monitored_channel(CHANNEL_CAPACITY, miner.router_counters.clone());
+        *miner.router.write().await = Some(router);
+        // and the miner must hear from the pool before it connects
+        if let Some(events) = miner.bus.take_events() {
+            spawn_critical(
+                "pool events",
+                Self::handle_pool_events(miner.clone(), events),
+            );
+        }
+        StratumClient::start(miner.stratum_client.clone()).await;
+        MeterRegistry::start(miner.meters.clone()).await;
+        NetworkDifficulty::start(miner.difficulty.clone()).await;
+        PoolApiClient::start(miner.pool_api.clone()).await;
+        let watched = miner.clone();
+        // a panic here only costs the alerts, the next run learns the baseline over again
+        supervise("hashrate baseline", RestartPolicy::default(), move || {
+            Self::watch_hashrate(watched.clone())
+        });
+        if let Some(thermal) = miner.thermal.clone() {
+            let watched = miner.clone();
+            // a panic here leaves the threads as they were until the restart
+            supervise("thermal throttle", RestartPolicy::default(), move || {
+                Self::watch_temperature(watched.clone(), thermal.clone())
+            });
+        }
+        Miner::mine(miner, handler).await;
+    }
+
+    /// Acts on what the pool connection tells, in the order it tells it.
+    async fn handle_pool_events(miner: Arc<Miner>, mut events: mpsc::UnboundedReceiver<PoolEvent>) {
+        while let Some(event) = events.recv().await {
+            match event {
+                PoolEvent::Graffiti(graffiti) => {
+                    if let Err(error) = miner.set_graffiti(&graffiti).await {
+                        error!(
+                            "{}pool sent an unusable graffiti: {}",
+                            miner.log_prefix(),
+                            error
+                        );
+                    }
+                }
+                PoolEvent::Target(target) => miner.set_target(&target).await,
+                PoolEvent::NewWork {
+                    mining_request_id,
+                    header,
+                } => miner.new_work(mining_request_id, &header).await,
+                PoolEvent::WaitForWork(reason) => miner.wait_for_work(reason).await,
+                PoolEvent::ShareSubmitted {
+                    mining_request_id,
+                    at,
+                } => miner.share_submitted(mining_request_id, at).await,
+                PoolEvent::Hashrate(reply) => {
+                    let _ = reply.send(miner.rate_1m().await);
+                }
+                PoolEvent::CurrentJob(reply) => {
+                    let _ = reply.send(miner.current_job().await);
+                }
+            }
+        }
+    }
+
+    /// Reads the CPU temperature every few seconds and has the mining loop mine on fewer
+    /// threads while it is above `--max-temp`.
+    async fn watch_temperature(miner: Arc<Miner>, thermal: Arc<Thermal>) {
+        let threads = miner.cli.threads_count;
+        // a restart starts from the current state, not from full speed
+        let mut interval = miner.clock.interval(THERMAL_POLL_INTERVAL);
+        let mut unreadable_warned = false;
+        loop {
+            interval.tick().await;
+            let (temperature, change) = thermal.poll();
+            let temperature = match temperature {
+                Some(temperature) => temperature,
+                None => {
+                    if !unreadable_warned {
+                        warn!("{}failed to read the CPU temperature", miner.log_prefix());
+                        unreadable_warned = true;
+                    }
+                    continue;
+                }
+            };
+            unreadable_warned = false;
+            let percent = match change {
+                Some(percent) => percent,
+                None => continue,
+            };
+            let active = crate::throttled_threads(threads, percent);
+            if temperature > thermal.max_temp() as f64 {
+                warn!(
+                    "{}CPU at {:.1}°C, above --max-temp {}, throttling to {}% ({} of {} threads)",
+                    miner.log_prefix(),
+                    temperature,
+                    thermal.max_temp(),
+                    percent,
+                    active,
+                    threads
+                );
+            } else {
+                info!(
+                    "{}CPU cooled down to {:.1}°C, mining at {}% ({} of {} threads)",
+                    miner.log_prefix(),
+                    temperature,
+                    percent,
+                    active,
+                    threads
+                );
+            }
+            miner.send_request(MinerRequest::Throttle(active)).await;
+        }
+    }
+
+    /// Compares the 5 minute hashrate against the baseline while mining, and warns when it
+    /// drops and when it recovers.
+    async fn watch_hashrate(miner: Arc<Miner>) {
+        let mut interval = miner.clock.interval(BASELINE_CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            let mut baseline = miner.baseline.write().await;
+            if miner.waiting.load(Ordering::Relaxed) || !miner.stratum_client.can_mine().await {
+                baseline.idle();
+                continue;
+            }
+            let rate = miner.hashrare.rate_over(BASELINE_RATE_WINDOW).await;
+            match baseline.observe(std::time::Instant::now(), rate) {
+                Some(BaselineEvent::Learned { baseline }) => info!(
+                    "{}Hashrate baseline: {}",
+                    miner.log_prefix(),
+                    Meter::format_as(baseline, miner.cli.hashrate_unit)
+                ),
+                Some(BaselineEvent::AlertRaised { rate, baseline }) => warn!(
+                    "{}Hashrate dropped to {} for more than 5 minutes, {:.0}% of the usual {}. Check for thermal throttling or other load on the rig",
+                    miner.log_prefix(),
+                    Meter::format_as(rate, miner.cli.hashrate_unit),
+                    rate / baseline * 100.0,
+                    Meter::format_as(baseline, miner.cli.hashrate_unit)
+                ),
+                Some(BaselineEvent::AlertCleared { rate, baseline }) => info!(
+                    "{}Hashrate recovered to {}, {:.0}% of the usual {}",
+                    miner.log_prefix(),
+                    Meter::format_as(rate, miner.cli.hashrate_unit),
+                    rate / baseline * 100.0,
+                    Meter::format_as(baseline, miner.cli.hashrate_unit)
+                ),
+                None => {}
+            }
+        }
+    }
+
+    /// Stops mining, then the pool connection, so that shares found while stopping are still
+    /// submitted. Everything is down when this returns.
     pub async fn stop(&self) {
+        self.stop_mining().await;
+        self.bus.flush().await;
         self.stratum_client.stop().await;
-        self.hashrare.stop().await;
-        self.send_request(MinerRequest::Stop).await;
+        self.meters.stop().await;
+        self.emit(MinerEvent::Stopped);
+    }
+
+    /// Stops mining and hands the pool session over for an upgrade in place, see
+    /// [`crate::handover`]. Without a session that can be handed over the miner is stopped as
+    /// usual and `None` is returned.
+    #[cfg(unix)]
+    pub async fn hand_over(&self) -> Option<MinerHandover> {
+        self.stop_mining().await;
+        self.bus.flush().await;
+        let handed_over = self.stratum_client.hand_over().await;
+        self.meters.stop().await;
+        self.emit(MinerEvent::Stopped);
+        let (stream, session) = handed_over?;
+        let socket = match detach_socket(stream) {
+            Ok(socket) => socket,
+            Err(error) => {
+                warn!(
+                    "{}failed to hand the pool connection over: {}",
+                    self.log_prefix(),
+                    error
+                );
+                return None;
+            }
+        };
+        Some(MinerHandover {
+            label: self.label.clone(),
+            socket,
+            session,
+            shares_found: self.shares_found.load(Ordering::Relaxed),
+            last_share_at: self.last_share_at.load(Ordering::Relaxed),
+        })
+    }
+
+    /// Goes on with the session a previous process handed over, from the next launch.
+    #[cfg(unix)]
+    pub async fn resume(&self, handover: MinerHandover) -> Result<()> {
+        let stream = attach_socket(handover.socket)?;
+        self.shares_found
+            .store(handover.shares_found, Ordering::Relaxed);
+        self.last_share_at
+            .store(handover.last_share_at, Ordering::Relaxed);
+        self.stratum_client.resume(stream, handover.session).await;
+        Ok(())
+    }
+
+    /// Stops the thread pool and returns once its last shares are submitted.
+    async fn stop_mining(&self) {
+        let (done, stopped) = oneshot::channel();
+        self.send_request(MinerRequest::Stop(done)).await;
+        // without a mining loop the request is dropped and this returns right away
+        if self
+            .clock
+            .timeout(THREAD_POOL_STOP_TIMEOUT * 2, stopped)
+            .await
+            .is_err()
+        {
+            warn!("{}mining loop did not stop in time", self.log_prefix());
+        }
     }
 
     async fn mine(miner: Arc<Miner>, mut miner_handler: MinerHandler) {
         let (router, handler) = oneshot::channel();
-        task::spawn(async move {
+        spawn_critical("mining loop", async move {
             let _ = router.send(());
-            let mut thread_pool =
-                mining::threadpool::ThreadPool::new(miner.cli.threads_count, miner.cli.batch_size);
-            let mut interval = time::interval(Duration::from_millis(10));
-            let mut hash_rate_printer = 0;
+            let mut threads = miner.cli.threads_count;
+            let concurrent_jobs = miner.cli.max_concurrent_jobs as usize;
+            // one thread pool per job mined at once, each job in the slot of its pool
+            let mut thread_pools = build_thread_pools(threads, concurrent_jobs, miner.batch_size);
+            let mut slots: JobSlots<Arc<Work>> = JobSlots::new(concurrent_jobs);
+            let mut paused = true;
+            let mut interval = miner.clock.interval(FOUND_SHARE_POLL);
+            let mut hashrate_interval = miner.clock.interval(HASHRATE_COLLECT_INTERVAL);
+            let mut hash_batch = HashBatch::new(miner.clock.now());
+            // the current jobs last, and in strict mode a few before them
+            let mut recent_jobs: VecDeque<Arc<Work>> = VecDeque::with_capacity(RECENT_JOBS);
+            let mut active = miner.active.subscribe();
             loop {
+                if !*active.borrow() {
+                    miner.idle_wakeups.fetch_add(1, Ordering::Relaxed);
+                }
                 tokio::select! {
-                    _ = interval.tick() => {
-                        if !miner.stratum_client.is_subscribed() {
-                            tokio::time::sleep(Duration::from_millis(50)).await;
-                            continue;
+                    now = hashrate_interval.tick() => {
+                        // taken every tick, so that hashes from before a pause do not show up
+                        // in the rate after it
+                        let amounts = thread_pools
+                            .iter()
+                            .map(|thread_pool| thread_pool.get_hash_rate_submission())
+                            .sum();
+                        miner.hashes.fetch_add(amounts, Ordering::Relaxed);
+                        // also catches the pool going away while mining through a disconnect
+                        if miner.refresh_state().await {
+                            hash_batch.add(amounts);
+                        } else {
+                            miner.idle_hashes.fetch_add(amounts, Ordering::Relaxed);
+                        }
+                        if let Some(hashes) = hash_batch.flush(now) {
+                            miner.hashrare.add(hashes).await;
                         }
-                        let graffiti_is_none = miner.graffiti.read().await.is_none();
-                        if graffiti_is_none {
-                            tokio::time::sleep(Duration::from_millis(50)).await;
-                            continue;
+                        let alert = miner.pauses.lock().unwrap().disconnected_alert(std::time::Instant::now(), DISCONNECTED_ALERT_AFTER);
+                        if let Some(paused) = alert {
+                            warn!(
+                                "{}not mining for {}s, the pool connection is still lost",
+                                miner.log_prefix(),
+                                paused.as_secs()
+                            );
                         }
-                        let block_result = thread_pool.get_found_block();
-                        if let Some((randomness, mining_request_id)) = block_result {
-                            info!(
-                                "Found share: randomness({}) mining_request_id({}) {} .",
-                                randomness,
-                                mining_request_id,
-                                Meter::format(miner.hashrare.get_rate_1s().await),
-                             );
-                            miner.stratum_client.submit(mining_request_id, hex::encode(randomness.to_be_bytes())).await;
-                            hash_rate_printer = 0;
+                    }
+                    Ok(()) = active.changed() => {
+                        // poll from now on, rather than catching up on the ticks missed while idle
+                        if *active.borrow() {
+                            interval.reset();
                         }
-                        // hashrate
-                        let amounts = thread_pool.get_hash_rate_submission();
-                        miner.hashrare.add(amounts as u64).await;
-                        hash_rate_printer = (hash_rate_printer + 1) % 10000;
-                        if hash_rate_printer == 0 {
-                            info!("Hash Rate: {}", Meter::format(miner.hashrare.get_rate_1s().await));
+                    }
+                    _ = interval.tick(), if *active.borrow() => {
+                        for thread_pool in &thread_pools {
+                            let block_result = thread_pool.get_found_block();
+                            if let Some((randomness, mining_request_id)) = block_result {
+                                miner.found_share(randomness, mining_request_id, &recent_jobs).await;
+                            }
                         }
-
                     }
                     Some(request) = miner_handler.recv() => match request {
-                        MinerRequest::NewWork(header_bytes, target, mining_request_id) => {
-                            thread_pool.new_work(header_bytes.as_slice(), target.as_slice(), mining_request_id);
+                        MinerRequest::NewWork(work) => {
+                            let (slot, _) = slots.assign(work.mining_request_id, work.clone());
+                            thread_pools[slot].new_work(&work.header, &work.target, work.mining_request_id, miner.randomness_start);
+                            if concurrent_jobs > 1 {
+                                debug!(
+                                    "{}mining request id({}) on thread pool {}, mining request ids({:?})",
+                                    miner.log_prefix(),
+                                    work.mining_request_id,
+                                    slot,
+                                    slots.mining_request_ids()
+                                );
+                            }
+                            paused = false;
+                            // the shares of the other jobs mined at once are still found, the
+                            // set forgets the oldest ones instead
+                            if concurrent_jobs == 1 {
+                                miner.found_shares.lock().unwrap().clear();
+                            }
+                            miner.job_latency.write().await.dispatched(work.mining_request_id, std::time::Instant::now());
+                            let keep = if miner.cli.strict_target { RECENT_JOBS } else { 1 } + concurrent_jobs - 1;
+                            recent_jobs.push_back(work);
+                            while recent_jobs.len() > keep {
+                                if let Some(previous) = recent_jobs.pop_front() {
+                                    miner.header_buffers.recycle(previous);
+                                }
+                            }
                         },
-                        MinerRequest::WaitForWork => {
-                            thread_pool.pause();
+                        MinerRequest::WaitForWork(reason) => {
+                            debug!("{}mining threads paused ({})", miner.log_prefix(), reason);
+                            for thread_pool in &thread_pools {
+                                thread_pool.pause();
+                            }
+                            slots.clear();
+                            paused = true;
+                        }
+                        // a thread pool can't change its size, new ones take over the current
+                        // jobs once the old ones have handed in their last shares
+                        MinerRequest::Throttle(active) if active != threads => {
+                            for thread_pool in &thread_pools {
+                                thread_pool.stop();
+                            }
+                            miner.drain(&thread_pools, &recent_jobs, false).await;
+                            threads = active;
+                            thread_pools = build_thread_pools(threads, concurrent_jobs, miner.batch_size);
+                            for (slot, work) in slots.jobs().filter(|_| !paused) {
+                                thread_pools[slot].new_work(&work.header, &work.target, work.mining_request_id, miner.randomness_start);
+                            }
                         }
-                        MinerRequest::Stop => {
+                        MinerRequest::Throttle(_) => {}
+                        MinerRequest::Stop(done) => {
                             debug!("miner stop.");
-                            thread_pool.stop();
-                            // A delay to allow thread pool resources to be released
-                            tokio::time::sleep(Duration::from_millis(2000)).await;
+                            for thread_pool in &thread_pools {
+                                thread_pool.stop();
+                            }
+                            miner.drain(&thread_pools, &recent_jobs, true).await;
+                            let _ = done.send(());
                             break;
                         }
                     }
@@ -172,35 +1423,447 @@ impl Miner {
         let _ = handler.await;
     }
 
-    async fn send_request(&self, request: MinerRequest) {
-        if self.router.read().await.is_none() {
+    /// The last target and the job being mined, as the pool sent them.
+    pub async fn current_job(&self) -> (Option<String>, Option<AssembledJob>) {
+        let job_assembler = self.job_assembler.read().await;
+        (
+            job_assembler.target().map(String::from),
+            job_assembler.active().cloned(),
+        )
+    }
+
+    /// Whether the thread pool's hashes are left out of the hashrate: no job, the pool is gone,
+    /// or no graffiti yet.
+    async fn is_idle(&self) -> bool {
+        self.waiting.load(Ordering::Relaxed)
+            || !self.stratum_client.can_mine().await
+            || self.graffiti.read().await.is_none()
+    }
+
+    /// Updates the state the mining loop waits on, and returns whether the miner is active.
+    async fn refresh_state(&self) -> bool {
+        let active = !self.is_idle().await;
+        self.active
+            .send_if_modified(|state| std::mem::replace(state, active) != active);
+        active
+    }
+
+    #[cfg(test)]
+    pub(crate) fn idle_wakeups(&self) -> u64 {
+        self.idle_wakeups.load(Ordering::Relaxed)
+    }
+
+    async fn found_share(
+        &self,
+        randomness: u64,
+        mining_request_id: u32,
+        recent_jobs: &VecDeque<Arc<Work>>,
+    ) {
+        if !self
+            .found_shares
+            .lock()
+            .unwrap()
+            .insert((mining_request_id, randomness))
+        {
+            debug!(
+                "{}share found again after a thread pool change: randomness({}) mining_request_id({})",
+                self.log_prefix(),
+                randomness,
+                mining_request_id
+            );
             return;
         }
-        let _ = self
-            .router
-            .read()
+        let now = std::time::Instant::now();
+        // the latest job of the id, a job replaced with its id mines the new header
+        let work = recent_jobs
+            .iter()
+            .rev()
+            .find(|work| work.mining_request_id == mining_request_id);
+        if !self
+            .passes_share_guard(randomness, mining_request_id, work, now)
             .await
-            .as_ref()
-            .unwrap()
-            .send(request)
-            .await;
+        {
+            return;
+        }
+        let notified_at = {
+            let mut job_latency = self.job_latency.write().await;
+            job_latency.share_found(mining_request_id, now);
+            job_latency.notified_at(mining_request_id)
+        };
+        let epoch = self
+            .stratum_client
+            .session()
+            .await
+            .map(|session| session.epoch);
+        let session_share_index = {
+            let mut session_shares = self.session_shares.lock().unwrap();
+            if session_shares.0 != epoch {
+                *session_shares = (epoch, 0);
+            }
+            session_shares.1 += 1;
+            session_shares.1 - 1
+        };
+        let suspicious = self.share_intervals.lock().unwrap().share_found(epoch, now);
+        if let Some(verdict) = suspicious {
+            warn!(
+                "{}share intervals of this pool session are {}",
+                self.log_prefix(),
+                verdict
+            );
+        }
+        let share = ShareLog::new(
+            self.clock.unix_timestamp_millis(),
+            mining_request_id,
+            randomness,
+            work.map(|work| &work.target),
+            notified_at
+                .map(|notified_at| now.saturating_duration_since(notified_at).as_millis() as u64),
+            session_share_index,
+        );
+        info!("{}{}", self.log_prefix(), share.format());
+        debug!(
+            "{}Found share: randomness({}) mining_request_id({}) {} .",
+            self.log_prefix(),
+            randomness,
+            mining_request_id,
+            Meter::format_as(self.hashrare.get_rate_1s().await, self.cli.hashrate_unit),
+        );
+        self.share_rate.add(1).await;
+        self.shares_found.fetch_add(1, Ordering::Relaxed);
+        self.emit(MinerEvent::ShareFound {
+            mining_request_id,
+            randomness,
+        });
+        self.last_share_at
+            .store(self.clock.unix_timestamp(), Ordering::Relaxed);
+        if let Some(work) = work {
+            self.record_best_share(randomness, work);
+        }
+        let header = work.map(|work| &work.header[..]);
+        if self.should_submit(header, randomness).await {
+            let decision = self.share_hook.decide(&ShareCandidate {
+                mining_request_id,
+                randomness,
+                header,
+                target: work.map(|work| &work.target),
+                found_at: now,
+                notified_at,
+            });
+            let randomness = match decision {
+                ShareDecision::Submit => randomness,
+                ShareDecision::Replace { randomness } => randomness,
+                ShareDecision::Drop { reason } => {
+                    info!(
+                        "{}Dropped share of mining request id({}) by the share hook: {}",
+                        self.log_prefix(),
+                        mining_request_id,
+                        reason
+                    );
+                    return;
+                }
+            };
+            // a wider randomness field takes the rest of its bytes from the job
+            let field = match header {
+                Some(header) => Randomness::in_header(randomness, header, &self.layout),
+                None if self.layout.randomness_width == Randomness::SEARCH_SIZE => {
+                    Ok(Randomness::from_value(randomness, &self.layout))
+                }
+                None => Err(anyhow::anyhow!("the job is no longer known")),
+            };
+            match field {
+                Ok(field) => {
+                    self.shares_submitted.fetch_add(1, Ordering::Relaxed);
+                    // replaced jobs included, every share submitted is valued
+                    self.share_value
+                        .lock()
+                        .unwrap()
+                        .share_submitted(epoch, mining_request_id);
+                    let elapsed_ms = self
+                        .share_guard
+                        .lock()
+                        .unwrap()
+                        .dispatched_at(mining_request_id)
+                        .map(|at| now.saturating_duration_since(at).as_millis() as u64);
+                    let command = MinerCommand::Submit {
+                        mining_request_id,
+                        randomness: field.to_wire_hex(),
+                        elapsed_ms,
+                    };
+                    if self.bus.send(command).await.is_err() {
+                        warn!(
+                            "{}pool connection is not taking shares, dropped share of mining request id({})",
+                            self.log_prefix(),
+                            mining_request_id
+                        );
+                    }
+                }
+                Err(error) => warn!(
+                    "{}Dropped share of mining request id({}): {}",
+                    self.log_prefix(),
+                    mining_request_id,
+                    error
+                ),
+            }
+        } else {
+            warn!(
+                "{}Dropped share of mining request id({}): below the current target",
+                self.log_prefix(),
+                mining_request_id
+            );
+        }
+    }
+
+    /// Keeps the difficulty of the share if it is the best so far. Positive floats order like
+    /// their bits, so the highest one wins.
+    fn record_best_share(&self, randomness: u64, work: &Work) {
+        let hash = Randomness::in_header(randomness, &work.header, &self.layout)
+            .and_then(|field| share_hash(&work.header, &field, &self.layout));
+        if let Ok(hash) = hash {
+            let difficulty = Target(hash).difficulty();
+            self.best_share
+                .fetch_max(difficulty.to_bits(), Ordering::Relaxed);
+        }
+    }
+
+    /// Hashes a share the [`ShareGuard`] flags again, against the target of its job. Returns
+    /// `false` for one that does not meet it. Shares of jobs no longer known, and every share
+    /// that is not flagged, pass unchecked.
+    async fn passes_share_guard(
+        &self,
+        randomness: u64,
+        mining_request_id: u32,
+        work: Option<&Arc<Work>>,
+        now: std::time::Instant,
+    ) -> bool {
+        let suspicion = match self.share_guard.lock().unwrap().check(randomness, now) {
+            Some(suspicion) => suspicion,
+            None => return true,
+        };
+        self.suspicious_shares.fetch_add(1, Ordering::Relaxed);
+        let work = match work {
+            Some(work) => work,
+            None => return true,
+        };
+        let verified = Randomness::in_header(randomness, &work.header, &self.layout)
+            .and_then(|field| verify_share(&work.header, &field, &work.target, &self.layout));
+        if !matches!(verified, Ok(false)) {
+            return true;
+        }
+        self.suspicious_shares_dropped
+            .fetch_add(1, Ordering::Relaxed);
+        let job_age = match self.job_latency.read().await.notified_at(mining_request_id) {
+            Some(notified_at) => format!(
+                "{:.1}s",
+                now.saturating_duration_since(notified_at).as_secs_f64()
+            ),
+            None => String::from("unknown"),
+        };
+        warn!(
+            "{}Dropped share of mining request id({}) with randomness({:016x}), {} and not meeting the job's target, job age {}",
+            self.log_prefix(),
+            mining_request_id,
+            randomness,
+            suspicion,
+            job_age
+        );
+        false
+    }
+
+    /// Waits for stopped thread pools to finish their last batches, submitting the shares they
+    /// find, until they report nothing for a while. When the miner is `stopping` those shares
+    /// are counted apart, and dropped with `--discard-shares-on-stop`.
+    async fn drain(
+        &self,
+        thread_pools: &[ThreadPool],
+        recent_jobs: &VecDeque<Arc<Work>>,
+        stopping: bool,
+    ) {
+        let started = self.clock.now();
+        let mut quiet_since = started;
+        loop {
+            let mut busy = false;
+            for thread_pool in thread_pools {
+                busy |= thread_pool.get_hash_rate_submission() > 0;
+            }
+            let found = thread_pools
+                .iter()
+                .flat_map(|thread_pool| std::iter::from_fn(|| thread_pool.get_found_block()))
+                .collect::<Vec<_>>();
+            for (randomness, mining_request_id) in found {
+                busy = true;
+                if stopping && self.cli.discard_shares_on_stop {
+                    debug!(
+                        "{}Discarded share of mining request id({}) found while stopping",
+                        self.log_prefix(),
+                        mining_request_id
+                    );
+                    self.shares_discarded_on_stop
+                        .fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
+                let submitted = self.shares_submitted.load(Ordering::Relaxed);
+                self.found_share(randomness, mining_request_id, recent_jobs)
+                    .await;
+                if stopping && self.shares_submitted.load(Ordering::Relaxed) > submitted {
+                    self.shares_submitted_on_stop
+                        .fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            let now = self.clock.now();
+            if busy {
+                quiet_since = now;
+            }
+            if now - quiet_since >= THREAD_POOL_QUIET {
+                return;
+            }
+            if now - started >= THREAD_POOL_STOP_TIMEOUT {
+                warn!(
+                    "{}mining threads still busy {:?} after stop",
+                    self.log_prefix(),
+                    THREAD_POOL_STOP_TIMEOUT
+                );
+                return;
+            }
+            self.clock.sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    async fn send_request(&self, request: MinerRequest) {
+        let router = self.router.read().await;
+        let router = match router.as_ref() {
+            Some(router) => router,
+            None => return,
+        };
+        match request {
+            // pausing is advisory, the next job resumes mining anyway
+            MinerRequest::WaitForWork(_) => {
+                if !router.try_send(request) {
+                    warn!(
+                        "{}mining loop is not taking requests, pause dropped",
+                        self.log_prefix()
+                    );
+                }
+            }
+            _ => {
+                if let Err(request) = router.send(request, SEND_TIMEOUT).await {
+                    error!(
+                        "{}mining loop is not taking requests, dropped {:?}",
+                        self.log_prefix(),
+                        request
+                    );
+                }
+            }
+        }
     }
 }
 
+/// The graffiti as it goes into the header: its bytes padded with zeros, and whether it had to
+/// be cut to fit [`GRAFFITI_SIZE`] bytes.
+///
+/// A longer graffiti is cut at the last whole character that fits, the way the Iron Fish node
+/// writes a graffiti string into its 32 bytes, so that a multibyte character is never split.
+pub fn graffiti_bytes(graffiti: &str) -> Result<([u8; GRAFFITI_SIZE], bool)> {
+    if graffiti.is_empty() {
+        return Err(anyhow::anyhow!("empty graffiti"));
+    }
+    let mut len = graffiti.len().min(GRAFFITI_SIZE);
+    while !graffiti.is_char_boundary(len) {
+        len -= 1;
+    }
+    let mut bytes = [0; GRAFFITI_SIZE];
+    bytes[..len].copy_from_slice(&graffiti.as_bytes()[..len]);
+    Ok((bytes, len < graffiti.len()))
+}
+
+/// Writes `suffix` into the graffiti from byte `start` on, where the pool's graffiti ends, cut
+/// at the last whole character that fits. Returns how many bytes of it were written.
+pub fn append_graffiti_suffix(
+    bytes: &mut [u8; GRAFFITI_SIZE],
+    start: usize,
+    suffix: &str,
+) -> usize {
+    let mut len = suffix.len().min(GRAFFITI_SIZE.saturating_sub(start));
+    while !suffix.is_char_boundary(len) {
+        len -= 1;
+    }
+    bytes[start.min(GRAFFITI_SIZE)..][..len].copy_from_slice(&suffix.as_bytes()[..len]);
+    len
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_util::{
+        easy_target, known_header, notify_message, set_target_message, subscribed_message,
+        test_cli, MockPoolListener, TestMinerBuilder, KNOWN_HEADER,
+    };
+    use crate::{
+        MiningSubmitBody, RandomnessStart, StratumMessage, CAPABILITY_SOLVE_TIME,
+        DEFAULT_WORKER_NAME,
+    };
+    use futures::SinkExt;
+    use std::collections::HashMap;
+    use tokio::time;
+    use tokio_stream::StreamExt;
 
     async fn prepare_test_miner() -> Arc<Miner> {
         let cli = Cli {
-            pool: "127.0.0.1:8080".parse().unwrap(),
-            address: String::from("xxxxxx"),
-            worker_name: String::from("xxxxxx"),
             threads_count: 16,
             batch_size: 10000,
+            ..test_cli("127.0.0.1:8080".parse().unwrap())
         };
-        Miner::initialize(cli).await
+        Miner::initialize(cli).await.unwrap()
     }
+    #[tokio::test]
+    async fn test_strict_target() {
+        let miner = prepare_test_miner().await;
+        let header = [0u8; 208];
+        // without strict mode every share is submitted
+        assert!(miner.should_submit(Some(&header), 0).await);
+
+        let mut cli = miner.cli.clone();
+        cli.strict_target = true;
+        let miner = Miner::initialize(cli).await.unwrap();
+        let easy_target = format!("0f{}", "ff".repeat(31));
+        miner.set_target(&easy_target).await;
+        let mut target = [0u8; 32];
+        target.copy_from_slice(&hex::decode(&easy_target).unwrap());
+        let randomness = (0..)
+            .find(|randomness| {
+                let randomness = Randomness::from_value(*randomness, &miner.layout);
+                verify_share(&header, &randomness, &target, &miner.layout).unwrap()
+            })
+            .unwrap();
+        assert!(miner.should_submit(Some(&header), randomness).await);
+
+        // the pool raised the difficulty after the job was dispatched
+        miner.set_target(&"00".repeat(32)).await;
+        assert!(!miner.should_submit(Some(&header), randomness).await);
+        assert_eq!(miner.stats().await.shares_below_target, 1);
+        // shares of jobs no longer known are submitted unchecked
+        assert!(miner.should_submit(None, randomness).await);
+    }
+
+    #[test]
+    fn test_hash_batch() {
+        let start = Instant::now();
+        let mut batch = HashBatch::new(start);
+        // polled as often as found shares used to be, for ten seconds
+        let mut flushes = 0;
+        let mut flushed = 0;
+        for tick in 1..=1000u32 {
+            batch.add(1000);
+            if let Some(hashes) = batch.flush(start + FOUND_SHARE_POLL * tick) {
+                flushes += 1;
+                flushed += hashes;
+            }
+        }
+        // one meter add per second instead of one per poll, with no hash lost
+        assert_eq!(flushes, 10);
+        assert_eq!(flushed, 1000 * 1000);
+        assert_eq!(batch.flush(start + Duration::from_secs(11)), Some(0));
+    }
+
     #[tokio::test]
     async fn test_target() {
         let target_hex = [
@@ -215,6 +1878,22 @@ mod tests {
         assert_eq!(target_hex, *miner.target.read().await);
     }
 
+    #[tokio::test]
+    async fn test_target_shorthands() {
+        let miner = prepare_test_miner().await;
+        // a target that does not parse is ignored
+        miner.set_target("0x0000").await;
+        assert_eq!(&miner.target.read().await[..3], &[0x00, 0x00, 0xff]);
+        miner.set_target("0000zz").await;
+        assert_eq!(&miner.target.read().await[..3], &[0x00, 0x00, 0xff]);
+        miner.set_target("diff:256").await;
+        assert_eq!(&miner.target.read().await[..2], &[0x01, 0x00]);
+        // the ignored one is not in the history
+        let history = miner.stats().await.target_history;
+        assert_eq!(history.changes.len(), 2);
+        assert_eq!(history.difficulty, Some(256.0));
+    }
+
     #[tokio::test]
     async fn test_graffiti() {
         let graffiti_hex = [
@@ -224,17 +1903,689 @@ mod tests {
         ];
         let graffiti_string = String::from("Iron Fish Pool.1");
         let miner = prepare_test_miner().await;
-        miner.set_graffiti(&graffiti_string[..]).await;
+        miner.set_graffiti(&graffiti_string[..]).await.unwrap();
         println!("{:0x?}", miner.graffiti.read().await.unwrap());
         assert_eq!(graffiti_hex, miner.graffiti.read().await.unwrap());
+
+        // an empty one keeps the graffiti
+        assert!(miner.set_graffiti("").await.is_err());
+        assert_eq!(graffiti_hex, miner.graffiti.read().await.unwrap());
+    }
+
+    #[test]
+    fn test_graffiti_bytes() {
+        let exact = "a".repeat(32);
+        assert_eq!(
+            graffiti_bytes(&exact).unwrap(),
+            (*b"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa", false)
+        );
+        let (bytes, truncated) = graffiti_bytes(&"b".repeat(33)).unwrap();
+        assert_eq!((bytes, truncated), ([b'b'; 32], true));
+
+        // "é" is two bytes, the one starting at byte 31 does not fit
+        let graffiti = format!("{}é", "c".repeat(31));
+        let (bytes, truncated) = graffiti_bytes(&graffiti).unwrap();
+        assert!(truncated);
+        assert_eq!(&bytes[..31], "c".repeat(31).as_bytes());
+        assert_eq!(bytes[31], 0);
+        // and one ending at byte 32 does
+        let graffiti = format!("{}é", "c".repeat(30));
+        assert_eq!(
+            graffiti_bytes(&graffiti).unwrap().0[..],
+            *graffiti.as_bytes()
+        );
+
+        assert!(graffiti_bytes("").is_err());
+    }
+
+    /// Hands the miner a job and returns the jobs it dispatched.
+    async fn dispatched(
+        miner: &Miner,
+        events: &mut broadcast::Receiver<MinerEvent>,
+        mining_request_id: u32,
+        header: &str,
+    ) -> Vec<u32> {
+        miner.new_work(mining_request_id, header).await;
+        jobs(events)
+    }
+
+    #[tokio::test]
+    async fn test_repeated_notify() {
+        let miner = prepare_test_miner().await;
+        let mut events = miner.subscribe_events();
+        miner.set_graffiti("zk.work").await.unwrap();
+        miner.set_target(&"ff".repeat(32)).await;
+        let header = "00".repeat(208);
+        let other_header = format!("01{}", "00".repeat(207));
+        assert_eq!(dispatched(&miner, &mut events, 7, &header).await, vec![7]);
+        // the same job again is ignored
+        assert!(dispatched(&miner, &mut events, 7, &header).await.is_empty());
+        assert_eq!(miner.stats().await.redundant_notifies, 1);
+        // the same id with a new header, and a new id, are mined
+        assert_eq!(
+            dispatched(&miner, &mut events, 7, &other_header).await,
+            vec![7]
+        );
+        assert_eq!(
+            dispatched(&miner, &mut events, 8, &other_header).await,
+            vec![8]
+        );
+        assert_eq!(miner.stats().await.redundant_notifies, 1);
+    }
+
+    #[tokio::test]
+    async fn test_share_guard() {
+        let miner = prepare_test_miner().await;
+        let mut events = miner.subscribe_events();
+        miner.set_graffiti("zk.work").await.unwrap();
+        let header = "00".repeat(208);
+        let work = |mining_request_id: u32, target: [u8; 32]| {
+            VecDeque::from([Arc::new(Work {
+                mining_request_id,
+                header: vec![0; 208].into(),
+                target,
+            })])
+        };
+        let counted = || miner.session_shares.lock().unwrap().1;
+
+        // a zero right after the dispatch that does not meet the target is dropped
+        miner.set_target(&"00".repeat(32)).await;
+        assert_eq!(dispatched(&miner, &mut events, 7, &header).await, vec![7]);
+        miner.found_share(0, 7, &work(7, [0; 32])).await;
+        let stats = miner.stats().await;
+        assert_eq!(stats.suspicious_shares, 1);
+        assert_eq!(stats.suspicious_shares_dropped, 1);
+        assert_eq!(counted(), 0);
+
+        // one that meets it is submitted
+        miner.set_target(&"ff".repeat(32)).await;
+        assert_eq!(dispatched(&miner, &mut events, 8, &header).await, vec![8]);
+        miner.found_share(0, 8, &work(8, [0xff; 32])).await;
+        let stats = miner.stats().await;
+        assert_eq!(stats.suspicious_shares, 2);
+        assert_eq!(stats.suspicious_shares_dropped, 1);
+        assert_eq!(counted(), 1);
+
+        // as is an unsuspicious share, whatever the target
+        miner.found_share(12345, 8, &work(8, [0; 32])).await;
+        assert_eq!(miner.stats().await.suspicious_shares, 2);
+        assert_eq!(counted(), 2);
+    }
+
+    /// The ids of the jobs dispatched since the last call.
+    fn jobs(events: &mut broadcast::Receiver<MinerEvent>) -> Vec<u32> {
+        let mut jobs = vec![];
+        while let Ok(event) = events.try_recv() {
+            if let MinerEvent::NewJob { mining_request_id } = event {
+                jobs.push(mining_request_id);
+            }
+        }
+        jobs
+    }
+
+    #[tokio::test]
+    async fn test_user_pause() {
+        let miner = prepare_test_miner().await;
+        let mut events = miner.subscribe_events();
+        // nothing to resume into yet
+        assert!(miner.pause_mining().await);
+        assert!(!miner.pause_mining().await);
+        assert!(miner.resume_mining().await);
+        assert!(!miner.resume_mining().await);
+        assert_eq!(miner.stats().await.pauses.reason, None);
+
+        miner.set_graffiti("zk.work").await.unwrap();
+        miner.set_target(&"ff".repeat(32)).await;
+        let header = "00".repeat(208);
+        assert_eq!(dispatched(&miner, &mut events, 7, &header).await, vec![7]);
+
+        // pause, notify, resume: the job of the pause is mined against the target sent after it
+        assert!(miner.pause_mining().await);
+        assert_eq!(miner.stats().await.pauses.reason, Some(PauseReason::Manual));
+        assert!(dispatched(&miner, &mut events, 8, &header).await.is_empty());
+        let target = format!("0f{}", "ff".repeat(31));
+        miner.set_target(&target).await;
+        assert!(jobs(&mut events).is_empty());
+        assert!(miner.resume_mining().await);
+        assert_eq!(jobs(&mut events), vec![8]);
+        let (_, job) = miner.current_job().await;
+        let job = job.unwrap();
+        assert_eq!((job.mining_request_id, job.target), (8, target));
+        let pauses = miner.stats().await.pauses;
+        assert_eq!(pauses.reason, None);
+        assert!(pauses.manual_secs > 0.0);
+
+        // pause, resume with no new job: the job of before the pause goes on
+        miner.pause_mining().await;
+        miner.resume_mining().await;
+        assert_eq!(jobs(&mut events), vec![8]);
+
+        // the pool asked to wait during the pause, so resuming waits too
+        miner.pause_mining().await;
+        miner.wait_for_work(PauseReason::PoolRequested).await;
+        assert_eq!(miner.stats().await.pauses.reason, Some(PauseReason::Manual));
+        miner.resume_mining().await;
+        assert!(jobs(&mut events).is_empty());
+        assert_eq!(
+            miner.stats().await.pauses.reason,
+            Some(PauseReason::PoolRequested)
+        );
+        assert_eq!(dispatched(&miner, &mut events, 9, &header).await, vec![9]);
+    }
+
+    #[tokio::test]
+    async fn test_user_pause_requires_fresh_work() {
+        let mut cli = prepare_test_miner().await.cli.clone();
+        cli.resume_requires_fresh_work = true;
+        let miner = Miner::initialize(cli).await.unwrap();
+        let mut events = miner.subscribe_events();
+        miner.set_graffiti("zk.work").await.unwrap();
+        miner.set_target(&"ff".repeat(32)).await;
+        let header = "00".repeat(208);
+        assert_eq!(dispatched(&miner, &mut events, 7, &header).await, vec![7]);
+
+        // pause, notify, resume: still paused until the next job
+        miner.pause_mining().await;
+        assert!(dispatched(&miner, &mut events, 8, &header).await.is_empty());
+        miner.resume_mining().await;
+        assert!(jobs(&mut events).is_empty());
+        assert_eq!(miner.stats().await.pauses.reason, Some(PauseReason::Manual));
+        assert_eq!(dispatched(&miner, &mut events, 9, &header).await, vec![9]);
+        assert_eq!(miner.stats().await.pauses.reason, None);
+
+        // pause, resume with no new job: the same
+        miner.pause_mining().await;
+        miner.resume_mining().await;
+        assert!(jobs(&mut events).is_empty());
+        assert_eq!(dispatched(&miner, &mut events, 10, &header).await, vec![10]);
+    }
+
+    #[tokio::test]
+    async fn test_job_expiry_pauses_mining() {
+        let listener = MockPoolListener::bind().await;
+        let mut miner = TestMinerBuilder::new(listener.address())
+            .cli(|cli| cli.job_ttl = 1)
+            .build()
+            .await
+            .unwrap();
+        miner.launch().await;
+        let mut pool = listener.accept().await;
+        pool.accept_subscribe().await;
+        assert!(pool.easy_job(7, KNOWN_HEADER).await);
+        miner
+            .wait_for(|event| matches!(event, MinerEvent::NewJob { .. }))
+            .await;
+        miner
+            .wait_for(|event| {
+                *event
+                    == MinerEvent::WaitingForWork {
+                        reason: PauseReason::JobExpired,
+                    }
+            })
+            .await;
+        assert_eq!(miner.stats().await.expired_jobs, 1);
+        miner.stop().await;
+    }
+
+    /// The pool floods the miner with jobs while every hash is a share, so that jobs and
+    /// shares cross on the bus at full speed in both directions.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_flood_jobs_and_shares() {
+        const JOBS: u32 = 2000;
+        let listener = MockPoolListener::bind().await;
+        let miner = TestMinerBuilder::new(listener.address())
+            .cli(|cli| cli.max_submit_rate = "100000/1s".parse().unwrap())
+            .build()
+            .await
+            .unwrap();
+        miner.launch().await;
+        let mut pool = listener.accept().await;
+        pool.accept_subscribe().await;
+        assert!(pool.send(set_target_message(&easy_target())).await);
+        let (mut reader, mut writer) = pool.into_parts();
+        let jobs = tokio::spawn(async move {
+            for mining_request_id in 1..=JOBS {
+                let notify = notify_message(mining_request_id, KNOWN_HEADER);
+                writer.send(notify).await.unwrap();
+            }
+            writer
+        });
+        // read on to the end, a pool that stops reading would hold up the stop
+        let (last_job, last_job_submitted) = oneshot::channel();
+        tokio::spawn(async move {
+            let mut last_job = Some(last_job);
+            while let Some(Ok(message)) = reader.next().await {
+                if let StratumMessage::MiningSubmitMessage(submit) = message {
+                    if submit.body.miningRequestId == JOBS {
+                        if let Some(last_job) = last_job.take() {
+                            let _ = last_job.send(());
+                        }
+                    }
+                }
+            }
+        });
+        time::timeout(Duration::from_secs(30), last_job_submitted)
+            .await
+            .expect("shares of the last job should reach the pool")
+            .unwrap();
+        let _writer = jobs.await.unwrap();
+        time::timeout(Duration::from_secs(10), miner.stop())
+            .await
+            .expect("stop should not wait on a full bus");
+        assert_eq!(miner.stats().await.command_channel.timeouts, 0);
+    }
+
+    /// The pool hands out two jobs at once, each is mined on half the threads and both get
+    /// shares submitted with their own id.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_concurrent_jobs() {
+        let listener = MockPoolListener::bind().await;
+        let miner = TestMinerBuilder::new(listener.address())
+            .threads(2)
+            .cli(|cli| {
+                cli.max_concurrent_jobs = 2;
+                cli.max_submit_rate = "100000/1s".parse().unwrap();
+            })
+            .build()
+            .await
+            .unwrap();
+        miner.launch().await;
+        let mut pool = listener.accept().await;
+        pool.accept_subscribe().await;
+        let mut secondary = known_header();
+        secondary[HeaderLayout::IRONFISH.graffiti_offset - 1] ^= 1;
+        assert!(
+            pool.send_all([
+                set_target_message(&easy_target()),
+                notify_message(1, KNOWN_HEADER),
+                notify_message(2, &hex::encode(secondary)),
+            ])
+            .await
+        );
+        let mut submitted = HashMap::new();
+        while submitted.len() < 2 {
+            let submit = pool.expect_submit().await;
+            *submitted.entry(submit.miningRequestId).or_insert(0) += 1;
+        }
+        assert!(submitted.contains_key(&1) && submitted.contains_key(&2));
+        let (_, job) = miner.current_job().await;
+        assert_eq!(job.unwrap().mining_request_id, 2);
+        miner.stop().await;
+    }
+
+    /// A pool that agreed to `solve_time` gets how long each share took since its job was
+    /// dispatched.
+    #[tokio::test]
+    async fn test_submit_solve_time() {
+        let listener = MockPoolListener::bind().await;
+        let miner = TestMinerBuilder::new(listener.address())
+            .build()
+            .await
+            .unwrap();
+        miner.launch().await;
+        let mut pool = listener.accept().await;
+        let subscribe = pool.expect_subscribe().await;
+        assert!(subscribe
+            .capabilities
+            .contains(&String::from(CAPABILITY_SOLVE_TIME)));
+        assert!(
+            pool.send(subscribed_message(
+                1,
+                "zk.work",
+                Some(vec![String::from(CAPABILITY_SOLVE_TIME)]),
+            ))
+            .await
+        );
+        let started = std::time::Instant::now();
+        assert!(pool.easy_job(7, KNOWN_HEADER).await);
+        let submit = pool.expect_submit().await;
+        assert_eq!(submit.miningRequestId, 7);
+        let elapsed_ms = submit.elapsedMs.expect("the pool agreed to solve_time");
+        assert!(elapsed_ms <= started.elapsed().as_millis() as u64);
+        miner.stop().await;
+    }
+
+    /// The pool raises the difficulty twice while jobs are mined. Each share is valued by the
+    /// target its job was dispatched with, not by the one set since.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_share_value_under_vardiff() {
+        let listener = MockPoolListener::bind().await;
+        let miner = TestMinerBuilder::new(listener.address())
+            .cli(|cli| cli.max_submit_rate = "100000/1s".parse().unwrap())
+            .build()
+            .await
+            .unwrap();
+        miner.launch().await;
+        let mut pool = listener.accept().await;
+        pool.accept_subscribe().await;
+        let target = |difficulty: f64| Target::from_difficulty(difficulty).unwrap().to_string();
+        let mut values: HashMap<u32, f64> = HashMap::new();
+        let mut expected = 0.0;
+        let mut count = |submit: MiningSubmitBody, values: &HashMap<u32, f64>| {
+            expected += values[&submit.miningRequestId];
+            submit.miningRequestId
+        };
+
+        // job 1 at difficulty 1, then the target gets harder while it is mined
+        assert!(pool.easy_job(1, KNOWN_HEADER).await);
+        values.insert(1, 1.0);
+        count(pool.expect_submit().await, &values);
+        assert!(pool.send(set_target_message(&target(2.0))).await);
+        // job 2 is dispatched at difficulty 2, then the target gets harder again
+        assert!(pool.send(notify_message(2, KNOWN_HEADER)).await);
+        values.insert(2, 2.0);
+        while count(pool.expect_submit().await, &values) != 2 {}
+        assert!(pool.send(set_target_message(&target(4.0))).await);
+        count(pool.expect_submit().await, &values);
+
+        time::timeout(Duration::from_secs(10), miner.stop())
+            .await
+            .expect("stop should return");
+        // whatever was submitted reaches the pool before the connection closes
+        while let Some(message) = pool.next().await {
+            if let StratumMessage::MiningSubmitMessage(submit) = message {
+                count(submit.body, &values);
+            }
+        }
+        let stats = miner.stats().await;
+        assert_eq!(stats.share_value.total_shares, stats.shares_submitted);
+        assert_eq!(stats.share_value.session_shares, stats.shares_submitted);
+        assert!(stats.share_value.total_value > stats.shares_submitted as f64);
+        assert_eq!(stats.share_value.total_value, expected);
+        assert_eq!(stats.share_value.session_value, expected);
+    }
+
+    #[tokio::test]
+    async fn test_share_hook() {
+        let listener = MockPoolListener::bind().await;
+        let miner = TestMinerBuilder::new(listener.address())
+            .build()
+            .await
+            .unwrap();
+        // every other share is dropped, the ones kept are recorded in the order decided
+        let kept = Arc::new(Mutex::new(vec![]));
+        let decided = AtomicU64::new(0);
+        let hook_kept = kept.clone();
+        miner.set_share_hook(Box::new(move |candidate| {
+            assert_eq!(candidate.mining_request_id, 7);
+            assert!(candidate.header.is_some() && candidate.target.is_some());
+            assert!(candidate.notified_at.is_some());
+            if decided.fetch_add(1, Ordering::SeqCst) % 2 == 1 {
+                return ShareDecision::Drop {
+                    reason: String::from("every other share"),
+                };
+            }
+            hook_kept.lock().unwrap().push(candidate.randomness);
+            ShareDecision::Submit
+        }));
+        miner.launch().await;
+        let mut pool = listener.accept().await;
+        pool.accept_subscribe().await;
+        assert!(pool.easy_job(7, KNOWN_HEADER).await);
+        let mut submitted = vec![];
+        for _ in 0..3 {
+            submitted.push(pool.expect_submit().await.randomness);
+        }
+        let expected: Vec<String> = kept.lock().unwrap()[..3]
+            .iter()
+            .map(|&randomness| Randomness::from_value(randomness, &miner.layout).to_wire_hex())
+            .collect();
+        assert_eq!(submitted, expected);
+        miner.stop().await;
+        let stats = miner.stats().await.share_hook.unwrap();
+        assert!(stats.dropped >= 2, "{:?}", stats);
+        assert_eq!(
+            stats.submitted,
+            kept.lock().unwrap().len() as u64,
+            "{:?}",
+            stats
+        );
+        assert_eq!(stats.replaced, 0);
+    }
+
+    #[tokio::test]
+    async fn test_header_mismatch() {
+        let miner = TestMinerBuilder::new("127.0.0.1:8080".parse().unwrap())
+            .build()
+            .await
+            .unwrap();
+        miner.miner.set_graffiti("zk.work").await.unwrap();
+        miner.miner.set_target(&"ff".repeat(32)).await;
+        let mut events = miner.miner.subscribe_events();
+        miner.miner.new_work(7, &"00".repeat(80)).await;
+        assert_eq!(
+            events.try_recv().unwrap(),
+            MinerEvent::WaitingForWork {
+                reason: PauseReason::HeaderMismatch
+            }
+        );
+        // the rest of the session is not mined either, without more errors
+        miner.miner.new_work(8, KNOWN_HEADER).await;
+        assert!(events.try_recv().is_err());
+        assert_eq!(
+            miner.stats().await.pauses.reason,
+            Some(PauseReason::HeaderMismatch)
+        );
+
+        let forced = TestMinerBuilder::new("127.0.0.1:8080".parse().unwrap())
+            .cli(|cli| {
+                cli.force_header_layout = true;
+                cli.tolerate_longer_headers = false;
+            })
+            .build()
+            .await
+            .unwrap();
+        forced.miner.set_graffiti("zk.work").await.unwrap();
+        forced.miner.set_target(&"ff".repeat(32)).await;
+        let mut events = forced.miner.subscribe_events();
+        forced.miner.new_work(7, &"00".repeat(240)).await;
+        assert!(matches!(
+            events.try_recv().unwrap(),
+            MinerEvent::NewJob {
+                mining_request_id: 7,
+                ..
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_longer_header() {
+        let longer = format!("{}{}", KNOWN_HEADER, "ab".repeat(32));
+        let miner = TestMinerBuilder::new("127.0.0.1:8080".parse().unwrap())
+            .build()
+            .await
+            .unwrap();
+        miner.miner.set_graffiti("zk.work").await.unwrap();
+        miner.miner.set_target(&"ff".repeat(32)).await;
+        let mut events = miner.miner.subscribe_events();
+        assert_eq!(miner.stats().await.header_len, None);
+        miner.miner.new_work(7, &longer).await;
+        assert!(matches!(
+            events.try_recv().unwrap(),
+            MinerEvent::NewJob {
+                mining_request_id: 7,
+                ..
+            }
+        ));
+        assert_eq!(miner.stats().await.header_len, Some(240));
+
+        let strict = TestMinerBuilder::new("127.0.0.1:8080".parse().unwrap())
+            .cli(|cli| cli.tolerate_longer_headers = false)
+            .build()
+            .await
+            .unwrap();
+        strict.miner.set_graffiti("zk.work").await.unwrap();
+        strict.miner.set_target(&"ff".repeat(32)).await;
+        let mut events = strict.miner.subscribe_events();
+        strict.miner.new_work(7, &longer).await;
+        assert_eq!(
+            events.try_recv().unwrap(),
+            MinerEvent::WaitingForWork {
+                reason: PauseReason::HeaderMismatch
+            }
+        );
+        assert_eq!(strict.stats().await.header_len, Some(240));
+    }
+
+    #[tokio::test]
+    async fn test_identity_seed() {
+        let pool = "127.0.0.1:8080".parse().unwrap();
+        let seeded = |seed: u64| {
+            TestMinerBuilder::new(pool)
+                .cli(move |cli| {
+                    cli.worker_name = String::from(DEFAULT_WORKER_NAME);
+                    cli.identity_seed = Some(seed);
+                })
+                .build()
+        };
+        let one = seeded(1).await.unwrap();
+        let two = seeded(2).await.unwrap();
+        let again = seeded(1).await.unwrap();
+        assert_ne!(one.stats().await.worker_name, two.stats().await.worker_name);
+        assert_ne!(one.identity().nonce_start, two.identity().nonce_start);
+        assert_eq!(one.identity(), again.identity());
+        assert_eq!(
+            one.stats().await.worker_name,
+            format!("{}-{}", DEFAULT_WORKER_NAME, one.identity().suffix)
+        );
+        // a worker name of its own is left as it is
+        let named = TestMinerBuilder::new(pool)
+            .cli(|cli| cli.identity_seed = Some(1))
+            .build()
+            .await
+            .unwrap();
+        assert_eq!(named.stats().await.worker_name, "xxxxxx");
+    }
+
+    /// The first shares of a miner on the easiest target, with `--identity-seed seed` and
+    /// `--randomness-start start`, and where its search began.
+    async fn first_shares(seed: u64, start: RandomnessStart) -> (u64, Vec<u64>) {
+        let listener = MockPoolListener::bind().await;
+        let mut miner = TestMinerBuilder::new(listener.address())
+            .cli(|cli| {
+                cli.identity_seed = Some(seed);
+                cli.randomness_start = start;
+            })
+            .build()
+            .await
+            .unwrap();
+        miner.launch().await;
+        let mut pool = listener.accept().await;
+        pool.accept_subscribe().await;
+        assert!(pool.easy_job(7, KNOWN_HEADER).await);
+        let mut found = vec![];
+        while found.len() < 16 {
+            let event = miner
+                .wait_for(|event| matches!(event, MinerEvent::ShareFound { .. }))
+                .await;
+            if let MinerEvent::ShareFound { randomness, .. } = event {
+                found.push(randomness);
+            }
+        }
+        miner.stop().await;
+        (miner.randomness_start(), found)
+    }
+
+    #[tokio::test]
+    async fn test_randomness_start() {
+        // every hash is a share, so the first shares are the first values searched
+        let (start, zero) = first_shares(1, RandomnessStart::Zero).await;
+        assert_eq!(start, 0);
+        assert!(
+            zero.iter().all(|randomness| *randomness < 1000),
+            "{:?}",
+            zero
+        );
+
+        // random starts are far apart, and so are the shares
+        let (start_one, one) = first_shares(1, RandomnessStart::Random).await;
+        let (start_two, two) = first_shares(2, RandomnessStart::Random).await;
+        assert_ne!(start_one, start_two);
+        for (start, found) in [(start_one, &one), (start_two, &two)] {
+            assert!(
+                found
+                    .iter()
+                    .all(|randomness| randomness.wrapping_sub(start) < 1000),
+                "{:016x} {:?}",
+                start,
+                found
+            );
+        }
+        assert!(one.iter().all(|randomness| !two.contains(randomness)));
+        assert!(one.iter().all(|randomness| !zero.contains(randomness)));
+
+        // a start given in hex, right below the wraparound
+        let (start, found) = first_shares(1, RandomnessStart::At(u64::MAX - 3)).await;
+        assert_eq!(start, u64::MAX - 3);
+        assert!(found.contains(&u64::MAX));
+        assert!(found.contains(&0));
+    }
+
+    #[test]
+    fn test_append_graffiti_suffix() {
+        let (mut bytes, _) = graffiti_bytes("zk.work").unwrap();
+        assert_eq!(append_graffiti_suffix(&mut bytes, 7, "-rig42"), 6);
+        assert_eq!(bytes[..13], *b"zk.work-rig42");
+        assert!(bytes[13..].iter().all(|byte| *byte == 0));
+
+        // cut at a whole character, never over the pool's graffiti
+        let pool = "p".repeat(29);
+        let (mut bytes, _) = graffiti_bytes(&pool).unwrap();
+        assert_eq!(append_graffiti_suffix(&mut bytes, 29, "-é-"), 3);
+        assert_eq!(bytes[29..], *"-é".as_bytes());
+        let (mut bytes, _) = graffiti_bytes(&pool).unwrap();
+        assert_eq!(append_graffiti_suffix(&mut bytes, 29, "ééé"), 2);
+        assert_eq!(bytes[29..31], *"é".as_bytes());
+        assert_eq!(bytes[31], 0);
+        let long = "l".repeat(40);
+        let (mut bytes, _) = graffiti_bytes(&long).unwrap();
+        assert_eq!(append_graffiti_suffix(&mut bytes, long.len(), "-rig42"), 0);
+        assert_eq!(bytes, graffiti_bytes(&long).unwrap().0);
+    }
+
+    #[tokio::test]
+    async fn test_graffiti_suffix() {
+        let listener = MockPoolListener::bind().await;
+        let mut miner = TestMinerBuilder::new(listener.address())
+            .cli(|cli| cli.graffiti_suffix = Some(String::from("-rig42")))
+            .build()
+            .await
+            .unwrap();
+        miner.launch().await;
+        let mut pool = listener.accept().await;
+        let subscribe = pool.accept_subscribe().await;
+        assert_eq!(subscribe.graffitiSuffix.as_deref(), Some("-rig42"));
+        miner
+            .wait_for(|event| matches!(event, MinerEvent::Subscribed { .. }))
+            .await;
+        // jobs, and so the shares checked against them, carry the pool's graffiti and the suffix
+        let mut graffiti = [0; GRAFFITI_SIZE];
+        graffiti[..13].copy_from_slice(b"zk.work-rig42");
+        assert_eq!(*miner.miner.graffiti.read().await, Some(graffiti));
+        assert_eq!(miner.stats().await.graffiti.as_deref(), Some("zk.work"));
+        miner.stop().await;
     }
 
     #[test]
     fn test_randomness() {
         let randomness = 0x00001234u64;
         let s_1 = format!("{:016x}", randomness);
-        let s_2 = hex::encode(randomness.to_be_bytes());
+        let s_2 = Randomness::from_value(randomness, &HeaderLayout::IRONFISH).to_wire_hex();
         println!("{}", s_1);
         println!("{}", s_2);
+        assert_eq!(s_1, s_2);
+
+        // the pool splices the submitted hex back into header bytes 0..8, which must give the
+        // exact bytes the thread pool hashed
+        let mut header = [0u8; 208];
+        Randomness::from_wire_hex(&s_2, &HeaderLayout::IRONFISH)
+            .unwrap()
+            .apply_to_header(&mut header, &HeaderLayout::IRONFISH)
+            .unwrap();
+        assert_eq!(header[0..8], randomness.to_be_bytes());
+        assert_eq!(
+            u64::from_be_bytes(header[0..8].try_into().unwrap()),
+            randomness
+        );
     }
 }
@@ -0,0 +1,887 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+#[cfg(unix)]
+use crate::{attach_socket, exec_upgrade, HandoverState};
+use crate::{
+    report_on_panic, supervise, Cli, ConfigReport, ExitReason, FinalSummary, HashrateUnit,
+    LifetimeCounters, LifetimeStore, Miner, MinerStats, Reporter, RestartPolicy, StatsBoard,
+    StatsSnapshot, LIFETIME_SAVE_INTERVAL, SNAPSHOT_INTERVAL, SUMMARY_INTERVAL,
+};
+use anyhow::Result;
+use arc_swap::ArcSwap;
+use log::*;
+use std::{
+    convert::Infallible,
+    future::Future,
+    path::PathBuf,
+    sync::{Arc, Weak},
+    time::Duration,
+};
+use tokio::{sync::Notify, time};
+
+/// Owns every `Miner` of the process. A single `--pool` yields one unlabeled miner, a
+/// `--split` yields one miner per pool, each labeled with its pool address and given its
+/// share of the configured worker threads.
+#[derive(Debug)]
+pub struct MinerSet {
+    miners: Vec<Arc<Miner>>,
+    /// `--upgrade`, on a platform that supports it.
+    upgrade: bool,
+    upgrade_requests: Notify,
+    stats: Arc<StatsBoard>,
+    /// Only with `--report-to`.
+    reporter: Option<Arc<Reporter>>,
+    hashrate_unit: HashrateUnit,
+    /// `--max-runtime`.
+    max_runtime: Option<Duration>,
+    /// `--summary-file`.
+    summary_file: Option<PathBuf>,
+    /// What the miners run with, updated from the snapshots.
+    config: ArcSwap<ConfigReport>,
+    /// Only with `--data-dir`.
+    lifetime: Option<LifetimeStore>,
+}
+
+impl MinerSet {
+    pub async fn initialize(cli: Cli) -> Result<Arc<Self>> {
+        let config = ConfigReport::from_cli(&cli)?;
+        debug!("config: {}", serde_json::to_string(&config)?);
+        let lifetime = cli
+            .data_dir
+            .as_deref()
+            .map(LifetimeStore::open)
+            .transpose()?;
+        let instances = cli.instances()?;
+        let labeled = instances.len() > 1;
+        let mut miners = Vec::with_capacity(instances.len());
+        for instance in instances {
+            let label = match (labeled, &instance.pool) {
+                (true, Some(pool)) => pool.to_string(),
+                _ => String::new(),
+            };
+            if labeled {
+                info!(
+                    "miner instance [{}] uses {} of {} threads",
+                    label, instance.threads_count, cli.threads_count
+                );
+            }
+            miners.push(Miner::initialize_with_label(instance, label).await?);
+        }
+        if cli.upgrade && !cfg!(unix) {
+            warn!("--upgrade is only supported on unix, ignoring it");
+        }
+        let stats = Arc::<StatsBoard>::default();
+        let set = Arc::new(Self {
+            miners,
+            upgrade: cli.upgrade && cfg!(unix),
+            upgrade_requests: Notify::new(),
+            reporter: Reporter::new(&cli, stats.clone()),
+            hashrate_unit: cli.hashrate_unit,
+            max_runtime: cli.max_runtime.map(Duration::from_secs),
+            summary_file: cli.summary_file.clone(),
+            config: ArcSwap::from_pointee(config),
+            lifetime,
+            stats,
+        });
+        set.publish_stats().await;
+        report_on_panic(set.stats.clone());
+        let weak = Arc::downgrade(&set);
+        supervise("stats snapshot", RestartPolicy::default(), move || {
+            Self::snapshot_stats(weak.clone())
+        });
+        if let Some(reporter) = &set.reporter {
+            Reporter::start(reporter);
+        }
+        Ok(set)
+    }
+
+    /// Assembles the snapshot every [`SNAPSHOT_INTERVAL`], logs a summary every
+    /// [`SUMMARY_INTERVAL`] and saves the lifetime counters every [`LIFETIME_SAVE_INTERVAL`],
+    /// until the set is dropped.
+    async fn snapshot_stats(set: Weak<MinerSet>) {
+        let mut interval = time::interval(SNAPSHOT_INTERVAL);
+        let mut summary = time::interval(SUMMARY_INTERVAL);
+        let mut save = time::interval(LIFETIME_SAVE_INTERVAL);
+        // the first ticks complete right away
+        interval.tick().await;
+        summary.tick().await;
+        save.tick().await;
+        loop {
+            tokio::select! {
+                _ = interval.tick() => match set.upgrade() {
+                    Some(set) => set.publish_stats().await,
+                    None => return,
+                },
+                _ = summary.tick() => match set.upgrade() {
+                    Some(set) => set.snapshot().log_summary(set.hashrate_unit),
+                    None => return,
+                },
+                _ = save.tick() => match set.upgrade() {
+                    Some(set) => {
+                        if let Some(lifetime) = &set.lifetime {
+                            lifetime.save(&set.snapshot().miners);
+                        }
+                    }
+                    None => return,
+                },
+            }
+        }
+    }
+
+    async fn publish_stats(&self) {
+        let mut snapshot = StatsSnapshot::assemble(&self.miners).await;
+        self.add_lifetime(&mut snapshot.miners);
+        snapshot.report = self.reporter.as_ref().map(|reporter| reporter.stats());
+        let mut config = ConfigReport::clone(&self.config.load());
+        if config.update(&snapshot.miners) {
+            self.config.store(Arc::new(config));
+        }
+        self.stats.publish(snapshot);
+    }
+
+    /// Sets the lifetime counters of `stats`, with `--data-dir`.
+    fn add_lifetime(&self, stats: &mut [MinerStats]) {
+        if let Some(lifetime) = &self.lifetime {
+            for stats in stats {
+                stats.lifetime =
+                    Some(lifetime.stats(&stats.label, LifetimeCounters::from_stats(stats)));
+            }
+        }
+    }
+
+    /// Saves the lifetime counters of the stopped miners and waits for the write.
+    async fn save_lifetime(&self) {
+        if let Some(lifetime) = &self.lifetime {
+            lifetime.save_and_flush(&self.stats().await).await;
+            debug!("lifetime counters saved to {}", lifetime.path().display());
+        }
+    }
+
+    /// What the miners run with, as of the last snapshot.
+    pub fn config(&self) -> Arc<ConfigReport> {
+        self.config.load_full()
+    }
+
+    /// The stats of every miner as of the last snapshot, at most [`SNAPSHOT_INTERVAL`] old.
+    /// Never waits.
+    pub fn snapshot(&self) -> Arc<StatsSnapshot> {
+        self.stats.latest()
+    }
+
+    pub fn miners(&self) -> &[Arc<Miner>] {
+        &self.miners
+    }
+
+    pub async fn start(set: Arc<MinerSet>) -> Result<()> {
+        for miner in set.miners.iter() {
+            Miner::launch(miner.clone()).await;
+        }
+        // Do not delete the following line of code
+        std::future::pending::<()>().await;
+        Ok(())
+    }
+
+    pub async fn stop(&self) {
+        for miner in self.miners.iter() {
+            miner.stop().await;
+        }
+        // the last snapshot shows them down
+        self.publish_stats().await;
+    }
+
+    /// Pauses every miner until [`Self::resume_mining`], see [`Miner::pause_mining`].
+    pub async fn pause_mining(&self) {
+        for miner in self.miners.iter() {
+            miner.pause_mining().await;
+        }
+    }
+
+    pub async fn resume_mining(&self) {
+        for miner in self.miners.iter() {
+            miner.resume_mining().await;
+        }
+    }
+
+    /// Asks for an upgrade in place, see [`crate::handover`]. Returns `false` without
+    /// `--upgrade`.
+    pub fn request_upgrade(&self) -> bool {
+        if self.upgrade {
+            self.upgrade_requests.notify_one();
+        }
+        self.upgrade
+    }
+
+    /// Returns on SIGUSR2 or [`Self::request_upgrade`], never without `--upgrade`.
+    async fn upgrade_requested(&self) {
+        if !self.upgrade {
+            return std::future::pending().await;
+        }
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+            match signal(SignalKind::user_defined2()) {
+                Ok(mut sigusr2) => tokio::select! {
+                    _ = sigusr2.recv() => {}
+                    _ = self.upgrade_requests.notified() => {}
+                },
+                Err(error) => {
+                    error!("failed to listen for SIGUSR2: {}", error);
+                    self.upgrade_requests.notified().await
+                }
+            }
+        }
+        #[cfg(not(unix))]
+        self.upgrade_requests.notified().await
+    }
+
+    /// Stops mining, hands the pool sessions over and execs the binary at the same path. Only
+    /// returns if the exec failed, with every miner down.
+    #[cfg(unix)]
+    pub async fn upgrade(&self) -> Result<Infallible> {
+        let state = self.hand_over().await;
+        // the new process goes on from these
+        self.save_lifetime().await;
+        info!(
+            "handing {} of {} pool sessions over to the new process",
+            state.miners.len(),
+            self.miners.len()
+        );
+        exec_upgrade(&state)
+    }
+
+    #[cfg(not(unix))]
+    pub async fn upgrade(&self) -> Result<Infallible> {
+        Err(anyhow::anyhow!(
+            "upgrading in place is only supported on unix"
+        ))
+    }
+
+    /// Stops every miner and returns the sessions that could be handed over. The other
+    /// miners are stopped as usual and connect again after the upgrade.
+    #[cfg(unix)]
+    pub async fn hand_over(&self) -> HandoverState {
+        let mut state = HandoverState::default();
+        for miner in self.miners.iter() {
+            match miner.hand_over().await {
+                Some(handover) => state.miners.push(handover),
+                None => info!(
+                    "no pool session of miner [{}] to hand over, it connects again after the upgrade",
+                    miner.label()
+                ),
+            }
+        }
+        state
+    }
+
+    /// Goes on with the sessions a previous process handed over, once the miners launch.
+    #[cfg(unix)]
+    pub async fn resume(&self, state: HandoverState) {
+        for handover in state.miners {
+            let miner = self
+                .miners
+                .iter()
+                .find(|miner| miner.label() == handover.label);
+            let result = match miner {
+                Some(miner) => miner.resume(handover).await,
+                // closed, the pool sees it go away
+                None => attach_socket(handover.socket)
+                    .map(drop)
+                    .and(Err(anyhow::anyhow!("no such miner"))),
+            };
+            if let Err(error) = result {
+                warn!("failed to resume a pool session: {}", error);
+            }
+        }
+    }
+
+    /// Waits for Ctrl-C, `--max-runtime` or `failure`, then stops every miner: the thread
+    /// pools are stopped, their last shares submitted and the pool connections closed. Logs the
+    /// [`crate::FinalReport`] of every miner last, writes it to `--summary-file`, saves the
+    /// lifetime counters to `--data-dir` and returns the exit code,
+    /// [`crate::CRITICAL_PANIC_EXIT_CODE`] after a failure. With `--upgrade` it upgrades in
+    /// place instead when asked to, and returns 1 only if that failed.
+    pub async fn shutdown_on(&self, failure: impl Future<Output = ()>) -> i32 {
+        tokio::pin!(failure);
+        let max_runtime = async {
+            match self.max_runtime {
+                Some(max_runtime) => time::sleep(max_runtime).await,
+                None => std::future::pending().await,
+            }
+        };
+        let reason = tokio::select! {
+            result = tokio::signal::ctrl_c() => match result {
+                Ok(()) => ExitReason::StoppedByUser,
+                Err(error) => {
+                    error!("tokio::signal::ctrl_c encountered an error: {}", error);
+                    failure.await;
+                    ExitReason::CriticalFailure
+                }
+            },
+            _ = &mut failure => ExitReason::CriticalFailure,
+            _ = max_runtime => {
+                info!("--max-runtime reached");
+                ExitReason::MaxRuntime
+            }
+            _ = self.upgrade_requested() => {
+                info!("upgrading...");
+                let Err(error) = self.upgrade().await;
+                error!("upgrade failed: {}", error);
+                ExitReason::UpgradeFailed
+            }
+        };
+        info!("shutdowning...");
+        // returns once the miners are down and their last shares submitted
+        self.stop().await;
+        self.final_report(reason).await;
+        self.save_lifetime().await;
+        reason.exit_code()
+    }
+
+    /// Logs the final report of every miner, and writes it to `--summary-file`.
+    async fn final_report(&self, reason: ExitReason) {
+        let summary = FinalSummary::new(&self.stats().await, reason);
+        for report in &summary.miners {
+            info!("{}", report.format(self.hashrate_unit));
+        }
+        if let Some(path) = &self.summary_file {
+            if let Err(error) = summary.write(path) {
+                error!(
+                    "failed to write the final report to {}: {}",
+                    path.display(),
+                    error
+                );
+            }
+        }
+    }
+
+    /// Fresh stats of every miner. Readers that need not be exact use [`Self::snapshot`].
+    pub async fn stats(&self) -> Vec<MinerStats> {
+        let mut stats = Vec::with_capacity(self.miners.len());
+        for miner in self.miners.iter() {
+            stats.push(miner.stats().await);
+        }
+        self.add_lifetime(&mut stats);
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        load_lifetime, supervise,
+        test_util::{
+            easy_target, notify_message, set_target_message, spawn_test_pool,
+            spawn_test_pool_with_target, subscribed_message, test_cli, MockPoolListener,
+        },
+        DisconnectReason, FinalReport, PauseReason, PoolSplit, RestartPolicy,
+        CRITICAL_PANIC_EXIT_CODE, LIFETIME_FILE, MAX_RECENT_SHARES,
+    };
+    #[cfg(unix)]
+    use crate::{receive_state, send_state};
+    use std::{
+        net::SocketAddr,
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_two_miners_two_pools() {
+        let (pool_1, submits_1) = spawn_test_pool().await;
+        let (pool_2, submits_2) = spawn_test_pool().await;
+        let split: PoolSplit = format!("{}=80,{}=20", pool_1, pool_2).parse().unwrap();
+        let cli = Cli {
+            pool: None,
+            threads_count: 5,
+            batch_size: 1000,
+            split: Some(split),
+            ..test_cli(pool_1)
+        };
+        let set = MinerSet::initialize(cli).await.unwrap();
+        assert_eq!(set.miners().len(), 2);
+        for miner in set.miners() {
+            Miner::launch(miner.clone()).await;
+        }
+
+        let received = async {
+            while submits_1.load(Ordering::SeqCst) == 0 || submits_2.load(Ordering::SeqCst) == 0 {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(30), received)
+            .await
+            .expect("both pools should receive shares");
+
+        let stats = set.stats().await;
+        assert_eq!(stats[0].label, pool_1.to_string());
+        assert_eq!(stats[0].threads, 4);
+        assert_eq!(stats[1].label, pool_2.to_string());
+        assert_eq!(stats[1].threads, 1);
+        assert!(stats.iter().all(|stats| stats.subscribed));
+        set.stop().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_stats_panic_keeps_mining() {
+        let (pool, submits) = spawn_test_pool().await;
+        let cli = Cli {
+            threads_count: 2,
+            batch_size: 1000,
+            ..test_cli(pool)
+        };
+        let set = MinerSet::initialize(cli).await.unwrap();
+        let miner = set.miners()[0].clone();
+        Miner::launch(miner.clone()).await;
+        let counted = submits.clone();
+        let submitted = move |count| {
+            let submits = counted.clone();
+            async move {
+                while submits.load(Ordering::SeqCst) < count {
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(30), submitted(1))
+            .await
+            .expect("the pool should receive shares");
+
+        // a stats task that always panics is restarted, then given up on
+        let runs = Arc::new(AtomicUsize::new(0));
+        let policy = RestartPolicy {
+            backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(10),
+            max_restarts: 3,
+        };
+        let (stats_miner, counter) = (miner.clone(), runs.clone());
+        supervise("stats task", policy, move || {
+            let (miner, counter) = (stats_miner.clone(), counter.clone());
+            async move {
+                miner.stats().await;
+                counter.fetch_add(1, Ordering::SeqCst);
+                panic!("stats task failed");
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(runs.load(Ordering::SeqCst), 4);
+
+        let before = submits.load(Ordering::SeqCst);
+        tokio::time::timeout(Duration::from_secs(30), submitted(before + 1))
+            .await
+            .expect("mining should go on after the stats task died");
+        assert!(miner.stats().await.subscribed);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_no_hashrate_while_waiting() {
+        let (pool, _) = spawn_test_pool().await;
+        let cli = Cli {
+            batch_size: 1000,
+            ..test_cli(pool)
+        };
+        let set = MinerSet::initialize(cli).await.unwrap();
+        let miner = set.miners()[0].clone();
+        Miner::launch(miner.clone()).await;
+        let rate_becomes = |mining: bool| {
+            let miner = miner.clone();
+            async move {
+                tokio::time::timeout(Duration::from_secs(10), async {
+                    while (miner.stats().await.rate_1s > 0.0) != mining {
+                        tokio::time::sleep(Duration::from_millis(50)).await;
+                    }
+                })
+                .await
+                .is_ok()
+            }
+        };
+        assert!(rate_becomes(true).await, "the miner should hash");
+
+        miner.wait_for_work(PauseReason::PoolRequested).await;
+        assert!(rate_becomes(false).await, "no hashrate while waiting");
+        // nothing counted while waiting shows up in the rates later
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        let stats = miner.stats().await;
+        assert_eq!(stats.rate_1s, 0.0);
+        assert_eq!(stats.pauses.reason, Some(PauseReason::PoolRequested));
+
+        miner.new_work(8, &"00".repeat(208)).await;
+        assert!(rate_becomes(true).await, "the hashrate should recover");
+        let pauses = miner.stats().await.pauses;
+        assert_eq!(pauses.reason, None);
+        assert!(pauses.pool_requested_secs >= 1.5);
+        assert_eq!(pauses.disconnected_secs, 0.0);
+        set.stop().await;
+    }
+
+    /// A pool that hands out a job and hangs up shortly after, on every connection.
+    async fn spawn_flapping_pool() -> SocketAddr {
+        let listener = MockPoolListener::bind().await;
+        let address = listener.address();
+        tokio::spawn(async move {
+            loop {
+                let mut pool = listener.accept().await;
+                pool.next().await;
+                pool.send_all([
+                    subscribed_message(1, "zk.work", None),
+                    set_target_message(&"00".repeat(32)),
+                    notify_message(7, &"00".repeat(208)),
+                ])
+                .await;
+                tokio::time::sleep(Duration::from_millis(200)).await;
+            }
+        });
+        address
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_no_busy_loop_while_disconnected() {
+        let pool = spawn_flapping_pool().await;
+        let cli = Cli {
+            batch_size: 1000,
+            ..test_cli(pool)
+        };
+        let set = MinerSet::initialize(cli).await.unwrap();
+        let miner = set.miners()[0].clone();
+        Miner::launch(miner.clone()).await;
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        // the pool came and went a few times
+        let stats = miner.stats().await;
+        assert!(stats.sessions.len() >= 2);
+        // the time between sessions counts as disconnected, not as asked by the pool
+        assert!(stats.pauses.disconnected_secs > 0.0);
+        assert!(stats.buffers.within_caps(), "{:?}", stats.buffers);
+        assert_eq!(stats.buffers.disconnects.len, stats.disconnects.len());
+        assert_eq!(stats.buffers.recent_shares.cap, MAX_RECENT_SHARES);
+        // a hashrate collection every 500ms and the state changes, not a poll every few ms
+        let wakeups = miner.idle_wakeups();
+        assert!(wakeups < 40, "{} wakeups while idle", wakeups);
+        set.stop().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_stop_submits_last_shares() {
+        // about one share in 65536 hashes, so the pool is not flooded
+        let (pool, submits) = spawn_test_pool_with_target(format!("0000{}", "ff".repeat(30))).await;
+        let cli = Cli {
+            batch_size: 1000,
+            max_submit_rate: "100000/1s".parse().unwrap(),
+            ..test_cli(pool)
+        };
+        let set = MinerSet::initialize(cli).await.unwrap();
+        let miner = set.miners()[0].clone();
+        Miner::launch(miner.clone()).await;
+        tokio::time::timeout(Duration::from_secs(30), async {
+            while submits.load(Ordering::SeqCst) < 3 {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .expect("the pool should receive shares");
+
+        tokio::time::timeout(Duration::from_secs(10), set.stop())
+            .await
+            .expect("stop should return once everything is down");
+        let stats = miner.stats().await;
+        assert!(!stats.subscribed);
+        assert_eq!(
+            stats.disconnects.last().map(|disconnect| disconnect.reason),
+            Some(DisconnectReason::StoppedByUser)
+        );
+        // every share found, also while stopping, went out before the connection closed
+        let session = stats.sessions.last().unwrap();
+        assert_eq!(session.shares_submitted, stats.shares_found);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while (submits.load(Ordering::SeqCst) as u64) < stats.shares_found {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .expect("the pool should receive every share found");
+        assert_eq!(submits.load(Ordering::SeqCst) as u64, stats.shares_found);
+    }
+
+    /// Every hash meets the target and the mining loop takes one share per poll, so the
+    /// thread pool always has shares left to hand in once stop is requested.
+    async fn stop_with_shares_left(discard_shares_on_stop: bool) -> (MinerStats, usize) {
+        let (pool, submits) = spawn_test_pool_with_target(easy_target()).await;
+        let cli = Cli {
+            max_submit_rate: "100000/1s".parse().unwrap(),
+            discard_shares_on_stop,
+            ..test_cli(pool)
+        };
+        let set = MinerSet::initialize(cli).await.unwrap();
+        let miner = set.miners()[0].clone();
+        Miner::launch(miner.clone()).await;
+        tokio::time::timeout(Duration::from_secs(30), async {
+            while submits.load(Ordering::SeqCst) < 3 {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .expect("the pool should receive shares");
+
+        tokio::time::timeout(Duration::from_secs(10), set.stop())
+            .await
+            .expect("stop should return once everything is down");
+        let stats = miner.stats().await;
+        // whatever was submitted reaches the pool before the connection closes
+        let _ = tokio::time::timeout(Duration::from_secs(5), async {
+            while (submits.load(Ordering::SeqCst) as u64) < stats.shares_submitted {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await;
+        (stats, submits.load(Ordering::SeqCst))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_shares_on_stop_submitted() {
+        let (stats, submits) = stop_with_shares_left(false).await;
+        assert!(stats.shares_submitted_on_stop > 0);
+        assert_eq!(stats.shares_discarded_on_stop, 0);
+        assert_eq!(stats.shares_submitted, stats.shares_found);
+        assert_eq!(submits as u64, stats.shares_submitted);
+        let report = FinalReport::new(&stats, ExitReason::StoppedByUser);
+        assert_eq!(
+            report.shares_submitted_on_stop,
+            stats.shares_submitted_on_stop
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_shares_on_stop_discarded() {
+        let (stats, submits) = stop_with_shares_left(true).await;
+        assert!(stats.shares_discarded_on_stop > 0);
+        assert_eq!(stats.shares_submitted_on_stop, 0);
+        // discarded shares are neither found nor submitted
+        assert_eq!(stats.shares_submitted, stats.shares_found);
+        assert_eq!(submits as u64, stats.shares_submitted);
+        let report = FinalReport::new(&stats, ExitReason::StoppedByUser);
+        assert_eq!(
+            report.shares_discarded_on_stop,
+            stats.shares_discarded_on_stop
+        );
+    }
+
+    /// An upgrade up to the exec: the session goes through the handover pipe to a second set
+    /// of miners, which mines on over the same connection. The pool takes a single connection,
+    /// so nothing can subscribe again.
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_hand_over_session() {
+        let (pool, submits) = spawn_test_pool_with_target(format!("0000{}", "ff".repeat(30))).await;
+        let cli = Cli {
+            batch_size: 1000,
+            max_submit_rate: "100000/1s".parse().unwrap(),
+            upgrade: true,
+            ..test_cli(pool)
+        };
+        let set = MinerSet::initialize(cli.clone()).await.unwrap();
+        let miner = set.miners()[0].clone();
+        Miner::launch(miner.clone()).await;
+        tokio::time::timeout(Duration::from_secs(30), async {
+            while submits.load(Ordering::SeqCst) < 2 {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .expect("the pool should receive shares");
+
+        let state = set.hand_over().await;
+        let stats = miner.stats().await;
+        assert!(!stats.subscribed);
+        assert_eq!(
+            stats.disconnects.last().map(|disconnect| disconnect.reason),
+            Some(DisconnectReason::HandedOver)
+        );
+        assert_eq!(state.miners.len(), 1);
+        let handed_over = state.miners[0].clone();
+        assert_eq!(handed_over.session.client_id, 1);
+        assert_eq!(
+            handed_over
+                .session
+                .job
+                .as_ref()
+                .map(|job| job.mining_request_id),
+            Some(7)
+        );
+        assert_eq!(handed_over.shares_found, stats.shares_found);
+        let state = receive_state(send_state(&state).unwrap()).unwrap();
+
+        let upgraded = MinerSet::initialize(cli).await.unwrap();
+        upgraded.resume(state).await;
+        let miner = upgraded.miners()[0].clone();
+        Miner::launch(miner.clone()).await;
+        let before = submits.load(Ordering::SeqCst);
+        tokio::time::timeout(Duration::from_secs(30), async {
+            while submits.load(Ordering::SeqCst) == before {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .expect("the pool should receive shares over the handed over connection");
+        let stats = miner.stats().await;
+        assert!(stats.subscribed);
+        assert_eq!(stats.client_id, Some(1));
+        assert_eq!(stats.graffiti.as_deref(), Some("zk.work"));
+        assert!(stats.shares_found > handed_over.shares_found);
+        upgraded.stop().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_critical_panic_shuts_down() {
+        let (pool, submits) = spawn_test_pool_with_target(format!("0000{}", "ff".repeat(30))).await;
+        let cli = Cli {
+            batch_size: 1000,
+            max_submit_rate: "100000/1s".parse().unwrap(),
+            ..test_cli(pool)
+        };
+        let set = MinerSet::initialize(cli).await.unwrap();
+        let miner = set.miners()[0].clone();
+        Miner::launch(miner.clone()).await;
+
+        // there is no mining backend to swap in, so a critical task panics as soon as the new
+        // work has reached the thread pool, as a backend failing on it would
+        let counted = submits.clone();
+        let backend = tokio::spawn(async move {
+            while counted.load(Ordering::SeqCst) == 0 {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+            panic!("new_work failed");
+        });
+        let failure = async move {
+            assert!(backend.await.unwrap_err().is_panic());
+        };
+        let exit_code = tokio::time::timeout(Duration::from_secs(30), set.shutdown_on(failure))
+            .await
+            .expect("a panic should shut the miners down");
+        assert_eq!(exit_code, CRITICAL_PANIC_EXIT_CODE);
+        let stats = miner.stats().await;
+        assert!(!stats.subscribed);
+        assert_eq!(
+            stats.disconnects.last().map(|disconnect| disconnect.reason),
+            Some(DisconnectReason::StoppedByUser)
+        );
+        // the shares found before the panic went out before the connection closed
+        assert_eq!(
+            stats.sessions.last().unwrap().shares_submitted,
+            stats.shares_found
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_final_report() {
+        let (pool, submits) = spawn_test_pool_with_target(format!("0000{}", "ff".repeat(30))).await;
+        let path = std::env::temp_dir().join(format!("ironminer-summary-{}", std::process::id()));
+        let data_dir = std::env::temp_dir().join(format!("ironminer-data-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&data_dir);
+        let cli = Cli {
+            batch_size: 1000,
+            max_submit_rate: "100000/1s".parse().unwrap(),
+            max_runtime: Some(2),
+            summary_file: Some(path.clone()),
+            data_dir: Some(data_dir.clone()),
+            ..test_cli(pool)
+        };
+        let set = MinerSet::initialize(cli).await.unwrap();
+        let miner = set.miners()[0].clone();
+        Miner::launch(miner.clone()).await;
+        let exit_code = tokio::time::timeout(
+            Duration::from_secs(30),
+            set.shutdown_on(std::future::pending()),
+        )
+        .await
+        .expect("--max-runtime should shut the miners down");
+        assert_eq!(exit_code, 0);
+
+        let summary: FinalSummary = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(summary.exit_reason, ExitReason::MaxRuntime);
+        assert_eq!(summary.exit_code, 0);
+        let report = &summary.miners[0];
+        // the stats api serves the snapshot published once the miners were down
+        let stats = &set.snapshot().miners[0];
+        assert!(stats.shares_found > 0, "no share in 2s on an easy target");
+        assert_eq!(report.shares_found, stats.shares_found);
+        assert_eq!(report.shares_submitted, stats.shares_submitted);
+        assert_eq!(report.shares_submitted, stats.shares_found);
+        assert_eq!(report.shares_stale, stats.shares_below_target);
+        assert_eq!(report.reconnects, 0);
+        assert_eq!(stats.sessions_started, 1);
+        assert_eq!(report.best_share_difficulty, stats.best_share_difficulty);
+        assert!(report.best_share_difficulty.is_some());
+        assert!((report.average_hashrate - stats.rate_avg).abs() <= stats.rate_avg * 0.5);
+        assert!(
+            (2..=4).contains(&report.runtime_secs),
+            "{}",
+            report.runtime_secs
+        );
+        assert!(submits.load(Ordering::SeqCst) as u64 <= report.shares_submitted);
+        // a first run: the lifetime totals are those of the process, and saved
+        let lifetime = stats.lifetime.as_ref().unwrap();
+        assert_eq!(lifetime.total, lifetime.process);
+        assert_eq!(lifetime.process.shares_found, stats.shares_found);
+        assert!(lifetime.process.hashes > 0);
+        assert_eq!(report.lifetime.as_ref(), Some(&lifetime.total));
+        let saved = load_lifetime(&data_dir.join(LIFETIME_FILE))
+            .unwrap()
+            .unwrap();
+        assert_eq!(saved[""].shares_found, stats.shares_found);
+        std::fs::remove_dir_all(&data_dir).unwrap();
+
+        let text = miner.final_report(ExitReason::MaxRuntime).await;
+        for field in [
+            "runtime:",
+            "average hashrate:",
+            "shares:",
+            "reconnects:",
+            "best share:",
+            "exit reason:      --max-runtime reached",
+        ] {
+            assert!(text.contains(field), "{} missing from\n{}", field, text);
+        }
+        assert!(text.contains(&format!(
+            "{} found, {} submitted, {} stale",
+            report.shares_found, report.shares_submitted, report.shares_stale
+        )));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_time_to_first_share() {
+        let (pool, submits) = spawn_test_pool().await;
+        let cli = Cli {
+            threads_count: 2,
+            batch_size: 1000,
+            ..test_cli(pool)
+        };
+        let set = MinerSet::initialize(cli).await.unwrap();
+        let miner = set.miners()[0].clone();
+        Miner::launch(miner.clone()).await;
+        tokio::time::timeout(Duration::from_secs(30), async {
+            while submits.load(Ordering::SeqCst) == 0 {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .expect("the pool should receive shares");
+
+        let first_share = miner.stats().await.first_share;
+        let times = first_share.latest.unwrap();
+        let steps = [
+            times.subscribed_ms,
+            times.first_notify_ms,
+            times.first_share_found_ms,
+            times.first_share_submitted_ms,
+        ];
+        assert!(steps.iter().all(Option::is_some), "{:?}", times);
+        assert!(
+            steps.windows(2).all(|pair| pair[0] <= pair[1]),
+            "{:?}",
+            times
+        );
+        assert_eq!(first_share.median, times);
+        set.stop().await;
+    }
+}
@@ -0,0 +1,361 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::AssembledJob;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+/// Why mining is paused.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PauseReason {
+    /// The pool sent `mining.wait_for_work`, which is normal between blocks.
+    PoolRequested,
+    /// The pool connection was lost.
+    Disconnected,
+    /// Paused on a schedule.
+    Scheduled,
+    /// Paused by the user.
+    Manual,
+    /// The job outlived its expiry with no new one from the pool.
+    JobExpired,
+    /// The pool's jobs are not of the header layout, see [`crate::HeaderLayout::fit`].
+    HeaderMismatch,
+}
+
+impl PauseReason {
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+impl fmt::Display for PauseReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            PauseReason::PoolRequested => "pool requested",
+            PauseReason::Disconnected => "disconnected",
+            PauseReason::Scheduled => "scheduled",
+            PauseReason::Manual => "manual",
+            PauseReason::JobExpired => "job expired",
+            PauseReason::HeaderMismatch => "header mismatch",
+        })
+    }
+}
+
+/// Time spent paused, per reason.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct PauseStats {
+    /// Why mining is paused right now, `None` while mining.
+    pub reason: Option<PauseReason>,
+    /// Seconds the current pause has lasted.
+    pub paused_secs: Option<f64>,
+    pub pool_requested_secs: f64,
+    pub disconnected_secs: f64,
+    pub scheduled_secs: f64,
+    pub manual_secs: f64,
+    #[serde(default)]
+    pub job_expired_secs: f64,
+    #[serde(default)]
+    pub header_mismatch_secs: f64,
+    /// Waits for work the pool asked for, the current one included.
+    #[serde(default)]
+    pub pool_waits: u64,
+    /// The longest of them, in seconds.
+    #[serde(default)]
+    pub longest_pool_wait_secs: f64,
+}
+
+/// Tracks the current pause and adds up the time spent in each kind of pause.
+#[derive(Debug, Default)]
+pub struct PauseClock {
+    current: Option<(PauseReason, Instant)>,
+    totals: [Duration; 6],
+    /// Whether the current pause was alerted about.
+    alerted: bool,
+    pool_waits: u64,
+    /// The longest [`PauseReason::PoolRequested`] pause that ended.
+    longest_pool_wait: Duration,
+}
+
+impl PauseClock {
+    /// Starts a pause. Pausing again for the same reason keeps the pause going, for another
+    /// reason it ends the pause and starts the new one. Returns whether the reason changed.
+    pub fn pause(&mut self, reason: PauseReason, now: Instant) -> bool {
+        match self.current {
+            Some((current, _)) if current == reason => false,
+            _ => {
+                self.resume(now);
+                self.current = Some((reason, now));
+                if reason == PauseReason::PoolRequested {
+                    self.pool_waits += 1;
+                }
+                true
+            }
+        }
+    }
+
+    /// Ends the current pause, returns its reason and length.
+    pub fn resume(&mut self, now: Instant) -> Option<(PauseReason, Duration)> {
+        let (reason, since) = self.current.take()?;
+        self.alerted = false;
+        let paused = now.saturating_duration_since(since);
+        self.totals[reason.index()] += paused;
+        if reason == PauseReason::PoolRequested {
+            self.longest_pool_wait = self.longest_pool_wait.max(paused);
+        }
+        Some((reason, paused))
+    }
+
+    /// The length of a disconnected pause once it has lasted `threshold`, only once per pause.
+    pub fn disconnected_alert(&mut self, now: Instant, threshold: Duration) -> Option<Duration> {
+        match self.current {
+            Some((PauseReason::Disconnected, since)) if !self.alerted => {
+                let paused = now.saturating_duration_since(since);
+                if paused < threshold {
+                    return None;
+                }
+                self.alerted = true;
+                Some(paused)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn stats(&self, now: Instant) -> PauseStats {
+        let mut totals = self.totals;
+        let current = self
+            .current
+            .map(|(reason, since)| (reason, now.saturating_duration_since(since)));
+        if let Some((reason, paused)) = current {
+            totals[reason.index()] += paused;
+        }
+        let secs = |reason: PauseReason| totals[reason.index()].as_secs_f64();
+        let longest_pool_wait = match current {
+            Some((PauseReason::PoolRequested, paused)) => self.longest_pool_wait.max(paused),
+            _ => self.longest_pool_wait,
+        };
+        PauseStats {
+            reason: current.map(|(reason, _)| reason),
+            paused_secs: current.map(|(_, paused)| paused.as_secs_f64()),
+            pool_requested_secs: secs(PauseReason::PoolRequested),
+            disconnected_secs: secs(PauseReason::Disconnected),
+            scheduled_secs: secs(PauseReason::Scheduled),
+            manual_secs: secs(PauseReason::Manual),
+            job_expired_secs: secs(PauseReason::JobExpired),
+            header_mismatch_secs: secs(PauseReason::HeaderMismatch),
+            pool_waits: self.pool_waits,
+            longest_pool_wait_secs: longest_pool_wait.as_secs_f64(),
+        }
+    }
+}
+
+/// What resuming from a user pause mines, see `--resume-requires-fresh-work`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResumePolicy {
+    /// The latest job the pool sent, during the pause or before it.
+    LatestJob,
+    /// Nothing until the pool sends a new job after the resume.
+    FreshWork,
+}
+
+/// Where a user pause left off, and what the end of it goes on with.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PendingJob {
+    /// There was no job yet.
+    None,
+    /// The latest job, mined against the target current at the resume.
+    Job(AssembledJob),
+    /// The pool has no job for us, the miner goes on waiting for this reason.
+    Waiting(PauseReason),
+}
+
+/// A pause by the user. Unlike the other pauses it is not ended by a new job: the jobs and
+/// waits the pool sends during it only update the [`PendingJob`] it resumes into.
+#[derive(Debug)]
+pub struct UserPause {
+    policy: ResumePolicy,
+    /// `None` while not paused.
+    pending: Option<PendingJob>,
+}
+
+impl UserPause {
+    pub fn new(policy: ResumePolicy) -> Self {
+        Self {
+            policy,
+            pending: None,
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    /// Starts the pause from what was being mined. Returns `false` if already paused.
+    pub fn pause(&mut self, current: PendingJob) -> bool {
+        if self.is_paused() {
+            return false;
+        }
+        self.pending = Some(current);
+        true
+    }
+
+    /// A new job, handed back if it is to be mined now and kept if paused.
+    pub fn job(&mut self, job: AssembledJob) -> Option<AssembledJob> {
+        match &mut self.pending {
+            Some(pending) => {
+                *pending = PendingJob::Job(job);
+                None
+            }
+            None => Some(job),
+        }
+    }
+
+    /// The pool paused mining. Returns whether the pause is for the miner to take now,
+    /// rather than for the resume.
+    pub fn wait(&mut self, reason: PauseReason) -> bool {
+        match &mut self.pending {
+            Some(pending) => {
+                *pending = PendingJob::Waiting(reason);
+                false
+            }
+            None => true,
+        }
+    }
+
+    /// Ends the pause, `None` if not paused. Under [`ResumePolicy::FreshWork`] a pending
+    /// job turns into waiting for the next one, still as a [`PauseReason::Manual`] pause.
+    pub fn resume(&mut self) -> Option<PendingJob> {
+        let pending = self.pending.take()?;
+        Some(match (self.policy, pending) {
+            (ResumePolicy::FreshWork, PendingJob::Job(_)) => {
+                PendingJob::Waiting(PauseReason::Manual)
+            }
+            (_, pending) => pending,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job(mining_request_id: u32) -> AssembledJob {
+        AssembledJob {
+            mining_request_id,
+            header: "00".repeat(208),
+            target: "ff".repeat(32),
+        }
+    }
+
+    #[test]
+    fn test_user_pause() {
+        let mut pause = UserPause::new(ResumePolicy::LatestJob);
+        assert_eq!(pause.job(job(1)), Some(job(1)));
+        assert!(pause.wait(PauseReason::PoolRequested));
+        assert_eq!(pause.resume(), None);
+
+        // the latest job of the pause wins
+        assert!(pause.pause(PendingJob::Job(job(1))));
+        assert!(!pause.pause(PendingJob::None));
+        assert_eq!(pause.job(job(2)), None);
+        assert_eq!(pause.job(job(3)), None);
+        assert_eq!(pause.resume(), Some(PendingJob::Job(job(3))));
+        assert!(!pause.is_paused());
+
+        // a wait after the last job leaves nothing to resume into
+        pause.pause(PendingJob::Job(job(3)));
+        assert!(!pause.wait(PauseReason::PoolRequested));
+        assert_eq!(
+            pause.resume(),
+            Some(PendingJob::Waiting(PauseReason::PoolRequested))
+        );
+        pause.pause(PendingJob::Waiting(PauseReason::Disconnected));
+        pause.job(job(4));
+        assert_eq!(pause.resume(), Some(PendingJob::Job(job(4))));
+
+        let mut pause = UserPause::new(ResumePolicy::FreshWork);
+        pause.pause(PendingJob::Job(job(1)));
+        pause.job(job(2));
+        assert_eq!(
+            pause.resume(),
+            Some(PendingJob::Waiting(PauseReason::Manual))
+        );
+        pause.pause(PendingJob::None);
+        assert_eq!(pause.resume(), Some(PendingJob::None));
+    }
+
+    #[test]
+    fn test_pause_clock() {
+        let reasons = [
+            PauseReason::PoolRequested,
+            PauseReason::Disconnected,
+            PauseReason::Scheduled,
+            PauseReason::Manual,
+            PauseReason::JobExpired,
+        ];
+        assert!(reasons
+            .iter()
+            .enumerate()
+            .all(|(index, reason)| reason.index() == index));
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut clock = PauseClock::default();
+        assert_eq!(clock.stats(start), PauseStats::default());
+        assert_eq!(clock.resume(start), None);
+
+        assert!(clock.pause(PauseReason::PoolRequested, at(0)));
+        // the same reason again keeps the pause going
+        assert!(!clock.pause(PauseReason::PoolRequested, at(5)));
+        let stats = clock.stats(at(10));
+        assert_eq!(stats.reason, Some(PauseReason::PoolRequested));
+        assert_eq!(stats.paused_secs, Some(10.0));
+        assert_eq!(stats.pool_requested_secs, 10.0);
+        assert_eq!(stats.pool_waits, 1);
+        assert_eq!(stats.longest_pool_wait_secs, 10.0);
+        // a pool requested pause is never alerted about
+        assert_eq!(
+            clock.disconnected_alert(at(100), Duration::from_secs(60)),
+            None
+        );
+        assert_eq!(
+            clock.resume(at(20)),
+            Some((PauseReason::PoolRequested, Duration::from_secs(20)))
+        );
+
+        // a disconnect takes over a pool requested pause
+        clock.pause(PauseReason::PoolRequested, at(30));
+        assert!(clock.pause(PauseReason::Disconnected, at(40)));
+        assert_eq!(
+            clock.disconnected_alert(at(99), Duration::from_secs(60)),
+            None
+        );
+        assert_eq!(
+            clock.disconnected_alert(at(100), Duration::from_secs(60)),
+            Some(Duration::from_secs(60))
+        );
+        // once per pause
+        assert_eq!(
+            clock.disconnected_alert(at(200), Duration::from_secs(60)),
+            None
+        );
+        clock.resume(at(200));
+        let stats = clock.stats(at(300));
+        assert_eq!(stats.reason, None);
+        assert_eq!(stats.paused_secs, None);
+        assert_eq!(stats.pool_requested_secs, 30.0);
+        // the longest of the two waits, the second one ended by the disconnect
+        assert_eq!(stats.pool_waits, 2);
+        assert_eq!(stats.longest_pool_wait_secs, 20.0);
+        assert_eq!(stats.disconnected_secs, 160.0);
+        assert_eq!(stats.manual_secs, 0.0);
+
+        clock.pause(PauseReason::Disconnected, at(300));
+        assert!(clock
+            .disconnected_alert(at(360), Duration::from_secs(60))
+            .is_some());
+    }
+}
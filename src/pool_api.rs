@@ -0,0 +1,345 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! What the pool reports about this address, polled from its HTTP API with `--pool-api-url`,
+//! so that the pool side hashrate can be compared with the local one.
+//!
+//! The API is not under our control, so every field is optional and read leniently: numbers
+//! may come as strings, the account may be wrapped in `data`, and anything unexpected is left
+//! out rather than failing the fetch.
+
+use crate::{
+    supervise, Cli, HashrateUnit, LogDecision, LogLimiter, Meter, RestartPolicy, SystemClock,
+};
+use anyhow::Result;
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::time;
+
+const POOL_API_TIMEOUT: Duration = Duration::from_secs(10);
+const POOL_API_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Failing fetches are logged at most this often.
+const POOL_API_ERROR_LOG_INTERVAL: Duration = Duration::from_secs(3600);
+/// Replaced by the mining address in `--pool-api-url`.
+pub const POOL_API_ADDRESS_PLACEHOLDER: &str = "{address}";
+
+/// A number the API may send as a number or as a string. Anything else is ignored.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+enum LenientNumber {
+    Number(f64),
+    String(String),
+    Other(serde::de::IgnoredAny),
+}
+
+impl LenientNumber {
+    fn get(&self) -> Option<f64> {
+        match self {
+            Self::Number(number) => Some(*number),
+            Self::String(string) => string.trim().parse().ok(),
+            Self::Other(_) => None,
+        }
+        .filter(|number: &f64| number.is_finite())
+    }
+}
+
+/// The last payout, as an object or as a bare amount.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+enum PayoutResponse {
+    Object {
+        #[serde(default)]
+        amount: Option<LenientNumber>,
+        #[serde(default, alias = "timestamp", alias = "paidAt", alias = "paid_at")]
+        time: Option<LenientNumber>,
+    },
+    Amount(LenientNumber),
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+struct AccountResponse {
+    #[serde(alias = "pendingBalance")]
+    pending_balance: Option<LenientNumber>,
+    #[serde(alias = "hashRate")]
+    hashrate: Option<LenientNumber>,
+    #[serde(alias = "lastPayout")]
+    last_payout: Option<PayoutResponse>,
+    /// Some APIs wrap the account in `{"data": ...}`.
+    data: Option<Box<AccountResponse>>,
+}
+
+/// What the pool reported on the last successful fetch, in the pool's own units.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct PoolAccount {
+    pub pending_balance: Option<f64>,
+    /// Hashes per second as the pool measures them from the shares it received.
+    pub hashrate: Option<f64>,
+    pub last_payout_amount: Option<f64>,
+    /// Unix time in seconds of the last payout.
+    pub last_payout_at: Option<u64>,
+}
+
+impl PoolAccount {
+    /// Reads whatever known fields the response has. Fails only on a body that is not JSON.
+    pub fn parse(body: &str) -> Result<Self> {
+        let response: serde_json::Value = serde_json::from_str(body)?;
+        // a shape that does not fit leaves the fields empty instead of failing
+        let mut response: AccountResponse = serde_json::from_value(response).unwrap_or_default();
+        if let Some(data) = response.data.take() {
+            response = *data;
+        }
+        let (last_payout_amount, last_payout_at) = match response.last_payout {
+            Some(PayoutResponse::Object { amount, time }) => (
+                amount.and_then(|amount| amount.get()),
+                time.and_then(|time| time.get()).map(unix_seconds),
+            ),
+            Some(PayoutResponse::Amount(amount)) => (amount.get(), None),
+            None => (None, None),
+        };
+        Ok(Self {
+            pending_balance: response.pending_balance.and_then(|balance| balance.get()),
+            hashrate: response.hashrate.and_then(|hashrate| hashrate.get()),
+            last_payout_amount,
+            last_payout_at,
+        })
+    }
+}
+
+/// Takes unix times in seconds or milliseconds.
+fn unix_seconds(time: f64) -> u64 {
+    if time > 1e11 {
+        (time / 1000.0) as u64
+    } else {
+        time as u64
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PoolApiStats {
+    #[serde(flatten)]
+    pub account: PoolAccount,
+    /// Unix time in seconds of the last successful fetch.
+    pub updated_at: u64,
+    /// No successful fetch for two intervals, the values may be out of date.
+    pub stale: bool,
+}
+
+impl PoolApiStats {
+    /// The summary line, next to `local_hashrate` to make a gap stand out.
+    pub fn format(&self, local_hashrate: f64, unit: HashrateUnit) -> String {
+        let mut line = format!(
+            "Pool side: hashrate {} (local 1h {})",
+            self.account
+                .hashrate
+                .map(|hashrate| Meter::format_as(hashrate, unit))
+                .unwrap_or_else(|| String::from("n/a")),
+            Meter::format_as(local_hashrate, unit)
+        );
+        if let Some(balance) = self.account.pending_balance {
+            line.push_str(&format!(", pending balance {}", balance));
+        }
+        if let Some(amount) = self.account.last_payout_amount {
+            line.push_str(&format!(", last payout {}", amount));
+        }
+        if let Some(at) = self.account.last_payout_at {
+            line.push_str(&format!(" at {}", at));
+        }
+        if self.stale {
+            line.push_str(" (stale)");
+        }
+        line
+    }
+}
+
+#[derive(Debug)]
+struct Fetched {
+    account: PoolAccount,
+    at: Instant,
+    unix_time: u64,
+}
+
+/// Polls the pool's HTTP API for the stats of our address. Does nothing without a url.
+#[derive(Debug)]
+pub struct PoolApiClient {
+    url: Option<String>,
+    interval: Duration,
+    latest: Mutex<Option<Fetched>>,
+    errors: LogLimiter<SystemClock>,
+}
+
+impl PoolApiClient {
+    pub fn new(cli: &Cli) -> Arc<Self> {
+        Arc::new(Self {
+            url: cli
+                .pool_api_url
+                .as_ref()
+                .map(|url| url.replace(POOL_API_ADDRESS_PLACEHOLDER, &cli.address)),
+            interval: Duration::from_secs(cli.pool_api_interval),
+            latest: Default::default(),
+            errors: LogLimiter::new(SystemClock, 1, POOL_API_ERROR_LOG_INTERVAL),
+        })
+    }
+
+    /// The last fetched stats, `None` without a url or before the first successful fetch.
+    pub fn stats(&self) -> Option<PoolApiStats> {
+        self.stats_at(Instant::now())
+    }
+
+    fn stats_at(&self, now: Instant) -> Option<PoolApiStats> {
+        let latest = self.latest.lock().unwrap();
+        let fetched = latest.as_ref()?;
+        Some(PoolApiStats {
+            account: fetched.account.clone(),
+            updated_at: fetched.unix_time,
+            stale: now.saturating_duration_since(fetched.at) > self.interval * 2,
+        })
+    }
+
+    fn update(&self, account: PoolAccount, at: Instant) {
+        let unix_time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        *self.latest.lock().unwrap() = Some(Fetched {
+            account,
+            at,
+            unix_time,
+        });
+    }
+
+    pub async fn start(client: Arc<Self>) {
+        let url = match client.url.clone() {
+            Some(url) => url,
+            None => return,
+        };
+        supervise("pool api", RestartPolicy::default(), move || {
+            Self::poll(client.clone(), url.clone())
+        });
+    }
+
+    async fn poll(client: Arc<Self>, url: String) {
+        let http = match reqwest::Client::builder()
+            .timeout(POOL_API_TIMEOUT)
+            .connect_timeout(POOL_API_CONNECT_TIMEOUT)
+            .build()
+        {
+            Ok(http) => http,
+            Err(error) => {
+                error!("failed to set up the pool api client: {}", error);
+                return;
+            }
+        };
+        let mut interval = time::interval(client.interval);
+        loop {
+            interval.tick().await;
+            match Self::fetch(&http, &url).await {
+                Ok(account) => {
+                    debug!("pool api {}: {:?}", url, account);
+                    client.update(account, Instant::now());
+                }
+                Err(error) => {
+                    if let LogDecision::Emit { suppressed, .. } = client.errors.check("pool api") {
+                        warn!(
+                            "failed to fetch pool stats from {}: {}{}",
+                            url,
+                            error,
+                            match suppressed {
+                                0 => String::new(),
+                                suppressed =>
+                                    format!(" ({} more failures in the last hour)", suppressed),
+                            }
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    async fn fetch(http: &reqwest::Client, url: &str) -> Result<PoolAccount> {
+        let body = http
+            .get(url)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        PoolAccount::parse(&body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_account() {
+        let account = PoolAccount::parse(
+            r#"{"pending_balance": 1.5, "hashrate": "2500000", "last_payout": {"amount": 3, "timestamp": 1700000000000}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            account,
+            PoolAccount {
+                pending_balance: Some(1.5),
+                hashrate: Some(2_500_000.0),
+                last_payout_amount: Some(3.0),
+                last_payout_at: Some(1_700_000_000),
+            }
+        );
+        let account = PoolAccount::parse(
+            r#"{"data": {"pendingBalance": "7", "hashRate": 10, "lastPayout": 2, "workers": []}}"#,
+        )
+        .unwrap();
+        assert_eq!(account.pending_balance, Some(7.0));
+        assert_eq!(account.hashrate, Some(10.0));
+        assert_eq!(account.last_payout_amount, Some(2.0));
+        assert_eq!(account.last_payout_at, None);
+
+        // unexpected shapes leave the fields empty
+        for body in [
+            "[]",
+            "42",
+            r#"{"hashrate": {"1h": 5}}"#,
+            r#"{"pending_balance": "lots", "last_payout": null}"#,
+            r#"{"data": "maintenance"}"#,
+        ] {
+            let account = PoolAccount::parse(body).unwrap();
+            assert_eq!(account.hashrate, None, "{}", body);
+            assert_eq!(account.pending_balance, None, "{}", body);
+        }
+        assert!(PoolAccount::parse("<html>502 Bad Gateway</html>").is_err());
+    }
+
+    #[test]
+    fn test_stale_stats() {
+        let client = PoolApiClient {
+            url: Some(String::from("http://127.0.0.1:1/account/xxxxxx")),
+            interval: Duration::from_secs(300),
+            latest: Default::default(),
+            errors: LogLimiter::new(SystemClock, 1, POOL_API_ERROR_LOG_INTERVAL),
+        };
+        let start = Instant::now();
+        assert_eq!(client.stats_at(start), None);
+        client.update(
+            PoolAccount {
+                hashrate: Some(1_000_000.0),
+                ..Default::default()
+            },
+            start,
+        );
+        let stats = client.stats_at(start + Duration::from_secs(600)).unwrap();
+        assert!(!stats.stale);
+        assert_eq!(stats.account.hashrate, Some(1_000_000.0));
+        let stats = client.stats_at(start + Duration::from_secs(601)).unwrap();
+        assert!(stats.stale);
+        let line = stats.format(900_000.0, HashrateUnit::MH);
+        assert!(line.starts_with("Pool side: hashrate 1.00 MH/s (local 1h 0.90 MH/s)"));
+        assert!(line.ends_with("(stale)"));
+    }
+}
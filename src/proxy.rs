@@ -0,0 +1,572 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! `--proxy-listen`: one pool session shared by the miners on the LAN. The proxy subscribes to
+//! the pool once, serves the pool's target and jobs to every miner that connects, and relays
+//! their shares upstream under its own subscription.
+//!
+//! Every worker gets the graffiti of the proxy's pool session, since that is what the pool
+//! checks shares against. Workers on the same job that search the randomness from the same
+//! `--randomness-start` find the same shares; the proxy relays each share once and drops the
+//! copies.
+
+#[cfg(feature = "tls")]
+use crate::tls_connect;
+use crate::{
+    critical_failure, spawn_critical, Capabilities, Cli, CoalescingWriter, Connector, MessageId,
+    MiningNotifyBody, MiningNotifyMessage, MiningSetTargetBody, MiningSetTargetMessage,
+    MiningSubmitBody, MiningSubmitMessage, MiningSubscribedBody, MiningSubscribedMessage,
+    MiningWaitForWorkMessage, RecentSet, ResolverCache, SessionEvent, StratumClientConfig,
+    StratumMessage, StratumMessageCodec, StratumSession, CHANNEL_CAPACITY, HANDSHAKE_TIMEOUT,
+    MAX_RELAYED_SHARES, POOL_IDLE_TIMEOUT,
+};
+use anyhow::{anyhow, Result};
+use log::*;
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{split, AsyncRead, AsyncWrite, ReadHalf, WriteHalf},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, watch},
+    time::{self, Instant},
+};
+use tokio_stream::StreamExt;
+use tokio_util::codec::FramedRead;
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(2);
+
+/// What the pool sent last. Workers are brought up to it whenever it changes.
+#[derive(Clone, Debug, Default, PartialEq)]
+struct Upstream {
+    /// The graffiti of the pool session, `None` while there is none.
+    graffiti: Option<String>,
+    target: Option<String>,
+    /// `None` after a `mining.wait_for_work`.
+    job: Option<MiningNotifyBody>,
+}
+
+/// Holds the pool session and serves it to the workers.
+#[derive(Debug)]
+pub struct Proxy {
+    config: StratumClientConfig,
+    upstream: watch::Sender<Upstream>,
+    /// The most recently relayed shares, as mining request id and randomness.
+    relayed: Mutex<RecentSet<(u32, String)>>,
+    next_worker_id: AtomicU64,
+    workers: AtomicUsize,
+    shares_relayed: AtomicU64,
+    duplicate_shares: AtomicU64,
+}
+
+impl Proxy {
+    pub fn new(config: StratumClientConfig) -> Arc<Self> {
+        Arc::new(Self {
+            config,
+            upstream: watch::channel(Upstream::default()).0,
+            relayed: Mutex::new(RecentSet::new(MAX_RELAYED_SHARES)),
+            next_worker_id: AtomicU64::new(1),
+            workers: Default::default(),
+            shares_relayed: Default::default(),
+            duplicate_shares: Default::default(),
+        })
+    }
+
+    /// Workers connected right now.
+    pub fn workers(&self) -> usize {
+        self.workers.load(Ordering::Relaxed)
+    }
+
+    /// Shares sent to the pool.
+    pub fn shares_relayed(&self) -> u64 {
+        self.shares_relayed.load(Ordering::Relaxed)
+    }
+
+    /// Shares dropped because another worker had already sent them.
+    pub fn duplicate_shares(&self) -> u64 {
+        self.duplicate_shares.load(Ordering::Relaxed)
+    }
+
+    /// Holds the pool session and serves the workers that connect to `listener`. Returns only
+    /// if accepting fails.
+    pub async fn run(self: Arc<Self>, listener: TcpListener) -> Result<()> {
+        let (submits, queue) = mpsc::channel(CHANNEL_CAPACITY);
+        spawn_critical("proxy pool session", self.clone().hold_pool_session(queue));
+        loop {
+            let (stream, peer) = listener.accept().await?;
+            tokio::spawn(self.clone().serve_worker(stream, peer, submits.clone()));
+        }
+    }
+
+    /// Connects to the pool, and again whenever the connection is lost.
+    async fn hold_pool_session(self: Arc<Self>, mut submits: mpsc::Receiver<MiningSubmitBody>) {
+        let pool = &self.config.pool_address;
+        let connector = Connector::new(self.config.source_ports);
+        let resolver = ResolverCache::new(self.config.dns_ttl);
+        let mut next_message_id = 0;
+        loop {
+            info!("Connecting to pool({})...", pool);
+            let outcome = match self.connect(&connector, &resolver).await {
+                #[cfg(feature = "tls")]
+                Ok(tcp_stream) if self.config.tls => match tls_connect(pool, tcp_stream).await {
+                    Ok(tls_stream) => {
+                        self.serve_pool(tls_stream, &mut submits, &mut next_message_id)
+                            .await
+                    }
+                    Err(error) => Err(anyhow!("tls handshake failed: {}", error)),
+                },
+                Ok(tcp_stream) => {
+                    self.serve_pool(tcp_stream, &mut submits, &mut next_message_id)
+                        .await
+                }
+                Err(error) => Err(error),
+            };
+            if let Err(error) = outcome {
+                warn!("Pool({}) disconnected: {}", pool, error);
+            }
+            // the workers wait for work until the next session
+            self.upstream.send_replace(Upstream::default());
+            time::sleep(RECONNECT_DELAY).await;
+        }
+    }
+
+    async fn connect(&self, connector: &Connector, resolver: &ResolverCache) -> Result<TcpStream> {
+        let pool = &self.config.pool_address;
+        let address = resolver.resolve(pool, Instant::now().into_std()).await?;
+        let tcp_stream = connector.connect(address).await?;
+        resolver.mark_good(pool, address).await;
+        Ok(tcp_stream)
+    }
+
+    /// Subscribes and passes on what the pool sends until the connection ends.
+    async fn serve_pool<T: AsyncRead + AsyncWrite>(
+        &self,
+        stream: T,
+        submits: &mut mpsc::Receiver<MiningSubmitBody>,
+        next_message_id: &mut i64,
+    ) -> Result<()> {
+        let pool = &self.config.pool_address;
+        let mut session = StratumSession::new(stream, self.config.lenient_decode);
+        if let Some(per_min) = self.config.max_protocol_errors_per_min {
+            session.limit_protocol_errors(per_min);
+        }
+        *next_message_id += 1;
+        let subscribed = session
+            .subscribe(*next_message_id, self.config.subscribe_body())
+            .await
+            .map_err(|error| anyhow!("{}", error))?;
+        info!(
+            "Pool({}) session started: client id({}) graffiti({}), serving workers",
+            pool, subscribed.clientId, subscribed.graffiti
+        );
+        // shares found for the previous session are of no use to this one
+        let mut stale = 0;
+        while submits.try_recv().is_ok() {
+            stale += 1;
+        }
+        if stale > 0 {
+            debug!("dropping {} shares found before the reconnect", stale);
+        }
+        // the workers are acked without capabilities, but may send what the pool did not agree to
+        let capabilities = Capabilities::negotiate(subscribed.capabilities);
+        self.upstream.send_replace(Upstream {
+            graffiti: Some(subscribed.graffiti),
+            ..Default::default()
+        });
+        let idle = time::sleep(POOL_IDLE_TIMEOUT);
+        tokio::pin!(idle);
+        loop {
+            tokio::select! {
+                Some(mut body) = submits.recv() => {
+                    capabilities.restrict_submit(&mut body);
+                    *next_message_id += 1;
+                    session
+                        .submit(MiningSubmitMessage {
+                            id: (*next_message_id).into(),
+                            method: String::from("mining.submit"),
+                            body,
+                        })
+                        .await
+                        .map_err(|error| anyhow!("{}", error))?;
+                    self.shares_relayed.fetch_add(1, Ordering::Relaxed);
+                }
+
+                _ = &mut idle => {
+                    return Err(anyhow!("no message from the pool for {:?}", POOL_IDLE_TIMEOUT));
+                }
+
+                event = session.next_event() => {
+                    let event = event.map_err(|error| anyhow!("{}", error))?;
+                    idle.as_mut().reset(Instant::now() + POOL_IDLE_TIMEOUT);
+                    match event {
+                        SessionEvent::NewTarget(target) => self.upstream.send_modify(|upstream| {
+                            upstream.target = Some(target);
+                        }),
+                        SessionEvent::NewJob { mining_request_id, header, timestamp, expires_in } => {
+                            self.upstream.send_modify(|upstream| {
+                                upstream.job = Some(MiningNotifyBody {
+                                    miningRequestId: mining_request_id,
+                                    header,
+                                    timestamp,
+                                    expiresInMs: expires_in.map(|expires_in| expires_in.as_millis() as u64),
+                                });
+                            })
+                        }
+                        SessionEvent::WaitForWork => self.upstream.send_modify(|upstream| {
+                            upstream.job = None;
+                        }),
+                        SessionEvent::SubmitAck { id, accepted, .. } => {
+                            debug!("pool answered submit id({}) accepted({})", id, accepted)
+                        }
+                        SessionEvent::Unknown(message) => debug!("ignoring {:?}", message),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Serves one worker like a pool would, with the jobs of the pool session.
+    async fn serve_worker(
+        self: Arc<Self>,
+        stream: TcpStream,
+        peer: SocketAddr,
+        submits: mpsc::Sender<MiningSubmitBody>,
+    ) {
+        let (r, w) = split(stream);
+        let mut r = FramedRead::new(r, StratumMessageCodec::default());
+        let mut w = CoalescingWriter::new(w);
+        let name = match time::timeout(HANDSHAKE_TIMEOUT, r.next()).await {
+            Ok(Some(Ok(StratumMessage::MiningSubscribeMessage(message)))) => message.body.name,
+            _ => {
+                debug!("worker {} did not subscribe, closing", peer);
+                return;
+            }
+        };
+        let worker_id = self.next_worker_id.fetch_add(1, Ordering::Relaxed);
+        let workers = self.workers.fetch_add(1, Ordering::Relaxed) + 1;
+        info!(
+            "Worker #{} {}({}) connected, {} workers",
+            worker_id, name, peer, workers
+        );
+        if let Err(error) = self.feed_worker(worker_id, &mut r, &mut w, &submits).await {
+            debug!("worker #{}: {}", worker_id, error);
+        }
+        let workers = self.workers.fetch_sub(1, Ordering::Relaxed) - 1;
+        info!(
+            "Worker #{} {}({}) disconnected, {} workers",
+            worker_id, name, peer, workers
+        );
+    }
+
+    /// Sends the worker the subscribe ack once the pool session is up, then every target and
+    /// job the pool sends, and relays the worker's shares. Returns when the worker is gone or
+    /// the pool session it subscribed to is.
+    async fn feed_worker<T: AsyncRead + AsyncWrite>(
+        &self,
+        worker_id: u64,
+        r: &mut FramedRead<ReadHalf<T>, StratumMessageCodec>,
+        w: &mut CoalescingWriter<WriteHalf<T>>,
+        submits: &mpsc::Sender<MiningSubmitBody>,
+    ) -> Result<()> {
+        let mut upstream = self.upstream.subscribe();
+        let graffiti = loop {
+            if let Some(graffiti) = upstream.borrow_and_update().graffiti.clone() {
+                break graffiti;
+            }
+            tokio::select! {
+                changed = upstream.changed() => changed?,
+                message = r.next() => match message {
+                    Some(Ok(message)) => debug!("ignoring {:?} before the subscribe ack", message),
+                    _ => return Err(anyhow!("closed before the pool session was up")),
+                },
+            }
+        };
+        let mut next_message_id: i64 = 0;
+        let mut message_id = || {
+            next_message_id += 1;
+            next_message_id.into()
+        };
+        w.write(StratumMessage::MiningSubscribedMessage(
+            MiningSubscribedMessage {
+                id: message_id(),
+                method: String::from("mining.subscribed"),
+                body: MiningSubscribedBody {
+                    clientId: worker_id,
+                    graffiti: graffiti.clone(),
+                    capabilities: None,
+                },
+            },
+        ))
+        .await?;
+        // what the worker has been sent
+        let mut sent = Upstream {
+            graffiti: Some(graffiti),
+            ..Default::default()
+        };
+        loop {
+            let current = upstream.borrow_and_update().clone();
+            match current.graffiti {
+                // the pool session is down, the worker waits as it would for a pool
+                None => {
+                    if sent.job.take().is_some() {
+                        w.write(wait_for_work(message_id())).await?;
+                    }
+                }
+                // a new pool session, the worker has to subscribe again to learn the graffiti
+                Some(_) if current.graffiti != sent.graffiti => {
+                    return Err(anyhow!("the pool session changed"));
+                }
+                Some(_) => {
+                    if current.target != sent.target {
+                        if let Some(target) = current.target.clone() {
+                            w.write(StratumMessage::MiningSetTargetMessage(
+                                MiningSetTargetMessage {
+                                    id: message_id(),
+                                    method: String::from("mining.set_target"),
+                                    body: MiningSetTargetBody { target },
+                                },
+                            ))
+                            .await?;
+                        }
+                    }
+                    if current.job != sent.job {
+                        match current.job.clone() {
+                            Some(body) => {
+                                w.write(StratumMessage::MiningNotifyMessage(MiningNotifyMessage {
+                                    id: message_id(),
+                                    method: String::from("mining.notify"),
+                                    body,
+                                }))
+                                .await?
+                            }
+                            None => w.write(wait_for_work(message_id())).await?,
+                        }
+                    }
+                    sent = current;
+                }
+            }
+            // one write for the ack, target and job sent together
+            w.flush().await?;
+            tokio::select! {
+                changed = upstream.changed() => changed?,
+                message = r.next() => match message {
+                    Some(Ok(StratumMessage::MiningSubmitMessage(message))) => {
+                        self.relay(worker_id, message.body, submits).await?
+                    }
+                    Some(Ok(message)) => debug!("worker #{}: ignoring {:?}", worker_id, message),
+                    Some(Err(error)) => return Err(error),
+                    None => return Ok(()),
+                },
+            }
+        }
+    }
+
+    /// Passes the share on to the pool session, unless another worker already did.
+    async fn relay(
+        &self,
+        worker_id: u64,
+        body: MiningSubmitBody,
+        submits: &mpsc::Sender<MiningSubmitBody>,
+    ) -> Result<()> {
+        let share = (body.miningRequestId, body.randomness.clone());
+        if !self.relayed.lock().unwrap().insert(share) {
+            self.duplicate_shares.fetch_add(1, Ordering::Relaxed);
+            debug!(
+                "worker #{}: dropping share of mining request id({}) randomness({}), already relayed",
+                worker_id, body.miningRequestId, body.randomness
+            );
+            return Ok(());
+        }
+        submits
+            .send(body)
+            .await
+            .map_err(|_| anyhow!("the pool session is gone"))
+    }
+}
+
+fn wait_for_work(id: MessageId) -> StratumMessage {
+    StratumMessage::MiningWaitForWorkMessage(MiningWaitForWorkMessage {
+        id,
+        method: String::from("mining.wait_for_work"),
+    })
+}
+
+/// Runs the proxy for the pool on the command line until Ctrl-C.
+pub async fn run(cli: &Cli, listen: SocketAddr) -> Result<()> {
+    let config = StratumClientConfig::from_cli(cli)?;
+    let listener = TcpListener::bind(listen)
+        .await
+        .map_err(|error| anyhow!("failed to listen on {}: {}", listen, error))?;
+    info!(
+        "proxy for pool({}) listening on {}",
+        config.pool_address, listen
+    );
+    tokio::select! {
+        result = Proxy::new(config).run(listener) => result,
+        result = tokio::signal::ctrl_c() => {
+            info!("goodbye");
+            Ok(result?)
+        }
+        _ = critical_failure() => Err(anyhow!("the pool session task panicked")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::test_cli, Miner, MinerSet};
+    use futures::SinkExt;
+    use tokio::sync::Notify;
+    use tokio_util::codec::FramedWrite;
+
+    /// A pool that sends one job, and a second one when `next_job` is notified. Counts the
+    /// connections and keeps the submits it receives.
+    async fn spawn_test_pool() -> (
+        SocketAddr,
+        Arc<AtomicUsize>,
+        Arc<Mutex<Vec<(u32, String)>>>,
+        Arc<Notify>,
+    ) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let submits = Arc::new(Mutex::new(Vec::new()));
+        let next_job = Arc::new(Notify::new());
+        let (counter, received, notified) =
+            (connections.clone(), submits.clone(), next_job.clone());
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+                let (received, notified) = (received.clone(), notified.clone());
+                tokio::spawn(async move {
+                    let (r, w) = split(stream);
+                    let mut w = FramedWrite::new(w, StratumMessageCodec::default());
+                    let mut r = FramedRead::new(r, StratumMessageCodec::default());
+                    let notify = |mining_request_id| {
+                        StratumMessage::MiningNotifyMessage(MiningNotifyMessage {
+                            id: 2.into(),
+                            method: String::from("mining.notify"),
+                            body: MiningNotifyBody {
+                                miningRequestId: mining_request_id,
+                                header: "00".repeat(208),
+                                timestamp: None,
+                                expiresInMs: None,
+                            },
+                        })
+                    };
+                    loop {
+                        tokio::select! {
+                            message = r.next() => match message {
+                                Some(Ok(StratumMessage::MiningSubscribeMessage(_))) => {
+                                    for message in [
+                                        StratumMessage::MiningSubscribedMessage(MiningSubscribedMessage {
+                                            id: 0.into(),
+                                            method: String::from("mining.subscribed"),
+                                            body: MiningSubscribedBody {
+                                                clientId: 1,
+                                                graffiti: String::from("zk.work"),
+                                                capabilities: None,
+                                            },
+                                        }),
+                                        StratumMessage::MiningSetTargetMessage(MiningSetTargetMessage {
+                                            id: 1.into(),
+                                            method: String::from("mining.set_target"),
+                                            body: MiningSetTargetBody {
+                                                target: format!("0000{}", "ff".repeat(30)),
+                                            },
+                                        }),
+                                        notify(7),
+                                    ] {
+                                        w.send(message).await.unwrap();
+                                    }
+                                }
+                                Some(Ok(StratumMessage::MiningSubmitMessage(message))) => {
+                                    received
+                                        .lock()
+                                        .unwrap()
+                                        .push((message.body.miningRequestId, message.body.randomness));
+                                }
+                                Some(Ok(_)) => {}
+                                _ => break,
+                            },
+                            _ = notified.notified() => w.send(notify(8)).await.unwrap(),
+                        }
+                    }
+                });
+            }
+        });
+        (address, connections, submits, next_job)
+    }
+
+    fn cli(pool: SocketAddr, worker_name: &str) -> Cli {
+        Cli {
+            worker_name: String::from(worker_name),
+            batch_size: 1000,
+            ..test_cli(pool)
+        }
+    }
+
+    async fn spawn_worker(proxy: SocketAddr, name: &str) -> Arc<MinerSet> {
+        let set = MinerSet::initialize(cli(proxy, name)).await.unwrap();
+        Miner::launch(set.miners()[0].clone()).await;
+        set
+    }
+
+    async fn wait_until(what: &str, condition: impl Fn() -> bool) {
+        let reached = async {
+            while !condition() {
+                time::sleep(Duration::from_millis(20)).await;
+            }
+        };
+        time::timeout(Duration::from_secs(30), reached)
+            .await
+            .unwrap_or_else(|_| panic!("timed out waiting for {}", what));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_two_workers_through_one_proxy() {
+        let (pool, connections, submits, next_job) = spawn_test_pool().await;
+        let proxy = Proxy::new(StratumClientConfig::from_cli(&cli(pool, "proxy")).unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let listen = listener.local_addr().unwrap();
+        tokio::spawn(proxy.clone().run(listener));
+
+        let worker_1 = spawn_worker(listen, "rig-1").await;
+        let worker_2 = spawn_worker(listen, "rig-2").await;
+        wait_until("both workers", || proxy.workers() == 2).await;
+        wait_until("shares of the first job", || {
+            submits.lock().unwrap().len() >= 4
+        })
+        .await;
+        for worker in [&worker_1, &worker_2] {
+            let stats = worker.stats().await;
+            assert!(stats[0].subscribed);
+        }
+
+        // a worker leaving does not touch the pool session
+        worker_1.stop().await;
+        wait_until("the worker to leave", || proxy.workers() == 1).await;
+        next_job.notify_one();
+        wait_until("shares of the second job", || {
+            submits.lock().unwrap().iter().any(|(id, _)| *id == 8)
+        })
+        .await;
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+
+        // the pool never sees a share twice
+        let mut shares = submits.lock().unwrap().clone();
+        let relayed = shares.len();
+        shares.sort();
+        shares.dedup();
+        assert_eq!(shares.len(), relayed);
+        assert!(proxy.shares_relayed() >= relayed as u64);
+        worker_2.stop().await;
+    }
+}
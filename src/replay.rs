@@ -0,0 +1,331 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! The `replay` command: plays the pool's side of a `--dump-stratum` recording to a miner
+//! running the current code, one thread on the CPU, and reports how it reacts: the jobs it
+//! mined, why it paused and why its connections ended.
+//!
+//! Every recorded connection is played to the next connection the miner opens, the pool's
+//! lines at their recorded times divided by the speed, and closed after its last line. Where
+//! the recording has the miner's lines, what the miner sends instead is compared by method.
+//! Submits are only counted, which shares are found depends on the hashing.
+
+use crate::{
+    line_method, parse_dump, Cli, DisconnectReason, DumpDirection, DumpLine, Miner, MinerEvent,
+    PauseReason, ReplayArgs,
+};
+use anyhow::{anyhow, Result};
+use clap::Parser;
+use futures::SinkExt;
+use std::{str::FromStr, time::Duration};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::{broadcast::error::RecvError, mpsc},
+    task, time,
+    time::Instant,
+};
+use tokio_stream::StreamExt;
+use tokio_util::codec::{FramedRead, FramedWrite, LinesCodec};
+
+/// How long the miner has to open the next connection, or to send a recorded message.
+const REPLAY_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long the miner is watched after the last line was played.
+const SETTLE_TIME: Duration = Duration::from_millis(500);
+
+/// How much faster than recorded the pool's lines are played, e.g. "10x".
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ReplaySpeed(pub f64);
+
+impl FromStr for ReplaySpeed {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim_end_matches('x').parse::<f64>() {
+            Ok(speed) if speed > 0.0 && speed.is_finite() => Ok(Self(speed)),
+            _ => Err(anyhow!("expected a speed like 10x, got '{}'", s)),
+        }
+    }
+}
+
+/// How the miner reacted to a recording.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ReplayReport {
+    /// Connections in the recording.
+    pub connections: usize,
+    /// Connections the miner opened while the recording was played.
+    pub connected: usize,
+    /// The mining request ids of the jobs handed to the mining threads, in order.
+    pub jobs: Vec<u32>,
+    pub pauses: Vec<PauseReason>,
+    /// Why the miner's connections ended.
+    pub disconnects: Vec<DisconnectReason>,
+    pub submits: u64,
+    /// Where the miner's messages differ from the recorded ones.
+    pub diffs: Vec<String>,
+}
+
+impl ReplayReport {
+    /// Whether the miner opened every connection and sent what was recorded.
+    pub fn passed(&self) -> bool {
+        self.connected == self.connections && self.diffs.is_empty()
+    }
+
+    pub fn lines(&self) -> Vec<String> {
+        fn join<T: ToString>(items: &[T]) -> String {
+            let items: Vec<String> = items.iter().map(T::to_string).collect();
+            items.join(", ")
+        }
+        let mut lines = vec![
+            format!("connections: {} of {}", self.connected, self.connections),
+            format!("jobs: {}", join(&self.jobs)),
+            format!("pauses: {}", join(&self.pauses)),
+            format!("disconnects: {}", join(&self.disconnects)),
+            format!("submits: {}", self.submits),
+        ];
+        lines.extend(self.diffs.iter().map(|diff| format!("diff: {}", diff)));
+        lines
+    }
+}
+
+/// What the pool side saw.
+#[derive(Default)]
+struct PoolSide {
+    connected: usize,
+    submits: u64,
+    diffs: Vec<String>,
+}
+
+/// Plays `dump` to a new miner, see the module docs.
+pub async fn replay(dump: &str, speed: ReplaySpeed) -> Result<ReplayReport> {
+    let mut connections: Vec<(u64, Vec<DumpLine>)> = vec![];
+    for line in parse_dump(dump)? {
+        match connections
+            .iter_mut()
+            .find(|(number, _)| *number == line.connection)
+        {
+            Some((_, lines)) => lines.push(line),
+            None => connections.push((line.connection, vec![line])),
+        }
+    }
+    if connections.is_empty() {
+        return Err(anyhow!("the dump has no stratum lines"));
+    }
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let pool = listener.local_addr()?.to_string();
+    let cli = Cli::try_parse_from([
+        "zkwork_ironminer",
+        "--pool",
+        &pool,
+        "--address",
+        "replay",
+        "--worker_name",
+        "replay",
+        "--threads",
+        "1",
+        "--batch_size",
+        "1000",
+    ])?;
+    // the recording is played in plain text, whether the pool was reached over TLS or not
+    let cli = Cli { tls: false, ..cli };
+    let miner = Miner::initialize(cli).await?;
+    let mut events = miner.subscribe_events();
+    let watch = task::spawn(async move {
+        let mut report = ReplayReport::default();
+        loop {
+            match events.recv().await {
+                Ok(MinerEvent::NewJob { mining_request_id }) => report.jobs.push(mining_request_id),
+                Ok(MinerEvent::WaitingForWork { reason }) => report.pauses.push(reason),
+                Ok(MinerEvent::Stopped) | Err(RecvError::Closed) => return report,
+                Ok(_) | Err(RecvError::Lagged(_)) => {}
+            }
+        }
+    });
+    let count = connections.len();
+    let played = task::spawn(play(
+        listener,
+        connections.into_iter().map(|(_, lines)| lines).collect(),
+        speed.0,
+    ));
+    Miner::launch(miner.clone()).await;
+    let side = played.await?;
+    time::sleep(SETTLE_TIME).await;
+    let disconnects = miner.stats().await.disconnects;
+    miner.stop().await;
+    let mut report = time::timeout(REPLAY_TIMEOUT, watch)
+        .await
+        .map_err(|_| anyhow!("the miner did not stop"))??;
+    report.connections = count;
+    report.connected = side.connected;
+    report.submits = side.submits;
+    report.diffs = side.diffs;
+    report.disconnects = disconnects
+        .iter()
+        .map(|disconnect| disconnect.reason)
+        .collect();
+    Ok(report)
+}
+
+/// Plays every connection to the next one the miner opens.
+async fn play(listener: TcpListener, connections: Vec<Vec<DumpLine>>, speed: f64) -> PoolSide {
+    let mut side = PoolSide::default();
+    let mut listener = Some(listener);
+    let count = connections.len();
+    for (index, lines) in connections.iter().enumerate() {
+        let number = index + 1;
+        let accepted = match &listener {
+            Some(listener) => time::timeout(REPLAY_TIMEOUT, listener.accept()).await,
+            None => break,
+        };
+        let stream = match accepted {
+            Ok(Ok((stream, _))) => stream,
+            _ => {
+                side.diffs
+                    .push(format!("connection {}: the miner did not connect", number));
+                break;
+            }
+        };
+        side.connected += 1;
+        if number == count {
+            // the miner's next reconnect fails right away instead of waiting for a subscribe ack
+            listener = None;
+        }
+        play_connection(stream, number, lines, speed, &mut side).await;
+    }
+    side
+}
+
+async fn play_connection(
+    stream: TcpStream,
+    number: usize,
+    lines: &[DumpLine],
+    speed: f64,
+    side: &mut PoolSide,
+) {
+    let (reader, writer) = stream.into_split();
+    let mut writer = FramedWrite::new(writer, LinesCodec::new());
+    let (sender, mut received) = mpsc::unbounded_channel();
+    let reading = task::spawn(async move {
+        let mut reader = FramedRead::new(reader, LinesCodec::new());
+        while let Some(Ok(line)) = reader.next().await {
+            if sender.send(line_method(&line)).is_err() {
+                break;
+            }
+        }
+    });
+    let started = Instant::now();
+    let first_ms = lines.first().map(|line| line.at_ms).unwrap_or_default();
+    for line in lines {
+        match line.direction {
+            DumpDirection::FromPool => {
+                let delay = line.at_ms.saturating_sub(first_ms) as f64 / 1000.0 / speed;
+                time::sleep_until(started + Duration::from_secs_f64(delay)).await;
+                if writer.send(line.line.as_str()).await.is_err() {
+                    side.diffs.push(format!(
+                        "connection {}: closed by the miner before the line at {}ms",
+                        number, line.at_ms
+                    ));
+                    break;
+                }
+            }
+            DumpDirection::ToPool => {
+                let expected = line.method();
+                if expected.as_deref() == Some("mining.submit") {
+                    continue;
+                }
+                let sent = loop {
+                    match time::timeout(REPLAY_TIMEOUT, received.recv()).await {
+                        Ok(Some(Some(method))) if method == "mining.submit" => side.submits += 1,
+                        Ok(Some(method)) => break method,
+                        Ok(None) | Err(_) => break None,
+                    }
+                };
+                if sent != expected {
+                    side.diffs.push(format!(
+                        "connection {}: expected {} at {}ms, the miner sent {}",
+                        number,
+                        expected.as_deref().unwrap_or("a line that is no message"),
+                        line.at_ms,
+                        sent.as_deref().unwrap_or("nothing")
+                    ));
+                }
+            }
+        }
+    }
+    reading.abort();
+    while let Ok(method) = received.try_recv() {
+        if method.as_deref() == Some("mining.submit") {
+            side.submits += 1;
+        }
+    }
+}
+
+/// Runs the `replay` command and returns the exit code.
+pub async fn run(args: &ReplayArgs) -> i32 {
+    let dump = match std::fs::read_to_string(&args.file) {
+        Ok(dump) => dump,
+        Err(error) => {
+            println!("failed to read {}: {}", args.file.display(), error);
+            return 1;
+        }
+    };
+    match replay(&dump, args.speed).await {
+        Ok(report) => {
+            for line in report.lines() {
+                println!("{}", line);
+            }
+            if report.passed() {
+                0
+            } else {
+                1
+            }
+        }
+        Err(error) => {
+            println!("replay failed: {}", error);
+            1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A pool that drops the connection, then after the reconnect sends the next job ahead of
+    /// its target.
+    const RECONNECT_WITH_REORDER: &str = include_str!("../testdata/reconnect_reorder.dump");
+
+    #[test]
+    fn test_replay_speed() {
+        assert_eq!("10x".parse::<ReplaySpeed>().unwrap(), ReplaySpeed(10.0));
+        assert_eq!("0.5".parse::<ReplaySpeed>().unwrap(), ReplaySpeed(0.5));
+        assert!("0x".parse::<ReplaySpeed>().is_err());
+        assert!("fast".parse::<ReplaySpeed>().is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_replay_reconnect_with_reorder() {
+        let report = replay(RECONNECT_WITH_REORDER, ReplaySpeed(10.0))
+            .await
+            .unwrap();
+        assert!(report.passed(), "{:?}", report.lines());
+        assert_eq!(report.connections, 2);
+        // the job that came ahead of the target after the reconnect waits for it
+        assert_eq!(report.jobs, [1, 2, 3]);
+        assert_eq!(report.pauses.first(), Some(&PauseReason::Disconnected));
+        assert_eq!(
+            report.disconnects.first(),
+            Some(&DisconnectReason::RemoteClosed)
+        );
+
+        // without the miner's lines there is nothing to compare, but the reactions are the same
+        let pool_side: String = RECONNECT_WITH_REORDER
+            .lines()
+            .filter(|line| !line.contains(" > "))
+            .map(|line| format!("{}\n", line))
+            .collect();
+        let report = replay(&pool_side, ReplaySpeed(10.0)).await.unwrap();
+        assert!(report.passed(), "{:?}", report.lines());
+        assert_eq!(report.jobs, [1, 2, 3]);
+    }
+}
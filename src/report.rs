@@ -0,0 +1,430 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! Pushes a compact report of the stats to a central collector with `--report-to`, for farms
+//! that would rather collect from their rigs than scrape every stats api. The report is read
+//! from the [`StatsBoard`] like the stats api reads it, so pushing never holds up mining.
+//!
+//! Over UDP a report is one datagram, sent once. Over TCP it is one JSON line on a kept
+//! connection, over HTTP the body of a POST. A failed TCP or HTTP push is retried with a
+//! doubling backoff until the next report is due. The sequence number goes up by one with every
+//! report, so the collector tells a lost report by the gap.
+
+use crate::{
+    status, supervise, Cli, LogDecision, LogLimiter, MinerStats, PauseReason, PoolAddress,
+    RestartPolicy, StatsBoard, StatsSnapshot, SystemClock, WindowRate,
+};
+use anyhow::{anyhow, Result};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    net::SocketAddr,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Weak,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{lookup_host, TcpStream, UdpSocket},
+    time::{self, Instant},
+};
+
+/// How long one push may take.
+const REPORT_TIMEOUT: Duration = Duration::from_secs(5);
+/// The first wait before a failed TCP or HTTP push is retried.
+const REPORT_RETRY_BACKOFF: Duration = Duration::from_secs(1);
+/// Failing pushes are logged at most this often.
+const REPORT_ERROR_LOG_INTERVAL: Duration = Duration::from_secs(600);
+/// A miner without a job for this long is reported as such.
+const REPORT_MAX_JOB_AGE: Duration = Duration::from_secs(300);
+
+/// Where `--report-to` pushes, e.g. "udp://collector:9999", "tcp://collector:9999" or
+/// "http://collector/report".
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ReportTarget {
+    Udp(PoolAddress),
+    Tcp(PoolAddress),
+    Http(String),
+}
+
+impl FromStr for ReportTarget {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (scheme, rest) = s
+            .split_once("://")
+            .ok_or_else(|| anyhow!("expected udp://, tcp:// or http:// in '{}'", s))?;
+        match scheme {
+            "udp" => Ok(Self::Udp(rest.parse()?)),
+            "tcp" => Ok(Self::Tcp(rest.parse()?)),
+            "http" | "https" if !rest.is_empty() => Ok(Self::Http(s.to_string())),
+            _ => Err(anyhow!("expected udp://, tcp:// or http:// in '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for ReportTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Udp(address) => write!(f, "udp://{}", address),
+            Self::Tcp(address) => write!(f, "tcp://{}", address),
+            Self::Http(url) => f.write_str(url),
+        }
+    }
+}
+
+/// What is pushed to the collector.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct StatsReport {
+    /// 1 for the first report of the process, one more for each after it.
+    pub seq: u64,
+    pub version: String,
+    pub worker_name: String,
+    /// Unix seconds the stats were taken at.
+    pub taken_at: u64,
+    pub miners: Vec<MinerReport>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MinerReport {
+    pub label: String,
+    pub pool: String,
+    /// "mining", "paused (<reason>)", or why the miner has no job as `status` reports it.
+    pub state: String,
+    pub rate_1s: f64,
+    pub rates: Vec<WindowRate>,
+    pub shares_found: u64,
+    pub last_share_at: Option<u64>,
+}
+
+impl MinerReport {
+    fn new(stats: &MinerStats) -> Self {
+        Self {
+            label: stats.label.clone(),
+            pool: stats.pool.clone(),
+            state: state(
+                status::check(stats, REPORT_MAX_JOB_AGE),
+                stats.pauses.reason,
+            ),
+            rate_1s: stats.rate_1s,
+            rates: stats.rates.clone(),
+            shares_found: stats.shares_found,
+            last_share_at: stats.last_share_at,
+        }
+    }
+}
+
+fn state(check: Result<(), String>, pause: Option<PauseReason>) -> String {
+    match (check, pause) {
+        (Err(reason), _) => reason,
+        (Ok(()), Some(reason)) => format!("paused ({})", reason),
+        (Ok(()), None) => String::from("mining"),
+    }
+}
+
+impl StatsReport {
+    pub fn new(seq: u64, worker_name: &str, snapshot: &StatsSnapshot) -> Self {
+        Self {
+            seq,
+            version: String::from(env!("CARGO_PKG_VERSION")),
+            worker_name: worker_name.to_string(),
+            taken_at: snapshot.taken_at,
+            miners: snapshot.miners.iter().map(MinerReport::new).collect(),
+        }
+    }
+}
+
+/// How the pushes went, in the stats api.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ReportStats {
+    /// `--report-to`.
+    pub target: String,
+    /// The sequence number of the last report, 0 before the first one.
+    pub last_seq: u64,
+    /// Reports sent over UDP, or accepted by the collector over TCP and HTTP.
+    pub sent: u64,
+    /// Pushes that failed, retries included.
+    pub errors: u64,
+}
+
+/// Pushes a [`StatsReport`] every `--report-push-interval` seconds.
+#[derive(Debug)]
+pub struct Reporter {
+    target: ReportTarget,
+    interval: Duration,
+    worker_name: String,
+    board: Arc<StatsBoard>,
+    seq: AtomicU64,
+    sent: AtomicU64,
+    errors: AtomicU64,
+    error_log: LogLimiter<SystemClock>,
+}
+
+impl Reporter {
+    /// A reporter reading from `board`, `None` without `--report-to`.
+    pub fn new(cli: &Cli, board: Arc<StatsBoard>) -> Option<Arc<Self>> {
+        Some(Arc::new(Self {
+            target: cli.report_to.clone()?,
+            interval: Duration::from_secs(cli.report_push_interval),
+            worker_name: cli.worker_name.clone(),
+            board,
+            seq: Default::default(),
+            sent: Default::default(),
+            errors: Default::default(),
+            error_log: LogLimiter::new(SystemClock, 1, REPORT_ERROR_LOG_INTERVAL),
+        }))
+    }
+
+    pub fn stats(&self) -> ReportStats {
+        ReportStats {
+            target: self.target.to_string(),
+            last_seq: self.seq.load(Ordering::Relaxed),
+            sent: self.sent.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Pushes reports until the reporter is dropped.
+    pub fn start(reporter: &Arc<Self>) {
+        let weak = Arc::downgrade(reporter);
+        supervise("stats report", RestartPolicy::default(), move || {
+            Self::push_reports(weak.clone())
+        });
+    }
+
+    async fn push_reports(reporter: Weak<Self>) {
+        let (target, mut interval) = match reporter.upgrade() {
+            Some(reporter) => (reporter.target.clone(), time::interval(reporter.interval)),
+            None => return,
+        };
+        let mut connection = ReportConnection::new(target);
+        loop {
+            interval.tick().await;
+            let reporter = match reporter.upgrade() {
+                Some(reporter) => reporter,
+                None => return,
+            };
+            let seq = reporter.seq.fetch_add(1, Ordering::Relaxed) + 1;
+            let report = StatsReport::new(seq, &reporter.worker_name, &reporter.board.latest());
+            let line = match serde_json::to_string(&report) {
+                Ok(line) => line,
+                Err(error) => {
+                    error!("failed to encode the stats report: {}", error);
+                    continue;
+                }
+            };
+            let due = Instant::now() + reporter.interval;
+            let mut backoff = REPORT_RETRY_BACKOFF;
+            loop {
+                match connection.push(&line).await {
+                    Ok(()) => {
+                        reporter.sent.fetch_add(1, Ordering::Relaxed);
+                        break;
+                    }
+                    Err(error) => reporter.failed(seq, error),
+                }
+                if matches!(connection.target, ReportTarget::Udp(_))
+                    || Instant::now() + backoff >= due
+                {
+                    break;
+                }
+                time::sleep(backoff).await;
+                backoff *= 2;
+            }
+        }
+    }
+
+    fn failed(&self, seq: u64, error: anyhow::Error) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+        if let LogDecision::Emit { suppressed, .. } = self.error_log.check("report") {
+            warn!(
+                "failed to push stats report {} to {}: {}{}",
+                seq,
+                self.target,
+                error,
+                match suppressed {
+                    0 => String::new(),
+                    suppressed => format!(" ({} more failures in the last 10 minutes)", suppressed),
+                }
+            );
+        }
+    }
+}
+
+/// The socket, connection or client reports go out on, set up again after a failure.
+struct ReportConnection {
+    target: ReportTarget,
+    udp: Option<UdpSocket>,
+    tcp: Option<TcpStream>,
+    http: Option<reqwest::Client>,
+}
+
+impl ReportConnection {
+    fn new(target: ReportTarget) -> Self {
+        Self {
+            target,
+            udp: None,
+            tcp: None,
+            http: None,
+        }
+    }
+
+    async fn push(&mut self, line: &str) -> Result<()> {
+        let result = match time::timeout(REPORT_TIMEOUT, self.send(line)).await {
+            Ok(result) => result,
+            Err(_) => Err(anyhow!("timed out after {:?}", REPORT_TIMEOUT)),
+        };
+        if result.is_err() {
+            // a failed write may have left half a line on the connection
+            self.tcp = None;
+        }
+        result
+    }
+
+    async fn send(&mut self, line: &str) -> Result<()> {
+        match &self.target {
+            ReportTarget::Udp(address) => {
+                let address = resolve(address).await?;
+                let socket = match self.udp.take() {
+                    Some(socket) if socket.local_addr()?.is_ipv4() == address.is_ipv4() => socket,
+                    _ if address.is_ipv4() => UdpSocket::bind("0.0.0.0:0").await?,
+                    _ => UdpSocket::bind("[::]:0").await?,
+                };
+                socket.send_to(line.as_bytes(), address).await?;
+                self.udp = Some(socket);
+            }
+            ReportTarget::Tcp(address) => {
+                if self.tcp.is_none() {
+                    self.tcp = Some(TcpStream::connect(resolve(address).await?).await?);
+                }
+                let tcp = self.tcp.as_mut().unwrap();
+                tcp.write_all(format!("{}\n", line).as_bytes()).await?;
+            }
+            ReportTarget::Http(url) => {
+                if self.http.is_none() {
+                    self.http = Some(reqwest::Client::builder().timeout(REPORT_TIMEOUT).build()?);
+                }
+                self.http
+                    .as_ref()
+                    .unwrap()
+                    .post(url)
+                    .header(reqwest::header::CONTENT_TYPE, "application/json")
+                    .body(line.to_string())
+                    .send()
+                    .await?
+                    .error_for_status()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+async fn resolve(address: &PoolAddress) -> Result<SocketAddr> {
+    lookup_host(address.to_string())
+        .await?
+        .next()
+        .ok_or_else(|| anyhow!("{} has no address", address))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::test_cli, Miner};
+    use tokio::{
+        io::{AsyncBufReadExt, BufReader},
+        net::TcpListener,
+    };
+
+    #[test]
+    fn test_report_target() {
+        assert_eq!(
+            "udp://127.0.0.1:9999".parse::<ReportTarget>().unwrap(),
+            ReportTarget::Udp("127.0.0.1:9999".parse().unwrap())
+        );
+        let tcp: ReportTarget = "tcp://collector:9999".parse().unwrap();
+        assert_eq!(tcp.to_string(), "tcp://collector:9999");
+        let http: ReportTarget = "https://collector/report".parse().unwrap();
+        assert_eq!(
+            http,
+            ReportTarget::Http(String::from("https://collector/report"))
+        );
+        for target in [
+            "collector:9999",
+            "udp://collector",
+            "ftp://collector:21",
+            "http://",
+        ] {
+            assert!(target.parse::<ReportTarget>().is_err(), "{}", target);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_report() {
+        let miner = Miner::initialize(test_cli("127.0.0.1:8181".parse().unwrap()))
+            .await
+            .unwrap();
+        let snapshot = StatsSnapshot::assemble(&[miner]).await;
+        let report = StatsReport::new(3, "rig-7", &snapshot);
+        assert_eq!(report.seq, 3);
+        assert_eq!(report.worker_name, "rig-7");
+        assert_eq!(report.version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(report.taken_at, snapshot.taken_at);
+        assert_eq!(report.miners[0].state, "disconnected");
+        assert_eq!(report.miners[0].pool, "127.0.0.1:8181");
+
+        assert_eq!(state(Ok(()), None), "mining");
+        assert_eq!(state(Ok(()), Some(PauseReason::Manual)), "paused (manual)");
+        // why there is no job comes first
+        assert_eq!(
+            state(
+                Err(String::from("waiting for first job")),
+                Some(PauseReason::PoolRequested)
+            ),
+            "waiting for first job"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tcp_retry() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        // nothing listens yet, the push fails and is retried
+        drop(listener);
+        let cli = Cli {
+            report_to: Some(format!("tcp://{}", address).parse().unwrap()),
+            report_push_interval: 60,
+            worker_name: String::from("rig-7"),
+            ..test_cli(address)
+        };
+        let board = Arc::new(StatsBoard::default());
+        let reporter = Reporter::new(&cli, board).unwrap();
+        Reporter::start(&reporter);
+        time::timeout(Duration::from_secs(10), async {
+            while reporter.stats().errors == 0 {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        let listener = TcpListener::bind(address).await.unwrap();
+        let (stream, _) = time::timeout(Duration::from_secs(10), listener.accept())
+            .await
+            .unwrap()
+            .unwrap();
+        let mut lines = BufReader::new(stream).lines();
+        let line = lines.next_line().await.unwrap().unwrap();
+        let report: StatsReport = serde_json::from_str(&line).unwrap();
+        assert_eq!(report.seq, 1);
+        assert_eq!(report.worker_name, "rig-7");
+        let stats = reporter.stats();
+        assert_eq!(stats.target, format!("tcp://{}", address));
+        assert_eq!(stats.last_seq, 1);
+        assert_eq!(stats.sent, 1);
+        assert!(stats.errors >= 1);
+    }
+}
@@ -0,0 +1,413 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! How long to wait before trying again, in one place for everything that retries: the pool
+//! reconnect, webhooks, pool API polls and report pushes. [`retry_with`] retries an operation
+//! until it succeeds, [`Backoff`] hands out the delays to loops that run their own attempts,
+//! like the reconnect loop of the stratum client.
+//!
+//! The jitter only ever shortens a delay, so that many miners behind one pool that drops them
+//! all at once do not come back in lockstep, and no delay is ever longer than the cap.
+
+use crate::{Clock, SystemClock};
+use std::{
+    collections::hash_map::RandomState,
+    fmt,
+    future::Future,
+    hash::{BuildHasher, Hasher},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio_util::sync::CancellationToken;
+
+/// How the delay between attempts grows.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RetryPolicy {
+    /// The delay after the first failure.
+    pub initial: Duration,
+    /// What each further failure multiplies the delay by, at least 1.
+    pub multiplier: f64,
+    /// The longest delay.
+    pub max_delay: Duration,
+    /// The fraction of a delay the jitter may take off, from 0 for none to 1.
+    pub jitter: f64,
+    /// Attempts in all, the first one included. `None` retries until cancelled.
+    pub max_attempts: Option<u32>,
+    /// Whether [`Backoff::succeeded`] starts over from `initial`.
+    pub reset_on_success: bool,
+}
+
+impl RetryPolicy {
+    /// The delay after `failures` failures before the last one, without jitter.
+    pub fn delay_for(&self, failures: u32) -> Duration {
+        let factor = self
+            .multiplier
+            .max(1.0)
+            .powi(failures.min(i32::MAX as u32) as i32);
+        let secs = (self.initial.as_secs_f64() * factor).min(self.max_delay.as_secs_f64());
+        Duration::from_secs_f64(secs.max(0.0))
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_secs(1),
+            multiplier: 2.0,
+            max_delay: Duration::from_secs(60),
+            jitter: 0.2,
+            max_attempts: None,
+            reset_on_success: true,
+        }
+    }
+}
+
+/// The delays of one run of attempts, for a loop that makes the attempts itself.
+#[derive(Debug)]
+pub struct Backoff<C: Clock = SystemClock> {
+    policy: RetryPolicy,
+    clock: C,
+    /// Failures since the start or the last reset.
+    failures: u32,
+    /// xorshift64 state of the jitter, never 0.
+    state: u64,
+}
+
+impl Backoff {
+    pub fn new(policy: RetryPolicy) -> Self {
+        Self::on_clock(policy, SystemClock)
+    }
+}
+
+impl<C: Clock> Backoff<C> {
+    /// A backoff that waits on `clock`.
+    pub fn on_clock(policy: RetryPolicy, clock: C) -> Self {
+        Self::with_clock(policy, clock, random_seed())
+    }
+
+    /// A backoff that waits on `clock` and draws its jitter from `seed`.
+    pub fn with_clock(policy: RetryPolicy, clock: C, seed: u64) -> Self {
+        Self {
+            policy,
+            clock,
+            failures: 0,
+            state: seed.max(1),
+        }
+    }
+
+    pub fn policy(&self) -> &RetryPolicy {
+        &self.policy
+    }
+
+    /// Failures since the start or the last reset.
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    /// Records a failure and returns the delay before the next attempt, `None` once the
+    /// attempts of the policy are used up.
+    pub fn next_delay(&mut self) -> Option<Duration> {
+        if let Some(max_attempts) = self.policy.max_attempts {
+            if self.failures.saturating_add(1) >= max_attempts {
+                return None;
+            }
+        }
+        let delay = self.policy.delay_for(self.failures);
+        self.failures = self.failures.saturating_add(1);
+        let jitter = self.policy.jitter.clamp(0.0, 1.0) * self.uniform();
+        Some(delay.mul_f64(1.0 - jitter))
+    }
+
+    /// Starts over from the initial delay, if the policy resets on success.
+    pub fn succeeded(&mut self) {
+        if self.policy.reset_on_success {
+            self.reset();
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.failures = 0;
+    }
+
+    /// Records a failure and waits out the delay before the next attempt. `false` once the
+    /// attempts are used up, or when `cancel` fires, also in the middle of the wait.
+    pub async fn wait(&mut self, cancel: &CancellationToken) -> bool {
+        let delay = match self.next_delay() {
+            Some(delay) => delay,
+            None => return false,
+        };
+        tokio::select! {
+            _ = cancel.cancelled() => false,
+            _ = self.clock.sleep(delay) => !cancel.is_cancelled(),
+        }
+    }
+
+    /// Uniform in [0, 1).
+    fn uniform(&mut self) -> f64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        (self.state >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+fn random_seed() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_nanos() as u64)
+        .unwrap_or_default();
+    hasher.write_u64(nanos);
+    hasher.finish()
+}
+
+/// Why [`retry_with`] gave up.
+#[derive(Debug, PartialEq, Eq)]
+pub enum RetryError<E> {
+    /// The cancellation token fired after `attempts` attempts.
+    Cancelled { attempts: u32 },
+    /// The attempts of the policy were used up, `error` is the last one.
+    Exhausted { attempts: u32, error: E },
+}
+
+impl<E: fmt::Display> fmt::Display for RetryError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Cancelled { attempts } => write!(f, "cancelled after {} attempts", attempts),
+            Self::Exhausted { attempts, error } => {
+                write!(f, "gave up after {} attempts: {}", attempts, error)
+            }
+        }
+    }
+}
+
+impl<E: fmt::Debug + fmt::Display> std::error::Error for RetryError<E> {}
+
+/// Runs `operation` until it succeeds, waiting between attempts as `policy` says. Gives up
+/// once the attempts are used up, or when `cancel` fires, which also abandons an attempt in
+/// progress.
+pub async fn retry_with<T, E, F, Fut>(
+    policy: RetryPolicy,
+    cancel: &CancellationToken,
+    operation: F,
+) -> Result<T, RetryError<E>>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    retry_backoff(Backoff::new(policy), cancel, operation).await
+}
+
+/// [`retry_with`] with the delays of `backoff`.
+pub async fn retry_backoff<T, E, F, Fut, C>(
+    mut backoff: Backoff<C>,
+    cancel: &CancellationToken,
+    mut operation: F,
+) -> Result<T, RetryError<E>>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+    C: Clock,
+{
+    let mut attempts = 0;
+    loop {
+        if cancel.is_cancelled() {
+            return Err(RetryError::Cancelled { attempts });
+        }
+        attempts += 1;
+        let error = tokio::select! {
+            _ = cancel.cancelled() => return Err(RetryError::Cancelled { attempts }),
+            result = operation() => match result {
+                Ok(value) => return Ok(value),
+                Err(error) => error,
+            },
+        };
+        if !backoff.wait(cancel).await {
+            if cancel.is_cancelled() {
+                return Err(RetryError::Cancelled { attempts });
+            }
+            return Err(RetryError::Exhausted { attempts, error });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ManualClock;
+    use futures::future;
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            initial: Duration::from_millis(100),
+            multiplier: 2.0,
+            max_delay: Duration::from_millis(1000),
+            jitter: 0.0,
+            max_attempts: None,
+            reset_on_success: true,
+        }
+    }
+
+    fn millis(delays: &[Duration]) -> Vec<u128> {
+        delays.iter().map(|delay| delay.as_millis()).collect()
+    }
+
+    #[test]
+    fn test_cap_and_reset() {
+        let mut backoff = Backoff::with_clock(policy(), ManualClock::new(), 1);
+        let delays: Vec<Duration> = (0..7).map(|_| backoff.next_delay().unwrap()).collect();
+        assert_eq!(millis(&delays), vec![100, 200, 400, 800, 1000, 1000, 1000]);
+        assert_eq!(backoff.failures(), 7);
+        // far past the cap without overflowing
+        assert_eq!(policy().delay_for(u32::MAX), Duration::from_millis(1000));
+
+        backoff.succeeded();
+        assert_eq!(backoff.next_delay(), Some(Duration::from_millis(100)));
+        let mut kept = Backoff::with_clock(
+            RetryPolicy {
+                reset_on_success: false,
+                ..policy()
+            },
+            ManualClock::new(),
+            1,
+        );
+        kept.next_delay();
+        kept.succeeded();
+        assert_eq!(kept.next_delay(), Some(Duration::from_millis(200)));
+
+        // a multiplier below 1 does not shrink the delay
+        let flat = RetryPolicy {
+            multiplier: 0.5,
+            ..policy()
+        };
+        assert_eq!(flat.delay_for(5), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_jitter_bounds() {
+        let jittered = RetryPolicy {
+            jitter: 0.5,
+            ..policy()
+        };
+        let mut shortened = 0;
+        for seed in 1..200 {
+            let mut backoff = Backoff::with_clock(jittered, ManualClock::new(), seed);
+            for failures in 0..8 {
+                let full = jittered.delay_for(failures);
+                let delay = backoff.next_delay().unwrap();
+                assert!(delay <= full, "{:?} > {:?}", delay, full);
+                assert!(delay >= full / 2, "{:?} < {:?}", delay, full / 2);
+                assert!(delay <= jittered.max_delay);
+                if delay < full {
+                    shortened += 1;
+                }
+            }
+        }
+        assert!(shortened > 1000, "{}", shortened);
+        // the same seed gives the same delays
+        let delays = |seed| {
+            let mut backoff = Backoff::with_clock(jittered, ManualClock::new(), seed);
+            (0..8)
+                .map(|_| backoff.next_delay().unwrap())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(delays(7), delays(7));
+        assert_ne!(delays(7), delays(8));
+    }
+
+    #[tokio::test]
+    async fn test_retry_until_success() {
+        let clock = ManualClock::auto_advance();
+        let start = clock.now();
+        let backoff = Backoff::with_clock(policy(), clock.clone(), 1);
+        let mut calls = 0;
+        let result: Result<u32, RetryError<String>> =
+            retry_backoff(backoff, &CancellationToken::new(), || {
+                calls += 1;
+                let attempt = calls;
+                async move {
+                    if attempt < 4 {
+                        Err(format!("attempt {}", attempt))
+                    } else {
+                        Ok(attempt)
+                    }
+                }
+            })
+            .await;
+        assert_eq!(result, Ok(4));
+        assert_eq!(millis(&clock.sleeps()), vec![100, 200, 400]);
+        assert_eq!(clock.now() - start, Duration::from_millis(700));
+    }
+
+    #[tokio::test]
+    async fn test_max_attempts() {
+        let clock = ManualClock::auto_advance();
+        let limited = RetryPolicy {
+            max_attempts: Some(3),
+            ..policy()
+        };
+        let backoff = Backoff::with_clock(limited, clock.clone(), 1);
+        let result: Result<(), _> = retry_backoff(backoff, &CancellationToken::new(), || async {
+            Err("refused")
+        })
+        .await;
+        assert_eq!(
+            result,
+            Err(RetryError::Exhausted {
+                attempts: 3,
+                error: "refused"
+            })
+        );
+        assert_eq!(clock.sleeps().len(), 2);
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "gave up after 3 attempts: refused"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cancel_mid_wait() {
+        // the clock never reaches the end of the wait, the token fires during it
+        let clock = ManualClock::new();
+        let cancel = CancellationToken::new();
+        let cancel_in_wait = || async {
+            clock.sleepers(1).await;
+            cancel.cancel();
+        };
+        let mut backoff = Backoff::with_clock(policy(), clock.clone(), 1);
+        let (waited, _) = tokio::join!(backoff.wait(&cancel), cancel_in_wait());
+        assert!(!waited);
+        assert_eq!(clock.sleeps().len(), 1);
+
+        // the retry stops in the wait after the first attempt
+        let clock = ManualClock::new();
+        let cancel = CancellationToken::new();
+        let cancel_in_wait = || async {
+            clock.sleepers(1).await;
+            cancel.cancel();
+        };
+        let backoff = Backoff::with_clock(policy(), clock.clone(), 1);
+        let mut calls = 0;
+        let retry = retry_backoff(backoff, &cancel, || {
+            calls += 1;
+            async { Err("refused") }
+        });
+        let (result, _): (Result<(), _>, _) = tokio::join!(retry, cancel_in_wait());
+        assert_eq!(result, Err(RetryError::Cancelled { attempts: 1 }));
+        assert_eq!(calls, 1);
+
+        // and an attempt in progress is abandoned
+        let cancel = CancellationToken::new();
+        let canceller = cancel.clone();
+        let result: Result<(), RetryError<&str>> = retry_with(policy(), &cancel, || {
+            canceller.cancel();
+            future::pending()
+        })
+        .await;
+        assert_eq!(result, Err(RetryError::Cancelled { attempts: 1 }));
+        // nothing is tried once cancelled
+        let result: Result<(), RetryError<&str>> =
+            retry_with(policy(), &cancel, || async { Ok(()) }).await;
+        assert_eq!(result, Err(RetryError::Cancelled { attempts: 0 }));
+    }
+}
@@ -0,0 +1,35 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::Cli;
+use anyhow::Result;
+use log::*;
+use tokio::runtime::{Builder, Runtime};
+
+/// Runtime threads left when the mining threads are lowered to the core count.
+const MIN_TOKIO_WORKER_THREADS: usize = 2;
+const MAX_TOKIO_BLOCKING_THREADS: usize = 1024; // 512 is tokio's current default
+
+/// Builds the runtime the miners run on. `--threads` is lowered to the core count first,
+/// unless oversubscribing is allowed, and the runtime then makes do with fewer threads.
+pub fn build_runtime(cli: &mut Cli) -> Result<Runtime> {
+    let cores = num_cpus::get();
+    let mut num_tokio_worker_threads = cores;
+    if let Some(requested) = cli.clamp_threads(cores) {
+        // the mining threads take every core, the runtime only serves the pool connection and
+        // the stats api
+        num_tokio_worker_threads = MIN_TOKIO_WORKER_THREADS.min(cores);
+        warn!(
+            "--threads {} is more than the {} cores of this machine, mining on {} threads with {} runtime threads. Pass --allow-oversubscribe to mine on {} threads anyway",
+            requested, cores, cli.threads_count, num_tokio_worker_threads, requested
+        );
+    }
+    info!("mining on {} threads, {} cores", cli.threads_count, cores);
+    Ok(Builder::new_multi_thread()
+        .enable_all()
+        .thread_stack_size(16 * 1024 * 1024)
+        .worker_threads(num_tokio_worker_threads)
+        .max_blocking_threads(MAX_TOKIO_BLOCKING_THREADS)
+        .build()?)
+}
@@ -0,0 +1,123 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! Checks at startup that the thread pool can be built from the configuration and hashes. A
+//! thread pool that can't mine, e.g. with no threads or a batch it never gets through, would
+//! otherwise only show as 0 H/s.
+
+use crate::{check::HASH_TEST_HEADER, Cli, ThreadPool};
+use anyhow::{anyhow, Result};
+use log::*;
+use std::{
+    panic::{self, AssertUnwindSafe},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Exit code of the process when the configuration can not mine, as for an invalid option.
+pub const CONFIG_ERROR_EXIT_CODE: i32 = 2;
+/// Far more hashes per batch than keep job switches quick on any machine.
+pub const MAX_BATCH_SIZE: u32 = 1 << 27;
+/// How long the thread pool has to report its first hashes.
+pub const SELF_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+const SELF_CHECK_POLL: Duration = Duration::from_millis(10);
+
+/// Rejects a thread pool that could not mine before it is built.
+pub fn validate_thread_pool(threads: usize, batch_size: u32) -> Result<()> {
+    if threads == 0 {
+        return Err(anyhow!(
+            "no threads to mine on, --threads must be at least 1"
+        ));
+    }
+    if batch_size == 0 {
+        return Err(anyhow!("the batch size must be at least 1"));
+    }
+    if batch_size > MAX_BATCH_SIZE {
+        return Err(anyhow!(
+            "the batch size {} is over the maximum of {}, the threads would hardly ever see a new job",
+            batch_size,
+            MAX_BATCH_SIZE
+        ));
+    }
+    Ok(())
+}
+
+/// Builds a thread pool, hands it a job with the easiest target and waits up to
+/// [`SELF_CHECK_TIMEOUT`] for hashes to show up. Returns the hashes counted.
+pub fn self_check(threads: usize, batch_size: u32) -> Result<u64> {
+    validate_thread_pool(threads, batch_size)?;
+    let header = hex::decode(HASH_TEST_HEADER)?;
+    let started = Instant::now();
+    let hashes = panic::catch_unwind(AssertUnwindSafe(|| {
+        let thread_pool = ThreadPool::new(threads, batch_size);
+        thread_pool.new_work(&header, &[0xff; 32], 0, 0);
+        let mut hashes = 0;
+        while hashes == 0 && started.elapsed() < SELF_CHECK_TIMEOUT {
+            thread::sleep(SELF_CHECK_POLL);
+            hashes += thread_pool.get_hash_rate_submission();
+        }
+        thread_pool.stop();
+        hashes
+    }))
+    .map_err(|_| {
+        anyhow!(
+            "the thread pool with {} threads and batch size {} could not be created",
+            threads,
+            batch_size
+        )
+    })?;
+    if hashes == 0 {
+        return Err(anyhow!(
+            "the thread pool with {} threads and batch size {} hashed nothing within {:?}, the threads may not be able to run or the batch size may be too large. Pass --skip-selfcheck to mine anyway",
+            threads,
+            batch_size,
+            SELF_CHECK_TIMEOUT
+        ));
+    }
+    Ok(hashes)
+}
+
+/// Validates the thread pool of every miner instance, then runs the self-check on each unless
+/// `--skip-selfcheck` is given.
+pub fn check_thread_pools(cli: &Cli) -> Result<()> {
+    for instance in cli.instances()? {
+        let batch_size = instance.effective_batch_size()?;
+        validate_thread_pool(instance.threads_count, batch_size)?;
+        if cli.skip_selfcheck {
+            continue;
+        }
+        let started = Instant::now();
+        let hashes = self_check(instance.threads_count, batch_size)?;
+        debug!(
+            "self-check passed: {} threads with batch size {} hashed {} in {:?}",
+            instance.threads_count,
+            batch_size,
+            hashes,
+            started.elapsed()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_thread_pool() {
+        assert!(validate_thread_pool(1, 1).is_ok());
+        assert!(validate_thread_pool(64, MAX_BATCH_SIZE).is_ok());
+        let no_threads = validate_thread_pool(0, 10000).unwrap_err().to_string();
+        assert!(no_threads.contains("--threads"), "{}", no_threads);
+        assert!(validate_thread_pool(4, 0).is_err());
+        assert!(validate_thread_pool(4, MAX_BATCH_SIZE + 1).is_err());
+        // rejected before anything is built
+        assert!(self_check(0, 10000).is_err());
+    }
+
+    #[test]
+    fn test_self_check() {
+        assert!(self_check(1, 1000).unwrap() > 0);
+    }
+}
@@ -0,0 +1,156 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! Flags randomness values that look more like a thread pool artifact than a search result,
+//! such as the bursts of zero seen after a thread pool rebuild. Flagged shares are hashed again
+//! before they are submitted, see [`crate::Miner`]. The checks only look at the value and a
+//! second of history, so every other share goes out unchecked.
+//!
+//! The guard also keeps when the recent jobs were dispatched, which gives the solve time a share
+//! is submitted with to pools that asked for it.
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    time::{Duration, Instant},
+};
+
+/// A randomness of zero this soon after a job was dispatched is checked.
+pub const ZERO_AFTER_DISPATCH: Duration = Duration::from_secs(1);
+/// A randomness found again within this long, on any job, is checked.
+pub const REPEAT_WINDOW: Duration = Duration::from_secs(1);
+/// Randomness values remembered for [`REPEAT_WINDOW`], the oldest are forgotten first.
+const MAX_RECENT: usize = 256;
+/// Dispatches remembered for [`ShareGuard::dispatched_at`], the oldest are forgotten first.
+const MAX_DISPATCHED: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Suspicion {
+    ZeroAfterDispatch,
+    Repeated,
+}
+
+impl fmt::Display for Suspicion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ZeroAfterDispatch => f.write_str("zero right after a job dispatch"),
+            Self::Repeated => f.write_str("found twice within a second"),
+        }
+    }
+}
+
+/// See the module docs.
+#[derive(Debug, Default)]
+pub struct ShareGuard {
+    /// The latest dispatch of each recent job, the latest job last.
+    dispatched: VecDeque<(u32, Instant)>,
+    recent: VecDeque<(Instant, u64)>,
+}
+
+impl ShareGuard {
+    pub fn dispatched(&mut self, mining_request_id: u32, now: Instant) {
+        self.dispatched
+            .retain(|&(dispatched, _)| dispatched != mining_request_id);
+        if self.dispatched.len() == MAX_DISPATCHED {
+            self.dispatched.pop_front();
+        }
+        self.dispatched.push_back((mining_request_id, now));
+    }
+
+    /// When the job was last dispatched, if it is recent enough to be remembered.
+    pub fn dispatched_at(&self, mining_request_id: u32) -> Option<Instant> {
+        self.dispatched
+            .iter()
+            .find(|&&(dispatched, _)| dispatched == mining_request_id)
+            .map(|&(_, at)| at)
+    }
+
+    /// Why a share found `now` should be hashed again before it is submitted, `None` if it
+    /// looks fine.
+    pub fn check(&mut self, randomness: u64, now: Instant) -> Option<Suspicion> {
+        while let Some(&(at, _)) = self.recent.front() {
+            if now.saturating_duration_since(at) < REPEAT_WINDOW {
+                break;
+            }
+            self.recent.pop_front();
+        }
+        let repeated = self.recent.iter().any(|&(_, seen)| seen == randomness);
+        if self.recent.len() == MAX_RECENT {
+            self.recent.pop_front();
+        }
+        self.recent.push_back((now, randomness));
+        let after_dispatch = self
+            .dispatched
+            .back()
+            .map(|&(_, at)| now.saturating_duration_since(at) < ZERO_AFTER_DISPATCH)
+            .unwrap_or_default();
+        if randomness == 0 && after_dispatch {
+            Some(Suspicion::ZeroAfterDispatch)
+        } else if repeated {
+            Some(Suspicion::Repeated)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_share_guard() {
+        let start = Instant::now();
+        let mut guard = ShareGuard::default();
+        // zero is a value like any other before the first job and long after a dispatch
+        assert_eq!(guard.check(0, start), None);
+        guard.dispatched(7, start);
+        assert_eq!(
+            guard.check(0, start + Duration::from_millis(10)),
+            Some(Suspicion::ZeroAfterDispatch)
+        );
+        assert_eq!(guard.check(0, start + Duration::from_secs(5)), None);
+
+        assert_eq!(guard.check(1234, start + Duration::from_secs(6)), None);
+        assert_eq!(guard.check(1235, start + Duration::from_secs(6)), None);
+        assert_eq!(
+            guard.check(1234, start + Duration::from_millis(6500)),
+            Some(Suspicion::Repeated)
+        );
+        assert_eq!(guard.check(1235, start + Duration::from_secs(8)), None);
+        assert!(guard.recent.len() <= 2);
+
+        // a burst remembers a bounded number of values
+        for randomness in 0..1000 {
+            guard.check(randomness + 10_000, start + Duration::from_secs(9));
+        }
+        assert_eq!(guard.recent.len(), MAX_RECENT);
+    }
+
+    #[test]
+    fn test_dispatched_at() {
+        let start = Instant::now();
+        let mut guard = ShareGuard::default();
+        assert_eq!(guard.dispatched_at(7), None);
+        guard.dispatched(7, start);
+        guard.dispatched(8, start + Duration::from_secs(1));
+        assert_eq!(guard.dispatched_at(7), Some(start));
+        // a job dispatched again counts from its latest dispatch
+        guard.dispatched(7, start + Duration::from_secs(2));
+        assert_eq!(guard.dispatched_at(7), Some(start + Duration::from_secs(2)));
+        assert_eq!(guard.dispatched_at(8), Some(start + Duration::from_secs(1)));
+        // zero is checked against the latest dispatch of any job
+        assert_eq!(
+            guard.check(0, start + Duration::from_millis(2500)),
+            Some(Suspicion::ZeroAfterDispatch)
+        );
+
+        for mining_request_id in 100..1000 {
+            guard.dispatched(mining_request_id, start);
+        }
+        assert_eq!(guard.dispatched.len(), MAX_DISPATCHED);
+        assert_eq!(guard.dispatched_at(7), None);
+        assert_eq!(guard.dispatched_at(999), Some(start));
+    }
+}
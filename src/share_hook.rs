@@ -0,0 +1,177 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! A hook for programs embedding the miner to decide on every share right before it is
+//! submitted, see [`crate::Miner::set_share_hook`].
+
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+/// A hook that takes longer than this is logged. It runs on the mining loop, which finds no
+/// shares while it waits.
+pub const SHARE_HOOK_BUDGET: Duration = Duration::from_millis(5);
+
+/// A share about to be submitted.
+#[derive(Clone, Debug)]
+pub struct ShareCandidate<'a> {
+    pub mining_request_id: u32,
+    pub randomness: u64,
+    /// The header and target of the job, `None` once the job is no longer known.
+    pub header: Option<&'a [u8]>,
+    pub target: Option<&'a [u8; 32]>,
+    /// When the share was found, and when the pool sent its job.
+    pub found_at: Instant,
+    pub notified_at: Option<Instant>,
+}
+
+/// What becomes of a [`ShareCandidate`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ShareDecision {
+    Submit,
+    /// Not submitted, the reason is logged.
+    Drop {
+        reason: String,
+    },
+    /// Submitted with this randomness instead.
+    Replace {
+        randomness: u64,
+    },
+}
+
+/// Decides on every share, see [`crate::Miner::set_share_hook`].
+pub type ShareHook = Box<dyn Fn(&ShareCandidate<'_>) -> ShareDecision + Send + Sync>;
+
+/// The decisions of the hook, present in the stats once one is installed.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ShareHookStats {
+    pub submitted: u64,
+    pub dropped: u64,
+    pub replaced: u64,
+    /// Calls over [`SHARE_HOOK_BUDGET`].
+    pub slow: u64,
+}
+
+/// Where a miner keeps its hook, and counts what it decided.
+#[derive(Default)]
+pub struct ShareHookSlot {
+    hook: RwLock<Option<ShareHook>>,
+    submitted: AtomicU64,
+    dropped: AtomicU64,
+    replaced: AtomicU64,
+    slow: AtomicU64,
+}
+
+impl ShareHookSlot {
+    pub fn set(&self, hook: ShareHook) {
+        *self.hook.write().unwrap() = Some(hook);
+    }
+
+    /// What the hook decides on `candidate`, [`ShareDecision::Submit`] without a hook.
+    pub fn decide(&self, candidate: &ShareCandidate<'_>) -> ShareDecision {
+        let hook = self.hook.read().unwrap();
+        let hook = match hook.as_ref() {
+            Some(hook) => hook,
+            None => return ShareDecision::Submit,
+        };
+        let started = Instant::now();
+        let decision = hook(candidate);
+        let took = started.elapsed();
+        if took > SHARE_HOOK_BUDGET {
+            self.slow.fetch_add(1, Ordering::Relaxed);
+            warn!(
+                "share hook took {:.1}ms on mining request id({}), over its {}ms budget",
+                took.as_secs_f64() * 1000.0,
+                candidate.mining_request_id,
+                SHARE_HOOK_BUDGET.as_millis()
+            );
+        }
+        let counter = match decision {
+            ShareDecision::Submit => &self.submitted,
+            ShareDecision::Drop { .. } => &self.dropped,
+            ShareDecision::Replace { .. } => &self.replaced,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        decision
+    }
+
+    /// `None` without a hook.
+    pub fn stats(&self) -> Option<ShareHookStats> {
+        self.hook.read().unwrap().as_ref()?;
+        Some(ShareHookStats {
+            submitted: self.submitted.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            replaced: self.replaced.load(Ordering::Relaxed),
+            slow: self.slow.load(Ordering::Relaxed),
+        })
+    }
+}
+
+impl fmt::Debug for ShareHookSlot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ShareHookSlot")
+            .field("stats", &self.stats())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_share_hook_slot() {
+        let slot = ShareHookSlot::default();
+        let candidate = |randomness| ShareCandidate {
+            mining_request_id: 7,
+            randomness,
+            header: None,
+            target: None,
+            found_at: Instant::now(),
+            notified_at: None,
+        };
+        assert_eq!(slot.decide(&candidate(1)), ShareDecision::Submit);
+        assert_eq!(slot.stats(), None);
+
+        slot.set(Box::new(|candidate| match candidate.randomness {
+            0 => ShareDecision::Drop {
+                reason: String::from("zero"),
+            },
+            1 => ShareDecision::Replace { randomness: 2 },
+            3 => {
+                std::thread::sleep(SHARE_HOOK_BUDGET * 2);
+                ShareDecision::Submit
+            }
+            _ => ShareDecision::Submit,
+        }));
+        assert_eq!(
+            slot.decide(&candidate(0)),
+            ShareDecision::Drop {
+                reason: String::from("zero")
+            }
+        );
+        assert_eq!(
+            slot.decide(&candidate(1)),
+            ShareDecision::Replace { randomness: 2 }
+        );
+        assert_eq!(slot.decide(&candidate(3)), ShareDecision::Submit);
+        assert_eq!(slot.decide(&candidate(4)), ShareDecision::Submit);
+        assert_eq!(
+            slot.stats(),
+            Some(ShareHookStats {
+                submitted: 2,
+                dropped: 1,
+                replaced: 1,
+                slow: 1,
+            })
+        );
+    }
+}
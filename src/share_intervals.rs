@@ -0,0 +1,259 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! The time between shares of a pool session. An honest random search finds shares as a
+//! Poisson process, so the intervals are exponential and their coefficient of variation, the
+//! standard deviation over the mean, is close to 1 whatever the hashrate and target. A thread
+//! pool that restarts its nonce search from the same point after every pause finds the same
+//! shares at the same offsets: far more regular intervals, or bursts right after resumes.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+/// Upper bounds of the buckets in seconds, the last bucket takes everything above.
+const BUCKETS_SECS: [f64; 12] = [
+    0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0,
+];
+/// Intervals needed before judging: the coefficient of variation of `n` exponential intervals
+/// strays about `1 / sqrt(n)` from 1.
+pub const MIN_INTERVALS: u64 = 30;
+/// Below this coefficient of variation the shares come too regularly for a random search.
+const REGULAR_CV: f64 = 0.5;
+/// Above it they come in bursts, e.g. the same shares found again after each resume.
+const CLUSTERED_CV: f64 = 2.0;
+
+/// What the intervals between shares say about the nonce search.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IntervalVerdict {
+    /// Fewer than [`MIN_INTERVALS`] intervals in the session.
+    #[default]
+    TooFewShares,
+    /// As expected of a random search.
+    Random,
+    /// Too regular, the search likely repeats itself.
+    TooRegular,
+    /// Bursts of shares, e.g. found again after every resume.
+    Clustered,
+}
+
+impl IntervalVerdict {
+    /// Judges intervals with the coefficient of variation `cv`.
+    pub fn judge(intervals: u64, cv: f64) -> Self {
+        if intervals < MIN_INTERVALS {
+            Self::TooFewShares
+        } else if cv < REGULAR_CV {
+            Self::TooRegular
+        } else if cv > CLUSTERED_CV {
+            Self::Clustered
+        } else {
+            Self::Random
+        }
+    }
+
+    pub fn is_suspicious(&self) -> bool {
+        matches!(self, Self::TooRegular | Self::Clustered)
+    }
+}
+
+impl fmt::Display for IntervalVerdict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TooFewShares => f.write_str("too few shares to judge"),
+            Self::Random => f.write_str("random, as expected"),
+            Self::TooRegular => f.write_str("too regular, the nonce search may repeat itself"),
+            Self::Clustered => f.write_str("clustered, shares may be found again after resumes"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct IntervalBucket {
+    /// Upper bound in seconds, `None` for the last bucket.
+    pub le_secs: Option<f64>,
+    pub count: u64,
+}
+
+/// The share intervals of the current or last session.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ShareIntervalStats {
+    /// The session the intervals belong to, `None` while no share was found with one.
+    pub epoch: Option<u64>,
+    pub intervals: u64,
+    pub mean_secs: Option<f64>,
+    /// Standard deviation over mean of the intervals, about 1 for a random search.
+    pub cv: Option<f64>,
+    pub verdict: IntervalVerdict,
+    pub buckets: Vec<IntervalBucket>,
+}
+
+/// Collects the intervals between the shares of one session at a time.
+#[derive(Clone, Debug, Default)]
+pub struct ShareIntervals {
+    epoch: Option<u64>,
+    last_share: Option<Instant>,
+    counts: [u64; BUCKETS_SECS.len() + 1],
+    total: u64,
+    sum: f64,
+    sum_squares: f64,
+    warned: bool,
+}
+
+impl ShareIntervals {
+    /// A share of the session `epoch` was found at `now`, a new session starts over. Returns
+    /// the verdict the first time it turns suspicious in the session.
+    pub fn share_found(&mut self, epoch: Option<u64>, now: Instant) -> Option<IntervalVerdict> {
+        if self.epoch != epoch {
+            *self = Self {
+                epoch,
+                ..Default::default()
+            };
+        }
+        let last_share = self.last_share.replace(now)?;
+        self.record(now.saturating_duration_since(last_share));
+        let verdict = self.verdict();
+        if verdict.is_suspicious() && !self.warned {
+            self.warned = true;
+            return Some(verdict);
+        }
+        None
+    }
+
+    pub fn record(&mut self, interval: Duration) {
+        let secs = interval.as_secs_f64();
+        let bucket = BUCKETS_SECS
+            .iter()
+            .position(|bound| secs <= *bound)
+            .unwrap_or(BUCKETS_SECS.len());
+        self.counts[bucket] += 1;
+        self.total += 1;
+        self.sum += secs;
+        self.sum_squares += secs * secs;
+    }
+
+    fn mean(&self) -> Option<f64> {
+        Some(self.sum / self.total as f64).filter(|_| self.total > 0)
+    }
+
+    /// `None` without intervals, or when they are all zero.
+    pub fn cv(&self) -> Option<f64> {
+        let mean = self.mean().filter(|mean| *mean > 0.0)?;
+        let variance = (self.sum_squares / self.total as f64 - mean * mean).max(0.0);
+        Some(variance.sqrt() / mean)
+    }
+
+    pub fn verdict(&self) -> IntervalVerdict {
+        IntervalVerdict::judge(self.total, self.cv().unwrap_or(0.0))
+    }
+
+    pub fn stats(&self) -> ShareIntervalStats {
+        ShareIntervalStats {
+            epoch: self.epoch,
+            intervals: self.total,
+            mean_secs: self.mean(),
+            cv: self.cv(),
+            verdict: self.verdict(),
+            buckets: self
+                .counts
+                .iter()
+                .enumerate()
+                .map(|(bucket, count)| IntervalBucket {
+                    le_secs: BUCKETS_SECS.get(bucket).copied(),
+                    count: *count,
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Exponential intervals with a mean of `mean_secs`, from a fixed seed.
+    fn exponential(count: usize, mean_secs: f64) -> Vec<Duration> {
+        let mut state: u64 = 0x9e37_79b9_7f4a_7c15;
+        (0..count)
+            .map(|_| {
+                // xorshift64, uniform in (0, 1]
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                let uniform = ((state >> 11) + 1) as f64 / (1u64 << 53) as f64;
+                Duration::from_secs_f64(-uniform.ln() * mean_secs)
+            })
+            .collect()
+    }
+
+    fn intervals(durations: &[Duration]) -> ShareIntervals {
+        let mut intervals = ShareIntervals::default();
+        for interval in durations {
+            intervals.record(*interval);
+        }
+        intervals
+    }
+
+    #[test]
+    fn test_exponential_is_random() {
+        let random = intervals(&exponential(500, 7.0));
+        let cv = random.cv().unwrap();
+        assert!((0.8..1.2).contains(&cv), "{}", cv);
+        assert_eq!(random.verdict(), IntervalVerdict::Random);
+        let stats = random.stats();
+        assert_eq!(stats.intervals, 500);
+        assert_eq!(stats.buckets.len(), BUCKETS_SECS.len() + 1);
+        assert_eq!(
+            stats.buckets.iter().map(|bucket| bucket.count).sum::<u64>(),
+            500
+        );
+        assert_eq!(stats.buckets.last().unwrap().le_secs, None);
+        assert!((stats.mean_secs.unwrap() - 7.0).abs() < 1.0);
+    }
+
+    #[test]
+    fn test_regular_is_suspicious() {
+        // the same search after every pause finds shares at the same offsets
+        let regular: Vec<Duration> = (0..100)
+            .map(|i| Duration::from_millis(5000 + (i % 5) * 100))
+            .collect();
+        assert_eq!(intervals(&regular).verdict(), IntervalVerdict::TooRegular);
+
+        // bursts of the same shares right after each resume, then long waits
+        let clustered: Vec<Duration> = (0..100)
+            .map(|i| match i % 20 {
+                0 => Duration::from_secs(600),
+                _ => Duration::from_millis(10),
+            })
+            .collect();
+        assert_eq!(intervals(&clustered).verdict(), IntervalVerdict::Clustered);
+
+        // too few intervals to tell
+        let few = intervals(&regular[..(MIN_INTERVALS - 1) as usize]);
+        assert_eq!(few.verdict(), IntervalVerdict::TooFewShares);
+    }
+
+    #[test]
+    fn test_share_found_per_session() {
+        let start = Instant::now();
+        let mut intervals = ShareIntervals::default();
+        let mut warnings = vec![];
+        for i in 0..=MIN_INTERVALS + 5 {
+            warnings.extend(intervals.share_found(Some(1), start + Duration::from_secs(i)));
+        }
+        // warned once, as soon as there were enough intervals
+        assert_eq!(warnings, vec![IntervalVerdict::TooRegular]);
+        assert_eq!(intervals.stats().intervals, MIN_INTERVALS + 5);
+        assert_eq!(intervals.stats().epoch, Some(1));
+
+        // a new session starts over, its first share has no interval
+        let later = start + Duration::from_secs(3600);
+        assert_eq!(intervals.share_found(Some(2), later), None);
+        assert_eq!(intervals.stats().intervals, 0);
+        assert_eq!(intervals.stats().cv, None);
+        assert_eq!(intervals.verdict(), IntervalVerdict::TooFewShares);
+    }
+}
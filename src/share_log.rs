@@ -0,0 +1,138 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! The line logged at info for every share found, meant for parsers:
+//!
+//! ```text
+//! share ts=1665000000123 request_id=7 randomness=00000000000004d2 target_prefix=0000ffff elapsed_since_notify_ms=850 session_share_index=3
+//! ```
+//!
+//! The keys are [`SHARE_LOG_KEYS`], in that order, each followed by `=` and a value without
+//! spaces, `-` when it is not known. Keys are only ever added, at the end, and never renamed
+//! or removed. The JSON form has the same keys, with `null` for a value not known.
+
+use serde::{Deserialize, Serialize};
+
+/// The keys of a share line, in the order they are written.
+pub const SHARE_LOG_KEYS: [&str; 6] = [
+    "ts",
+    "request_id",
+    "randomness",
+    "target_prefix",
+    "elapsed_since_notify_ms",
+    "session_share_index",
+];
+
+/// One share found, see the module docs.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShareLog {
+    /// Unix time in milliseconds.
+    pub ts: u64,
+    /// The mining request id of the job.
+    pub request_id: u32,
+    /// 16 lowercase hex digits, as submitted to a pool with the default randomness width.
+    pub randomness: String,
+    /// The first 4 bytes of the job's target in hex, `None` if the job is no longer known.
+    pub target_prefix: Option<String>,
+    /// Time since the job's `mining.notify`, `None` once the miner moved to another job.
+    pub elapsed_since_notify_ms: Option<u64>,
+    /// The share's index in the pool session, from 0, and across sessions while disconnected.
+    pub session_share_index: u64,
+}
+
+impl ShareLog {
+    pub fn new(
+        ts: u64,
+        request_id: u32,
+        randomness: u64,
+        target: Option<&[u8; 32]>,
+        elapsed_since_notify_ms: Option<u64>,
+        session_share_index: u64,
+    ) -> Self {
+        Self {
+            ts,
+            request_id,
+            randomness: format!("{:016x}", randomness),
+            target_prefix: target.map(|target| hex::encode(&target[..4])),
+            elapsed_since_notify_ms,
+            session_share_index,
+        }
+    }
+
+    /// The `key=value` line.
+    pub fn format(&self) -> String {
+        let known = |value: Option<String>| value.unwrap_or_else(|| String::from("-"));
+        let values = [
+            self.ts.to_string(),
+            self.request_id.to_string(),
+            self.randomness.clone(),
+            known(self.target_prefix.clone()),
+            known(self.elapsed_since_notify_ms.map(|ms| ms.to_string())),
+            self.session_share_index.to_string(),
+        ];
+        let pairs: Vec<String> = SHARE_LOG_KEYS
+            .iter()
+            .zip(values)
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect();
+        format!("share {}", pairs.join(" "))
+    }
+
+    /// The JSON object, with the keys of [`ShareLog::format`] in the same order.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn share() -> ShareLog {
+        let mut target = [0xff; 32];
+        target[..2].copy_from_slice(&[0, 0]);
+        ShareLog::new(1665000000123, 7, 1234, Some(&target), Some(850), 3)
+    }
+
+    #[test]
+    fn test_format() {
+        // parsers rely on this line, only ever add keys at the end
+        assert_eq!(
+            share().format(),
+            "share ts=1665000000123 request_id=7 randomness=00000000000004d2 target_prefix=0000ffff elapsed_since_notify_ms=850 session_share_index=3"
+        );
+        let unknown = ShareLog::new(1665000000123, 7, u64::MAX, None, None, 0);
+        assert_eq!(
+            unknown.format(),
+            "share ts=1665000000123 request_id=7 randomness=ffffffffffffffff target_prefix=- elapsed_since_notify_ms=- session_share_index=0"
+        );
+        assert_eq!(
+            unknown.to_json(),
+            r#"{"ts":1665000000123,"request_id":7,"randomness":"ffffffffffffffff","target_prefix":null,"elapsed_since_notify_ms":null,"session_share_index":0}"#
+        );
+    }
+
+    #[test]
+    fn test_json_keys() {
+        let share = share();
+        let line = share.format();
+        let keys: Vec<&str> = line
+            .split(' ')
+            .skip(1)
+            .map(|pair| pair.split('=').next().unwrap())
+            .collect();
+        assert_eq!(keys, SHARE_LOG_KEYS);
+        let json: serde_json::Map<String, serde_json::Value> =
+            serde_json::from_str(&share.to_json()).unwrap();
+        let mut json_keys: Vec<&str> = json.keys().map(String::as_str).collect();
+        let mut sorted = SHARE_LOG_KEYS.to_vec();
+        json_keys.sort_unstable();
+        sorted.sort_unstable();
+        assert_eq!(json_keys, sorted);
+        assert_eq!(
+            serde_json::from_str::<ShareLog>(&share.to_json()).unwrap(),
+            share
+        );
+    }
+}
@@ -0,0 +1,130 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! What the submitted shares are worth. A pool with vardiff pays a share by the difficulty of
+//! the target it was mined against, so once the target changes a count of shares no longer
+//! says how much was contributed. Each share is valued by the target its job was dispatched
+//! with, a target set while the job was mined only applies to the next job. The targets of the
+//! recent jobs are kept here, so that shares of a job the miner already replaced are valued
+//! too; those of a job too old to be remembered take the latest target.
+
+use crate::Target;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+/// Jobs whose target is remembered.
+const MAX_JOBS: usize = 64;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ShareValueStats {
+    /// The session `session_value` belongs to, `None` before the first valued share.
+    pub epoch: Option<u64>,
+    /// The summed difficulty of the shares submitted in the session.
+    pub session_value: f64,
+    pub session_shares: u64,
+    /// The summed difficulty of the shares submitted since the start.
+    pub total_value: f64,
+    pub total_shares: u64,
+}
+
+/// Sums the difficulty of submitted shares, per pool session and since the start.
+#[derive(Clone, Debug, Default)]
+pub struct ShareValue {
+    stats: ShareValueStats,
+    /// The target of each recent job, the latest job last.
+    jobs: VecDeque<(u32, [u8; 32])>,
+}
+
+impl ShareValue {
+    /// The job is mined against `target` from now on.
+    pub fn job_dispatched(&mut self, mining_request_id: u32, target: &[u8; 32]) {
+        self.jobs.retain(|&(job, _)| job != mining_request_id);
+        if self.jobs.len() == MAX_JOBS {
+            self.jobs.pop_front();
+        }
+        self.jobs.push_back((mining_request_id, *target));
+    }
+
+    /// A share of the job in the session `epoch` was submitted, a new session starts over.
+    /// Returns the value of the share.
+    pub fn share_submitted(&mut self, epoch: Option<u64>, mining_request_id: u32) -> f64 {
+        let target = self
+            .jobs
+            .iter()
+            .find(|&&(job, _)| job == mining_request_id)
+            .or_else(|| self.jobs.back())
+            .map(|&(_, target)| target)
+            .unwrap_or(Target::MAX.0);
+        let value = Target(target).difficulty();
+        let stats = &mut self.stats;
+        if stats.epoch != epoch {
+            stats.epoch = epoch;
+            stats.session_value = 0.0;
+            stats.session_shares = 0;
+        }
+        stats.session_value += value;
+        stats.session_shares += 1;
+        stats.total_value += value;
+        stats.total_shares += 1;
+        value
+    }
+
+    pub fn stats(&self) -> ShareValueStats {
+        self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_share_value() {
+        let easiest = Target::MAX.0;
+        let harder = Target::from_difficulty(16.0).unwrap().0;
+        let mut value = ShareValue::default();
+        value.job_dispatched(1, &easiest);
+        value.job_dispatched(2, &harder);
+        assert_eq!(value.share_submitted(Some(1), 1), 1.0);
+        assert_eq!(value.share_submitted(Some(1), 2), 16.0);
+        assert_eq!(
+            value.stats(),
+            ShareValueStats {
+                epoch: Some(1),
+                session_value: 17.0,
+                session_shares: 2,
+                total_value: 17.0,
+                total_shares: 2,
+            }
+        );
+
+        // a new session starts its value over, the total goes on
+        value.share_submitted(Some(2), 2);
+        let stats = value.stats();
+        assert_eq!((stats.epoch, stats.session_value), (Some(2), 16.0));
+        assert_eq!(stats.session_shares, 1);
+        assert_eq!((stats.total_value, stats.total_shares), (33.0, 3));
+    }
+
+    #[test]
+    fn test_replaced_jobs() {
+        let easiest = Target::MAX.0;
+        let harder = Target::from_difficulty(16.0).unwrap().0;
+        let mut value = ShareValue::default();
+        // nothing dispatched yet, the easiest target
+        assert_eq!(value.share_submitted(None, 1), 1.0);
+        value.job_dispatched(1, &easiest);
+        for job in 2..=MAX_JOBS as u32 {
+            value.job_dispatched(job, &harder);
+        }
+        // a replaced job keeps its target while it is remembered
+        assert_eq!(value.share_submitted(None, 1), 1.0);
+        value.job_dispatched(MAX_JOBS as u32 + 1, &harder);
+        assert_eq!(value.share_submitted(None, 1), 16.0);
+        // dispatched again, the job gets the new target
+        value.job_dispatched(2, &easiest);
+        assert_eq!(value.share_submitted(None, 2), 1.0);
+        assert_eq!(value.stats().total_shares, 4);
+    }
+}
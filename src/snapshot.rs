@@ -0,0 +1,249 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! The stats of every miner, assembled once per second into a [`StatsSnapshot`].
+//!
+//! Assembling is the only place that locks the meters and counters. The stats api, the
+//! summary log and the panic report read the latest snapshot from a [`StatsBoard`], which never
+//! waits, so a slow or hammered reader cannot hold up mining.
+
+use crate::{
+    status, Clock, ExitReason, FinalReport, HashrateUnit, HistorySample, HistoryWindow, Meter,
+    Miner, MinerStats, ReportStats, SystemClock,
+};
+use arc_swap::ArcSwap;
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+
+/// How often the snapshot is assembled.
+pub const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(1);
+/// How often the hash rate, share rate and session of every miner are logged.
+pub const SUMMARY_INTERVAL: Duration = Duration::from_secs(100);
+/// The age after which a job is reported stale in the panic report.
+const REPORT_MAX_JOB_AGE: Duration = Duration::from_secs(300);
+
+/// The body of `GET /stats`, also read back by the `status` command.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct StatsSnapshot {
+    /// Unix seconds it was assembled at, 0 before the first one.
+    pub taken_at: u64,
+    pub miners: Vec<MinerStats>,
+    /// The hashrate history of each miner, in the order of `miners`. Served by
+    /// `/stats/history` rather than `/stats`.
+    #[serde(skip)]
+    pub histories: Vec<MinerHistory>,
+    /// How the pushes to `--report-to` went, only present with it.
+    #[serde(default)]
+    pub report: Option<ReportStats>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct MinerHistory {
+    pub second: Vec<HistorySample>,
+    pub minute: Vec<HistorySample>,
+    /// Timestamps of the irregular samples of `second`, see [`crate::Meter::sample`].
+    pub irregular: Vec<u64>,
+}
+
+impl MinerHistory {
+    pub fn window(&self, window: HistoryWindow) -> &[HistorySample] {
+        match window {
+            HistoryWindow::Second => &self.second,
+            HistoryWindow::Minute => &self.minute,
+        }
+    }
+}
+
+impl StatsSnapshot {
+    /// Reads the meters and counters of every miner, the one place that does.
+    pub async fn assemble(miners: &[Arc<Miner>]) -> Self {
+        let mut snapshot = Self {
+            // the miners keep time on the same clock
+            taken_at: miners.first().map_or_else(
+                || SystemClock.unix_timestamp(),
+                |miner| miner.clock().unix_timestamp(),
+            ),
+            miners: Vec::with_capacity(miners.len()),
+            histories: Vec::with_capacity(miners.len()),
+            report: None,
+        };
+        for miner in miners {
+            snapshot.miners.push(miner.stats().await);
+            snapshot.histories.push(MinerHistory {
+                second: miner.history(HistoryWindow::Second).await,
+                minute: miner.history(HistoryWindow::Minute).await,
+                irregular: miner.irregular_samples().await,
+            });
+        }
+        snapshot
+    }
+
+    /// Logs the hash rate, share rate, estimate and session of every miner, hashrates in
+    /// `unit`.
+    pub fn log_summary(&self, unit: HashrateUnit) {
+        for stats in &self.miners {
+            let prefix = if stats.label.is_empty() {
+                String::new()
+            } else {
+                format!("[{}] ", stats.label)
+            };
+            if let Some(hashrate) = stats.meters.get("hashrate") {
+                info!(
+                    "{}Hash Rate: {} ({})",
+                    prefix,
+                    Meter::format_as(hashrate.rate_1s, unit),
+                    Meter::format_rates(&hashrate.rates, unit)
+                );
+            }
+            if let Some(shares) = stats.meters.get("shares") {
+                info!(
+                    "{}Share Rate: {}",
+                    prefix,
+                    Meter::format_per_minute(&shares.rates)
+                );
+            }
+            if let Some(difficulty) = stats.target_history.difficulty {
+                info!("{}Difficulty: {:.0}", prefix, difficulty);
+            }
+            if let Some(estimate) = &stats.estimate {
+                info!("{}{}", prefix, estimate.format());
+            }
+            if let Some(pool_api) = &stats.pool_api {
+                info!("{}{}", prefix, pool_api.format(stats.rate_1h, unit));
+            }
+            if let Some(session) = &stats.session {
+                info!("{}{}", prefix, session.format());
+            }
+        }
+    }
+
+    /// One `status` line per miner, as of when the snapshot was taken.
+    pub fn report(&self) -> Vec<String> {
+        self.miners
+            .iter()
+            .map(|stats| {
+                status::summary(stats, REPORT_MAX_JOB_AGE, self.taken_at, HashrateUnit::Auto)
+            })
+            .collect()
+    }
+}
+
+/// Where the latest [`StatsSnapshot`] is published. Reading it is lock free and never waits
+/// for the next one.
+#[derive(Debug, Default)]
+pub struct StatsBoard {
+    latest: ArcSwap<StatsSnapshot>,
+}
+
+impl StatsBoard {
+    pub fn latest(&self) -> Arc<StatsSnapshot> {
+        self.latest.load_full()
+    }
+
+    pub fn publish(&self, snapshot: StatsSnapshot) {
+        self.latest.store(Arc::new(snapshot));
+    }
+}
+
+static PANIC_REPORT: OnceLock<Arc<StatsBoard>> = OnceLock::new();
+
+/// Makes `board` the one the panic hook reports from. Only the first board of the process is
+/// kept.
+pub fn report_on_panic(board: Arc<StatsBoard>) {
+    let _ = PANIC_REPORT.set(board);
+}
+
+/// The last stats before a panic, nothing if no snapshot was taken yet.
+pub fn panic_report() -> Vec<String> {
+    match PANIC_REPORT.get() {
+        Some(board) => board.latest().report(),
+        None => vec![],
+    }
+}
+
+/// The final report of every miner as of the last snapshot, in case the panic keeps the
+/// process from shutting down cleanly.
+pub fn panic_final_report() -> Vec<String> {
+    match PANIC_REPORT.get() {
+        Some(board) => board
+            .latest()
+            .miners
+            .iter()
+            .map(|stats| FinalReport::new(stats, ExitReason::Panic).format(HashrateUnit::Auto))
+            .collect(),
+        None => vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        sync::atomic::{AtomicBool, Ordering},
+        time::Instant,
+    };
+    use tokio::task;
+
+    const READERS: usize = 16;
+    const MEASURE_FOR: Duration = Duration::from_millis(300);
+
+    /// Meter updates in `MEASURE_FOR`, publishing a snapshot and yielding to the readers every
+    /// 10000 of them.
+    async fn updates(meter: &Meter, board: &StatsBoard) -> u64 {
+        let started = Instant::now();
+        let mut count = 0;
+        while started.elapsed() < MEASURE_FOR {
+            for _ in 0..10_000 {
+                meter.add(1).await;
+            }
+            count += 10_000;
+            board.publish(StatsSnapshot::default());
+            task::yield_now().await;
+        }
+        count
+    }
+
+    #[tokio::test]
+    async fn test_readers_never_block_meter_updates() {
+        let meter = Meter::new();
+        let board = Arc::new(StatsBoard::default());
+        let without_readers = updates(&meter, &board).await;
+
+        let done = Arc::new(AtomicBool::new(false));
+        let readers: Vec<_> = (0..READERS)
+            .map(|_| {
+                let board = board.clone();
+                let done = done.clone();
+                task::spawn(async move {
+                    let mut reads = 0u64;
+                    while !done.load(Ordering::Relaxed) {
+                        // held across the yield, like a slow reader serializing it
+                        let snapshot = board.latest();
+                        task::yield_now().await;
+                        assert!(snapshot.miners.is_empty());
+                        reads += 1;
+                    }
+                    reads
+                })
+            })
+            .collect();
+        let with_readers = updates(&meter, &board).await;
+        done.store(true, Ordering::Relaxed);
+        for reader in readers {
+            assert!(reader.await.unwrap() > 0);
+        }
+
+        assert!(
+            with_readers * 2 >= without_readers,
+            "{} updates with {} readers, {} without",
+            with_readers,
+            READERS,
+            without_readers
+        );
+    }
+}
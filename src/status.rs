@@ -0,0 +1,250 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! The `status` command: asks the stats api of a running miner whether it is healthy, for
+//! scripts and container health checks.
+
+use crate::{ApiSocket, HashrateUnit, Meter, MinerStats, StatsSnapshot, StatusArgs};
+use anyhow::{anyhow, Result};
+use std::{
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::time;
+
+/// Fetches `GET /stats` from the miner at `api`, e.g. "http://127.0.0.1:3030".
+pub async fn fetch_stats(api: &str, timeout: Duration) -> Result<StatsSnapshot> {
+    let api = api.trim_end_matches('/');
+    let url = if api.contains("://") {
+        format!("{}/stats", api)
+    } else {
+        format!("http://{}/stats", api)
+    };
+    let client = reqwest::Client::builder()
+        .connect_timeout(timeout)
+        .timeout(timeout)
+        .build()?;
+    Ok(client
+        .get(&url)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?)
+}
+
+/// Asks for `GET /stats` on the api socket at `path`, see [`crate::api_socket`]. `Ok(None)`
+/// if no miner listens there.
+pub async fn fetch_stats_local(path: &Path, timeout: Duration) -> Result<Option<StatsSnapshot>> {
+    let request = async {
+        let stream = match ApiSocket::connect(path).await {
+            Ok(stream) => stream,
+            Err(_) => return Ok(None),
+        };
+        let body = ApiSocket::request(stream, "GET /stats").await?;
+        Ok(Some(serde_json::from_str(&body)?))
+    };
+    time::timeout(timeout, request)
+        .await
+        .map_err(|_| anyhow!("no answer within {:?}", timeout))?
+}
+
+/// Whether a miner is subscribed and got a job within `max_job_age`, and why not.
+pub fn check(stats: &MinerStats, max_job_age: Duration) -> Result<(), String> {
+    let session = match (&stats.session, stats.subscribed) {
+        (Some(session), true) => session,
+        _ => return Err(String::from("disconnected")),
+    };
+    match session.last_job_age_ms {
+        None => Err(String::from("waiting for first job")),
+        Some(age) if Duration::from_millis(age) > max_job_age => {
+            Err(format!("no job for {}s", age / 1000))
+        }
+        Some(_) => Ok(()),
+    }
+}
+
+/// One line per miner: state, hashrate in `unit`, shares and the time of the last share.
+pub fn summary(stats: &MinerStats, max_job_age: Duration, now: u64, unit: HashrateUnit) -> String {
+    let state = match check(stats, max_job_age) {
+        Ok(()) => String::from("ok"),
+        Err(reason) => reason,
+    };
+    let (found, submitted) = stats
+        .session
+        .as_ref()
+        .map(|session| (session.shares_found, session.shares_submitted))
+        .unwrap_or_default();
+    let last_share = match stats.last_share_at {
+        Some(at) => format!("{}s ago", now.saturating_sub(at)),
+        None => String::from("never"),
+    };
+    let label = match stats.label.as_str() {
+        "" => String::new(),
+        label => format!("[{}] ", label),
+    };
+    format!(
+        "{}{}: {}, shares {} found {} submitted, last share {}",
+        label,
+        state,
+        Meter::format_as(stats.rate_1s, unit),
+        found,
+        submitted,
+        last_share
+    )
+}
+
+/// Runs the `status` command and returns the exit code. Asks the api socket when a miner
+/// listens on it, `--api` otherwise.
+pub async fn run(args: &StatusArgs) -> i32 {
+    let timeout = Duration::from_secs(args.timeout);
+    let response = match fetch_stats_local(&args.api_socket, timeout).await {
+        Ok(Some(response)) => Ok(response),
+        Ok(None) => fetch_stats(&args.api, timeout).await,
+        Err(error) => {
+            println!("unreachable: {}: {}", args.api_socket.display(), error);
+            return 1;
+        }
+    };
+    let response = match response {
+        Ok(response) => response,
+        Err(error) => {
+            println!("unreachable: {}: {}", args.api, error);
+            return 1;
+        }
+    };
+    let max_job_age = Duration::from_secs(args.max_job_age * 60);
+    if args.json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&response).unwrap_or_default()
+        );
+    } else {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or_default();
+        for stats in &response.miners {
+            println!("{}", summary(stats, max_job_age, now, args.hashrate_unit));
+        }
+    }
+    let healthy = !response.miners.is_empty()
+        && response
+            .miners
+            .iter()
+            .all(|stats| check(stats, max_job_age).is_ok());
+    if healthy {
+        0
+    } else {
+        1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        test_util::{spawn_test_pool, test_cli},
+        Api, Cli, Miner, MinerSet,
+    };
+    use std::sync::atomic::Ordering;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_status_command() {
+        let (pool, submits) = spawn_test_pool().await;
+        let api = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let cli = Cli {
+            batch_size: 1000,
+            api: Some(api),
+            ..test_cli(pool)
+        };
+        let set = MinerSet::initialize(cli).await.unwrap();
+        Api::start(api, set.clone()).await.unwrap();
+        let socket_dir =
+            std::env::temp_dir().join(format!("ironminer-status-{}", std::process::id()));
+        let mut args = StatusArgs {
+            api: api.to_string(),
+            api_socket: socket_dir.join("api.sock"),
+            max_job_age: 5,
+            timeout: 5,
+            json: false,
+            hashrate_unit: HashrateUnit::Auto,
+        };
+
+        // up, but not connected to the pool yet
+        let response = fetch_stats(&args.api, Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(
+            check(&response.miners[0], Duration::from_secs(300)),
+            Err(String::from("disconnected"))
+        );
+        assert_eq!(run(&args).await, 1);
+
+        for miner in set.miners() {
+            Miner::launch(miner.clone()).await;
+        }
+        tokio::time::timeout(Duration::from_secs(30), async {
+            while submits.load(Ordering::SeqCst) == 0 {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .expect("the pool should receive shares");
+        // the api serves the snapshot, taken once a second
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while set.snapshot().miners[0].last_share_at.is_none() {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .expect("the snapshot should catch up");
+        // updated with the same snapshot
+        let (code, body) = Api::respond("GET /stats/config HTTP/1.1", &set, true).await;
+        assert_eq!(code, 200);
+        let config: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(config["miners"][0]["active"], true);
+        assert_eq!(config["miners"][0]["pool"], pool.to_string());
+        assert_eq!(config["miners"][0]["batch_size"], 1000);
+        args.api = format!("http://{}/", api);
+        assert_eq!(run(&args).await, 0);
+        let response = fetch_stats(&args.api, Duration::from_secs(5))
+            .await
+            .unwrap();
+        let stats = &response.miners[0];
+        assert_eq!(stats.label, "");
+        assert!(stats.last_share_at.is_some());
+        let summary = summary(
+            stats,
+            Duration::from_secs(300),
+            stats.last_share_at.unwrap(),
+            HashrateUnit::Auto,
+        );
+        assert!(summary.starts_with("ok: "), "{}", summary);
+        assert!(summary.ends_with("last share 0s ago"), "{}", summary);
+        #[cfg(unix)]
+        {
+            // the socket is preferred when a miner listens on it
+            std::fs::create_dir_all(&socket_dir).unwrap();
+            crate::ApiSocket::start(&args.api_socket, 0o600, set.clone())
+                .await
+                .unwrap();
+            args.api = String::from("127.0.0.1:1");
+            assert_eq!(run(&args).await, 0);
+            std::fs::remove_dir_all(&socket_dir).unwrap();
+        }
+        set.stop().await;
+
+        // a miner that does not answer is reported within the timeout
+        let hung = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        args.api = hung.local_addr().unwrap().to_string();
+        args.timeout = 1;
+        let started = std::time::Instant::now();
+        assert_eq!(run(&args).await, 1);
+        assert!(started.elapsed() < Duration::from_secs(3));
+    }
+}
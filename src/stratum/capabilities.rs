@@ -0,0 +1,125 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::MiningSubmitBody;
+
+/// The pool may send its clock with every job, used to warn about clock skew.
+pub const CAPABILITY_TIMESTAMPS: &str = "timestamps";
+
+/// The pool takes `mining.hashrate` reports, offered only with `--hashrate-report-interval`.
+pub const CAPABILITY_HASHRATE: &str = "hashrate";
+
+/// The pool takes the solve time of each share, as `elapsedMs` in the submit.
+pub const CAPABILITY_SOLVE_TIME: &str = "solve_time";
+
+/// Both sides compress the lines after the subscribe ack, offered only with `--compress`, see
+/// [`crate::LineDeflater`].
+pub const CAPABILITY_DEFLATE: &str = "deflate";
+
+/// What this client offers in the subscribe.
+pub const SUPPORTED_CAPABILITIES: [&str; 4] = [
+    CAPABILITY_TIMESTAMPS,
+    CAPABILITY_HASHRATE,
+    CAPABILITY_SOLVE_TIME,
+    CAPABILITY_DEFLATE,
+];
+
+/// What a pool that does not negotiate gets, i.e. the behavior from before the handshake.
+const LEGACY_CAPABILITIES: [&str; 1] = [CAPABILITY_TIMESTAMPS];
+
+/// The optional protocol features in use on a connection, as agreed in the subscribe ack.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    /// `None` if the pool did not negotiate.
+    agreed: Option<Vec<String>>,
+}
+
+impl Capabilities {
+    /// Keeps what the pool will use out of what this client offered. A pool that does not
+    /// list any gets the legacy behavior.
+    pub fn negotiate(pool: Option<Vec<String>>) -> Self {
+        Self {
+            agreed: pool.map(|pool| {
+                pool.into_iter()
+                    .filter(|capability| SUPPORTED_CAPABILITIES.contains(&capability.as_str()))
+                    .collect()
+            }),
+        }
+    }
+
+    pub fn uses(&self, capability: &str) -> bool {
+        match &self.agreed {
+            Some(agreed) => agreed.iter().any(|agreed| agreed == capability),
+            None => LEGACY_CAPABILITIES.contains(&capability),
+        }
+    }
+
+    /// Leaves out of a submit the fields the pool did not agree to, which a strict pool would
+    /// reject the share over.
+    pub fn restrict_submit(&self, body: &mut MiningSubmitBody) {
+        if !self.uses(CAPABILITY_SOLVE_TIME) {
+            body.elapsedMs = None;
+        }
+    }
+
+    /// The agreed capabilities, `None` if the pool did not negotiate.
+    pub fn agreed(&self) -> Option<&[String]> {
+        self.agreed.as_deref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate() {
+        let legacy = Capabilities::negotiate(None);
+        assert!(legacy.uses(CAPABILITY_TIMESTAMPS));
+        assert_eq!(legacy.agreed(), None);
+
+        let agreed = Capabilities::negotiate(Some(vec![
+            String::from("timestamps"),
+            String::from("submit_ack"),
+        ]));
+        assert!(agreed.uses(CAPABILITY_TIMESTAMPS));
+        // never offered, so never used
+        assert!(!agreed.uses("submit_ack"));
+        assert!(!agreed.uses(CAPABILITY_HASHRATE));
+        assert_eq!(agreed.agreed(), Some(&[String::from("timestamps")][..]));
+
+        // never assumed of a pool that does not negotiate
+        assert!(!legacy.uses(CAPABILITY_HASHRATE));
+        assert!(!legacy.uses(CAPABILITY_DEFLATE));
+        let hashrate = Capabilities::negotiate(Some(vec![String::from("hashrate")]));
+        assert!(hashrate.uses(CAPABILITY_HASHRATE));
+
+        let none = Capabilities::negotiate(Some(vec![]));
+        assert!(!none.uses(CAPABILITY_TIMESTAMPS));
+    }
+
+    #[test]
+    fn test_restrict_submit() {
+        let submit = MiningSubmitBody {
+            miningRequestId: 1,
+            randomness: String::from("00000000000004d2"),
+            elapsedMs: Some(1500),
+        };
+        let restricted = |capabilities: &Capabilities| {
+            let mut body = submit.clone();
+            capabilities.restrict_submit(&mut body);
+            body.elapsedMs
+        };
+        let solve_time = Capabilities::negotiate(Some(vec![String::from("solve_time")]));
+        assert_eq!(restricted(&solve_time), Some(1500));
+        // neither a pool that does not negotiate nor one that left it out gets it
+        assert_eq!(restricted(&Capabilities::negotiate(None)), None);
+        assert_eq!(
+            restricted(&Capabilities::negotiate(Some(vec![String::from(
+                "timestamps"
+            )]))),
+            None
+        );
+    }
+}
@@ -0,0 +1,80 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::RollingAverage;
+use std::time::Duration;
+
+const SKEW_SAMPLES: usize = 8;
+
+/// Smoothed offset between the local clock and the pool clock, measured from the optional
+/// timestamps carried by `mining.notify`. Positive values mean the local clock is ahead.
+#[derive(Debug)]
+pub struct ClockSkew {
+    samples: RollingAverage,
+    threshold_ms: f64,
+    exceeded: bool,
+}
+
+impl ClockSkew {
+    pub fn new(threshold: Duration) -> Self {
+        Self {
+            samples: RollingAverage::new(SKEW_SAMPLES),
+            threshold_ms: threshold.as_millis() as f64,
+            exceeded: false,
+        }
+    }
+
+    /// Records one pool timestamp. Returns true when the smoothed skew has just crossed the
+    /// threshold, so the caller warns once instead of on every notify.
+    pub fn observe(&mut self, local_ms: u64, pool_ms: u64) -> bool {
+        self.samples.add(local_ms as f64 - pool_ms as f64);
+        let exceeded = self.samples.average().abs() > self.threshold_ms;
+        let crossed = exceeded && !self.exceeded;
+        self.exceeded = exceeded;
+        crossed
+    }
+
+    /// The smoothed skew in milliseconds, `None` until the pool has sent a timestamp.
+    pub fn skew_ms(&self) -> Option<f64> {
+        if self.samples.is_empty() {
+            None
+        } else {
+            Some(self.samples.average())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_no_timestamps() {
+        let skew = ClockSkew::new(Duration::from_secs(30));
+        assert_eq!(skew.skew_ms(), None);
+    }
+
+    #[test]
+    fn test_skew() {
+        let mut skew = ClockSkew::new(Duration::from_secs(30));
+        assert!(!skew.observe(1_000_000, 1_000_000));
+        assert_eq!(skew.skew_ms(), Some(0.0));
+        for _ in 0..6 {
+            assert!(!skew.observe(1_000_000, 1_000_000));
+        }
+        // one outlier is smoothed away
+        assert!(!skew.observe(2_000_000, 1_900_000));
+        assert_eq!(skew.skew_ms(), Some(12_500.0));
+        // a steady 40s skew crosses the threshold once
+        let crossed = (0..8)
+            .filter(|_| skew.observe(1_040_000, 1_000_000))
+            .count();
+        assert_eq!(crossed, 1);
+        assert_eq!(skew.skew_ms(), Some(40_000.0));
+        // the local clock being behind counts as well
+        let mut skew = ClockSkew::new(Duration::from_secs(30));
+        assert!(skew.observe(1_000_000, 1_031_000));
+        assert_eq!(skew.skew_ms(), Some(-31_000.0));
+    }
+}
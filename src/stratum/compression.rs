@@ -0,0 +1,273 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! Compression of the stratum lines, for links that are paid by the byte. A job is mostly hex,
+//! and every message repeats the same JSON keys, so once both sides agreed to
+//! [`CAPABILITY_DEFLATE`](crate::CAPABILITY_DEFLATE) in the subscribe, each line after the ack
+//! goes out deflated and base64 encoded behind [`COMPRESSED_LINE_PREFIX`]. The deflate window
+//! is kept across the lines of a connection, each line flushed on its own, so later lines
+//! refer back to earlier ones. The lines stay newline delimited, and a plain JSON line is
+//! still read as one, as from a pool that switches a little late.
+
+use crate::{base64, MAX_LINE_LENGTH};
+use anyhow::{anyhow, Result};
+use bytes::{BufMut, BytesMut};
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress};
+use serde::{Deserialize, Serialize};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+/// Starts a compressed line, which a JSON line never does.
+pub const COMPRESSED_LINE_PREFIX: u8 = b'~';
+
+/// Whether `line` is a compressed one.
+pub fn is_compressed(line: &[u8]) -> bool {
+    line.first() == Some(&COMPRESSED_LINE_PREFIX)
+}
+
+/// Bytes of the compressed lines of one connection, as JSON and on the wire, newlines left
+/// out. Shared by its reader and writer.
+#[derive(Debug, Default)]
+pub struct CompressionCounts {
+    json_in: AtomicU64,
+    wire_in: AtomicU64,
+    json_out: AtomicU64,
+    wire_out: AtomicU64,
+}
+
+impl CompressionCounts {
+    pub fn stats(&self) -> CompressionStats {
+        CompressionStats {
+            json_in: self.json_in.load(Ordering::Relaxed),
+            wire_in: self.wire_in.load(Ordering::Relaxed),
+            json_out: self.json_out.load(Ordering::Relaxed),
+            wire_out: self.wire_out.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// What compression saved on a connection.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CompressionStats {
+    /// The compressed lines from the peer, inflated.
+    pub json_in: u64,
+    /// The same lines as they came.
+    pub wire_in: u64,
+    /// The lines to the peer, before deflating.
+    pub json_out: u64,
+    /// The same lines as they went out.
+    pub wire_out: u64,
+}
+
+impl CompressionStats {
+    /// Bytes not sent or received thanks to compression, negative if it cost more than it
+    /// saved.
+    pub fn saved(&self) -> i64 {
+        (self.json_in + self.json_out) as i64 - (self.wire_in + self.wire_out) as i64
+    }
+
+    /// The saved bytes as a percentage of the JSON, 0 before any line.
+    pub fn saved_percent(&self) -> f64 {
+        let json = self.json_in + self.json_out;
+        if json == 0 {
+            return 0.0;
+        }
+        self.saved() as f64 * 100.0 / json as f64
+    }
+}
+
+/// Compresses the lines written to a connection.
+#[derive(Debug)]
+pub struct LineDeflater {
+    deflate: Compress,
+    counts: Arc<CompressionCounts>,
+}
+
+impl LineDeflater {
+    pub fn new(counts: Arc<CompressionCounts>) -> Self {
+        Self {
+            deflate: Compress::new(Compression::default(), false),
+            counts,
+        }
+    }
+
+    /// Appends the compressed `line` to `dst`, with the prefix and without a newline.
+    pub fn deflate(&mut self, line: &[u8], dst: &mut BytesMut) -> Result<()> {
+        let mut deflated = Vec::with_capacity(line.len() / 2 + 64);
+        let start = self.deflate.total_in();
+        loop {
+            let consumed = (self.deflate.total_in() - start) as usize;
+            self.deflate
+                .compress_vec(&line[consumed..], &mut deflated, FlushCompress::Sync)?;
+            // done once the input is in and the flush did not run out of room
+            if (self.deflate.total_in() - start) as usize == line.len()
+                && deflated.len() < deflated.capacity()
+            {
+                break;
+            }
+            deflated.reserve(256);
+        }
+        let encoded = base64(&deflated);
+        dst.put_u8(COMPRESSED_LINE_PREFIX);
+        dst.put_slice(encoded.as_bytes());
+        self.counts
+            .json_out
+            .fetch_add(line.len() as u64, Ordering::Relaxed);
+        self.counts
+            .wire_out
+            .fetch_add(encoded.len() as u64 + 1, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+/// Restores the compressed lines read from a connection. After a failure the window is lost,
+/// and with it every later line.
+#[derive(Debug)]
+pub struct LineInflater {
+    inflate: Decompress,
+    counts: Arc<CompressionCounts>,
+}
+
+impl LineInflater {
+    pub fn new(counts: Arc<CompressionCounts>) -> Self {
+        Self {
+            inflate: Decompress::new(false),
+            counts,
+        }
+    }
+
+    /// The JSON line a compressed `line`, prefix included, stands for. Fails on a line that
+    /// does not inflate, or to more than [`MAX_LINE_LENGTH`].
+    pub fn inflate(&mut self, line: &[u8]) -> Result<Vec<u8>> {
+        let encoded = line
+            .strip_prefix(&[COMPRESSED_LINE_PREFIX])
+            .ok_or_else(|| anyhow!("not a compressed line"))?;
+        let deflated = decode_base64(encoded).ok_or_else(|| anyhow!("not base64"))?;
+        let mut inflated = Vec::with_capacity(deflated.len() * 4);
+        let start = self.inflate.total_in();
+        loop {
+            let consumed = (self.inflate.total_in() - start) as usize;
+            self.inflate.decompress_vec(
+                &deflated[consumed..],
+                &mut inflated,
+                FlushDecompress::Sync,
+            )?;
+            if inflated.len() > MAX_LINE_LENGTH {
+                return Err(anyhow!("inflates to over {} bytes", MAX_LINE_LENGTH));
+            }
+            if (self.inflate.total_in() - start) as usize == deflated.len()
+                && inflated.len() < inflated.capacity()
+            {
+                break;
+            }
+            inflated.reserve(1024);
+        }
+        self.counts
+            .wire_in
+            .fetch_add(line.len() as u64, Ordering::Relaxed);
+        self.counts
+            .json_in
+            .fetch_add(inflated.len() as u64, Ordering::Relaxed);
+        Ok(inflated)
+    }
+}
+
+/// The bytes of padded base64 the way [`base64`] writes it, `None` for anything else.
+fn decode_base64(encoded: &[u8]) -> Option<Vec<u8>> {
+    if !encoded.len().is_multiple_of(4) {
+        return None;
+    }
+    let chunks = encoded.len() / 4;
+    let mut decoded = Vec::with_capacity(chunks * 3);
+    for (index, chunk) in encoded.chunks(4).enumerate() {
+        let mut n = 0u32;
+        let mut padding = 0;
+        for (i, &c) in chunk.iter().enumerate() {
+            let value = match c {
+                b'A'..=b'Z' => c - b'A',
+                b'a'..=b'z' => c - b'a' + 26,
+                b'0'..=b'9' => c - b'0' + 52,
+                b'+' => 62,
+                b'/' => 63,
+                // only at the end, and at most two
+                b'=' if i >= 2 && index == chunks - 1 => {
+                    padding += 1;
+                    0
+                }
+                _ => return None,
+            };
+            if padding > 0 && c != b'=' {
+                return None;
+            }
+            n = n << 6 | value as u32;
+        }
+        decoded.extend_from_slice(&n.to_be_bytes()[1..4 - padding]);
+    }
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOTIFY: &str = r#"{"id":2,"method":"mining.notify","body":{"miningRequestId":0,"header":"0000000000000000677101000000000000000000000232f50bb970eeab81d7e2053ebaa585d9b7297f7d14c2063a60e8509d3e86a44918c8f318377cbb327f4fc5b602e78784994cf2926f0addd55d1b0d36880100000000f1baa930706f8b9058bc55be1f464b472639a288763a16f7a5713aa761052e43f7bec3000000000000000000000c6072a3898d86f685d4b9bba50e87f750f9773da7ac2cf96663e357c8b30082010000000000007735ccc1666978796f750000000000000000000000000000000000000000000000000000"}}"#;
+
+    #[test]
+    fn test_round_trip() {
+        let counts = Arc::new(CompressionCounts::default());
+        let mut deflater = LineDeflater::new(counts.clone());
+        let mut inflater = LineInflater::new(counts.clone());
+        let mut wire = Vec::new();
+        for _ in 0..3 {
+            let mut line = BytesMut::new();
+            deflater.deflate(NOTIFY.as_bytes(), &mut line).unwrap();
+            assert!(is_compressed(&line));
+            assert!(!line.contains(&b'\n'));
+            wire.push(line.len());
+            assert_eq!(inflater.inflate(&line).unwrap(), NOTIFY.as_bytes());
+        }
+        // the repeats refer back to the first
+        assert!(wire[0] < NOTIFY.len());
+        assert!(wire[1] < wire[0] / 4);
+
+        let stats = counts.stats();
+        assert_eq!(stats.json_in, 3 * NOTIFY.len() as u64);
+        assert_eq!(stats.json_out, stats.json_in);
+        assert_eq!(stats.wire_out, wire.iter().sum::<usize>() as u64);
+        assert_eq!(stats.wire_in, stats.wire_out);
+        assert!(stats.saved() > 0);
+        assert!(stats.saved_percent() > 50.0);
+        assert_eq!(CompressionStats::default().saved_percent(), 0.0);
+    }
+
+    #[test]
+    fn test_decode_base64() {
+        let cases: [&[u8]; 6] = [b"", b"a", b"ab", b"abc", b"abcd", &[0xff, 0x00, 0xfe, 0x7f]];
+        for bytes in cases {
+            assert_eq!(decode_base64(base64(bytes).as_bytes()).unwrap(), bytes);
+        }
+        for encoded in ["abc", "ab=c", "a===", "YQ==YQ==", "YW!j"] {
+            assert_eq!(decode_base64(encoded.as_bytes()), None, "{}", encoded);
+        }
+    }
+
+    #[test]
+    fn test_inflate_errors() {
+        let mut inflater = LineInflater::new(Arc::default());
+        assert!(inflater.inflate(NOTIFY.as_bytes()).is_err());
+        // not base64
+        assert!(inflater.inflate(b"~not base64!").is_err());
+
+        // the first line's window is needed for the second
+        let mut deflater = LineDeflater::new(Arc::default());
+        let mut first = BytesMut::new();
+        deflater.deflate(NOTIFY.as_bytes(), &mut first).unwrap();
+        let mut second = BytesMut::new();
+        deflater.deflate(NOTIFY.as_bytes(), &mut second).unwrap();
+        let mut fresh = LineInflater::new(Arc::default());
+        assert!(!matches!(fresh.inflate(&second), Ok(line) if line == NOTIFY.as_bytes()));
+    }
+}
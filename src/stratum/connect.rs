@@ -0,0 +1,166 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use anyhow::{anyhow, Result};
+use log::*;
+use std::{
+    fmt, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+    sync::atomic::{AtomicU16, Ordering},
+};
+use tokio::net::{TcpSocket, TcpStream};
+
+/// An inclusive range of local ports, e.g. "40000-40100".
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl FromStr for PortRange {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (start, end) = s
+            .split_once('-')
+            .ok_or_else(|| anyhow!("expected a port range like 40000-40100, got '{}'", s))?;
+        let start: u16 = start
+            .trim()
+            .parse()
+            .map_err(|_| anyhow!("invalid port '{}'", start))?;
+        let end: u16 = end
+            .trim()
+            .parse()
+            .map_err(|_| anyhow!("invalid port '{}'", end))?;
+        if start == 0 || start > end {
+            return Err(anyhow!("invalid port range {}-{}", start, end));
+        }
+        Ok(Self { start, end })
+    }
+}
+
+impl fmt::Display for PortRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.start, self.end)
+    }
+}
+
+impl PortRange {
+    fn len(&self) -> usize {
+        (self.end - self.start) as usize + 1
+    }
+}
+
+/// Opens pool connections, from a configured range of local ports if there is one.
+///
+/// Each connection starts looking after the port used last, so that a reconnect does not
+/// run into the previous connection still in TIME_WAIT.
+#[derive(Debug)]
+pub struct Connector {
+    source_ports: Option<PortRange>,
+    next: AtomicU16,
+}
+
+impl Connector {
+    pub fn new(source_ports: Option<PortRange>) -> Self {
+        Self {
+            next: AtomicU16::new(source_ports.map(|range| range.start).unwrap_or_default()),
+            source_ports,
+        }
+    }
+
+    pub async fn connect(&self, address: SocketAddr) -> Result<TcpStream> {
+        let range = match self.source_ports {
+            Some(range) => range,
+            None => return Ok(TcpStream::connect(address).await?),
+        };
+        let first = self
+            .next
+            .load(Ordering::Relaxed)
+            .clamp(range.start, range.end);
+        for i in 0..range.len() {
+            let port = range.start + (((first - range.start) as usize + i) % range.len()) as u16;
+            let socket = match Self::bind(address, port) {
+                Ok(socket) => socket,
+                Err(error) if error.kind() == io::ErrorKind::AddrInUse => continue,
+                Err(error) => return Err(error.into()),
+            };
+            match socket.connect(address).await {
+                Ok(stream) => {
+                    info!("Connected to pool({}) from local port {}", address, port);
+                    let next = if port == range.end {
+                        range.start
+                    } else {
+                        port + 1
+                    };
+                    self.next.store(next, Ordering::Relaxed);
+                    return Ok(stream);
+                }
+                // the port is taken towards this pool, by another connection or one in
+                // TIME_WAIT
+                Err(error)
+                    if matches!(
+                        error.kind(),
+                        io::ErrorKind::AddrInUse | io::ErrorKind::AddrNotAvailable
+                    ) =>
+                {
+                    continue
+                }
+                Err(error) => return Err(error.into()),
+            }
+        }
+        Err(anyhow!("no free local port in source port range {}", range))
+    }
+
+    fn bind(address: SocketAddr, port: u16) -> io::Result<TcpSocket> {
+        let (socket, ip) = match address {
+            SocketAddr::V4(_) => (TcpSocket::new_v4()?, IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+            SocketAddr::V6(_) => (TcpSocket::new_v6()?, IpAddr::V6(Ipv6Addr::UNSPECIFIED)),
+        };
+        socket.set_reuseaddr(true)?;
+        socket.bind(SocketAddr::new(ip, port))?;
+        Ok(socket)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_parse_port_range() {
+        assert_eq!(
+            "40000-40100".parse::<PortRange>().unwrap(),
+            PortRange {
+                start: 40000,
+                end: 40100
+            }
+        );
+        assert_eq!("40000-40000".parse::<PortRange>().unwrap().len(), 1);
+        assert!("40100-40000".parse::<PortRange>().is_err());
+        assert!("0-10".parse::<PortRange>().is_err());
+        assert!("40000".parse::<PortRange>().is_err());
+        assert!("40000-70000".parse::<PortRange>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_source_port_range() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let pool = listener.local_addr().unwrap();
+        let range: PortRange = "47311-47312".parse().unwrap();
+
+        let first = Connector::new(Some(range)).connect(pool).await.unwrap();
+        let second = Connector::new(Some(range)).connect(pool).await.unwrap();
+        let first_port = first.local_addr().unwrap().port();
+        let second_port = second.local_addr().unwrap().port();
+        assert_ne!(first_port, second_port);
+        assert!((range.start..=range.end).contains(&first_port));
+        assert!((range.start..=range.end).contains(&second_port));
+
+        let error = Connector::new(Some(range)).connect(pool).await.unwrap_err();
+        assert!(error.to_string().contains("47311-47312"));
+    }
+}
@@ -0,0 +1,351 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! One record per TCP connection to the pool: addresses, TLS, how long the connect and the
+//! handshake took and what went over it. Unlike the pool sessions this covers connections
+//! that never got subscribed, where a flaky network shows first.
+
+use crate::{
+    BoundedQueue, BufferUsage, CompressionCounts, CompressionStats, DisconnectReason,
+    MessageCounts, MAX_CONNECTIONS,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Bytes read from and written to a [`CountingStream`].
+#[derive(Debug, Default)]
+pub struct ByteCounts {
+    read: AtomicU64,
+    written: AtomicU64,
+}
+
+impl ByteCounts {
+    pub fn read(&self) -> u64 {
+        self.read.load(Ordering::Relaxed)
+    }
+
+    pub fn written(&self) -> u64 {
+        self.written.load(Ordering::Relaxed)
+    }
+}
+
+/// A stream that counts the bytes going through it, below TLS when wrapped by it.
+#[derive(Debug)]
+pub struct CountingStream<T> {
+    inner: T,
+    counts: Arc<ByteCounts>,
+}
+
+impl<T> CountingStream<T> {
+    pub fn new(inner: T, counts: Arc<ByteCounts>) -> Self {
+        Self { inner, counts }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for CountingStream<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let read = buf.filled().len() - before;
+        self.counts.read.fetch_add(read as u64, Ordering::Relaxed);
+        poll
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for CountingStream<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = poll {
+            self.counts
+                .written
+                .fetch_add(written as u64, Ordering::Relaxed);
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// One TCP connection to the pool.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ConnectionStats {
+    pub local_addr: Option<String>,
+    pub remote_addr: Option<String>,
+    /// The entry of the pool list connected to, 0 for `--pool`, see [`crate::PoolStats`].
+    #[serde(default)]
+    pub pool_index: usize,
+    pub tls: bool,
+    /// The ALPN protocol agreed in the TLS handshake, if the pool picked one.
+    pub tls_protocol: Option<String>,
+    /// Unix time in seconds.
+    pub connected_at: u64,
+    /// Resolving the pool and the TCP connect, absent for a connection handed over by the
+    /// previous process.
+    pub connect_ms: Option<f64>,
+    /// The TLS handshake, also when it failed.
+    pub handshake_ms: Option<f64>,
+    /// From the end of the handshake until the disconnect, absent while the connection is
+    /// open.
+    pub duration_ms: Option<u64>,
+    /// On the wire, TLS records included.
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    /// Stratum messages, counted once the connection closed.
+    pub frames_in: u64,
+    pub frames_out: u64,
+    /// What compressing the lines saved, absent unless the pool agreed to it.
+    pub compression: Option<CompressionStats>,
+    /// Why it closed, absent while the connection is open.
+    pub disconnect_reason: Option<DisconnectReason>,
+}
+
+impl ConnectionStats {
+    /// A connection that just completed its connect, which took `connect`, at the unix
+    /// timestamp `connected_at`.
+    pub fn new(
+        local_addr: Option<SocketAddr>,
+        remote_addr: Option<SocketAddr>,
+        connect: Option<Duration>,
+        connected_at: u64,
+    ) -> Self {
+        Self {
+            local_addr: local_addr.map(|address| address.to_string()),
+            remote_addr: remote_addr.map(|address| address.to_string()),
+            connected_at,
+            connect_ms: connect.map(as_ms),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_tls(mut self, handshake: Duration, protocol: Option<String>) -> Self {
+        self.tls = true;
+        self.handshake_ms = Some(as_ms(handshake));
+        self.tls_protocol = protocol;
+        self
+    }
+
+    /// One line for the log.
+    pub fn format(&self) -> String {
+        let mut line = format!(
+            "{} -> {}",
+            self.local_addr.as_deref().unwrap_or("?"),
+            self.remote_addr.as_deref().unwrap_or("?")
+        );
+        if let Some(connect_ms) = self.connect_ms {
+            line.push_str(&format!(" connect({:.1}ms)", connect_ms));
+        }
+        if self.tls {
+            line.push_str(&format!(
+                " tls({}, {:.1}ms)",
+                self.tls_protocol.as_deref().unwrap_or("no alpn"),
+                self.handshake_ms.unwrap_or_default()
+            ));
+        }
+        if let Some(duration_ms) = self.duration_ms {
+            line.push_str(&format!(" up({:.1}s)", duration_ms as f64 / 1000.0));
+        }
+        line.push_str(&format!(
+            " bytes({} in, {} out) frames({} in, {} out)",
+            self.bytes_in, self.bytes_out, self.frames_in, self.frames_out
+        ));
+        if let Some(compression) = &self.compression {
+            line.push_str(&format!(
+                " deflate(saved {} bytes, {:.0}%)",
+                compression.saved(),
+                compression.saved_percent()
+            ));
+        }
+        if let Some(reason) = &self.disconnect_reason {
+            line.push_str(&format!(" reason({})", reason));
+        }
+        line
+    }
+}
+
+/// The connection open now and the last closed ones.
+#[derive(Debug)]
+pub struct ConnectionLog {
+    current: Option<(ConnectionStats, Instant, Arc<ByteCounts>)>,
+    /// Of the current connection, once its lines are compressed.
+    compression: Option<Arc<CompressionCounts>>,
+    closed: BoundedQueue<ConnectionStats>,
+}
+
+impl Default for ConnectionLog {
+    fn default() -> Self {
+        Self {
+            current: None,
+            compression: None,
+            closed: BoundedQueue::new(MAX_CONNECTIONS),
+        }
+    }
+}
+
+impl ConnectionLog {
+    /// Starts the record of a new connection, whose bytes are counted in `bytes`.
+    pub fn open(&mut self, connection: ConnectionStats, bytes: Arc<ByteCounts>) {
+        self.current = Some((connection, Instant::now(), bytes));
+        self.compression = None;
+    }
+
+    /// Counts what compression saves on the current connection, from now on.
+    pub fn compressed(&mut self, counts: Arc<CompressionCounts>) {
+        if self.current.is_some() {
+            self.compression = Some(counts);
+        }
+    }
+
+    /// Closes the current connection and returns its record, `None` if there is none.
+    pub fn close(
+        &mut self,
+        reason: DisconnectReason,
+        frames: MessageCounts,
+    ) -> Option<ConnectionStats> {
+        let (mut connection, opened, bytes) = self.current.take()?;
+        connection.duration_ms = Some(opened.elapsed().as_millis() as u64);
+        connection.bytes_in = bytes.read();
+        connection.bytes_out = bytes.written();
+        connection.frames_in = frames.received;
+        connection.frames_out = frames.sent;
+        connection.compression = self.compression.take().map(|counts| counts.stats());
+        connection.disconnect_reason = Some(reason);
+        self.closed.push(connection.clone());
+        Some(connection)
+    }
+
+    /// Oldest first, the open connection last.
+    pub fn list(&self) -> Vec<ConnectionStats> {
+        let mut list: Vec<_> = self.closed.iter().cloned().collect();
+        if let Some((connection, _, bytes)) = &self.current {
+            list.push(ConnectionStats {
+                bytes_in: bytes.read(),
+                bytes_out: bytes.written(),
+                compression: self.compression.as_ref().map(|counts| counts.stats()),
+                ..connection.clone()
+            });
+        }
+        list
+    }
+
+    pub fn usage(&self) -> BufferUsage {
+        self.closed.usage()
+    }
+}
+
+fn as_ms(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_connection_log() {
+        let mut log = ConnectionLog::default();
+        assert!(log
+            .close(DisconnectReason::RemoteClosed, MessageCounts::default())
+            .is_none());
+
+        let bytes = Arc::new(ByteCounts::default());
+        let (client, mut pool) = duplex(64);
+        let mut client = CountingStream::new(client, bytes.clone());
+        let local = "127.0.0.1:40000".parse().ok();
+        log.open(
+            ConnectionStats::new(local, "127.0.0.1:8181".parse().ok(), None, 1_665_000_000),
+            bytes,
+        );
+        client.write_all(b"hello\n").await.unwrap();
+        pool.write_all(b"hi\n").await.unwrap();
+        let mut buf = [0; 3];
+        client.read_exact(&mut buf).await.unwrap();
+        let open = log.list();
+        assert_eq!((open[0].bytes_out, open[0].bytes_in), (6, 3));
+        assert_eq!(open[0].disconnect_reason, None);
+
+        let closed = log
+            .close(
+                DisconnectReason::IdleTimeout,
+                MessageCounts {
+                    received: 1,
+                    sent: 2,
+                },
+            )
+            .unwrap();
+        assert_eq!(
+            closed.disconnect_reason,
+            Some(DisconnectReason::IdleTimeout)
+        );
+        assert_eq!((closed.frames_in, closed.frames_out), (1, 2));
+        assert!(closed.duration_ms.is_some());
+        assert!(closed
+            .format()
+            .starts_with("127.0.0.1:40000 -> 127.0.0.1:8181 up("));
+        assert!(closed
+            .format()
+            .ends_with("bytes(3 in, 6 out) frames(1 in, 2 out) reason(idle_timeout)"));
+        assert_eq!(log.list(), vec![closed]);
+
+        for _ in 0..MAX_CONNECTIONS {
+            log.open(ConnectionStats::default(), Arc::default());
+            log.close(DisconnectReason::RemoteClosed, MessageCounts::default());
+        }
+        assert_eq!(log.list().len(), MAX_CONNECTIONS);
+        assert_eq!(log.usage().dropped, 1);
+
+        // compressed from the subscribe ack on, not carried over to the next connection
+        log.open(ConnectionStats::default(), Arc::default());
+        let counts = Arc::new(CompressionCounts::default());
+        let mut deflater = crate::LineDeflater::new(counts.clone());
+        deflater
+            .deflate(&[b'0'; 400], &mut bytes::BytesMut::new())
+            .unwrap();
+        log.compressed(counts);
+        let compression = log.list().last().unwrap().compression.unwrap();
+        assert_eq!(compression.json_out, 400);
+        let closed = log
+            .close(DisconnectReason::RemoteClosed, MessageCounts::default())
+            .unwrap();
+        assert_eq!(closed.compression, Some(compression));
+        assert!(closed
+            .format()
+            .contains(&format!(" deflate(saved {} bytes, ", compression.saved())));
+        log.open(ConnectionStats::default(), Arc::default());
+        assert_eq!(log.list().last().unwrap().compression, None);
+    }
+}
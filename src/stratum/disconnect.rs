@@ -0,0 +1,371 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::{
+    BoundedQueue, BufferUsage, DecodeError, ProtocolErrors, TooManyProtocolErrors, MAX_DISCONNECTS,
+};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::{fmt, io, str::FromStr, time::Duration};
+
+/// The io error kinds a connection usually fails with, others read back as `Other`.
+const KNOWN_ERROR_KINDS: [io::ErrorKind; 10] = [
+    io::ErrorKind::ConnectionReset,
+    io::ErrorKind::ConnectionAborted,
+    io::ErrorKind::ConnectionRefused,
+    io::ErrorKind::BrokenPipe,
+    io::ErrorKind::NotConnected,
+    io::ErrorKind::TimedOut,
+    io::ErrorKind::UnexpectedEof,
+    io::ErrorKind::InvalidData,
+    io::ErrorKind::Interrupted,
+    io::ErrorKind::Other,
+];
+
+/// Why a pool connection ended.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The pool closed the connection.
+    RemoteClosed,
+    ReadError(io::ErrorKind),
+    WriteError(io::ErrorKind),
+    /// The pool sent a line that is not a stratum message.
+    DecodeError,
+    /// More protocol errors than `--max-protocol-errors-per-min` allows.
+    ProtocolErrors,
+    /// Nothing heard from the pool for too long.
+    IdleTimeout,
+    /// Nothing heard from the pool for `--half-open-timeout` while frames kept going out to
+    /// it, see [`crate::HalfOpenDetector`].
+    HalfOpenSuspected,
+    /// The pool did not acknowledge the subscribe in time.
+    SubscribeTimeout,
+    /// No job arrived in time after the subscribe.
+    FirstJobTimeout,
+    /// A job came with a target older than `--max-target-age`.
+    StaleTarget,
+    /// The job outlived `--job-ttl`, or the expiry the pool sent with it, with no new one.
+    JobExpired,
+    /// The pool kept the miner waiting for work longer than `--reconnect-on-long-wait`.
+    LongWait,
+    StoppedByUser,
+    /// The TLS handshake failed.
+    TlsError,
+    /// The tunnel through `--proxy` closed. Over a tunnel, the pool closing looks the same.
+    ProxyClosed,
+    /// Left open for the process an upgrade execs, see [`crate::handover`].
+    HandedOver,
+}
+
+impl DisconnectReason {
+    /// Tells a line the codec could not decode from a failing read.
+    pub fn from_read_error(error: &anyhow::Error) -> Self {
+        if error.downcast_ref::<DecodeError>().is_some() {
+            return Self::DecodeError;
+        }
+        if error.downcast_ref::<TooManyProtocolErrors>().is_some() {
+            return Self::ProtocolErrors;
+        }
+        Self::ReadError(io_error_kind(error))
+    }
+
+    pub fn from_write_error(error: &anyhow::Error) -> Self {
+        Self::WriteError(io_error_kind(error))
+    }
+
+    /// A stable snake_case name, as used in the stats.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::RemoteClosed => "remote_closed",
+            Self::ReadError(_) => "read_error",
+            Self::WriteError(_) => "write_error",
+            Self::DecodeError => "decode_error",
+            Self::ProtocolErrors => "protocol_errors",
+            Self::IdleTimeout => "idle_timeout",
+            Self::HalfOpenSuspected => "half_open_suspected",
+            Self::SubscribeTimeout => "subscribe_timeout",
+            Self::FirstJobTimeout => "first_job_timeout",
+            Self::StaleTarget => "stale_target",
+            Self::JobExpired => "job_expired",
+            Self::LongWait => "long_wait",
+            Self::StoppedByUser => "stopped_by_user",
+            Self::TlsError => "tls_error",
+            Self::ProxyClosed => "proxy_closed",
+            Self::HandedOver => "handed_over",
+        }
+    }
+
+    fn kind(&self) -> Option<io::ErrorKind> {
+        match self {
+            Self::ReadError(kind) | Self::WriteError(kind) => Some(*kind),
+            _ => None,
+        }
+    }
+}
+
+fn io_error_kind(error: &anyhow::Error) -> io::ErrorKind {
+    error
+        .downcast_ref::<io::Error>()
+        .map(io::Error::kind)
+        .unwrap_or(io::ErrorKind::Other)
+}
+
+impl fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind() {
+            Some(kind) => write!(f, "{} ({})", self.code(), kind),
+            None => write!(f, "{}", self.code()),
+        }
+    }
+}
+
+impl FromStr for DisconnectReason {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (code, kind) = match s.split_once(" (") {
+            Some((code, kind)) => (code, kind.strip_suffix(')')),
+            None => (s, None),
+        };
+        let kind = || {
+            let kind =
+                kind.ok_or_else(|| anyhow!("disconnect reason '{}' has no error kind", s))?;
+            Ok::<_, anyhow::Error>(
+                KNOWN_ERROR_KINDS
+                    .into_iter()
+                    .find(|known| known.to_string() == kind)
+                    .unwrap_or(io::ErrorKind::Other),
+            )
+        };
+        Ok(match code {
+            "remote_closed" => Self::RemoteClosed,
+            "read_error" => Self::ReadError(kind()?),
+            "write_error" => Self::WriteError(kind()?),
+            "decode_error" => Self::DecodeError,
+            "protocol_errors" => Self::ProtocolErrors,
+            "idle_timeout" => Self::IdleTimeout,
+            "half_open_suspected" => Self::HalfOpenSuspected,
+            "subscribe_timeout" => Self::SubscribeTimeout,
+            "first_job_timeout" => Self::FirstJobTimeout,
+            "stale_target" => Self::StaleTarget,
+            "job_expired" => Self::JobExpired,
+            "long_wait" => Self::LongWait,
+            "stopped_by_user" => Self::StoppedByUser,
+            "tls_error" => Self::TlsError,
+            "proxy_closed" => Self::ProxyClosed,
+            "handed_over" => Self::HandedOver,
+            _ => return Err(anyhow!("unknown disconnect reason '{}'", s)),
+        })
+    }
+}
+
+/// Serialized as its code, followed by the io error kind if there is one, e.g.
+/// "read_error (connection reset)".
+impl Serialize for DisconnectReason {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for DisconnectReason {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Messages read from and written to one pool connection.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MessageCounts {
+    pub received: u64,
+    pub sent: u64,
+}
+
+/// The end of one pool connection.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Disconnect {
+    pub reason: DisconnectReason,
+    /// Unix time in seconds.
+    pub at: u64,
+    /// The session that ended, if the subscribe had completed.
+    pub epoch: Option<u64>,
+    /// From the connect until the disconnect.
+    pub duration_ms: u64,
+    pub messages_received: u64,
+    pub messages_sent: u64,
+    /// Lines from the pool that were not usable messages.
+    #[serde(default)]
+    pub protocol_errors: ProtocolErrors,
+}
+
+impl Disconnect {
+    /// A disconnect `at` a unix timestamp.
+    pub fn new(
+        reason: DisconnectReason,
+        epoch: Option<u64>,
+        duration: Duration,
+        counts: MessageCounts,
+        at: u64,
+    ) -> Self {
+        Self {
+            reason,
+            at,
+            epoch,
+            duration_ms: duration.as_millis() as u64,
+            messages_received: counts.received,
+            messages_sent: counts.sent,
+            protocol_errors: ProtocolErrors::default(),
+        }
+    }
+
+    pub fn with_protocol_errors(mut self, protocol_errors: ProtocolErrors) -> Self {
+        self.protocol_errors = protocol_errors;
+        self
+    }
+
+    pub fn format(&self) -> String {
+        let epoch = match self.epoch {
+            Some(epoch) => format!("#{}", epoch),
+            None => String::from("none"),
+        };
+        let mut line = format!(
+            "reason({}) session({}) duration({:.1}s) received({}) sent({})",
+            self.reason,
+            epoch,
+            self.duration_ms as f64 / 1000.0,
+            self.messages_received,
+            self.messages_sent
+        );
+        if self.protocol_errors.total() > 0 {
+            line.push_str(&format!(
+                " protocol errors({})",
+                self.protocol_errors.total()
+            ));
+        }
+        line
+    }
+}
+
+/// The most recent disconnects, oldest first.
+#[derive(Debug)]
+pub struct DisconnectHistory {
+    disconnects: BoundedQueue<Disconnect>,
+}
+
+impl Default for DisconnectHistory {
+    fn default() -> Self {
+        Self {
+            disconnects: BoundedQueue::new(MAX_DISCONNECTS),
+        }
+    }
+}
+
+impl DisconnectHistory {
+    pub fn record(&mut self, disconnect: Disconnect) {
+        self.disconnects.push(disconnect);
+    }
+
+    pub fn list(&self) -> Vec<Disconnect> {
+        self.disconnects.iter().cloned().collect()
+    }
+
+    pub fn usage(&self) -> BufferUsage {
+        self.disconnects.usage()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disconnect_reason() {
+        let reset = anyhow::Error::from(io::Error::from(io::ErrorKind::ConnectionReset));
+        assert_eq!(
+            DisconnectReason::from_read_error(&reset),
+            DisconnectReason::ReadError(io::ErrorKind::ConnectionReset)
+        );
+        assert_eq!(
+            DisconnectReason::from_write_error(&reset),
+            DisconnectReason::WriteError(io::ErrorKind::ConnectionReset)
+        );
+        let decode = anyhow::Error::from(DecodeError {
+            snippet: String::from("not json"),
+            len: 8,
+            kind: crate::ProtocolErrorKind::Decode,
+            error: serde_json::from_str::<serde_json::Value>("not json").err(),
+        });
+        assert_eq!(
+            DisconnectReason::from_read_error(&decode),
+            DisconnectReason::DecodeError
+        );
+        let too_many = anyhow::Error::from(TooManyProtocolErrors { limit: 10 });
+        assert_eq!(
+            DisconnectReason::from_read_error(&too_many),
+            DisconnectReason::ProtocolErrors
+        );
+        assert_eq!(
+            serde_json::to_string(&DisconnectReason::RemoteClosed).unwrap(),
+            "\"remote_closed\""
+        );
+        assert_eq!(
+            serde_json::to_string(&DisconnectReason::WriteError(io::ErrorKind::BrokenPipe))
+                .unwrap(),
+            "\"write_error (broken pipe)\""
+        );
+        for reason in [
+            DisconnectReason::RemoteClosed,
+            DisconnectReason::ReadError(io::ErrorKind::ConnectionReset),
+            DisconnectReason::WriteError(io::ErrorKind::BrokenPipe),
+            DisconnectReason::ProtocolErrors,
+            DisconnectReason::StaleTarget,
+            DisconnectReason::JobExpired,
+            DisconnectReason::LongWait,
+            DisconnectReason::HalfOpenSuspected,
+            DisconnectReason::StoppedByUser,
+            DisconnectReason::ProxyClosed,
+            DisconnectReason::HandedOver,
+        ] {
+            let json = serde_json::to_string(&reason).unwrap();
+            assert_eq!(
+                serde_json::from_str::<DisconnectReason>(&json).unwrap(),
+                reason
+            );
+        }
+        assert_eq!(
+            "read_error (out of memory)"
+                .parse::<DisconnectReason>()
+                .unwrap(),
+            DisconnectReason::ReadError(io::ErrorKind::Other)
+        );
+        assert!("read_error".parse::<DisconnectReason>().is_err());
+        assert!("hung_up".parse::<DisconnectReason>().is_err());
+    }
+
+    #[test]
+    fn test_disconnect_history() {
+        let mut history = DisconnectHistory::default();
+        for epoch in 0..MAX_DISCONNECTS as u64 + 5 {
+            history.record(Disconnect::new(
+                DisconnectReason::IdleTimeout,
+                Some(epoch),
+                Duration::from_millis(1500),
+                MessageCounts {
+                    received: 3,
+                    sent: 2,
+                },
+                1_665_000_000 + epoch,
+            ));
+        }
+        let disconnects = history.list();
+        assert_eq!(disconnects.len(), MAX_DISCONNECTS);
+        assert_eq!(history.usage().dropped, 5);
+        assert_eq!(disconnects[0].epoch, Some(5));
+        assert_eq!(
+            disconnects[0].format(),
+            "reason(idle_timeout) session(#5) duration(1.5s) received(3) sent(2)"
+        );
+    }
+}
@@ -0,0 +1,309 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! Records the lines exchanged with the pool with `--dump-stratum`, for `replay` to play them
+//! back later. A dump starts with a version header, then has one line per stratum line:
+//!
+//! ```text
+//! # zkwork_ironminer stratum dump v1
+//! 0 1 > {"id":0,"method":"mining.subscribe","body":{...}}
+//! 35 1 < {"id":0,"method":"mining.subscribed","body":{...}}
+//! ```
+//!
+//! That is the milliseconds since the dump started, the connection, counted from 1, `<` for
+//! a line from the pool or `>` for one to it, and the line as it went over the wire. Lines the
+//! pool sent that were not messages are recorded too. Other lines starting with `#` are
+//! comments.
+
+use anyhow::{anyhow, Result};
+use log::*;
+use std::{
+    fmt,
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+
+/// The dump format this build writes, and the newest it reads.
+pub const DUMP_VERSION: u32 = 1;
+const DUMP_HEADER: &str = "# zkwork_ironminer stratum dump v";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DumpDirection {
+    FromPool,
+    ToPool,
+}
+
+impl DumpDirection {
+    fn symbol(self) -> &'static str {
+        match self {
+            Self::FromPool => "<",
+            Self::ToPool => ">",
+        }
+    }
+}
+
+/// One stratum line of a dump.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DumpLine {
+    pub at_ms: u64,
+    pub connection: u64,
+    pub direction: DumpDirection,
+    pub line: String,
+}
+
+impl DumpLine {
+    /// The method of the message on the line, see [`line_method`].
+    pub fn method(&self) -> Option<String> {
+        line_method(&self.line)
+    }
+}
+
+/// The method of the stratum message on a line, `None` if it is no message.
+pub fn line_method(line: &str) -> Option<String> {
+    let value: serde_json::Value = serde_json::from_str(line).ok()?;
+    value.get("method")?.as_str().map(String::from)
+}
+
+/// Reads a dump back. Fails on a dump without the header, of a newer version or with a line
+/// that is not in the format.
+pub fn parse_dump(dump: &str) -> Result<Vec<DumpLine>> {
+    let mut lines = dump.lines().enumerate();
+    let version = lines
+        .next()
+        .and_then(|(_, header)| header.strip_prefix(DUMP_HEADER))
+        .and_then(|version| version.trim().parse::<u32>().ok())
+        .ok_or_else(|| {
+            anyhow!(
+                "not a stratum dump, the first line should be '{}1'",
+                DUMP_HEADER
+            )
+        })?;
+    if version > DUMP_VERSION {
+        return Err(anyhow!(
+            "dump version {} is newer than this build reads ({})",
+            version,
+            DUMP_VERSION
+        ));
+    }
+    let mut parsed = vec![];
+    for (index, line) in lines {
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = || anyhow!("line {} of the dump is invalid: {}", index + 1, line);
+        let mut fields = line.splitn(4, ' ');
+        let at_ms = fields
+            .next()
+            .and_then(|at| at.parse().ok())
+            .ok_or_else(invalid)?;
+        let connection = fields
+            .next()
+            .and_then(|connection| connection.parse().ok())
+            .ok_or_else(invalid)?;
+        let direction = match fields.next() {
+            Some("<") => DumpDirection::FromPool,
+            Some(">") => DumpDirection::ToPool,
+            _ => return Err(invalid()),
+        };
+        parsed.push(DumpLine {
+            at_ms,
+            connection,
+            direction,
+            line: fields.next().unwrap_or_default().to_string(),
+        });
+    }
+    Ok(parsed)
+}
+
+/// The dump file `--dump-stratum` writes, shared by every connection of a client.
+pub struct StratumDump {
+    started: Instant,
+    writer: Mutex<BufWriter<File>>,
+    connections: AtomicU64,
+    /// Whether a write failed, logged once.
+    failed: AtomicBool,
+}
+
+impl StratumDump {
+    /// Creates the file, or truncates it, and writes the header.
+    pub fn create(path: &Path) -> Result<Self> {
+        let file = File::create(path)
+            .map_err(|error| anyhow!("failed to create {}: {}", path.display(), error))?;
+        let mut writer = BufWriter::new(file);
+        writeln!(writer, "{}{}", DUMP_HEADER, DUMP_VERSION)?;
+        writer.flush()?;
+        Ok(Self {
+            started: Instant::now(),
+            writer: Mutex::new(writer),
+            connections: AtomicU64::new(0),
+            failed: AtomicBool::new(false),
+        })
+    }
+
+    /// The number of the next connection, from 1.
+    pub fn next_connection(&self) -> u64 {
+        self.connections.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Writes a line, and flushes it so that the dump is complete up to a crash.
+    pub fn record(&self, connection: u64, direction: DumpDirection, line: &[u8]) {
+        let mut writer = self.writer.lock().unwrap();
+        let written = writeln!(
+            writer,
+            "{} {} {} {}",
+            self.started.elapsed().as_millis(),
+            connection,
+            direction.symbol(),
+            String::from_utf8_lossy(line).trim_end_matches('\r')
+        )
+        .and_then(|()| writer.flush());
+        if let Err(error) = written {
+            if !self.failed.swap(true, Ordering::Relaxed) {
+                error!("failed to write the stratum dump: {}", error);
+            }
+        }
+    }
+}
+
+impl fmt::Debug for StratumDump {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StratumDump")
+            .field("connections", &self.connections)
+            .finish()
+    }
+}
+
+/// Where a codec records the lines of one connection.
+#[derive(Clone, Debug)]
+pub struct DumpTap {
+    dump: Arc<StratumDump>,
+    connection: u64,
+}
+
+impl DumpTap {
+    /// Taps the next connection of `dump`.
+    pub fn new(dump: Arc<StratumDump>) -> Self {
+        let connection = dump.next_connection();
+        Self { dump, connection }
+    }
+
+    pub fn record(&self, direction: DumpDirection, line: &[u8]) {
+        self.dump.record(self.connection, direction, line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        test_util::{MockPool, KNOWN_HEADER},
+        MiningSubscribeBody, SessionEvent, StratumSession,
+    };
+    use tokio::io::AsyncWriteExt;
+
+    #[test]
+    fn test_dump() {
+        let path = std::env::temp_dir().join(format!("ironminer-dump-{}", std::process::id()));
+        let dump = Arc::new(StratumDump::create(&path).unwrap());
+        let first = DumpTap::new(dump.clone());
+        first.record(
+            DumpDirection::ToPool,
+            br#"{"id":0,"method":"mining.subscribe"}"#,
+        );
+        first.record(DumpDirection::FromPool, b"not json\r");
+        DumpTap::new(dump).record(DumpDirection::FromPool, br#"{"method":"mining.notify"}"#);
+        let lines = parse_dump(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let summary: Vec<_> = lines
+            .iter()
+            .map(|line| (line.connection, line.direction, line.line.as_str()))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (
+                    1,
+                    DumpDirection::ToPool,
+                    r#"{"id":0,"method":"mining.subscribe"}"#
+                ),
+                (1, DumpDirection::FromPool, "not json"),
+                (2, DumpDirection::FromPool, r#"{"method":"mining.notify"}"#),
+            ]
+        );
+        assert_eq!(lines[0].method().as_deref(), Some("mining.subscribe"));
+        assert_eq!(lines[1].method(), None);
+    }
+
+    #[tokio::test]
+    async fn test_session_dump() {
+        let path = std::env::temp_dir().join(format!("ironminer-session-{}", std::process::id()));
+        let (stream, mut pool) = MockPool::pair();
+        let mut session = StratumSession::new(stream, true);
+        session.record_to(DumpTap::new(Arc::new(StratumDump::create(&path).unwrap())));
+        let body = MiningSubscribeBody {
+            version: 1,
+            name: String::from("rig-7"),
+            publicAddress: Some(String::from("xxxxxx")),
+            agent: None,
+            graffitiSuffix: None,
+            capabilities: vec![],
+        };
+        let (subscribed, _) = tokio::join!(session.subscribe(0, body), pool.accept_subscribe());
+        assert_eq!(subscribed.unwrap().graffiti, "zk.work");
+        pool.writer
+            .get_mut()
+            .write_all(b"not json\n")
+            .await
+            .unwrap();
+        pool.easy_job(1, KNOWN_HEADER).await;
+        // the line that is no message is skipped, and recorded
+        assert!(matches!(
+            session.next_event().await.unwrap(),
+            SessionEvent::NewTarget(_)
+        ));
+        let lines = parse_dump(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let methods: Vec<_> = lines
+            .iter()
+            .map(|line| (line.direction, line.method()))
+            .collect();
+        let method = |method: &str| Some(String::from(method));
+        assert_eq!(
+            methods,
+            [
+                (DumpDirection::ToPool, method("mining.subscribe")),
+                (DumpDirection::FromPool, method("mining.subscribed")),
+                (DumpDirection::FromPool, None),
+                (DumpDirection::FromPool, method("mining.set_target")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_dump() {
+        // a dump without outbound lines, as captured on the pool side, is fine
+        let lines =
+            parse_dump("# zkwork_ironminer stratum dump v1\n\n# comment\n5 1 < {}\n").unwrap();
+        assert_eq!(
+            lines,
+            [DumpLine {
+                at_ms: 5,
+                connection: 1,
+                direction: DumpDirection::FromPool,
+                line: String::from("{}"),
+            }]
+        );
+        assert!(parse_dump("5 1 < {}\n").is_err());
+        assert!(parse_dump("# zkwork_ironminer stratum dump v2\n").is_err());
+        assert!(parse_dump("# zkwork_ironminer stratum dump v1\n5 1 = {}\n").is_err());
+        assert!(parse_dump("# zkwork_ironminer stratum dump v1\nfive 1 < {}\n").is_err());
+    }
+}
@@ -0,0 +1,203 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! A pool behind a flapping load balancer takes the connection and the subscribe, then drops
+//! the socket moments later, over and over. Every connect succeeds, so without telling such a
+//! pool from one that drops now and then the client would reconnect in a tight loop. While a
+//! pool flaps the client keeps backing off further with every drop, see [`crate::Backoff`],
+//! and logs the flapping once instead of every cycle.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/// When a pool counts as flapping.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FlapPolicy {
+    /// A pool flaps once more subscribed sessions than this dropped within `window`.
+    pub threshold: usize,
+    /// It stops flapping once a session stays up this long.
+    pub window: Duration,
+}
+
+impl Default for FlapPolicy {
+    fn default() -> Self {
+        Self {
+            threshold: 5,
+            window: Duration::from_secs(60),
+        }
+    }
+}
+
+/// What changed with the end of a connection, for the client to log.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlapChange {
+    /// `drops` subscribed sessions dropped within the window.
+    Started { drops: usize },
+    /// A session stayed up after `cycles` drops while flapping.
+    Stopped { cycles: u64 },
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FlapStats {
+    pub flapping: bool,
+    /// Subscribed sessions that dropped within the window.
+    pub recent_drops: usize,
+    /// Times the pool started flapping since the start.
+    pub episodes: u64,
+    /// Drops of the current or last time the pool flapped.
+    pub cycles: u64,
+    /// The delay before the next connect, as the client backs off.
+    pub delay_ms: u64,
+}
+
+/// Tells a flapping pool from the ends of its connections.
+#[derive(Clone, Debug)]
+pub struct FlapDetector {
+    policy: FlapPolicy,
+    /// When the recent subscribed sessions dropped, oldest first.
+    drops: VecDeque<Instant>,
+    flapping: bool,
+    episodes: u64,
+    cycles: u64,
+}
+
+impl FlapDetector {
+    pub fn new(policy: FlapPolicy) -> Self {
+        Self {
+            policy,
+            drops: VecDeque::with_capacity(policy.threshold + 1),
+            flapping: false,
+            episodes: 0,
+            cycles: 0,
+        }
+    }
+
+    /// A connection ended at `now`, `subscribed_at` is when it was opened if it got as far as
+    /// the subscribe. A connect that failed leaves everything as it is.
+    pub fn connection_ended(
+        &mut self,
+        now: Instant,
+        subscribed_at: Option<Instant>,
+    ) -> Option<FlapChange> {
+        let subscribed_at = subscribed_at?;
+        if now.saturating_duration_since(subscribed_at) >= self.policy.window {
+            self.drops.clear();
+            if !std::mem::take(&mut self.flapping) {
+                return None;
+            }
+            return Some(FlapChange::Stopped {
+                cycles: self.cycles,
+            });
+        }
+        self.drops.push_back(now);
+        while let Some(oldest) = self.drops.front() {
+            if now.saturating_duration_since(*oldest) < self.policy.window {
+                break;
+            }
+            self.drops.pop_front();
+        }
+        if self.flapping {
+            self.cycles += 1;
+            return None;
+        }
+        if self.drops.len() <= self.policy.threshold {
+            return None;
+        }
+        self.flapping = true;
+        self.episodes += 1;
+        self.cycles = self.drops.len() as u64;
+        Some(FlapChange::Started {
+            drops: self.drops.len(),
+        })
+    }
+
+    pub fn is_flapping(&self) -> bool {
+        self.flapping
+    }
+
+    /// Without the delay, which the client knows.
+    pub fn stats(&self) -> FlapStats {
+        FlapStats {
+            flapping: self.flapping,
+            recent_drops: self.drops.len(),
+            episodes: self.episodes,
+            cycles: self.cycles,
+            delay_ms: 0,
+        }
+    }
+}
+
+impl Default for FlapDetector {
+    fn default() -> Self {
+        Self::new(FlapPolicy::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flapping() {
+        let start = Instant::now();
+        let mut detector = FlapDetector::default();
+        let second = |secs: u64| start + Duration::from_secs(secs);
+        // failed connects are not drops
+        assert_eq!(detector.connection_ended(second(1), None), None);
+        // five quick drops are tolerated, the sixth within a minute is flapping
+        for secs in 0..5 {
+            let now = second(secs * 3);
+            assert_eq!(detector.connection_ended(now, Some(now)), None);
+            assert!(!detector.is_flapping());
+        }
+        assert_eq!(
+            detector.connection_ended(second(15), Some(second(15))),
+            Some(FlapChange::Started { drops: 6 })
+        );
+        assert!(detector.is_flapping());
+
+        // every further drop is a cycle, still flapping although the client backing off
+        // leaves fewer drops per minute
+        let mut now = second(15);
+        for delay in [8, 16, 32, 64, 120, 120, 120, 120] {
+            now += Duration::from_secs(delay);
+            assert_eq!(detector.connection_ended(now, Some(now)), None);
+        }
+        assert!(detector.stats().recent_drops < 6);
+        assert!(detector.is_flapping());
+        assert_eq!(detector.stats().cycles, 14);
+
+        // a failed connect in between changes nothing
+        assert_eq!(detector.connection_ended(now, None), None);
+        assert!(detector.is_flapping());
+
+        // a session that stays up for the window ends it
+        let subscribed_at = now;
+        now += Duration::from_secs(60);
+        assert_eq!(
+            detector.connection_ended(now, Some(subscribed_at)),
+            Some(FlapChange::Stopped { cycles: 14 })
+        );
+        let stats = detector.stats();
+        assert!(!stats.flapping);
+        assert_eq!(stats.episodes, 1);
+        assert_eq!(stats.recent_drops, 0);
+    }
+
+    #[test]
+    fn test_occasional_drops_do_not_flap() {
+        let start = Instant::now();
+        let mut detector = FlapDetector::default();
+        // a drop every 15s after a short session never makes six within a minute
+        for i in 0..20 {
+            let now = start + Duration::from_secs(i * 15);
+            assert_eq!(detector.connection_ended(now, Some(now)), None);
+        }
+        assert!(!detector.is_flapping());
+        assert_eq!(detector.stats().episodes, 0);
+    }
+}
@@ -0,0 +1,137 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! A firewall that drops what the pool sends leaves the connection half open: every write
+//! succeeds, so the submits look sent, but nothing comes back and the read never fails. Only
+//! the idle timeout would end such a session, after every share of the last ten minutes was
+//! lost. The detector tells it apart sooner: nothing came in for `--half-open-timeout` while
+//! frames kept going out to the pool.
+//!
+//! Iron Fish pools do not answer submits, so a share that was never acknowledged says nothing
+//! on its own. What the detector counts instead are the frames sent since the last one came
+//! in, which a pool that is merely slow between blocks also answers with its next job.
+
+use crate::MessageCounts;
+use std::time::{Duration, Instant};
+
+/// Frames sent since the last one received before the silence counts as half open.
+pub const HALF_OPEN_MIN_SENT: u64 = 2;
+
+/// See the module docs.
+#[derive(Clone, Debug)]
+pub struct HalfOpenDetector {
+    /// Zero to never suspect a session.
+    window: Duration,
+    counts: MessageCounts,
+    /// When the last frame came in, or the session started.
+    last_received: Instant,
+    /// Frames sent since then.
+    sent: u64,
+    last_sent: Option<Instant>,
+}
+
+impl HalfOpenDetector {
+    pub fn new(window: Duration, counts: MessageCounts, now: Instant) -> Self {
+        Self {
+            window,
+            counts,
+            last_received: now,
+            sent: 0,
+            last_sent: None,
+        }
+    }
+
+    /// Takes the frame counts of the session as of `now`. Frames received at the same time as
+    /// others were sent count as coming first.
+    pub fn observe(&mut self, counts: MessageCounts, now: Instant) {
+        if counts.received > self.counts.received {
+            self.last_received = now;
+            self.sent = 0;
+            self.last_sent = None;
+        }
+        if counts.sent > self.counts.sent {
+            self.sent += counts.sent - self.counts.sent;
+            self.last_sent = Some(now);
+        }
+        self.counts = counts;
+    }
+
+    /// Whether nothing came in for the window while frames kept going out, the last of them
+    /// within the window.
+    pub fn suspected(&self, now: Instant) -> bool {
+        !self.window.is_zero()
+            && self.sent >= HALF_OPEN_MIN_SENT
+            && now.saturating_duration_since(self.last_received) >= self.window
+            && self
+                .last_sent
+                .map(|at| now.saturating_duration_since(at) < self.window)
+                .unwrap_or_default()
+    }
+
+    /// When the session is to be checked again, if only time passing can make it suspected.
+    /// A frame sent later is checked for as it is observed.
+    pub fn next_check(&self, now: Instant) -> Option<Instant> {
+        if self.window.is_zero() || self.sent < HALF_OPEN_MIN_SENT {
+            return None;
+        }
+        Some(self.last_received + self.window).filter(|deadline| *deadline > now)
+    }
+
+    /// How long nothing has come in.
+    pub fn silent_for(&self, now: Instant) -> Duration {
+        now.saturating_duration_since(self.last_received)
+    }
+
+    /// Frames sent since the last one came in.
+    pub fn unanswered(&self) -> u64 {
+        self.sent
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn counts(received: u64, sent: u64) -> MessageCounts {
+        MessageCounts { received, sent }
+    }
+
+    #[test]
+    fn test_half_open_detector() {
+        let window = Duration::from_secs(60);
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut detector = HalfOpenDetector::new(window, counts(3, 2), start);
+        // silence alone is a pool between blocks
+        assert!(!detector.suspected(at(90)));
+        assert_eq!(detector.next_check(at(90)), None);
+
+        // submits that go unanswered
+        detector.observe(counts(3, 3), at(10));
+        assert_eq!(detector.next_check(at(10)), None);
+        detector.observe(counts(3, 4), at(20));
+        assert_eq!(detector.unanswered(), 2);
+        assert_eq!(detector.next_check(at(20)), Some(at(60)));
+        assert!(!detector.suspected(at(59)));
+        assert!(detector.suspected(at(60)));
+        assert_eq!(detector.silent_for(at(60)), window);
+        // the last frame went out too long ago for it to still be flowing
+        assert!(!detector.suspected(at(80)));
+        assert_eq!(detector.next_check(at(80)), None);
+        // until the next one
+        detector.observe(counts(3, 5), at(85));
+        assert!(detector.suspected(at(85)));
+
+        // anything from the pool starts over
+        detector.observe(counts(4, 6), at(90));
+        assert_eq!(detector.unanswered(), 1);
+        assert!(!detector.suspected(at(200)));
+
+        // disabled
+        let mut detector = HalfOpenDetector::new(Duration::ZERO, counts(0, 0), start);
+        detector.observe(counts(0, 10), at(100));
+        assert!(!detector.suspected(at(100)));
+        assert_eq!(detector.next_check(at(100)), None);
+    }
+}
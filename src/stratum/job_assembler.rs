@@ -0,0 +1,299 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+/// A job with the target it is to be mined against, handed on as one.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AssembledJob {
+    pub mining_request_id: u32,
+    pub header: String,
+    /// Hex, as the pool sent it.
+    pub target: String,
+}
+
+/// Pairs each job with the last target the pool sent before it, so that a target change
+/// right before or after a job cannot end up applied to the wrong one. Pools send targets,
+/// jobs and the graffiti in any order, some even ahead of the subscribe ack, so a job is held
+/// back until a target and the graffiti are known.
+#[derive(Debug, Default)]
+pub struct JobAssembler {
+    /// How many of the jobs handed on are mined at once, see `--max-concurrent-jobs`. 0 is 1.
+    concurrent_jobs: usize,
+    target: Option<String>,
+    graffiti: bool,
+    /// A job held back, with the target it is paired with once there is one.
+    held: Option<(u32, String, Option<String>)>,
+    /// The jobs handed on that are still mined, the last one last.
+    mined: VecDeque<AssembledJob>,
+}
+
+impl JobAssembler {
+    /// An assembler for a miner that mines up to `concurrent_jobs` jobs at once.
+    pub fn with_concurrent_jobs(concurrent_jobs: usize) -> Self {
+        Self {
+            concurrent_jobs,
+            ..Default::default()
+        }
+    }
+
+    /// A new target, for the jobs after it. Returns a held job if it can start now: a job
+    /// that came before any target is mined against the first one after it.
+    pub fn set_target(&mut self, target: String) -> Option<AssembledJob> {
+        if let Some((mining_request_id, _, held_target @ None)) = &mut self.held {
+            debug!(
+                "job of mining request id({}) came before any target, pairing it with target({})",
+                mining_request_id, target
+            );
+            *held_target = Some(target.clone());
+        }
+        self.target = Some(target);
+        self.release()
+    }
+
+    /// The graffiti is known, returns the held job if it can start now.
+    pub fn set_graffiti(&mut self) -> Option<AssembledJob> {
+        self.graffiti = true;
+        self.release()
+    }
+
+    /// Whether a notify only repeats a job being mined, as pools that re-send it as a
+    /// keepalive do. A new target since makes it a new job, to be mined against that target.
+    pub fn repeats_active(&self, mining_request_id: u32, header: &str) -> bool {
+        self.held.is_none()
+            && self.mined.iter().any(|active| {
+                active.mining_request_id == mining_request_id
+                    && active.header == header
+                    && Some(&active.target) == self.target.as_ref()
+            })
+    }
+
+    /// Returns the job with its target if it can start now, holds it back otherwise. A newer
+    /// job replaces the one held.
+    pub fn notify(&mut self, mining_request_id: u32, header: String) -> Option<AssembledJob> {
+        for active in &self.mined {
+            if active.mining_request_id == mining_request_id && active.header != header {
+                warn!(
+                    "pool sent mining request id({}) again with a different header, mining the new one",
+                    mining_request_id
+                );
+            }
+        }
+        if let Some((held, _, _)) = &self.held {
+            debug!(
+                "job of mining request id({}) replaces the held one of mining request id({})",
+                mining_request_id, held
+            );
+        }
+        self.held = Some((mining_request_id, header, self.target.clone()));
+        self.release()
+    }
+
+    /// A job held back by a user pause, paired with the latest target now that it is mined.
+    pub fn resume(&mut self, job: AssembledJob) -> AssembledJob {
+        let job = AssembledJob {
+            target: self.target.clone().unwrap_or(job.target),
+            ..job
+        };
+        self.hand_on(job.clone());
+        job
+    }
+
+    /// The last target.
+    pub fn target(&self) -> Option<&str> {
+        self.target.as_deref()
+    }
+
+    /// The last job handed on.
+    pub fn active(&self) -> Option<&AssembledJob> {
+        self.mined.back()
+    }
+
+    /// What a held job waits for, e.g. for the log.
+    pub fn missing(&self) -> &'static str {
+        match (self.target.is_some(), self.graffiti) {
+            (false, false) => "target and graffiti",
+            (false, true) => "target",
+            (true, false) => "graffiti",
+            (true, true) => "nothing",
+        }
+    }
+
+    fn release(&mut self) -> Option<AssembledJob> {
+        match self.held.take() {
+            Some((mining_request_id, header, Some(target))) if self.graffiti => {
+                let job = AssembledJob {
+                    mining_request_id,
+                    header,
+                    target,
+                };
+                self.hand_on(job.clone());
+                Some(job)
+            }
+            held => {
+                self.held = held;
+                None
+            }
+        }
+    }
+
+    /// A job handed on takes the place of the one with its id, or of the oldest one once
+    /// there are as many as are mined at once.
+    fn hand_on(&mut self, job: AssembledJob) {
+        self.mined
+            .retain(|active| active.mining_request_id != job.mining_request_id);
+        self.mined.push_back(job);
+        while self.mined.len() > self.concurrent_jobs.max(1) {
+            self.mined.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Copy, Debug)]
+    enum Arrival {
+        Target(&'static str),
+        Graffiti,
+        Notify(u32),
+    }
+
+    /// Feeds the arrivals and returns the jobs that started with their targets.
+    fn started(arrivals: &[Arrival]) -> Vec<(u32, &'static str)> {
+        let mut assembler = JobAssembler::default();
+        arrivals
+            .iter()
+            .filter_map(|arrival| match arrival {
+                Arrival::Target(target) => assembler.set_target(target.to_string()),
+                Arrival::Graffiti => assembler.set_graffiti(),
+                Arrival::Notify(id) => assembler.notify(*id, String::from("00")),
+            })
+            .map(|job| {
+                let target = ["t1", "t2"]
+                    .into_iter()
+                    .find(|target| *target == job.target)
+                    .unwrap();
+                (job.mining_request_id, target)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_arrival_orders() {
+        use Arrival::*;
+        let orders = [
+            [Target("t1"), Graffiti, Notify(1)],
+            [Target("t1"), Notify(1), Graffiti],
+            [Graffiti, Target("t1"), Notify(1)],
+            [Graffiti, Notify(1), Target("t1")],
+            [Notify(1), Target("t1"), Graffiti],
+            [Notify(1), Graffiti, Target("t1")],
+        ];
+        for order in orders {
+            assert_eq!(started(&order), vec![(1, "t1")], "{:?}", order);
+        }
+        // only the newest job held starts, and nothing twice
+        assert_eq!(
+            started(&[
+                Notify(1),
+                Target("t1"),
+                Notify(2),
+                Graffiti,
+                Target("t1"),
+                Graffiti
+            ]),
+            vec![(2, "t1")]
+        );
+
+        let mut assembler = JobAssembler::default();
+        assert_eq!(assembler.missing(), "target and graffiti");
+        assembler.set_graffiti();
+        assert_eq!(assembler.missing(), "target");
+    }
+
+    #[test]
+    fn test_target_pairing() {
+        use Arrival::*;
+        // T,N and N,T
+        assert_eq!(
+            started(&[Graffiti, Target("t1"), Notify(1)]),
+            vec![(1, "t1")]
+        );
+        assert_eq!(
+            started(&[Graffiti, Notify(1), Target("t1")]),
+            vec![(1, "t1")]
+        );
+        // T1,N1,T2,N2: a new target is for the jobs after it
+        assert_eq!(
+            started(&[Graffiti, Target("t1"), Notify(1), Target("t2"), Notify(2)]),
+            vec![(1, "t1"), (2, "t2")]
+        );
+        // T1,N1,N2: jobs keep the target until a new one comes
+        assert_eq!(
+            started(&[Graffiti, Target("t1"), Notify(1), Notify(2)]),
+            vec![(1, "t1"), (2, "t1")]
+        );
+        // held for the graffiti, a job keeps the target it came after
+        assert_eq!(
+            started(&[Target("t1"), Notify(1), Target("t2"), Graffiti]),
+            vec![(1, "t1")]
+        );
+    }
+
+    #[test]
+    fn test_repeated_notify() {
+        let mut assembler = JobAssembler::default();
+        assembler.set_graffiti();
+        assembler.set_target(String::from("t1"));
+        assert!(!assembler.repeats_active(1, "h1"));
+        assert!(assembler.notify(1, String::from("h1")).is_some());
+        // the same id and header again is ignored
+        assert!(assembler.repeats_active(1, "h1"));
+        // the same id with a new header is new work
+        assert!(!assembler.repeats_active(1, "h2"));
+        let job = assembler.notify(1, String::from("h2")).unwrap();
+        assert_eq!(job.header, "h2");
+        assert!(assembler.repeats_active(1, "h2"));
+        assert!(!assembler.repeats_active(1, "h1"));
+        // a new id always is
+        assert!(!assembler.repeats_active(2, "h2"));
+        assert_eq!(
+            assembler
+                .notify(2, String::from("h2"))
+                .unwrap()
+                .mining_request_id,
+            2
+        );
+        // a new target makes the repeated job new work against it
+        assembler.set_target(String::from("t2"));
+        assert!(!assembler.repeats_active(2, "h2"));
+        assert_eq!(
+            assembler.notify(2, String::from("h2")).unwrap().target,
+            "t2"
+        );
+        assert!(assembler.repeats_active(2, "h2"));
+    }
+
+    #[test]
+    fn test_repeated_concurrent_notify() {
+        let mut assembler = JobAssembler::with_concurrent_jobs(2);
+        assembler.set_graffiti();
+        assembler.set_target(String::from("t1"));
+        assembler.notify(1, String::from("h1")).unwrap();
+        assembler.notify(2, String::from("h2")).unwrap();
+        // either job repeated is ignored, not only the last one
+        assert!(assembler.repeats_active(1, "h1"));
+        assert!(assembler.repeats_active(2, "h2"));
+        assert_eq!(assembler.active().unwrap().mining_request_id, 2);
+        // a third job takes the place of the oldest
+        assembler.notify(3, String::from("h3")).unwrap();
+        assert!(!assembler.repeats_active(1, "h1"));
+        assert!(assembler.repeats_active(2, "h2"));
+        assert!(assembler.repeats_active(3, "h3"));
+    }
+}
@@ -2,23 +2,149 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 
+use crate::{
+    is_compressed, CompressionCounts, DumpDirection, DumpTap, LineDeflater, LineInflater,
+    ProtocolErrorKind, ProtocolErrorLimit, ProtocolErrors,
+};
 use anyhow::Result;
-use std::io::Write;
+use log::*;
+use std::{fmt, io::Write, sync::Arc, time::Instant};
 
 use bytes::{BufMut, BytesMut};
-use serde::{Deserialize, Serialize};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 use tokio_util::codec::{Decoder, Encoder};
 
+/// A message id the way the peer sent it. Most pools send integers, some send numeric strings
+/// ("17"), or null on notifications. An id is written back as it came, ids this miner assigns
+/// are integers. Ids compare by their value, so "17" equals 17.
+#[derive(Clone, Debug)]
+pub enum MessageId {
+    Number(i64),
+    /// A numeric string, kept as sent.
+    String(String),
+    Null,
+}
+
+impl MessageId {
+    /// The id as a number, `None` for null.
+    pub fn value(&self) -> Option<i64> {
+        match self {
+            MessageId::Number(id) => Some(*id),
+            MessageId::String(id) => id.parse().ok(),
+            MessageId::Null => None,
+        }
+    }
+}
+
+impl From<i64> for MessageId {
+    fn from(id: i64) -> Self {
+        MessageId::Number(id)
+    }
+}
+
+impl PartialEq for MessageId {
+    fn eq(&self, other: &Self) -> bool {
+        self.value() == other.value()
+    }
+}
+
+impl Eq for MessageId {}
+
+impl fmt::Display for MessageId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MessageId::Number(id) => write!(f, "{}", id),
+            MessageId::String(id) => write!(f, "{}", id),
+            MessageId::Null => write!(f, "null"),
+        }
+    }
+}
+
+impl Serialize for MessageId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            MessageId::Number(id) => serializer.serialize_i64(*id),
+            MessageId::String(id) => serializer.serialize_str(id),
+            MessageId::Null => serializer.serialize_unit(),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for MessageId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct MessageIdVisitor;
+
+        impl<'de> de::Visitor<'de> for MessageIdVisitor {
+            type Value = MessageId;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("an integer, a numeric string or null")
+            }
+
+            fn visit_i64<E: de::Error>(self, id: i64) -> Result<MessageId, E> {
+                Ok(MessageId::Number(id))
+            }
+
+            fn visit_u64<E: de::Error>(self, id: u64) -> Result<MessageId, E> {
+                i64::try_from(id)
+                    .map(MessageId::Number)
+                    .map_err(|_| E::invalid_value(de::Unexpected::Unsigned(id), &self))
+            }
+
+            fn visit_str<E: de::Error>(self, id: &str) -> Result<MessageId, E> {
+                match id.parse::<i64>() {
+                    Ok(_) => Ok(MessageId::String(id.to_string())),
+                    Err(_) => Err(E::invalid_value(de::Unexpected::Str(id), &self)),
+                }
+            }
+
+            fn visit_unit<E: de::Error>(self) -> Result<MessageId, E> {
+                Ok(MessageId::Null)
+            }
+
+            fn visit_none<E: de::Error>(self) -> Result<MessageId, E> {
+                Ok(MessageId::Null)
+            }
+        }
+
+        deserializer.deserialize_any(MessageIdVisitor)
+    }
+}
+
 #[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
 #[allow(non_snake_case)]
 pub struct MiningSubscribeBody {
     pub version: i64,
     pub name: String,
-    pub publicAddress: String,
+    /// The reward address, left out of the JSON for pools that identify workers by the token
+    /// of `mining.authorize` instead.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub publicAddress: Option<String>,
+    /// Which miner build is connecting, see [`user_agent`]. Left out of the JSON when not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub agent: Option<String>,
+    /// What the miner writes after the pool's graffiti, see `--graffiti-suffix`. Left out of
+    /// the JSON when not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub graffitiSuffix: Option<String>,
+    /// Optional protocol features this client supports, left out of the JSON when empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub capabilities: Vec<String>,
+}
+
+/// Identifies this miner to the pool, e.g. "zkwork_ironminer/0.1.3 (linux; x86_64)".
+pub fn user_agent() -> String {
+    format!(
+        "{}/{} ({}; {})",
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION"),
+        std::env::consts::OS,
+        std::env::consts::ARCH
+    )
 }
 #[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
 pub struct MiningSubscribeMessage {
-    pub id: i64,
+    pub id: MessageId,
     pub method: String,
     pub body: MiningSubscribeBody,
 }
@@ -28,11 +154,15 @@ pub struct MiningSubscribeMessage {
 pub struct MiningSubscribedBody {
     pub clientId: u64,
     pub graffiti: String,
+    /// The offered capabilities the pool will use. A pool that leaves it out negotiates
+    /// nothing, see [`Capabilities`](crate::Capabilities).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub capabilities: Option<Vec<String>>,
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
 pub struct MiningSubscribedMessage {
-    pub id: i64,
+    pub id: MessageId,
     pub method: String,
     pub body: MiningSubscribedBody,
 }
@@ -44,7 +174,7 @@ pub struct MiningSetTargetBody {
 
 #[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
 pub struct MiningSetTargetMessage {
-    pub id: i64,
+    pub id: MessageId,
     pub method: String,
     pub body: MiningSetTargetBody,
 }
@@ -54,11 +184,17 @@ pub struct MiningSetTargetMessage {
 pub struct MiningNotifyBody {
     pub miningRequestId: u32,
     pub header: String,
+    /// Pool time in milliseconds since the unix epoch, not sent by every pool.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
+    /// Milliseconds after which the job is not worth mining any more, not sent by every pool.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expiresInMs: Option<u64>,
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
 pub struct MiningNotifyMessage {
-    pub id: i64,
+    pub id: MessageId,
     pub method: String,
     pub body: MiningNotifyBody,
 }
@@ -68,18 +204,70 @@ pub struct MiningNotifyMessage {
 pub struct MiningSubmitBody {
     pub miningRequestId: u32,
     pub randomness: String,
+    /// Milliseconds from the dispatch of the job to the share being found, only for pools
+    /// that agreed to the `solve_time` capability.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub elapsedMs: Option<u64>,
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
 pub struct MiningSubmitMessage {
-    pub id: i64,
+    pub id: MessageId,
     pub method: String,
     pub body: MiningSubmitBody,
 }
 
+/// The pool's answer to a submit, from pools that send one: whether the share was accepted,
+/// and why not.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct MiningSubmittedBody {
+    /// The id of the submit answered.
+    pub id: MessageId,
+    pub result: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct MiningSubmittedMessage {
+    pub id: MessageId,
+    pub method: String,
+    pub body: MiningSubmittedBody,
+}
+
+/// The miner's hashrate, sent only to pools that agreed to the `hashrate` capability.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct MiningHashrateBody {
+    /// Hashes per second over the last minute.
+    pub hashrate: f64,
+    /// The worker name.
+    pub name: String,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct MiningHashrateMessage {
+    pub id: MessageId,
+    pub method: String,
+    pub body: MiningHashrateBody,
+}
+
+/// Identifies the worker by a token, sent after the subscribe to pools that authenticate
+/// that way, see `--pass`.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct MiningAuthorizeBody {
+    pub token: String,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct MiningAuthorizeMessage {
+    pub id: MessageId,
+    pub method: String,
+    pub body: MiningAuthorizeBody,
+}
+
 #[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
 pub struct MiningWaitForWorkMessage {
-    pub id: i64,
+    pub id: MessageId,
     pub method: String,
 }
 
@@ -91,11 +279,209 @@ pub enum StratumMessage {
     MiningSetTargetMessage(MiningSetTargetMessage),
     MiningNotifyMessage(MiningNotifyMessage),
     MiningSubmitMessage(MiningSubmitMessage),
+    MiningHashrateMessage(MiningHashrateMessage),
+    MiningAuthorizeMessage(MiningAuthorizeMessage),
+    MiningSubmittedMessage(MiningSubmittedMessage),
     MiningWaitForWorkMessage(MiningWaitForWorkMessage),
 }
+impl StratumMessage {
+    /// Whether the method is the one the message's shape is for. Messages are told apart by
+    /// their shape only, e.g. any method without a body reads as a wait for work.
+    pub fn has_known_method(&self) -> bool {
+        let (method, known) = match self {
+            StratumMessage::MiningSubscribeMessage(message) => {
+                (&message.method, "mining.subscribe")
+            }
+            StratumMessage::MiningSubscribedMessage(message) => {
+                (&message.method, "mining.subscribed")
+            }
+            StratumMessage::MiningSetTargetMessage(message) => {
+                (&message.method, "mining.set_target")
+            }
+            StratumMessage::MiningNotifyMessage(message) => (&message.method, "mining.notify"),
+            StratumMessage::MiningSubmitMessage(message) => (&message.method, "mining.submit"),
+            StratumMessage::MiningHashrateMessage(message) => (&message.method, "mining.hashrate"),
+            StratumMessage::MiningAuthorizeMessage(message) => {
+                (&message.method, "mining.authorize")
+            }
+            StratumMessage::MiningSubmittedMessage(message) => {
+                (&message.method, "mining.submitted")
+            }
+            StratumMessage::MiningWaitForWorkMessage(message) => {
+                (&message.method, "mining.wait_for_work")
+            }
+        };
+        method == known
+    }
+}
+
+/// How much of an undecodable line is kept for the error message.
+const DECODE_ERROR_SNIPPET: usize = 256;
+/// Longest line taken from the peer. Stratum messages are a few hundred bytes, a longer line
+/// means the framing is out of sync.
+pub const MAX_LINE_LENGTH: usize = 64 * 1024;
+
+/// A line from the peer that is not a stratum message, with the start of the line so that
+/// protocol mismatches can be diagnosed from the log.
+#[derive(Debug)]
+pub struct DecodeError {
+    /// The first bytes of the line, lossily decoded with control characters escaped.
+    pub snippet: String,
+    /// Length of the whole line in bytes, or of what was read of it when it was too long.
+    pub len: usize,
+    pub kind: ProtocolErrorKind,
+    /// Why the line is not a stratum message, `None` for a line too long to try.
+    pub error: Option<serde_json::Error>,
+}
+
+impl DecodeError {
+    fn new(line: &[u8], error: serde_json::Error) -> Self {
+        let kind = match error.classify() {
+            serde_json::error::Category::Data => ProtocolErrorKind::WrongShape,
+            _ => ProtocolErrorKind::Decode,
+        };
+        Self::with_kind(line, kind, Some(error))
+    }
+
+    fn oversize(line: &[u8]) -> Self {
+        Self::with_kind(line, ProtocolErrorKind::Oversize, None)
+    }
+
+    fn compressed(line: &[u8]) -> Self {
+        Self::with_kind(line, ProtocolErrorKind::Decode, None)
+    }
+
+    fn with_kind(line: &[u8], kind: ProtocolErrorKind, error: Option<serde_json::Error>) -> Self {
+        let truncated = line.len() > DECODE_ERROR_SNIPPET;
+        let mut snippet = String::new();
+        for c in String::from_utf8_lossy(&line[..line.len().min(DECODE_ERROR_SNIPPET)]).chars() {
+            if c.is_control() {
+                snippet.extend(c.escape_default());
+            } else {
+                snippet.push(c);
+            }
+        }
+        if truncated {
+            snippet.push_str("...");
+        }
+        Self {
+            snippet,
+            len: line.len(),
+            kind,
+            error,
+        }
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.error, self.kind) {
+            (Some(error), _) => write!(
+                f,
+                "undecodable message ({}) in {} byte line \"{}\"",
+                error, self.len, self.snippet
+            ),
+            (None, ProtocolErrorKind::Oversize) => write!(
+                f,
+                "line longer than {} bytes \"{}\"",
+                MAX_LINE_LENGTH, self.snippet
+            ),
+            (None, _) => write!(
+                f,
+                "undecodable compressed line of {} bytes \"{}\"",
+                self.len, self.snippet
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.error
+            .as_ref()
+            .map(|error| error as &(dyn std::error::Error + 'static))
+    }
+}
+
 #[derive(Default)]
 pub struct StratumMessageCodec {
     cursor: usize,
+    /// Skip undecodable lines instead of failing, which would end the stream.
+    lenient: bool,
+    skipped: u64,
+    errors: ProtocolErrors,
+    /// Fails the stream once there are too many protocol errors, lenient or not.
+    limit: Option<ProtocolErrorLimit>,
+    /// Dropping the rest of a line that was too long.
+    discarding: bool,
+    /// Records every line read and written, see [`crate::StratumDump`].
+    tap: Option<DumpTap>,
+    /// Compresses the lines written, once the peer agreed, see [`crate::LineDeflater`].
+    deflater: Option<LineDeflater>,
+    /// Restores the compressed lines read, plain ones are read as they are.
+    inflater: Option<LineInflater>,
+}
+
+impl StratumMessageCodec {
+    /// A codec that logs and skips lines it cannot decode.
+    pub fn lenient() -> Self {
+        Self {
+            lenient: true,
+            ..Default::default()
+        }
+    }
+
+    /// Number of lines skipped in lenient mode.
+    pub fn skipped(&self) -> u64 {
+        self.skipped
+    }
+
+    /// Fails the stream with [`TooManyProtocolErrors`](crate::TooManyProtocolErrors) once more
+    /// than `per_min` protocol errors came within a minute.
+    pub fn limit_errors(&mut self, per_min: u32) {
+        self.limit = Some(ProtocolErrorLimit::new(per_min));
+    }
+
+    /// Records every line this codec reads or writes from now on.
+    pub fn record_to(&mut self, tap: DumpTap) {
+        self.tap = Some(tap);
+    }
+
+    /// Compresses the lines this codec writes from now on, counted in `counts`.
+    pub fn compress_writes(&mut self, counts: Arc<CompressionCounts>) {
+        self.deflater = Some(LineDeflater::new(counts));
+    }
+
+    /// Reads compressed lines from now on, counted in `counts`. Before, such a line does not
+    /// decode.
+    pub fn decompress_reads(&mut self, counts: Arc<CompressionCounts>) {
+        self.inflater = Some(LineInflater::new(counts));
+    }
+
+    /// The protocol errors so far, whether they ended the stream or not.
+    pub fn protocol_errors(&self) -> ProtocolErrors {
+        self.errors
+    }
+
+    /// Counts a protocol error and checks the limit.
+    fn record(&mut self, kind: ProtocolErrorKind) -> Result<()> {
+        self.errors.record(kind);
+        if let Some(limit) = self.limit.as_mut() {
+            limit.record(Instant::now())?;
+        }
+        Ok(())
+    }
+
+    /// Skips the line in lenient mode, fails otherwise.
+    fn reject(&mut self, error: DecodeError) -> Result<()> {
+        self.record(error.kind)?;
+        if !self.lenient {
+            return Err(error.into());
+        }
+        self.skipped += 1;
+        warn!("skipping {}", error);
+        Ok(())
+    }
 }
 
 impl Encoder<StratumMessage> for StratumMessageCodec {
@@ -103,7 +489,13 @@ impl Encoder<StratumMessage> for StratumMessageCodec {
     fn encode(&mut self, message: StratumMessage, dst: &mut BytesMut) -> Result<(), Self::Error> {
         //bincode::serialize_into(&mut dst.writer(), &message)?;
         let json_string = serde_json::to_string(&message).unwrap();
-        dst.writer().write_all(json_string.as_bytes())?;
+        if let Some(tap) = &self.tap {
+            tap.record(DumpDirection::ToPool, json_string.as_bytes());
+        }
+        match self.deflater.as_mut() {
+            Some(deflater) => deflater.deflate(json_string.as_bytes(), dst)?,
+            None => dst.writer().write_all(json_string.as_bytes())?,
+        }
         dst.writer().write_all("\n".as_bytes())?;
         Ok(())
     }
@@ -123,11 +515,58 @@ impl Decoder for StratumMessageCodec {
                     data.set_len(i);
                 }
                 src.reserve(100);
-                let message = serde_json::from_slice(&data[..])?;
-                return Ok(Some(message));
+                i = 0;
+                if self.discarding {
+                    // the end of a line already rejected as too long
+                    self.discarding = false;
+                    continue;
+                }
+                if let Some(inflater) = self.inflater.as_mut().filter(|_| is_compressed(&data)) {
+                    match inflater.inflate(&data[..]) {
+                        Ok(inflated) => data = BytesMut::from(&inflated[..]),
+                        Err(error) => {
+                            // the window is gone, so is every line after, lenient or not
+                            warn!("failed to inflate a line from the peer: {}", error);
+                            self.record(ProtocolErrorKind::Decode)?;
+                            return Err(DecodeError::compressed(&data[..]).into());
+                        }
+                    }
+                }
+                if let Some(tap) = &self.tap {
+                    tap.record(DumpDirection::FromPool, &data[..]);
+                }
+                if data.len() > MAX_LINE_LENGTH {
+                    self.reject(DecodeError::oversize(&data[..]))?;
+                    continue;
+                }
+                match serde_json::from_slice::<StratumMessage>(&data[..]) {
+                    Ok(message) => {
+                        if !message.has_known_method() {
+                            self.record(ProtocolErrorKind::UnknownMethod)?;
+                        }
+                        return Ok(Some(message));
+                    }
+                    Err(error) => {
+                        self.reject(DecodeError::new(&data[..], error))?;
+                        continue;
+                    }
+                }
             }
             i += 1;
         }
+        if src.len() > MAX_LINE_LENGTH {
+            // no newline in sight, drop what there is rather than buffer without bound
+            self.cursor = 0;
+            if self.discarding {
+                src.clear();
+                return Ok(None);
+            }
+            let error = DecodeError::oversize(&src[..]);
+            src.clear();
+            self.discarding = true;
+            self.reject(error)?;
+            return Ok(None);
+        }
         self.cursor = i;
         Ok(None)
     }
@@ -138,14 +577,18 @@ mod tests {
     use super::*;
     #[test]
     fn test_subscribe_message() {
-        let origin_json_string = "{\"id\":0,\"method\":\"mining.subscribe\",\"body\":{\"version\":0,\"publicAddress\":\"127.0.0.1:8888\"}}";
+        let origin_json_string = "{\"id\":0,\"method\":\"mining.subscribe\",\"body\":{\"version\":0,\"name\":\"zkwork miner\",\"publicAddress\":\"127.0.0.1:8888\"}}";
 
         let message = StratumMessage::MiningSubscribeMessage(MiningSubscribeMessage {
-            id: 0,
+            id: 0.into(),
             method: String::from("mining.subscribe"),
             body: MiningSubscribeBody {
                 version: 0,
-                publicAddress: String::from("127.0.0.1:8888"),
+                name: String::from("zkwork miner"),
+                publicAddress: Some(String::from("127.0.0.1:8888")),
+                agent: None,
+                graffitiSuffix: None,
+                capabilities: vec![],
             },
         });
         let json_string = serde_json::to_string(&message).unwrap();
@@ -169,16 +612,131 @@ mod tests {
         assert_eq!(message, message_one);
     }
 
+    #[test]
+    fn test_subscribe_message_with_agent() {
+        let message = StratumMessage::MiningSubscribeMessage(MiningSubscribeMessage {
+            id: 0.into(),
+            method: String::from("mining.subscribe"),
+            body: MiningSubscribeBody {
+                version: 1,
+                name: String::from("zkwork miner"),
+                publicAddress: Some(String::from("127.0.0.1:8888")),
+                agent: Some(String::from("zkwork_ironminer/0.1.3 (linux; x86_64)")),
+                graffitiSuffix: None,
+                capabilities: vec![],
+            },
+        });
+        let mut buf = BytesMut::new();
+        let mut codec = StratumMessageCodec::default();
+        codec.encode(message.clone(), &mut buf).unwrap();
+        assert_eq!(
+            &buf[..],
+            b"{\"id\":0,\"method\":\"mining.subscribe\",\"body\":{\"version\":1,\"name\":\"zkwork miner\",\"publicAddress\":\"127.0.0.1:8888\",\"agent\":\"zkwork_ironminer/0.1.3 (linux; x86_64)\"}}\n"
+        );
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap(), message);
+
+        // without an agent the line is what it always was
+        let message = StratumMessage::MiningSubscribeMessage(MiningSubscribeMessage {
+            id: 0.into(),
+            method: String::from("mining.subscribe"),
+            body: MiningSubscribeBody {
+                version: 1,
+                name: String::from("zkwork miner"),
+                publicAddress: Some(String::from("127.0.0.1:8888")),
+                agent: None,
+                graffitiSuffix: None,
+                capabilities: vec![],
+            },
+        });
+        codec.encode(message, &mut buf).unwrap();
+        assert_eq!(
+            &buf[..],
+            b"{\"id\":0,\"method\":\"mining.subscribe\",\"body\":{\"version\":1,\"name\":\"zkwork miner\",\"publicAddress\":\"127.0.0.1:8888\"}}\n"
+        );
+
+        // without an address, for a pool that takes a token, the field is left out
+        buf.clear();
+        let message = StratumMessage::MiningSubscribeMessage(MiningSubscribeMessage {
+            id: 0.into(),
+            method: String::from("mining.subscribe"),
+            body: MiningSubscribeBody {
+                version: 1,
+                name: String::from("zkwork miner"),
+                publicAddress: None,
+                agent: None,
+                graffitiSuffix: None,
+                capabilities: vec![],
+            },
+        });
+        codec.encode(message.clone(), &mut buf).unwrap();
+        assert_eq!(
+            &buf[..],
+            b"{\"id\":0,\"method\":\"mining.subscribe\",\"body\":{\"version\":1,\"name\":\"zkwork miner\"}}\n"
+        );
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap(), message);
+
+        let agent = user_agent();
+        assert!(agent.starts_with("zkwork_ironminer/"), "{}", agent);
+        assert!(agent.ends_with(')'), "{}", agent);
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let subscribe = StratumMessage::MiningSubscribeMessage(MiningSubscribeMessage {
+            id: 0.into(),
+            method: String::from("mining.subscribe"),
+            body: MiningSubscribeBody {
+                version: 1,
+                name: String::from("zkwork miner"),
+                publicAddress: Some(String::from("127.0.0.1:8888")),
+                agent: None,
+                graffitiSuffix: None,
+                capabilities: vec![String::from("timestamps")],
+            },
+        });
+        let json = "{\"id\":0,\"method\":\"mining.subscribe\",\"body\":{\"version\":1,\"name\":\"zkwork miner\",\"publicAddress\":\"127.0.0.1:8888\",\"capabilities\":[\"timestamps\"]}}";
+        assert_eq!(serde_json::to_string(&subscribe).unwrap(), json);
+        assert_eq!(
+            serde_json::from_str::<StratumMessage>(json).unwrap(),
+            subscribe
+        );
+
+        // an ack with and without the pool's choice
+        let json = "{\"id\":0,\"method\":\"mining.subscribed\",\"body\":{\"clientId\":0,\"graffiti\":\"zk.work\",\"capabilities\":[\"timestamps\",\"keepalive\"]}}";
+        let mut buf = BytesMut::from(format!("{}\n", json).as_bytes());
+        match StratumMessageCodec::default().decode(&mut buf).unwrap() {
+            Some(StratumMessage::MiningSubscribedMessage(message)) => assert_eq!(
+                message.body.capabilities,
+                Some(vec![String::from("timestamps"), String::from("keepalive")])
+            ),
+            other => panic!("expected subscribed, got {:?}", other),
+        }
+        let json = "{\"id\":0,\"method\":\"mining.subscribed\",\"body\":{\"clientId\":0,\"graffiti\":\"zk.work\"}}";
+        let mut buf = BytesMut::from(format!("{}\n", json).as_bytes());
+        match StratumMessageCodec::default().decode(&mut buf).unwrap() {
+            Some(StratumMessage::MiningSubscribedMessage(message)) => {
+                assert_eq!(message.body.capabilities, None);
+                assert_eq!(
+                    serde_json::to_string(&StratumMessage::MiningSubscribedMessage(message))
+                        .unwrap(),
+                    json
+                );
+            }
+            other => panic!("expected subscribed, got {:?}", other),
+        }
+    }
+
     #[test]
     fn test_subscribed_message() {
         let  origin_json_string = "{\"id\":0,\"method\":\"mining.subscribed\",\"body\":{\"clientId\":0,\"graffiti\":\"zk.work\"}}";
 
         let message = StratumMessage::MiningSubscribedMessage(MiningSubscribedMessage {
-            id: 0,
+            id: 0.into(),
             method: String::from("mining.subscribed"),
             body: MiningSubscribedBody {
                 clientId: 0,
                 graffiti: String::from("zk.work"),
+                capabilities: None,
             },
         });
         let json_string = serde_json::to_string(&message).unwrap();
@@ -189,7 +747,10 @@ mod tests {
         assert_eq!(origin_json_string, json_string);
 
         let mut buf = BytesMut::new();
-        let mut codec = StratumMessageCodec { cursor: 0 };
+        let mut codec = StratumMessageCodec {
+            cursor: 0,
+            ..Default::default()
+        };
         let _ = codec.encode(message.clone(), &mut buf);
         println!("buf: {:?}", buf);
         let message_one = codec.decode(&mut buf).unwrap().unwrap();
@@ -208,7 +769,7 @@ mod tests {
             "{\"id\":0,\"method\":\"mining.set_target\",\"body\":{\"target\":\"1234567890\"}}";
 
         let message = StratumMessage::MiningSetTargetMessage(MiningSetTargetMessage {
-            id: 0,
+            id: 0.into(),
             method: String::from("mining.set_target"),
             body: MiningSetTargetBody {
                 target: 1234567890.to_string(),
@@ -240,11 +801,13 @@ mod tests {
         let  origin_json_string = "{\"id\":0,\"method\":\"mining.notify\",\"body\":{\"miningRequestId\":12345,\"header\":\"header data...\"}}";
 
         let message = StratumMessage::MiningNotifyMessage(MiningNotifyMessage {
-            id: 0,
+            id: 0.into(),
             method: String::from("mining.notify"),
             body: MiningNotifyBody {
                 miningRequestId: 12345,
                 header: String::from("header data..."),
+                timestamp: None,
+                expiresInMs: None,
             },
         });
         let json_string = serde_json::to_string(&message).unwrap();
@@ -273,11 +836,12 @@ mod tests {
         let  origin_json_string = "{\"id\":0,\"method\":\"mining.submit\",\"body\":{\"miningRequestId\":12345,\"randomness\":\"123456789\"}}";
 
         let message = StratumMessage::MiningSubmitMessage(MiningSubmitMessage {
-            id: 0,
+            id: 0.into(),
             method: String::from("mining.submit"),
             body: MiningSubmitBody {
                 miningRequestId: 12345,
                 randomness: 123456789.to_string(),
+                elapsedMs: None,
             },
         });
         let json_string = serde_json::to_string(&message).unwrap();
@@ -301,12 +865,122 @@ mod tests {
         assert_eq!(message, message_one);
     }
 
+    #[test]
+    fn test_submit_solve_time() {
+        let json = "{\"id\":4,\"method\":\"mining.submit\",\"body\":{\"miningRequestId\":7,\"randomness\":\"00000000000004d2\",\"elapsedMs\":1520}}";
+        let message = StratumMessage::MiningSubmitMessage(MiningSubmitMessage {
+            id: 4.into(),
+            method: String::from("mining.submit"),
+            body: MiningSubmitBody {
+                miningRequestId: 7,
+                randomness: String::from("00000000000004d2"),
+                elapsedMs: Some(1520),
+            },
+        });
+        let mut codec = StratumMessageCodec::default();
+        let mut buf = BytesMut::new();
+        codec.encode(message.clone(), &mut buf).unwrap();
+        assert_eq!(&buf[..], format!("{}\n", json).as_bytes());
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap(), message);
+
+        // without it the line is the one pools always got
+        let mut without = message;
+        if let StratumMessage::MiningSubmitMessage(message) = &mut without {
+            message.body.elapsedMs = None;
+        }
+        codec.encode(without.clone(), &mut buf).unwrap();
+        assert!(!String::from_utf8_lossy(&buf).contains("elapsedMs"));
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap(), without);
+    }
+
+    #[test]
+    fn test_hashrate_message() {
+        let origin_json_string = "{\"id\":3,\"method\":\"mining.hashrate\",\"body\":{\"hashrate\":1234567.5,\"name\":\"rig1\"}}";
+
+        let message = StratumMessage::MiningHashrateMessage(MiningHashrateMessage {
+            id: 3.into(),
+            method: String::from("mining.hashrate"),
+            body: MiningHashrateBody {
+                hashrate: 1234567.5,
+                name: String::from("rig1"),
+            },
+        });
+        let json_string = serde_json::to_string(&message).unwrap();
+        let message_one: StratumMessage = serde_json::from_str(origin_json_string).unwrap();
+        assert_eq!(message, message_one);
+        assert!(message_one.has_known_method());
+        assert_eq!(origin_json_string, json_string);
+
+        let mut buf = BytesMut::new();
+        let mut codec = StratumMessageCodec::default();
+        let _ = codec.encode(message.clone(), &mut buf);
+        let message_one = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(message, message_one);
+    }
+
+    #[test]
+    fn test_authorize_message() {
+        let origin_json_string =
+            "{\"id\":4,\"method\":\"mining.authorize\",\"body\":{\"token\":\"s3cret\"}}";
+
+        let message = StratumMessage::MiningAuthorizeMessage(MiningAuthorizeMessage {
+            id: 4.into(),
+            method: String::from("mining.authorize"),
+            body: MiningAuthorizeBody {
+                token: String::from("s3cret"),
+            },
+        });
+        let message_one: StratumMessage = serde_json::from_str(origin_json_string).unwrap();
+        assert_eq!(message, message_one);
+        assert!(message_one.has_known_method());
+        assert_eq!(origin_json_string, serde_json::to_string(&message).unwrap());
+
+        let mut buf = BytesMut::new();
+        let mut codec = StratumMessageCodec::default();
+        let _ = codec.encode(message.clone(), &mut buf);
+        let message_one = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(message, message_one);
+    }
+
+    #[test]
+    fn test_submitted_message() {
+        let origin_json_string = "{\"id\":9,\"method\":\"mining.submitted\",\"body\":{\"id\":5,\"result\":false,\"message\":\"duplicate share\"}}";
+
+        let message = StratumMessage::MiningSubmittedMessage(MiningSubmittedMessage {
+            id: 9.into(),
+            method: String::from("mining.submitted"),
+            body: MiningSubmittedBody {
+                id: 5.into(),
+                result: false,
+                message: Some(String::from("duplicate share")),
+            },
+        });
+        let message_one: StratumMessage = serde_json::from_str(origin_json_string).unwrap();
+        assert_eq!(message, message_one);
+        assert!(message_one.has_known_method());
+        assert_eq!(origin_json_string, serde_json::to_string(&message).unwrap());
+
+        // the reason is left out by pools that accepted the share
+        let accepted: StratumMessage = serde_json::from_str(
+            "{\"id\":null,\"method\":\"mining.submitted\",\"body\":{\"id\":\"6\",\"result\":true}}",
+        )
+        .unwrap();
+        match accepted {
+            StratumMessage::MiningSubmittedMessage(accepted) => {
+                assert_eq!(accepted.body.id, MessageId::from(6));
+                assert!(accepted.body.result);
+                assert_eq!(accepted.body.message, None);
+            }
+            other => panic!("expected a submitted message, got {:?}", other),
+        }
+    }
+
     #[test]
     fn test_waitfortask_message() {
         let origin_json_string = "{\"id\":0,\"method\":\"mining.wait_for_work\"}";
 
         let message = StratumMessage::MiningWaitForWorkMessage(MiningWaitForWorkMessage {
-            id: 0,
+            id: 0.into(),
             method: String::from("mining.wait_for_work"),
         });
         let json_string = serde_json::to_string(&message).unwrap();
@@ -329,4 +1003,163 @@ mod tests {
         println!("{:?}", message_one);
         assert_eq!(message, message_one);
     }
+
+    #[test]
+    fn test_notify_message_with_timestamp() {
+        let origin_json_string = "{\"id\":0,\"method\":\"mining.notify\",\"body\":{\"miningRequestId\":12345,\"header\":\"header data...\",\"timestamp\":1665000000000}}";
+
+        let message = StratumMessage::MiningNotifyMessage(MiningNotifyMessage {
+            id: 0.into(),
+            method: String::from("mining.notify"),
+            body: MiningNotifyBody {
+                miningRequestId: 12345,
+                header: String::from("header data..."),
+                timestamp: Some(1665000000000),
+                expiresInMs: None,
+            },
+        });
+        let json_string = serde_json::to_string(&message).unwrap();
+        let message_one: StratumMessage = serde_json::from_str(origin_json_string).unwrap();
+        assert_eq!(message, message_one);
+        assert_eq!(origin_json_string, json_string);
+    }
+
+    #[test]
+    fn test_decode_error_snippet() {
+        let mut codec = StratumMessageCodec::default();
+        let mut buf = BytesMut::from(&b"<html>bad gateway</html>\n"[..]);
+        let error = codec.decode(&mut buf).unwrap_err();
+        let decode_error = error.downcast_ref::<DecodeError>().unwrap();
+        assert_eq!(decode_error.snippet, "<html>bad gateway</html>");
+        assert_eq!(decode_error.len, 24);
+        assert!(error.to_string().contains("\"<html>bad gateway</html>\""));
+
+        // binary garbage is escaped and cut short
+        let mut garbage: Vec<u8> = (0..=255u8)
+            .filter(|byte| *byte != 10)
+            .cycle()
+            .take(1000)
+            .collect();
+        garbage.push(10);
+        let mut buf = BytesMut::from(&garbage[..]);
+        let error = codec.decode(&mut buf).unwrap_err();
+        let decode_error = error.downcast_ref::<DecodeError>().unwrap();
+        assert_eq!(decode_error.len, 1000);
+        assert!(decode_error.snippet.starts_with("\\u{0}\\u{1}"));
+        assert!(decode_error.snippet.ends_with("..."));
+        assert!(!decode_error.snippet.chars().any(char::is_control));
+        let _ = error.to_string();
+    }
+
+    #[test]
+    fn test_lenient_decode() {
+        let message = StratumMessage::MiningWaitForWorkMessage(MiningWaitForWorkMessage {
+            id: 0.into(),
+            method: String::from("mining.wait_for_work"),
+        });
+        let mut codec = StratumMessageCodec::lenient();
+        let mut buf = BytesMut::from(&b"not json\n\xff\xfe\n"[..]);
+        codec.encode(message.clone(), &mut buf).unwrap();
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(message));
+        assert_eq!(codec.skipped(), 2);
+        let mut buf = BytesMut::from(&b"not json\n"[..]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        assert_eq!(codec.skipped(), 3);
+    }
+
+    #[test]
+    fn test_compressed_lines() {
+        let message = StratumMessage::MiningWaitForWorkMessage(MiningWaitForWorkMessage {
+            id: 0.into(),
+            method: String::from("mining.wait_for_work"),
+        });
+        let counts = Arc::new(CompressionCounts::default());
+        let mut pool = StratumMessageCodec::default();
+        pool.compress_writes(counts.clone());
+        let mut compressed = BytesMut::new();
+        pool.encode(message.clone(), &mut compressed).unwrap();
+        assert_eq!(compressed[0], crate::COMPRESSED_LINE_PREFIX);
+
+        // a miner that did not agree can not read it, lenient or not
+        let error = StratumMessageCodec::default()
+            .decode(&mut compressed.clone())
+            .unwrap_err();
+        assert!(error.downcast_ref::<DecodeError>().is_some());
+        let mut lenient = StratumMessageCodec::lenient();
+        assert_eq!(lenient.decode(&mut compressed.clone()).unwrap(), None);
+        assert_eq!(lenient.protocol_errors().decode_errors, 1);
+
+        // one that did reads compressed and plain lines alike, as from a pool that switches
+        // late
+        let mut miner = StratumMessageCodec::default();
+        miner.decompress_reads(counts.clone());
+        let mut buf = BytesMut::new();
+        StratumMessageCodec::default()
+            .encode(message.clone(), &mut buf)
+            .unwrap();
+        buf.extend_from_slice(&compressed);
+        pool.encode(message.clone(), &mut buf).unwrap();
+        for _ in 0..3 {
+            assert_eq!(miner.decode(&mut buf).unwrap(), Some(message.clone()));
+        }
+        let stats = counts.stats();
+        assert_eq!(stats.json_in, stats.json_out);
+        assert_eq!(stats.wire_in, stats.wire_out);
+    }
+
+    #[test]
+    fn test_corrupt_compressed_line() {
+        // the window is lost with the line, so even a lenient codec gives up
+        let mut codec = StratumMessageCodec::lenient();
+        codec.decompress_reads(Arc::default());
+        let mut buf = BytesMut::from(&b"~bm90IGRlZmxhdGVk\n"[..]);
+        let error = codec.decode(&mut buf).unwrap_err();
+        let decode_error = error.downcast_ref::<DecodeError>().unwrap();
+        assert_eq!(decode_error.kind, ProtocolErrorKind::Decode);
+        assert_eq!(decode_error.snippet, "~bm90IGRlZmxhdGVk");
+        assert!(error.to_string().starts_with("undecodable compressed line"));
+        assert_eq!(codec.protocol_errors().decode_errors, 1);
+        assert_eq!(codec.skipped(), 0);
+    }
+
+    #[test]
+    fn test_message_ids() {
+        // ids go back out the way they came in
+        for json in [
+            "{\"id\":\"17\",\"method\":\"mining.set_target\",\"body\":{\"target\":\"12\"}}",
+            "{\"id\":null,\"method\":\"mining.notify\",\"body\":{\"miningRequestId\":1,\"header\":\"00\"}}",
+            "{\"id\":-3,\"method\":\"mining.wait_for_work\"}",
+        ] {
+            let message: StratumMessage = serde_json::from_str(json).unwrap();
+            assert_eq!(serde_json::to_string(&message).unwrap(), json);
+        }
+        let message: StratumMessage = serde_json::from_str(
+            "{\"id\":\"17\",\"method\":\"mining.set_target\",\"body\":{\"target\":\"12\"}}",
+        )
+        .unwrap();
+        match message {
+            StratumMessage::MiningSetTargetMessage(message) => {
+                assert!(matches!(message.id, MessageId::String(_)));
+                assert_eq!(message.id.to_string(), "17");
+                // compared by value
+                assert_eq!(message.id, MessageId::from(17));
+            }
+            message => panic!("unexpected {:?}", message),
+        }
+        assert_ne!(MessageId::Null, MessageId::from(0));
+        assert_eq!(MessageId::Null, MessageId::Null);
+
+        // anything else is not an id
+        for json in [
+            "{\"id\":\"abc\",\"method\":\"mining.wait_for_work\"}",
+            "{\"id\":1.5,\"method\":\"mining.wait_for_work\"}",
+            "{\"id\":18446744073709551615,\"method\":\"mining.wait_for_work\"}",
+        ] {
+            assert!(
+                serde_json::from_str::<StratumMessage>(json).is_err(),
+                "{}",
+                json
+            );
+        }
+    }
 }
@@ -2,8 +2,74 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 
+pub mod capabilities;
+pub use capabilities::*;
+
+pub mod clock_skew;
+pub use clock_skew::*;
+
+pub mod compression;
+pub use compression::*;
+
+pub mod connect;
+pub use connect::*;
+
+pub mod connections;
+pub use connections::*;
+
+pub mod disconnect;
+pub use disconnect::*;
+
+pub mod dump;
+pub use dump::*;
+
+pub mod flapping;
+pub use flapping::*;
+
+pub mod half_open;
+pub use half_open::*;
+
+pub mod http_proxy;
+pub use http_proxy::*;
+
+pub mod job_assembler;
+pub use job_assembler::*;
+
 pub mod message;
 pub use message::*;
 
+pub mod pool_stats;
+pub use pool_stats::*;
+
+pub mod protocol;
+pub use protocol::*;
+
+pub mod protocol_errors;
+pub use protocol_errors::*;
+
+pub mod resolve;
+pub use resolve::*;
+
+pub mod session;
+pub use session::*;
+
 pub mod stratum_client;
 pub use stratum_client::*;
+
+pub mod stratum_session;
+pub use stratum_session::*;
+
+pub mod submit_connection;
+pub use submit_connection::*;
+
+pub mod submit_ledger;
+pub use submit_ledger::*;
+
+pub mod submit_limiter;
+pub use submit_limiter::*;
+
+pub mod tls;
+pub use tls::*;
+
+pub mod writer;
+pub use writer::*;
@@ -0,0 +1,290 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! How each pool of the list, `--pool` then every `--failover-pool`, did in this process.
+//! The connection and session records only keep the last few, these are the totals per pool
+//! entry since the start, to reorder the list by. Only the main connection counts, the
+//! submit connection follows it to the same pool.
+
+use crate::{DisconnectReason, PoolAddress};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+
+/// One entry of the pool list.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct PoolStats {
+    /// The position in the list, 0 for `--pool`.
+    pub index: usize,
+    pub address: String,
+    /// Whether the client is on this pool now, connected or trying to.
+    pub current: bool,
+    /// Connects tried, a session handed over by the previous process not included.
+    pub connect_attempts: u64,
+    /// Connects that got through, subscribed or not.
+    pub connects: u64,
+    /// Subscribed sessions.
+    pub sessions: u64,
+    /// Time subscribed, the open session included.
+    pub connected_ms: u64,
+    /// Shares written while on this pool.
+    pub shares_submitted: u64,
+    /// Shares the pool acknowledged, only pools that answer submits do.
+    pub shares_accepted: u64,
+    pub shares_rejected: u64,
+    /// From writing a share to the pool's answer, on average. Absent until the pool answered
+    /// one.
+    pub submit_latency_ms: Option<f64>,
+    /// Disconnects by the code of their reason.
+    pub disconnects: BTreeMap<String, u64>,
+}
+
+impl PoolStats {
+    /// `pools` as a table for the log, one line each, the current pool marked.
+    pub fn format_table(pools: &[PoolStats]) -> String {
+        let mut lines = vec![format!(
+            "  {:<4} {:>8} {:>8} {:>8} {:>11} {:>9} {:>9}  {:<24} disconnects",
+            "pool",
+            "attempts",
+            "connects",
+            "sessions",
+            "connected",
+            "accepted",
+            "latency",
+            "address"
+        )];
+        for pool in pools {
+            let connected = pool.connected_ms / 1000;
+            let disconnects = pool
+                .disconnects
+                .iter()
+                .map(|(reason, count)| format!("{} {}", reason, count))
+                .collect::<Vec<_>>();
+            lines.push(format!(
+                "{} {:<4} {:>8} {:>8} {:>8} {:>11} {:>9} {:>9}  {:<24} {}",
+                if pool.current { '*' } else { ' ' },
+                format!("#{}", pool.index),
+                pool.connect_attempts,
+                pool.connects,
+                pool.sessions,
+                format!(
+                    "{}h {:02}m {:02}s",
+                    connected / 3600,
+                    connected / 60 % 60,
+                    connected % 60
+                ),
+                format!("{}/{}", pool.shares_accepted, pool.shares_submitted),
+                match pool.submit_latency_ms {
+                    Some(latency) => format!("{:.1}ms", latency),
+                    None => String::from("-"),
+                },
+                pool.address,
+                if disconnects.is_empty() {
+                    String::from("none")
+                } else {
+                    disconnects.join(", ")
+                }
+            ));
+        }
+        lines.join("\n")
+    }
+}
+
+#[derive(Debug)]
+struct PoolRecord {
+    stats: PoolStats,
+    /// When the open session was subscribed.
+    session_since: Option<Instant>,
+    latency_total: Duration,
+    latency_count: u64,
+}
+
+/// The stats of every entry of the pool list, and which one the client is on.
+#[derive(Debug)]
+pub struct PoolStatsLog {
+    pools: Vec<PoolRecord>,
+    current: usize,
+}
+
+impl PoolStatsLog {
+    /// `pools` in the order they are tried, starting on the first.
+    pub fn new(pools: &[PoolAddress]) -> Self {
+        Self {
+            pools: pools
+                .iter()
+                .enumerate()
+                .map(|(index, address)| PoolRecord {
+                    stats: PoolStats {
+                        index,
+                        address: address.to_string(),
+                        ..Default::default()
+                    },
+                    session_since: None,
+                    latency_total: Duration::ZERO,
+                    latency_count: 0,
+                })
+                .collect(),
+            current: 0,
+        }
+    }
+
+    /// The index of the pool the client is on.
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    fn record(&mut self) -> &mut PoolRecord {
+        &mut self.pools[self.current]
+    }
+
+    /// Moves on to the next pool of the list, the first after the last, and returns its
+    /// index. An open session is closed at `now`.
+    pub fn next_pool(&mut self, now: Instant) -> usize {
+        self.session_ended(now);
+        self.current = (self.current + 1) % self.pools.len();
+        self.current
+    }
+
+    pub fn connect_attempted(&mut self) {
+        self.record().stats.connect_attempts += 1;
+    }
+
+    pub fn connected(&mut self) {
+        self.record().stats.connects += 1;
+    }
+
+    /// The subscribe completed at `now`.
+    pub fn session_started(&mut self, now: Instant) {
+        let record = self.record();
+        record.stats.sessions += 1;
+        record.session_since = Some(now);
+    }
+
+    /// The session closed at `now`, nothing without one open.
+    pub fn session_ended(&mut self, now: Instant) {
+        let record = self.record();
+        if let Some(since) = record.session_since.take() {
+            record.stats.connected_ms += now.saturating_duration_since(since).as_millis() as u64;
+        }
+    }
+
+    pub fn disconnected(&mut self, reason: DisconnectReason) {
+        *self
+            .record()
+            .stats
+            .disconnects
+            .entry(reason.code().to_string())
+            .or_default() += 1;
+    }
+
+    pub fn share_submitted(&mut self) {
+        self.record().stats.shares_submitted += 1;
+    }
+
+    /// The pool answered a share `latency` after it was written, if that is still known.
+    pub fn share_acked(&mut self, accepted: bool, latency: Option<Duration>) {
+        let record = self.record();
+        if accepted {
+            record.stats.shares_accepted += 1;
+        } else {
+            record.stats.shares_rejected += 1;
+        }
+        if let Some(latency) = latency {
+            record.latency_total += latency;
+            record.latency_count += 1;
+        }
+    }
+
+    /// Every pool of the list in order, an open session counted up to `now`.
+    pub fn stats(&self, now: Instant) -> Vec<PoolStats> {
+        self.pools
+            .iter()
+            .map(|record| {
+                let mut stats = record.stats.clone();
+                stats.current = stats.index == self.current;
+                if let Some(since) = record.session_since {
+                    stats.connected_ms += now.saturating_duration_since(since).as_millis() as u64;
+                }
+                stats.submit_latency_ms = (record.latency_count > 0).then(|| {
+                    record.latency_total.as_micros() as f64 / 1000.0 / record.latency_count as f64
+                });
+                stats
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    #[test]
+    fn test_pool_stats_log() {
+        let pools: Vec<PoolAddress> = vec![
+            "127.0.0.1:8181".parse().unwrap(),
+            "pool.example.com:8181".parse().unwrap(),
+        ];
+        let mut log = PoolStatsLog::new(&pools);
+        let start = Instant::now();
+        let ms = Duration::from_millis;
+        log.connect_attempted();
+        log.connected();
+        log.session_started(start);
+        log.share_submitted();
+        log.share_submitted();
+        log.share_acked(true, Some(ms(10)));
+        log.share_acked(false, Some(ms(30)));
+        // the open session counts up to now
+        assert_eq!(log.stats(start + ms(500))[0].connected_ms, 500);
+        log.session_ended(start + ms(1000));
+        log.disconnected(DisconnectReason::ReadError(io::ErrorKind::ConnectionReset));
+        log.connect_attempted();
+        log.connect_attempted();
+        assert_eq!(log.next_pool(start + ms(2000)), 1);
+        log.connect_attempted();
+        log.connected();
+        log.session_started(start + ms(2000));
+        log.share_submitted();
+
+        let stats = log.stats(start + ms(2250));
+        assert_eq!(
+            stats[0],
+            PoolStats {
+                index: 0,
+                address: String::from("127.0.0.1:8181"),
+                current: false,
+                connect_attempts: 3,
+                connects: 1,
+                sessions: 1,
+                connected_ms: 1000,
+                shares_submitted: 2,
+                shares_accepted: 1,
+                shares_rejected: 1,
+                submit_latency_ms: Some(20.0),
+                disconnects: BTreeMap::from([(String::from("read_error"), 1)]),
+            }
+        );
+        assert!(stats[1].current);
+        assert_eq!(stats[1].address, "pool.example.com:8181");
+        assert_eq!((stats[1].connect_attempts, stats[1].sessions), (1, 1));
+        assert_eq!(stats[1].connected_ms, 250);
+        assert_eq!(stats[1].submit_latency_ms, None);
+        // switching closes the open session, and wraps around
+        assert_eq!(log.next_pool(start + ms(3000)), 0);
+        assert_eq!(log.stats(start + ms(9000))[1].connected_ms, 1000);
+
+        let table = PoolStats::format_table(&stats);
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[1].starts_with("  #0 "));
+        assert!(lines[1].contains(" 1/2 "));
+        assert!(lines[1].contains(" 20.0ms "));
+        assert!(lines[1].ends_with("127.0.0.1:8181           read_error 1"));
+        assert!(lines[2].starts_with("* #1 "));
+        assert!(lines[2].ends_with(" none"));
+    }
+}
@@ -0,0 +1,344 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! The wire format a client must keep to, checked field by field so that a change to the
+//! client's messages can not slip past the pool unnoticed.
+
+use anyhow::{anyhow, Result};
+use serde_json::{Map, Value};
+
+/// Checks one line a client sent against the stratum wire format: the exact method strings,
+/// every field present with its type and nothing else, ids going up from `last_id`, and the
+/// randomness as 16 lowercase hex digits, or 64 for a 32 byte randomness. Whether the pool
+/// agreed to the optional fields is not known here. Returns the message id.
+pub fn validate_client_message(line: &str, last_id: Option<i64>) -> Result<i64> {
+    let message: Map<String, Value> =
+        serde_json::from_str(line).map_err(|error| anyhow!("not a JSON object: {}", error))?;
+    let id = message
+        .get("id")
+        .ok_or_else(|| anyhow!("no id"))?
+        .as_i64()
+        .ok_or_else(|| anyhow!("id is not an integer"))?;
+    if let Some(last_id) = last_id {
+        if id <= last_id {
+            return Err(anyhow!("id {} does not follow id {}", id, last_id));
+        }
+    }
+    let method = message
+        .get("method")
+        .ok_or_else(|| anyhow!("no method"))?
+        .as_str()
+        .ok_or_else(|| anyhow!("method is not a string"))?;
+    no_unknown_fields(&message, &["id", "method", "body"], "message")?;
+    let body = match message.get("body") {
+        Some(Value::Object(body)) => body,
+        Some(_) => return Err(anyhow!("body is not an object")),
+        None => return Err(anyhow!("{} has no body", method)),
+    };
+    match method {
+        "mining.subscribe" => validate_subscribe(body)?,
+        "mining.submit" => validate_submit(body)?,
+        "mining.hashrate" => validate_hashrate(body)?,
+        "mining.authorize" => validate_authorize(body)?,
+        _ => return Err(anyhow!("unexpected method '{}'", method)),
+    }
+    Ok(id)
+}
+
+fn validate_subscribe(body: &Map<String, Value>) -> Result<()> {
+    no_unknown_fields(
+        body,
+        &[
+            "version",
+            "name",
+            "publicAddress",
+            "agent",
+            "graffitiSuffix",
+            "capabilities",
+        ],
+        "subscribe body",
+    )?;
+    if !matches!(body.get("version"), Some(version) if version.is_u64()) {
+        return Err(anyhow!("version is not a non-negative integer"));
+    }
+    if !matches!(body.get("name"), Some(Value::String(_))) {
+        return Err(anyhow!("name is not a string"));
+    }
+    // left out when not set, never null
+    for field in ["publicAddress", "agent", "graffitiSuffix"] {
+        if !matches!(body.get(field), None | Some(Value::String(_))) {
+            return Err(anyhow!("{} is not a string", field));
+        }
+    }
+    match body.get("capabilities") {
+        None => {}
+        Some(Value::Array(capabilities)) if capabilities.is_empty() => {
+            return Err(anyhow!("capabilities is empty instead of left out"))
+        }
+        Some(Value::Array(capabilities)) if capabilities.iter().all(Value::is_string) => {}
+        Some(_) => return Err(anyhow!("capabilities is not a list of strings")),
+    }
+    Ok(())
+}
+
+fn validate_submit(body: &Map<String, Value>) -> Result<()> {
+    no_unknown_fields(
+        body,
+        &["miningRequestId", "randomness", "elapsedMs"],
+        "submit body",
+    )?;
+    match body.get("miningRequestId").and_then(Value::as_u64) {
+        Some(id) if u32::try_from(id).is_ok() => {}
+        _ => return Err(anyhow!("miningRequestId is not a 32 bit unsigned integer")),
+    }
+    match body.get("randomness") {
+        Some(Value::String(randomness))
+            if (randomness.len() == 16 || randomness.len() == 64)
+                && randomness
+                    .chars()
+                    .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c)) => {}
+        Some(Value::String(randomness)) => {
+            return Err(anyhow!(
+                "randomness '{}' is not 16 lowercase hex digits, or 64 for a 32 byte randomness",
+                randomness
+            ))
+        }
+        _ => return Err(anyhow!("randomness is not a string")),
+    }
+    // left out when not set, never null
+    match body.get("elapsedMs") {
+        None => {}
+        Some(elapsed) if elapsed.is_u64() => {}
+        Some(_) => return Err(anyhow!("elapsedMs is not a non-negative integer")),
+    }
+    Ok(())
+}
+
+fn validate_hashrate(body: &Map<String, Value>) -> Result<()> {
+    no_unknown_fields(body, &["hashrate", "name"], "hashrate body")?;
+    match body.get("hashrate").and_then(Value::as_f64) {
+        Some(hashrate) if hashrate >= 0.0 => {}
+        _ => return Err(anyhow!("hashrate is not a non-negative number")),
+    }
+    if !matches!(body.get("name"), Some(Value::String(_))) {
+        return Err(anyhow!("name is not a string"));
+    }
+    Ok(())
+}
+
+fn validate_authorize(body: &Map<String, Value>) -> Result<()> {
+    no_unknown_fields(body, &["token"], "authorize body")?;
+    match body.get("token") {
+        Some(Value::String(token)) if !token.is_empty() => Ok(()),
+        _ => Err(anyhow!("token is not a non-empty string")),
+    }
+}
+
+fn no_unknown_fields(object: &Map<String, Value>, known: &[&str], what: &str) -> Result<()> {
+    match object.keys().find(|key| !known.contains(&key.as_str())) {
+        Some(key) => Err(anyhow!("unknown field '{}' in {}", key, what)),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        HeaderLayout, MiningHashrateBody, MiningHashrateMessage, MiningSubmitBody,
+        MiningSubmitMessage, MiningSubscribeMessage, Randomness, StratumClientConfig,
+        StratumMessage, StratumMessageCodec,
+    };
+    use bytes::BytesMut;
+    use clap::Parser;
+    use tokio_util::codec::{Decoder, Encoder};
+
+    fn encode(message: StratumMessage) -> String {
+        let mut buf = BytesMut::new();
+        StratumMessageCodec::default()
+            .encode(message, &mut buf)
+            .unwrap();
+        String::from_utf8(buf.to_vec())
+            .unwrap()
+            .trim_end()
+            .to_string()
+    }
+
+    #[test]
+    fn test_client_encoder_output() {
+        let cli = crate::Cli::parse_from([
+            "zkwork_ironminer",
+            "--pool",
+            "127.0.0.1:8181",
+            "--address",
+            "91f65bdad677058f",
+        ]);
+        let config = StratumClientConfig::from_cli(&cli).unwrap();
+        let subscribe = encode(StratumMessage::MiningSubscribeMessage(
+            MiningSubscribeMessage {
+                id: 0.into(),
+                method: String::from("mining.subscribe"),
+                body: config.subscribe_body(),
+            },
+        ));
+        assert_eq!(validate_client_message(&subscribe, None).unwrap(), 0);
+        let submit = encode(StratumMessage::MiningSubmitMessage(MiningSubmitMessage {
+            id: 1.into(),
+            method: String::from("mining.submit"),
+            body: MiningSubmitBody {
+                miningRequestId: 7,
+                randomness: Randomness::from_value(0x1234, &HeaderLayout::IRONFISH).to_wire_hex(),
+                elapsedMs: Some(250),
+            },
+        }));
+        assert_eq!(validate_client_message(&submit, Some(0)).unwrap(), 1);
+        // the same id again
+        assert!(validate_client_message(&submit, Some(1)).is_err());
+        let hashrate = encode(StratumMessage::MiningHashrateMessage(
+            MiningHashrateMessage {
+                id: 2.into(),
+                method: String::from("mining.hashrate"),
+                body: MiningHashrateBody {
+                    hashrate: 0.0,
+                    name: String::from("w"),
+                },
+            },
+        ));
+        assert_eq!(validate_client_message(&hashrate, Some(1)).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_submit_round_trip() {
+        let submit = |randomness: String| {
+            StratumMessage::MiningSubmitMessage(MiningSubmitMessage {
+                id: 3.into(),
+                method: String::from("mining.submit"),
+                body: MiningSubmitBody {
+                    miningRequestId: 7,
+                    randomness,
+                    elapsedMs: None,
+                },
+            })
+        };
+        // width 8 is byte for byte what pools have always been sent
+        let narrow = encode(submit(
+            Randomness::from_value(0x04d2, &HeaderLayout::IRONFISH).to_wire_hex(),
+        ));
+        assert_eq!(
+            narrow,
+            r#"{"id":3,"method":"mining.submit","body":{"miningRequestId":7,"randomness":"00000000000004d2"}}"#
+        );
+        assert_eq!(validate_client_message(&narrow, None).unwrap(), 3);
+
+        let wide = HeaderLayout::IRONFISH.with_randomness_width(32).unwrap();
+        let mut header = vec![0x11; wide.header_size];
+        header[8..32].fill(0xab);
+        let randomness = Randomness::in_header(0x04d2, &header, &wide).unwrap();
+        let line = encode(submit(randomness.to_wire_hex()));
+        assert_eq!(validate_client_message(&line, None).unwrap(), 3);
+        let mut buf = BytesMut::from(format!("{}\n", line).as_str());
+        let decoded = match StratumMessageCodec::default().decode(&mut buf).unwrap() {
+            Some(StratumMessage::MiningSubmitMessage(message)) => message.body.randomness,
+            other => panic!("decoded {:?}", other),
+        };
+        assert_eq!(
+            Randomness::from_wire_hex(&decoded, &wide).unwrap(),
+            randomness
+        );
+        assert!(Randomness::from_wire_hex(&decoded, &HeaderLayout::IRONFISH).is_err());
+    }
+
+    #[test]
+    fn test_violations() {
+        let subscribe = r#"{"id":0,"method":"mining.subscribe","body":{"version":1,"name":"w","publicAddress":"a"}}"#;
+        assert!(validate_client_message(subscribe, None).is_ok());
+        let suffixed = r#"{"id":0,"method":"mining.subscribe","body":{"version":1,"name":"w","publicAddress":"a","graffitiSuffix":"-rig1"}}"#;
+        assert!(validate_client_message(suffixed, None).is_ok());
+        let tokened = r#"{"id":0,"method":"mining.subscribe","body":{"version":1,"name":"w"}}"#;
+        assert!(validate_client_message(tokened, None).is_ok());
+        let authorize = r#"{"id":1,"method":"mining.authorize","body":{"token":"t"}}"#;
+        assert!(validate_client_message(authorize, Some(0)).is_ok());
+        let submit = r#"{"id":3,"method":"mining.submit","body":{"miningRequestId":7,"randomness":"00000000000004d2"}}"#;
+        assert!(validate_client_message(submit, Some(2)).is_ok());
+        for (line, error) in [
+            ("not json", "not a JSON object"),
+            (
+                r#"{"id":"0","method":"mining.subscribe","body":{}}"#,
+                "id is not an integer",
+            ),
+            (
+                r#"{"id":0,"method":"mining.Subscribe","body":{}}"#,
+                "unexpected method",
+            ),
+            (r#"{"id":0,"method":"mining.subscribe"}"#, "has no body"),
+            (
+                r#"{"id":0,"method":"mining.subscribe","body":{"version":1,"name":"w","publicAddress":"a"},"jsonrpc":"2.0"}"#,
+                "unknown field 'jsonrpc' in message",
+            ),
+            (
+                r#"{"id":0,"method":"mining.subscribe","body":{"version":-1,"name":"w","publicAddress":"a"}}"#,
+                "version",
+            ),
+            (
+                r#"{"id":0,"method":"mining.subscribe","body":{"version":1,"name":"w","publicAddress":"a","agent":null}}"#,
+                "agent",
+            ),
+            (
+                r#"{"id":0,"method":"mining.subscribe","body":{"version":1,"name":"w","publicAddress":"a","graffitiSuffix":1}}"#,
+                "graffitiSuffix is not a string",
+            ),
+            (
+                r#"{"id":0,"method":"mining.subscribe","body":{"version":1,"name":"w","publicAddress":"a","capabilities":[]}}"#,
+                "capabilities is empty",
+            ),
+            (
+                r#"{"id":0,"method":"mining.submit","body":{"miningRequestId":7,"randomness":"00000000000004D2"}}"#,
+                "16 lowercase hex digits",
+            ),
+            (
+                r#"{"id":0,"method":"mining.submit","body":{"miningRequestId":7,"randomness":"4d2"}}"#,
+                "16 lowercase hex digits",
+            ),
+            (
+                r#"{"id":0,"method":"mining.submit","body":{"miningRequestId":4294967296,"randomness":"00000000000004d2"}}"#,
+                "miningRequestId",
+            ),
+            (
+                r#"{"id":0,"method":"mining.submit","body":{"miningRequestId":7,"randomness":"00000000000004d2","nonce":1}}"#,
+                "unknown field 'nonce' in submit body",
+            ),
+            (
+                r#"{"id":0,"method":"mining.submit","body":{"miningRequestId":7,"randomness":"00000000000004d2","elapsedMs":null}}"#,
+                "elapsedMs is not a non-negative integer",
+            ),
+            (
+                r#"{"id":0,"method":"mining.submit","body":{"miningRequestId":7,"randomness":"00000000000004d2","elapsedMs":-1}}"#,
+                "elapsedMs is not a non-negative integer",
+            ),
+            (
+                r#"{"id":0,"method":"mining.hashrate","body":{"hashrate":"1.5","name":"w"}}"#,
+                "hashrate is not a non-negative number",
+            ),
+            (
+                r#"{"id":0,"method":"mining.hashrate","body":{"hashrate":1.5}}"#,
+                "name is not a string",
+            ),
+            (
+                r#"{"id":0,"method":"mining.subscribe","body":{"version":1,"name":"w","publicAddress":null}}"#,
+                "publicAddress is not a string",
+            ),
+            (
+                r#"{"id":0,"method":"mining.authorize","body":{"token":""}}"#,
+                "token is not a non-empty string",
+            ),
+        ] {
+            let violation = validate_client_message(line, None).unwrap_err().to_string();
+            assert!(violation.contains(error), "{}: {}", line, violation);
+        }
+        assert!(validate_client_message(submit, Some(3))
+            .unwrap_err()
+            .to_string()
+            .contains("does not follow"));
+    }
+}
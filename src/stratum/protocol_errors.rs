@@ -0,0 +1,128 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! Lines from the peer that are not usable messages, kept apart from transport errors so that
+//! a pool sending data we can't parse can be told from a bad link.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    fmt,
+    time::{Duration, Instant},
+};
+
+/// The window `--max-protocol-errors-per-min` counts over.
+const PROTOCOL_ERROR_WINDOW: Duration = Duration::from_secs(60);
+
+/// What was wrong with a line from the peer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProtocolErrorKind {
+    /// Not JSON, or not UTF-8.
+    Decode,
+    /// JSON, but not shaped like any stratum message.
+    WrongShape,
+    /// A stratum message with a method this miner does not know.
+    UnknownMethod,
+    /// Longer than any stratum message, usually framing gone out of sync.
+    Oversize,
+}
+
+/// Protocol errors by kind.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProtocolErrors {
+    pub decode_errors: u64,
+    pub wrong_shape: u64,
+    pub unknown_methods: u64,
+    pub oversize_frames: u64,
+}
+
+impl ProtocolErrors {
+    pub fn record(&mut self, kind: ProtocolErrorKind) {
+        match kind {
+            ProtocolErrorKind::Decode => self.decode_errors += 1,
+            ProtocolErrorKind::WrongShape => self.wrong_shape += 1,
+            ProtocolErrorKind::UnknownMethod => self.unknown_methods += 1,
+            ProtocolErrorKind::Oversize => self.oversize_frames += 1,
+        }
+    }
+
+    pub fn add(&mut self, other: &ProtocolErrors) {
+        self.decode_errors += other.decode_errors;
+        self.wrong_shape += other.wrong_shape;
+        self.unknown_methods += other.unknown_methods;
+        self.oversize_frames += other.oversize_frames;
+    }
+
+    pub fn total(&self) -> u64 {
+        self.decode_errors + self.wrong_shape + self.unknown_methods + self.oversize_frames
+    }
+}
+
+/// The peer sent more protocol errors in a minute than allowed.
+#[derive(Debug)]
+pub struct TooManyProtocolErrors {
+    pub limit: u32,
+}
+
+impl fmt::Display for TooManyProtocolErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "more than {} protocol errors within a minute",
+            self.limit
+        )
+    }
+}
+
+impl std::error::Error for TooManyProtocolErrors {}
+
+/// Counts protocol errors over the last minute against a limit.
+#[derive(Debug)]
+pub struct ProtocolErrorLimit {
+    limit: u32,
+    errors: VecDeque<Instant>,
+}
+
+impl ProtocolErrorLimit {
+    pub fn new(limit: u32) -> Self {
+        Self {
+            limit,
+            errors: VecDeque::new(),
+        }
+    }
+
+    /// Records an error at `now`, and fails once there were more than the limit within the
+    /// window.
+    pub fn record(&mut self, now: Instant) -> Result<(), TooManyProtocolErrors> {
+        while matches!(self.errors.front(), Some(at) if now.saturating_duration_since(*at) >= PROTOCOL_ERROR_WINDOW)
+        {
+            self.errors.pop_front();
+        }
+        self.errors.push_back(now);
+        if self.errors.len() > self.limit as usize {
+            return Err(TooManyProtocolErrors { limit: self.limit });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_protocol_error_limit() {
+        let start = Instant::now();
+        let mut limit = ProtocolErrorLimit::new(2);
+        assert!(limit.record(start).is_ok());
+        assert!(limit.record(start + Duration::from_secs(10)).is_ok());
+        assert!(limit.record(start + Duration::from_secs(20)).is_err());
+        // the first errors age out of the window
+        let mut limit = ProtocolErrorLimit::new(2);
+        assert!(limit.record(start).is_ok());
+        assert!(limit.record(start + Duration::from_secs(30)).is_ok());
+        assert!(limit.record(start + Duration::from_secs(61)).is_ok());
+        assert!(limit.record(start + Duration::from_secs(62)).is_err());
+    }
+}
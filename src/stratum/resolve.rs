@@ -0,0 +1,345 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use anyhow::{anyhow, Result};
+use futures::future::BoxFuture;
+use log::*;
+use std::{
+    collections::HashMap,
+    fmt, io,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+use tokio::sync::Mutex;
+
+/// Longest wait for one lookup, so that a resolver that is down does not hold up the
+/// reconnect.
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A pool to connect to, an IP address or a hostname with a port, e.g. "pool.zk.work:8181".
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct PoolAddress {
+    host: String,
+    port: u16,
+}
+
+impl PoolAddress {
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// The address to connect to if the host is an IP address, no lookup needed.
+    pub fn socket_addr(&self) -> Option<SocketAddr> {
+        self.host
+            .parse::<IpAddr>()
+            .ok()
+            .map(|ip| SocketAddr::new(ip, self.port))
+    }
+}
+
+impl From<SocketAddr> for PoolAddress {
+    fn from(address: SocketAddr) -> Self {
+        Self {
+            host: address.ip().to_string(),
+            port: address.port(),
+        }
+    }
+}
+
+impl FromStr for PoolAddress {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Ok(address) = s.parse::<SocketAddr>() {
+            return Ok(address.into());
+        }
+        let (host, port) = s
+            .rsplit_once(':')
+            .ok_or_else(|| anyhow!("expected <host>:<port>, got '{}'", s))?;
+        if host.is_empty() || host.contains(':') || host.contains(char::is_whitespace) {
+            return Err(anyhow!("invalid pool host '{}'", host));
+        }
+        let port: u16 = port
+            .parse()
+            .map_err(|_| anyhow!("invalid pool port '{}'", port))?;
+        if port == 0 {
+            return Err(anyhow!("pool port must be greater than 0"));
+        }
+        Ok(Self {
+            host: host.to_ascii_lowercase(),
+            port,
+        })
+    }
+}
+
+impl fmt::Display for PoolAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.socket_addr() {
+            Some(address) => write!(f, "{}", address),
+            None => write!(f, "{}:{}", self.host, self.port),
+        }
+    }
+}
+
+/// Looks up the addresses of a host.
+pub trait Resolve: fmt::Debug + Send + Sync {
+    fn lookup<'a>(&'a self, host: &'a str, port: u16)
+        -> BoxFuture<'a, io::Result<Vec<SocketAddr>>>;
+}
+
+/// Asks the system resolver.
+#[derive(Debug, Default)]
+pub struct SystemResolver;
+
+impl Resolve for SystemResolver {
+    fn lookup<'a>(
+        &'a self,
+        host: &'a str,
+        port: u16,
+    ) -> BoxFuture<'a, io::Result<Vec<SocketAddr>>> {
+        Box::pin(async move { Ok(tokio::net::lookup_host((host, port)).await?.collect()) })
+    }
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    addresses: Vec<SocketAddr>,
+    looked_up_at: Instant,
+    /// The address the last successful connect went to.
+    known_good: Option<SocketAddr>,
+}
+
+/// Resolves pool hostnames at most once per `ttl`, so that reconnecting every few seconds
+/// during an outage does not hammer the resolver, and keeps connecting to the addresses it
+/// has when the resolver itself is down. Pools given by IP address are never looked up.
+#[derive(Debug)]
+pub struct ResolverCache {
+    resolver: Box<dyn Resolve>,
+    ttl: Duration,
+    entries: Mutex<HashMap<PoolAddress, CacheEntry>>,
+}
+
+impl ResolverCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self::with_resolver(Box::new(SystemResolver), ttl)
+    }
+
+    pub fn with_resolver(resolver: Box<dyn Resolve>, ttl: Duration) -> Self {
+        Self {
+            resolver,
+            ttl,
+            entries: Default::default(),
+        }
+    }
+
+    /// The address to connect to `pool` at as of `now`.
+    pub async fn resolve(&self, pool: &PoolAddress, now: Instant) -> Result<SocketAddr> {
+        if let Some(address) = pool.socket_addr() {
+            return Ok(address);
+        }
+        let mut entries = self.entries.lock().await;
+        if let Some(entry) = entries.get(pool) {
+            if now.saturating_duration_since(entry.looked_up_at) < self.ttl {
+                if let Some(address) = entry.preferred() {
+                    return Ok(address);
+                }
+            }
+        }
+        let lookup =
+            tokio::time::timeout(LOOKUP_TIMEOUT, self.resolver.lookup(&pool.host, pool.port))
+                .await
+                .unwrap_or_else(|_| Err(io::Error::from(io::ErrorKind::TimedOut)))
+                .and_then(|addresses| match addresses.is_empty() {
+                    true => Err(io::Error::new(io::ErrorKind::NotFound, "no addresses")),
+                    false => Ok(addresses),
+                });
+        match (lookup, entries.get_mut(pool)) {
+            (Ok(addresses), Some(entry)) => {
+                if entry.addresses != addresses {
+                    info!("pool({}) now resolves to {:?}", pool, addresses);
+                }
+                entry.addresses = addresses;
+                entry.looked_up_at = now;
+                Ok(entry.preferred().unwrap())
+            }
+            (Ok(addresses), None) => {
+                debug!("pool({}) resolves to {:?}", pool, addresses);
+                let address = addresses[0];
+                entries.insert(
+                    pool.clone(),
+                    CacheEntry {
+                        addresses,
+                        looked_up_at: now,
+                        known_good: None,
+                    },
+                );
+                Ok(address)
+            }
+            (Err(error), Some(entry)) if entry.preferred().is_some() => {
+                // try again once the ttl is over, not on every reconnect
+                entry.looked_up_at = now;
+                let address = entry.preferred().unwrap();
+                warn!(
+                    "failed to resolve pool({}): {}, falling back to {}",
+                    pool, error, address
+                );
+                Ok(address)
+            }
+            (Err(error), _) => Err(anyhow!("failed to resolve pool({}): {}", pool, error)),
+        }
+    }
+
+    /// Remembers that connecting to `pool` at `address` worked.
+    pub async fn mark_good(&self, pool: &PoolAddress, address: SocketAddr) {
+        if let Some(entry) = self.entries.lock().await.get_mut(pool) {
+            entry.known_good = Some(address);
+        }
+    }
+}
+
+impl CacheEntry {
+    /// The known good address while the pool still resolves to it, the first one otherwise.
+    fn preferred(&self) -> Option<SocketAddr> {
+        self.known_good
+            .filter(|address| self.addresses.contains(address))
+            .or_else(|| self.addresses.first().copied())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{collections::VecDeque, sync::Arc};
+
+    /// Answers lookups from a script and counts them.
+    #[derive(Debug, Default)]
+    struct MockResolver {
+        answers: std::sync::Mutex<VecDeque<io::Result<Vec<SocketAddr>>>>,
+        lookups: std::sync::Mutex<usize>,
+    }
+
+    impl MockResolver {
+        fn answer(&self, answer: io::Result<Vec<SocketAddr>>) {
+            self.answers.lock().unwrap().push_back(answer);
+        }
+
+        fn lookups(&self) -> usize {
+            *self.lookups.lock().unwrap()
+        }
+    }
+
+    impl Resolve for Arc<MockResolver> {
+        fn lookup<'a>(&'a self, _: &'a str, _: u16) -> BoxFuture<'a, io::Result<Vec<SocketAddr>>> {
+            *self.lookups.lock().unwrap() += 1;
+            let answer = self.answers.lock().unwrap().pop_front().unwrap();
+            Box::pin(async move { answer })
+        }
+    }
+
+    fn address(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse_pool_address() {
+        let pool: PoolAddress = "Pool.zk.work:8181".parse().unwrap();
+        assert_eq!(pool.to_string(), "pool.zk.work:8181");
+        assert_eq!(pool.socket_addr(), None);
+        let pool: PoolAddress = "127.0.0.1:8181".parse().unwrap();
+        assert_eq!(pool.socket_addr(), Some(address("127.0.0.1:8181")));
+        let pool: PoolAddress = "[::1]:8181".parse().unwrap();
+        assert_eq!(pool.to_string(), "[::1]:8181");
+        assert!("pool.zk.work".parse::<PoolAddress>().is_err());
+        assert!(":8181".parse::<PoolAddress>().is_err());
+        assert!("pool.zk.work:0".parse::<PoolAddress>().is_err());
+        assert!("::1:8181".parse::<PoolAddress>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_resolve_once_per_ttl() {
+        let resolver = Arc::new(MockResolver::default());
+        let cache =
+            ResolverCache::with_resolver(Box::new(resolver.clone()), Duration::from_secs(60));
+        let pool: PoolAddress = "pool.zk.work:8181".parse().unwrap();
+        let start = Instant::now();
+
+        // NXDOMAIN with nothing cached fails, and is retried on the next reconnect
+        resolver.answer(Err(io::Error::new(io::ErrorKind::NotFound, "nxdomain")));
+        assert!(cache.resolve(&pool, start).await.is_err());
+        resolver.answer(Ok(vec![address("10.0.0.1:8181"), address("10.0.0.2:8181")]));
+        assert_eq!(
+            cache.resolve(&pool, start).await.unwrap(),
+            address("10.0.0.1:8181")
+        );
+        assert_eq!(resolver.lookups(), 2);
+
+        // reconnects within the ttl reuse the cache
+        cache.mark_good(&pool, address("10.0.0.2:8181")).await;
+        let later = start + Duration::from_secs(30);
+        assert_eq!(
+            cache.resolve(&pool, later).await.unwrap(),
+            address("10.0.0.2:8181")
+        );
+        assert_eq!(resolver.lookups(), 2);
+
+        // a timeout after the ttl falls back to the known good address, without another
+        // lookup until the ttl is over again
+        resolver.answer(Err(io::Error::from(io::ErrorKind::TimedOut)));
+        let later = start + Duration::from_secs(61);
+        assert_eq!(
+            cache.resolve(&pool, later).await.unwrap(),
+            address("10.0.0.2:8181")
+        );
+        assert_eq!(
+            cache
+                .resolve(&pool, later + Duration::from_secs(2))
+                .await
+                .unwrap(),
+            address("10.0.0.2:8181")
+        );
+        assert_eq!(resolver.lookups(), 3);
+
+        // a changed record replaces the cached addresses
+        resolver.answer(Ok(vec![address("10.0.0.3:8181")]));
+        let later = later + Duration::from_secs(61);
+        assert_eq!(
+            cache.resolve(&pool, later).await.unwrap(),
+            address("10.0.0.3:8181")
+        );
+        assert_eq!(resolver.lookups(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_resolve_per_pool() {
+        let resolver = Arc::new(MockResolver::default());
+        let cache =
+            ResolverCache::with_resolver(Box::new(resolver.clone()), Duration::from_secs(60));
+        let now = Instant::now();
+        resolver.answer(Ok(vec![address("10.0.0.1:8181")]));
+        resolver.answer(Ok(vec![address("10.0.1.1:8181")]));
+        let first: PoolAddress = "a.zk.work:8181".parse().unwrap();
+        let second: PoolAddress = "b.zk.work:8181".parse().unwrap();
+        assert_eq!(
+            cache.resolve(&first, now).await.unwrap(),
+            address("10.0.0.1:8181")
+        );
+        assert_eq!(
+            cache.resolve(&second, now).await.unwrap(),
+            address("10.0.1.1:8181")
+        );
+        assert_eq!(
+            cache.resolve(&first, now).await.unwrap(),
+            address("10.0.0.1:8181")
+        );
+
+        // IP addresses are never looked up
+        let ip: PoolAddress = "127.0.0.1:8181".parse().unwrap();
+        assert_eq!(
+            cache.resolve(&ip, now).await.unwrap(),
+            address("127.0.0.1:8181")
+        );
+        assert_eq!(resolver.lookups(), 2);
+    }
+}
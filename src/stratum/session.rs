@@ -0,0 +1,419 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::{BoundedQueue, BufferUsage, DisconnectReason, MAX_FIRST_SHARES, MAX_SESSION_HISTORY};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+/// Milliseconds from the start of the miner, or from the loss of the previous session, until
+/// a session reached each step on the way to its first share.
+///
+/// The pool does not acknowledge submits, so the first share counts as submitted once it is
+/// written to the connection.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FirstShareTimes {
+    pub subscribed_ms: Option<u64>,
+    pub first_notify_ms: Option<u64>,
+    pub first_share_found_ms: Option<u64>,
+    pub first_share_submitted_ms: Option<u64>,
+}
+
+impl FirstShareTimes {
+    /// Each step of `times` at its median over the sessions that reached it.
+    fn median(times: &[FirstShareTimes]) -> Self {
+        let median = |step: fn(&FirstShareTimes) -> Option<u64>| {
+            let mut values = times.iter().filter_map(step).collect::<Vec<_>>();
+            values.sort_unstable();
+            match values.len() {
+                0 => None,
+                len if len % 2 == 1 => Some(values[len / 2]),
+                len => Some((values[len / 2 - 1] + values[len / 2]) / 2),
+            }
+        };
+        Self {
+            subscribed_ms: median(|times| times.subscribed_ms),
+            first_notify_ms: median(|times| times.first_notify_ms),
+            first_share_found_ms: median(|times| times.first_share_found_ms),
+            first_share_submitted_ms: median(|times| times.first_share_submitted_ms),
+        }
+    }
+}
+
+/// The times to the first share of the current or last session, and their medians over the
+/// recent sessions.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FirstShareStats {
+    pub latest: Option<FirstShareTimes>,
+    pub median: FirstShareTimes,
+}
+
+/// Counters of one pool session, from a successful subscribe until the connection closes.
+///
+/// The pool does not acknowledge submits, so `shares_submitted` counts the shares written to
+/// the connection.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SessionStats {
+    pub epoch: u64,
+    /// The id the pool assigned to this connection, needed to look the session up pool side.
+    pub client_id: u64,
+    /// The entry of the pool list subscribed to, 0 for `--pool`, see [`crate::PoolStats`].
+    #[serde(default)]
+    pub pool_index: usize,
+    /// Decides which account the pool credits.
+    pub graffiti: String,
+    /// Unix time in seconds.
+    pub started_at: u64,
+    pub shares_found: u64,
+    pub shares_submitted: u64,
+    pub notifies: u64,
+    /// Time since the last `mining.notify`, or between it and the close of the session.
+    pub last_job_age_ms: Option<u64>,
+    /// Time since the last `mining.set_target`, or between it and the close of the session.
+    pub last_target_age_ms: Option<u64>,
+    /// Jobs that came with a target older than `--max-target-age`.
+    pub stale_target_jobs: u64,
+    /// Time from the subscribe until both a target and a job had arrived.
+    pub first_job_ms: Option<u64>,
+    /// How long the session has been waiting for its first job, while it still is.
+    pub waiting_for_first_job_ms: Option<u64>,
+    pub closed_at: Option<u64>,
+    pub close_reason: Option<DisconnectReason>,
+    /// What the pool agreed to use in the subscribe, `None` if it did not negotiate.
+    pub capabilities: Option<Vec<String>>,
+    pub first_share: FirstShareTimes,
+    /// The start of the miner, or the loss of the previous session.
+    #[serde(skip, default = "Instant::now")]
+    since: Instant,
+    #[serde(skip, default = "Instant::now")]
+    started: Instant,
+    #[serde(skip)]
+    last_job: Option<Instant>,
+    #[serde(skip)]
+    last_target: Option<Instant>,
+    #[serde(skip)]
+    closed: Option<Instant>,
+}
+
+impl SessionStats {
+    fn new(epoch: u64, client_id: u64, graffiti: &str, since: Instant, started_at: u64) -> Self {
+        let started = Instant::now();
+        Self {
+            epoch,
+            client_id,
+            pool_index: 0,
+            graffiti: graffiti.to_string(),
+            started_at,
+            shares_found: 0,
+            shares_submitted: 0,
+            notifies: 0,
+            last_job_age_ms: None,
+            last_target_age_ms: None,
+            stale_target_jobs: 0,
+            first_job_ms: None,
+            waiting_for_first_job_ms: None,
+            closed_at: None,
+            close_reason: None,
+            capabilities: None,
+            first_share: FirstShareTimes {
+                subscribed_ms: Some(millis(started - since)),
+                ..Default::default()
+            },
+            since,
+            started,
+            last_job: None,
+            last_target: None,
+            closed: None,
+        }
+    }
+
+    pub fn record_notify(&mut self) {
+        self.notifies += 1;
+        self.last_job = Some(Instant::now());
+        if self.first_share.first_notify_ms.is_none() {
+            self.first_share.first_notify_ms = Some(millis(self.since.elapsed()));
+        }
+    }
+
+    pub fn record_target(&mut self) {
+        self.last_target = Some(Instant::now());
+    }
+
+    /// How long ago the last target arrived, `None` before the first one.
+    pub fn target_age(&self) -> Option<Duration> {
+        self.last_target.map(|last_target| last_target.elapsed())
+    }
+
+    pub fn record_share_found(&mut self) {
+        self.shares_found += 1;
+        if self.first_share.first_share_found_ms.is_none() {
+            self.first_share.first_share_found_ms = Some(millis(self.since.elapsed()));
+        }
+    }
+
+    /// Returns the time to the first share if this was it.
+    pub fn record_share_submitted(&mut self) -> Option<Duration> {
+        self.shares_submitted += 1;
+        if self.first_share.first_share_submitted_ms.is_some() {
+            return None;
+        }
+        let elapsed = self.since.elapsed();
+        self.first_share.first_share_submitted_ms = Some(millis(elapsed));
+        Some(elapsed)
+    }
+
+    /// Records that mining started on the first job of the session.
+    pub fn record_first_job(&mut self) {
+        if self.first_job_ms.is_none() {
+            self.first_job_ms = Some(self.started.elapsed().as_millis() as u64);
+        }
+    }
+
+    pub fn is_waiting_for_first_job(&self) -> bool {
+        self.first_job_ms.is_none()
+    }
+
+    /// When the subscribe completed.
+    pub fn subscribed_at(&self) -> Instant {
+        self.started
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.closed.unwrap_or_else(Instant::now) - self.started
+    }
+
+    fn snapshot(&self) -> Self {
+        let mut session = self.clone();
+        let now = self.closed.unwrap_or_else(Instant::now);
+        session.last_job_age_ms = self
+            .last_job
+            .map(|last_job| (now - last_job).as_millis() as u64);
+        session.last_target_age_ms = self
+            .last_target
+            .map(|last_target| (now - last_target).as_millis() as u64);
+        if self.first_job_ms.is_none() && self.closed.is_none() {
+            session.waiting_for_first_job_ms = Some((now - self.started).as_millis() as u64);
+        }
+        session
+    }
+
+    pub fn format(&self) -> String {
+        let ago = |age: Option<u64>| match age {
+            Some(age) => format!("{}s ago", age / 1000),
+            None => String::from("none"),
+        };
+        format!(
+            "Session #{} (client id {}, graffiti {}): up {}s, {} shares found, {} submitted, {} jobs, last job {}, last target {}",
+            self.epoch,
+            self.client_id,
+            self.graffiti,
+            self.uptime().as_secs(),
+            self.shares_found,
+            self.shares_submitted,
+            self.notifies,
+            ago(self.last_job_age_ms),
+            ago(self.last_target_age_ms)
+        )
+    }
+}
+
+/// The current pool session and the most recently closed ones.
+#[derive(Debug)]
+pub struct SessionHistory {
+    next_epoch: u64,
+    current: Option<SessionStats>,
+    closed: BoundedQueue<SessionStats>,
+    /// What the times to the first share of the next session count from.
+    since: Instant,
+    /// The sessions the medians of [`FirstShareStats`] are taken over.
+    first_shares: BoundedQueue<FirstShareTimes>,
+}
+
+impl SessionHistory {
+    /// Keeps the last `max_closed` closed sessions, at most [`MAX_SESSION_HISTORY`].
+    pub fn new(max_closed: usize) -> Self {
+        Self {
+            next_epoch: 1,
+            current: None,
+            closed: BoundedQueue::new(max_closed.min(MAX_SESSION_HISTORY)),
+            since: Instant::now(),
+            first_shares: BoundedQueue::new(MAX_FIRST_SHARES),
+        }
+    }
+
+    /// Starts a new session at the unix timestamp `at`, closing a current one that was never
+    /// closed.
+    pub fn open(&mut self, client_id: u64, graffiti: &str, at: u64) -> u64 {
+        self.close(DisconnectReason::RemoteClosed, at);
+        let epoch = self.next_epoch;
+        self.next_epoch += 1;
+        self.current = Some(SessionStats::new(
+            epoch, client_id, graffiti, self.since, at,
+        ));
+        epoch
+    }
+
+    /// Sessions opened since the start.
+    pub fn started(&self) -> u64 {
+        self.next_epoch - 1
+    }
+
+    /// Closes the current session, if any, at the unix timestamp `at`. Returns the closed
+    /// session.
+    pub fn close(&mut self, reason: DisconnectReason, at: u64) -> Option<SessionStats> {
+        let mut session = self.current.take()?;
+        session.closed_at = Some(at);
+        session.closed = Some(Instant::now());
+        session.close_reason = Some(reason);
+        let session = session.snapshot();
+        self.since = Instant::now();
+        self.first_shares.push(session.first_share);
+        self.closed.push(session.clone());
+        Some(session)
+    }
+
+    pub fn current_mut(&mut self) -> Option<&mut SessionStats> {
+        self.current.as_mut()
+    }
+
+    pub fn current(&self) -> Option<SessionStats> {
+        self.current.as_ref().map(SessionStats::snapshot)
+    }
+
+    /// Closed sessions, oldest first.
+    pub fn closed(&self) -> Vec<SessionStats> {
+        self.closed.iter().cloned().collect()
+    }
+
+    /// How full the closed sessions and the first share times are.
+    pub fn usage(&self) -> (BufferUsage, BufferUsage) {
+        (self.closed.usage(), self.first_shares.usage())
+    }
+
+    pub fn first_share_stats(&self) -> FirstShareStats {
+        let mut times = self.first_shares.iter().copied().collect::<Vec<_>>();
+        times.extend(self.current.as_ref().map(|session| session.first_share));
+        FirstShareStats {
+            latest: times.last().copied(),
+            median: FirstShareTimes::median(&times),
+        }
+    }
+}
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session_history() {
+        let mut sessions = SessionHistory::new(2);
+        assert!(sessions.current().is_none());
+        assert!(sessions.close(DisconnectReason::StoppedByUser, 0).is_none());
+
+        assert_eq!(sessions.open(7, "zk.work", 1_665_000_000), 1);
+        let session = sessions.current_mut().unwrap();
+        session.shares_found += 1;
+        session.record_notify();
+        let current = sessions.current().unwrap();
+        assert!(current.waiting_for_first_job_ms.is_some());
+        assert!(current.last_target_age_ms.is_none());
+        sessions.current_mut().unwrap().record_target();
+        assert!(sessions.current().unwrap().last_target_age_ms.is_some());
+        sessions.current_mut().unwrap().record_first_job();
+        let current = sessions.current().unwrap();
+        assert!(current.first_job_ms.is_some());
+        assert!(current.waiting_for_first_job_ms.is_none());
+        assert_eq!(current.shares_found, 1);
+        assert_eq!(current.notifies, 1);
+        assert_eq!(current.client_id, 7);
+        assert_eq!(current.graffiti, "zk.work");
+        assert!(current.last_job_age_ms.is_some());
+        assert!(current.close_reason.is_none());
+
+        let closed = sessions
+            .close(
+                DisconnectReason::WriteError(std::io::ErrorKind::BrokenPipe),
+                1_665_000_060,
+            )
+            .unwrap();
+        assert_eq!(closed.epoch, 1);
+        assert_eq!(
+            closed.close_reason,
+            Some(DisconnectReason::WriteError(std::io::ErrorKind::BrokenPipe))
+        );
+        assert_eq!(closed.started_at, 1_665_000_000);
+        assert_eq!(closed.closed_at, Some(1_665_000_060));
+        assert!(sessions.current().is_none());
+
+        // counters start over and only the last two closed sessions are kept
+        assert_eq!(sessions.open(8, "zk.work", 0), 2);
+        assert_eq!(sessions.current().unwrap().shares_found, 0);
+        sessions.open(9, "zk.work", 0);
+        sessions.close(DisconnectReason::IdleTimeout, 0);
+        let closed = sessions.closed();
+        assert_eq!(closed.len(), 2);
+        assert_eq!(closed[0].epoch, 2);
+        assert_eq!(closed[0].close_reason, Some(DisconnectReason::RemoteClosed));
+        assert_eq!(closed[1].epoch, 3);
+        assert_eq!(closed[1].close_reason, Some(DisconnectReason::IdleTimeout));
+        let (closed, first_shares) = sessions.usage();
+        assert_eq!((closed.len, closed.cap, closed.dropped), (2, 2, 1));
+        assert_eq!(first_shares.len, 3);
+
+        // however many the command line asks for
+        assert_eq!(
+            SessionHistory::new(usize::MAX).usage().0.cap,
+            MAX_SESSION_HISTORY
+        );
+    }
+
+    #[test]
+    fn test_first_share_times() {
+        let mut sessions = SessionHistory::new(0);
+        assert_eq!(sessions.first_share_stats(), FirstShareStats::default());
+        sessions.open(7, "zk.work", 0);
+        let session = sessions.current_mut().unwrap();
+        session.record_notify();
+        session.record_share_found();
+        assert!(session.record_share_submitted().is_some());
+        // only the first share counts
+        assert!(session.record_share_submitted().is_none());
+        let times = sessions.first_share_stats().latest.unwrap();
+        let steps = [
+            times.subscribed_ms.unwrap(),
+            times.first_notify_ms.unwrap(),
+            times.first_share_found_ms.unwrap(),
+            times.first_share_submitted_ms.unwrap(),
+        ];
+        assert!(steps.windows(2).all(|pair| pair[0] <= pair[1]));
+
+        // a session that never got a job leaves the later medians to the others
+        sessions.close(DisconnectReason::RemoteClosed, 0);
+        sessions.open(8, "zk.work", 0);
+        let stats = sessions.first_share_stats();
+        assert_eq!(stats.latest.unwrap().first_notify_ms, None);
+        assert_eq!(stats.median.first_notify_ms, times.first_notify_ms);
+        assert!(stats.median.subscribed_ms.is_some());
+
+        let times = [10, 20, 30, 50]
+            .map(|ms| FirstShareTimes {
+                subscribed_ms: Some(ms),
+                ..Default::default()
+            })
+            .to_vec();
+        assert_eq!(FirstShareTimes::median(&times).subscribed_ms, Some(25));
+        assert_eq!(FirstShareTimes::median(&times[1..]).subscribed_ms, Some(30));
+    }
+
+    #[test]
+    fn test_serialize_reason() {
+        assert_eq!(
+            serde_json::to_string(&DisconnectReason::DecodeError).unwrap(),
+            "\"decode_error\""
+        );
+    }
+}
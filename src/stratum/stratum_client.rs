@@ -3,328 +3,3393 @@
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 
 use crate::{
-    Miner, MiningNotifyBody, MiningNotifyMessage, MiningSetTargetBody, MiningSetTargetMessage,
-    MiningSubmitBody, MiningSubmitMessage, MiningSubscribeBody, MiningSubscribeMessage,
-    MiningSubscribedBody, MiningSubscribedMessage, MiningWaitForWorkMessage, StratumMessage,
-    StratumMessageCodec,
+    monitored_channel, spawn_critical, user_agent, AssembledJob, Backoff, BoundedQueue,
+    BufferStats, ByteCounts, Capabilities, ChannelCounters, ChannelStats, Cli, Clock, ClockSkew,
+    ConnectionLog, ConnectionStats, Connector, CountingStream, Disconnect, DisconnectHistory,
+    DisconnectReason, DumpTap, FirstShareStats, FlapChange, FlapDetector, FlapPolicy, FlapStats,
+    HalfOpenDetector, HttpProxy, JobAssembler, LogLimiter, MessageCounts, MessageId, MinerCommand,
+    MiningHashrateBody, MiningHashrateMessage, MiningSubmitBody, MiningSubmitMessage,
+    MiningSubscribeBody, MiningSubscribedBody, MonitoredSender, PauseReason, PoolAddress, PoolEnd,
+    PoolEvent, PoolStats, PoolStatsLog, PortRange, ProtocolErrors, ResendDecision, ResolverCache,
+    RetryPolicy, SessionError, SessionEvent, SessionHandover, SessionHistory, SessionStats,
+    SharedClock, StratumDump, StratumMessage, StratumSession, SubmitConnection,
+    SubmitConnectionStats, SubmitLedger, SubmitLimiter, SubmitRate, SystemClock,
+    CAPABILITY_DEFLATE, CAPABILITY_HASHRATE, CAPABILITY_TIMESTAMPS, CHANNEL_CAPACITY,
+    MAX_HELD_SUBMITS, MAX_PENDING_SUBMITS, MAX_RESENDS, SEND_TIMEOUT, SUBMIT_WORKER_SUFFIX,
+    SUPPORTED_CAPABILITIES,
 };
+#[cfg(feature = "tls")]
+use crate::{negotiated_protocol, tls_connect};
 use anyhow::{anyhow, Result};
-use futures::SinkExt;
 use log::*;
 use std::{
-    net::SocketAddr,
     sync::{
-        atomic::{AtomicBool, AtomicI64, Ordering},
-        Arc, Weak,
+        atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering},
+        Arc, Mutex,
     },
     time::Duration,
 };
 use tokio::{
-    io::{split, AsyncRead, AsyncWrite},
+    io::{AsyncRead, AsyncWrite},
     net::TcpStream,
     sync::{mpsc, oneshot, RwLock},
     task,
+    time::{self, Instant},
 };
-use tokio_native_tls::{native_tls, TlsConnector};
-use tokio_stream::StreamExt;
-use tokio_util::codec::{FramedRead, FramedWrite};
+use tokio_util::sync::CancellationToken;
 
-type Router = mpsc::Sender<StratumClientRequest>;
+type Router = MonitoredSender<StratumClientRequest>;
 #[allow(dead_code)]
 type Handler = mpsc::Receiver<StratumClientRequest>;
 
+/// Submits that failed to be written are re-sent after the next subscribe, unless they have
+/// become older than this.
+const PENDING_SUBMIT_TTL: Duration = Duration::from_secs(60);
+/// A session that hears nothing from the pool for this long is considered dead. Pools send a
+/// job for every new block, about once a minute.
+pub const POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(600);
+const FIRST_JOB_LOG_INTERVAL: Duration = Duration::from_secs(10);
+/// How long `stop` waits for the connection task to end. A connect in progress is not
+/// interrupted.
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+/// Reconnects every 2s, less the jitter. While the pool flaps the delay doubles with every
+/// drop, up to 2 minutes.
+pub const RECONNECT_POLICY: RetryPolicy = RetryPolicy {
+    initial: Duration::from_secs(2),
+    multiplier: 2.0,
+    max_delay: Duration::from_secs(120),
+    jitter: 0.25,
+    max_attempts: None,
+    reset_on_success: true,
+};
+
+#[derive(Debug)]
 enum StratumClientRequest {
     Message(StratumMessage),
     Stop,
+    /// Leave the connection open for [`StratumClient::hand_over`].
+    HandOver,
+}
+
+#[derive(Debug)]
+struct PendingSubmit {
+    message: MiningSubmitMessage,
+    queued_at: Instant,
 }
 
 #[derive(Clone, Debug)]
 pub struct StratumClientConfig {
     pub tls: bool,
-    pub pool_address: SocketAddr,
-    pub public_address: String,
+    pub pool_address: PoolAddress,
+    /// Connected to in turn once `pool_address` can not be reached, see [`PoolStatsLog`].
+    pub failover_pools: Vec<PoolAddress>,
+    /// Connects in a row that did not end up subscribed before moving on to the next pool.
+    pub failover_after: u32,
+    /// The reward address, `None` for a pool that identifies workers by `auth_token`.
+    pub public_address: Option<String>,
+    /// Sent in a `mining.authorize` after every subscribe, see `--pass`.
+    pub auth_token: Option<String>,
     pub worker_name: String,
+    pub max_clock_skew: Duration,
+    /// How many closed sessions are kept for the stats.
+    pub session_history: usize,
+    /// Warn when no job has arrived this long after the subscribe.
+    pub first_job_timeout: Duration,
+    /// Reconnect instead of only warning when the first job timeout expires.
+    pub first_job_reconnect: bool,
+    /// How long a job is mined with no new one, zero for no limit. An expiry the pool sends
+    /// with the job takes precedence.
+    pub job_ttl: Duration,
+    /// Warn when a job arrives on a target older than this.
+    pub max_target_age: Duration,
+    /// Reconnect instead of mining a job on a target older than `max_target_age`.
+    pub max_target_age_reconnect: bool,
+    /// Warn when the pool keeps the miner waiting for work this long, zero for never.
+    pub max_wait_warn: Duration,
+    /// Reconnect once the pool has kept the miner waiting for work this long.
+    pub reconnect_on_long_wait: Option<Duration>,
+    /// Reconnect once nothing came in this long while frames kept going out, zero for never,
+    /// see [`HalfOpenDetector`].
+    pub half_open_timeout: Duration,
+    /// Local ports to connect from, any port if `None`.
+    pub source_ports: Option<PortRange>,
+    /// Reach the pool through this HTTP proxy, see [`HttpProxy`].
+    pub proxy: Option<HttpProxy>,
+    /// Skip undecodable lines from the pool instead of closing the session.
+    pub lenient_decode: bool,
+    /// Tell the pool which miner build connects, see [`user_agent`].
+    pub send_agent: bool,
+    /// Written after the pool's graffiti, and sent with the subscribe.
+    pub graffiti_suffix: Option<String>,
+    /// Shares found faster than this are delayed, and dropped if they keep coming.
+    pub max_submit_rate: SubmitRate,
+    /// Send a share whose write failed again after the reconnect, once, unless the pool
+    /// acknowledged it meanwhile, see [`SubmitLedger`].
+    pub resend_submits: bool,
+    /// How long the last job is mined after the connection is lost, zero to pause right away.
+    pub mine_through_disconnects: Duration,
+    /// How long a pool hostname lookup is reused.
+    pub dns_ttl: Duration,
+    /// Reconnect once the pool sends more protocol errors than this within a minute.
+    pub max_protocol_errors_per_min: Option<u32>,
+    /// Submit on a second connection of its own, see [`SubmitConnection`].
+    pub dual_connection: bool,
+    /// How often the hashrate is reported to a pool that agreed to
+    /// [`CAPABILITY_HASHRATE`], `None` to neither offer nor report it.
+    pub hashrate_report_interval: Option<Duration>,
+    /// Where the lines of the main connection are recorded, see [`StratumDump`].
+    pub dump: Option<Arc<StratumDump>>,
+    /// When the pool counts as flapping.
+    pub flap_policy: FlapPolicy,
+    /// The delay before reconnecting, which only grows while the pool flaps.
+    pub reconnect_policy: RetryPolicy,
+    /// Offer [`CAPABILITY_DEFLATE`], compressing the lines of a pool that agrees.
+    pub compress: bool,
+}
+
+impl StratumClientConfig {
+    /// The configuration for the pool the command line names.
+    pub fn from_cli(cli: &Cli) -> Result<Self> {
+        let pool_address = cli
+            .pool
+            .clone()
+            .ok_or_else(|| anyhow!("no pool address configured"))?;
+        Ok(Self {
+            tls: cli.tls,
+            pool_address,
+            failover_pools: cli.failover_pools.clone(),
+            failover_after: cli.failover_after,
+            public_address: Some(cli.address.clone()).filter(|address| !address.is_empty()),
+            auth_token: cli.pass.clone(),
+            worker_name: cli.worker_name.clone(),
+            max_clock_skew: Duration::from_secs(cli.max_clock_skew),
+            session_history: cli.session_history,
+            first_job_timeout: Duration::from_secs(cli.first_job_timeout),
+            first_job_reconnect: cli.first_job_reconnect,
+            job_ttl: Duration::from_secs(cli.job_ttl),
+            max_target_age: Duration::from_secs(cli.max_target_age * 60),
+            max_target_age_reconnect: cli.max_target_age_reconnect,
+            max_wait_warn: Duration::from_secs(cli.max_wait_warn),
+            reconnect_on_long_wait: cli.reconnect_on_long_wait.map(Duration::from_secs),
+            half_open_timeout: Duration::from_secs(cli.half_open_timeout),
+            source_ports: cli.source_port_range,
+            proxy: cli.proxy.clone(),
+            lenient_decode: cli.lenient_decode,
+            send_agent: cli.send_agent,
+            graffiti_suffix: cli
+                .graffiti_suffix
+                .clone()
+                .filter(|suffix| !suffix.is_empty()),
+            max_submit_rate: cli.max_submit_rate,
+            resend_submits: cli.resend_submits,
+            mine_through_disconnects: Duration::from_secs(cli.mine_through_disconnects),
+            dns_ttl: Duration::from_secs(cli.dns_ttl),
+            max_protocol_errors_per_min: cli.max_protocol_errors_per_min,
+            dual_connection: cli.dual_connection,
+            hashrate_report_interval: cli.hashrate_report_interval.map(Duration::from_secs),
+            dump: match &cli.dump_stratum {
+                Some(path) => Some(Arc::new(StratumDump::create(path)?)),
+                None => None,
+            },
+            flap_policy: FlapPolicy::default(),
+            reconnect_policy: RECONNECT_POLICY,
+            compress: cli.compress,
+        })
+    }
+
+    /// Every pool in the order they are tried, `pool_address` first.
+    pub fn pools(&self) -> Vec<PoolAddress> {
+        std::iter::once(&self.pool_address)
+            .chain(&self.failover_pools)
+            .cloned()
+            .collect()
+    }
+
+    /// What this worker subscribes with.
+    pub fn subscribe_body(&self) -> MiningSubscribeBody {
+        MiningSubscribeBody {
+            version: 1,
+            name: self.worker_name.clone(),
+            publicAddress: self.public_address.clone(),
+            agent: Some(user_agent()).filter(|_| self.send_agent),
+            graffitiSuffix: self.graffiti_suffix.clone(),
+            capabilities: SUPPORTED_CAPABILITIES
+                .into_iter()
+                .filter(|&capability| match capability {
+                    CAPABILITY_HASHRATE => self.hashrate_report_interval.is_some(),
+                    CAPABILITY_DEFLATE => self.compress,
+                    _ => true,
+                })
+                .map(String::from)
+                .collect(),
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct StratumClient {
+    client_id: RwLock<Option<u64>>,
+    /// What the pool agreed to on the last subscribe.
+    capabilities: RwLock<Capabilities>,
+    clock_skew: RwLock<ClockSkew>,
+    /// What the reconnect backoff and the idle timeouts wait on.
+    clock: SharedClock,
     config: StratumClientConfig,
-    miner: RwLock<Option<Weak<Miner>>>,
+    connector: Connector,
+    disconnects: RwLock<DisconnectHistory>,
+    connections: RwLock<ConnectionLog>,
+    /// Every pool in the order they are tried, see [`StratumClientConfig::pools`].
+    pools: Vec<PoolAddress>,
+    /// The totals of each pool, and which one the client is on.
+    pool_stats: Mutex<PoolStatsLog>,
+    /// Jobs that expired with no new one, over all sessions.
+    expired_jobs: AtomicU64,
+    /// Whether the pool keeps dropping the connection right after the subscribe.
+    flaps: Mutex<FlapDetector>,
+    /// The delay before the next reconnect, without the jitter.
+    reconnect_delay_ms: AtomicU64,
+    /// Ends the wait before a reconnect on stop, replaced on every start.
+    reconnect_cancel: Mutex<CancellationToken>,
+    /// Waits for work longer than `max_wait_warn`, over all sessions.
+    long_waits: AtomicU64,
+    /// Sessions ended as [`DisconnectReason::HalfOpenSuspected`].
+    half_open: AtomicU64,
+    graffiti: RwLock<Option<String>>,
+    /// Waits for the connection and the session while they are handed over.
+    handover: RwLock<Option<oneshot::Sender<(TcpStream, SessionHandover)>>>,
+    /// Until when mining goes on after the connection was lost.
+    grace_until: RwLock<Option<Instant>>,
+    /// Shares found during the grace period, submitted if the job is still current after the
+    /// reconnect.
+    held_submits: RwLock<BoundedQueue<MiningSubmitMessage>>,
+    log_limiter: LogLimiter,
+    /// Where the miner hears from this client, and submits through it.
+    bus: PoolEnd,
     next_message_id: AtomicI64,
+    pending_submits: RwLock<BoundedQueue<PendingSubmit>>,
+    /// Shares that went out again after a failed write.
+    shares_resent: AtomicU64,
+    /// Shares of a failed write kept from going out again, as acknowledged or re-sent before.
+    resends_suppressed: AtomicU64,
+    /// Protocol errors of the closed connections, and of the current one so far.
+    protocol_errors: RwLock<(ProtocolErrors, ProtocolErrors)>,
+    resolver: ResolverCache,
+    /// A session another process handed over, served instead of connecting on start.
+    resumed: RwLock<Option<(TcpStream, SessionHandover)>>,
     router: RwLock<Option<Router>>,
+    router_counters: Arc<ChannelCounters>,
+    sessions: RwLock<SessionHistory>,
     started: AtomicBool,
     stopped: AtomicBool,
+    /// The connection task, while started.
+    task: RwLock<Option<task::JoinHandle<()>>>,
+    /// Only with `dual_connection`.
+    submit_connection: Option<SubmitConnection>,
+    /// The task of the submit connection, while started.
+    submit_task: RwLock<Option<task::JoinHandle<()>>>,
+    /// Which shares were written, and which of them the pool acknowledged.
+    submit_ledger: Mutex<SubmitLedger>,
+    submit_limiter: SubmitLimiter,
+    submits_dropped: AtomicU64,
     subscribed: AtomicBool,
 }
 
 impl StratumClient {
+    /// A client without a miner, whose events go nowhere.
     pub fn new(config: StratumClientConfig) -> Arc<Self> {
+        Self::with_bus(config, PoolEnd::detached())
+    }
+
+    /// A client that tells the miner on the other end of `bus` what the pool sends, and
+    /// submits what it asks to.
+    pub fn with_bus(config: StratumClientConfig, bus: PoolEnd) -> Arc<Self> {
+        Self::with_clock(config, bus, SystemClock::shared())
+    }
+
+    /// [`Self::with_bus`] with the reconnect backoff and the idle timeouts on `clock`.
+    pub fn with_clock(config: StratumClientConfig, bus: PoolEnd, clock: SharedClock) -> Arc<Self> {
+        let pools = config.pools();
         Arc::new(Self {
-            config,
-            miner: Default::default(),
+            bus,
+            clock,
+            capabilities: Default::default(),
+            client_id: Default::default(),
+            clock_skew: RwLock::new(ClockSkew::new(config.max_clock_skew)),
+            sessions: RwLock::new(SessionHistory::new(config.session_history)),
+            connector: Connector::new(config.source_ports),
+            resolver: ResolverCache::new(config.dns_ttl),
+            submit_ledger: Mutex::new(SubmitLedger::new(if config.resend_submits {
+                MAX_RESENDS
+            } else {
+                0
+            })),
+            submit_limiter: SubmitLimiter::with_rate(config.max_submit_rate),
+            submit_connection: config.dual_connection.then(SubmitConnection::default),
+            disconnects: Default::default(),
+            connections: Default::default(),
+            pool_stats: Mutex::new(PoolStatsLog::new(&pools)),
+            pools,
+            expired_jobs: Default::default(),
+            flaps: Mutex::new(FlapDetector::new(config.flap_policy)),
+            reconnect_delay_ms: AtomicU64::new(config.reconnect_policy.initial.as_millis() as u64),
+            reconnect_cancel: Default::default(),
+            long_waits: Default::default(),
+            half_open: Default::default(),
+            graffiti: Default::default(),
+            handover: Default::default(),
+            grace_until: Default::default(),
+            held_submits: RwLock::new(BoundedQueue::new(MAX_HELD_SUBMITS)),
+            log_limiter: Default::default(),
             next_message_id: Default::default(),
+            pending_submits: RwLock::new(BoundedQueue::new(MAX_PENDING_SUBMITS)),
+            shares_resent: Default::default(),
+            resends_suppressed: Default::default(),
+            protocol_errors: Default::default(),
+            resumed: Default::default(),
             router: Default::default(),
+            router_counters: Default::default(),
             subscribed: Default::default(),
             started: Default::default(),
             stopped: Default::default(),
+            task: Default::default(),
+            submit_task: Default::default(),
+            submits_dropped: Default::default(),
+            config,
         })
     }
 
+    /// The entry of the pool list connected to, or tried next, 0 for `pool_address` of the
+    /// config.
+    pub fn pool_index(&self) -> usize {
+        self.pool_stats.lock().unwrap().current()
+    }
+
+    /// The pool connected to, or tried next.
+    pub fn pool_address(&self) -> &PoolAddress {
+        &self.pools[self.pool_index()]
+    }
+
+    /// The totals of every pool in the order they are tried, see [`PoolStatsLog`].
+    pub fn pool_stats(&self) -> Vec<PoolStats> {
+        self.pool_stats
+            .lock()
+            .unwrap()
+            .stats(std::time::Instant::now())
+    }
+
+    /// Moves on to the next pool after `failed` connects in a row that did not end up
+    /// subscribed, and logs how every pool did so far.
+    fn fail_over(&self, failed: u32) {
+        let now = std::time::Instant::now();
+        let (from, pools) = {
+            let mut pool_stats = self.pool_stats.lock().unwrap();
+            let from = pool_stats.current();
+            pool_stats.next_pool(now);
+            (from, pool_stats.stats(now))
+        };
+        warn!(
+            "Pool({}) not subscribed after {} connects, failing over to pool({}):\n{}",
+            self.pools[from],
+            failed,
+            self.pool_address(),
+            PoolStats::format_table(&pools)
+        );
+    }
+
     pub fn is_subscribed(&self) -> bool {
         self.subscribed.load(Ordering::Relaxed)
     }
 
-    pub async fn set_miner(&self, miner: Weak<Miner>) {
-        *self.miner.write().await = Some(miner);
+    /// Whether found shares can still be used: subscribed, or within the grace period after
+    /// losing the connection.
+    pub async fn can_mine(&self) -> bool {
+        self.is_subscribed() || self.in_grace().await
+    }
+
+    async fn in_grace(&self) -> bool {
+        matches!(*self.grace_until.read().await, Some(until) if Instant::now() < until)
+    }
+
+    /// Lines from the pool that were not usable messages, over all connections.
+    pub async fn protocol_errors(&self) -> ProtocolErrors {
+        let (mut closed, current) = *self.protocol_errors.read().await;
+        closed.add(&current);
+        closed
+    }
+
+    /// Smoothed local minus pool clock in milliseconds, if the pool sends timestamps.
+    pub async fn clock_skew_ms(&self) -> Option<f64> {
+        self.clock_skew.read().await.skew_ms()
+    }
+
+    /// The client id the pool assigned on the last subscribe.
+    pub async fn client_id(&self) -> Option<u64> {
+        *self.client_id.read().await
+    }
+
+    /// The graffiti the pool assigned on the last subscribe.
+    pub async fn graffiti(&self) -> Option<String> {
+        self.graffiti.read().await.clone()
+    }
+
+    /// The current pool session, if subscribed.
+    pub async fn session(&self) -> Option<SessionStats> {
+        self.sessions.read().await.current()
+    }
+
+    /// Pool sessions opened since the start.
+    pub async fn sessions_started(&self) -> u64 {
+        self.sessions.read().await.started()
+    }
+
+    /// The most recently closed pool sessions, oldest first.
+    pub async fn closed_sessions(&self) -> Vec<SessionStats> {
+        self.sessions.read().await.closed()
+    }
+
+    /// How long the sessions took to their first share.
+    pub async fn first_share_stats(&self) -> FirstShareStats {
+        self.sessions.read().await.first_share_stats()
+    }
+
+    async fn record_share_submitted(&self, mining_request_id: u32) {
+        self.bus.send(PoolEvent::ShareSubmitted {
+            mining_request_id,
+            at: std::time::Instant::now(),
+        });
+        self.pool_stats.lock().unwrap().share_submitted();
+        let mut sessions = self.sessions.write().await;
+        let session = match sessions.current_mut() {
+            Some(session) => session,
+            None => return,
+        };
+        if let Some(elapsed) = session.record_share_submitted() {
+            info!(
+                "Pool({}) session #{}: first share submitted after {:.1}s",
+                self.pool_address(),
+                session.epoch,
+                elapsed.as_secs_f64()
+            );
+        }
+    }
+
+    /// The most recent disconnects from the pool, oldest first.
+    pub async fn disconnects(&self) -> Vec<Disconnect> {
+        self.disconnects.read().await.list()
+    }
+
+    /// Jobs that expired with no new one from the pool.
+    pub fn expired_jobs(&self) -> u64 {
+        self.expired_jobs.load(Ordering::Relaxed)
+    }
+
+    /// Waits for work the pool kept up longer than `max_wait_warn`.
+    pub fn flap_stats(&self) -> FlapStats {
+        FlapStats {
+            delay_ms: self.reconnect_delay_ms.load(Ordering::Relaxed),
+            ..self.flaps.lock().unwrap().stats()
+        }
+    }
+
+    /// `level` for what is logged on every connect and drop, debug while the pool flaps so
+    /// that the flapping is logged once instead of every cycle.
+    fn cycle_level(&self, level: Level) -> Level {
+        if self.flaps.lock().unwrap().is_flapping() {
+            Level::Debug
+        } else {
+            level
+        }
+    }
+
+    /// `delay` is the one before the next connect.
+    fn log_flap_change(&self, change: Option<FlapChange>, delay: Duration) {
+        let window = self.config.flap_policy.window.as_secs();
+        match change {
+            Some(FlapChange::Started { drops }) => warn!(
+                "Pool({}) is flapping, {} sessions dropped within {}s of each other: reconnecting with a growing delay from {}s, and logging each reconnect at debug level until a session stays up for {}s",
+                self.pool_address(),
+                drops,
+                window,
+                delay.as_secs_f64(),
+                window
+            ),
+            Some(FlapChange::Stopped { cycles }) => info!(
+                "Pool({}) stopped flapping after {} dropped sessions",
+                self.pool_address(), cycles
+            ),
+            None => {}
+        }
+    }
+
+    pub fn long_waits(&self) -> u64 {
+        self.long_waits.load(Ordering::Relaxed)
+    }
+
+    pub fn half_open_disconnects(&self) -> u64 {
+        self.half_open.load(Ordering::Relaxed)
+    }
+
+    /// The last pool connections, oldest first, the open one last.
+    pub async fn connections(&self) -> Vec<ConnectionStats> {
+        self.connections.read().await.list()
+    }
+
+    /// Ends the record of the current connection and logs it on one line.
+    async fn close_connection(&self, reason: DisconnectReason, frames: MessageCounts) {
+        let closed = self.connections.write().await.close(reason, frames);
+        if let Some(connection) = closed {
+            log!(
+                self.cycle_level(Level::Info),
+                "Pool({}) connection closed: {}",
+                self.pool_address(),
+                connection.format()
+            );
+        }
+    }
+
+    /// The submit connection, only with `dual_connection`.
+    pub async fn submit_connection_stats(&self) -> Option<SubmitConnectionStats> {
+        match &self.submit_connection {
+            Some(connection) => Some(connection.stats().await),
+            None => None,
+        }
+    }
+
+    /// How full the buffers of the pool connection are.
+    pub async fn buffer_stats(&self) -> BufferStats {
+        let (session_history, first_shares) = self.sessions.read().await.usage();
+        BufferStats {
+            pending_submits: self.pending_submits.read().await.usage(),
+            held_submits: self.held_submits.read().await.usage(),
+            session_history,
+            first_shares,
+            disconnects: self.disconnects.read().await.usage(),
+            connections: self.connections.read().await.usage(),
+            ..Default::default()
+        }
+    }
+
+    /// Logs the end of a pool connection on one line and keeps it for the stats.
+    async fn record_disconnect(&self, disconnect: Disconnect) {
+        let level = match disconnect.reason {
+            DisconnectReason::StoppedByUser | DisconnectReason::HandedOver => Level::Info,
+            _ => self.cycle_level(Level::Warn),
+        };
+        log!(
+            level,
+            "Pool({}) disconnected: {}",
+            self.pool_address(),
+            disconnect.format()
+        );
+        self.pool_stats
+            .lock()
+            .unwrap()
+            .disconnected(disconnect.reason);
+        self.disconnects.write().await.record(disconnect);
     }
 
-    pub async fn submit(&self, mining_request_id: u32, randomness: String) {
+    /// Submits a share, with its solve time `elapsed_ms` if the pool agreed to take it.
+    pub async fn submit(
+        self: &Arc<Self>,
+        mining_request_id: u32,
+        randomness: String,
+        elapsed_ms: Option<u64>,
+    ) {
         trace!("submit {} {}", mining_request_id, randomness);
         if !self.subscribed.load(Ordering::Relaxed) {
+            if self.in_grace().await {
+                debug!(
+                    "holding share of mining request id({}) found while disconnected",
+                    mining_request_id
+                );
+                self.held_submits.write().await.push(MiningSubmitMessage {
+                    id: 0.into(),
+                    method: String::from("mining.submit"),
+                    // the pool it goes to after the reconnect decides, see resend_pending_submits
+                    body: MiningSubmitBody {
+                        miningRequestId: mining_request_id,
+                        randomness,
+                        elapsedMs: elapsed_ms,
+                    },
+                });
+            }
             return;
         }
-        let message = StratumMessage::MiningSubmitMessage(MiningSubmitMessage {
-            id: self.next_message_id.fetch_add(1, Ordering::SeqCst),
+        if let Some(session) = self.sessions.write().await.current_mut() {
+            session.record_share_found();
+        }
+        let delay = match self.submit_limiter.reserve() {
+            Some(delay) => delay,
+            None => {
+                self.submits_dropped.fetch_add(1, Ordering::Relaxed);
+                self.log_limiter.log(
+                    Level::Warn,
+                    "submit rate",
+                    &format!(
+                        "finding shares faster than --max-submit-rate {}, dropping them. Check the pool target, it is likely too easy",
+                        self.submit_limiter.rate()
+                    ),
+                );
+                return;
+            }
+        };
+        let mut message = MiningSubmitMessage {
+            id: self.next_message_id.fetch_add(1, Ordering::SeqCst).into(),
             method: String::from("mining.submit"),
             body: MiningSubmitBody {
                 miningRequestId: mining_request_id,
                 randomness,
+                elapsedMs: elapsed_ms,
             },
-        });
-        let _ = self
-            .router
+        };
+        self.capabilities
             .read()
             .await
-            .as_ref()
+            .restrict_submit(&mut message.body);
+        if delay.is_zero() {
+            self.send_submit(message).await;
+        } else {
+            debug!(
+                "delaying share of mining request id({}) by {:?} to keep under the submit rate",
+                mining_request_id, delay
+            );
+            let client = self.clone();
+            task::spawn(async move {
+                time::sleep(delay).await;
+                client.send_submit(message).await;
+            });
+        }
+    }
+
+    /// Number of shares dropped because they were found faster than the submit rate limit.
+    pub fn submits_dropped(&self) -> u64 {
+        self.submits_dropped.load(Ordering::Relaxed)
+    }
+
+    /// Shares submitted again after the write of them failed.
+    pub fn shares_resent(&self) -> u64 {
+        self.shares_resent.load(Ordering::Relaxed)
+    }
+
+    /// Shares whose write failed and that were not submitted again, as the pool acknowledged
+    /// them or they had been re-sent already.
+    pub fn resends_suppressed(&self) -> u64 {
+        self.resends_suppressed.load(Ordering::Relaxed)
+    }
+
+    /// Writes a share, noted in the ledger first.
+    async fn write_submit<T: AsyncRead + AsyncWrite>(
+        &self,
+        session: &mut StratumSession<T>,
+        message: MiningSubmitMessage,
+    ) -> Result<(), SessionError> {
+        self.submit_ledger.lock().unwrap().record_sent(&message);
+        session.submit(message).await
+    }
+
+    fn record_submit_ack(&self, id: &MessageId, accepted: bool, message: Option<&str>) {
+        let (submission, latency) = {
+            let mut submit_ledger = self.submit_ledger.lock().unwrap();
+            let latency = submit_ledger.since_written(id, std::time::Instant::now());
+            (submit_ledger.record_ack(id), latency)
+        };
+        let submission = match submission {
+            Some(submission) => submission,
+            None => {
+                debug!("pool answered submit id({}), not a recent share", id);
+                return;
+            }
+        };
+        self.pool_stats
+            .lock()
             .unwrap()
-            .send(StratumClientRequest::Message(message))
-            .await;
+            .share_acked(accepted, latency);
+        if accepted {
+            debug!("pool accepted share {}", submission);
+        } else {
+            warn!(
+                "Pool({}) rejected share {}: {}",
+                self.pool_address(),
+                submission,
+                message.unwrap_or("no reason given")
+            );
+        }
+    }
+
+    /// Sends the share on the submit connection if it can take it, on the main one otherwise.
+    async fn send_submit(&self, message: MiningSubmitMessage) {
+        let message = match &self.submit_connection {
+            Some(connection) => {
+                let graffiti = self.graffiti().await;
+                match connection.send(message, graffiti.as_deref()).await {
+                    Ok(()) => return,
+                    Err(message) => message,
+                }
+            }
+            None => message,
+        };
+        self.send_primary_submit(message).await;
+    }
+
+    async fn send_primary_submit(&self, message: MiningSubmitMessage) {
+        let router = self.router.read().await;
+        let router = match router.as_ref() {
+            Some(router) => router,
+            None => return,
+        };
+        if let Err(StratumClientRequest::Message(StratumMessage::MiningSubmitMessage(message))) =
+            router
+                .send(
+                    StratumClientRequest::Message(StratumMessage::MiningSubmitMessage(message)),
+                    SEND_TIMEOUT,
+                )
+                .await
+        {
+            warn!(
+                "stratum connection is not taking submits, keeping share of mining request id({}) for later",
+                message.body.miningRequestId
+            );
+            self.queue_pending_submit(message).await;
+        }
+    }
+
+    /// Depth and drop counters of the queue between the miner and the pool connection.
+    pub async fn channel_stats(&self) -> ChannelStats {
+        match self.router.read().await.as_ref() {
+            Some(router) => router.stats(),
+            None => self.router_counters.stats(CHANNEL_CAPACITY),
+        }
+    }
+
+    async fn queue_pending_submit(&self, message: MiningSubmitMessage) {
+        self.pending_submits.write().await.push(PendingSubmit {
+            message,
+            queued_at: Instant::now(),
+        });
+    }
+
+    /// Re-sends the submits whose write failed on a previous connection. Returns an error,
+    /// with the unsent submits queued again, if writing fails once more.
+    async fn resend_pending_submits<T: AsyncRead + AsyncWrite>(
+        &self,
+        session: &mut StratumSession<T>,
+    ) -> Result<(), SessionError> {
+        let mut pending_submits = self.pending_submits.write().await.take().into_iter();
+        while let Some(PendingSubmit {
+            mut message,
+            queued_at,
+        }) = pending_submits.next()
+        {
+            if queued_at.elapsed() > PENDING_SUBMIT_TTL {
+                warn!(
+                    "dropping stale share of mining request id({}) queued {:?} ago",
+                    message.body.miningRequestId,
+                    queued_at.elapsed()
+                );
+                continue;
+            }
+            let decision = self.submit_ledger.lock().unwrap().resend(&message.body);
+            match decision {
+                ResendDecision::Send => info!(
+                    "sending share of mining request id({}) after reconnect",
+                    message.body.miningRequestId
+                ),
+                ResendDecision::Resend(submission) => {
+                    self.shares_resent.fetch_add(1, Ordering::Relaxed);
+                    info!(
+                        "re-sending share {} of mining request id({}) after reconnect",
+                        submission, message.body.miningRequestId
+                    );
+                }
+                ResendDecision::Acked(submission) => {
+                    self.resends_suppressed.fetch_add(1, Ordering::Relaxed);
+                    info!(
+                        "not re-sending share {} of mining request id({}), the pool acknowledged it before the connection was lost",
+                        submission, message.body.miningRequestId
+                    );
+                    continue;
+                }
+                ResendDecision::ResendLimit(submission) => {
+                    self.resends_suppressed.fetch_add(1, Ordering::Relaxed);
+                    warn!(
+                        "not re-sending share {} of mining request id({}), it may have reached the pool already",
+                        submission, message.body.miningRequestId
+                    );
+                    continue;
+                }
+            }
+            message.id = self.next_message_id.fetch_add(1, Ordering::SeqCst).into();
+            // queued for another connection, whose pool may have agreed to more
+            self.capabilities
+                .read()
+                .await
+                .restrict_submit(&mut message.body);
+            if let Err(error) = self.write_submit(session, message.clone()).await {
+                let mut queue = self.pending_submits.write().await;
+                queue.push(PendingSubmit { message, queued_at });
+                for pending in pending_submits {
+                    queue.push(pending);
+                }
+                return Err(error);
+            }
+            self.record_share_submitted(message.body.miningRequestId)
+                .await;
+        }
+        Ok(())
+    }
+
+    /// Sends the miner's 1 minute hashrate to the pool, nothing if the miner does not say.
+    async fn report_hashrate<T: AsyncRead + AsyncWrite>(
+        &self,
+        session: &mut StratumSession<T>,
+    ) -> Result<(), SessionError> {
+        let hashrate = match self.bus.ask(PoolEvent::Hashrate).await {
+            Some(hashrate) => hashrate,
+            None => return Ok(()),
+        };
+        let message = MiningHashrateMessage {
+            id: self.next_message_id.fetch_add(1, Ordering::SeqCst).into(),
+            method: String::from("mining.hashrate"),
+            body: MiningHashrateBody {
+                hashrate,
+                name: self.config.worker_name.clone(),
+            },
+        };
+        session.report_hashrate(message).await
+    }
+
+    /// Queues the held shares of the job the pool still sends after a reconnect, and drops
+    /// those of older jobs.
+    async fn release_held_submits(&self, mining_request_id: u32) {
+        let held_submits = self.held_submits.write().await.take();
+        if held_submits.is_empty() {
+            return;
+        }
+        let (current, stale): (Vec<_>, Vec<_>) = held_submits
+            .into_iter()
+            .partition(|message| message.body.miningRequestId == mining_request_id);
+        if !stale.is_empty() {
+            info!(
+                "dropping {} stale shares found while disconnected, the pool moved on to mining request id({})",
+                stale.len(),
+                mining_request_id
+            );
+        }
+        for message in current {
+            self.queue_pending_submit(message).await;
+        }
     }
 
+    /// Pauses mining, or with `mine_through_disconnects` keeps mining the last job for a while
+    /// and pauses only if the pool is not back by then.
+    async fn connection_lost(self: &Arc<Self>) {
+        self.subscribed.store(false, Ordering::SeqCst);
+        let grace = self.config.mine_through_disconnects;
+        if grace.is_zero() {
+            self.bus
+                .send(PoolEvent::WaitForWork(PauseReason::Disconnected));
+            return;
+        }
+        let until = Instant::now() + grace;
+        *self.grace_until.write().await = Some(until);
+        log!(
+            self.cycle_level(Level::Info),
+            "Lost pool({}), mining the last job for up to {}s",
+            self.pool_address(),
+            grace.as_secs()
+        );
+        let client = self.clone();
+        task::spawn(async move {
+            time::sleep_until(until).await;
+            if client.is_subscribed() || *client.grace_until.read().await != Some(until) {
+                return;
+            }
+            warn!(
+                "Pool({}) still unreachable after {}s, pausing mining",
+                client.pool_address(),
+                grace.as_secs()
+            );
+            client
+                .bus
+                .send(PoolEvent::WaitForWork(PauseReason::Disconnected));
+        });
+    }
+
+    /// Closes the pool connection and returns once the connection task has ended. Submits
+    /// made before are written first.
     pub async fn stop(&self) {
+        self.end(StratumClientRequest::Stop).await;
+    }
+
+    /// Ends the connection task like [`Self::stop`], but leaves the connection open and returns
+    /// it with the session, for another process to go on with. `None` without a plain TCP
+    /// session, the connection is closed then. A compressed one is not plain either, its
+    /// deflate window can not be handed over. Nor is a session with a failover pool, the next
+    /// process starts out on the first.
+    pub async fn hand_over(&self) -> Option<(TcpStream, SessionHandover)> {
+        if self.config.tls
+            || !self.is_subscribed()
+            || self.capabilities.read().await.uses(CAPABILITY_DEFLATE)
+            || self.pool_index() != 0
+        {
+            self.stop().await;
+            return None;
+        }
+        let (reply, handed_over) = oneshot::channel();
+        *self.handover.write().await = Some(reply);
+        self.end(StratumClientRequest::HandOver).await;
+        // left if the session ended some other way
+        self.handover.write().await.take();
+        handed_over.await.ok()
+    }
+
+    /// Serves a session another process handed over on the next start, instead of connecting
+    /// and subscribing.
+    pub async fn resume(&self, stream: TcpStream, session: SessionHandover) {
+        self.next_message_id
+            .fetch_max(session.next_message_id, Ordering::SeqCst);
+        for message in session.pending_submits.iter().cloned() {
+            self.queue_pending_submit(message).await;
+        }
+        *self.resumed.write().await = Some((stream, session));
+    }
+
+    async fn complete_handover(&self, session: StratumSession<CountingStream<TcpStream>>) {
+        let reply = match self.handover.write().await.take() {
+            Some(reply) => reply,
+            None => return,
+        };
+        let (target, job) = self
+            .bus
+            .ask(PoolEvent::CurrentJob)
+            .await
+            .unwrap_or_default();
+        let (stream, buffered) = session.into_parts();
+        let stream = stream.into_inner();
+        let pending_submits = self
+            .pending_submits
+            .write()
+            .await
+            .take()
+            .into_iter()
+            .map(|pending| pending.message)
+            .collect();
+        let handover = SessionHandover {
+            client_id: self.client_id().await.unwrap_or_default(),
+            graffiti: self.graffiti().await.unwrap_or_default(),
+            capabilities: self
+                .capabilities
+                .read()
+                .await
+                .agreed()
+                .map(<[String]>::to_vec),
+            next_message_id: self.next_message_id.load(Ordering::SeqCst),
+            target,
+            job,
+            buffered: hex::encode(buffered),
+            pending_submits,
+        };
+        let _ = reply.send((stream, handover));
+    }
+
+    async fn end(&self, request: StratumClientRequest) {
         if !self.started.load(Ordering::Relaxed) {
             return;
         }
         self.stopped.store(true, Ordering::SeqCst);
-        if !self.is_subscribed() {
-            return;
+        self.reconnect_cancel.lock().unwrap().cancel();
+        // first, so that the shares it still has go to the main connection before it ends.
+        // It is never handed over, the next process opens its own.
+        if let Some(connection) = &self.submit_connection {
+            connection.close().await;
+            let task = self.submit_task.write().await.take();
+            if let Some(task) = task {
+                if time::timeout(STOP_TIMEOUT, task).await.is_err() {
+                    warn!(
+                        "submit connection to pool({}) still open {:?} after stop",
+                        self.pool_address(),
+                        STOP_TIMEOUT
+                    );
+                }
+            }
+        }
+        if self.is_subscribed() {
+            if let Some(router) = self.router.read().await.as_ref() {
+                if router.send(request, SEND_TIMEOUT).await.is_err() {
+                    error!("failed to deliver stop to the stratum connection");
+                }
+            }
+        }
+        let task = self.task.write().await.take();
+        if let Some(task) = task {
+            if time::timeout(STOP_TIMEOUT, task).await.is_err() {
+                warn!(
+                    "connection to pool({}) still open {:?} after stop",
+                    self.pool_address(),
+                    STOP_TIMEOUT
+                );
+            }
         }
-        let _ = self
-            .router
-            .read()
-            .await
-            .as_ref()
-            .unwrap()
-            .send(StratumClientRequest::Stop)
-            .await;
     }
 
     pub async fn start(client: Arc<Self>) {
         if client.started.load(Ordering::Relaxed) {
             return;
         }
+        #[cfg(not(feature = "tls"))]
+        if client.config.tls {
+            error!(
+                "Pool({}): {}",
+                client.pool_address(),
+                crate::TLS_UNSUPPORTED
+            );
+            return;
+        }
         client.stopped.store(false, Ordering::SeqCst);
         client.started.store(true, Ordering::SeqCst);
+        // lives as long as the miner, across restarts of the connection task
+        if let Some(commands) = client.bus.take_commands() {
+            spawn_critical(
+                "stratum commands",
+                Self::handle_commands(client.clone(), commands),
+            );
+        }
         let (router, handler) = oneshot::channel();
-        task::spawn(async move {
+        let connection = client.clone();
+        let cancel = CancellationToken::new();
+        *client.reconnect_cancel.lock().unwrap() = cancel.clone();
+        let task = spawn_critical("stratum client", async move {
             let _ = router.send(());
-            let client = client.clone();
+            let client = connection;
+            let mut backoff =
+                Backoff::on_clock(client.config.reconnect_policy, client.clock.clone());
+            // connects in a row that did not end up subscribed, until the next pool is tried
+            let mut failed_connects = 0;
             'outer: loop {
-                info!("Connecting to pool({})...", client.config.pool_address);
+                info!("Connecting to pool({})...", client.pool_address());
                 let mut connect_warned = false;
                 loop {
-                    if let Ok(tcp_stream) = TcpStream::connect(client.config.pool_address).await {
-                        if client.config.tls {
-                            let mut native_tls_builder = native_tls::TlsConnector::builder();
-                            native_tls_builder.danger_accept_invalid_certs(true);
-                            native_tls_builder.danger_accept_invalid_hostnames(true);
-                            native_tls_builder.use_sni(false);
-                            let native_tls_connector = native_tls_builder.build().unwrap();
-                            let tokio_tls_connector = TlsConnector::from(native_tls_connector);
-                            if let Ok(tls_stream) = tokio_tls_connector
-                                .connect(&client.config.pool_address.to_string(), tcp_stream)
-                                .await
-                            {
-                                if Self::handle_stratum_connect(client.clone(), tls_stream)
-                                    .await
-                                    .is_err()
-                                {
-                                    break;
+                    let resumed = client.resumed.write().await.take();
+                    let connecting = Instant::now();
+                    let (tcp_stream, resumed, connect) = match resumed {
+                        Some((tcp_stream, resumed)) => (Some(tcp_stream), Some(resumed), None),
+                        None => match client.attempt_connect().await {
+                            Ok(tcp_stream) => (Some(tcp_stream), None, Some(connecting.elapsed())),
+                            Err(error) => {
+                                if !connect_warned {
+                                    warn!(
+                                        "Failed to connect to pool ({}): {}",
+                                        client.pool_address(),
+                                        error
+                                    );
+                                }
+                                (None, None, None)
+                            }
+                        },
+                    };
+                    let mut subscribed_at = None;
+                    if let Some(tcp_stream) = tcp_stream {
+                        let opened = client.clock.now();
+                        let connection = ConnectionStats {
+                            pool_index: client.pool_index(),
+                            ..ConnectionStats::new(
+                                tcp_stream.local_addr().ok(),
+                                tcp_stream.peer_addr().ok(),
+                                connect,
+                                client.clock.unix_timestamp(),
+                            )
+                        };
+                        let bytes = Arc::new(ByteCounts::default());
+                        let tcp_stream = CountingStream::new(tcp_stream, bytes.clone());
+                        match client.config.tls {
+                            #[cfg(feature = "tls")]
+                            true => {
+                                let connected = Instant::now();
+                                let handshake =
+                                    tls_connect(client.pool_address(), tcp_stream).await;
+                                let protocol =
+                                    handshake.as_ref().ok().and_then(negotiated_protocol);
+                                client.connections.write().await.open(
+                                    connection.with_tls(connected.elapsed(), protocol),
+                                    bytes,
+                                );
+                                match handshake {
+                                    Ok(tls_stream) => {
+                                        let mut session = StratumSession::new(
+                                            tls_stream,
+                                            client.config.lenient_decode,
+                                        );
+                                        if Self::handle_stratum_connect(
+                                            client.clone(),
+                                            &mut session,
+                                            None,
+                                        )
+                                        .await
+                                            == DisconnectReason::StoppedByUser
+                                        {
+                                            break;
+                                        }
+                                        if client.is_subscribed() {
+                                            subscribed_at = Some(opened);
+                                            client.connection_lost().await;
+                                        }
+                                    }
+                                    Err(error) => {
+                                        debug!("tls handshake with pool failed: {}", error);
+                                        client
+                                            .close_connection(
+                                                DisconnectReason::TlsError,
+                                                MessageCounts::default(),
+                                            )
+                                            .await;
+                                        client
+                                            .record_disconnect(Disconnect::new(
+                                                DisconnectReason::TlsError,
+                                                None,
+                                                connected.elapsed(),
+                                                MessageCounts::default(),
+                                                client.clock.unix_timestamp(),
+                                            ))
+                                            .await;
+                                    }
                                 }
                             }
-                        } else {
-                            if Self::handle_stratum_connect(client.clone(), tcp_stream)
+                            // without TLS support, `start` never gets here with --tls
+                            _ => {
+                                client.connections.write().await.open(connection, bytes);
+                                let lenient = client.config.lenient_decode;
+                                let mut session = match &resumed {
+                                    Some(resumed) => StratumSession::resume(
+                                        tcp_stream,
+                                        lenient,
+                                        &hex::decode(&resumed.buffered).unwrap_or_default(),
+                                        resumed.replay(),
+                                    ),
+                                    None => StratumSession::new(tcp_stream, lenient),
+                                };
+                                match Self::handle_stratum_connect(
+                                    client.clone(),
+                                    &mut session,
+                                    resumed,
+                                )
                                 .await
-                                .is_err()
-                            {
-                                break;
+                                {
+                                    DisconnectReason::StoppedByUser => break,
+                                    DisconnectReason::HandedOver => {
+                                        client.complete_handover(session).await;
+                                        break;
+                                    }
+                                    _ => {}
+                                }
+                                if client.is_subscribed() {
+                                    subscribed_at = Some(opened);
+                                    client.connection_lost().await;
+                                }
                             }
                         }
                     }
+                    let (change, flapping) = {
+                        let mut flaps = client.flaps.lock().unwrap();
+                        let change = flaps.connection_ended(client.clock.now(), subscribed_at);
+                        (change, flaps.is_flapping())
+                    };
+                    // backs off further only while the pool flaps
+                    if !flapping {
+                        backoff.succeeded();
+                    }
+                    let delay = backoff.policy().delay_for(backoff.failures());
+                    client
+                        .reconnect_delay_ms
+                        .store(delay.as_millis() as u64, Ordering::Relaxed);
+                    client.log_flap_change(change, delay);
                     if client.stopped.load(Ordering::Relaxed) {
                         break 'outer;
                     }
                     if !connect_warned {
                         warn!(
                             "Failed to connect to pool ({}), retrying...",
-                            client.config.pool_address
+                            client.pool_address()
                         );
                         connect_warned = true;
                     }
-                    tokio::time::sleep(Duration::from_secs(2)).await;
+                    if subscribed_at.is_some() {
+                        failed_connects = 0;
+                    } else {
+                        failed_connects += 1;
+                    }
+                    if client.pools.len() > 1 && failed_connects >= client.config.failover_after {
+                        client.fail_over(failed_connects);
+                        failed_connects = 0;
+                        connect_warned = false;
+                    }
+                    if !backoff.wait(&cancel).await {
+                        break 'outer;
+                    }
+                }
+                if client.stopped.load(Ordering::Relaxed) {
+                    break;
                 }
                 // current link is closed, so reset stratum status
                 client.subscribed.store(false, Ordering::SeqCst);
-                if let Some(miner) = client.miner.read().await.clone() {
-                    miner.upgrade().unwrap().wait_for_work().await;
-                }
+                client
+                    .bus
+                    .send(PoolEvent::WaitForWork(PauseReason::Disconnected));
             }
             // has been stopped, reset stoped flag
+            client.subscribed.store(false, Ordering::SeqCst);
             client.started.store(false, Ordering::SeqCst);
             client.stopped.store(false, Ordering::SeqCst);
         });
+        *client.task.write().await = Some(task);
+        if client.submit_connection.is_some() {
+            let task = spawn_critical(
+                "submit connection",
+                Self::run_submit_connection(client.clone()),
+            );
+            *client.submit_task.write().await = Some(task);
+        }
         let _ = handler.await;
     }
 
-    async fn handle_stratum_connect<T: AsyncRead + AsyncWrite>(
-        client: Arc<Self>,
-        stream: T,
-    ) -> Result<()> {
-        info!("Connect pool success({})", client.config.pool_address);
-        // process net message
-        Self::handle_io_message(client, stream).await?;
-        Ok(())
+    /// Handles what the miner asks, in order, until it is gone.
+    async fn handle_commands(client: Arc<Self>, mut commands: mpsc::Receiver<MinerCommand>) {
+        while let Some(command) = commands.recv().await {
+            match command {
+                MinerCommand::Submit {
+                    mining_request_id,
+                    randomness,
+                    elapsed_ms,
+                } => {
+                    client
+                        .submit(mining_request_id, randomness, elapsed_ms)
+                        .await
+                }
+                MinerCommand::Flush(done) => {
+                    let _ = done.send(());
+                }
+            }
+        }
     }
-    async fn handle_io_message<T: AsyncRead + AsyncWrite>(
-        client: Arc<Self>,
-        stream: T,
-    ) -> Result<()> {
-        let (r, w) = split(stream);
-        let mut socket_w_handle = FramedWrite::new(w, StratumMessageCodec::default());
-        let mut socket_r_handle = FramedRead::new(r, StratumMessageCodec::default());
-        let (router, mut handler) = mpsc::channel(1024);
-        *client.router.write().await = Some(router);
-        // subscrible
-        if let Err(error) = socket_w_handle
-            .send(StratumMessage::MiningSubscribeMessage(
-                MiningSubscribeMessage {
-                    id: client.next_message_id.fetch_add(1, Ordering::SeqCst),
-                    method: String::from("mining.subscribe"),
-                    body: MiningSubscribeBody {
-                        version: 1,
-                        name: client.config.worker_name.clone(),
-                        publicAddress: client.config.public_address.clone(),
-                    },
-                },
-            ))
-            .await
-        {
-            error!("[Connect pool] {}", error);
-            return Ok(());
-        }
-        match socket_r_handle.next().await {
-            Some(Ok(message)) => match message {
-                StratumMessage::MiningSubscribedMessage(MiningSubscribedMessage {
-                    id,
-                    method,
-                    body:
-                        MiningSubscribedBody {
-                            clientId: client_id,
-                            graffiti,
+
+    /// Keeps the submit connection up until the client stops, retrying like the main
+    /// connection does.
+    async fn run_submit_connection(client: Arc<Self>) {
+        let connection = match &client.submit_connection {
+            Some(connection) => connection,
+            None => return,
+        };
+        let mut connect_warned = false;
+        while !client.stopped.load(Ordering::Relaxed) {
+            match client.connect().await {
+                Ok(tcp_stream) => {
+                    connect_warned = false;
+                    let connected = Instant::now();
+                    let lenient = client.config.lenient_decode;
+                    let (reason, counts) = match client.config.tls {
+                        #[cfg(feature = "tls")]
+                        true => match tls_connect(client.pool_address(), tcp_stream).await {
+                            Ok(tls_stream) => {
+                                Self::serve_submits(
+                                    &client,
+                                    StratumSession::new(tls_stream, lenient),
+                                )
+                                .await
+                            }
+                            Err(error) => {
+                                debug!("tls handshake with pool failed: {}", error);
+                                (DisconnectReason::TlsError, MessageCounts::default())
+                            }
                         },
-                }) => {
-                    debug!(
-                        "message id({}) method({}) stratum client id({}) graffiti({})",
-                        id, method, client_id, graffiti
+                        _ => {
+                            Self::serve_submits(&client, StratumSession::new(tcp_stream, lenient))
+                                .await
+                        }
+                    };
+                    let reason = client.through_proxy(reason);
+                    let disconnect = Disconnect::new(
+                        reason,
+                        None,
+                        connected.elapsed(),
+                        counts,
+                        client.clock.unix_timestamp(),
                     );
-                    client.subscribed.store(true, Ordering::SeqCst);
-                    if let Some(miner) = client.miner.read().await.clone() {
-                        miner.upgrade().unwrap().set_graffiti(&graffiti[..]).await;
+                    let level = match reason {
+                        DisconnectReason::StoppedByUser => Level::Info,
+                        _ => Level::Warn,
+                    };
+                    log!(
+                        level,
+                        "Pool({}) submit connection disconnected: {}",
+                        client.pool_address(),
+                        disconnect.format()
+                    );
+                    connection.record_disconnect(disconnect).await;
+                    if reason == DisconnectReason::StoppedByUser {
+                        break;
                     }
                 }
-                _ => {
-                    error!("connect pool error, unexpected response message");
-                    return Ok(());
+                Err(error) => {
+                    if !connect_warned {
+                        warn!(
+                            "Failed to open submit connection to pool({}), submitting on the main connection: {}",
+                            client.pool_address(), error
+                        );
+                        connect_warned = true;
+                    }
                 }
-            },
-            Some(Err(error)) => {
-                error!("[Connect pool] {}", error);
-                return Ok(());
             }
-            None => return Ok(()),
+            if client.stopped.load(Ordering::Relaxed) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_secs(2)).await;
         }
+    }
 
-        // main loop
+    /// Serves one submit connection until it closes. The shares it had not written yet go to
+    /// the main connection.
+    async fn serve_submits<T: AsyncRead + AsyncWrite>(
+        client: &Arc<Self>,
+        mut session: StratumSession<T>,
+    ) -> (DisconnectReason, MessageCounts) {
+        let mut shares = None;
+        let reason = Self::run_submit_session(client, &mut session, &mut shares).await;
+        if let Some(connection) = &client.submit_connection {
+            connection.close().await;
+        }
+        if let Some(mut shares) = shares {
+            shares.close();
+            while let Ok(message) = shares.try_recv() {
+                client.send_primary_submit(message).await;
+            }
+        }
+        (reason, session.counts())
+    }
+
+    /// Subscribes the submit connection and writes the shares handed to it. What the pool
+    /// sends on it only keeps it from timing out.
+    async fn run_submit_session<T: AsyncRead + AsyncWrite>(
+        client: &Arc<Self>,
+        session: &mut StratumSession<T>,
+        shares: &mut Option<mpsc::Receiver<MiningSubmitMessage>>,
+    ) -> DisconnectReason {
+        let connection = match &client.submit_connection {
+            Some(connection) => connection,
+            None => return DisconnectReason::StoppedByUser,
+        };
+        let mut body = client.config.subscribe_body();
+        body.name.push_str(SUBMIT_WORKER_SUFFIX);
+        let subscribed = client.subscribe(session, body).await;
+        let MiningSubscribedBody {
+            clientId: client_id,
+            graffiti,
+            ..
+        } = match subscribed {
+            Ok(body) => body,
+            Err(error) => {
+                client
+                    .log_limiter
+                    .log(Level::Error, "submit connection", &error.to_string());
+                return error.reason;
+            }
+        };
+        if client.stopped.load(Ordering::Relaxed) {
+            return DisconnectReason::StoppedByUser;
+        }
+        info!(
+            "Pool({}) submit connection subscribed: client id({}) graffiti({})",
+            client.pool_address(),
+            client_id,
+            graffiti
+        );
+        if let Some(main) = client.graffiti().await.filter(|main| *main != graffiti) {
+            warn!(
+                "Pool({}) submit connection got graffiti({}) but the main session has({}), shares stay on the main connection",
+                client.pool_address(), graffiti, main
+            );
+        }
+        let shares = shares.insert(connection.open(client_id, graffiti).await);
+        let mut idle = client.clock.sleep(POOL_IDLE_TIMEOUT);
         loop {
             tokio::select! {
-                Some(request) = handler.recv() =>  match request {
-                    StratumClientRequest::Message(
-                        StratumMessage::MiningSubmitMessage(message)
-                    ) => {
-                        if let Err(error) = socket_w_handle.send(StratumMessage::MiningSubmitMessage(message)).await {
-                            error!("[Stratum submit] {}", error);
+                message = shares.recv() => match message {
+                    Some(message) => {
+                        if let Err(error) = client.write_submit(session, message.clone()).await {
+                            client.log_limiter.log(Level::Error, "submit", &error.to_string());
+                            connection.close().await;
+                            client.send_primary_submit(message).await;
+                            return error.reason;
                         }
+                        connection.record_submitted();
+                        client.record_share_submitted(message.body.miningRequestId).await;
                     }
-                    StratumClientRequest::Stop => {
-                        debug!("[Stratum client stoped]");
-                        return Err(anyhow!("Exit"));
-                    }
-                    _ => error!("invalid message"),
+                    None => return DisconnectReason::StoppedByUser,
                 },
 
-                message = socket_r_handle.next() => match message {
-                    Some(Ok(message)) => match message {
-                        // 'mining.settarget'
-                        StratumMessage::MiningSetTargetMessage(
-                            MiningSetTargetMessage {
-                                id,
-                                method,
-                                body: MiningSetTargetBody { target },
-                            }
-                        ) => {
-                            debug!("message id({}) method({}) target({})", id, method, target);
-                            if let Some(miner) = client.miner.read().await.clone() {
-                                miner.upgrade().unwrap().set_target(&target[..]).await;
-                            }
-                        }
-                        // 'mining.notify'
-                        StratumMessage::MiningNotifyMessage(
-                            MiningNotifyMessage {
-                                id,
-                                method,
-                                body: MiningNotifyBody {
-                                    miningRequestId: mining_request_id,
-                                    header,
-                                }
-                            }
-                        ) => {
-                            debug!("message id({}) method({}) mining request id({}) header({})", id, method, mining_request_id, header);
-                            if let Some(miner) = client.miner.read().await.clone() {
-                                miner.upgrade().unwrap().new_work(mining_request_id, header).await;
+                _ = &mut idle => {
+                    error!("no message from pool({}) on the submit connection for {:?}, reconnecting", client.pool_address(), POOL_IDLE_TIMEOUT);
+                    return DisconnectReason::IdleTimeout;
+                }
+
+                event = session.next_event() => match event {
+                    Ok(event) => {
+                        idle = client.clock.sleep(POOL_IDLE_TIMEOUT);
+                        match event {
+                            SessionEvent::SubmitAck { id, accepted, message } => {
+                                client.record_submit_ack(&id, accepted, message.as_deref());
                             }
+                            event => trace!("submit connection ignoring {:?}", event),
                         }
-                        // 'mining.wait_for_work'
-                        StratumMessage::MiningWaitForWorkMessage(
-                            MiningWaitForWorkMessage {
-                                id,
-                                method,
-                            }
-                        ) => {
-                            debug!("message id({}) method({})", id, method);
-                            if let Some(miner) = client.miner.read().await.clone() {
-                                miner.upgrade().unwrap().wait_for_work().await;
+                    }
+                    Err(error) => {
+                        client.log_limiter.log(Level::Warn, "submit connection read", &error.to_string());
+                        return error.reason;
+                    }
+                },
+            }
+        }
+    }
+
+    /// [`Self::connect`] for the main connection, counted in the stats of the pool.
+    async fn attempt_connect(&self) -> Result<TcpStream> {
+        self.pool_stats.lock().unwrap().connect_attempted();
+        let tcp_stream = self.connect().await?;
+        self.pool_stats.lock().unwrap().connected();
+        Ok(tcp_stream)
+    }
+
+    /// Resolves the pool and opens a TCP connection to it, or a tunnel through the proxy. The
+    /// pool's hostname is left for the proxy to resolve.
+    async fn connect(&self) -> Result<TcpStream> {
+        let pool = self.pool_address();
+        let target = match &self.config.proxy {
+            Some(proxy) => &proxy.address,
+            None => pool,
+        };
+        let address = self
+            .resolver
+            .resolve(target, Instant::now().into_std())
+            .await?;
+        let mut tcp_stream = self.connector.connect(address).await?;
+        if let Some(proxy) = &self.config.proxy {
+            proxy.connect(&mut tcp_stream, pool).await?;
+        }
+        self.resolver.mark_good(target, address).await;
+        Ok(tcp_stream)
+    }
+
+    /// A tunnel closing reads like the pool closing, it is told apart by whether there is one.
+    fn through_proxy(&self, reason: DisconnectReason) -> DisconnectReason {
+        match reason {
+            DisconnectReason::RemoteClosed if self.config.proxy.is_some() => {
+                DisconnectReason::ProxyClosed
+            }
+            reason => reason,
+        }
+    }
+
+    /// Serves a pool connection, plain or over TLS alike. A `resumed` session is not
+    /// subscribed again.
+    async fn handle_stratum_connect<T: AsyncRead + AsyncWrite>(
+        client: Arc<Self>,
+        session: &mut StratumSession<T>,
+        resumed: Option<SessionHandover>,
+    ) -> DisconnectReason {
+        if resumed.is_none() {
+            log!(
+                client.cycle_level(Level::Info),
+                "Connect pool success({})",
+                client.pool_address()
+            );
+        }
+        // process net message
+        Self::serve(client, session, resumed).await
+    }
+
+    /// Serves one pool connection and records why it ended. A `resumed` session is not
+    /// subscribed again.
+    async fn serve<T: AsyncRead + AsyncWrite>(
+        client: Arc<Self>,
+        session: &mut StratumSession<T>,
+        resumed: Option<SessionHandover>,
+    ) -> DisconnectReason {
+        let connected = Instant::now();
+        if let Some(per_min) = client.config.max_protocol_errors_per_min {
+            session.limit_protocol_errors(per_min);
+        }
+        if let Some(dump) = &client.config.dump {
+            session.record_to(DumpTap::new(dump.clone()));
+        }
+        let reason =
+            client.through_proxy(Self::run_session(client.clone(), session, resumed).await);
+        client
+            .pool_stats
+            .lock()
+            .unwrap()
+            .session_ended(std::time::Instant::now());
+        client.close_connection(reason, session.counts()).await;
+        let closed = client
+            .sessions
+            .write()
+            .await
+            .close(reason, client.clock.unix_timestamp());
+        let protocol_errors = session.protocol_errors();
+        {
+            let mut totals = client.protocol_errors.write().await;
+            totals.0.add(&protocol_errors);
+            totals.1 = ProtocolErrors::default();
+        }
+        client
+            .record_disconnect(
+                Disconnect::new(
+                    reason,
+                    closed.map(|closed| closed.epoch),
+                    connected.elapsed(),
+                    session.counts(),
+                    client.clock.unix_timestamp(),
+                )
+                .with_protocol_errors(protocol_errors),
+            )
+            .await;
+        reason
+    }
+
+    /// Hands the job to the miner, which was given every target before it in the same order.
+    async fn start_job(client: &Arc<Self>, job: AssembledJob) {
+        if let Some(session) = client.sessions.write().await.current_mut() {
+            if session.is_waiting_for_first_job() {
+                session.record_first_job();
+                log!(
+                    client.cycle_level(Level::Info),
+                    "Received first job from pool({})",
+                    client.pool_address()
+                );
+            }
+        }
+        client.bus.send(PoolEvent::NewWork {
+            mining_request_id: job.mining_request_id,
+            header: job.header,
+        });
+    }
+
+    /// Subscribes with `body`, and right after the ack sends the token to a pool that takes
+    /// one.
+    async fn subscribe<T: AsyncRead + AsyncWrite>(
+        &self,
+        session: &mut StratumSession<T>,
+        body: MiningSubscribeBody,
+    ) -> Result<MiningSubscribedBody, SessionError> {
+        let subscribed = session
+            .subscribe(self.next_message_id.fetch_add(1, Ordering::SeqCst), body)
+            .await?;
+        if let Some(token) = &self.config.auth_token {
+            session
+                .authorize(self.next_message_id.fetch_add(1, Ordering::SeqCst), token)
+                .await?;
+        }
+        Ok(subscribed)
+    }
+
+    /// Subscribes, unless the session is `resumed`, and serves one pool connection until it
+    /// closes, and tells why it did.
+    async fn run_session<T: AsyncRead + AsyncWrite>(
+        client: Arc<Self>,
+        session: &mut StratumSession<T>,
+        resumed: Option<SessionHandover>,
+    ) -> DisconnectReason {
+        let (router, mut handler) =
+            monitored_channel(CHANNEL_CAPACITY, client.router_counters.clone());
+        *client.router.write().await = Some(router);
+        let started = if resumed.is_some() {
+            "resumed"
+        } else {
+            "started"
+        };
+        let subscribed = match resumed {
+            Some(resumed) => Ok(resumed.subscribed()),
+            None => {
+                client
+                    .subscribe(session, client.config.subscribe_body())
+                    .await
+            }
+        };
+        let MiningSubscribedBody {
+            clientId: client_id,
+            graffiti,
+            capabilities,
+        } = match subscribed {
+            Ok(body) => body,
+            Err(error) => {
+                client
+                    .log_limiter
+                    .log(Level::Error, "connect", &error.to_string());
+                return error.reason;
+            }
+        };
+        client.subscribed.store(true, Ordering::SeqCst);
+        client
+            .pool_stats
+            .lock()
+            .unwrap()
+            .session_started(std::time::Instant::now());
+        *client.grace_until.write().await = None;
+        let capabilities = Capabilities::negotiate(capabilities);
+        let epoch =
+            client
+                .sessions
+                .write()
+                .await
+                .open(client_id, &graffiti, client.clock.unix_timestamp());
+        log!(
+            client.cycle_level(Level::Info),
+            "Pool({}) session #{} {}: client id({}) graffiti({})",
+            client.pool_address(),
+            epoch,
+            started,
+            client_id,
+            graffiti
+        );
+        if let Some(agreed) = capabilities.agreed() {
+            debug!(
+                "Pool({}) capabilities: [{}]",
+                client.pool_address(),
+                agreed.join(", ")
+            );
+        }
+        if let Some(counts) = session.compression() {
+            debug!(
+                "Pool({}) compresses the lines of this session",
+                client.pool_address()
+            );
+            client.connections.write().await.compressed(counts.clone());
+        }
+        if let Some(session) = client.sessions.write().await.current_mut() {
+            session.pool_index = client.pool_index();
+            session.capabilities = capabilities.agreed().map(<[String]>::to_vec);
+        }
+        let report_interval = client
+            .config
+            .hashrate_report_interval
+            .filter(|_| capabilities.uses(CAPABILITY_HASHRATE));
+        *client.capabilities.write().await = capabilities;
+        *client.client_id.write().await = Some(client_id);
+        let previous = client.graffiti.write().await.replace(graffiti.clone());
+        if let Some(previous) = previous.filter(|previous| *previous != graffiti) {
+            warn!(
+                "Pool({}) graffiti changed from({}) to({}), the pool may have reassigned this worker",
+                client.pool_address(), previous, graffiti
+            );
+        }
+        client.bus.send(PoolEvent::Graffiti(graffiti));
+        if let Err(error) = client.resend_pending_submits(session).await {
+            client
+                .log_limiter
+                .log(Level::Error, "submit", &error.to_string());
+            return error.reason;
+        }
+        let mut idle = client.clock.sleep(POOL_IDLE_TIMEOUT);
+        // mining starts once both a target and a job have arrived, a job that comes first waits
+        let mut job_assembler = JobAssembler::default();
+        // the graffiti came with the subscribe ack
+        job_assembler.set_graffiti();
+        let mut waiting_for_job = true;
+        let mut first_job_warned = false;
+        let mut first_notify = true;
+        // warned about the current target being too old
+        let mut target_age_warned = false;
+        // how long the last job notified is worth mining
+        let mut job_ttl = None;
+        // when the job being mined expires, its id and how long it was given
+        let mut job_expiry: Option<(Instant, u32, Duration)> = None;
+        // since when the pool has kept us waiting for work, and whether that was warned about
+        let mut pool_wait: Option<Instant> = None;
+        let mut pool_wait_warned = false;
+        let first_job_timeout = time::sleep(client.config.first_job_timeout);
+        tokio::pin!(first_job_timeout);
+        let mut waiting_log = time::interval_at(
+            Instant::now() + FIRST_JOB_LOG_INTERVAL,
+            FIRST_JOB_LOG_INTERVAL,
+        );
+        // never ticks without an interval, the period is only a placeholder then
+        let report_period = report_interval.unwrap_or(FIRST_JOB_LOG_INTERVAL);
+        let mut hashrate_report = time::interval_at(Instant::now() + report_period, report_period);
+        let mut half_open = HalfOpenDetector::new(
+            client.config.half_open_timeout,
+            session.counts(),
+            Instant::now().into_std(),
+        );
+
+        // main loop
+        loop {
+            let now = Instant::now();
+            half_open.observe(session.counts(), now.into_std());
+            if half_open.suspected(now.into_std()) {
+                client.half_open.fetch_add(1, Ordering::Relaxed);
+                error!(
+                    "nothing from pool({}) for {}s while {} messages went out to it, the connection looks half open, reconnecting",
+                    client.pool_address(),
+                    half_open.silent_for(now.into_std()).as_secs(),
+                    half_open.unanswered()
+                );
+                return DisconnectReason::HalfOpenSuspected;
+            }
+            let half_open_at = half_open.next_check(now.into_std()).map(Instant::from_std);
+            let flush_at = session.flush_deadline();
+            tokio::select! {
+                _ = time::sleep_until(half_open_at.unwrap_or(now)), if half_open_at.is_some() => {}
+
+                _ = time::sleep_until(flush_at.unwrap_or_else(Instant::now)), if flush_at.is_some() => {
+                    if let Err(error) = session.flush().await {
+                        client.log_limiter.log(Level::Error, "write", &error.to_string());
+                        return error.reason;
+                    }
+                }
+
+                Some(request) = handler.recv() =>  match request {
+                    StratumClientRequest::Message(
+                        StratumMessage::MiningSubmitMessage(message)
+                    ) => {
+                        if let Err(error) = client.write_submit(session, message.clone()).await {
+                            // the connection is gone, keep the share for the next one
+                            client.log_limiter.log(Level::Error, "submit", &error.to_string());
+                            client.queue_pending_submit(message).await;
+                            return error.reason;
+                        }
+                        client.record_share_submitted(message.body.miningRequestId).await;
+                        // nothing more queued, whatever the policy held back goes out now
+                        if handler.is_empty() {
+                            if let Err(error) = session.flush().await {
+                                client.log_limiter.log(Level::Error, "submit", &error.to_string());
+                                return error.reason;
                             }
                         }
-                        _ => {}
                     }
-                    Some(Err(error)) => error!("failed to read message from server: {}", error),
-                    None => {
-                        error!("failed to read message from server");
-                        break;
+                    StratumClientRequest::Stop => {
+                        debug!("[Stratum client stoped]");
+                        return DisconnectReason::StoppedByUser;
+                    }
+                    StratumClientRequest::HandOver => return DisconnectReason::HandedOver,
+                    _ => error!("invalid message"),
+                },
+
+                _ = hashrate_report.tick(), if report_interval.is_some() => {
+                    // a report that fails to go out is only logged, a broken connection shows
+                    // on the next read or submit
+                    if let Err(error) = client.report_hashrate(session).await {
+                        client.log_limiter.log(Level::Warn, "hashrate report", &error.to_string());
+                    }
+                }
+
+                _ = waiting_log.tick(), if waiting_for_job => {
+                    let waited = match client.sessions.read().await.current() {
+                        Some(session) => session.subscribed_at().elapsed(),
+                        None => FIRST_JOB_LOG_INTERVAL,
+                    };
+                    info!("Subscribed, waiting for work from pool({})... {}s", client.pool_address(), waited.as_secs());
+                }
+
+                _ = &mut first_job_timeout, if waiting_for_job && !first_job_warned => {
+                    warn!(
+                        "no job from pool({}) {}s after subscribing, check your address and the pool address",
+                        client.pool_address(),
+                        client.config.first_job_timeout.as_secs()
+                    );
+                    if client.config.first_job_reconnect {
+                        return DisconnectReason::FirstJobTimeout;
+                    }
+                    first_job_warned = true;
+                }
+
+                _ = &mut idle => {
+                    error!("no message from pool({}) for {:?}, reconnecting", client.pool_address(), POOL_IDLE_TIMEOUT);
+                    return DisconnectReason::IdleTimeout;
+                }
+
+                Some((mining_request_id, ttl)) = job_expired(job_expiry) => {
+                    client.expired_jobs.fetch_add(1, Ordering::Relaxed);
+                    warn!(
+                        "job of mining request id({}) from pool({}) expired after {:.1}s with no new job, reconnecting for fresh work",
+                        mining_request_id,
+                        client.pool_address(),
+                        ttl.as_secs_f64()
+                    );
+                    client.bus.send(PoolEvent::WaitForWork(PauseReason::JobExpired));
+                    return DisconnectReason::JobExpired;
+                }
+
+                Some(waited) = waited_for(pool_wait.filter(|_| !pool_wait_warned), Some(client.config.max_wait_warn).filter(|warn| !warn.is_zero())) => {
+                    pool_wait_warned = true;
+                    client.long_waits.fetch_add(1, Ordering::Relaxed);
+                    warn!(
+                        "pool({}) has kept the miner waiting for work for {}s, longer than between blocks, the pool may be stuck",
+                        client.pool_address(),
+                        waited.as_secs()
+                    );
+                }
+
+                Some(waited) = waited_for(pool_wait, client.config.reconnect_on_long_wait) => {
+                    warn!(
+                        "pool({}) has kept the miner waiting for work for {}s, reconnecting",
+                        client.pool_address(),
+                        waited.as_secs()
+                    );
+                    return DisconnectReason::LongWait;
+                }
+
+                event = session.next_event() => {
+                    client.protocol_errors.write().await.1 = session.protocol_errors();
+                    let event = match event {
+                        Ok(event) => event,
+                        Err(error) => {
+                            let level = match error.reason {
+                                DisconnectReason::DecodeError | DisconnectReason::ProtocolErrors => Level::Warn,
+                                _ => Level::Error,
+                            };
+                            client.log_limiter.log(level, "read", &error.to_string());
+                            return error.reason;
+                        }
+                    };
+                    idle = client.clock.sleep(POOL_IDLE_TIMEOUT);
+                    match event {
+                        SessionEvent::NewTarget(target) => {
+                            if let Some(session) = client.sessions.write().await.current_mut() {
+                                session.record_target();
+                            }
+                            target_age_warned = false;
+                            client.bus.send(PoolEvent::Target(target.clone()));
+                            if let Some(job) = job_assembler.set_target(target) {
+                                waiting_for_job = false;
+                                pool_wait = None;
+                                job_expiry = job_ttl.map(|ttl| (Instant::now() + ttl, job.mining_request_id, ttl));
+                                Self::start_job(&client, job).await;
+                            }
+                        }
+                        SessionEvent::NewJob { mining_request_id, header, timestamp, expires_in } => {
+                            job_ttl = expires_in.or(Some(client.config.job_ttl).filter(|ttl| !ttl.is_zero()));
+                            let target_age = match client.sessions.write().await.current_mut() {
+                                Some(session) => {
+                                    session.record_notify();
+                                    let target_age = session
+                                        .target_age()
+                                        .filter(|age| *age > client.config.max_target_age);
+                                    if target_age.is_some() {
+                                        session.stale_target_jobs += 1;
+                                    }
+                                    target_age
+                                }
+                                None => None,
+                            };
+                            if let Some(target_age) = target_age {
+                                if !target_age_warned {
+                                    warn!(
+                                        "job of mining request id({}) from pool({}) comes with a target set {}s ago, the pool may have stopped sending mining.set_target",
+                                        mining_request_id,
+                                        client.pool_address(),
+                                        target_age.as_secs()
+                                    );
+                                    target_age_warned = true;
+                                }
+                                if client.config.max_target_age_reconnect {
+                                    return DisconnectReason::StaleTarget;
+                                }
+                            }
+                            if first_notify {
+                                first_notify = false;
+                                client.release_held_submits(mining_request_id).await;
+                                if let Err(error) = client.resend_pending_submits(session).await {
+                                    client.log_limiter.log(Level::Error, "submit", &error.to_string());
+                                    return error.reason;
+                                }
+                            }
+                            let uses_timestamps = client.capabilities.read().await.uses(CAPABILITY_TIMESTAMPS);
+                            if let Some(timestamp) = timestamp.filter(|_| uses_timestamps) {
+                                let mut clock_skew = client.clock_skew.write().await;
+                                if clock_skew.observe(client.clock.unix_timestamp_millis(), timestamp) {
+                                    warn!(
+                                        "local clock differs from pool({}) clock by {:.1}s, check your system time",
+                                        client.pool_address(),
+                                        clock_skew.skew_ms().unwrap_or_default() / 1000.0
+                                    );
+                                }
+                            }
+                            match job_assembler.notify(mining_request_id, header) {
+                                Some(job) => {
+                                    waiting_for_job = false;
+                                    pool_wait = None;
+                                    job_expiry = job_ttl.map(|ttl| (Instant::now() + ttl, mining_request_id, ttl));
+                                    Self::start_job(&client, job).await;
+                                }
+                                None => debug!("no {} yet, holding job of mining request id({})", job_assembler.missing(), mining_request_id),
+                            }
+                        }
+                        SessionEvent::WaitForWork => {
+                            job_expiry = None;
+                            // a wait repeated keeps the wait going
+                            if pool_wait.is_none() {
+                                pool_wait = Some(Instant::now());
+                                pool_wait_warned = false;
+                            }
+                            client.bus.send(PoolEvent::WaitForWork(PauseReason::PoolRequested));
+                        }
+                        SessionEvent::SubmitAck { id, accepted, message } => {
+                            client.record_submit_ack(&id, accepted, message.as_deref());
+                        }
+                        SessionEvent::Unknown(message) => debug!("ignoring {:?}", message),
                     }
                 }
             }
         }
-        Ok(())
+    }
+}
+
+/// Waits until a wait for work started `since` has lasted `limit`, and returns how long it has
+/// lasted. `None` right away when not waiting, or without a limit.
+async fn waited_for(since: Option<Instant>, limit: Option<Duration>) -> Option<Duration> {
+    let (since, limit) = (since?, limit?);
+    time::sleep_until(since + limit).await;
+    Some(since.elapsed())
+}
+
+/// Waits for the job to expire, and returns its id and how long it was given. `None` right
+/// away for a job that does not expire.
+async fn job_expired(expiry: Option<(Instant, u32, Duration)>) -> Option<(u32, Duration)> {
+    let (at, mining_request_id, ttl) = expiry?;
+    time::sleep_until(at).await;
+    Some((mining_request_id, ttl))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        test_util::{
+            easy_target, notify_message, set_target_message, submitted_message, subscribed_message,
+            wait_for_work_message, ConnectProxy, MockPool, MockPoolListener,
+        },
+        HistoryWindow, ManualClock, Meter, StratumMessageCodec, CAPABILITY_SOLVE_TIME,
+        MAX_DISCONNECTS, MAX_FIRST_SHARES, MAX_LINE_LENGTH, METER_HISTORY_SECONDS,
+    };
+    use futures::SinkExt;
+    use std::net::SocketAddr;
+    use std::{
+        collections::BTreeMap,
+        io,
+        pin::Pin,
+        sync::atomic::AtomicUsize,
+        task::{Context, Poll},
+    };
+    use tokio::{
+        io::{duplex, DuplexStream, ReadBuf},
+        net::TcpListener,
+        sync::broadcast,
+    };
+    use tokio_stream::StreamExt;
+    use tokio_util::codec::{FramedRead, FramedWrite};
+
+    /// A client side stream whose writes start failing once `fail_writes` is set.
+    struct FlakyStream {
+        inner: DuplexStream,
+        fail_writes: Arc<AtomicBool>,
+    }
+
+    impl AsyncRead for FlakyStream {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for FlakyStream {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            if self.fail_writes.load(Ordering::SeqCst) {
+                return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+            }
+            Pin::new(&mut self.inner).poll_write(cx, buf)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_flush(cx)
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_shutdown(cx)
+        }
+    }
+
+    impl StratumClient {
+        /// Serves `stream` as a new pool connection, the way `start` serves a plain one.
+        async fn handle_io_message<T: AsyncRead + AsyncWrite>(
+            client: Arc<Self>,
+            stream: T,
+        ) -> DisconnectReason {
+            let mut session = StratumSession::new(stream, client.config.lenient_decode);
+            Self::handle_stratum_connect(client, &mut session, None).await
+        }
+    }
+
+    fn test_client() -> Arc<StratumClient> {
+        StratumClient::new(StratumClientConfig {
+            tls: false,
+            pool_address: "127.0.0.1:8181".parse().unwrap(),
+            failover_pools: vec![],
+            failover_after: 3,
+            public_address: Some(String::from("xxxxxx")),
+            auth_token: None,
+            worker_name: String::from("xxxxxx"),
+            max_clock_skew: Duration::from_secs(30),
+            session_history: 10,
+            first_job_timeout: Duration::from_secs(120),
+            first_job_reconnect: false,
+            job_ttl: Duration::from_secs(120),
+            max_target_age: Duration::from_secs(30 * 60),
+            max_target_age_reconnect: false,
+            max_wait_warn: Duration::from_secs(180),
+            reconnect_on_long_wait: None,
+            half_open_timeout: Duration::ZERO,
+            source_ports: None,
+            proxy: None,
+            lenient_decode: false,
+            send_agent: true,
+            graffiti_suffix: None,
+            max_submit_rate: Default::default(),
+            resend_submits: true,
+            mine_through_disconnects: Duration::ZERO,
+            dns_ttl: Duration::from_secs(60),
+            max_protocol_errors_per_min: None,
+            dual_connection: false,
+            hashrate_report_interval: None,
+            dump: None,
+            flap_policy: FlapPolicy::default(),
+            reconnect_policy: RECONNECT_POLICY,
+            compress: false,
+        })
+    }
+
+    type PoolHalves = (
+        FramedRead<tokio::io::ReadHalf<DuplexStream>, StratumMessageCodec>,
+        FramedWrite<tokio::io::WriteHalf<DuplexStream>, StratumMessageCodec>,
+    );
+
+    /// Answers the subscribe of a new session like a pool would.
+    async fn accept_subscribe(pool: DuplexStream) -> PoolHalves {
+        accept_subscribe_after(pool, vec![]).await
+    }
+
+    /// Answers the subscribe, sending `early` ahead of the ack.
+    async fn accept_subscribe_after(pool: DuplexStream, early: Vec<StratumMessage>) -> PoolHalves {
+        accept_subscribe_acking(pool, early, None).await
+    }
+
+    /// Answers the subscribe with an ack listing `capabilities`.
+    async fn accept_subscribe_acking(
+        pool: DuplexStream,
+        early: Vec<StratumMessage>,
+        capabilities: Option<Vec<String>>,
+    ) -> PoolHalves {
+        let mut pool = MockPool::new(pool);
+        let subscribe = pool.expect_subscribe().await;
+        assert_eq!(subscribe.agent, Some(user_agent()));
+        assert_eq!(
+            subscribe.capabilities,
+            vec![CAPABILITY_TIMESTAMPS, CAPABILITY_SOLVE_TIME]
+        );
+        assert!(pool.send_all(early).await);
+        assert!(
+            pool.send(subscribed_message(1, "zk.work", capabilities))
+                .await
+        );
+        pool.into_parts()
+    }
+
+    #[tokio::test]
+    async fn test_resubmit_after_write_failure() {
+        let client = test_client();
+
+        // first session: the pool stops accepting our writes right before a submit
+        let (client_io, pool_io) = duplex(4096);
+        let fail_writes = Arc::new(AtomicBool::new(false));
+        let stream = FlakyStream {
+            inner: client_io,
+            fail_writes: fail_writes.clone(),
+        };
+        let session = task::spawn(StratumClient::handle_io_message(client.clone(), stream));
+        let _pool = accept_subscribe(pool_io).await;
+        while !client.is_subscribed() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        fail_writes.store(true, Ordering::SeqCst);
+        client
+            .submit(7, String::from("00000000000004d2"), None)
+            .await;
+        // the session ends on its own instead of waiting for the read side
+        let reason = tokio::time::timeout(Duration::from_secs(5), session)
+            .await
+            .expect("session should end after a failed write")
+            .unwrap();
+        assert_eq!(
+            reason,
+            DisconnectReason::WriteError(io::ErrorKind::BrokenPipe)
+        );
+        assert_eq!(client.pending_submits.read().await.len(), 1);
+
+        // second session: the share is re-sent right after the subscribe
+        let (client_io, pool_io) = duplex(4096);
+        let _session = task::spawn(StratumClient::handle_io_message(client.clone(), client_io));
+        let (mut r, _w) = accept_subscribe(pool_io).await;
+        match r.next().await {
+            Some(Ok(StratumMessage::MiningSubmitMessage(message))) => {
+                assert_eq!(message.body.miningRequestId, 7);
+                assert_eq!(message.body.randomness, "00000000000004d2");
+            }
+            other => panic!("expected submit, got {:?}", other),
+        }
+        assert!(client.pending_submits.read().await.is_empty());
+    }
+
+    /// The next submit from the client, within 5 seconds.
+    async fn expect_submit(r: &mut PoolHalves) -> MiningSubmitMessage {
+        match tokio::time::timeout(Duration::from_secs(5), r.0.next()).await {
+            Ok(Some(Ok(StratumMessage::MiningSubmitMessage(message)))) => message,
+            other => panic!("expected submit, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_resend_once_unless_acked() {
+        let client = test_client();
+        let (client_io, pool_io) = duplex(4096);
+        let fail_writes = Arc::new(AtomicBool::new(false));
+        let stream = FlakyStream {
+            inner: client_io,
+            fail_writes: fail_writes.clone(),
+        };
+        let session = task::spawn(StratumClient::handle_io_message(client.clone(), stream));
+        let mut pool = accept_subscribe(pool_io).await;
+        pool.1.send(notify(7)).await.unwrap();
+        while client.session().await.map(|session| session.notifies) != Some(1) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        // the pool acks the first share...
+        client
+            .submit(7, String::from("00000000000000aa"), None)
+            .await;
+        let acked = expect_submit(&mut pool).await;
+        pool.1
+            .send(submitted_message(acked.id.clone(), true))
+            .await
+            .unwrap();
+        pool.1.send(notify(7)).await.unwrap();
+        while client.session().await.map(|session| session.notifies) != Some(2) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        // ...which is queued as if its write had failed after all, like the second one's does
+        client.queue_pending_submit(acked).await;
+        fail_writes.store(true, Ordering::SeqCst);
+        client
+            .submit(7, String::from("00000000000000bb"), None)
+            .await;
+        assert!(matches!(
+            session.await.unwrap(),
+            DisconnectReason::WriteError(_)
+        ));
+        assert_eq!(client.pending_submits.read().await.len(), 2);
+
+        // only the share never acked goes out again
+        let (client_io, pool_io) = duplex(4096);
+        let session = task::spawn(StratumClient::handle_io_message(client.clone(), client_io));
+        let mut pool = accept_subscribe(pool_io).await;
+        pool.1.send(notify(7)).await.unwrap();
+        let resent = expect_submit(&mut pool).await;
+        assert_eq!(resent.body.randomness, "00000000000000bb");
+        assert!(
+            tokio::time::timeout(Duration::from_millis(200), pool.0.next())
+                .await
+                .is_err(),
+            "the acked share should not be re-sent"
+        );
+        assert_eq!(client.shares_resent(), 1);
+        assert_eq!(client.resends_suppressed(), 1);
+
+        // and only once, even if that write fails too
+        client.queue_pending_submit(resent).await;
+        drop(pool);
+        assert_eq!(session.await.unwrap(), DisconnectReason::RemoteClosed);
+        let (client_io, pool_io) = duplex(4096);
+        let _session = task::spawn(StratumClient::handle_io_message(client.clone(), client_io));
+        let mut pool = accept_subscribe(pool_io).await;
+        pool.1.send(notify(7)).await.unwrap();
+        while client.session().await.map(|session| session.notifies) != Some(1) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(
+            tokio::time::timeout(Duration::from_millis(200), pool.0.next())
+                .await
+                .is_err(),
+            "a share should be re-sent once at most"
+        );
+        assert!(client.pending_submits.read().await.is_empty());
+        assert_eq!(client.shares_resent(), 1);
+        assert_eq!(client.resends_suppressed(), 2);
+    }
+
+    fn notify(mining_request_id: u32) -> StratumMessage {
+        notify_message(mining_request_id, &"00".repeat(208))
+    }
+
+    #[tokio::test]
+    async fn test_capabilities() {
+        // a notify from a pool clock an hour ahead
+        let skewed = || {
+            let mut message = notify(1);
+            if let StratumMessage::MiningNotifyMessage(message) = &mut message {
+                message.body.timestamp = Some(SystemClock.unix_timestamp_millis() + 3_600_000);
+            }
+            message
+        };
+        let cases = [
+            (None, None, true),
+            (
+                Some(vec![String::from("timestamps"), String::from("unknown")]),
+                Some(vec![String::from("timestamps")]),
+                true,
+            ),
+            (Some(vec![]), Some(vec![]), false),
+        ];
+        for (acked, agreed, skew) in cases {
+            let client = test_client();
+            let (client_io, pool_io) = duplex(4096);
+            let _session = task::spawn(StratumClient::handle_io_message(client.clone(), client_io));
+            let (_r, mut w) = accept_subscribe_acking(pool_io, vec![], acked).await;
+            w.send(set_target()).await.unwrap();
+            w.send(skewed()).await.unwrap();
+            // the job starts after the timestamp is looked at
+            while client
+                .session()
+                .await
+                .and_then(|session| session.first_job_ms)
+                .is_none()
+            {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            assert_eq!(client.session().await.unwrap().capabilities, agreed);
+            assert_eq!(client.clock_skew_ms().await.is_some(), skew);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_authorize_with_token() {
+        let mut config = test_client().config.clone();
+        config.public_address = None;
+        config.auth_token = Some(String::from("s3cret"));
+        let client = StratumClient::new(config);
+        let (client_io, pool_io) = duplex(4096);
+        let _session = task::spawn(StratumClient::handle_io_message(client.clone(), client_io));
+        let mut pool = MockPool::new(pool_io);
+        // no address in the subscribe, the token follows the ack
+        assert_eq!(pool.expect_subscribe().await.publicAddress, None);
+        assert!(pool.send(subscribed_message(1, "zk.work", None)).await);
+        match pool.next().await {
+            Some(StratumMessage::MiningAuthorizeMessage(message)) => {
+                assert_eq!(message.body.token, "s3cret")
+            }
+            other => panic!("expected an authorize, got {:?}", other),
+        }
+        while !client.is_subscribed() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_first_job_waits_for_target() {
+        let client = test_client();
+        let (client_io, pool_io) = duplex(4096);
+        let _session = task::spawn(StratumClient::handle_io_message(client.clone(), client_io));
+        let (_r, mut w) = accept_subscribe(pool_io).await;
+        w.send(notify(1)).await.unwrap();
+        while client.session().await.map(|session| session.notifies) != Some(1) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let session = client.session().await.unwrap();
+        assert!(session.first_job_ms.is_none());
+        assert!(session.waiting_for_first_job_ms.is_some());
+
+        w.send(set_target()).await.unwrap();
+        while client.session().await.unwrap().first_job_ms.is_none() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(client
+            .session()
+            .await
+            .unwrap()
+            .waiting_for_first_job_ms
+            .is_none());
+    }
+
+    fn set_target() -> StratumMessage {
+        set_target_message(&easy_target())
+    }
+
+    #[tokio::test]
+    async fn test_messages_before_subscribe_ack() {
+        let orderings = [
+            (vec![], vec![set_target(), notify(1)]),
+            (vec![set_target()], vec![notify(1)]),
+            (vec![notify(1)], vec![set_target()]),
+            (vec![notify(1), set_target()], vec![]),
+        ];
+        for (early, late) in orderings {
+            let client = test_client();
+            let (client_io, pool_io) = duplex(4096);
+            let _session = task::spawn(StratumClient::handle_io_message(client.clone(), client_io));
+            let (_r, mut w) = accept_subscribe_after(pool_io, early).await;
+            for message in late {
+                w.send(message).await.unwrap();
+            }
+            tokio::time::timeout(Duration::from_secs(5), async {
+                while client
+                    .session()
+                    .await
+                    .and_then(|session| session.first_job_ms)
+                    .is_none()
+                {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            })
+            .await
+            .expect("the first job should start");
+            assert_eq!(client.session().await.unwrap().notifies, 1);
+            assert!(client.closed_sessions().await.is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_first_job_timeout_reconnects() {
+        let mut config = test_client().config.clone();
+        config.first_job_timeout = Duration::from_millis(100);
+        config.first_job_reconnect = true;
+        let client = StratumClient::new(config);
+        let (client_io, pool_io) = duplex(4096);
+        let session = task::spawn(StratumClient::handle_io_message(client.clone(), client_io));
+        let _pool = accept_subscribe(pool_io).await;
+        let reason = tokio::time::timeout(Duration::from_secs(5), session)
+            .await
+            .expect("session should end without a first job")
+            .unwrap();
+        assert_eq!(reason, DisconnectReason::FirstJobTimeout);
+        assert_eq!(
+            client.closed_sessions().await[0].close_reason,
+            Some(DisconnectReason::FirstJobTimeout)
+        );
+    }
+
+    /// Waits until the current session has seen `notifies` jobs.
+    async fn wait_for_notifies(client: &StratumClient, notifies: u64) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while client.session().await.map(|session| session.notifies) != Some(notifies) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the jobs should arrive");
+    }
+
+    #[tokio::test]
+    async fn test_stale_target() {
+        let mut config = test_client().config.clone();
+        config.max_target_age = Duration::from_millis(200);
+
+        // a pool that keeps sending jobs but stopped sending targets
+        let client = StratumClient::new(config.clone());
+        let (client_io, pool_io) = duplex(4096);
+        let session = task::spawn(StratumClient::handle_io_message(client.clone(), client_io));
+        let (_r, mut w) = accept_subscribe(pool_io).await;
+        w.send(set_target()).await.unwrap();
+        w.send(notify(1)).await.unwrap();
+        wait_for_notifies(&client, 1).await;
+        assert_eq!(client.session().await.unwrap().stale_target_jobs, 0);
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        w.send(notify(2)).await.unwrap();
+        w.send(notify(3)).await.unwrap();
+        wait_for_notifies(&client, 3).await;
+        let current = client.session().await.unwrap();
+        assert_eq!(current.stale_target_jobs, 2);
+        assert!(current.last_target_age_ms.unwrap() >= 300);
+
+        // a fresh target makes the jobs fine again
+        w.send(set_target()).await.unwrap();
+        w.send(notify(4)).await.unwrap();
+        wait_for_notifies(&client, 4).await;
+        let current = client.session().await.unwrap();
+        assert_eq!(current.stale_target_jobs, 2);
+        assert!(current.last_target_age_ms.unwrap() < 200);
+        // only warned about
+        assert!(!session.is_finished());
+
+        // with the reconnect, the first job on a stale target ends the session
+        config.max_target_age_reconnect = true;
+        let client = StratumClient::new(config);
+        let (client_io, pool_io) = duplex(4096);
+        let session = task::spawn(StratumClient::handle_io_message(client.clone(), client_io));
+        let (_r, mut w) = accept_subscribe(pool_io).await;
+        w.send(set_target()).await.unwrap();
+        w.send(notify(1)).await.unwrap();
+        wait_for_notifies(&client, 1).await;
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        w.send(notify(2)).await.unwrap();
+        let reason = tokio::time::timeout(Duration::from_secs(5), session)
+            .await
+            .expect("session should end on a stale target")
+            .unwrap();
+        assert_eq!(reason, DisconnectReason::StaleTarget);
+        let closed = &client.closed_sessions().await[0];
+        assert_eq!(closed.close_reason, Some(DisconnectReason::StaleTarget));
+        assert_eq!(closed.stale_target_jobs, 1);
+    }
+
+    #[tokio::test]
+    async fn test_job_expiry() {
+        let mut config = test_client().config.clone();
+        config.job_ttl = Duration::from_millis(300);
+        let client = StratumClient::new(config);
+        let (client_io, pool_io) = duplex(4096);
+        let session = task::spawn(StratumClient::handle_io_message(client.clone(), client_io));
+        let (_r, mut w) = accept_subscribe(pool_io).await;
+        w.send(set_target()).await.unwrap();
+        w.send(notify(1)).await.unwrap();
+        wait_for_notifies(&client, 1).await;
+        // every new job restarts the clock
+        for job in 2..=6 {
+            tokio::time::sleep(Duration::from_millis(150)).await;
+            w.send(notify(job)).await.unwrap();
+            wait_for_notifies(&client, job as u64).await;
+        }
+        assert!(!session.is_finished());
+        assert_eq!(client.expired_jobs(), 0);
+        let reason = tokio::time::timeout(Duration::from_secs(5), session)
+            .await
+            .expect("the last job should expire")
+            .unwrap();
+        assert_eq!(reason, DisconnectReason::JobExpired);
+        assert_eq!(client.expired_jobs(), 1);
+        assert_eq!(
+            client.closed_sessions().await[0].close_reason,
+            Some(DisconnectReason::JobExpired)
+        );
+
+        // the expiry the pool sends overrides the default of two minutes
+        let client = test_client();
+        let (client_io, pool_io) = duplex(4096);
+        let session = task::spawn(StratumClient::handle_io_message(client.clone(), client_io));
+        let (_r, mut w) = accept_subscribe(pool_io).await;
+        w.send(set_target()).await.unwrap();
+        let mut expiring = notify(1);
+        if let StratumMessage::MiningNotifyMessage(message) = &mut expiring {
+            message.body.expiresInMs = Some(200);
+        }
+        w.send(expiring).await.unwrap();
+        let reason = tokio::time::timeout(Duration::from_secs(5), session)
+            .await
+            .expect("the job should expire as the pool said")
+            .unwrap();
+        assert_eq!(reason, DisconnectReason::JobExpired);
+    }
+
+    #[tokio::test]
+    async fn test_half_open() {
+        let mut config = test_client().config.clone();
+        config.half_open_timeout = Duration::from_millis(500);
+        let client = StratumClient::new(config);
+        let (client_io, pool_io) = duplex(4096);
+        let session = task::spawn(StratumClient::handle_io_message(client.clone(), client_io));
+        let (_r, mut w) = accept_subscribe(pool_io).await;
+        w.send(set_target()).await.unwrap();
+        w.send(notify(1)).await.unwrap();
+        wait_for_notifies(&client, 1).await;
+        // a pool that is quiet while nothing goes out to it is fine
+        tokio::time::sleep(Duration::from_millis(700)).await;
+        assert!(!session.is_finished());
+
+        // the pool answers a share with a job, the next ones go unanswered
+        client
+            .submit(1, String::from("00000000000004d2"), None)
+            .await;
+        w.send(notify(2)).await.unwrap();
+        wait_for_notifies(&client, 2).await;
+        let answered = Instant::now();
+        client
+            .submit(2, String::from("00000000000004d3"), None)
+            .await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        client
+            .submit(2, String::from("00000000000004d4"), None)
+            .await;
+        let reason = tokio::time::timeout(Duration::from_secs(5), session)
+            .await
+            .expect("the session should end as half open")
+            .unwrap();
+        assert_eq!(reason, DisconnectReason::HalfOpenSuspected);
+        // detected within the window, not at the idle timeout
+        let detected = answered.elapsed();
+        assert!(
+            detected >= Duration::from_millis(400) && detected < Duration::from_millis(1500),
+            "{:?}",
+            detected
+        );
+        assert_eq!(client.half_open_disconnects(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_long_wait() {
+        let mut config = test_client().config.clone();
+        config.max_wait_warn = Duration::from_millis(300);
+        let client = StratumClient::new(config.clone());
+        let (client_io, pool_io) = duplex(4096);
+        let session = task::spawn(StratumClient::handle_io_message(client.clone(), client_io));
+        let (_r, mut w) = accept_subscribe(pool_io).await;
+        w.send(set_target()).await.unwrap();
+        w.send(notify(1)).await.unwrap();
+        wait_for_notifies(&client, 1).await;
+        // a wait ended by a job in time is not warned about
+        w.send(wait_for_work_message()).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        w.send(notify(2)).await.unwrap();
+        wait_for_notifies(&client, 2).await;
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(client.long_waits(), 0);
+        // the pool asking again keeps the wait going
+        w.send(wait_for_work_message()).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        w.send(wait_for_work_message()).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(client.long_waits(), 1);
+        // warned once per wait, and only warned about
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(client.long_waits(), 1);
+        assert!(!session.is_finished());
+
+        // with --reconnect-on-long-wait the session ends once the wait lasts that long
+        config.reconnect_on_long_wait = Some(Duration::from_millis(500));
+        let client = StratumClient::new(config);
+        let (client_io, pool_io) = duplex(4096);
+        let session = task::spawn(StratumClient::handle_io_message(client.clone(), client_io));
+        let (_r, mut w) = accept_subscribe(pool_io).await;
+        w.send(set_target()).await.unwrap();
+        w.send(notify(1)).await.unwrap();
+        wait_for_notifies(&client, 1).await;
+        w.send(wait_for_work_message()).await.unwrap();
+        let reason = tokio::time::timeout(Duration::from_secs(5), session)
+            .await
+            .expect("the session should end on the long wait")
+            .unwrap();
+        assert_eq!(reason, DisconnectReason::LongWait);
+        assert_eq!(client.long_waits(), 1);
+        assert_eq!(
+            client.closed_sessions().await[0].close_reason,
+            Some(DisconnectReason::LongWait)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_session_close_reasons() {
+        let client = test_client();
+
+        // first session: one job, then the pool hangs up
+        let (client_io, pool_io) = duplex(4096);
+        let session = task::spawn(StratumClient::handle_io_message(client.clone(), client_io));
+        let (r, mut w) = accept_subscribe(pool_io).await;
+        w.send(notify(1)).await.unwrap();
+        while client.session().await.map(|session| session.notifies) != Some(1) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        drop((r, w));
+        assert_eq!(session.await.unwrap(), DisconnectReason::RemoteClosed);
+
+        // second session: the pool sends garbage
+        let (client_io, pool_io) = duplex(4096);
+        let session = task::spawn(StratumClient::handle_io_message(client.clone(), client_io));
+        let (_r, w) = accept_subscribe(pool_io).await;
+        let mut pool_io = w.into_inner();
+        tokio::io::AsyncWriteExt::write_all(&mut pool_io, b"not json\n")
+            .await
+            .unwrap();
+        assert_eq!(session.await.unwrap(), DisconnectReason::DecodeError);
+
+        assert!(client.session().await.is_none());
+        // the pool assigned identity outlives the session
+        assert_eq!(client.client_id().await, Some(1));
+        assert_eq!(client.graffiti().await.as_deref(), Some("zk.work"));
+        let sessions = client.closed_sessions().await;
+        assert_eq!(sessions.len(), 2);
+        assert_eq!(sessions[0].epoch, 1);
+        assert_eq!(sessions[0].client_id, 1);
+        assert_eq!(sessions[0].graffiti, "zk.work");
+        assert_eq!(sessions[0].notifies, 1);
+        assert_eq!(
+            sessions[0].close_reason,
+            Some(DisconnectReason::RemoteClosed)
+        );
+        assert_eq!(sessions[1].epoch, 2);
+        assert_eq!(sessions[1].notifies, 0);
+        assert_eq!(
+            sessions[1].close_reason,
+            Some(DisconnectReason::DecodeError)
+        );
+
+        // both ends are in the disconnect log, with what went over the connection
+        let disconnects = client.disconnects().await;
+        assert_eq!(disconnects.len(), 2);
+        assert_eq!(disconnects[0].reason, DisconnectReason::RemoteClosed);
+        assert_eq!(disconnects[0].epoch, Some(1));
+        // the subscribe ack and the job, and the subscribe
+        assert_eq!(disconnects[0].messages_received, 2);
+        assert_eq!(disconnects[0].messages_sent, 1);
+        assert_eq!(disconnects[1].reason, DisconnectReason::DecodeError);
+        assert_eq!(disconnects[1].epoch, Some(2));
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_reasons() {
+        // stopped while subscribed
+        let client = test_client();
+        let (client_io, pool_io) = duplex(4096);
+        let session = task::spawn(StratumClient::handle_io_message(client.clone(), client_io));
+        let _pool = accept_subscribe(pool_io).await;
+        while !client.is_subscribed() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        client
+            .router
+            .read()
+            .await
+            .as_ref()
+            .unwrap()
+            .send(StratumClientRequest::Stop, SEND_TIMEOUT)
+            .await
+            .unwrap();
+        assert_eq!(session.await.unwrap(), DisconnectReason::StoppedByUser);
+
+        // the pool hangs up before acknowledging the subscribe, there is no session to close
+        let (client_io, pool_io) = duplex(4096);
+        let session = task::spawn(StratumClient::handle_io_message(client.clone(), client_io));
+        drop(pool_io);
+        assert!(matches!(
+            session.await.unwrap(),
+            DisconnectReason::RemoteClosed | DisconnectReason::WriteError(_)
+        ));
+
+        let disconnects = client.disconnects().await;
+        assert_eq!(disconnects.len(), 2);
+        assert_eq!(disconnects[0].reason, DisconnectReason::StoppedByUser);
+        assert_eq!(disconnects[0].epoch, Some(1));
+        assert_eq!(disconnects[1].epoch, None);
+        assert_eq!(client.closed_sessions().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_lenient_decode_keeps_session() {
+        let mut config = test_client().config.clone();
+        config.lenient_decode = true;
+        let client = StratumClient::new(config);
+        let (client_io, pool_io) = duplex(4096);
+        let _session = task::spawn(StratumClient::handle_io_message(client.clone(), client_io));
+        let (_r, w) = accept_subscribe(pool_io).await;
+        let mut w = w.into_inner();
+        tokio::io::AsyncWriteExt::write_all(&mut w, b"not json\n")
+            .await
+            .unwrap();
+        let mut w = FramedWrite::new(w, StratumMessageCodec::default());
+        w.send(notify(1)).await.unwrap();
+        while client.session().await.map(|session| session.notifies) != Some(1) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(client.closed_sessions().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_protocol_errors() {
+        let mut config = test_client().config.clone();
+        config.lenient_decode = true;
+        config.max_protocol_errors_per_min = Some(4);
+        let client = StratumClient::new(config);
+        let (client_io, pool_io) = duplex(4096);
+        let session = task::spawn(StratumClient::handle_io_message(client.clone(), client_io));
+        let (_r, w) = accept_subscribe(pool_io).await;
+        let mut w = w.into_inner();
+        let oversize = format!("{}\n", "a".repeat(MAX_LINE_LENGTH + 1));
+        for line in [
+            "not json\n",
+            "{\"id\":1,\"jsonrpc\":\"2.0\"}\n",
+            "{\"id\":1,\"method\":\"mining.ping\"}\n",
+            &oversize,
+        ] {
+            tokio::io::AsyncWriteExt::write_all(&mut w, line.as_bytes())
+                .await
+                .unwrap();
+        }
+        let mut w = FramedWrite::new(w, StratumMessageCodec::default());
+        w.send(notify(1)).await.unwrap();
+        while client.session().await.map(|session| session.notifies) != Some(1) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let errors = ProtocolErrors {
+            decode_errors: 1,
+            wrong_shape: 1,
+            unknown_methods: 1,
+            oversize_frames: 1,
+        };
+        assert_eq!(client.protocol_errors().await, errors);
+        // an unknown method does not pause mining
+        assert!(client.is_subscribed());
+
+        // one more within the minute is one too many
+        let mut w = w.into_inner();
+        tokio::io::AsyncWriteExt::write_all(&mut w, b"not json\n")
+            .await
+            .unwrap();
+        assert_eq!(session.await.unwrap(), DisconnectReason::ProtocolErrors);
+        let disconnects = client.disconnects().await;
+        assert_eq!(disconnects[0].reason, DisconnectReason::ProtocolErrors);
+        assert_eq!(disconnects[0].protocol_errors.decode_errors, 2);
+        assert_eq!(client.protocol_errors().await.total(), 5);
+    }
+
+    /// Mines job 5 on a session that then drops, finds a share in the grace period and
+    /// reconnects to a pool whose first job is `next_job`. Returns the pool side.
+    async fn reconnect_with_held_share(client: &Arc<StratumClient>, next_job: u32) -> PoolHalves {
+        let (client_io, pool_io) = duplex(4096);
+        let session = task::spawn(StratumClient::handle_io_message(client.clone(), client_io));
+        let (r, mut w) = accept_subscribe(pool_io).await;
+        w.send(notify(5)).await.unwrap();
+        while client.session().await.map(|session| session.notifies) != Some(1) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        drop((r, w));
+        assert_eq!(session.await.unwrap(), DisconnectReason::RemoteClosed);
+        client.connection_lost().await;
+        assert!(!client.is_subscribed());
+        assert!(client.can_mine().await);
+
+        client
+            .submit(5, String::from("00000000000004d2"), None)
+            .await;
+        assert_eq!(client.held_submits.read().await.len(), 1);
+        assert!(client.pending_submits.read().await.is_empty());
+
+        let (client_io, pool_io) = duplex(4096);
+        let _session = task::spawn(StratumClient::handle_io_message(client.clone(), client_io));
+        let (r, mut w) = accept_subscribe(pool_io).await;
+        w.send(notify(next_job)).await.unwrap();
+        (r, w)
+    }
+
+    fn grace_client() -> Arc<StratumClient> {
+        let mut config = test_client().config.clone();
+        config.mine_through_disconnects = Duration::from_secs(60);
+        StratumClient::new(config)
+    }
+
+    #[tokio::test]
+    async fn test_held_share_same_job_after_reconnect() {
+        let client = grace_client();
+        let (mut r, _w) = reconnect_with_held_share(&client, 5).await;
+        match tokio::time::timeout(Duration::from_secs(5), r.next()).await {
+            Ok(Some(Ok(StratumMessage::MiningSubmitMessage(message)))) => {
+                assert_eq!(message.body.miningRequestId, 5);
+                assert_eq!(message.body.randomness, "00000000000004d2");
+            }
+            other => panic!("expected the held share, got {:?}", other),
+        }
+        assert!(client.held_submits.read().await.is_empty());
+        assert!(client.can_mine().await);
+    }
+
+    #[tokio::test]
+    async fn test_held_share_new_job_after_reconnect() {
+        let client = grace_client();
+        let (mut r, _w) = reconnect_with_held_share(&client, 6).await;
+        while client.session().await.map(|session| session.notifies) != Some(1) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(client.held_submits.read().await.is_empty());
+        assert!(client.pending_submits.read().await.is_empty());
+        assert!(
+            tokio::time::timeout(Duration::from_millis(200), r.next())
+                .await
+                .is_err(),
+            "the stale share should not be submitted"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_no_grace_by_default() {
+        let client = test_client();
+        client.subscribed.store(true, Ordering::SeqCst);
+        client.connection_lost().await;
+        assert!(!client.can_mine().await);
+        client
+            .submit(5, String::from("00000000000004d2"), None)
+            .await;
+        assert!(client.held_submits.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_stale_pending_submit_is_dropped() {
+        let client = test_client();
+        client
+            .queue_pending_submit(MiningSubmitMessage {
+                id: 0.into(),
+                method: String::from("mining.submit"),
+                body: MiningSubmitBody {
+                    miningRequestId: 7,
+                    randomness: String::from("00000000000004d2"),
+                    elapsedMs: None,
+                },
+            })
+            .await;
+        client.pending_submits.write().await[0].queued_at -= PENDING_SUBMIT_TTL * 2;
+        let (client_io, _pool_io) = duplex(4096);
+        let mut session = StratumSession::new(client_io, false);
+        client.resend_pending_submits(&mut session).await.unwrap();
+        assert!(client.pending_submits.read().await.is_empty());
+    }
+
+    /// A pool that gives every worker the same target and job, and passes on what each sends
+    /// after its subscribe, with its name. The worker whose name goes to the returned sender
+    /// is disconnected.
+    async fn spawn_worker_pool() -> (
+        SocketAddr,
+        mpsc::UnboundedReceiver<(String, StratumMessage)>,
+        broadcast::Sender<String>,
+    ) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (messages, received) = mpsc::unbounded_channel();
+        let (kick, _) = broadcast::channel(4);
+        let kicks = kick.clone();
+        task::spawn(async move {
+            for client_id in 1.. {
+                let (stream, _) = listener.accept().await.unwrap();
+                let messages = messages.clone();
+                let mut kicks = kicks.subscribe();
+                task::spawn(async move {
+                    let mut pool = MockPool::new(stream);
+                    let name = match pool.next().await {
+                        Some(StratumMessage::MiningSubscribeMessage(message)) => message.body.name,
+                        _ => return,
+                    };
+                    let subscribed = subscribed_message(client_id, "zk.work", None);
+                    if !pool.send_all([subscribed, set_target(), notify(5)]).await {
+                        return;
+                    }
+                    loop {
+                        tokio::select! {
+                            kicked = kicks.recv() => if matches!(kicked, Ok(kicked) if kicked == name) {
+                                return;
+                            },
+                            message = pool.next() => match message {
+                                Some(message) => {
+                                    let _ = messages.send((name.clone(), message));
+                                }
+                                _ => return,
+                            },
+                        }
+                    }
+                });
+            }
+        });
+        (address, received, kick)
+    }
+
+    /// Which worker the next submit arrives from, and its randomness.
+    async fn next_submit(
+        received: &mut mpsc::UnboundedReceiver<(String, StratumMessage)>,
+    ) -> (String, String) {
+        match tokio::time::timeout(Duration::from_secs(5), received.recv()).await {
+            Ok(Some((worker, StratumMessage::MiningSubmitMessage(message)))) => {
+                (worker, message.body.randomness)
+            }
+            other => panic!("expected a submit, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dual_connection() {
+        let (pool, mut received, kick) = spawn_worker_pool().await;
+        let mut config = test_client().config.clone();
+        config.pool_address = pool.into();
+        config.dual_connection = true;
+        let client = StratumClient::new(config);
+        StratumClient::start(client.clone()).await;
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let submit_subscribed =
+            || async { client.submit_connection_stats().await.unwrap().subscribed };
+        while client.session().await.map(|session| session.notifies) != Some(1)
+            || !submit_subscribed().await
+        {
+            assert!(Instant::now() < deadline, "not connected");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        // the job came on the main connection, the share goes out on the other one
+        client
+            .submit(5, String::from("0000000000000001"), None)
+            .await;
+        assert_eq!(
+            next_submit(&mut received).await,
+            (String::from("xxxxxx-tx"), String::from("0000000000000001"))
+        );
+        // the job the pool also sent on the submit connection was not taken as a new one
+        assert_eq!(client.session().await.unwrap().notifies, 1);
+
+        // while it is down the main connection takes the shares
+        kick.send(String::from("xxxxxx-tx")).unwrap();
+        while submit_subscribed().await {
+            assert!(Instant::now() < deadline, "submit connection not closed");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        client
+            .submit(5, String::from("0000000000000002"), None)
+            .await;
+        assert_eq!(
+            next_submit(&mut received).await,
+            (String::from("xxxxxx"), String::from("0000000000000002"))
+        );
+        let stats = client.submit_connection_stats().await.unwrap();
+        assert_eq!(stats.shares_submitted, 1);
+        assert_eq!(stats.shares_fallback, 1);
+        assert_eq!(stats.disconnects[0].reason, DisconnectReason::RemoteClosed);
+        // the main connection was not affected
+        assert!(client.disconnects().await.is_empty());
+
+        // and back on the submit connection once it reconnected
+        while !submit_subscribed().await {
+            assert!(Instant::now() < deadline, "submit connection not back");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        client
+            .submit(5, String::from("0000000000000003"), None)
+            .await;
+        assert_eq!(next_submit(&mut received).await.0, "xxxxxx-tx");
+        assert_eq!(client.submit_connection_stats().await.unwrap().sessions, 2);
+        client.stop().await;
+        assert!(!submit_subscribed().await);
+    }
+
+    #[cfg(feature = "tls-native")]
+    #[tokio::test]
+    async fn test_http_proxy() {
+        let listener = MockPoolListener::bind_tls().await;
+        let proxy = ConnectProxy::spawn(Some(("miner", "secret"))).await;
+        let mut config = test_client().config.clone();
+        config.pool_address = listener.address().into();
+        config.tls = true;
+        config.proxy = Some(
+            format!("http://miner:secret@{}", proxy.address())
+                .parse()
+                .unwrap(),
+        );
+        let client = StratumClient::new(config);
+        StratumClient::start(client.clone()).await;
+        let mut pool = listener.accept_tls().await;
+        pool.accept_subscribe().await;
+        assert!(pool.send(notify(5)).await);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while client.session().await.map(|session| session.notifies) != Some(1) {
+            assert!(Instant::now() < deadline, "no job");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(proxy.tunnels(), 1);
+        assert_eq!(
+            client.connections().await[0].remote_addr,
+            Some(proxy.address().to_string())
+        );
+
+        // the proxy drops the tunnel, the client opens another
+        proxy.close_tunnels();
+        let mut pool = listener.accept_tls().await;
+        pool.accept_subscribe().await;
+        assert_eq!(proxy.tunnels(), 2);
+        let disconnects = client.disconnects().await;
+        assert_eq!(disconnects[0].reason, DisconnectReason::ProxyClosed);
+        client.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_compression() {
+        let listener = MockPoolListener::bind().await;
+        let mut config = test_client().config.clone();
+        config.pool_address = listener.address().into();
+        config.compress = true;
+        let client = StratumClient::new(config);
+        StratumClient::start(client.clone()).await;
+        let mut pool = listener.accept().await;
+        let subscribe = pool.expect_subscribe().await;
+        assert!(subscribe
+            .capabilities
+            .contains(&String::from(CAPABILITY_DEFLATE)));
+        assert!(
+            pool.send(subscribed_message(
+                1,
+                "zk.work",
+                Some(vec![String::from(CAPABILITY_DEFLATE)])
+            ))
+            .await
+        );
+        let counts = pool.compress();
+        assert!(pool.easy_job(5, &"00".repeat(208)).await);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while client.session().await.map(|session| session.notifies) != Some(1) {
+            assert!(Instant::now() < deadline, "no job");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        client
+            .submit(5, String::from("00000000000004d2"), None)
+            .await;
+        assert_eq!(pool.expect_submit().await.miningRequestId, 5);
+
+        let compression = client.connections().await[0].compression.unwrap();
+        // what one side wrote, the other read
+        let pool_side = counts.stats();
+        assert_eq!(
+            (compression.json_in, compression.wire_in),
+            (pool_side.json_out, pool_side.wire_out)
+        );
+        assert_eq!(
+            (compression.json_out, compression.wire_out),
+            (pool_side.json_in, pool_side.wire_in)
+        );
+        assert!(compression.wire_in < compression.json_in);
+        assert!(compression.wire_out > 0);
+        // the deflate window can not go along with the connection
+        assert!(client.hand_over().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_http_proxy_credentials() {
+        let listener = MockPoolListener::bind().await;
+        let proxy = ConnectProxy::spawn(Some(("miner", "secret"))).await;
+        let mut config = test_client().config.clone();
+        config.pool_address = listener.address().into();
+        config.proxy = Some(format!("http://{}", proxy.address()).parse().unwrap());
+        let error = StratumClient::new(config).connect().await.unwrap_err();
+        assert!(
+            error.to_string().contains("requires credentials"),
+            "{}",
+            error
+        );
+        assert_eq!(proxy.tunnels(), 0);
+    }
+
+    #[cfg(feature = "tls-native")]
+    #[tokio::test]
+    async fn test_connection_stats() {
+        let listener = MockPoolListener::bind_tls().await;
+        let mut config = test_client().config.clone();
+        config.pool_address = listener.address().into();
+        config.tls = true;
+        let client = StratumClient::new(config);
+        StratumClient::start(client.clone()).await;
+        let mut pool = listener.accept_tls().await;
+        pool.accept_subscribe().await;
+        assert!(pool.send(notify(5)).await);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while client.session().await.map(|session| session.notifies) != Some(1) {
+            assert!(Instant::now() < deadline, "no job");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let open = client.connections().await;
+        assert_eq!(open.len(), 1);
+        assert!(open[0].tls);
+        assert!(open[0].handshake_ms.is_some());
+        assert!(open[0].connect_ms.is_some());
+        // nothing was offered
+        assert_eq!(open[0].tls_protocol, None);
+        assert_eq!(open[0].remote_addr, Some(listener.address().to_string()));
+        assert!(open[0].local_addr.is_some());
+        assert_eq!(open[0].disconnect_reason, None);
+
+        // the pool goes away, the client connects again
+        drop(pool);
+        let _pool = listener.accept_tls().await;
+        let connections = client.connections().await;
+        let closed = &connections[0];
+        assert!(closed.disconnect_reason.is_some());
+        assert!(closed.tls);
+        // TLS records are bigger than the messages in them
+        assert!(closed.bytes_in > 0 && closed.bytes_out > 0);
+        assert_eq!((closed.frames_in, closed.frames_out), (2, 1));
+        assert!(closed.duration_ms.is_some());
+        assert_eq!(client.buffer_stats().await.connections.len, 1);
+        client.stop().await;
+    }
+
+    /// A pool that drops every session right after the subscribe, as behind a flapping load
+    /// balancer. The delay before reconnecting grows with every drop once the pool flaps, and
+    /// is back to normal after a session that stays up.
+    #[tokio::test]
+    async fn test_flapping_pool() {
+        let listener = MockPoolListener::bind().await;
+        let mut config = test_client().config.clone();
+        config.pool_address = listener.address().into();
+        config.flap_policy = FlapPolicy {
+            threshold: 3,
+            window: Duration::from_secs(2),
+        };
+        config.reconnect_policy = RetryPolicy {
+            initial: Duration::from_millis(20),
+            max_delay: Duration::from_millis(160),
+            jitter: 0.0,
+            ..RECONNECT_POLICY
+        };
+        let clock = ManualClock::new();
+        let client =
+            StratumClient::with_clock(config, PoolEnd::detached(), Arc::new(clock.clone()));
+        StratumClient::start(client.clone()).await;
+        let mut delays = vec![];
+        let mut pool = None;
+        for _ in 0..8 {
+            if let Some(pool) = pool.take() {
+                drop(pool);
+                let delay = next_backoff(&clock, delays.len()).await;
+                delays.push(delay.as_millis());
+                clock.advance(delay);
+            }
+            let mut next = listener.accept().await;
+            next.accept_subscribe().await;
+            while !client.is_subscribed() {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+            pool = Some(next);
+        }
+        // the fourth drop within the window started the flapping, each one after doubled
+        // the delay up to the maximum
+        assert_eq!(delays, [20, 20, 20, 40, 80, 160, 160]);
+        let stats = client.flap_stats();
+        assert!(stats.flapping);
+        assert_eq!(stats.episodes, 1);
+        assert_eq!(stats.cycles, 7);
+        assert_eq!(stats.delay_ms, 160);
+
+        // a session that stays up for the window ends it
+        clock.advance(Duration::from_millis(2100));
+        drop(pool);
+        let delay = next_backoff(&clock, delays.len()).await;
+        assert_eq!(delay, Duration::from_millis(20));
+        clock.advance(delay);
+        let mut next = listener.accept().await;
+        next.accept_subscribe().await;
+        let stats = client.flap_stats();
+        assert!(!stats.flapping);
+        assert_eq!(stats.episodes, 1);
+        assert_eq!(stats.delay_ms, 20);
+        client.stop().await;
+    }
+
+    /// The first pool goes away for good after a share, the client fails over to the second
+    /// and mines there. Each pool keeps its own stats.
+    #[tokio::test]
+    async fn test_failover() {
+        let primary = MockPoolListener::bind().await;
+        let secondary = MockPoolListener::bind().await;
+        let mut config = test_client().config.clone();
+        config.pool_address = primary.address().into();
+        config.failover_pools = vec![secondary.address().into()];
+        config.failover_after = 2;
+        config.reconnect_policy = RetryPolicy {
+            initial: Duration::from_millis(20),
+            jitter: 0.0,
+            ..RECONNECT_POLICY
+        };
+        let client = StratumClient::new(config);
+        StratumClient::start(client.clone()).await;
+
+        let mut pool = primary.accept().await;
+        mine_one_share(&client, &mut pool, 5).await;
+        // gone for good: the listener first, so that the reconnects are refused
+        drop(primary);
+        drop(pool);
+        let mut pool = secondary.accept().await;
+        mine_one_share(&client, &mut pool, 6).await;
+        assert_eq!(client.pool_index(), 1);
+        assert_eq!(
+            client.pool_address().to_string(),
+            secondary.address().to_string()
+        );
+
+        let pools = client.pool_stats();
+        assert_eq!(pools.len(), 2);
+        let first = &pools[0];
+        assert!(!first.current);
+        assert_eq!(
+            (first.connect_attempts, first.connects, first.sessions),
+            (3, 1, 1)
+        );
+        assert_eq!((first.shares_submitted, first.shares_accepted), (1, 1));
+        assert!(first.submit_latency_ms.is_some());
+        assert_eq!(
+            first.disconnects,
+            BTreeMap::from([(String::from("remote_closed"), 1)])
+        );
+        let second = &pools[1];
+        assert!(second.current);
+        assert_eq!(second.address, secondary.address().to_string());
+        assert_eq!(
+            (second.connect_attempts, second.connects, second.sessions),
+            (1, 1, 1)
+        );
+        assert_eq!((second.shares_submitted, second.shares_accepted), (1, 1));
+        assert!(second.submit_latency_ms.is_some());
+        assert!(second.disconnects.is_empty());
+
+        let connections = client.connections().await;
+        assert_eq!(connections[0].pool_index, 0);
+        assert_eq!(connections[1].pool_index, 1);
+        assert_eq!(client.session().await.unwrap().pool_index, 1);
+        // the next process starts on the first pool again
+        assert!(client.hand_over().await.is_none());
+    }
+
+    /// Subscribes on `pool`, hands out job `mining_request_id`, and accepts the share the
+    /// client submits for it.
+    async fn mine_one_share(
+        client: &Arc<StratumClient>,
+        pool: &mut MockPool<TcpStream>,
+        mining_request_id: u32,
+    ) {
+        pool.accept_subscribe().await;
+        assert!(pool.easy_job(mining_request_id, &"00".repeat(208)).await);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while client.session().await.map(|session| session.notifies) != Some(1) {
+            assert!(Instant::now() < deadline, "no job");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        client
+            .submit(mining_request_id, String::from("00000000000004d2"), None)
+            .await;
+        let submit = match pool.next().await {
+            Some(StratumMessage::MiningSubmitMessage(message)) => message,
+            other => panic!("expected a submit, got {:?}", other),
+        };
+        assert!(pool.send(submitted_message(submit.id, true)).await);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while client.pool_stats()[client.pool_index()].shares_accepted != 1 {
+            assert!(Instant::now() < deadline, "no ack");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    /// Waits for the reconnect backoff after the `seen` ones before it, by the sleeps of the
+    /// client other than its idle timeouts.
+    async fn next_backoff(clock: &ManualClock, seen: usize) -> Duration {
+        loop {
+            let backoffs = clock
+                .sleeps()
+                .into_iter()
+                .filter(|sleep| *sleep != POOL_IDLE_TIMEOUT)
+                .collect::<Vec<_>>();
+            if let Some(delay) = backoffs.get(seen) {
+                return *delay;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    }
+
+    /// Three hours of a pool that drops the connection every minute, each time right as a
+    /// share is written, with more shares found during the grace period than are held. No
+    /// buffer may grow past its cap.
+    #[tokio::test]
+    async fn test_buffers_stay_within_caps() {
+        const MINUTES: u64 = 180;
+        const HELD_PER_MINUTE: u64 = MAX_HELD_SUBMITS as u64 + 16;
+        let mut config = grace_client().config.clone();
+        config.max_submit_rate = "100000/1s".parse().unwrap();
+        let client = StratumClient::new(config);
+        let meter = Meter::new();
+        let start = Instant::now();
+        for minute in 0..MINUTES {
+            let (client_io, pool_io) = duplex(4096);
+            let fail_writes = Arc::new(AtomicBool::new(false));
+            let stream = FlakyStream {
+                inner: client_io,
+                fail_writes: fail_writes.clone(),
+            };
+            let session = task::spawn(StratumClient::handle_io_message(client.clone(), stream));
+            let (mut r, mut w) = accept_subscribe(pool_io).await;
+            // the held shares are re-sent after the notify
+            w.send(notify(5)).await.unwrap();
+            let received = Arc::new(AtomicUsize::new(0));
+            let counter = received.clone();
+            task::spawn(async move {
+                while let Some(Ok(_)) = r.next().await {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                }
+            });
+            // the share whose write failed and the held ones, all written before writes fail
+            let resent = if minute == 0 { 0 } else { 1 + MAX_HELD_SUBMITS };
+            while received.load(Ordering::SeqCst) < resent
+                || client.session().await.map(|session| session.notifies) != Some(1)
+            {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+            fail_writes.store(true, Ordering::SeqCst);
+            client.submit(5, format!("{:016x}", minute), None).await;
+            tokio::time::timeout(Duration::from_secs(5), session)
+                .await
+                .expect("session should end after a failed write")
+                .unwrap();
+            client.connection_lost().await;
+            for share in 0..HELD_PER_MINUTE {
+                client
+                    .submit(
+                        5,
+                        format!("{:016x}", minute * HELD_PER_MINUTE + share),
+                        None,
+                    )
+                    .await;
+            }
+            for second in 1..=60 {
+                meter
+                    .sample(start + Duration::from_secs(minute * 60 + second))
+                    .await;
+            }
+
+            let buffers = BufferStats {
+                meter_history_1s: meter.history_usage(HistoryWindow::Second).await,
+                meter_history_1m: meter.history_usage(HistoryWindow::Minute).await,
+                ..client.buffer_stats().await
+            };
+            assert!(buffers.within_caps(), "minute {}: {:?}", minute, buffers);
+        }
+
+        let buffers = BufferStats {
+            meter_history_1s: meter.history_usage(HistoryWindow::Second).await,
+            meter_history_1m: meter.history_usage(HistoryWindow::Minute).await,
+            ..client.buffer_stats().await
+        };
+        // the buffers filled up and dropped their oldest entries, the held shares are all
+        // re-sent on the next connection
+        assert_eq!(buffers.held_submits.len, MAX_HELD_SUBMITS);
+        assert_eq!(buffers.held_submits.dropped, 16 * MINUTES);
+        assert!(buffers.pending_submits.len <= 1);
+        assert_eq!(buffers.session_history.len, 10);
+        assert_eq!(buffers.session_history.dropped, MINUTES - 10);
+        assert_eq!(
+            buffers.first_shares.dropped,
+            MINUTES - MAX_FIRST_SHARES as u64
+        );
+        assert_eq!(
+            buffers.disconnects.dropped,
+            MINUTES - MAX_DISCONNECTS as u64
+        );
+        assert_eq!(buffers.meter_history_1s.len, METER_HISTORY_SECONDS);
+        assert_eq!(buffers.meter_history_1m.len, MINUTES as usize);
     }
 }
@@ -0,0 +1,742 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::{
+    Capabilities, CoalescingWriter, CompressionCounts, DisconnectReason, DumpTap, FlushPolicy,
+    MessageCounts, MessageId, MiningAuthorizeBody, MiningAuthorizeMessage, MiningHashrateMessage,
+    MiningNotifyBody, MiningNotifyMessage, MiningSetTargetBody, MiningSetTargetMessage,
+    MiningSubmitMessage, MiningSubmittedBody, MiningSubmittedMessage, MiningSubscribeBody,
+    MiningSubscribeMessage, MiningSubscribedBody, MiningSubscribedMessage,
+    MiningWaitForWorkMessage, ProtocolErrors, StratumMessage, StratumMessageCodec, SubmitFirst,
+    CAPABILITY_DEFLATE,
+};
+use bytes::BytesMut;
+use log::*;
+use std::{collections::VecDeque, fmt, sync::Arc, time::Duration};
+use tokio::{
+    io::{split, AsyncRead, AsyncWrite, ReadHalf, WriteHalf},
+    time::{self, Instant},
+};
+use tokio_stream::StreamExt;
+use tokio_util::codec::{Encoder, FramedRead};
+
+/// How long the pool has to acknowledge the subscribe.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Messages kept when a pool sends them ahead of the subscribe ack.
+const MAX_EARLY_MESSAGES: usize = 8;
+
+/// What the pool told us. Most pools do not acknowledge submits, those that do send a
+/// [`SessionEvent::SubmitAck`] for each.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SessionEvent {
+    NewTarget(String),
+    NewJob {
+        mining_request_id: u32,
+        header: String,
+        /// Pool time in milliseconds since the unix epoch, not sent by every pool.
+        timestamp: Option<u64>,
+        /// How long the job is worth mining, not sent by every pool.
+        expires_in: Option<Duration>,
+    },
+    WaitForWork,
+    /// The pool's answer to the submit of message `id`.
+    SubmitAck {
+        id: MessageId,
+        accepted: bool,
+        /// Why the share was rejected, if the pool says.
+        message: Option<String>,
+    },
+    /// A message that has no meaning after the subscribe.
+    Unknown(StratumMessage),
+}
+
+/// Why a session can not go on, and what to log about it.
+#[derive(Debug)]
+pub struct SessionError {
+    pub reason: DisconnectReason,
+    pub message: String,
+}
+
+impl SessionError {
+    fn new(reason: DisconnectReason, message: String) -> Self {
+        Self { reason, message }
+    }
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+/// The stratum protocol over one pool connection: the subscribe handshake, typed events for
+/// the pool messages and the submits. Connection management and retries are left to the
+/// caller.
+pub struct StratumSession<T> {
+    reader: FramedRead<ReadHalf<T>, StratumMessageCodec>,
+    writer: CoalescingWriter<WriteHalf<T>>,
+    /// Messages that came ahead of the subscribe ack, handed out first.
+    early: VecDeque<StratumMessage>,
+    counts: MessageCounts,
+    /// Set once the pool agreed to compress the lines after the subscribe ack.
+    compression: Option<Arc<CompressionCounts>>,
+}
+
+impl<T: AsyncRead + AsyncWrite> StratumSession<T> {
+    /// With `lenient` set, undecodable lines from the pool are skipped instead of ending the
+    /// session.
+    pub fn new(stream: T, lenient: bool) -> Self {
+        Self::with_flush_policy(stream, lenient, Arc::new(SubmitFirst))
+    }
+
+    /// A session whose writes are flushed as `policy` says, see [`CoalescingWriter`].
+    pub fn with_flush_policy(stream: T, lenient: bool, policy: Arc<dyn FlushPolicy>) -> Self {
+        let (r, w) = split(stream);
+        let codec = if lenient {
+            StratumMessageCodec::lenient()
+        } else {
+            StratumMessageCodec::default()
+        };
+        Self {
+            reader: FramedRead::new(r, codec),
+            writer: CoalescingWriter::with_policy(w, policy),
+            early: VecDeque::new(),
+            counts: MessageCounts::default(),
+            compression: None,
+        }
+    }
+
+    /// A session another process subscribed, with what it read from the pool but did not
+    /// decode yet. `replay` is handed out first, ahead of those bytes.
+    pub fn resume(stream: T, lenient: bool, buffered: &[u8], replay: Vec<StratumMessage>) -> Self {
+        let mut session = Self::new(stream, lenient);
+        session.reader.read_buffer_mut().extend_from_slice(buffered);
+        session.early.extend(replay);
+        session
+    }
+
+    /// Ends the session without closing the connection, and returns it with what was read
+    /// but not handed out yet: messages held back, encoded again, then the undecoded bytes.
+    /// What was written but not flushed is dropped, see [`Self::flush`].
+    pub fn into_parts(self) -> (T, Vec<u8>)
+    where
+        T: Unpin,
+    {
+        let mut buffered = BytesMut::new();
+        let mut codec = StratumMessageCodec::default();
+        for message in self.early {
+            // only fails on a failing writer, which a buffer is not
+            let _ = codec.encode(message, &mut buffered);
+        }
+        buffered.extend_from_slice(self.reader.read_buffer());
+        let stream = self.reader.into_inner().unsplit(self.writer.into_inner());
+        (stream, buffered.to_vec())
+    }
+
+    /// Messages read and written so far.
+    pub fn counts(&self) -> MessageCounts {
+        self.counts
+    }
+
+    /// What compression saved on the connection, `None` unless the pool agreed to
+    /// [`CAPABILITY_DEFLATE`].
+    pub fn compression(&self) -> Option<&Arc<CompressionCounts>> {
+        self.compression.as_ref()
+    }
+
+    /// Writes to the connection so far, see [`CoalescingWriter::flushes`].
+    pub fn flushes(&self) -> u64 {
+        self.writer.flushes()
+    }
+
+    /// When the messages written but not flushed yet are due, `None` if there are none.
+    pub fn flush_deadline(&self) -> Option<Instant> {
+        self.writer.flush_deadline()
+    }
+
+    /// Flushes what was written since the last flush.
+    pub async fn flush(&mut self) -> Result<(), SessionError> {
+        self.writer.flush().await.map_err(|error| {
+            SessionError::new(
+                DisconnectReason::from_write_error(&error),
+                format!("[Stratum flush] {}, reconnecting", error),
+            )
+        })
+    }
+
+    /// Records the lines read and written from now on, see [`crate::StratumDump`].
+    pub fn record_to(&mut self, tap: DumpTap) {
+        self.reader.decoder_mut().record_to(tap.clone());
+        self.writer.record_to(tap);
+    }
+
+    /// Lines from the pool so far that were not usable messages.
+    pub fn protocol_errors(&self) -> ProtocolErrors {
+        self.reader.decoder().protocol_errors()
+    }
+
+    /// Ends the session once more than `per_min` protocol errors came within a minute.
+    pub fn limit_protocol_errors(&mut self, per_min: u32) {
+        self.reader.decoder_mut().limit_errors(per_min);
+    }
+
+    /// Subscribes and waits for the ack, which carries the client id and graffiti the pool
+    /// assigned. Some pools push the target and a job along with, or even before, the ack.
+    /// Those are kept and returned by [`Self::next_event`] first. If `body` offers
+    /// [`CAPABILITY_DEFLATE`] and the pool agrees, the lines after the ack are compressed.
+    pub async fn subscribe(
+        &mut self,
+        id: i64,
+        body: MiningSubscribeBody,
+    ) -> Result<MiningSubscribedBody, SessionError> {
+        let offers_deflate = body
+            .capabilities
+            .iter()
+            .any(|capability| capability == CAPABILITY_DEFLATE);
+        let subscribe = StratumMessage::MiningSubscribeMessage(MiningSubscribeMessage {
+            id: id.into(),
+            method: String::from("mining.subscribe"),
+            body,
+        });
+        // the ack is waited for, whatever the policy
+        let written = match self.writer.write(subscribe).await {
+            Ok(()) => self.writer.flush().await,
+            Err(error) => Err(error),
+        };
+        written.map_err(|error| {
+            SessionError::new(
+                DisconnectReason::from_write_error(&error),
+                format!("[Connect pool] {}", error),
+            )
+        })?;
+        self.counts.sent += 1;
+        let deadline = Instant::now() + HANDSHAKE_TIMEOUT;
+        loop {
+            let message = time::timeout_at(deadline, self.reader.next())
+                .await
+                .map_err(|_| {
+                    SessionError::new(
+                        DisconnectReason::SubscribeTimeout,
+                        format!(
+                            "connect pool error, no subscribe ack within {:?}",
+                            HANDSHAKE_TIMEOUT
+                        ),
+                    )
+                })?;
+            match message {
+                Some(Ok(StratumMessage::MiningSubscribedMessage(MiningSubscribedMessage {
+                    id,
+                    method,
+                    body,
+                }))) => {
+                    debug!(
+                        "message id({}) method({}) stratum client id({}) graffiti({})",
+                        id, method, body.clientId, body.graffiti
+                    );
+                    self.counts.received += 1;
+                    if offers_deflate
+                        && Capabilities::negotiate(body.capabilities.clone())
+                            .uses(CAPABILITY_DEFLATE)
+                    {
+                        self.enable_compression();
+                    }
+                    return Ok(body);
+                }
+                // counted when handed out
+                Some(Ok(message)) if self.early.len() < MAX_EARLY_MESSAGES => {
+                    debug!("holding {:?} received before the subscribe ack", message);
+                    self.early.push_back(message);
+                }
+                Some(Ok(_)) => {
+                    return Err(SessionError::new(
+                        DisconnectReason::DecodeError,
+                        String::from("connect pool error, unexpected response message"),
+                    ))
+                }
+                Some(Err(error)) => {
+                    return Err(SessionError::new(
+                        DisconnectReason::from_read_error(&error),
+                        format!("[Connect pool] {}", error),
+                    ))
+                }
+                None => {
+                    return Err(SessionError::new(
+                        DisconnectReason::RemoteClosed,
+                        String::from("connect pool error, closed before the subscribe ack"),
+                    ))
+                }
+            }
+        }
+    }
+
+    /// Compresses the lines written from now on, and reads compressed lines.
+    fn enable_compression(&mut self) {
+        let counts = Arc::new(CompressionCounts::default());
+        self.reader.decoder_mut().decompress_reads(counts.clone());
+        self.writer.compress_writes(counts.clone());
+        self.compression = Some(counts);
+    }
+
+    /// Waits for the next message from the pool. Cancel safe, so it can be raced against
+    /// other work.
+    pub async fn next_event(&mut self) -> Result<SessionEvent, SessionError> {
+        let message = match self.early.pop_front() {
+            Some(message) => message,
+            // the stream is terminated after a decode error
+            None => match self.reader.next().await {
+                Some(Ok(message)) => message,
+                Some(Err(error)) => {
+                    return Err(SessionError::new(
+                        DisconnectReason::from_read_error(&error),
+                        format!("failed to read message from server: {}", error),
+                    ))
+                }
+                None => {
+                    return Err(SessionError::new(
+                        DisconnectReason::RemoteClosed,
+                        String::from("failed to read message from server"),
+                    ))
+                }
+            },
+        };
+        self.counts.received += 1;
+        Ok(match message {
+            message if !message.has_known_method() => SessionEvent::Unknown(message),
+            // 'mining.set_target'
+            StratumMessage::MiningSetTargetMessage(MiningSetTargetMessage {
+                id,
+                method,
+                body: MiningSetTargetBody { target },
+            }) => {
+                debug!("message id({}) method({}) target({})", id, method, target);
+                SessionEvent::NewTarget(target)
+            }
+            // 'mining.notify'
+            StratumMessage::MiningNotifyMessage(MiningNotifyMessage {
+                id,
+                method,
+                body:
+                    MiningNotifyBody {
+                        miningRequestId: mining_request_id,
+                        header,
+                        timestamp,
+                        expiresInMs: expires_in_ms,
+                    },
+            }) => {
+                debug!(
+                    "message id({}) method({}) mining request id({}) header({})",
+                    id, method, mining_request_id, header
+                );
+                SessionEvent::NewJob {
+                    mining_request_id,
+                    header,
+                    timestamp,
+                    expires_in: expires_in_ms.map(Duration::from_millis),
+                }
+            }
+            // 'mining.wait_for_work'
+            StratumMessage::MiningWaitForWorkMessage(MiningWaitForWorkMessage { id, method }) => {
+                debug!("message id({}) method({})", id, method);
+                SessionEvent::WaitForWork
+            }
+            // 'mining.submitted'
+            StratumMessage::MiningSubmittedMessage(MiningSubmittedMessage {
+                id,
+                method,
+                body:
+                    MiningSubmittedBody {
+                        id: submit_id,
+                        result,
+                        message,
+                    },
+            }) => {
+                debug!(
+                    "message id({}) method({}) submit id({}) result({})",
+                    id, method, submit_id, result
+                );
+                SessionEvent::SubmitAck {
+                    id: submit_id,
+                    accepted: result,
+                    message,
+                }
+            }
+            message => SessionEvent::Unknown(message),
+        })
+    }
+
+    pub async fn submit(&mut self, message: MiningSubmitMessage) -> Result<(), SessionError> {
+        self.writer
+            .write(StratumMessage::MiningSubmitMessage(message))
+            .await
+            .map_err(|error| {
+                SessionError::new(
+                    DisconnectReason::from_write_error(&error),
+                    format!("[Stratum submit] {}, reconnecting", error),
+                )
+            })?;
+        self.counts.sent += 1;
+        Ok(())
+    }
+
+    /// Sends the token of a pool that identifies workers by it, right after the subscribe
+    /// ack. The pool does not answer, a token it does not take ends the connection.
+    pub async fn authorize(&mut self, id: i64, token: &str) -> Result<(), SessionError> {
+        let authorize = StratumMessage::MiningAuthorizeMessage(MiningAuthorizeMessage {
+            id: id.into(),
+            method: String::from("mining.authorize"),
+            body: MiningAuthorizeBody {
+                token: token.to_string(),
+            },
+        });
+        let written = match self.writer.write(authorize).await {
+            Ok(()) => self.writer.flush().await,
+            Err(error) => Err(error),
+        };
+        written.map_err(|error| {
+            SessionError::new(
+                DisconnectReason::from_write_error(&error),
+                format!("[Stratum authorize] {}", error),
+            )
+        })?;
+        self.counts.sent += 1;
+        Ok(())
+    }
+
+    pub async fn report_hashrate(
+        &mut self,
+        message: MiningHashrateMessage,
+    ) -> Result<(), SessionError> {
+        self.writer
+            .write(StratumMessage::MiningHashrateMessage(message))
+            .await
+            .map_err(|error| {
+                SessionError::new(
+                    DisconnectReason::from_write_error(&error),
+                    format!("[Stratum hashrate] {}", error),
+                )
+            })?;
+        self.counts.sent += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MiningSubmitBody;
+    use futures::SinkExt;
+    use tokio::io::{duplex, DuplexStream};
+    use tokio_util::codec::FramedWrite;
+
+    type Pool = (
+        FramedRead<ReadHalf<DuplexStream>, StratumMessageCodec>,
+        FramedWrite<WriteHalf<DuplexStream>, StratumMessageCodec>,
+    );
+
+    fn pool(io: DuplexStream) -> Pool {
+        let (r, w) = split(io);
+        (
+            FramedRead::new(r, StratumMessageCodec::default()),
+            FramedWrite::new(w, StratumMessageCodec::default()),
+        )
+    }
+
+    fn subscribe_body() -> MiningSubscribeBody {
+        MiningSubscribeBody {
+            version: 1,
+            name: String::from("worker"),
+            publicAddress: Some(String::from("address")),
+            agent: None,
+            graffitiSuffix: None,
+            capabilities: vec![],
+        }
+    }
+
+    fn subscribed() -> StratumMessage {
+        StratumMessage::MiningSubscribedMessage(MiningSubscribedMessage {
+            id: 0.into(),
+            method: String::from("mining.subscribed"),
+            body: MiningSubscribedBody {
+                clientId: 3,
+                graffiti: String::from("zk.work"),
+                capabilities: None,
+            },
+        })
+    }
+
+    fn set_target() -> StratumMessage {
+        StratumMessage::MiningSetTargetMessage(MiningSetTargetMessage {
+            id: 1.into(),
+            method: String::from("mining.set_target"),
+            body: MiningSetTargetBody {
+                target: "ff".repeat(32),
+            },
+        })
+    }
+
+    #[tokio::test]
+    async fn test_session_events() {
+        let (client_io, pool_io) = duplex(4096);
+        let mut session = StratumSession::new(client_io, false);
+        let (mut r, mut w) = pool(pool_io);
+        // the target comes ahead of the ack and is handed out first
+        w.send(set_target()).await.unwrap();
+        w.send(subscribed()).await.unwrap();
+        w.send(StratumMessage::MiningNotifyMessage(MiningNotifyMessage {
+            id: 2.into(),
+            method: String::from("mining.notify"),
+            body: MiningNotifyBody {
+                miningRequestId: 7,
+                header: "00".repeat(208),
+                timestamp: Some(1_000),
+                expiresInMs: Some(60_000),
+            },
+        }))
+        .await
+        .unwrap();
+        w.send(StratumMessage::MiningWaitForWorkMessage(
+            MiningWaitForWorkMessage {
+                id: 3.into(),
+                method: String::from("mining.wait_for_work"),
+            },
+        ))
+        .await
+        .unwrap();
+        w.send(subscribed()).await.unwrap();
+
+        let ack = session.subscribe(0, subscribe_body()).await.unwrap();
+        assert_eq!(ack.clientId, 3);
+        assert_eq!(ack.graffiti, "zk.work");
+        match r.next().await {
+            Some(Ok(StratumMessage::MiningSubscribeMessage(message))) => {
+                assert_eq!(message.body.name, "worker");
+                assert_eq!(message.body.publicAddress.as_deref(), Some("address"));
+            }
+            other => panic!("expected subscribe, got {:?}", other),
+        }
+        assert_eq!(
+            session.next_event().await.unwrap(),
+            SessionEvent::NewTarget("ff".repeat(32))
+        );
+        assert_eq!(
+            session.next_event().await.unwrap(),
+            SessionEvent::NewJob {
+                mining_request_id: 7,
+                header: "00".repeat(208),
+                timestamp: Some(1_000),
+                expires_in: Some(Duration::from_secs(60)),
+            }
+        );
+        assert_eq!(
+            session.next_event().await.unwrap(),
+            SessionEvent::WaitForWork
+        );
+        assert_eq!(
+            session.next_event().await.unwrap(),
+            SessionEvent::Unknown(subscribed())
+        );
+
+        session
+            .submit(MiningSubmitMessage {
+                id: 4.into(),
+                method: String::from("mining.submit"),
+                body: MiningSubmitBody {
+                    miningRequestId: 7,
+                    randomness: String::from("00000000000004d2"),
+                    elapsedMs: None,
+                },
+            })
+            .await
+            .unwrap();
+        // the subscribe and the submit went out right away, one write each
+        assert_eq!(session.flushes(), 2);
+        assert_eq!(session.flush_deadline(), None);
+        match r.next().await {
+            Some(Ok(StratumMessage::MiningSubmitMessage(message))) => {
+                assert_eq!(message.body.miningRequestId, 7)
+            }
+            other => panic!("expected submit, got {:?}", other),
+        }
+        assert_eq!(
+            session.counts(),
+            MessageCounts {
+                received: 5,
+                sent: 2
+            }
+        );
+
+        drop((r, w));
+        let error = session.next_event().await.unwrap_err();
+        assert_eq!(error.reason, DisconnectReason::RemoteClosed);
+    }
+
+    #[tokio::test]
+    async fn test_resume() {
+        let (client_io, pool_io) = duplex(4096);
+        let mut session = StratumSession::new(client_io, false);
+        let (_r, mut w) = pool(pool_io);
+        w.send(set_target()).await.unwrap();
+        w.send(subscribed()).await.unwrap();
+        let mut w = w.into_inner();
+        tokio::io::AsyncWriteExt::write_all(
+            &mut w,
+            b"{\"id\":3,\"method\":\"mining.wait_for_work\"",
+        )
+        .await
+        .unwrap();
+        session.subscribe(0, subscribe_body()).await.unwrap();
+
+        // the target held back and the start of the next line go along with the connection
+        let (stream, buffered) = session.into_parts();
+        assert!(buffered.ends_with(b"\"mining.wait_for_work\""));
+        let mut session = StratumSession::resume(stream, false, &buffered, vec![subscribed()]);
+        tokio::io::AsyncWriteExt::write_all(&mut w, b"}\n")
+            .await
+            .unwrap();
+        assert_eq!(
+            session.next_event().await.unwrap(),
+            SessionEvent::Unknown(subscribed())
+        );
+        assert_eq!(
+            session.next_event().await.unwrap(),
+            SessionEvent::NewTarget("ff".repeat(32))
+        );
+        assert_eq!(
+            session.next_event().await.unwrap(),
+            SessionEvent::WaitForWork
+        );
+    }
+
+    #[tokio::test]
+    async fn test_compression() {
+        let submit = || MiningSubmitMessage {
+            id: 4.into(),
+            method: String::from("mining.submit"),
+            body: MiningSubmitBody {
+                miningRequestId: 7,
+                randomness: String::from("00000000000004d2"),
+                elapsedMs: None,
+            },
+        };
+        let acked = |capabilities: Option<Vec<String>>| {
+            StratumMessage::MiningSubscribedMessage(MiningSubscribedMessage {
+                id: 0.into(),
+                method: String::from("mining.subscribed"),
+                body: MiningSubscribedBody {
+                    clientId: 3,
+                    graffiti: String::from("zk.work"),
+                    capabilities,
+                },
+            })
+        };
+        let deflate = || Some(vec![String::from(CAPABILITY_DEFLATE)]);
+        let offering = || MiningSubscribeBody {
+            capabilities: vec![String::from(CAPABILITY_DEFLATE)],
+            ..subscribe_body()
+        };
+
+        // both agree: the pool compresses after its ack, and so does the session
+        let (client_io, pool_io) = duplex(4096);
+        let mut session = StratumSession::new(client_io, false);
+        let (mut r, mut w) = pool(pool_io);
+        w.send(acked(deflate())).await.unwrap();
+        let counts = Arc::new(CompressionCounts::default());
+        w.encoder_mut().compress_writes(counts.clone());
+        w.send(set_target()).await.unwrap();
+        session.subscribe(0, offering()).await.unwrap();
+        assert_eq!(
+            session.next_event().await.unwrap(),
+            SessionEvent::NewTarget("ff".repeat(32))
+        );
+        assert!(matches!(
+            r.next().await,
+            Some(Ok(StratumMessage::MiningSubscribeMessage(_)))
+        ));
+        r.decoder_mut().decompress_reads(counts.clone());
+        session.submit(submit()).await.unwrap();
+        assert!(matches!(
+            r.next().await,
+            Some(Ok(StratumMessage::MiningSubmitMessage(_)))
+        ));
+        let stats = session.compression().unwrap().stats();
+        assert!(stats.json_in > 0 && stats.json_out > 0);
+        // what one side wrote, the other read
+        let pool_side = counts.stats();
+        assert_eq!(
+            (stats.json_in, stats.wire_in, stats.json_out, stats.wire_out),
+            (
+                pool_side.json_out,
+                pool_side.wire_out,
+                pool_side.json_in,
+                pool_side.wire_in
+            )
+        );
+
+        // a pool that does not agree gets, and sends, plain lines
+        for (offered, agreed) in [(offering(), None), (subscribe_body(), deflate())] {
+            let (client_io, pool_io) = duplex(4096);
+            let mut session = StratumSession::new(client_io, false);
+            let (mut r, mut w) = pool(pool_io);
+            w.send(acked(agreed)).await.unwrap();
+            w.send(set_target()).await.unwrap();
+            session.subscribe(0, offered).await.unwrap();
+            assert!(session.compression().is_none());
+            assert_eq!(
+                session.next_event().await.unwrap(),
+                SessionEvent::NewTarget("ff".repeat(32))
+            );
+            session.submit(submit()).await.unwrap();
+            r.next().await;
+            assert!(matches!(
+                r.next().await,
+                Some(Ok(StratumMessage::MiningSubmitMessage(_)))
+            ));
+        }
+
+        // one that compresses without agreeing can not be read
+        let (client_io, pool_io) = duplex(4096);
+        let mut session = StratumSession::new(client_io, false);
+        let (_r, mut w) = pool(pool_io);
+        w.send(acked(None)).await.unwrap();
+        w.encoder_mut().compress_writes(Arc::default());
+        w.send(set_target()).await.unwrap();
+        session.subscribe(0, offering()).await.unwrap();
+        let error = session.next_event().await.unwrap_err();
+        assert_eq!(error.reason, DisconnectReason::DecodeError);
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_errors() {
+        // garbage instead of the ack
+        let (client_io, pool_io) = duplex(4096);
+        let mut session = StratumSession::new(client_io, false);
+        let (_r, w) = pool(pool_io);
+        let mut w = w.into_inner();
+        tokio::io::AsyncWriteExt::write_all(&mut w, b"not json\n")
+            .await
+            .unwrap();
+        let error = session.subscribe(0, subscribe_body()).await.unwrap_err();
+        assert_eq!(error.reason, DisconnectReason::DecodeError);
+        assert!(error.message.contains("not json"), "{}", error);
+
+        // too many messages ahead of the ack
+        let (client_io, pool_io) = duplex(4096);
+        let mut session = StratumSession::new(client_io, false);
+        let (_r, mut w) = pool(pool_io);
+        for _ in 0..=MAX_EARLY_MESSAGES {
+            w.send(set_target()).await.unwrap();
+        }
+        let error = session.subscribe(0, subscribe_body()).await.unwrap_err();
+        assert_eq!(error.reason, DisconnectReason::DecodeError);
+
+        // the pool hangs up
+        let (client_io, pool_io) = duplex(4096);
+        let mut session = StratumSession::new(client_io, false);
+        let (_r, w) = pool(pool_io);
+        let mut w = w.into_inner();
+        tokio::io::AsyncWriteExt::shutdown(&mut w).await.unwrap();
+        let error = session.subscribe(0, subscribe_body()).await.unwrap_err();
+        assert_eq!(error.reason, DisconnectReason::RemoteClosed);
+    }
+}
@@ -0,0 +1,183 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! `--dual-connection`: a second pool connection that only carries submits, so that on high
+//! latency links shares are not queued behind the jobs coming in on the main connection.
+//!
+//! The pool sees it as another worker of the same address, named with [`SUBMIT_WORKER_SUFFIX`].
+//! Shares are only sent on it while it is subscribed with the graffiti of the main session,
+//! which is what the pool checks them against, and go on the main connection otherwise.
+
+use crate::{
+    monitored_channel, ChannelCounters, ChannelStats, Disconnect, DisconnectHistory,
+    MiningSubmitMessage, MonitoredSender, CHANNEL_CAPACITY, SEND_TIMEOUT,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    Arc,
+};
+use tokio::sync::{mpsc, RwLock};
+
+/// Appended to the worker name for the submit connection.
+pub const SUBMIT_WORKER_SUFFIX: &str = "-tx";
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SubmitConnectionStats {
+    pub subscribed: bool,
+    /// Assigned by the pool on the last subscribe of the submit connection.
+    pub client_id: Option<u64>,
+    pub graffiti: Option<String>,
+    /// Subscribes so far.
+    pub sessions: u64,
+    /// Shares written to the submit connection.
+    pub shares_submitted: u64,
+    /// Shares sent on the main connection instead, because the submit connection was down or
+    /// subscribed with another graffiti.
+    pub shares_fallback: u64,
+    pub channel: ChannelStats,
+    /// The last disconnects of the submit connection, oldest first.
+    pub disconnects: Vec<Disconnect>,
+}
+
+/// The state of the submit connection, shared between the miner side that hands it shares
+/// and the task that keeps it connected.
+#[derive(Debug, Default)]
+pub struct SubmitConnection {
+    router: RwLock<Option<MonitoredSender<MiningSubmitMessage>>>,
+    router_counters: Arc<ChannelCounters>,
+    subscribed: AtomicBool,
+    /// Client id and graffiti of the last subscribe.
+    session: RwLock<Option<(u64, String)>>,
+    sessions: AtomicU64,
+    shares_submitted: AtomicU64,
+    shares_fallback: AtomicU64,
+    disconnects: RwLock<DisconnectHistory>,
+}
+
+impl SubmitConnection {
+    pub fn is_subscribed(&self) -> bool {
+        self.subscribed.load(Ordering::Relaxed)
+    }
+
+    /// Starts taking shares after a subscribe. Returns where they arrive.
+    pub async fn open(
+        &self,
+        client_id: u64,
+        graffiti: String,
+    ) -> mpsc::Receiver<MiningSubmitMessage> {
+        let (router, handler) = monitored_channel(CHANNEL_CAPACITY, self.router_counters.clone());
+        *self.router.write().await = Some(router);
+        *self.session.write().await = Some((client_id, graffiti));
+        self.sessions.fetch_add(1, Ordering::Relaxed);
+        self.subscribed.store(true, Ordering::SeqCst);
+        handler
+    }
+
+    /// Stops taking shares. The receiver [`Self::open`] returned gets the ones already taken,
+    /// then `None`.
+    pub async fn close(&self) {
+        self.subscribed.store(false, Ordering::SeqCst);
+        self.router.write().await.take();
+    }
+
+    /// Hands the share to the submit connection if it can send it for a main session with
+    /// `graffiti`, otherwise hands it back.
+    pub async fn send(
+        &self,
+        message: MiningSubmitMessage,
+        graffiti: Option<&str>,
+    ) -> Result<(), MiningSubmitMessage> {
+        let router = self.router.read().await;
+        let same_graffiti = graffiti.is_some()
+            && self
+                .session
+                .read()
+                .await
+                .as_ref()
+                .map(|(_, own)| own.as_str())
+                == graffiti;
+        let result = match router.as_ref() {
+            Some(router) if same_graffiti && self.is_subscribed() => {
+                router.send(message, SEND_TIMEOUT).await
+            }
+            _ => Err(message),
+        };
+        if result.is_err() {
+            self.shares_fallback.fetch_add(1, Ordering::Relaxed);
+        }
+        result
+    }
+
+    pub fn record_submitted(&self) {
+        self.shares_submitted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub async fn record_disconnect(&self, disconnect: Disconnect) {
+        self.disconnects.write().await.record(disconnect);
+    }
+
+    pub async fn stats(&self) -> SubmitConnectionStats {
+        let session = self.session.read().await.clone();
+        SubmitConnectionStats {
+            subscribed: self.is_subscribed(),
+            client_id: session.as_ref().map(|(client_id, _)| *client_id),
+            graffiti: session.map(|(_, graffiti)| graffiti),
+            sessions: self.sessions.load(Ordering::Relaxed),
+            shares_submitted: self.shares_submitted.load(Ordering::Relaxed),
+            shares_fallback: self.shares_fallback.load(Ordering::Relaxed),
+            channel: match self.router.read().await.as_ref() {
+                Some(router) => router.stats(),
+                None => self.router_counters.stats(CHANNEL_CAPACITY),
+            },
+            disconnects: self.disconnects.read().await.list(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MiningSubmitBody;
+
+    fn share(mining_request_id: u32) -> MiningSubmitMessage {
+        MiningSubmitMessage {
+            id: 0.into(),
+            method: String::from("mining.submit"),
+            body: MiningSubmitBody {
+                miningRequestId: mining_request_id,
+                randomness: String::from("00000000000004d2"),
+                elapsedMs: None,
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn test_routing() {
+        let connection = SubmitConnection::default();
+        // not connected yet
+        assert!(connection.send(share(1), Some("zk.work")).await.is_err());
+
+        let mut handler = connection.open(3, String::from("zk.work")).await;
+        assert!(connection.send(share(2), Some("zk.work")).await.is_ok());
+        // the pool checks shares against the graffiti of the session that found them
+        assert!(connection.send(share(3), Some("other")).await.is_err());
+        assert!(connection.send(share(4), None).await.is_err());
+        connection.record_submitted();
+
+        // what was taken before the close still arrives
+        connection.close().await;
+        assert!(connection.send(share(5), Some("zk.work")).await.is_err());
+        assert_eq!(handler.recv().await.unwrap().body.miningRequestId, 2);
+        assert!(handler.recv().await.is_none());
+
+        let stats = connection.stats().await;
+        assert!(!stats.subscribed);
+        assert_eq!(stats.client_id, Some(3));
+        assert_eq!(stats.graffiti.as_deref(), Some("zk.work"));
+        assert_eq!(stats.sessions, 1);
+        assert_eq!(stats.shares_submitted, 1);
+        assert_eq!(stats.shares_fallback, 4);
+    }
+}
@@ -0,0 +1,268 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! What became of the shares written to the pool, so that a share is not submitted twice. A
+//! write that fails may still have reached the pool, buffered before the connection broke, and
+//! a pool flags the copy sent again after the reconnect as a duplicate. Pools that answer
+//! submits with `mining.submitted` say which ones arrived, those are never sent again; for the
+//! others a share goes out once more at most.
+
+use crate::{MessageId, MiningSubmitBody, MiningSubmitMessage, RecentSet};
+use std::{
+    collections::VecDeque,
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+/// Shares written lately whose message ids are kept to match the pool's acks.
+pub const MAX_TRACKED_SUBMISSIONS: usize = 256;
+/// Shares acknowledged lately, that are never re-sent.
+pub const MAX_ACKED_SHARES: usize = 1024;
+/// How often a share goes out again after the write of it failed.
+pub const MAX_RESENDS: u32 = 1;
+
+static NEXT_SUBMISSION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Tells the shares written by this process apart in the log, unique over all pool
+/// connections. Never sent to the pool.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SubmissionId(u64);
+
+impl SubmissionId {
+    fn next() -> Self {
+        Self(NEXT_SUBMISSION_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+impl fmt::Display for SubmissionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+/// What to do with a share whose write failed, once the pool is back.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResendDecision {
+    /// It was never written, e.g. held while disconnected.
+    Send,
+    /// Written before, with no sign it arrived.
+    Resend(SubmissionId),
+    /// The pool acknowledged it, it arrived after all.
+    Acked(SubmissionId),
+    /// Re-sent [`MAX_RESENDS`] times already.
+    ResendLimit(SubmissionId),
+}
+
+impl ResendDecision {
+    /// Whether the share goes out.
+    pub fn sends(&self) -> bool {
+        matches!(self, ResendDecision::Send | ResendDecision::Resend(_))
+    }
+}
+
+/// A share by the job and the randomness, as the pool tells duplicates apart.
+type ShareKey = (u32, String);
+
+fn share_key(body: &MiningSubmitBody) -> ShareKey {
+    (body.miningRequestId, body.randomness.clone())
+}
+
+#[derive(Debug)]
+struct Submission {
+    share: ShareKey,
+    id: SubmissionId,
+    /// The ids of the submit messages it went out with, and when each was written.
+    message_ids: Vec<(i64, Instant)>,
+    resends: u32,
+}
+
+impl Submission {
+    /// When message `id` of this share was written.
+    fn written(&self, id: i64) -> Option<Instant> {
+        self.message_ids
+            .iter()
+            .find(|(message_id, _)| *message_id == id)
+            .map(|(_, written)| *written)
+    }
+}
+
+/// The submit-ack table of the shares written lately, and the shares the pool acknowledged.
+#[derive(Debug)]
+pub struct SubmitLedger {
+    submissions: VecDeque<Submission>,
+    acked: RecentSet<ShareKey>,
+    max_resends: u32,
+}
+
+impl Default for SubmitLedger {
+    fn default() -> Self {
+        Self::new(MAX_RESENDS)
+    }
+}
+
+impl SubmitLedger {
+    /// A ledger letting a share go out again `max_resends` times, none with 0.
+    pub fn new(max_resends: u32) -> Self {
+        Self {
+            submissions: VecDeque::new(),
+            acked: RecentSet::new(MAX_ACKED_SHARES),
+            max_resends,
+        }
+    }
+
+    fn find(&mut self, share: &ShareKey) -> Option<&mut Submission> {
+        self.submissions
+            .iter_mut()
+            .find(|submission| submission.share == *share)
+    }
+
+    /// Records that `message` is about to be written. Before the write, as a failed one may
+    /// have reached the pool all the same. Returns the id of the share, the one it got on its
+    /// first write.
+    pub fn record_sent(&mut self, message: &MiningSubmitMessage) -> SubmissionId {
+        let share = share_key(&message.body);
+        let message_id = message.id.value().map(|id| (id, Instant::now()));
+        if let Some(submission) = self.find(&share) {
+            submission.message_ids.extend(message_id);
+            return submission.id;
+        }
+        if self.submissions.len() == MAX_TRACKED_SUBMISSIONS {
+            self.submissions.pop_front();
+        }
+        let id = SubmissionId::next();
+        self.submissions.push_back(Submission {
+            share,
+            id,
+            message_ids: message_id.into_iter().collect(),
+            resends: 0,
+        });
+        id
+    }
+
+    /// Records the pool's answer to the submit of message `id`, accepted or not: either way
+    /// the share arrived. Returns the share answered, `None` for an id of no share written
+    /// lately.
+    pub fn record_ack(&mut self, id: &MessageId) -> Option<SubmissionId> {
+        let id = id.value()?;
+        let submission = self
+            .submissions
+            .iter()
+            .find(|submission| submission.written(id).is_some())?;
+        self.acked.insert(submission.share.clone());
+        Some(submission.id)
+    }
+
+    /// How long ago message `id` was written, `None` for an id of no share written lately.
+    pub fn since_written(&self, id: &MessageId, now: Instant) -> Option<Duration> {
+        let id = id.value()?;
+        self.submissions
+            .iter()
+            .find_map(|submission| submission.written(id))
+            .map(|written| now.saturating_duration_since(written))
+    }
+
+    /// Decides whether the share of a failed write goes out after the reconnect, and counts
+    /// the re-send if it does.
+    pub fn resend(&mut self, body: &MiningSubmitBody) -> ResendDecision {
+        let share = share_key(body);
+        let acked = self.acked.contains(&share);
+        let max_resends = self.max_resends;
+        let submission = match self.find(&share) {
+            Some(submission) => submission,
+            None => return ResendDecision::Send,
+        };
+        if acked {
+            return ResendDecision::Acked(submission.id);
+        }
+        if submission.resends >= max_resends {
+            return ResendDecision::ResendLimit(submission.id);
+        }
+        submission.resends += 1;
+        ResendDecision::Resend(submission.id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn submit(id: i64, mining_request_id: u32, randomness: &str) -> MiningSubmitMessage {
+        MiningSubmitMessage {
+            id: id.into(),
+            method: String::from("mining.submit"),
+            body: MiningSubmitBody {
+                miningRequestId: mining_request_id,
+                randomness: randomness.to_string(),
+                elapsedMs: None,
+            },
+        }
+    }
+
+    #[test]
+    fn test_submit_ledger() {
+        let mut ledger = SubmitLedger::default();
+        let acked = submit(1, 7, "00000000000000aa");
+        let unacked = submit(2, 7, "00000000000000bb");
+        let first = ledger.record_sent(&acked);
+        let second = ledger.record_sent(&unacked);
+        assert_ne!(first, second);
+        assert_eq!(ledger.record_ack(&MessageId::from(1)), Some(first));
+        // an ack of something else, or of nothing in particular
+        assert_eq!(ledger.record_ack(&MessageId::from(99)), None);
+        assert_eq!(ledger.record_ack(&MessageId::Null), None);
+        let later = Instant::now() + Duration::from_millis(50);
+        assert!(
+            ledger.since_written(&MessageId::from(1), later).unwrap() >= Duration::from_millis(50)
+        );
+        assert_eq!(ledger.since_written(&MessageId::from(99), later), None);
+
+        assert_eq!(ledger.resend(&acked.body), ResendDecision::Acked(first));
+        assert_eq!(ledger.resend(&unacked.body), ResendDecision::Resend(second));
+        // the re-sent copy is the same share, and goes out only once
+        let resent = submit(3, 7, "00000000000000bb");
+        assert_eq!(ledger.record_sent(&resent), second);
+        assert_eq!(
+            ledger.resend(&unacked.body),
+            ResendDecision::ResendLimit(second)
+        );
+        // an ack of the copy is one of the share
+        assert_eq!(
+            ledger.record_ack(&MessageId::String(String::from("3"))),
+            Some(second)
+        );
+        assert_eq!(ledger.resend(&unacked.body), ResendDecision::Acked(second));
+
+        // never written
+        let held = submit(0, 8, "00000000000000cc");
+        assert_eq!(ledger.resend(&held.body), ResendDecision::Send);
+        assert!(ResendDecision::Send.sends());
+        assert!(!ResendDecision::Acked(first).sends());
+    }
+
+    #[test]
+    fn test_submit_ledger_limits() {
+        // no re-sends at all
+        let mut ledger = SubmitLedger::new(0);
+        let message = submit(1, 7, "00000000000000aa");
+        let id = ledger.record_sent(&message);
+        assert_eq!(
+            ledger.resend(&message.body),
+            ResendDecision::ResendLimit(id)
+        );
+
+        // only the last shares are tracked, a forgotten one reads as never written
+        let mut ledger = SubmitLedger::default();
+        for n in 0..MAX_TRACKED_SUBMISSIONS as i64 + 1 {
+            ledger.record_sent(&submit(n, 7, &format!("{:016x}", n)));
+        }
+        assert_eq!(
+            ledger.resend(&submit(0, 7, &format!("{:016x}", 0)).body),
+            ResendDecision::Send
+        );
+        assert!(ledger
+            .resend(&submit(1, 7, &format!("{:016x}", 1)).body)
+            .sends());
+    }
+}
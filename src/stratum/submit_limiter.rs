@@ -0,0 +1,168 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::{Clock, SystemClock};
+use anyhow::{anyhow, Result};
+use std::{
+    fmt,
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// At most `shares` submits per `period`, e.g. "30/10s".
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SubmitRate {
+    pub shares: u32,
+    pub period: Duration,
+}
+
+impl Default for SubmitRate {
+    fn default() -> Self {
+        Self {
+            shares: 30,
+            period: Duration::from_secs(10),
+        }
+    }
+}
+
+impl FromStr for SubmitRate {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (shares, period) = s
+            .split_once('/')
+            .ok_or_else(|| anyhow!("expected a submit rate like 30/10s, got '{}'", s))?;
+        let shares: u32 = shares
+            .trim()
+            .parse()
+            .map_err(|_| anyhow!("invalid share count '{}'", shares))?;
+        let period: u64 = period
+            .trim()
+            .strip_suffix('s')
+            .and_then(|period| period.parse().ok())
+            .ok_or_else(|| anyhow!("invalid period '{}', expected seconds like 10s", period))?;
+        if shares == 0 || period == 0 {
+            return Err(anyhow!("submit rate {} must be greater than zero", s));
+        }
+        Ok(Self {
+            shares,
+            period: Duration::from_secs(period),
+        })
+    }
+}
+
+impl fmt::Display for SubmitRate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}s", self.shares, self.period.as_secs())
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// Goes below zero for queued submits, each waits until the bucket is back at zero.
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+/// A token bucket in front of the pool submits. A burst up to the rate goes out at once,
+/// up to as many again are delayed to spread them out, anything beyond is dropped.
+#[derive(Debug)]
+pub struct SubmitLimiter<C: Clock = SystemClock> {
+    clock: C,
+    rate: SubmitRate,
+    bucket: Mutex<Bucket>,
+}
+
+impl SubmitLimiter {
+    pub fn with_rate(rate: SubmitRate) -> Self {
+        Self::new(SystemClock, rate)
+    }
+}
+
+impl<C: Clock> SubmitLimiter<C> {
+    pub fn new(clock: C, rate: SubmitRate) -> Self {
+        let bucket = Mutex::new(Bucket {
+            tokens: rate.shares as f64,
+            refilled_at: clock.now(),
+        });
+        Self {
+            clock,
+            rate,
+            bucket,
+        }
+    }
+
+    pub fn rate(&self) -> SubmitRate {
+        self.rate
+    }
+
+    /// Takes a token for one submit. Returns how long the submit has to wait, zero under the
+    /// limit, or `None` if the queue is full and the submit should be dropped.
+    pub fn reserve(&self) -> Option<Duration> {
+        let now = self.clock.now();
+        let burst = self.rate.shares as f64;
+        let per_second = burst / self.rate.period.as_secs_f64();
+        let mut bucket = self.bucket.lock().unwrap();
+        let refill = (now - bucket.refilled_at).as_secs_f64() * per_second;
+        bucket.tokens = (bucket.tokens + refill).min(burst);
+        bucket.refilled_at = now;
+        if bucket.tokens - 1.0 < -burst {
+            return None;
+        }
+        bucket.tokens -= 1.0;
+        if bucket.tokens >= 0.0 {
+            Some(Duration::ZERO)
+        } else {
+            Some(Duration::from_secs_f64(-bucket.tokens / per_second))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ManualClock;
+
+    #[test]
+    fn test_parse_submit_rate() {
+        assert_eq!(
+            "30/10s".parse::<SubmitRate>().unwrap(),
+            SubmitRate::default()
+        );
+        assert_eq!(SubmitRate::default().to_string(), "30/10s");
+        assert!("30".parse::<SubmitRate>().is_err());
+        assert!("30/10".parse::<SubmitRate>().is_err());
+        assert!("0/10s".parse::<SubmitRate>().is_err());
+        assert!("30/0s".parse::<SubmitRate>().is_err());
+    }
+
+    #[test]
+    fn test_submit_limiter() {
+        let clock = ManualClock::new();
+        let rate = "4/2s".parse().unwrap();
+        let limiter = SubmitLimiter::new(clock.clone(), rate);
+
+        // under the limit nothing waits
+        for _ in 0..4 {
+            assert_eq!(limiter.reserve(), Some(Duration::ZERO));
+        }
+        // the next burst is spread out at the refill rate of one every 500ms
+        for i in 1..=4 {
+            assert_eq!(limiter.reserve(), Some(Duration::from_millis(500 * i)));
+        }
+        // and beyond that dropped
+        assert_eq!(limiter.reserve(), None);
+        assert_eq!(limiter.reserve(), None);
+
+        // refilled tokens first pay off the queue
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(limiter.reserve(), Some(Duration::from_millis(500)));
+        clock.advance(Duration::from_secs(60));
+        for _ in 0..4 {
+            assert_eq!(limiter.reserve(), Some(Duration::ZERO));
+        }
+        assert!(limiter.reserve().unwrap() > Duration::ZERO);
+    }
+}
@@ -0,0 +1,169 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! TLS to the pool with `--tls`, through native-tls (the `tls-native` feature, on by default)
+//! or rustls (`tls-rustls`), native-tls if both are enabled. A build with neither, e.g. the
+//! fully static musl binary, has no TLS code at all: `--tls` is refused at startup and the
+//! plain connection is all that is left of the pool connection.
+
+use crate::Cli;
+use anyhow::{anyhow, Result};
+
+/// Why `--tls` does not work in a build without TLS support.
+pub const TLS_UNSUPPORTED: &str =
+    "--tls is not available, this binary was built without TLS support (cargo features tls-native or tls-rustls)";
+
+/// The TLS library the pool connections go through, `None` in a build without TLS.
+pub fn tls_backend() -> Option<&'static str> {
+    if cfg!(feature = "tls-native") {
+        // what native-tls is built on for this platform
+        Some(if cfg!(target_os = "windows") {
+            "schannel"
+        } else if cfg!(any(target_os = "macos", target_os = "ios")) {
+            "security-framework"
+        } else {
+            "openssl"
+        })
+    } else if cfg!(feature = "tls-rustls") {
+        Some("rustls")
+    } else {
+        None
+    }
+}
+
+/// Rejects `--tls` in a build without TLS support.
+pub fn check_tls_support(cli: &Cli) -> Result<()> {
+    if cli.tls && tls_backend().is_none() {
+        return Err(anyhow!(TLS_UNSUPPORTED));
+    }
+    Ok(())
+}
+
+#[cfg(feature = "tls-native")]
+mod backend {
+    use crate::PoolAddress;
+    use anyhow::Result;
+    use tokio::io::{AsyncRead, AsyncWrite};
+    use tokio_native_tls::{native_tls, TlsConnector};
+
+    pub type TlsStream<S> = tokio_native_tls::TlsStream<S>;
+
+    pub async fn connect<S: AsyncRead + AsyncWrite + Unpin>(
+        pool: &PoolAddress,
+        tcp_stream: S,
+    ) -> Result<TlsStream<S>> {
+        let mut native_tls_builder = native_tls::TlsConnector::builder();
+        native_tls_builder.danger_accept_invalid_certs(true);
+        native_tls_builder.danger_accept_invalid_hostnames(true);
+        native_tls_builder.use_sni(false);
+        let tokio_tls_connector = TlsConnector::from(native_tls_builder.build()?);
+        Ok(tokio_tls_connector
+            .connect(&pool.to_string(), tcp_stream)
+            .await?)
+    }
+
+    pub fn negotiated_protocol<S: AsyncRead + AsyncWrite + Unpin>(
+        tls_stream: &TlsStream<S>,
+    ) -> Option<Vec<u8>> {
+        tls_stream.get_ref().negotiated_alpn().ok().flatten()
+    }
+}
+
+#[cfg(all(feature = "tls-rustls", not(feature = "tls-native")))]
+mod backend {
+    use crate::PoolAddress;
+    use anyhow::{anyhow, Result};
+    use std::{convert::TryFrom, sync::Arc, time::SystemTime};
+    use tokio::io::{AsyncRead, AsyncWrite};
+    use tokio_rustls::{
+        rustls::{
+            self,
+            client::{ServerCertVerified, ServerCertVerifier},
+            Certificate, ClientConfig, ServerName,
+        },
+        TlsConnector,
+    };
+
+    pub type TlsStream<S> = tokio_rustls::client::TlsStream<S>;
+
+    /// Takes any certificate, as native-tls is told to.
+    struct AcceptAnyCertificate;
+
+    impl ServerCertVerifier for AcceptAnyCertificate {
+        fn verify_server_cert(
+            &self,
+            _end_entity: &Certificate,
+            _intermediates: &[Certificate],
+            _server_name: &ServerName,
+            _scts: &mut dyn Iterator<Item = &[u8]>,
+            _ocsp_response: &[u8],
+            _now: SystemTime,
+        ) -> Result<ServerCertVerified, rustls::Error> {
+            Ok(ServerCertVerified::assertion())
+        }
+    }
+
+    pub async fn connect<S: AsyncRead + AsyncWrite + Unpin>(
+        pool: &PoolAddress,
+        tcp_stream: S,
+    ) -> Result<TlsStream<S>> {
+        let mut config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(Arc::new(AcceptAnyCertificate))
+            .with_no_client_auth();
+        config.enable_sni = false;
+        let server_name = ServerName::try_from(pool.host())
+            .map_err(|error| anyhow!("invalid pool host '{}': {}", pool.host(), error))?;
+        Ok(TlsConnector::from(Arc::new(config))
+            .connect(server_name, tcp_stream)
+            .await?)
+    }
+
+    pub fn negotiated_protocol<S>(tls_stream: &TlsStream<S>) -> Option<Vec<u8>> {
+        tls_stream.get_ref().1.alpn_protocol().map(<[u8]>::to_vec)
+    }
+}
+
+#[cfg(feature = "tls")]
+pub use backend::TlsStream;
+
+/// Runs the TLS handshake with the pool. The certificate is not checked.
+#[cfg(feature = "tls")]
+pub async fn tls_connect<S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin>(
+    pool: &crate::PoolAddress,
+    tcp_stream: S,
+) -> Result<TlsStream<S>> {
+    backend::connect(pool, tcp_stream).await
+}
+
+/// The ALPN protocol the pool picked in the handshake, if any.
+#[cfg(feature = "tls")]
+pub fn negotiated_protocol<S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin>(
+    tls_stream: &TlsStream<S>,
+) -> Option<String> {
+    backend::negotiated_protocol(tls_stream)
+        .map(|protocol| String::from_utf8_lossy(&protocol).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::test_cli;
+
+    #[test]
+    fn test_check_tls_support() {
+        let mut cli = test_cli("127.0.0.1:8181".parse().unwrap());
+        assert!(check_tls_support(&cli).is_ok());
+        cli.tls = true;
+        if cfg!(feature = "tls") {
+            assert!(tls_backend().is_some());
+            assert!(check_tls_support(&cli).is_ok());
+        } else {
+            assert_eq!(tls_backend(), None);
+            let error = check_tls_support(&cli).unwrap_err().to_string();
+            assert!(error.contains("built without TLS support"), "{}", error);
+            assert!(error.contains("tls-native"), "{}", error);
+        }
+    }
+}
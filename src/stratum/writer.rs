@@ -0,0 +1,227 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! Writes stratum messages with as few flushes, and so syscalls, as latency allows. Messages
+//! are fed to the framed writer and flushed together: right away for the ones the
+//! [`FlushPolicy`] deems urgent, otherwise when the writer's caller has nothing more to send
+//! or at the latest after the policy's delay.
+
+use crate::{CompressionCounts, DumpTap, StratumMessage, StratumMessageCodec};
+use anyhow::Result;
+use futures::SinkExt;
+use std::{fmt, sync::Arc, time::Duration};
+use tokio::{io::AsyncWrite, time::Instant};
+use tokio_util::codec::FramedWrite;
+
+/// The longest a message waits for its flush under [`SubmitFirst`].
+pub const MAX_FLUSH_DELAY: Duration = Duration::from_millis(5);
+
+/// Which messages are flushed right away, and how long the others may wait for company.
+pub trait FlushPolicy: Send + Sync + fmt::Debug {
+    fn flush_now(&self, message: &StratumMessage) -> bool;
+
+    fn max_delay(&self) -> Duration;
+}
+
+/// Shares and subscribes go out right away, everything else waits up to
+/// [`MAX_FLUSH_DELAY`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SubmitFirst;
+
+impl FlushPolicy for SubmitFirst {
+    fn flush_now(&self, message: &StratumMessage) -> bool {
+        matches!(
+            message,
+            StratumMessage::MiningSubmitMessage(_) | StratumMessage::MiningSubscribeMessage(_)
+        )
+    }
+
+    fn max_delay(&self) -> Duration {
+        MAX_FLUSH_DELAY
+    }
+}
+
+/// A framed stratum writer that coalesces flushes, see the module docs.
+pub struct CoalescingWriter<W> {
+    inner: FramedWrite<W, StratumMessageCodec>,
+    policy: Arc<dyn FlushPolicy>,
+    /// When the oldest message not flushed yet was written, `None` if there is none.
+    unflushed_since: Option<Instant>,
+    flushes: u64,
+}
+
+impl<W: AsyncWrite + Unpin> CoalescingWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self::with_policy(inner, Arc::new(SubmitFirst))
+    }
+
+    pub fn with_policy(inner: W, policy: Arc<dyn FlushPolicy>) -> Self {
+        Self {
+            inner: FramedWrite::new(inner, StratumMessageCodec::default()),
+            policy,
+            unflushed_since: None,
+            flushes: 0,
+        }
+    }
+
+    /// Writes the message, and flushes if the policy asks for it.
+    pub async fn write(&mut self, message: StratumMessage) -> Result<()> {
+        let flush_now = self.policy.flush_now(&message);
+        self.inner.feed(message).await?;
+        self.unflushed_since.get_or_insert_with(Instant::now);
+        if flush_now {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Flushes what was written since the last flush, if anything.
+    pub async fn flush(&mut self) -> Result<()> {
+        if self.unflushed_since.is_none() {
+            return Ok(());
+        }
+        self.inner.flush().await?;
+        self.unflushed_since = None;
+        self.flushes += 1;
+        Ok(())
+    }
+
+    /// When the messages not flushed yet are due, `None` if there are none.
+    pub fn flush_deadline(&self) -> Option<Instant> {
+        self.unflushed_since
+            .map(|since| since + self.policy.max_delay())
+    }
+
+    /// Flushes done so far. The framed writer also writes on its own once its buffer holds
+    /// 8 KiB, which is not counted.
+    pub fn flushes(&self) -> u64 {
+        self.flushes
+    }
+
+    /// Records every message written from now on, see [`crate::StratumDump`].
+    pub fn record_to(&mut self, tap: DumpTap) {
+        self.inner.encoder_mut().record_to(tap);
+    }
+
+    /// Compresses every message written from now on, see [`crate::LineDeflater`].
+    pub fn compress_writes(&mut self, counts: Arc<CompressionCounts>) {
+        self.inner.encoder_mut().compress_writes(counts);
+    }
+
+    /// The writer, dropping what was written but not flushed.
+    pub fn into_inner(self) -> W {
+        self.inner.into_inner()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        MiningSetTargetBody, MiningSetTargetMessage, MiningSubmitBody, MiningSubmitMessage,
+    };
+    use std::{
+        io,
+        pin::Pin,
+        sync::atomic::{AtomicU64, Ordering},
+        task::{Context, Poll},
+    };
+
+    /// Counts the flushes that reach the connection.
+    #[derive(Default)]
+    struct Sink(Arc<AtomicU64>);
+
+    impl AsyncWrite for Sink {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// Flushes every message, the behavior before coalescing.
+    #[derive(Debug)]
+    struct EveryMessage;
+
+    impl FlushPolicy for EveryMessage {
+        fn flush_now(&self, _: &StratumMessage) -> bool {
+            true
+        }
+
+        fn max_delay(&self) -> Duration {
+            Duration::ZERO
+        }
+    }
+
+    fn submit(id: i64) -> StratumMessage {
+        StratumMessage::MiningSubmitMessage(MiningSubmitMessage {
+            id: id.into(),
+            method: String::from("mining.submit"),
+            body: MiningSubmitBody {
+                miningRequestId: 1,
+                randomness: format!("{:016x}", id),
+                elapsedMs: None,
+            },
+        })
+    }
+
+    fn set_target(id: i64) -> StratumMessage {
+        StratumMessage::MiningSetTargetMessage(MiningSetTargetMessage {
+            id: id.into(),
+            method: String::from("mining.set_target"),
+            body: MiningSetTargetBody {
+                target: "ff".repeat(32),
+            },
+        })
+    }
+
+    #[tokio::test]
+    async fn test_coalesced_flushes() {
+        let flushed = Arc::new(AtomicU64::new(0));
+        let mut writer = CoalescingWriter::new(Sink(flushed.clone()));
+        assert_eq!(writer.flush_deadline(), None);
+        // a burst of 20 targets goes out in one flush, a larger one would also be written
+        // once 8 KiB are buffered
+        let before = Instant::now();
+        for id in 0..20 {
+            writer.write(set_target(id)).await.unwrap();
+        }
+        assert_eq!(flushed.load(Ordering::Relaxed), 0);
+        let deadline = writer.flush_deadline().unwrap();
+        assert!(deadline >= before + MAX_FLUSH_DELAY);
+        assert!(deadline <= Instant::now() + MAX_FLUSH_DELAY);
+        writer.flush().await.unwrap();
+        writer.flush().await.unwrap();
+        assert_eq!(flushed.load(Ordering::Relaxed), 1);
+        assert_eq!(writer.flush_deadline(), None);
+
+        // every submit goes out right away, taking along what waited
+        writer.write(set_target(20)).await.unwrap();
+        for id in 0..100 {
+            writer.write(submit(id)).await.unwrap();
+        }
+        assert_eq!(flushed.load(Ordering::Relaxed), 101);
+        assert_eq!(writer.flushes(), 101);
+        assert_eq!(writer.flush_deadline(), None);
+
+        let flushed = Arc::new(AtomicU64::new(0));
+        let mut writer =
+            CoalescingWriter::with_policy(Sink(flushed.clone()), Arc::new(EveryMessage));
+        for id in 0..100 {
+            writer.write(set_target(id)).await.unwrap();
+        }
+        assert_eq!(flushed.load(Ordering::Relaxed), 100);
+    }
+}
@@ -0,0 +1,221 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! Keeps a panic in one task from taking the miner down with it, or from leaving it running
+//! without the part that mines.
+//!
+//! Tasks mining can do without, like the stats api, are restarted with a backoff. A panic in a
+//! task mining depends on is reported through [`critical_failure`], which shuts the process
+//! down with [`CRITICAL_PANIC_EXIT_CODE`].
+
+use crate::{panic_final_report, panic_report};
+use log::*;
+use std::{
+    any::Any,
+    backtrace::{Backtrace, BacktraceStatus},
+    future::Future,
+    panic,
+    sync::OnceLock,
+    thread,
+    time::Duration,
+};
+use tokio::{
+    runtime::Handle,
+    sync::Notify,
+    task::{self, JoinHandle},
+    time,
+};
+
+/// Exit code of the process after a panic in a task mining depends on.
+pub const CRITICAL_PANIC_EXIT_CODE: i32 = 10;
+
+/// How often and how fast a panicked task is restarted.
+#[derive(Clone, Copy, Debug)]
+pub struct RestartPolicy {
+    /// Wait before the first restart, doubled for every further one.
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+    /// The task stays down after this many restarts.
+    pub max_restarts: u32,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        Self {
+            backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+            max_restarts: 5,
+        }
+    }
+}
+
+/// The message a task panicked with.
+pub fn panic_message(payload: &(dyn Any + Send)) -> String {
+    match (
+        payload.downcast_ref::<&str>(),
+        payload.downcast_ref::<String>(),
+    ) {
+        (Some(message), _) => message.to_string(),
+        (_, Some(message)) => message.clone(),
+        _ => String::from("unknown panic payload"),
+    }
+}
+
+/// Runs the task `make` returns, and a new one whenever it panics, until it returns or has
+/// been restarted `policy.max_restarts` times.
+pub fn supervise<F, T>(name: &'static str, policy: RestartPolicy, mut make: F) -> JoinHandle<()>
+where
+    F: FnMut() -> T + Send + 'static,
+    T: Future<Output = ()> + Send + 'static,
+{
+    task::spawn(async move {
+        let mut restarts = 0;
+        let mut backoff = policy.backoff;
+        loop {
+            let error = match task::spawn(make()).await {
+                Ok(()) => return,
+                Err(error) if error.is_panic() => error,
+                Err(_) => return,
+            };
+            let message = panic_message(&*error.into_panic());
+            if restarts >= policy.max_restarts {
+                error!(
+                    "{} panicked: {}. Gave up after {} restarts, mining goes on without it",
+                    name, message, restarts
+                );
+                return;
+            }
+            restarts += 1;
+            error!(
+                "{} panicked: {}. Restarting it in {:?} ({}/{})",
+                name, message, backoff, restarts, policy.max_restarts
+            );
+            time::sleep(backoff).await;
+            backoff = (backoff * 2).min(policy.max_backoff);
+        }
+    })
+}
+
+fn critical_failures() -> &'static Notify {
+    static CRITICAL_FAILURES: OnceLock<Notify> = OnceLock::new();
+    CRITICAL_FAILURES.get_or_init(Notify::new)
+}
+
+/// Spawns a task mining depends on. If it panics the process is shut down, see
+/// [`critical_failure`]. The returned handle completes once the task has.
+pub fn spawn_critical<T>(name: &'static str, future: T) -> JoinHandle<()>
+where
+    T: Future<Output = ()> + Send + 'static,
+{
+    let handle = task::spawn(future);
+    task::spawn(async move {
+        if let Err(error) = handle.await {
+            if error.is_panic() {
+                error!(
+                    "{} panicked: {}. Shutting down",
+                    name,
+                    panic_message(&*error.into_panic())
+                );
+                critical_failures().notify_one();
+            }
+        }
+    })
+}
+
+/// Completes once a task spawned with [`spawn_critical`] panicked.
+pub async fn critical_failure() {
+    critical_failures().notified().await
+}
+
+/// Logs every panic through the logger, with the backtrace if `RUST_BACKTRACE` asks for one,
+/// instead of the default message on stderr. A panic on a thread outside the runtime, like
+/// the mining threads, has no task to report it and is a critical failure.
+pub fn install_panic_hook() {
+    panic::set_hook(Box::new(|info| {
+        let thread = thread::current();
+        let location = info
+            .location()
+            .map(|location| format!(" at {}:{}", location.file(), location.line()))
+            .unwrap_or_default();
+        let backtrace = Backtrace::capture();
+        error!(
+            "thread '{}' panicked{}: {}{}",
+            thread.name().unwrap_or("<unnamed>"),
+            location,
+            panic_message(info.payload()),
+            match backtrace.status() {
+                BacktraceStatus::Captured => format!("\n{}", backtrace),
+                _ => String::new(),
+            }
+        );
+        for line in panic_report() {
+            error!("last stats: {}", line);
+        }
+        for report in panic_final_report() {
+            error!("{}", report);
+        }
+        if Handle::try_current().is_err() {
+            critical_failures().notify_one();
+        }
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    };
+
+    const FAST: RestartPolicy = RestartPolicy {
+        backoff: Duration::from_millis(10),
+        max_backoff: Duration::from_millis(20),
+        max_restarts: 3,
+    };
+
+    #[tokio::test]
+    async fn test_supervise_restarts() {
+        // recovers after two panics
+        let runs = Arc::new(AtomicU32::new(0));
+        let counter = runs.clone();
+        supervise("flaky task", FAST, move || {
+            let counter = counter.clone();
+            async move {
+                if counter.fetch_add(1, Ordering::SeqCst) < 2 {
+                    panic!("flaky");
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+
+        // stays down after the last restart
+        let runs = Arc::new(AtomicU32::new(0));
+        let counter = runs.clone();
+        supervise("broken task", FAST, move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            async { panic!("broken") }
+        })
+        .await
+        .unwrap();
+        assert_eq!(runs.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn test_critical_panic() {
+        spawn_critical("critical task", async { panic!("critical") });
+        time::timeout(Duration::from_secs(5), critical_failure())
+            .await
+            .expect("a critical panic should be reported");
+    }
+
+    #[test]
+    fn test_panic_message() {
+        assert_eq!(panic_message(&"static"), "static");
+        assert_eq!(panic_message(&String::from("formatted")), "formatted");
+        assert_eq!(panic_message(&7), "unknown panic payload");
+    }
+}
@@ -0,0 +1,221 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! Share targets as pools and users write them. Besides the 64 hex digits of the wire format,
+//! a target can be given as a shorter hex prefix or as a difficulty, like pool dashboards show.
+
+use anyhow::{anyhow, Error, Result};
+use std::{fmt, str::FromStr};
+
+const TARGET_HEX_DIGITS: usize = 64;
+const DIFFICULTY_PREFIX: &str = "diff:";
+
+/// A 256 bit big-endian share target, a hash at or below it meets the target.
+///
+/// Parses from:
+/// - 64 hex digits, with or without `0x`, in any case.
+/// - fewer hex digits, taken as the prefix of the target and padded with `f` on the right.
+///   So `0000` is the easiest target whose first two bytes are zero.
+/// - `diff:<difficulty>`, the target a block of that difficulty needs: 2^256 / difficulty,
+///   the largest target for difficulty 1. The difficulty must be at least 1.
+///
+/// Always displays as the 64 lowercase hex digits of the wire format.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Target(pub [u8; 32]);
+
+impl Target {
+    pub const MAX: Target = Target([0xff; 32]);
+
+    /// The target of `difficulty`, 2^256 / difficulty. Fails on a difficulty below 1, which
+    /// would need a target above the largest one.
+    pub fn from_difficulty(difficulty: f64) -> Result<Self> {
+        if !difficulty.is_finite() {
+            return Err(anyhow!("difficulty {} is not a finite number", difficulty));
+        }
+        if difficulty < 1.0 {
+            return Err(anyhow!(
+                "difficulty {} is below the minimum of 1",
+                difficulty
+            ));
+        }
+        if difficulty == 1.0 {
+            return Ok(Self::MAX);
+        }
+        // at most 53 significant bits, placed at their power of two
+        let quotient = 2f64.powi(256) / difficulty;
+        let exponent = quotient.log2().floor() as i32 - 52;
+        let mantissa = (quotient / 2f64.powi(exponent)) as u64;
+        let mut target = [0u8; 32];
+        for bit in 0..64 {
+            let position = bit + exponent;
+            if mantissa >> bit & 1 == 1 && (0..256).contains(&position) {
+                target[31 - position as usize / 8] |= 1 << (position % 8);
+            }
+        }
+        Ok(Self(target))
+    }
+
+    /// The difficulty this target stands for, 2^256 / target.
+    pub fn difficulty(&self) -> f64 {
+        let target = self
+            .0
+            .iter()
+            .fold(0f64, |value, byte| value * 256.0 + *byte as f64);
+        if target == 0.0 {
+            return f64::INFINITY;
+        }
+        (2f64.powi(256) / target).max(1.0)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+impl FromStr for Target {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        if let Some(difficulty) = s.strip_prefix(DIFFICULTY_PREFIX) {
+            let difficulty: f64 = difficulty.trim().parse().map_err(|_| {
+                anyhow!(
+                    "invalid difficulty target '{}': '{}' is not a number",
+                    s,
+                    difficulty
+                )
+            })?;
+            return Self::from_difficulty(difficulty)
+                .map_err(|error| anyhow!("invalid difficulty target '{}': {}", s, error));
+        }
+        let digits = s
+            .strip_prefix("0x")
+            .or_else(|| s.strip_prefix("0X"))
+            .unwrap_or(s);
+        if digits.is_empty() {
+            return Err(anyhow!(
+                "empty target, expected up to 64 hex digits or {}<difficulty>",
+                DIFFICULTY_PREFIX
+            ));
+        }
+        if let Some((position, c)) = digits.char_indices().find(|(_, c)| !c.is_ascii_hexdigit()) {
+            return Err(anyhow!(
+                "invalid hex target '{}': '{}' at position {} is not a hex digit",
+                s,
+                c,
+                position
+            ));
+        }
+        if digits.len() > TARGET_HEX_DIGITS {
+            return Err(anyhow!(
+                "invalid hex target '{}': {} hex digits, more than the {} of a full target",
+                s,
+                digits.len(),
+                TARGET_HEX_DIGITS
+            ));
+        }
+        let padded = format!("{:f<width$}", digits, width = TARGET_HEX_DIGITS);
+        let mut target = [0u8; 32];
+        hex::decode_to_slice(padded, &mut target)
+            .map_err(|error| anyhow!("invalid hex target '{}': {}", s, error))?;
+        Ok(Self(target))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_hex() {
+        let full = "00000049494cff9a3f4f473f91d116af7382c45e653facfeef85b8f43d9d6b64";
+        let target: Target = full.parse().unwrap();
+        assert_eq!(target.to_string(), full);
+        assert_eq!(format!("0x{}", full).parse::<Target>().unwrap(), target);
+        assert_eq!(full.to_uppercase().parse::<Target>().unwrap(), target);
+        assert_eq!(format!(" {}\n", full).parse::<Target>().unwrap(), target);
+
+        // prefixes are padded with f
+        assert_eq!(
+            "0000".parse::<Target>().unwrap().to_string(),
+            format!("0000{}", "ff".repeat(30))
+        );
+        assert_eq!(
+            "0x000".parse::<Target>().unwrap().to_string(),
+            format!("000{}", "f".repeat(61))
+        );
+        assert_eq!("ff".parse::<Target>().unwrap(), Target::MAX);
+        assert_eq!("0".repeat(64).parse::<Target>().unwrap(), Target([0; 32]));
+    }
+
+    #[test]
+    fn test_parse_difficulty() {
+        assert_eq!("diff:1".parse::<Target>().unwrap(), Target::MAX);
+        assert_eq!(
+            "diff:2".parse::<Target>().unwrap().to_string(),
+            format!("8{}", "0".repeat(63))
+        );
+        assert_eq!(
+            "diff:65536".parse::<Target>().unwrap().to_string(),
+            format!("0001{}", "0".repeat(60))
+        );
+        assert_eq!(
+            "diff: 1e6".parse::<Target>().unwrap(),
+            Target::from_difficulty(1_000_000.0).unwrap()
+        );
+        for difficulty in [1.5, 3.0, 1e6, 123_456_789.0, 1e20] {
+            let target = Target::from_difficulty(difficulty).unwrap();
+            let back = target.difficulty();
+            assert!(
+                (back - difficulty).abs() / difficulty < 1e-9,
+                "{} came back as {}",
+                difficulty,
+                back
+            );
+        }
+        // harder is smaller
+        assert!(Target::from_difficulty(3.0).unwrap() < Target::from_difficulty(2.0).unwrap());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        for (input, error) in [
+            ("", "empty target"),
+            ("0x", "empty target"),
+            ("  ", "empty target"),
+            ("00g0", "'g' at position 2 is not a hex digit"),
+            ("0x-1", "'-' at position 0 is not a hex digit"),
+            ("00 00", "' ' at position 2 is not a hex digit"),
+            ("0xx00", "'x' at position 0 is not a hex digit"),
+            (&"0".repeat(65), "65 hex digits, more than the 64"),
+            ("diff:", "'' is not a number"),
+            ("diff:lots", "'lots' is not a number"),
+            ("diff:0.5", "below the minimum of 1"),
+            ("diff:0", "below the minimum of 1"),
+            ("diff:-3", "below the minimum of 1"),
+            ("diff:inf", "not a finite number"),
+            ("diff:NaN", "not a finite number"),
+            ("difficulty:5", "'i' at position 1 is not a hex digit"),
+        ] {
+            let message = input.parse::<Target>().unwrap_err().to_string();
+            assert!(message.contains(error), "{:?}: {}", input, message);
+        }
+        assert!("diff:0.5"
+            .parse::<Target>()
+            .unwrap_err()
+            .to_string()
+            .starts_with("invalid difficulty target"));
+        assert!("00g0"
+            .parse::<Target>()
+            .unwrap_err()
+            .to_string()
+            .starts_with("invalid hex target"));
+    }
+}
@@ -0,0 +1,240 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! The targets the pool set, as difficulties. A pool can credit fewer shares than the hashrate
+//! warrants by handing a miner a far harder target than its others get, or by raising it in
+//! one step where vardiff would take several. Both are flagged with a warning and in the
+//! stats, nothing more: the limits are for judging the pool, not for leaving it.
+
+use crate::{Cli, Target};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+/// Target changes kept for the stats.
+pub const TARGET_HISTORY_LEN: usize = 50;
+/// The default of `--max-difficulty-jump`.
+pub const DEFAULT_MAX_DIFFICULTY_JUMP: f64 = 8.0;
+
+/// Why a target change was flagged.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TargetFlag {
+    /// Harder than `--max-difficulty`.
+    AboveMaxDifficulty,
+    /// Harder than the target before by more than `--max-difficulty-jump` times.
+    DifficultyJump,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TargetChange {
+    /// Unix time in seconds.
+    pub at: u64,
+    pub target: String,
+    pub difficulty: f64,
+    /// The difficulty over the one before, e.g. 0.5 for a target twice as easy. `None` for
+    /// the first target.
+    pub factor: Option<f64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub flags: Vec<TargetFlag>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct TargetHistoryStats {
+    /// The difficulty of the current target.
+    pub difficulty: Option<f64>,
+    /// The last [`TARGET_HISTORY_LEN`] changes, oldest first.
+    pub changes: Vec<TargetChange>,
+    /// Changes flagged since the start.
+    pub flagged: u64,
+}
+
+/// When a target change is flagged.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DifficultyLimits {
+    /// `--max-difficulty`.
+    pub max_difficulty: Option<f64>,
+    /// `--max-difficulty-jump`, 0 to never flag a jump.
+    pub max_jump: f64,
+}
+
+impl Default for DifficultyLimits {
+    fn default() -> Self {
+        Self {
+            max_difficulty: None,
+            max_jump: DEFAULT_MAX_DIFFICULTY_JUMP,
+        }
+    }
+}
+
+impl DifficultyLimits {
+    pub fn from_cli(cli: &Cli) -> Self {
+        Self {
+            max_difficulty: cli.max_difficulty,
+            max_jump: cli.max_difficulty_jump,
+        }
+    }
+}
+
+/// Records the targets the pool sets and flags the suspicious ones.
+#[derive(Clone, Debug, Default)]
+pub struct TargetHistory {
+    limits: DifficultyLimits,
+    changes: VecDeque<TargetChange>,
+    flagged: u64,
+}
+
+impl TargetHistory {
+    pub fn new(limits: DifficultyLimits) -> Self {
+        Self {
+            limits,
+            ..Default::default()
+        }
+    }
+
+    /// Records `target`, set at unix time `at`. The same target again, as some pools send with
+    /// every job, is no change. Returns a warning for each flag of the change.
+    pub fn record(&mut self, target: &Target, at: u64) -> Vec<String> {
+        let target_hex = target.to_string();
+        let last = self.changes.back();
+        if last
+            .map(|last| last.target == target_hex)
+            .unwrap_or_default()
+        {
+            return vec![];
+        }
+        let difficulty = target.difficulty();
+        let previous = last.map(|last| last.difficulty);
+        let factor = previous.map(|previous| difficulty / previous);
+        let mut flags = vec![];
+        let mut warnings = vec![];
+        if let Some(max_difficulty) = self.limits.max_difficulty {
+            if difficulty > max_difficulty {
+                flags.push(TargetFlag::AboveMaxDifficulty);
+                warnings.push(format!(
+                    "pool set difficulty {:.0}, above --max-difficulty {:.0}",
+                    difficulty, max_difficulty
+                ));
+            }
+        }
+        if let (Some(previous), Some(factor)) = (previous, factor) {
+            if self.limits.max_jump > 0.0 && factor > self.limits.max_jump {
+                flags.push(TargetFlag::DifficultyJump);
+                warnings.push(format!(
+                    "pool raised the difficulty {:.1}x in one step, from {:.0} to {:.0}, more than --max-difficulty-jump {}",
+                    factor, previous, difficulty, self.limits.max_jump
+                ));
+            }
+        }
+        if !flags.is_empty() {
+            self.flagged += 1;
+        }
+        if self.changes.len() == TARGET_HISTORY_LEN {
+            self.changes.pop_front();
+        }
+        self.changes.push_back(TargetChange {
+            at,
+            target: target_hex,
+            difficulty,
+            factor,
+            flags,
+        });
+        warnings
+    }
+
+    pub fn difficulty(&self) -> Option<f64> {
+        self.changes.back().map(|last| last.difficulty)
+    }
+
+    pub fn stats(&self) -> TargetHistoryStats {
+        TargetHistoryStats {
+            difficulty: self.difficulty(),
+            changes: self.changes.iter().cloned().collect(),
+            flagged: self.flagged,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(difficulty: f64) -> Target {
+        Target::from_difficulty(difficulty).unwrap()
+    }
+
+    #[test]
+    fn test_target_history() {
+        let mut history = TargetHistory::new(DifficultyLimits {
+            max_difficulty: Some(100_000.0),
+            max_jump: 8.0,
+        });
+        assert_eq!(history.difficulty(), None);
+        // vardiff settling in
+        assert!(history.record(&target(1024.0), 100).is_empty());
+        assert!(history.record(&target(4096.0), 160).is_empty());
+        assert!(history.record(&target(512.0), 220).is_empty());
+        // sent again with a job
+        assert!(history.record(&target(512.0), 230).is_empty());
+        assert_eq!(history.stats().changes.len(), 3);
+
+        // a step too big
+        let warnings = history.record(&target(8192.0), 280);
+        assert_eq!(warnings.len(), 1);
+        assert!(
+            warnings[0]
+                .starts_with("pool raised the difficulty 16.0x in one step, from 512 to 8192"),
+            "{}",
+            warnings[0]
+        );
+        // as big as allowed
+        assert!(history.record(&target(65536.0), 290).is_empty());
+        // above the limit, but only twice as hard
+        let warnings = history.record(&target(131072.0), 300);
+        assert_eq!(warnings.len(), 1);
+        assert!(
+            warnings[0].contains("difficulty 131072, above --max-difficulty 100000"),
+            "{}",
+            warnings[0]
+        );
+        // both at once
+        assert!(history.record(&target(1024.0), 320).is_empty());
+        assert_eq!(history.record(&target(1048576.0), 340).len(), 2);
+
+        let stats = history.stats();
+        assert_eq!(stats.flagged, 3);
+        assert_eq!(stats.difficulty, Some(1048576.0));
+        let last = stats.changes.last().unwrap();
+        assert_eq!(last.at, 340);
+        assert_eq!(last.target, target(1048576.0).to_string());
+        assert_eq!(last.factor, Some(1024.0));
+        assert_eq!(
+            last.flags,
+            vec![TargetFlag::AboveMaxDifficulty, TargetFlag::DifficultyJump]
+        );
+        assert_eq!(stats.changes[0].factor, None);
+        assert!(stats.changes[0].flags.is_empty());
+    }
+
+    #[test]
+    fn test_target_history_limits() {
+        // by default only jumps are flagged, and 0 flags none
+        let mut history = TargetHistory::new(DifficultyLimits::default());
+        history.record(&target(1.0), 0);
+        assert_eq!(history.record(&target(1e12), 1).len(), 1);
+        let mut history = TargetHistory::new(DifficultyLimits {
+            max_jump: 0.0,
+            ..Default::default()
+        });
+        history.record(&target(1.0), 0);
+        assert!(history.record(&target(1e12), 1).is_empty());
+
+        // only the last ones are kept
+        for step in 0..TARGET_HISTORY_LEN as u64 + 10 {
+            history.record(&target(1.0 + step as f64), step);
+        }
+        let changes = history.stats().changes;
+        assert_eq!(changes.len(), TARGET_HISTORY_LEN);
+        assert_eq!(changes[0].at, 10);
+    }
+}
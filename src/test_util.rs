@@ -0,0 +1,606 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! Helpers to test against this crate, built with the `test-util` feature: a pool scripted by
+//! the test, a miner wired to it, and the header and hashes the crate's own tests check.
+//!
+//! Not covered by semver: anything in here may change in any release. Pin the exact version
+//! when depending on it.
+
+use crate::{
+    base64, read_head, Cli, Clock, CompressionCounts, MessageId, Miner, MinerEvent,
+    MiningNotifyBody, MiningNotifyMessage, MiningSetTargetBody, MiningSetTargetMessage,
+    MiningSubmitBody, MiningSubmittedBody, MiningSubmittedMessage, MiningSubscribeBody,
+    MiningSubscribedBody, MiningSubscribedMessage, MiningWaitForWorkMessage, RandomnessStart,
+    SharedClock, StratumMessage, StratumMessageCodec, SystemClock,
+};
+use anyhow::Result;
+use futures::SinkExt;
+use std::{
+    net::SocketAddr,
+    ops::Deref,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{
+        copy_bidirectional, duplex, split, AsyncRead, AsyncWrite, AsyncWriteExt, DuplexStream,
+        ReadHalf, WriteHalf,
+    },
+    net::{TcpListener, TcpStream},
+    sync::broadcast::{self, error::RecvError},
+    time,
+};
+#[cfg(feature = "tls-native")]
+use tokio_native_tls::{native_tls, TlsAcceptor, TlsStream};
+use tokio_stream::StreamExt;
+use tokio_util::codec::{FramedRead, FramedWrite};
+
+/// A mainnet header, as the test server hands it out.
+pub const KNOWN_HEADER: &str = "0000000000000000677101000000000000000000000232f50bb970eeab81d7e2053ebaa585d9b7297f7d14c2063a60e8509d3e86a44918c8f318377cbb327f4fc5b602e78784994cf2926f0addd55d1b0d36880100000000f1baa930706f8b9058bc55be1f464b472639a288763a16f7a5713aa761052e43f7bec3000000000000000000000c6072a3898d86f685d4b9bba50e87f750f9773da7ac2cf96663e357c8b30082010000000000007735ccc1666978796f750000000000000000000000000000000000000000000000000000";
+/// The randomness of [`KNOWN_HASH`].
+pub const KNOWN_RANDOMNESS: u64 = 0x1234;
+/// The hash of [`KNOWN_HEADER`] with [`KNOWN_RANDOMNESS`].
+pub const KNOWN_HASH: &str = "d4c5351759e62f047a319c6c223d92025c2b745ddcf1644306c121931ab5c6a2";
+/// The hash of no bytes at all.
+pub const EMPTY_HASH: &str = "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262";
+
+/// A self-signed certificate for `localhost`, the one `MockPoolListener::bind_tls` uses.
+pub const TEST_CERT: &str = include_str!("../testdata/pool.crt");
+/// The PKCS #8 key of [`TEST_CERT`].
+pub const TEST_KEY: &str = include_str!("../testdata/pool.key");
+
+/// How long [`MockPool`] and [`TestMiner`] wait for what a test expects.
+pub const EXPECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// [`KNOWN_HEADER`] as bytes.
+pub fn known_header() -> Vec<u8> {
+    hex::decode(KNOWN_HEADER).expect("the known header is hex")
+}
+
+/// The target every hash meets, so each one is a share.
+pub fn easy_target() -> String {
+    "ff".repeat(32)
+}
+
+/// A command line that mines on one thread with tiny batches against `pool`.
+pub fn test_cli(pool: SocketAddr) -> Cli {
+    Cli {
+        pool: Some(pool.into()),
+        address: String::from("xxxxxx"),
+        pass: None,
+        worker_name: String::from("xxxxxx"),
+        identity_seed: None,
+        graffiti_suffix: None,
+        threads_count: 1,
+        batch_size: 100,
+        batch_per_thread: None,
+        max_concurrent_jobs: 1,
+        allow_oversubscribe: false,
+        tls: false,
+        api: None,
+        api_socket: None,
+        api_socket_mode: 0o600,
+        network_difficulty: None,
+        difficulty_url: None,
+        max_clock_skew: 30,
+        session_history: 10,
+        first_job_timeout: 120,
+        first_job_reconnect: false,
+        job_ttl: 120,
+        max_target_age: 30,
+        max_target_age_reconnect: false,
+        max_difficulty: None,
+        max_difficulty_jump: 8.0,
+        max_wait_warn: 180,
+        reconnect_on_long_wait: None,
+        half_open_timeout: 180,
+        source_port_range: None,
+        proxy: None,
+        strict_target: false,
+        randomness_width: 8,
+        // the first shares on an easy target are the lowest values
+        randomness_start: RandomnessStart::Zero,
+        header_layout: String::from("ironfish"),
+        force_header_layout: false,
+        tolerate_longer_headers: true,
+        resume_requires_fresh_work: false,
+        lenient_decode: false,
+        compress: false,
+        max_protocol_errors_per_min: None,
+        dump_stratum: None,
+        send_agent: true,
+        hashrate_windows: Default::default(),
+        hashrate_unit: Default::default(),
+        max_submit_rate: Default::default(),
+        resend_submits: true,
+        mine_through_disconnects: 0,
+        dns_ttl: 60,
+        alert_threshold: 80,
+        check: false,
+        check_skip: vec![],
+        json: false,
+        pool_api_url: None,
+        pool_api_interval: 300,
+        report_to: None,
+        report_push_interval: 10,
+        proxy_listen: None,
+        max_temp: None,
+        upgrade: false,
+        max_runtime: None,
+        summary_file: None,
+        data_dir: None,
+        discard_shares_on_stop: false,
+        dual_connection: false,
+        skip_selfcheck: false,
+        hashrate_report_interval: None,
+        command: None,
+        split: None,
+        failover_pools: vec![],
+        failover_after: 3,
+    }
+}
+
+pub fn subscribed_message(
+    client_id: u64,
+    graffiti: &str,
+    capabilities: Option<Vec<String>>,
+) -> StratumMessage {
+    StratumMessage::MiningSubscribedMessage(MiningSubscribedMessage {
+        id: 0.into(),
+        method: String::from("mining.subscribed"),
+        body: MiningSubscribedBody {
+            clientId: client_id,
+            graffiti: graffiti.to_string(),
+            capabilities,
+        },
+    })
+}
+
+pub fn set_target_message(target: &str) -> StratumMessage {
+    StratumMessage::MiningSetTargetMessage(MiningSetTargetMessage {
+        id: 2.into(),
+        method: String::from("mining.set_target"),
+        body: MiningSetTargetBody {
+            target: target.to_string(),
+        },
+    })
+}
+
+pub fn notify_message(mining_request_id: u32, header: &str) -> StratumMessage {
+    StratumMessage::MiningNotifyMessage(MiningNotifyMessage {
+        id: 1.into(),
+        method: String::from("mining.notify"),
+        body: MiningNotifyBody {
+            miningRequestId: mining_request_id,
+            header: header.to_string(),
+            timestamp: None,
+            expiresInMs: None,
+        },
+    })
+}
+
+pub fn wait_for_work_message() -> StratumMessage {
+    StratumMessage::MiningWaitForWorkMessage(MiningWaitForWorkMessage {
+        id: 3.into(),
+        method: String::from("mining.wait_for_work"),
+    })
+}
+
+/// The answer of a pool that acknowledges submits to the submit of message `submit_id`.
+pub fn submitted_message(submit_id: MessageId, accepted: bool) -> StratumMessage {
+    StratumMessage::MiningSubmittedMessage(MiningSubmittedMessage {
+        id: 4.into(),
+        method: String::from("mining.submitted"),
+        body: MiningSubmittedBody {
+            id: submit_id,
+            result: accepted,
+            message: (!accepted).then(|| String::from("duplicate share")),
+        },
+    })
+}
+
+/// The pool end of a connection, driven step by step by the test. The `expect_*` methods
+/// panic on anything else, or after [`EXPECT_TIMEOUT`].
+pub struct MockPool<S = DuplexStream> {
+    pub reader: FramedRead<ReadHalf<S>, StratumMessageCodec>,
+    pub writer: FramedWrite<WriteHalf<S>, StratumMessageCodec>,
+}
+
+impl MockPool<DuplexStream> {
+    /// A pool and the stream to hand the client in its place.
+    pub fn pair() -> (DuplexStream, Self) {
+        let (client, pool) = duplex(4096);
+        (client, Self::new(pool))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite> MockPool<S> {
+    pub fn new(stream: S) -> Self {
+        let (r, w) = split(stream);
+        Self {
+            reader: FramedRead::new(r, StratumMessageCodec::default()),
+            writer: FramedWrite::new(w, StratumMessageCodec::default()),
+        }
+    }
+
+    pub fn into_parts(
+        self,
+    ) -> (
+        FramedRead<ReadHalf<S>, StratumMessageCodec>,
+        FramedWrite<WriteHalf<S>, StratumMessageCodec>,
+    ) {
+        (self.reader, self.writer)
+    }
+
+    /// The next message from the client, `None` once it is gone or sent something that does
+    /// not decode.
+    pub async fn next(&mut self) -> Option<StratumMessage> {
+        self.reader.next().await?.ok()
+    }
+
+    /// Sends to the client, returns whether it is still there.
+    pub async fn send(&mut self, message: StratumMessage) -> bool {
+        self.writer.send(message).await.is_ok()
+    }
+
+    pub async fn send_all(&mut self, messages: impl IntoIterator<Item = StratumMessage>) -> bool {
+        for message in messages {
+            if !self.send(message).await {
+                return false;
+            }
+        }
+        true
+    }
+
+    async fn expect(&mut self, what: &str) -> StratumMessage {
+        match time::timeout(EXPECT_TIMEOUT, self.next()).await {
+            Ok(Some(message)) => message,
+            Ok(None) => panic!("expected {}, the client is gone", what),
+            Err(_) => panic!("expected {}, got nothing", what),
+        }
+    }
+
+    pub async fn expect_subscribe(&mut self) -> MiningSubscribeBody {
+        match self.expect("a subscribe").await {
+            StratumMessage::MiningSubscribeMessage(message) => message.body,
+            other => panic!("expected a subscribe, got {:?}", other),
+        }
+    }
+
+    pub async fn expect_submit(&mut self) -> MiningSubmitBody {
+        match self.expect("a submit").await {
+            StratumMessage::MiningSubmitMessage(message) => message.body,
+            other => panic!("expected a submit, got {:?}", other),
+        }
+    }
+
+    /// Acks the subscribe like a pool would, as client 1 with the graffiti `zk.work`.
+    pub async fn accept_subscribe(&mut self) -> MiningSubscribeBody {
+        let subscribe = self.expect_subscribe().await;
+        self.send(subscribed_message(1, "zk.work", None)).await;
+        subscribe
+    }
+
+    /// Compresses what goes either way from now on, as a pool that acked
+    /// [`CAPABILITY_DEFLATE`](crate::CAPABILITY_DEFLATE).
+    pub fn compress(&mut self) -> Arc<CompressionCounts> {
+        let counts = Arc::new(CompressionCounts::default());
+        self.reader.decoder_mut().decompress_reads(counts.clone());
+        self.writer.encoder_mut().compress_writes(counts.clone());
+        counts
+    }
+
+    /// Hands out a job of `header` with the easy target.
+    pub async fn easy_job(&mut self, mining_request_id: u32, header: &str) -> bool {
+        self.send_all([
+            set_target_message(&easy_target()),
+            notify_message(mining_request_id, header),
+        ])
+        .await
+    }
+}
+
+/// Accepts the connections of a miner pointed at [`Self::address`].
+pub struct MockPoolListener {
+    listener: TcpListener,
+    #[cfg(feature = "tls-native")]
+    tls: Option<TlsAcceptor>,
+}
+
+impl MockPoolListener {
+    pub async fn bind() -> Self {
+        Self {
+            listener: TcpListener::bind("127.0.0.1:0")
+                .await
+                .expect("a local port to listen on"),
+            #[cfg(feature = "tls-native")]
+            tls: None,
+        }
+    }
+
+    /// A listener for a miner with `--tls`, see [`Self::accept_tls`]. The acceptor is
+    /// native-tls, so only in a build with `tls-native`.
+    #[cfg(feature = "tls-native")]
+    pub async fn bind_tls() -> Self {
+        let identity = native_tls::Identity::from_pkcs8(TEST_CERT.as_bytes(), TEST_KEY.as_bytes())
+            .expect("the test certificate");
+        let acceptor = native_tls::TlsAcceptor::new(identity).expect("a TLS acceptor");
+        Self {
+            tls: Some(acceptor.into()),
+            ..Self::bind().await
+        }
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.listener.local_addr().expect("a bound listener")
+    }
+
+    /// The pool end of the next connection.
+    pub async fn accept(&self) -> MockPool<TcpStream> {
+        let (stream, _) = self.listener.accept().await.expect("a connection");
+        MockPool::new(stream)
+    }
+
+    /// The pool end of the next connection, once the TLS handshake completed. Only on a
+    /// listener from [`Self::bind_tls`].
+    #[cfg(feature = "tls-native")]
+    pub async fn accept_tls(&self) -> MockPool<TlsStream<TcpStream>> {
+        let acceptor = self.tls.as_ref().expect("a listener bound with bind_tls");
+        let (stream, _) = self.listener.accept().await.expect("a connection");
+        let stream = time::timeout(EXPECT_TIMEOUT, acceptor.accept(stream))
+            .await
+            .expect("a TLS handshake in time")
+            .expect("a TLS handshake");
+        MockPool::new(stream)
+    }
+}
+
+/// [`spawn_test_pool_with_target`] with the easiest target.
+pub async fn spawn_test_pool() -> (SocketAddr, Arc<AtomicUsize>) {
+    spawn_test_pool_with_target(easy_target()).await
+}
+
+/// A pool that sends one job with `target` and counts the submits it receives.
+pub async fn spawn_test_pool_with_target(target: String) -> (SocketAddr, Arc<AtomicUsize>) {
+    let listener = MockPoolListener::bind().await;
+    let address = listener.address();
+    let submits = Arc::new(AtomicUsize::new(0));
+    let counter = submits.clone();
+    tokio::spawn(async move {
+        let mut pool = listener.accept().await;
+        while let Some(message) = pool.next().await {
+            match message {
+                StratumMessage::MiningSubscribeMessage(_) => {
+                    let messages = [
+                        subscribed_message(1, "zk.work", None),
+                        set_target_message(&target),
+                        notify_message(7, &"00".repeat(208)),
+                    ];
+                    assert!(pool.send_all(messages).await);
+                }
+                StratumMessage::MiningSubmitMessage(_) => {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                }
+                _ => {}
+            }
+        }
+    });
+    (address, submits)
+}
+
+/// An HTTP proxy for a miner with `--proxy`, opening a tunnel for every CONNECT. With
+/// credentials, a CONNECT without them or with others is answered 407.
+pub struct ConnectProxy {
+    address: SocketAddr,
+    tunnels: Arc<AtomicUsize>,
+    close: broadcast::Sender<()>,
+}
+
+impl ConnectProxy {
+    pub async fn spawn(credentials: Option<(&str, &str)>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("a local port to listen on");
+        let authorization = credentials.map(|(username, password)| {
+            format!(
+                "Proxy-Authorization: Basic {}",
+                base64(format!("{}:{}", username, password).as_bytes())
+            )
+        });
+        let tunnels = Arc::new(AtomicUsize::new(0));
+        let (close, _) = broadcast::channel(1);
+        let proxy = Self {
+            address: listener.local_addr().expect("a bound listener"),
+            tunnels: tunnels.clone(),
+            close: close.clone(),
+        };
+        tokio::spawn(async move {
+            while let Ok((mut client, _)) = listener.accept().await {
+                let authorization = authorization.clone();
+                let tunnels = tunnels.clone();
+                let mut closed = close.subscribe();
+                tokio::spawn(async move {
+                    let head = match read_head(&mut client).await {
+                        Ok(head) => head,
+                        Err(_) => return,
+                    };
+                    let authorized = match &authorization {
+                        Some(expected) => head.lines().any(|line| line == expected),
+                        None => true,
+                    };
+                    if !authorized {
+                        let _ = client
+                            .write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\nProxy-Authenticate: Basic realm=\"test\"\r\n\r\n")
+                            .await;
+                        return;
+                    }
+                    let target = head.split_whitespace().nth(1).unwrap_or_default();
+                    let mut upstream = match TcpStream::connect(target).await {
+                        Ok(upstream) => upstream,
+                        Err(_) => {
+                            let _ = client.write_all(b"HTTP/1.1 502 Bad Gateway\r\n\r\n").await;
+                            return;
+                        }
+                    };
+                    if client
+                        .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                        .await
+                        .is_err()
+                    {
+                        return;
+                    }
+                    tunnels.fetch_add(1, Ordering::SeqCst);
+                    tokio::select! {
+                        _ = copy_bidirectional(&mut client, &mut upstream) => {}
+                        _ = closed.recv() => {}
+                    }
+                });
+            }
+        });
+        proxy
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// The tunnels opened so far.
+    pub fn tunnels(&self) -> usize {
+        self.tunnels.load(Ordering::SeqCst)
+    }
+
+    /// Closes the open tunnels at both ends, as a proxy that drops idle connections does.
+    pub fn close_tunnels(&self) {
+        let _ = self.close.send(());
+    }
+}
+
+/// Sets up a [`TestMiner`] on [`test_cli`], with room to change the command line first.
+pub struct TestMinerBuilder {
+    cli: Cli,
+    clock: SharedClock,
+}
+
+impl TestMinerBuilder {
+    pub fn new(pool: SocketAddr) -> Self {
+        Self {
+            cli: test_cli(pool),
+            clock: SystemClock::shared(),
+        }
+    }
+
+    pub fn threads(mut self, threads_count: usize) -> Self {
+        self.cli.threads_count = threads_count;
+        self
+    }
+
+    pub fn batch_size(mut self, batch_size: u32) -> Self {
+        self.cli.batch_size = batch_size;
+        self
+    }
+
+    pub fn cli(mut self, configure: impl FnOnce(&mut Cli)) -> Self {
+        configure(&mut self.cli);
+        self
+    }
+
+    /// Runs the timers of the miner on `clock`, e.g. a [`crate::ManualClock`].
+    pub fn clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Initializes the miner. Nothing connects before [`TestMiner::launch`].
+    pub async fn build(self) -> Result<TestMiner> {
+        let miner = Miner::initialize_with_clock(self.cli, String::new(), self.clock).await?;
+        let events = miner.subscribe_events();
+        Ok(TestMiner { miner, events })
+    }
+}
+
+/// A miner and every event it emitted since it was built.
+pub struct TestMiner {
+    pub miner: Arc<Miner>,
+    events: broadcast::Receiver<MinerEvent>,
+}
+
+impl TestMiner {
+    pub async fn launch(&self) {
+        Miner::launch(self.miner.clone()).await;
+    }
+
+    /// The next event, `None` if there was none for [`EXPECT_TIMEOUT`].
+    pub async fn next_event(&mut self) -> Option<MinerEvent> {
+        time::timeout(EXPECT_TIMEOUT, async {
+            loop {
+                match self.events.recv().await {
+                    Ok(event) => return Some(event),
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        })
+        .await
+        .ok()
+        .flatten()
+    }
+
+    /// Skips events up to the first one that `matches`, panics if none comes.
+    pub async fn wait_for(&mut self, matches: impl Fn(&MinerEvent) -> bool) -> MinerEvent {
+        loop {
+            match self.next_event().await {
+                Some(event) if matches(&event) => return event,
+                Some(_) => {}
+                None => panic!("the awaited event never came"),
+            }
+        }
+    }
+}
+
+impl Deref for TestMiner {
+    type Target = Miner;
+
+    fn deref(&self) -> &Miner {
+        &self.miner
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_miner_against_mock_pool() {
+        let listener = MockPoolListener::bind().await;
+        let mut miner = TestMinerBuilder::new(listener.address())
+            .build()
+            .await
+            .unwrap();
+        miner.launch().await;
+        let mut pool = listener.accept().await;
+        assert_eq!(pool.accept_subscribe().await.name, "xxxxxx");
+        assert!(pool.easy_job(7, KNOWN_HEADER).await);
+
+        let found = miner
+            .wait_for(|event| matches!(event, MinerEvent::ShareFound { .. }))
+            .await;
+        let submit = pool.expect_submit().await;
+        assert_eq!(submit.miningRequestId, 7);
+        // the pool did not agree to solve_time
+        assert_eq!(submit.elapsedMs, None);
+        assert!(matches!(
+            found,
+            MinerEvent::ShareFound {
+                mining_request_id: 7,
+                ..
+            }
+        ));
+        miner.stop().await;
+        miner.wait_for(|event| *event == MinerEvent::Stopped).await;
+    }
+}
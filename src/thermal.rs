@@ -0,0 +1,269 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! Keeps the CPU below `--max-temp` by mining on fewer threads while it is too hot.
+//!
+//! The temperature is read from hwmon in sysfs on Linux. Other platforms have no sensor and
+//! mine unthrottled.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Duration,
+};
+
+pub const THERMAL_POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// Degrees below `--max-temp` the CPU has to cool down to before mining speeds up again.
+const HYSTERESIS: f64 = 5.0;
+/// Percentage points mining slows down or speeds up by per reading.
+const STEP_PERCENT: u32 = 10;
+const MIN_PERCENT: u32 = 10;
+#[cfg(target_os = "linux")]
+const HWMON_ROOT: &str = "/sys/class/hwmon";
+/// hwmon drivers that report the CPU package or core temperatures.
+const CPU_HWMON_NAMES: &[&str] = &[
+    "coretemp",
+    "k10temp",
+    "zenpower",
+    "cpu_thermal",
+    "soc_thermal",
+];
+
+/// Where the CPU temperature comes from.
+pub trait TemperatureSensor: fmt::Debug + Send + Sync {
+    /// The hottest CPU temperature in °C, `None` if it could not be read this time.
+    fn read(&self) -> Option<f64>;
+}
+
+/// The `temp*_input` files of the CPU drivers under a hwmon directory.
+#[derive(Debug)]
+pub struct HwmonSensor {
+    inputs: Vec<PathBuf>,
+}
+
+impl HwmonSensor {
+    /// Finds the CPU temperature inputs under `root`, `None` if there are none that read.
+    pub fn discover(root: &Path) -> Option<Self> {
+        let mut inputs = vec![];
+        for device in fs::read_dir(root).ok()?.flatten() {
+            let path = device.path();
+            let name = fs::read_to_string(path.join("name")).unwrap_or_default();
+            if !CPU_HWMON_NAMES.contains(&name.trim()) {
+                continue;
+            }
+            for file in fs::read_dir(&path).into_iter().flatten().flatten() {
+                let file_name = file.file_name();
+                let file_name = file_name.to_string_lossy();
+                if file_name.starts_with("temp") && file_name.ends_with("_input") {
+                    inputs.push(file.path());
+                }
+            }
+        }
+        inputs.sort();
+        let sensor = Self { inputs };
+        sensor.read().map(|_| sensor)
+    }
+}
+
+impl TemperatureSensor for HwmonSensor {
+    fn read(&self) -> Option<f64> {
+        self.inputs
+            .iter()
+            .filter_map(|input| fs::read_to_string(input).ok())
+            // millidegrees
+            .filter_map(|value| value.trim().parse::<f64>().ok())
+            .map(|millidegrees| millidegrees / 1000.0)
+            .reduce(f64::max)
+    }
+}
+
+/// The CPU temperature sensor of this machine, if it has one that reads.
+pub fn system_sensor() -> Option<Box<dyn TemperatureSensor>> {
+    #[cfg(target_os = "linux")]
+    {
+        HwmonSensor::discover(Path::new(HWMON_ROOT))
+            .map(|sensor| Box::new(sensor) as Box<dyn TemperatureSensor>)
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        None
+    }
+}
+
+/// Lowers the share of threads mining step by step while the temperature is above the limit,
+/// and raises it again step by step once the temperature is `HYSTERESIS` degrees below it.
+#[derive(Debug)]
+pub struct ThermalController {
+    max_temp: f64,
+    percent: u32,
+}
+
+impl ThermalController {
+    pub fn new(max_temp: f64) -> Self {
+        Self {
+            max_temp,
+            percent: 100,
+        }
+    }
+
+    pub fn percent(&self) -> u32 {
+        self.percent
+    }
+
+    /// Takes a reading and returns the new percentage if it changed.
+    pub fn observe(&mut self, temperature: f64) -> Option<u32> {
+        let percent = if temperature > self.max_temp {
+            self.percent.saturating_sub(STEP_PERCENT).max(MIN_PERCENT)
+        } else if temperature <= self.max_temp - HYSTERESIS {
+            (self.percent + STEP_PERCENT).min(100)
+        } else {
+            self.percent
+        };
+        if percent == self.percent {
+            return None;
+        }
+        self.percent = percent;
+        Some(percent)
+    }
+}
+
+/// Threads mining at `percent` of `threads`, at least one.
+pub fn throttled_threads(threads: usize, percent: u32) -> usize {
+    (threads * percent as usize)
+        .div_ceil(100)
+        .clamp(1, threads.max(1))
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ThermalStats {
+    /// The last reading in °C, `None` if the last read failed.
+    pub temperature: Option<f64>,
+    pub max_temp: u32,
+    /// Share of the threads mining, 100 when not throttled.
+    pub mining_percent: u32,
+    pub threads: usize,
+}
+
+/// A sensor and the controller it feeds.
+#[derive(Debug)]
+pub struct Thermal {
+    max_temp: u32,
+    sensor: Box<dyn TemperatureSensor>,
+    controller: Mutex<ThermalController>,
+    temperature: Mutex<Option<f64>>,
+}
+
+impl Thermal {
+    pub fn new(max_temp: u32, sensor: Box<dyn TemperatureSensor>) -> Self {
+        Self {
+            max_temp,
+            sensor,
+            controller: Mutex::new(ThermalController::new(max_temp as f64)),
+            temperature: Default::default(),
+        }
+    }
+
+    pub fn max_temp(&self) -> u32 {
+        self.max_temp
+    }
+
+    /// Reads the sensor. Returns the temperature and the new percentage if it changed.
+    pub fn poll(&self) -> (Option<f64>, Option<u32>) {
+        let temperature = self.sensor.read();
+        *self.temperature.lock().unwrap() = temperature;
+        let change = temperature
+            .and_then(|temperature| self.controller.lock().unwrap().observe(temperature));
+        (temperature, change)
+    }
+
+    pub fn stats(&self, threads: usize) -> ThermalStats {
+        let mining_percent = self.controller.lock().unwrap().percent();
+        ThermalStats {
+            temperature: *self.temperature.lock().unwrap(),
+            max_temp: self.max_temp,
+            mining_percent,
+            threads: throttled_threads(threads, mining_percent),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(controller: &mut ThermalController, temperatures: &[f64]) -> Vec<u32> {
+        temperatures
+            .iter()
+            .map(|temperature| {
+                controller.observe(*temperature);
+                controller.percent()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_controller() {
+        let mut controller = ThermalController::new(85.0);
+        // cool, and right at the limit, stays at full speed
+        assert_eq!(run(&mut controller, &[60.0, 84.0, 85.0]), [100, 100, 100]);
+        // steps down while above the limit, down to the minimum
+        assert_eq!(run(&mut controller, &[86.0, 87.0, 86.5]), [90, 80, 70]);
+        let mut hot = ThermalController::new(85.0);
+        assert_eq!(run(&mut hot, &[95.0; 12]).last(), Some(&MIN_PERCENT));
+        // within the hysteresis band it holds
+        assert_eq!(run(&mut controller, &[85.0, 82.0, 80.1]), [70, 70, 70]);
+        // and only ramps back up once cool enough, step by step
+        assert_eq!(
+            run(&mut controller, &[80.0, 79.0, 81.0, 86.0, 75.0]),
+            [80, 90, 90, 80, 90]
+        );
+        assert_eq!(run(&mut controller, &[70.0, 70.0, 70.0]), [100, 100, 100]);
+        // only changes are reported
+        assert_eq!(controller.observe(70.0), None);
+        assert_eq!(controller.observe(90.0), Some(90));
+    }
+
+    #[test]
+    fn test_throttled_threads() {
+        assert_eq!(throttled_threads(8, 100), 8);
+        assert_eq!(throttled_threads(8, 90), 8);
+        assert_eq!(throttled_threads(8, 50), 4);
+        assert_eq!(throttled_threads(8, 10), 1);
+        assert_eq!(throttled_threads(1, 10), 1);
+        assert_eq!(throttled_threads(3, 70), 3);
+        assert_eq!(throttled_threads(3, 60), 2);
+    }
+
+    #[test]
+    fn test_hwmon_sensor() {
+        let root = std::env::temp_dir().join(format!("hwmon-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        assert!(HwmonSensor::discover(&root).is_none());
+        for (device, name, temperatures) in [
+            ("hwmon0", "acpitz", &[99_000][..]),
+            ("hwmon1", "coretemp", &[61_000, 67_500][..]),
+            ("hwmon2", "nvme", &[40_000][..]),
+        ] {
+            let path = root.join(device);
+            fs::create_dir_all(&path).unwrap();
+            fs::write(path.join("name"), format!("{}\n", name)).unwrap();
+            for (index, temperature) in temperatures.iter().enumerate() {
+                fs::write(
+                    path.join(format!("temp{}_input", index + 1)),
+                    format!("{}\n", temperature),
+                )
+                .unwrap();
+            }
+        }
+        let sensor = HwmonSensor::discover(&root).unwrap();
+        // the hottest core, other devices left out
+        assert_eq!(sensor.read(), Some(67.5));
+        fs::remove_dir_all(root.join("hwmon1")).unwrap();
+        assert_eq!(sensor.read(), None);
+        assert!(HwmonSensor::discover(&root).is_none());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
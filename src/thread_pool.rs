@@ -0,0 +1,434 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! The mining threads, in place of the thread pool of ironfish-rust, whose search always began
+//! at 0 so that every rig raced through the same low values. Here each job comes with the
+//! randomness to start from, see `--randomness-start`. Thread `i` of `n` hashes `start + i`,
+//! `start + i + n` and so on, so that together the threads go through the values in order
+//! from the start. Past `u64::MAX` the search wraps around to 0 and goes on up to the start,
+//! which covers the whole space either way. Shares of a job that was replaced and not picked
+//! up yet are dropped, so that those of the new job never wait behind them.
+//!
+//! Like the ironfish-rust pool, the value is written big-endian into header bytes 0..8 and the
+//! header hashed with blake3, a hash at or below the target being a share.
+
+use anyhow::{anyhow, Result};
+use std::{
+    fmt,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Receiver, Sender, SyncSender, TryRecvError},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+/// Shares found and not picked up yet. Past that the threads drop what they find, which only
+/// happens with a target nearly every hash meets.
+pub const MAX_UNCOLLECTED_SHARES: usize = 4096;
+
+/// The parsed value of `--randomness-start`: where the search of every job begins.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RandomnessStart {
+    /// At 0, as the ironfish-rust thread pool did.
+    Zero,
+    /// At a value drawn once per process, see [`crate::Identity::nonce_start`].
+    #[default]
+    Random,
+    /// At this value, given as hex.
+    At(u64),
+}
+
+impl RandomnessStart {
+    /// The value searched from, `random` being the one drawn for this process.
+    pub fn resolve(&self, random: u64) -> u64 {
+        match self {
+            Self::Zero => 0,
+            Self::Random => random,
+            Self::At(start) => *start,
+        }
+    }
+}
+
+impl FromStr for RandomnessStart {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "zero" => Ok(Self::Zero),
+            "random" => Ok(Self::Random),
+            _ => {
+                let hex = s.strip_prefix("0x").unwrap_or(s);
+                if hex.is_empty() || hex.len() > 16 {
+                    return Err(anyhow!(
+                        "invalid randomness start '{}', expected zero, random or up to 16 hex digits",
+                        s
+                    ));
+                }
+                u64::from_str_radix(hex, 16).map(Self::At).map_err(|_| {
+                    anyhow!(
+                        "invalid randomness start '{}', expected zero, random or up to 16 hex digits",
+                        s
+                    )
+                })
+            }
+        }
+    }
+}
+
+impl fmt::Display for RandomnessStart {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Zero => f.write_str("zero"),
+            Self::Random => f.write_str("random"),
+            Self::At(start) => write!(f, "{:016x}", start),
+        }
+    }
+}
+
+/// A job, shared by the threads that search it.
+#[derive(Debug)]
+struct Job {
+    header: Box<[u8]>,
+    target: [u8; 32],
+    mining_request_id: u32,
+    start: u64,
+    /// Counts the jobs of the pool, to tell the shares of replaced ones.
+    generation: u64,
+}
+
+/// A share, of the job of `generation`.
+#[derive(Debug)]
+struct Found {
+    randomness: u64,
+    mining_request_id: u32,
+    generation: u64,
+}
+
+#[derive(Debug)]
+enum Command {
+    Work(Arc<Job>),
+    Pause,
+    Stop,
+}
+
+/// One thread's part of a job.
+struct Search {
+    header: Vec<u8>,
+    job: Arc<Job>,
+    /// From the start of the job, of the next value to hash.
+    offset: u64,
+}
+
+impl Search {
+    fn new(job: Arc<Job>, index: u64) -> Self {
+        Self {
+            header: job.header.to_vec(),
+            job,
+            offset: index,
+        }
+    }
+
+    /// Hashes `batch_size` values, `step` apart. Returns those that make a share.
+    fn batch(&mut self, batch_size: u64, step: u64) -> Vec<u64> {
+        let mut found = vec![];
+        for _ in 0..batch_size {
+            let randomness = self.job.start.wrapping_add(self.offset);
+            self.offset = self.offset.wrapping_add(step);
+            if self.header.len() < 8 {
+                continue;
+            }
+            self.header[0..8].copy_from_slice(&randomness.to_be_bytes());
+            if blake3::hash(&self.header).as_bytes() <= &self.job.target {
+                found.push(randomness);
+            }
+        }
+        found
+    }
+}
+
+/// A fixed number of mining threads, all searching the latest job. Shares and hash counts are
+/// picked up by polling.
+#[derive(Debug)]
+pub struct ThreadPool {
+    commands: Vec<Sender<Command>>,
+    found: Mutex<Receiver<Found>>,
+    hashes: Arc<AtomicU64>,
+    generation: AtomicU64,
+}
+
+impl ThreadPool {
+    /// Starts `threads` threads, at least one, each hashing `batch_size` values at a time
+    /// between looking for a new job.
+    pub fn new(threads: usize, batch_size: u32) -> Self {
+        let threads = threads.max(1);
+        let (found_sender, found) = mpsc::sync_channel(MAX_UNCOLLECTED_SHARES);
+        let hashes = Arc::new(AtomicU64::new(0));
+        let mut commands = Vec::with_capacity(threads);
+        for index in 0..threads {
+            let (sender, receiver) = mpsc::channel();
+            commands.push(sender);
+            let found = found_sender.clone();
+            let hashes = hashes.clone();
+            let step = threads as u64;
+            let batch_size = batch_size.max(1) as u64;
+            thread::Builder::new()
+                .name(format!("mining {}", index))
+                .spawn(move || mine(index as u64, step, batch_size, receiver, found, hashes))
+                .expect("failed to spawn a mining thread");
+        }
+        Self {
+            commands,
+            found: Mutex::new(found),
+            hashes,
+            generation: AtomicU64::new(0),
+        }
+    }
+
+    /// Mines `header` from now on, searching from `start`. Shares of the jobs before are not
+    /// handed out anymore.
+    pub fn new_work(&self, header: &[u8], target: &[u8], mining_request_id: u32, start: u64) {
+        let mut job_target = [0xff; 32];
+        let len = target.len().min(32);
+        job_target[..len].copy_from_slice(&target[..len]);
+        let job = Arc::new(Job {
+            header: header.into(),
+            target: job_target,
+            mining_request_id,
+            start,
+            generation: self.generation.fetch_add(1, Ordering::SeqCst) + 1,
+        });
+        self.send(|| Command::Work(job.clone()));
+    }
+
+    /// Stops hashing until the next job.
+    pub fn pause(&self) {
+        self.send(|| Command::Pause);
+    }
+
+    /// Ends the threads once they are done with their batch. The shares and hashes of that
+    /// batch can still be picked up after.
+    pub fn stop(&self) {
+        self.send(|| Command::Stop);
+    }
+
+    /// The next share found of the latest job, with its mining request id.
+    pub fn get_found_block(&self) -> Option<(u64, u32)> {
+        let found = self.found.lock().unwrap();
+        let generation = self.generation.load(Ordering::SeqCst);
+        // the shares of replaced jobs are skipped
+        found
+            .try_iter()
+            .find(|share| share.generation == generation)
+            .map(|share| (share.randomness, share.mining_request_id))
+    }
+
+    /// The hashes since the last call.
+    pub fn get_hash_rate_submission(&self) -> u64 {
+        self.hashes.swap(0, Ordering::Relaxed)
+    }
+
+    fn send(&self, command: impl Fn() -> Command) {
+        for commands in &self.commands {
+            // a thread that is gone has nothing to do anymore
+            let _ = commands.send(command());
+        }
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        self.send(|| Command::Stop);
+    }
+}
+
+/// The loop of thread `index`, searching every `step`th value of the job.
+fn mine(
+    index: u64,
+    step: u64,
+    batch_size: u64,
+    commands: Receiver<Command>,
+    found: SyncSender<Found>,
+    hashes: Arc<AtomicU64>,
+) {
+    let mut search: Option<Search> = None;
+    loop {
+        // waits for a job while there is none
+        let command = match &search {
+            Some(_) => match commands.try_recv() {
+                Ok(command) => Some(command),
+                Err(TryRecvError::Empty) => None,
+                Err(TryRecvError::Disconnected) => return,
+            },
+            None => match commands.recv() {
+                Ok(command) => Some(command),
+                Err(_) => return,
+            },
+        };
+        match command {
+            Some(Command::Work(job)) => search = Some(Search::new(job, index)),
+            Some(Command::Pause) => search = None,
+            Some(Command::Stop) => return,
+            None => {}
+        }
+        if let Some(search) = &mut search {
+            for randomness in search.batch(batch_size, step) {
+                let _ = found.try_send(Found {
+                    randomness,
+                    mining_request_id: search.job.mining_request_id,
+                    generation: search.job.generation,
+                });
+            }
+            hashes.fetch_add(batch_size, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    /// The shares of the pool until `count` were found.
+    fn collect(pool: &ThreadPool, count: usize) -> Vec<(u64, u32)> {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let mut found = vec![];
+        while found.len() < count {
+            assert!(Instant::now() < deadline, "found {:?}", found);
+            match pool.get_found_block() {
+                Some(share) => found.push(share),
+                None => thread::sleep(Duration::from_millis(1)),
+            }
+        }
+        found
+    }
+
+    #[test]
+    fn test_search_start() {
+        // every hash is a share with the easiest target
+        let pool = ThreadPool::new(1, 4);
+        pool.new_work(&[0; 208], &[0xff; 32], 7, 1000);
+        let found = collect(&pool, 8);
+        pool.stop();
+        assert_eq!(
+            found,
+            (1000..1008)
+                .map(|randomness| (randomness, 7))
+                .collect::<Vec<_>>()
+        );
+        let mut hashes = 0;
+        while hashes < 8 {
+            hashes += pool.get_hash_rate_submission();
+        }
+
+        // the threads split the values between them, none is hashed twice
+        let pool = ThreadPool::new(3, 4);
+        pool.new_work(&[0; 208], &[0xff; 32], 7, 1000);
+        let mut values: Vec<u64> = collect(&pool, 30)
+            .into_iter()
+            .map(|(randomness, _)| randomness)
+            .collect();
+        pool.stop();
+        values.sort();
+        values.dedup();
+        assert_eq!(values.len(), 30);
+        assert!(values[0] >= 1000);
+    }
+
+    #[test]
+    fn test_wraparound() {
+        let pool = ThreadPool::new(1, 2);
+        pool.new_work(&[0; 208], &[0xff; 32], 1, u64::MAX - 2);
+        let values: Vec<u64> = collect(&pool, 6)
+            .into_iter()
+            .map(|(randomness, _)| randomness)
+            .collect();
+        pool.stop();
+        // past the last value the search goes on at 0
+        assert_eq!(values, [u64::MAX - 2, u64::MAX - 1, u64::MAX, 0, 1, 2]);
+    }
+
+    #[test]
+    fn test_shares_meet_target() {
+        let pool = ThreadPool::new(2, 64);
+        let header = [0x5a; 208];
+        let mut target = [0xff; 32];
+        target[0] = 0x0f;
+        pool.new_work(&header, &target, 3, 1 << 40);
+        let found = collect(&pool, 4);
+        pool.stop();
+        for (randomness, _) in found {
+            assert!(randomness >= 1 << 40);
+            // the value goes in big-endian, as the pool splices the submitted hex back
+            let mut hashed = header;
+            hashed[0..8].copy_from_slice(&randomness.to_be_bytes());
+            assert!(blake3::hash(&hashed).as_bytes()[0] <= 0x0f);
+        }
+    }
+
+    #[test]
+    fn test_randomness_start() {
+        assert_eq!(
+            "zero".parse::<RandomnessStart>().unwrap(),
+            RandomnessStart::Zero
+        );
+        assert_eq!(
+            "random".parse::<RandomnessStart>().unwrap(),
+            RandomnessStart::Random
+        );
+        let at: RandomnessStart = "0x00000000000004d2".parse().unwrap();
+        assert_eq!(at, RandomnessStart::At(1234));
+        assert_eq!("4d2".parse::<RandomnessStart>().unwrap(), at);
+        assert_eq!(at.to_string(), "00000000000004d2");
+        assert_eq!(at.to_string().parse::<RandomnessStart>().unwrap(), at);
+        assert_eq!(
+            "ffffffffffffffff".parse::<RandomnessStart>().unwrap(),
+            RandomnessStart::At(u64::MAX)
+        );
+        for invalid in ["", "0x", "1ffffffffffffffff", "-1", "zz", "Random"] {
+            assert!(invalid.parse::<RandomnessStart>().is_err(), "{}", invalid);
+        }
+
+        assert_eq!(RandomnessStart::Zero.resolve(7), 0);
+        assert_eq!(RandomnessStart::Random.resolve(7), 7);
+        assert_eq!(at.resolve(7), 1234);
+    }
+
+    #[test]
+    fn test_stale_shares_dropped() {
+        let pool = ThreadPool::new(1, 64);
+        pool.new_work(&[0; 208], &[0xff; 32], 1, 0);
+        // the channel fills up with shares of the first job nobody picks up
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while pool.hashes.load(Ordering::Relaxed) < MAX_UNCOLLECTED_SHARES as u64 {
+            assert!(Instant::now() < deadline);
+            thread::sleep(Duration::from_millis(1));
+        }
+        pool.new_work(&[0; 208], &[0xff; 32], 2, 1 << 32);
+        let found = collect(&pool, 8);
+        pool.stop();
+        assert_eq!(
+            found,
+            ((1 << 32)..(1 << 32) + 8)
+                .map(|randomness| (randomness, 2))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_pause() {
+        let pool = ThreadPool::new(1, 8);
+        pool.new_work(&[0; 208], &[0xff; 32], 1, 0);
+        collect(&pool, 1);
+        pool.pause();
+        thread::sleep(Duration::from_millis(20));
+        while pool.get_found_block().is_some() {}
+        pool.get_hash_rate_submission();
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(pool.get_found_block(), None);
+        assert_eq!(pool.get_hash_rate_submission(), 0);
+        pool.stop();
+    }
+}
@@ -0,0 +1,149 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::{HeaderLayout, Randomness};
+use anyhow::{anyhow, Result};
+use std::sync::{Arc, Mutex};
+
+/// A job ready for the thread pool: the pool header with our graffiti and the target it was
+/// dispatched with. Shared as `Arc<Work>` so that dispatching does not copy the header.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Work {
+    pub mining_request_id: u32,
+    pub header: Box<[u8]>,
+    pub target: [u8; 32],
+}
+
+impl Work {
+    /// Decodes a `mining.notify` header in place into `buffer`, reused if it has the right
+    /// size, and splices in the graffiti.
+    pub fn from_notify(
+        mining_request_id: u32,
+        header: &str,
+        graffiti: &[u8],
+        target: [u8; 32],
+        layout: &HeaderLayout,
+        buffer: Option<Box<[u8]>>,
+    ) -> Result<Self> {
+        let size = header.len() / 2;
+        let mut bytes = match buffer {
+            Some(buffer) if buffer.len() == size => buffer,
+            _ => vec![0u8; size].into_boxed_slice(),
+        };
+        hex::decode_to_slice(header, &mut bytes)?;
+        let range = layout.graffiti_range();
+        if bytes.len() < range.end || graffiti.len() != range.len() {
+            return Err(anyhow!(
+                "header of {} bytes has no room for graffiti at {:?}",
+                bytes.len(),
+                range
+            ));
+        }
+        bytes[range].copy_from_slice(graffiti);
+        Ok(Self {
+            mining_request_id,
+            header: bytes,
+            target,
+        })
+    }
+
+    /// Writes `start` into the randomness bytes past the search value, so that miners cloned
+    /// from one image do not hash the same headers. Only for a field wider than the search,
+    /// and only if the pool left those bytes zero. Returns whether it did.
+    pub fn seed_randomness(&mut self, start: u64, layout: &HeaderLayout) -> bool {
+        let range = layout.randomness_range();
+        let spare = range.start + Randomness::SEARCH_SIZE..range.end;
+        let seeded = spare.start..spare.start + 8;
+        if seeded.end > spare.end
+            || self.header.len() < spare.end
+            || self.header[spare].iter().any(|byte| *byte != 0)
+        {
+            return false;
+        }
+        self.header[seeded].copy_from_slice(&start.to_be_bytes());
+        true
+    }
+}
+
+/// Keeps the header buffer of the last replaced job for the next one.
+#[derive(Debug, Default)]
+pub struct HeaderBuffers {
+    spare: Mutex<Option<Box<[u8]>>>,
+}
+
+impl HeaderBuffers {
+    pub fn take(&self) -> Option<Box<[u8]>> {
+        self.spare.lock().unwrap().take()
+    }
+
+    /// Gives the header of `work` back, once nothing else holds the job.
+    pub fn recycle(&self, work: Arc<Work>) {
+        if let Ok(work) = Arc::try_unwrap(work) {
+            *self.spare.lock().unwrap() = Some(work.header);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_notify() {
+        let layout = HeaderLayout::IRONFISH;
+        let graffiti = [7u8; 32];
+        let header = "01".repeat(208);
+        let work = Work::from_notify(1, &header, &graffiti, [0xff; 32], &layout, None).unwrap();
+        let mut expected = hex::decode(&header).unwrap();
+        expected[176..208].copy_from_slice(&graffiti);
+        assert_eq!(&work.header[..], &expected[..]);
+        assert_eq!(work.target, [0xff; 32]);
+
+        // a recycled buffer gives the same job
+        let buffers = HeaderBuffers::default();
+        buffers.recycle(Arc::new(work));
+        let buffer = buffers.take().unwrap();
+        let pointer = buffer.as_ptr();
+        let work =
+            Work::from_notify(1, &header, &graffiti, [0xff; 32], &layout, Some(buffer)).unwrap();
+        assert_eq!(work.header.as_ptr(), pointer);
+        assert_eq!(&work.header[..], &expected[..]);
+
+        // a job still in use is not recycled
+        let work = Arc::new(work);
+        buffers.recycle(work.clone());
+        assert!(buffers.take().is_none());
+
+        // a longer header is kept whole, the extra bytes are hashed as the pool sent them
+        let longer = format!("{}{}", header, "ab".repeat(32));
+        let work = Work::from_notify(1, &longer, &graffiti, [0xff; 32], &layout, None).unwrap();
+        assert_eq!(&work.header[..208], &expected[..]);
+        assert_eq!(work.header[208..], [0xab; 32]);
+
+        assert!(Work::from_notify(1, "zz", &graffiti, [0; 32], &layout, None).is_err());
+        assert!(
+            Work::from_notify(1, &"00".repeat(100), &graffiti, [0; 32], &layout, None).is_err()
+        );
+    }
+
+    #[test]
+    fn test_seed_randomness() {
+        let graffiti = [7u8; 32];
+        let header = "00".repeat(208);
+        let layout = HeaderLayout::IRONFISH;
+        let mut work = Work::from_notify(1, &header, &graffiti, [0xff; 32], &layout, None).unwrap();
+        // the thread pool owns all of an 8 byte field
+        assert!(!work.seed_randomness(7, &layout));
+        assert!(work.header[..176].iter().all(|byte| *byte == 0));
+
+        let wide = layout.with_randomness_width(32).unwrap();
+        assert!(work.seed_randomness(7, &wide));
+        assert_eq!(work.header[8..16], 7u64.to_be_bytes());
+        let randomness = Randomness::in_header(1, &work.header, &wide).unwrap();
+        assert_eq!(randomness.as_bytes()[8..16], 7u64.to_be_bytes());
+        // bytes the pool set are kept
+        assert!(!work.seed_randomness(9, &wide));
+        assert_eq!(work.header[8..16], 7u64.to_be_bytes());
+    }
+}
@@ -0,0 +1,48 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! The build without TLS, for static binaries: `cargo test --no-default-features --test
+//! no_tls`. That it builds at all shows no TLS code is left, the tests that `--tls` is
+//! refused before anything connects. Empty in a build with TLS.
+
+#![cfg(not(feature = "tls"))]
+
+use clap::Parser;
+use std::time::Duration;
+use tokio::{net::TcpListener, time};
+use zkwork_ironminer::{check_tls_support, tls_backend, Cli, StratumClient, StratumClientConfig};
+
+fn tls_cli(pool: &str) -> Cli {
+    Cli::try_parse_from([
+        "zkwork_ironminer",
+        "--pool",
+        pool,
+        "--address",
+        "xxxxxx",
+        "--tls",
+        "true",
+    ])
+    .expect("valid options")
+}
+
+#[test]
+fn test_tls_refused() {
+    assert_eq!(tls_backend(), None);
+    let error = check_tls_support(&tls_cli("127.0.0.1:8181"))
+        .unwrap_err()
+        .to_string();
+    assert!(error.contains("built without TLS support"), "{}", error);
+}
+
+#[tokio::test]
+async fn test_start_without_tls() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let cli = tls_cli(&listener.local_addr().unwrap().to_string());
+    let client = StratumClient::new(StratumClientConfig::from_cli(&cli).unwrap());
+    StratumClient::start(client.clone()).await;
+    // a plain connection would get the pool's jobs to a miner that asked for TLS
+    assert!(time::timeout(Duration::from_millis(200), listener.accept())
+        .await
+        .is_err());
+}
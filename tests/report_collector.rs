@@ -0,0 +1,116 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! The `report_collector` example: reports pushed over UDP and TCP are printed with their
+//! sender, gaps and restarts in a worker's sequence numbers get a line of their own.
+
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::{SocketAddr, TcpStream, UdpSocket},
+    process::{Child, Command, Stdio},
+    sync::mpsc::{self, Receiver},
+    thread,
+    time::Duration,
+};
+use zkwork_ironminer::{StatsReport, StatsSnapshot};
+
+/// The example, killed when dropped.
+struct Collector {
+    child: Child,
+    lines: Receiver<String>,
+}
+
+impl Collector {
+    fn launch() -> Self {
+        let mut build = Command::new(env!("CARGO"));
+        build.args(["build", "--example", "report_collector"]);
+        if !cfg!(debug_assertions) {
+            build.arg("--release");
+        }
+        let status = build.status().expect("cargo runs");
+        assert!(status.success());
+        // target/<profile>/deps/<this test> -> target/<profile>/examples/report_collector
+        let mut path = std::env::current_exe().unwrap();
+        path.pop();
+        path.pop();
+        path.push("examples");
+        path.push(format!("report_collector{}", std::env::consts::EXE_SUFFIX));
+        let mut child = Command::new(path)
+            .args(["--listen", "127.0.0.1:0"])
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("the example runs");
+        let stdout = BufReader::new(child.stdout.take().unwrap());
+        let (sender, lines) = mpsc::channel();
+        thread::spawn(move || {
+            for line in stdout.lines() {
+                if sender.send(line.unwrap()).is_err() {
+                    break;
+                }
+            }
+        });
+        Self { child, lines }
+    }
+
+    fn next_line(&self) -> String {
+        self.lines
+            .recv_timeout(Duration::from_secs(10))
+            .expect("the collector prints a line")
+    }
+}
+
+impl Drop for Collector {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn report(seq: u64) -> String {
+    serde_json::to_string(&StatsReport::new(seq, "rig-7", &StatsSnapshot::default())).unwrap()
+}
+
+#[test]
+fn test_report_collector() {
+    let collector = Collector::launch();
+    let address: SocketAddr = collector
+        .next_line()
+        .strip_prefix("listening on ")
+        .expect("the listen address comes first")
+        .parse()
+        .unwrap();
+
+    let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+    for seq in [1, 2] {
+        udp.send_to(report(seq).as_bytes(), address).unwrap();
+        assert_eq!(
+            collector.next_line(),
+            format!("{} {}", udp.local_addr().unwrap(), report(seq))
+        );
+    }
+
+    let mut tcp = TcpStream::connect(address).unwrap();
+    writeln!(tcp, "{}", report(5)).unwrap();
+    assert_eq!(collector.next_line(), "rig-7 missed 2 reports before 5");
+    assert_eq!(
+        collector.next_line(),
+        format!("{} {}", tcp.local_addr().unwrap(), report(5))
+    );
+
+    // a restarted miner counts from 1 again
+    writeln!(tcp, "{}", report(1)).unwrap();
+    assert_eq!(collector.next_line(), "rig-7 restarted");
+    assert_eq!(
+        collector.next_line(),
+        format!("{} {}", tcp.local_addr().unwrap(), report(1))
+    );
+
+    // invalid reports are not printed
+    udp.send_to(b"not a report", address).unwrap();
+    writeln!(tcp, "{}", report(2)).unwrap();
+    assert_eq!(
+        collector.next_line(),
+        format!("{} {}", tcp.local_addr().unwrap(), report(2))
+    );
+}